@@ -0,0 +1,2 @@
+ALTER TABLE pending_blockchain_transactions
+  DROP COLUMN raw_tx;
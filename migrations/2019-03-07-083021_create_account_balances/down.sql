@@ -0,0 +1,3 @@
+DROP TRIGGER IF EXISTS update_account_balances ON transactions;
+DROP FUNCTION IF EXISTS update_account_balances();
+DROP TABLE account_balances;
@@ -0,0 +1,33 @@
+CREATE TABLE account_balances (
+    account_id UUID PRIMARY KEY REFERENCES accounts,
+    dr_turnover NUMERIC NOT NULL DEFAULT 0,
+    cr_turnover NUMERIC NOT NULL DEFAULT 0,
+    created_at TIMESTAMP NOT NULL DEFAULT current_timestamp,
+    updated_at TIMESTAMP NOT NULL DEFAULT current_timestamp
+);
+
+SELECT diesel_manage_updated_at('account_balances');
+
+CREATE OR REPLACE FUNCTION update_account_balances() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP = 'UPDATE' OR TG_OP = 'DELETE' THEN
+        UPDATE account_balances SET dr_turnover = dr_turnover - OLD.value WHERE account_id = OLD.dr_account_id;
+        UPDATE account_balances SET cr_turnover = cr_turnover - OLD.value WHERE account_id = OLD.cr_account_id;
+    END IF;
+    IF TG_OP = 'INSERT' OR TG_OP = 'UPDATE' THEN
+        INSERT INTO account_balances (account_id, dr_turnover) VALUES (NEW.dr_account_id, NEW.value)
+            ON CONFLICT (account_id) DO UPDATE SET dr_turnover = account_balances.dr_turnover + EXCLUDED.dr_turnover;
+        INSERT INTO account_balances (account_id, cr_turnover) VALUES (NEW.cr_account_id, NEW.value)
+            ON CONFLICT (account_id) DO UPDATE SET cr_turnover = account_balances.cr_turnover + EXCLUDED.cr_turnover;
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER update_account_balances AFTER INSERT OR DELETE OR UPDATE OF value, dr_account_id, cr_account_id ON transactions
+    FOR EACH ROW EXECUTE PROCEDURE update_account_balances();
+
+INSERT INTO account_balances (account_id, dr_turnover, cr_turnover)
+    SELECT accounts.id, COALESCE(dr.sum, 0), COALESCE(cr.sum, 0) FROM accounts
+    LEFT JOIN (SELECT dr_account_id, SUM(value) FROM transactions GROUP BY dr_account_id) AS dr ON dr.dr_account_id = accounts.id
+    LEFT JOIN (SELECT cr_account_id, SUM(value) FROM transactions GROUP BY cr_account_id) AS cr ON cr.cr_account_id = accounts.id;
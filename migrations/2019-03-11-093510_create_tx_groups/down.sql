@@ -0,0 +1,3 @@
+DROP TRIGGER IF EXISTS update_tx_groups ON transactions;
+DROP FUNCTION IF EXISTS update_tx_groups();
+DROP TABLE tx_groups;
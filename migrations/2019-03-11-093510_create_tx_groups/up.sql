@@ -0,0 +1,27 @@
+CREATE TABLE tx_groups (
+    gid UUID PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users,
+    group_kind VARCHAR NOT NULL,
+    created_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX tx_groups_user_id_created_at_idx ON tx_groups (user_id, created_at DESC, gid DESC) WHERE group_kind <> 'approval';
+
+CREATE OR REPLACE FUNCTION update_tx_groups() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+        INSERT INTO tx_groups (gid, user_id, group_kind, created_at) VALUES (NEW.gid, NEW.user_id, NEW.group_kind, NEW.created_at)
+            ON CONFLICT (gid) DO UPDATE SET created_at = LEAST(tx_groups.created_at, EXCLUDED.created_at);
+    END IF;
+    IF TG_OP = 'DELETE' THEN
+        DELETE FROM tx_groups WHERE gid = OLD.gid AND NOT EXISTS (SELECT 1 FROM transactions WHERE gid = OLD.gid);
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER update_tx_groups AFTER INSERT OR DELETE ON transactions
+    FOR EACH ROW EXECUTE PROCEDURE update_tx_groups();
+
+INSERT INTO tx_groups (gid, user_id, group_kind, created_at)
+    SELECT DISTINCT ON (gid) gid, user_id, group_kind, created_at FROM transactions ORDER BY gid, created_at;
@@ -0,0 +1,6 @@
+DROP INDEX accounts_meta_idx;
+DROP INDEX accounts_labels_idx;
+
+ALTER TABLE accounts
+  DROP COLUMN labels,
+  DROP COLUMN meta;
@@ -0,0 +1,6 @@
+ALTER TABLE accounts
+  ADD COLUMN meta JSONB NOT NULL DEFAULT '{}',
+  ADD COLUMN labels VARCHAR[] NOT NULL DEFAULT '{}';
+
+CREATE INDEX accounts_labels_idx ON accounts USING GIN (labels);
+CREATE INDEX accounts_meta_idx ON accounts USING GIN (meta jsonb_path_ops);
@@ -0,0 +1 @@
+DROP TABLE small_deposits;
@@ -0,0 +1,14 @@
+CREATE TABLE small_deposits (
+    blockchain_tx_id VARCHAR NOT NULL,
+    account_id UUID NOT NULL REFERENCES accounts,
+    user_id UUID NOT NULL REFERENCES users,
+    currency VARCHAR NOT NULL,
+    value NUMERIC NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT current_timestamp,
+    updated_at TIMESTAMP NOT NULL DEFAULT current_timestamp,
+    PRIMARY KEY (blockchain_tx_id, account_id)
+);
+
+CREATE INDEX small_deposits_created_at_idx ON small_deposits (created_at DESC);
+
+SELECT diesel_manage_updated_at('small_deposits');
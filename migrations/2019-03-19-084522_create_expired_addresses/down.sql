@@ -0,0 +1 @@
+DROP TABLE expired_addresses;
@@ -0,0 +1,9 @@
+CREATE TABLE expired_addresses (
+    address VARCHAR NOT NULL,
+    currency VARCHAR NOT NULL,
+    account_id UUID NOT NULL REFERENCES accounts,
+    expired_at TIMESTAMP NOT NULL DEFAULT current_timestamp,
+    PRIMARY KEY (address, currency)
+);
+
+CREATE INDEX expired_addresses_account_id_idx ON expired_addresses (account_id, expired_at DESC);
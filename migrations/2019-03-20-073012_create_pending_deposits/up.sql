@@ -0,0 +1,19 @@
+CREATE TABLE pending_deposits (
+    id UUID PRIMARY KEY,
+    blockchain_tx_id VARCHAR NOT NULL,
+    account_id UUID NOT NULL REFERENCES accounts,
+    user_id UUID NOT NULL REFERENCES users,
+    currency VARCHAR NOT NULL,
+    from_ JSONB NOT NULL,
+    address VARCHAR NOT NULL,
+    value NUMERIC NOT NULL,
+    confirmations INTEGER NOT NULL,
+    required_confirmations INTEGER NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT current_timestamp,
+    updated_at TIMESTAMP NOT NULL DEFAULT current_timestamp
+);
+
+CREATE UNIQUE INDEX pending_deposits_blockchain_tx_id_account_id_idx ON pending_deposits (blockchain_tx_id, account_id);
+CREATE INDEX pending_deposits_user_id_idx ON pending_deposits (user_id, created_at DESC);
+
+SELECT diesel_manage_updated_at('pending_deposits');
@@ -0,0 +1,2 @@
+ALTER TABLE accounts
+  ADD COLUMN auto_convert_to UUID REFERENCES accounts;
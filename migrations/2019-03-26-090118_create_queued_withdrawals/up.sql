@@ -0,0 +1,19 @@
+CREATE TABLE queued_withdrawals (
+    id UUID PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users,
+    account_id UUID NOT NULL REFERENCES accounts,
+    currency VARCHAR NOT NULL,
+    address VARCHAR NOT NULL,
+    value NUMERIC NOT NULL,
+    fee NUMERIC NOT NULL,
+    status VARCHAR NOT NULL,
+    transaction_id UUID,
+    error_message VARCHAR,
+    created_at TIMESTAMP NOT NULL DEFAULT current_timestamp,
+    updated_at TIMESTAMP NOT NULL DEFAULT current_timestamp
+);
+
+CREATE INDEX queued_withdrawals_status_idx ON queued_withdrawals (status, created_at);
+CREATE INDEX queued_withdrawals_user_id_idx ON queued_withdrawals (user_id, created_at DESC);
+
+SELECT diesel_manage_updated_at('queued_withdrawals');
@@ -0,0 +1,4 @@
+ALTER TABLE users
+  DROP COLUMN withdrawal_whitelist;
+
+DROP TABLE withdrawal_addresses;
@@ -0,0 +1,17 @@
+CREATE TABLE withdrawal_addresses (
+    id UUID PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users,
+    currency VARCHAR NOT NULL,
+    address VARCHAR NOT NULL,
+    label VARCHAR,
+    active_from TIMESTAMP NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT current_timestamp,
+    updated_at TIMESTAMP NOT NULL DEFAULT current_timestamp
+);
+
+CREATE UNIQUE INDEX withdrawal_addresses_user_id_currency_address_idx ON withdrawal_addresses (user_id, currency, address);
+
+SELECT diesel_manage_updated_at('withdrawal_addresses');
+
+ALTER TABLE users
+  ADD COLUMN withdrawal_whitelist BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,2 @@
+ALTER TABLE pending_deposits
+  DROP COLUMN estimated_completion_at;
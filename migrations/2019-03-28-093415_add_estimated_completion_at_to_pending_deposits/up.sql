@@ -0,0 +1,2 @@
+ALTER TABLE pending_deposits
+  ADD COLUMN estimated_completion_at TIMESTAMP NOT NULL DEFAULT current_timestamp;
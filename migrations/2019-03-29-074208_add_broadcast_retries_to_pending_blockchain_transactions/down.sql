@@ -0,0 +1,5 @@
+DROP INDEX pending_blockchain_transactions_next_broadcast_at_idx;
+
+ALTER TABLE pending_blockchain_transactions
+  DROP COLUMN broadcast_attempts,
+  DROP COLUMN next_broadcast_at;
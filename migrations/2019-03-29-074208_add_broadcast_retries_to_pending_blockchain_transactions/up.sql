@@ -0,0 +1,5 @@
+ALTER TABLE pending_blockchain_transactions
+  ADD COLUMN broadcast_attempts INTEGER NOT NULL DEFAULT 0,
+  ADD COLUMN next_broadcast_at TIMESTAMP;
+
+CREATE INDEX pending_blockchain_transactions_next_broadcast_at_idx ON pending_blockchain_transactions (next_broadcast_at);
@@ -0,0 +1,16 @@
+CREATE TABLE rate_locks (
+    id UUID PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users,
+    exchange_id UUID NOT NULL,
+    from_currency VARCHAR NOT NULL,
+    to_currency VARCHAR NOT NULL,
+    amount NUMERIC NOT NULL,
+    amount_currency VARCHAR NOT NULL,
+    rate DOUBLE PRECISION NOT NULL,
+    expires_at TIMESTAMP NOT NULL,
+    used_at TIMESTAMP,
+    created_at TIMESTAMP NOT NULL DEFAULT current_timestamp,
+    updated_at TIMESTAMP NOT NULL DEFAULT current_timestamp
+);
+
+SELECT diesel_manage_updated_at('rate_locks');
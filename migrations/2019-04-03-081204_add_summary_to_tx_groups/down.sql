@@ -0,0 +1,26 @@
+DROP INDEX tx_groups_account_ids_idx;
+
+DROP TRIGGER update_tx_groups ON transactions;
+DROP FUNCTION refresh_tx_group(UUID);
+
+CREATE OR REPLACE FUNCTION update_tx_groups() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+        INSERT INTO tx_groups (gid, user_id, group_kind, created_at) VALUES (NEW.gid, NEW.user_id, NEW.group_kind, NEW.created_at)
+            ON CONFLICT (gid) DO UPDATE SET created_at = LEAST(tx_groups.created_at, EXCLUDED.created_at);
+    END IF;
+    IF TG_OP = 'DELETE' THEN
+        DELETE FROM tx_groups WHERE gid = OLD.gid AND NOT EXISTS (SELECT 1 FROM transactions WHERE gid = OLD.gid);
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER update_tx_groups AFTER INSERT OR DELETE ON transactions
+    FOR EACH ROW EXECUTE PROCEDURE update_tx_groups();
+
+ALTER TABLE tx_groups
+    DROP COLUMN status,
+    DROP COLUMN account_ids,
+    DROP COLUMN value,
+    DROP COLUMN last_created_at;
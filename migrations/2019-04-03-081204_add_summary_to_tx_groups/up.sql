@@ -0,0 +1,58 @@
+ALTER TABLE tx_groups
+    ADD COLUMN status VARCHAR NOT NULL DEFAULT 'done',
+    ADD COLUMN account_ids UUID[] NOT NULL DEFAULT '{}',
+    ADD COLUMN value NUMERIC NOT NULL DEFAULT 0,
+    ADD COLUMN last_created_at TIMESTAMP;
+
+-- Summary of a group is recomputed from its transactions, groups are small so it's cheap.
+-- Value of the group is what's sent by it, the same way converter computes `from_value`,
+-- and the group is pending while any of its transactions is pending
+CREATE OR REPLACE FUNCTION refresh_tx_group(group_id UUID) RETURNS void AS $$
+BEGIN
+    IF NOT EXISTS (SELECT 1 FROM transactions WHERE gid = group_id) THEN
+        DELETE FROM tx_groups WHERE gid = group_id;
+        RETURN;
+    END IF;
+    INSERT INTO tx_groups (gid, user_id, group_kind, status, account_ids, value, created_at, last_created_at)
+        SELECT
+            gid,
+            (array_agg(user_id ORDER BY created_at))[1],
+            (array_agg(group_kind ORDER BY created_at))[1],
+            max(status),
+            ARRAY(SELECT dr_account_id FROM transactions WHERE gid = group_id UNION SELECT cr_account_id FROM transactions WHERE gid = group_id),
+            COALESCE(sum(value) FILTER (WHERE kind = 'multi_from'), sum(value) FILTER (WHERE kind IN ('deposit', 'internal', 'withdrawal')), 0),
+            min(created_at),
+            max(created_at)
+        FROM transactions WHERE gid = group_id GROUP BY gid
+        ON CONFLICT (gid) DO UPDATE SET
+            user_id = EXCLUDED.user_id,
+            group_kind = EXCLUDED.group_kind,
+            status = EXCLUDED.status,
+            account_ids = EXCLUDED.account_ids,
+            value = EXCLUDED.value,
+            created_at = EXCLUDED.created_at,
+            last_created_at = EXCLUDED.last_created_at;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION update_tx_groups() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        PERFORM refresh_tx_group(OLD.gid);
+    ELSE
+        PERFORM refresh_tx_group(NEW.gid);
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+-- status changes when blockchain transactions are confirmed
+DROP TRIGGER update_tx_groups ON transactions;
+CREATE TRIGGER update_tx_groups AFTER INSERT OR UPDATE OF status, value OR DELETE ON transactions
+    FOR EACH ROW EXECUTE PROCEDURE update_tx_groups();
+
+SELECT refresh_tx_group(gid) FROM tx_groups;
+
+ALTER TABLE tx_groups ALTER COLUMN last_created_at SET NOT NULL;
+
+CREATE INDEX tx_groups_account_ids_idx ON tx_groups USING GIN (account_ids);
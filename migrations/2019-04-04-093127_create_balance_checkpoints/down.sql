@@ -0,0 +1,2 @@
+DROP INDEX transactions_created_at_idx;
+DROP TABLE balance_checkpoints;
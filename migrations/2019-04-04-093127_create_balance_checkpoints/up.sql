@@ -0,0 +1,11 @@
+CREATE TABLE balance_checkpoints (
+    account_id UUID NOT NULL REFERENCES accounts,
+    as_of TIMESTAMP NOT NULL,
+    dr_turnover NUMERIC NOT NULL,
+    cr_turnover NUMERIC NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT current_timestamp,
+    PRIMARY KEY (as_of, account_id)
+);
+
+-- transactions after the latest checkpoint are read by range
+CREATE INDEX transactions_created_at_idx ON transactions (created_at);
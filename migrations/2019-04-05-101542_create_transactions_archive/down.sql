@@ -0,0 +1,35 @@
+-- archived transactions are moved back, so that nothing is lost, still without touching balances
+SET LOCAL ledger.archiving = 'on';
+INSERT INTO transactions SELECT * FROM transactions_archive;
+SET LOCAL ledger.archiving = 'off';
+
+CREATE OR REPLACE FUNCTION update_tx_groups() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        PERFORM refresh_tx_group(OLD.gid);
+    ELSE
+        PERFORM refresh_tx_group(NEW.gid);
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION update_account_balances() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP = 'UPDATE' OR TG_OP = 'DELETE' THEN
+        UPDATE account_balances SET dr_turnover = dr_turnover - OLD.value WHERE account_id = OLD.dr_account_id;
+        UPDATE account_balances SET cr_turnover = cr_turnover - OLD.value WHERE account_id = OLD.cr_account_id;
+    END IF;
+    IF TG_OP = 'INSERT' OR TG_OP = 'UPDATE' THEN
+        INSERT INTO account_balances (account_id, dr_turnover) VALUES (NEW.dr_account_id, NEW.value)
+            ON CONFLICT (account_id) DO UPDATE SET dr_turnover = account_balances.dr_turnover + EXCLUDED.dr_turnover;
+        INSERT INTO account_balances (account_id, cr_turnover) VALUES (NEW.cr_account_id, NEW.value)
+            ON CONFLICT (account_id) DO UPDATE SET cr_turnover = account_balances.cr_turnover + EXCLUDED.cr_turnover;
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+ALTER TABLE tx_groups DROP COLUMN archived;
+DROP VIEW all_transactions;
+DROP TABLE transactions_archive;
@@ -0,0 +1,52 @@
+-- Groups of transactions behind retention are moved here by `archive_transactions` command.
+-- Columns must be kept the same as in transactions, since rows are moved as they are
+CREATE TABLE transactions_archive (LIKE transactions INCLUDING DEFAULTS INCLUDING CONSTRAINTS);
+
+ALTER TABLE transactions_archive ADD PRIMARY KEY (id);
+CREATE INDEX transactions_archive_gid_idx ON transactions_archive (gid);
+CREATE INDEX transactions_archive_dr_account_id_idx ON transactions_archive (dr_account_id);
+CREATE INDEX transactions_archive_cr_account_id_idx ON transactions_archive (cr_account_id);
+
+-- read paths query both tables through the view
+CREATE VIEW all_transactions AS
+    SELECT * FROM transactions
+    UNION ALL
+    SELECT * FROM transactions_archive;
+
+-- archived groups stay listed, so that history looks the same
+ALTER TABLE tx_groups ADD COLUMN archived BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- Moving rows to archive is not a change of the ledger, so materialized balances and group summaries
+-- are not touched while `ledger.archiving` is set for the db transaction
+CREATE OR REPLACE FUNCTION update_account_balances() RETURNS trigger AS $$
+BEGIN
+    IF current_setting('ledger.archiving', true) = 'on' THEN
+        RETURN NULL;
+    END IF;
+    IF TG_OP = 'UPDATE' OR TG_OP = 'DELETE' THEN
+        UPDATE account_balances SET dr_turnover = dr_turnover - OLD.value WHERE account_id = OLD.dr_account_id;
+        UPDATE account_balances SET cr_turnover = cr_turnover - OLD.value WHERE account_id = OLD.cr_account_id;
+    END IF;
+    IF TG_OP = 'INSERT' OR TG_OP = 'UPDATE' THEN
+        INSERT INTO account_balances (account_id, dr_turnover) VALUES (NEW.dr_account_id, NEW.value)
+            ON CONFLICT (account_id) DO UPDATE SET dr_turnover = account_balances.dr_turnover + EXCLUDED.dr_turnover;
+        INSERT INTO account_balances (account_id, cr_turnover) VALUES (NEW.cr_account_id, NEW.value)
+            ON CONFLICT (account_id) DO UPDATE SET cr_turnover = account_balances.cr_turnover + EXCLUDED.cr_turnover;
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION update_tx_groups() RETURNS trigger AS $$
+BEGIN
+    IF current_setting('ledger.archiving', true) = 'on' THEN
+        RETURN NULL;
+    END IF;
+    IF TG_OP = 'DELETE' THEN
+        PERFORM refresh_tx_group(OLD.gid);
+    ELSE
+        PERFORM refresh_tx_group(NEW.gid);
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
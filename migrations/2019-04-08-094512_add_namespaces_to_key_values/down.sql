@@ -0,0 +1,9 @@
+DELETE FROM key_values WHERE expires_at IS NOT NULL AND expires_at <= now() AT TIME ZONE 'utc';
+UPDATE key_values SET key = namespace || ':' || key WHERE namespace <> 'settings';
+
+ALTER TABLE key_values DROP CONSTRAINT key_values_pkey;
+ALTER TABLE key_values ADD PRIMARY KEY (key);
+
+ALTER TABLE key_values DROP COLUMN expires_at;
+ALTER TABLE key_values DROP COLUMN version;
+ALTER TABLE key_values DROP COLUMN namespace;
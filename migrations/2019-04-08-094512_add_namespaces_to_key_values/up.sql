@@ -0,0 +1,12 @@
+ALTER TABLE key_values ADD COLUMN namespace VARCHAR NOT NULL DEFAULT 'settings';
+-- bumped on every write, so that concurrent writers can detect lost updates
+ALTER TABLE key_values ADD COLUMN version BIGINT NOT NULL DEFAULT 1;
+-- entries past `expires_at` are treated as absent
+ALTER TABLE key_values ADD COLUMN expires_at TIMESTAMP;
+
+-- existing `prefix:key` keys are split into namespace and key, keys without prefix are runtime settings
+UPDATE key_values SET namespace = split_part(key, ':', 1), key = substring(key FROM position(':' IN key) + 1) WHERE position(':' IN key) > 0;
+
+ALTER TABLE key_values DROP CONSTRAINT key_values_pkey;
+ALTER TABLE key_values ADD PRIMARY KEY (namespace, key);
+ALTER TABLE key_values ALTER COLUMN namespace DROP DEFAULT;
@@ -0,0 +1,4 @@
+DROP INDEX strange_blockchain_transactions_to_idx;
+DROP INDEX strange_blockchain_transactions_from_idx;
+DROP INDEX strange_blockchain_transactions_created_at_idx;
+ALTER TABLE strange_blockchain_transactions DROP COLUMN violation_kind;
@@ -0,0 +1,18 @@
+ALTER TABLE strange_blockchain_transactions ADD COLUMN violation_kind VARCHAR;
+
+-- violations were recorded only as their description in commentary
+UPDATE strange_blockchain_transactions SET violation_kind = CASE commentary
+    WHEN 'blockchain transaction invariant violation - unexpected number of addresses' THEN 'withdrawal_adresses_count'
+    WHEN 'blockchain transaction invariant violation - address of transaction not found in our database' THEN 'withdrawal_adresses_not_found'
+    WHEN 'blockchain transaction invariant violation - transaction referred to non-existing account' THEN 'not_existing_account'
+    WHEN 'blockchain transaction invariant violation - withdrawal happened to internal account, which shouldn''t be the case' THEN 'withdrawal_adresses_internal'
+    WHEN 'blockchain transaction invariant violation - withdrawal transaction should be in pending state when blockchain tx arrives' THEN 'withdrawal_not_pending_address'
+    WHEN 'blockchain transaction invariant violation - withdrawal blockchain tx doesn''t have corresponding pending part' THEN 'withdrawal_no_pending_tx'
+    WHEN 'blockchain transaction invariant violation - withdrawal blockchain tx value is not equal to pending tx value' THEN 'withdrawal_value'
+    WHEN 'blockchain transaction invariant violation - withdrawal blockchain tx fee exceeds balance of the account paying it' THEN 'withdrawal_fee'
+    WHEN 'blockchain transaction invariant violation - deposit arrived from internal address' THEN 'deposit_address_internal'
+END;
+
+CREATE INDEX strange_blockchain_transactions_created_at_idx ON strange_blockchain_transactions (created_at);
+CREATE INDEX strange_blockchain_transactions_from_idx ON strange_blockchain_transactions USING GIN (from_ jsonb_path_ops);
+CREATE INDEX strange_blockchain_transactions_to_idx ON strange_blockchain_transactions USING GIN (to_ jsonb_path_ops);
@@ -0,0 +1,15 @@
+CREATE TABLE quarantined_messages (
+    id UUID PRIMARY KEY,
+    currency VARCHAR NOT NULL,
+    payload BYTEA NOT NULL,
+    error TEXT NOT NULL,
+    attempts INTEGER NOT NULL,
+    request_id VARCHAR,
+    replayed_at TIMESTAMP,
+    created_at TIMESTAMP NOT NULL DEFAULT current_timestamp,
+    updated_at TIMESTAMP NOT NULL DEFAULT current_timestamp
+);
+
+CREATE INDEX quarantined_messages_created_at_idx ON quarantined_messages (created_at);
+
+SELECT diesel_manage_updated_at('quarantined_messages');
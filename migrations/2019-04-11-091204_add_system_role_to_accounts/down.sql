@@ -0,0 +1,2 @@
+DROP INDEX accounts_system_role_idx;
+ALTER TABLE accounts DROP COLUMN system_role;
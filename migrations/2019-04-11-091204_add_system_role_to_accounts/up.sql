@@ -0,0 +1,4 @@
+ALTER TABLE accounts ADD COLUMN system_role VARCHAR;
+
+-- one credit and one debit account of each role per currency
+CREATE UNIQUE INDEX accounts_system_role_idx ON accounts (currency, system_role, kind) WHERE system_role IS NOT NULL;
@@ -0,0 +1,15 @@
+-- Fee charged by transaction group and network fees actually paid for its blockchain transactions,
+-- that are accrued as they are confirmed. Charged fee is also kept converted to the currency of network fees
+-- at the rate it was estimated with, so that they can be compared
+CREATE TABLE group_fees (
+    gid UUID PRIMARY KEY,
+    fee_currency VARCHAR NOT NULL,
+    fee NUMERIC NOT NULL,
+    blockchain_fee_currency VARCHAR NOT NULL,
+    charged_blockchain_fee NUMERIC NOT NULL,
+    blockchain_fee NUMERIC NOT NULL DEFAULT 0,
+    created_at TIMESTAMP NOT NULL DEFAULT current_timestamp,
+    updated_at TIMESTAMP NOT NULL DEFAULT current_timestamp
+);
+
+SELECT diesel_manage_updated_at('group_fees');
@@ -0,0 +1,6 @@
+ALTER TABLE accounts ADD COLUMN last_spent_at TIMESTAMP;
+
+-- withdrawals are sent from hot accounts least recently spent from first
+UPDATE accounts SET last_spent_at = spent.last_spent_at
+FROM (SELECT cr_account_id, MAX(created_at) AS last_spent_at FROM all_transactions WHERE kind = 'withdrawal' GROUP BY cr_account_id) AS spent
+WHERE accounts.id = spent.cr_account_id AND accounts.kind = 'dr';
@@ -54,7 +54,7 @@ pub fn get_users_accounts(ctx: &Context, user_id: UserId) -> ControllerFuture {
                     .and_then(move |token| {
                         let input_clone = input.clone();
                         accounts_service
-                            .get_accounts_for_user(token, user_id, input.offset, input.limit)
+                            .get_accounts_for_user(token, user_id, input.offset, input.limit, input.filter())
                             .map_err(ectx!(convert => input_clone))
                     })
             })
@@ -65,6 +65,35 @@ pub fn get_users_accounts(ctx: &Context, user_id: UserId) -> ControllerFuture {
     )
 }
 
+pub fn get_users_wallet(ctx: &Context, user_id: UserId) -> ControllerFuture {
+    let wallet_service = ctx.wallet_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    let amount_format = ctx.amount_format;
+    let path_and_query = ctx.uri.path_and_query();
+    // all params are optional, so is the query
+    let query = ctx.uri.query().unwrap_or_default();
+    Box::new(
+        serde_qs::from_str::<GetUsersWalletParams>(query)
+            .map_err(|e| {
+                let e = format_err!("{}", e);
+                ectx!(err e, ErrorContext::RequestQueryParams, ErrorKind::BadRequest => path_and_query)
+            })
+            .into_future()
+            .and_then(move |input| {
+                maybe_token
+                    .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+                    .into_future()
+                    .and_then(move |token| {
+                        let input_clone = input.clone();
+                        wallet_service
+                            .get_wallet(token, user_id, input.reference_currency)
+                            .map_err(ectx!(convert => input_clone))
+                    })
+            })
+            .and_then(move |wallet| response_with_model(&WalletResponse::from((wallet, amount_format)))),
+    )
+}
+
 pub fn get_accounts(ctx: &Context, account_id: AccountId) -> ControllerFuture {
     let accounts_service = ctx.accounts_service.clone();
     let maybe_token = ctx.get_auth_token();
@@ -102,6 +131,73 @@ pub fn put_accounts(ctx: &Context, account_id: AccountId) -> ControllerFuture {
     )
 }
 
+pub fn get_accounts_addresses(ctx: &Context, account_id: AccountId) -> ControllerFuture {
+    let accounts_service = ctx.accounts_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    Box::new(
+        maybe_token
+            .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+            .into_future()
+            .and_then(move |token| {
+                accounts_service
+                    .get_addresses(token, account_id)
+                    .map_err(ectx!(convert))
+                    .and_then(|addresses| response_with_model(&AccountAddressesResponse::from(addresses)))
+            }),
+    )
+}
+
+pub fn post_accounts_addresses_rotate(ctx: &Context, account_id: AccountId) -> ControllerFuture {
+    let accounts_service = ctx.accounts_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    Box::new(
+        maybe_token
+            .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+            .into_future()
+            .and_then(move |token| {
+                accounts_service
+                    .rotate_address(token, account_id)
+                    .map_err(ectx!(convert))
+                    .and_then(|addresses| response_with_model(&AccountAddressesResponse::from(addresses)))
+            }),
+    )
+}
+
+pub fn get_accounts_payment_uri(ctx: &Context, account_id: AccountId) -> ControllerFuture {
+    let accounts_service = ctx.accounts_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    let amount_format = ctx.amount_format;
+    let path_and_query = ctx.uri.path_and_query();
+    let path_and_query_clone = ctx.uri.path_and_query();
+    // without amount it's just the address in a form wallets understand
+    let query = ctx.uri.query().unwrap_or_default();
+    Box::new(
+        serde_qs::from_str::<GetAccountsPaymentUriParams>(query)
+            .map_err(|e| {
+                let e = format_err!("{}", e);
+                ectx!(err e, ErrorContext::RequestQueryParams, ErrorKind::BadRequest => path_and_query)
+            })
+            .and_then(|input| {
+                input.amount().map_err(|e| {
+                    let e = format_err!("{}", e);
+                    ectx!(err e, ErrorContext::RequestQueryParams, ErrorKind::BadRequest => path_and_query_clone)
+                })
+            })
+            .into_future()
+            .and_then(move |amount| {
+                maybe_token
+                    .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+                    .into_future()
+                    .and_then(move |token| {
+                        accounts_service
+                            .get_payment_uri(token, account_id, amount)
+                            .map_err(ectx!(convert => account_id, amount))
+                    })
+            })
+            .and_then(move |payment_uri| response_with_model(&PaymentUriResponse::from((payment_uri, amount_format)))),
+    )
+}
+
 pub fn delete_accounts(ctx: &Context, account_id: AccountId) -> ControllerFuture {
     let accounts_service = ctx.accounts_service.clone();
     let maybe_token = ctx.get_auth_token();
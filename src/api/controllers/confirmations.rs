@@ -0,0 +1,52 @@
+use futures::prelude::*;
+
+use super::super::utils::{parse_body, response_with_model};
+use super::Context;
+use super::ControllerFuture;
+use api::error::*;
+use api::requests::*;
+use api::responses::*;
+
+pub fn get_confirmation_thresholds(ctx: &Context) -> ControllerFuture {
+    let confirmations_service = ctx.confirmations_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    Box::new(
+        maybe_token
+            .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+            .into_future()
+            .and_then(move |token| confirmations_service.get_thresholds(token).map_err(ectx!(convert)))
+            .and_then(|settings| response_with_model(&ConfirmationThresholdsResponse::from(settings))),
+    )
+}
+
+pub fn put_confirmation_thresholds(ctx: &Context) -> ControllerFuture {
+    let confirmations_service = ctx.confirmations_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    let body = ctx.body.clone();
+    Box::new(
+        maybe_token
+            .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+            .into_future()
+            .and_then(move |token| {
+                parse_body::<PutConfirmationThresholdsRequest>(body).and_then(move |input| {
+                    let input_clone = input.clone();
+                    confirmations_service
+                        .set_thresholds(token, input.into())
+                        .map_err(ectx!(convert => input_clone))
+                })
+            })
+            .and_then(|settings| response_with_model(&ConfirmationThresholdsResponse::from(settings))),
+    )
+}
+
+pub fn delete_confirmation_thresholds(ctx: &Context) -> ControllerFuture {
+    let confirmations_service = ctx.confirmations_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    Box::new(
+        maybe_token
+            .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+            .into_future()
+            .and_then(move |token| confirmations_service.reset_thresholds(token).map_err(ectx!(convert)))
+            .and_then(|settings| response_with_model(&ConfirmationThresholdsResponse::from(settings))),
+    )
+}
@@ -5,11 +5,13 @@ use super::super::utils::{parse_body, response_with_model};
 use super::Context;
 use super::ControllerFuture;
 use api::error::*;
+use api::responses::*;
 use models::*;
 
 pub fn post_rate(ctx: &Context) -> ControllerFuture {
     let exchange_service = ctx.exchange_service.clone();
     let maybe_token = ctx.get_auth_token();
+    let amount_format = ctx.amount_format;
     let body = ctx.body.clone();
     Box::new(
         maybe_token
@@ -21,7 +23,47 @@ pub fn post_rate(ctx: &Context) -> ControllerFuture {
                         let input_clone = input.clone();
                         exchange_service.rate(token, input).map_err(ectx!(convert => input_clone))
                     })
-                    .and_then(|rate| response_with_model(&rate))
+                    .and_then(move |rate| response_with_model(&RateResponse::from((rate, amount_format))))
+            }),
+    )
+}
+
+pub fn post_exchange_quote(ctx: &Context) -> ControllerFuture {
+    let exchange_service = ctx.exchange_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    let amount_format = ctx.amount_format;
+    let body = ctx.body.clone();
+    Box::new(
+        maybe_token
+            .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+            .into_future()
+            .and_then(move |token| {
+                parse_body::<RateInput>(body)
+                    .and_then(move |input| {
+                        let input_clone = input.clone();
+                        exchange_service.quote(token, input).map_err(ectx!(convert => input_clone))
+                    })
+                    .and_then(move |quote| response_with_model(&ExchangeQuoteResponse::from((quote, amount_format))))
+            }),
+    )
+}
+
+pub fn post_rate_lock(ctx: &Context) -> ControllerFuture {
+    let rate_locks_service = ctx.rate_locks_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    let amount_format = ctx.amount_format;
+    let body = ctx.body.clone();
+    Box::new(
+        maybe_token
+            .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+            .into_future()
+            .and_then(move |token| {
+                parse_body::<RateInput>(body)
+                    .and_then(move |input| {
+                        let input_clone = input.clone();
+                        rate_locks_service.lock_rate(token, input).map_err(ectx!(convert => input_clone))
+                    })
+                    .and_then(move |rate_lock| response_with_model(&RateLockResponse::from((rate_lock, amount_format))))
             }),
     )
 }
@@ -29,6 +71,7 @@ pub fn post_rate(ctx: &Context) -> ControllerFuture {
 pub fn post_rate_refresh(ctx: &Context) -> ControllerFuture {
     let exchange_service = ctx.exchange_service.clone();
     let maybe_token = ctx.get_auth_token();
+    let amount_format = ctx.amount_format;
     let body = ctx.body.clone();
     Box::new(
         maybe_token
@@ -40,7 +83,7 @@ pub fn post_rate_refresh(ctx: &Context) -> ControllerFuture {
                         let input_clone = input.clone();
                         exchange_service.refresh_rate(input).map_err(ectx!(convert => input_clone))
                     })
-                    .and_then(|rate| response_with_model(&rate))
+                    .and_then(move |refresh| response_with_model(&RateRefreshResponse::from((refresh, amount_format))))
             }),
     )
 }
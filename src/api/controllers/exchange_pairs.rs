@@ -0,0 +1,52 @@
+use futures::prelude::*;
+
+use super::super::utils::{parse_body, response_with_model};
+use super::Context;
+use super::ControllerFuture;
+use api::error::*;
+use api::requests::*;
+use api::responses::*;
+
+pub fn get_exchange_pairs(ctx: &Context) -> ControllerFuture {
+    let exchange_pairs_service = ctx.exchange_pairs_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    Box::new(
+        maybe_token
+            .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+            .into_future()
+            .and_then(move |token| exchange_pairs_service.get_pairs(token).map_err(ectx!(convert)))
+            .and_then(|settings| response_with_model(&ExchangePairsResponse::from(settings))),
+    )
+}
+
+pub fn put_exchange_pairs(ctx: &Context) -> ControllerFuture {
+    let exchange_pairs_service = ctx.exchange_pairs_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    let body = ctx.body.clone();
+    Box::new(
+        maybe_token
+            .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+            .into_future()
+            .and_then(move |token| {
+                parse_body::<PutExchangePairsRequest>(body).and_then(move |input| {
+                    let input_clone = input.clone();
+                    exchange_pairs_service
+                        .set_pairs(token, input.into())
+                        .map_err(ectx!(convert => input_clone))
+                })
+            })
+            .and_then(|settings| response_with_model(&ExchangePairsResponse::from(settings))),
+    )
+}
+
+pub fn delete_exchange_pairs(ctx: &Context) -> ControllerFuture {
+    let exchange_pairs_service = ctx.exchange_pairs_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    Box::new(
+        maybe_token
+            .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+            .into_future()
+            .and_then(move |token| exchange_pairs_service.reset_pairs(token).map_err(ectx!(convert)))
+            .and_then(|settings| response_with_model(&ExchangePairsResponse::from(settings))),
+    )
+}
@@ -11,6 +11,7 @@ use api::responses::*;
 pub fn post_fees(ctx: &Context) -> ControllerFuture {
     let fees_service = ctx.fees_service.clone();
     let maybe_token = ctx.get_auth_token();
+    let amount_format = ctx.amount_format;
     let body = ctx.body.clone();
     Box::new(
         maybe_token
@@ -22,7 +23,7 @@ pub fn post_fees(ctx: &Context) -> ControllerFuture {
                     fees_service
                         .get_fees(fees.into())
                         .map_err(ectx!(convert => fees_clone))
-                        .and_then(|fees| response_with_model(&FeesResponse::from(fees)))
+                        .and_then(move |fees| response_with_model(&FeesResponse::from((fees, amount_format))))
                 })
             }),
     )
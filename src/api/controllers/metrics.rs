@@ -1,3 +1,5 @@
+use hyper::StatusCode;
+
 use super::super::utils::response_with_model;
 use super::Context;
 use super::ControllerFuture;
@@ -12,3 +14,19 @@ pub fn get_metrics(ctx: &Context) -> ControllerFuture {
             .and_then(|metrics| response_with_model(&metrics)),
     )
 }
+
+/// Responds with 503 if db pools or rabbit connection are unhealthy, so that it can be used as readiness probe
+pub fn get_healthz(ctx: &Context) -> ControllerFuture {
+    let metrics_service = ctx.metrics_service.clone();
+    Box::new(metrics_service.get_health().map_err(ectx!(convert)).and_then(|health| {
+        let status = if health.healthy {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+        response_with_model(&health).map(move |mut response| {
+            *response.status_mut() = status;
+            response
+        })
+    }))
+}
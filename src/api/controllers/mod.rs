@@ -5,24 +5,41 @@ use futures::prelude::*;
 use hyper::{header::HeaderValue, header::AUTHORIZATION, Body, HeaderMap, Method, Response, Uri};
 
 use super::error::*;
+use super::requests::AmountFormat;
 use models::*;
-use services::{AccountsService, ExchangeService, FeesService, MetricsService, TransactionsService, UsersService};
+use services::{
+    AccountsService, ConfirmationsService, ExchangePairsService, ExchangeService, FeesService, MetricsService, PendingDepositsService,
+    RateLocksService, SmallDepositsService, SystemAccountsService, TransactionLimitsService, TransactionsService, UsersService,
+    WalletService, WithdrawalAddressesService,
+};
 
 mod accounts;
+mod confirmations;
 mod exchange;
+mod exchange_pairs;
 mod fallback;
 mod fees;
 mod metrics;
+mod small_deposits;
+mod system_accounts;
+mod transaction_limits;
 mod transactions;
 mod users;
+mod withdrawal_addresses;
 
 pub use self::accounts::*;
+pub use self::confirmations::*;
 pub use self::exchange::*;
+pub use self::exchange_pairs::*;
 pub use self::fallback::*;
 pub use self::fees::*;
 pub use self::metrics::*;
+pub use self::small_deposits::*;
+pub use self::system_accounts::*;
+pub use self::transaction_limits::*;
 pub use self::transactions::*;
 pub use self::users::*;
+pub use self::withdrawal_addresses::*;
 
 pub type ControllerFuture = Box<Future<Item = Response<Body>, Error = Error> + Send>;
 
@@ -32,12 +49,22 @@ pub struct Context {
     pub method: Method,
     pub uri: Uri,
     pub headers: HeaderMap<HeaderValue>,
+    pub amount_format: AmountFormat,
     pub users_service: Arc<dyn UsersService>,
     pub accounts_service: Arc<dyn AccountsService>,
     pub transactions_service: Arc<dyn TransactionsService>,
     pub exchange_service: Arc<dyn ExchangeService>,
+    pub rate_locks_service: Arc<dyn RateLocksService>,
     pub metrics_service: Arc<dyn MetricsService>,
     pub fees_service: Arc<dyn FeesService>,
+    pub confirmations_service: Arc<dyn ConfirmationsService>,
+    pub exchange_pairs_service: Arc<dyn ExchangePairsService>,
+    pub wallet_service: Arc<dyn WalletService>,
+    pub small_deposits_service: Arc<dyn SmallDepositsService>,
+    pub pending_deposits_service: Arc<dyn PendingDepositsService>,
+    pub withdrawal_addresses_service: Arc<dyn WithdrawalAddressesService>,
+    pub transaction_limits_service: Arc<dyn TransactionLimitsService>,
+    pub system_accounts_service: Arc<dyn SystemAccountsService>,
 }
 
 impl Context {
@@ -0,0 +1,42 @@
+use failure::Fail;
+use futures::prelude::*;
+
+use super::super::utils::response_with_model;
+use super::Context;
+use super::ControllerFuture;
+use api::error::*;
+use api::requests::*;
+use api::responses::*;
+use serde_qs;
+
+pub fn get_small_deposits(ctx: &Context) -> ControllerFuture {
+    let small_deposits_service = ctx.small_deposits_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    let amount_format = ctx.amount_format;
+    let path_and_query = ctx.uri.path_and_query();
+    let path_and_query_clone = ctx.uri.path_and_query();
+    Box::new(
+        ctx.uri
+            .query()
+            .ok_or(ectx!(err ErrorContext::RequestMissingQuery, ErrorKind::BadRequest => path_and_query))
+            .and_then(|query| {
+                serde_qs::from_str::<GetSmallDepositsParams>(query).map_err(|e| {
+                    let e = format_err!("{}", e);
+                    ectx!(err e, ErrorContext::RequestQueryParams, ErrorKind::BadRequest => path_and_query_clone)
+                })
+            })
+            .into_future()
+            .and_then(move |input| {
+                maybe_token
+                    .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+                    .into_future()
+                    .and_then(move |token| {
+                        let input_clone = input.clone();
+                        small_deposits_service
+                            .get_report(token, input.currency, input.offset, input.limit)
+                            .map_err(ectx!(convert => input_clone))
+                    })
+            })
+            .and_then(move |report| response_with_model(&SmallDepositsReportResponse::from((report, amount_format)))),
+    )
+}
@@ -0,0 +1,73 @@
+use futures::prelude::*;
+
+use super::super::utils::{parse_body, response_with_model};
+use super::Context;
+use super::ControllerFuture;
+use api::error::*;
+use api::requests::*;
+use api::responses::*;
+use models::*;
+
+pub fn get_system_accounts(ctx: &Context) -> ControllerFuture {
+    let system_accounts_service = ctx.system_accounts_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    let amount_format = ctx.amount_format;
+    Box::new(
+        maybe_token
+            .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+            .into_future()
+            .and_then(move |token| {
+                system_accounts_service
+                    .get_accounts(token)
+                    .map_err(ectx!(convert))
+                    .and_then(move |accounts| {
+                        let resp: Vec<SystemAccountOverviewResponse> =
+                            accounts.into_iter().map(|overview| (overview, amount_format).into()).collect();
+                        response_with_model(&resp)
+                    })
+            }),
+    )
+}
+
+pub fn post_system_accounts(ctx: &Context) -> ControllerFuture {
+    let system_accounts_service = ctx.system_accounts_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    let body = ctx.body.clone();
+    Box::new(
+        maybe_token
+            .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+            .into_future()
+            .and_then(move |token| {
+                parse_body::<PostSystemAccountsRequest>(body).and_then(move |input| {
+                    let input_clone = input.clone();
+                    system_accounts_service
+                        .create_account(token, input.kind, input.currency)
+                        .map_err(ectx!(convert => input_clone))
+                        .and_then(|account| {
+                            let resp: AccountsResponse = account.into();
+                            response_with_model(&resp)
+                        })
+                })
+            }),
+    )
+}
+
+pub fn post_system_accounts_rotate_address(ctx: &Context, account_id: AccountId) -> ControllerFuture {
+    let system_accounts_service = ctx.system_accounts_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    let amount_format = ctx.amount_format;
+    Box::new(
+        maybe_token
+            .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+            .into_future()
+            .and_then(move |token| {
+                system_accounts_service
+                    .rotate_address(token, account_id)
+                    .map_err(ectx!(convert => account_id))
+                    .and_then(move |rotation| {
+                        let resp: SystemAddressRotationResponse = (rotation, amount_format).into();
+                        response_with_model(&resp)
+                    })
+            }),
+    )
+}
@@ -0,0 +1,62 @@
+use failure::Fail;
+use futures::prelude::*;
+
+use super::super::utils::{parse_body, response_with_model};
+use super::Context;
+use super::ControllerFuture;
+use api::error::*;
+use api::requests::*;
+use api::responses::*;
+use models::*;
+
+pub fn get_transaction_limits(ctx: &Context, user_id: UserId) -> ControllerFuture {
+    let transaction_limits_service = ctx.transaction_limits_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    Box::new(
+        maybe_token
+            .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+            .into_future()
+            .and_then(move |token| {
+                transaction_limits_service
+                    .get_limits(token, user_id)
+                    .map_err(ectx!(convert => user_id))
+            })
+            .and_then(|settings| response_with_model(&TransactionLimitsResponse::from(settings))),
+    )
+}
+
+pub fn put_transaction_limits(ctx: &Context, user_id: UserId) -> ControllerFuture {
+    let transaction_limits_service = ctx.transaction_limits_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    let body = ctx.body.clone();
+    Box::new(
+        maybe_token
+            .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+            .into_future()
+            .and_then(move |token| {
+                parse_body::<PutTransactionLimitsRequest>(body).and_then(move |input| {
+                    let input_clone = input.clone();
+                    transaction_limits_service
+                        .set_limits(token, user_id, input.into())
+                        .map_err(ectx!(convert => input_clone))
+                })
+            })
+            .and_then(|settings| response_with_model(&TransactionLimitsResponse::from(settings))),
+    )
+}
+
+pub fn delete_transaction_limits(ctx: &Context, user_id: UserId) -> ControllerFuture {
+    let transaction_limits_service = ctx.transaction_limits_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    Box::new(
+        maybe_token
+            .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+            .into_future()
+            .and_then(move |token| {
+                transaction_limits_service
+                    .reset_limits(token, user_id)
+                    .map_err(ectx!(convert => user_id))
+            })
+            .and_then(|settings| response_with_model(&TransactionLimitsResponse::from(settings))),
+    )
+}
@@ -1,31 +1,118 @@
 use failure::Fail;
+use futures::future::{self, Either};
 use futures::prelude::*;
 
-use super::super::utils::{parse_body, response_with_model};
+use super::super::utils::{parse_body, response_with_json_stream, response_with_model};
 use super::Context;
 use super::ControllerFuture;
 use api::error::*;
 use api::requests::*;
 use api::responses::*;
 use models::*;
+use serde_json;
 use serde_qs;
+use validator::{ValidationError, ValidationErrors};
 
 pub fn post_transactions(ctx: &Context) -> ControllerFuture {
     let transactions_service = ctx.transactions_service.clone();
     let maybe_token = ctx.get_auth_token();
+    let amount_format = ctx.amount_format;
     let body = ctx.body.clone();
     Box::new(
         maybe_token
             .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
             .into_future()
             .and_then(move |token| {
-                parse_body::<PostTransactionsRequest>(body).and_then(move |input| {
+                parse_body::<PostTransactionsRequest>(body)
+                    .and_then(check_value)
+                    .and_then(move |input| {
+                        let input_clone = input.clone();
+                        match input.execution {
+                            WithdrawalExecution::Immediate => Box::new(
+                                transactions_service
+                                    .create_transaction(token, input.into())
+                                    .map_err(ectx!(convert => input_clone))
+                                    .and_then(move |transaction| {
+                                        let resp: TransactionsResponse = (transaction, amount_format).into();
+                                        response_with_model(&resp)
+                                    }),
+                            ) as ControllerFuture,
+                            WithdrawalExecution::Queued => Box::new(
+                                transactions_service
+                                    .queue_withdrawal(token, input.into())
+                                    .map_err(ectx!(convert => input_clone))
+                                    .and_then(move |withdrawal| {
+                                        let resp: QueuedWithdrawalResponse = (withdrawal, amount_format).into();
+                                        response_with_model(&resp)
+                                    }),
+                            ),
+                        }
+                    })
+            }),
+    )
+}
+
+// `value` can be omitted only by sweep, otherwise it would be taken for zero
+fn check_value(input: PostTransactionsRequest) -> Result<PostTransactionsRequest, Error> {
+    if input.value.is_some() || input.sweep {
+        return Ok(input);
+    }
+    let mut errors = ValidationErrors::new();
+    let mut error = ValidationError::new("required");
+    error.message = Some("value is required unless sweep is set".into());
+    errors.add("value", error);
+    Err(ectx!(err ErrorContext::RequestJson, ErrorKind::UnprocessableEntity(serde_json::to_string(&errors).unwrap_or_default()) => input))
+}
+
+pub fn post_payouts(ctx: &Context) -> ControllerFuture {
+    let transactions_service = ctx.transactions_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    let amount_format = ctx.amount_format;
+    let body = ctx.body.clone();
+    Box::new(
+        maybe_token
+            .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+            .into_future()
+            .and_then(move |token| {
+                parse_body::<PostPayoutsRequest>(body).and_then(move |input| {
                     let input_clone = input.clone();
                     transactions_service
-                        .create_transaction(token, input.into())
+                        .create_payout(token, input.into())
                         .map_err(ectx!(convert => input_clone))
-                        .and_then(|transaction| {
-                            let resp: TransactionsResponse = transaction.into();
+                        .and_then(move |payout| {
+                            if payout.is_complete() {
+                                let resp: TransactionsResponse = (payout.transaction, amount_format).into();
+                                return response_with_model(&resp);
+                            }
+                            // the sent payouts can't be taken back, so client needs to know which of them to retry
+                            let resp: PartialPayoutResponse = (payout, amount_format).into();
+                            let details = serde_json::to_string(&resp).unwrap_or_default();
+                            Box::new(future::err(
+                                ectx!(err ErrorContext::PartialPayout, ErrorKind::PartialFailure(details)),
+                            ))
+                        })
+                })
+            }),
+    )
+}
+
+pub fn post_bounces(ctx: &Context) -> ControllerFuture {
+    let transactions_service = ctx.transactions_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    let amount_format = ctx.amount_format;
+    let body = ctx.body.clone();
+    Box::new(
+        maybe_token
+            .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+            .into_future()
+            .and_then(move |token| {
+                parse_body::<PostBouncesRequest>(body).and_then(move |input| {
+                    let input_clone = input.clone();
+                    transactions_service
+                        .bounce_strange_transaction(token, input.hash, input.to)
+                        .map_err(ectx!(convert => input_clone))
+                        .and_then(move |transaction| {
+                            let resp: TransactionsResponse = (transaction, amount_format).into();
                             response_with_model(&resp)
                         })
                 })
@@ -35,7 +122,9 @@ pub fn post_transactions(ctx: &Context) -> ControllerFuture {
 
 pub fn get_users_transactions(ctx: &Context, user_id: UserId) -> ControllerFuture {
     let transactions_service = ctx.transactions_service.clone();
+    let pending_deposits_service = ctx.pending_deposits_service.clone();
     let maybe_token = ctx.get_auth_token();
+    let amount_format = ctx.amount_format;
     let path_and_query = ctx.uri.path_and_query();
     let path_and_query_clone = ctx.uri.path_and_query();
     Box::new(
@@ -55,13 +144,50 @@ pub fn get_users_transactions(ctx: &Context, user_id: UserId) -> ControllerFutur
                     .into_future()
                     .and_then(move |token| {
                         let input_clone = input.clone();
-                        transactions_service
-                            .get_transactions_for_user(token, user_id, input.offset, input.limit)
+                        // unconfirmed deposits are newer than any transaction in the ledger,
+                        // so they head the first page of the default order
+                        let pending_deposits = if input.offset == 0 && input.sort() == TransactionsSort::default() {
+                            Either::A(pending_deposits_service.get_pending_deposits_for_user(token.clone(), user_id))
+                        } else {
+                            Either::B(future::ok(vec![]))
+                        };
+                        pending_deposits
+                            .join(transactions_service.get_transactions_for_user(token, user_id, input.offset, input.limit, input.sort()))
+                            .map(|(mut pending_deposits, transactions)| {
+                                pending_deposits.extend(transactions);
+                                pending_deposits
+                            })
                             .map_err(ectx!(convert => input_clone))
                     })
             })
-            .and_then(|transactions| {
-                let transactions: Vec<TransactionsResponse> = transactions.into_iter().map(From::from).collect();
+            .and_then(move |transactions| {
+                let transactions: Vec<TransactionsResponse> = transactions
+                    .into_iter()
+                    .map(|transaction| (transaction, amount_format).into())
+                    .collect();
+                response_with_model(&transactions)
+            }),
+    )
+}
+
+pub fn get_users_pending_deposits(ctx: &Context, user_id: UserId) -> ControllerFuture {
+    let pending_deposits_service = ctx.pending_deposits_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    let amount_format = ctx.amount_format;
+    Box::new(
+        maybe_token
+            .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+            .into_future()
+            .and_then(move |token| {
+                pending_deposits_service
+                    .get_pending_deposits_for_user(token, user_id)
+                    .map_err(ectx!(convert => user_id))
+            })
+            .and_then(move |transactions| {
+                let transactions: Vec<TransactionsResponse> = transactions
+                    .into_iter()
+                    .map(|transaction| (transaction, amount_format).into())
+                    .collect();
                 response_with_model(&transactions)
             }),
     )
@@ -70,6 +196,7 @@ pub fn get_users_transactions(ctx: &Context, user_id: UserId) -> ControllerFutur
 pub fn get_transactions(ctx: &Context, transaction_id: TransactionId) -> ControllerFuture {
     let transactions_service = ctx.transactions_service.clone();
     let maybe_token = ctx.get_auth_token();
+    let amount_format = ctx.amount_format;
     Box::new(
         maybe_token
             .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
@@ -78,7 +205,105 @@ pub fn get_transactions(ctx: &Context, transaction_id: TransactionId) -> Control
                 transactions_service
                     .get_transaction(token, transaction_id)
                     .map_err(ectx!(convert))
-                    .and_then(|transaction| response_with_model(&transaction.map(TransactionsResponse::from)))
+                    .and_then(move |transaction| {
+                        response_with_model(&transaction.map(|transaction| TransactionsResponse::from((transaction, amount_format))))
+                    })
+            }),
+    )
+}
+
+pub fn get_admin_transactions_fee(ctx: &Context, transaction_id: TransactionId) -> ControllerFuture {
+    let transactions_service = ctx.transactions_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    let amount_format = ctx.amount_format;
+    Box::new(
+        maybe_token
+            .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+            .into_future()
+            .and_then(move |token| {
+                transactions_service
+                    .get_group_fee(token, transaction_id)
+                    .map_err(ectx!(convert))
+                    .and_then(move |group_fee| {
+                        response_with_model(&group_fee.map(|group_fee| GroupFeeResponse::from((group_fee, amount_format))))
+                    })
+            }),
+    )
+}
+
+pub fn get_users_queued_withdrawals(ctx: &Context, user_id: UserId) -> ControllerFuture {
+    let transactions_service = ctx.transactions_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    let amount_format = ctx.amount_format;
+    let path_and_query = ctx.uri.path_and_query();
+    let path_and_query_clone = ctx.uri.path_and_query();
+    Box::new(
+        ctx.uri
+            .query()
+            .ok_or(ectx!(err ErrorContext::RequestMissingQuery, ErrorKind::BadRequest => path_and_query))
+            .and_then(|query| {
+                serde_qs::from_str::<GetUsersQueuedWithdrawalsParams>(query).map_err(|e| {
+                    let e = format_err!("{}", e);
+                    ectx!(err e, ErrorContext::RequestQueryParams, ErrorKind::BadRequest => path_and_query_clone)
+                })
+            })
+            .into_future()
+            .and_then(move |input| {
+                maybe_token
+                    .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+                    .into_future()
+                    .and_then(move |token| {
+                        let input_clone = input.clone();
+                        transactions_service
+                            .get_queued_withdrawals_for_user(token, user_id, input.offset, input.limit)
+                            .map_err(ectx!(convert => input_clone))
+                    })
+            })
+            .and_then(move |withdrawals| {
+                let withdrawals: Vec<QueuedWithdrawalResponse> = withdrawals
+                    .into_iter()
+                    .map(|withdrawal| (withdrawal, amount_format).into())
+                    .collect();
+                response_with_model(&withdrawals)
+            }),
+    )
+}
+
+pub fn get_queued_withdrawals(ctx: &Context, withdrawal_id: TransactionId) -> ControllerFuture {
+    let transactions_service = ctx.transactions_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    let amount_format = ctx.amount_format;
+    Box::new(
+        maybe_token
+            .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+            .into_future()
+            .and_then(move |token| {
+                transactions_service
+                    .get_queued_withdrawal(token, withdrawal_id)
+                    .map_err(ectx!(convert))
+                    .and_then(move |withdrawal| {
+                        response_with_model(&withdrawal.map(|withdrawal| QueuedWithdrawalResponse::from((withdrawal, amount_format))))
+                    })
+            }),
+    )
+}
+
+pub fn delete_queued_withdrawals(ctx: &Context, withdrawal_id: TransactionId) -> ControllerFuture {
+    let transactions_service = ctx.transactions_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    let amount_format = ctx.amount_format;
+    Box::new(
+        maybe_token
+            .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+            .into_future()
+            .and_then(move |token| {
+                transactions_service
+                    .cancel_queued_withdrawal(token, withdrawal_id)
+                    .map_err(ectx!(convert => withdrawal_id))
+                    .and_then(move |withdrawal| {
+                        let resp: QueuedWithdrawalResponse = (withdrawal, amount_format).into();
+                        response_with_model(&resp)
+                    })
             }),
     )
 }
@@ -86,6 +311,7 @@ pub fn get_transactions(ctx: &Context, transaction_id: TransactionId) -> Control
 pub fn get_accounts_transactions(ctx: &Context, account_id: AccountId) -> ControllerFuture {
     let transactions_service = ctx.transactions_service.clone();
     let maybe_token = ctx.get_auth_token();
+    let amount_format = ctx.amount_format;
     let path_and_query = ctx.uri.path_and_query();
     let path_and_query_clone = ctx.uri.path_and_query();
     Box::new(
@@ -105,20 +331,75 @@ pub fn get_accounts_transactions(ctx: &Context, account_id: AccountId) -> Contro
                     .into_future()
                     .and_then(move |token| {
                         transactions_service
-                            .get_account_transactions(token, account_id, input.offset, input.limit)
+                            .get_account_transactions(token, account_id, input.offset, input.limit, input.sort())
                             .map_err(ectx!(convert))
                     })
             })
-            .and_then(|transactions| {
-                let transactions: Vec<TransactionsResponse> = transactions.into_iter().map(From::from).collect();
+            .and_then(move |transactions| {
+                let transactions: Vec<TransactionsResponse> = transactions
+                    .into_iter()
+                    .map(|transaction| (transaction, amount_format).into())
+                    .collect();
                 response_with_model(&transactions)
             }),
     )
 }
 
+pub fn get_accounts_transactions_export(ctx: &Context, account_id: AccountId) -> ControllerFuture {
+    let transactions_service = ctx.transactions_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    let amount_format = ctx.amount_format;
+    Box::new(
+        maybe_token
+            .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+            .into_future()
+            .and_then(move |token| {
+                transactions_service
+                    .stream_account_transactions(token, account_id)
+                    .map_err(ectx!(convert => account_id))
+            })
+            .map(move |transactions| {
+                let transactions = transactions.map_err(ectx!(convert => account_id)).and_then(move |transaction| {
+                    let resp: TransactionsResponse = (transaction, amount_format).into();
+                    serde_json::to_string(&resp).map_err(ectx!(ErrorContext::ResponseJson, ErrorKind::Internal => resp))
+                });
+                response_with_json_stream(transactions)
+            }),
+    )
+}
+
+pub fn get_accounts_stats(ctx: &Context, account_id: AccountId) -> ControllerFuture {
+    let transactions_service = ctx.transactions_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    let amount_format = ctx.amount_format;
+    let path_and_query = ctx.uri.path_and_query();
+    // last 24 hours if window is not specified
+    let query = ctx.uri.query().unwrap_or_default();
+    Box::new(
+        serde_qs::from_str::<GetAccountsStatsParams>(query)
+            .map_err(|e| {
+                let e = format_err!("{}", e);
+                ectx!(err e, ErrorContext::RequestQueryParams, ErrorKind::BadRequest => path_and_query)
+            })
+            .into_future()
+            .and_then(move |input| {
+                maybe_token
+                    .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+                    .into_future()
+                    .and_then(move |token| {
+                        transactions_service
+                            .get_account_stats(token, account_id, input.window)
+                            .map_err(ectx!(convert => account_id, input.window))
+                    })
+            })
+            .and_then(move |stats| response_with_model(&AccountStatsResponse::from((stats, amount_format)))),
+    )
+}
+
 pub fn get_accounts_balances(ctx: &Context, account_id: AccountId) -> ControllerFuture {
     let transactions_service = ctx.transactions_service.clone();
     let maybe_token = ctx.get_auth_token();
+    let amount_format = ctx.amount_format;
     Box::new(
         maybe_token
             .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
@@ -127,7 +408,9 @@ pub fn get_accounts_balances(ctx: &Context, account_id: AccountId) -> Controller
                 transactions_service
                     .get_account_balance(token, account_id)
                     .map_err(ectx!(convert))
-                    .and_then(|account_with_balance| response_with_model(&account_with_balance))
+                    .and_then(move |account_with_balance| {
+                        response_with_model(&BalanceResponse::from((account_with_balance, amount_format)))
+                    })
             }),
     )
 }
@@ -7,6 +7,7 @@ use super::ControllerFuture;
 use api::error::*;
 use api::requests::*;
 use api::responses::*;
+use serde_qs;
 
 pub fn post_users(ctx: &Context) -> ControllerFuture {
     let users_service = ctx.users_service.clone();
@@ -31,3 +32,38 @@ pub fn get_users_me(ctx: &Context) -> ControllerFuture {
             .and_then(|user| response_with_model(&user.map(UsersResponse::from))),
     )
 }
+
+pub fn get_admin_users(ctx: &Context) -> ControllerFuture {
+    let users_service = ctx.users_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    let amount_format = ctx.amount_format;
+    let path_and_query = ctx.uri.path_and_query();
+    let path_and_query_clone = ctx.uri.path_and_query();
+    Box::new(
+        ctx.uri
+            .query()
+            .ok_or(ectx!(err ErrorContext::RequestMissingQuery, ErrorKind::BadRequest => path_and_query))
+            .and_then(|query| {
+                serde_qs::from_str::<GetAdminUsersParams>(query).map_err(|e| {
+                    let e = format_err!("{}", e);
+                    ectx!(err e, ErrorContext::RequestQueryParams, ErrorKind::BadRequest => path_and_query_clone)
+                })
+            })
+            .into_future()
+            .and_then(move |input| {
+                maybe_token
+                    .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+                    .into_future()
+                    .and_then(move |token| {
+                        let input_clone = input.clone();
+                        users_service
+                            .get_users(token, input.query, input.offset, input.limit)
+                            .map_err(ectx!(convert => input_clone))
+                    })
+            })
+            .and_then(move |users| {
+                let users: Vec<AdminUserResponse> = users.into_iter().map(|user| (user, amount_format).into()).collect();
+                response_with_model(&users)
+            }),
+    )
+}
@@ -0,0 +1,88 @@
+use failure::Fail;
+use futures::prelude::*;
+use uuid::Uuid;
+
+use super::super::utils::{parse_body, response_with_model};
+use super::Context;
+use super::ControllerFuture;
+use api::error::*;
+use api::requests::*;
+use api::responses::*;
+use models::*;
+
+pub fn get_users_withdrawal_addresses(ctx: &Context, user_id: UserId) -> ControllerFuture {
+    let withdrawal_addresses_service = ctx.withdrawal_addresses_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    Box::new(
+        maybe_token
+            .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+            .into_future()
+            .and_then(move |token| {
+                withdrawal_addresses_service
+                    .get_withdrawal_addresses(token, user_id)
+                    .map_err(ectx!(convert => user_id))
+            })
+            .and_then(|addresses| {
+                let addresses: Vec<WithdrawalAddressesResponse> = addresses.into_iter().map(From::from).collect();
+                response_with_model(&addresses)
+            }),
+    )
+}
+
+pub fn post_users_withdrawal_addresses(ctx: &Context, user_id: UserId) -> ControllerFuture {
+    let withdrawal_addresses_service = ctx.withdrawal_addresses_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    let body = ctx.body.clone();
+    Box::new(
+        maybe_token
+            .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+            .into_future()
+            .and_then(move |token| {
+                parse_body::<PostWithdrawalAddressesRequest>(body)
+                    .and_then(move |input| {
+                        let input_clone = input.clone();
+                        withdrawal_addresses_service
+                            .create_withdrawal_address(token, (user_id, input).into())
+                            .map_err(ectx!(convert => input_clone))
+                    })
+                    .and_then(|address| response_with_model(&WithdrawalAddressesResponse::from(address)))
+            }),
+    )
+}
+
+pub fn delete_users_withdrawal_addresses(ctx: &Context, user_id: UserId, address_id: Uuid) -> ControllerFuture {
+    let withdrawal_addresses_service = ctx.withdrawal_addresses_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    Box::new(
+        maybe_token
+            .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+            .into_future()
+            .and_then(move |token| {
+                withdrawal_addresses_service
+                    .delete_withdrawal_address(token, user_id, address_id)
+                    .map_err(ectx!(convert => user_id, address_id))
+            })
+            .and_then(|address| response_with_model(&WithdrawalAddressesResponse::from(address))),
+    )
+}
+
+pub fn put_users_withdrawal_whitelist(ctx: &Context, user_id: UserId) -> ControllerFuture {
+    let withdrawal_addresses_service = ctx.withdrawal_addresses_service.clone();
+    let maybe_token = ctx.get_auth_token();
+    let body = ctx.body.clone();
+    Box::new(
+        maybe_token
+            .ok_or_else(|| ectx!(err ErrorContext::Token, ErrorKind::Unauthorized))
+            .into_future()
+            .and_then(move |token| {
+                parse_body::<PutWithdrawalWhitelistRequest>(body)
+                    .and_then(move |input| {
+                        let input_clone = input.clone();
+                        withdrawal_addresses_service
+                            .set_withdrawal_whitelist(token, user_id, input.enabled)
+                            .map_err(ectx!(convert => input_clone))
+                    })
+                    .and_then(|user| response_with_model(&UsersResponse::from(user)))
+            }),
+    )
+}
@@ -1,5 +1,6 @@
 use failure::{Backtrace, Context, Fail};
-use services::ErrorKind as ServiceErrorKind;
+use serde_json::{self, Value};
+use services::{ErrorContext as ServiceErrorContext, ErrorKind as ServiceErrorKind};
 use std::fmt;
 use std::fmt::Display;
 
@@ -21,6 +22,11 @@ pub enum ErrorKind {
     Internal,
     #[fail(display = "controller error - not found")]
     NotFound,
+    #[fail(display = "controller error - conflict")]
+    Conflict,
+    /// Some items of the request are done, details of each of them are in json
+    #[fail(display = "controller error - partial failure")]
+    PartialFailure(String),
 }
 
 #[allow(dead_code)]
@@ -41,12 +47,16 @@ pub enum ErrorContext {
     RequestUTF8,
     #[fail(display = "controller context - error converting json data from request")]
     ResponseJson,
+    #[fail(display = "controller context - error streaming response")]
+    ResponseStream,
     #[fail(display = "controller context - error with authentication token")]
     Token,
     #[fail(display = "controller context - missing query despite required params")]
     RequestMissingQuery,
     #[fail(display = "controller context - failed to extract query params")]
     RequestQueryParams,
+    #[fail(display = "controller context - some of the payouts are not sent")]
+    PartialPayout,
 }
 
 derive_error_impls!();
@@ -59,6 +69,175 @@ impl From<ServiceErrorKind> for ErrorKind {
             ServiceErrorKind::MalformedInput => ErrorKind::BadRequest,
             ServiceErrorKind::NotFound => ErrorKind::NotFound,
             ServiceErrorKind::InvalidInput(s) => ErrorKind::UnprocessableEntity(s),
+            ServiceErrorKind::Conflict => ErrorKind::Conflict,
         }
     }
 }
+
+impl Error {
+    pub fn status(&self) -> u16 {
+        match self.kind() {
+            ErrorKind::BadRequest => 400,
+            ErrorKind::Unauthorized => 401,
+            ErrorKind::NotFound => 404,
+            ErrorKind::Conflict => 409,
+            ErrorKind::UnprocessableEntity(_) => 422,
+            ErrorKind::Internal => 500,
+            ErrorKind::PartialFailure(_) => 500,
+        }
+    }
+
+    /// Stable machine-readable code of the error. The most specific context found
+    /// in the chain of causes wins, internal errors are never detailed to clients.
+    pub fn code(&self) -> &'static str {
+        let kind = self.kind();
+        if let ErrorKind::Internal = kind {
+            return kind.code();
+        }
+        let e: &Fail = self;
+        e.iter_causes().filter_map(context_code).last().unwrap_or_else(|| kind.code())
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self.kind() {
+            ErrorKind::BadRequest => "Bad request",
+            ErrorKind::Unauthorized => "Unauthorized",
+            ErrorKind::NotFound => "Not found",
+            ErrorKind::Conflict => "Conflict",
+            ErrorKind::UnprocessableEntity(_) => "Invalid input",
+            ErrorKind::Internal => "Internal server error",
+            ErrorKind::PartialFailure(_) => "Partially failed",
+        }
+    }
+
+    /// Details of the error, e.g. validation errors by field
+    pub fn params(&self) -> Value {
+        match self.kind() {
+            ErrorKind::UnprocessableEntity(errors) | ErrorKind::PartialFailure(errors) => match serde_json::from_str::<Value>(&errors) {
+                Ok(params @ Value::Object(_)) => params,
+                _ => json!({ "details": errors }),
+            },
+            _ => json!({}),
+        }
+    }
+}
+
+impl ErrorKind {
+    fn code(&self) -> &'static str {
+        match self {
+            ErrorKind::BadRequest => "bad_request",
+            ErrorKind::Unauthorized => "unauthorized",
+            ErrorKind::NotFound => "not_found",
+            ErrorKind::Conflict => "conflict",
+            ErrorKind::UnprocessableEntity(_) => "invalid_input",
+            ErrorKind::Internal => "internal_error",
+            ErrorKind::PartialFailure(_) => "partial_failure",
+        }
+    }
+}
+
+impl ErrorContext {
+    fn code(&self) -> &'static str {
+        match self {
+            ErrorContext::Config => "config",
+            ErrorContext::RequestJson => "invalid_json",
+            ErrorContext::RequestUTF8 => "invalid_utf8",
+            ErrorContext::ResponseJson => "response_json",
+            ErrorContext::ResponseStream => "response_stream",
+            ErrorContext::Token => "invalid_token",
+            ErrorContext::RequestMissingQuery => "missing_query",
+            ErrorContext::RequestQueryParams => "invalid_query_params",
+            ErrorContext::PartialPayout => "partial_payout",
+        }
+    }
+}
+
+// contexts are either attached to an error or are the root error themselves
+fn context_code(cause: &Fail) -> Option<&'static str> {
+    cause
+        .downcast_ref::<Context<ServiceErrorContext>>()
+        .map(|ctx| *ctx.get_context())
+        .or_else(|| cause.downcast_ref::<ServiceErrorContext>().cloned())
+        .map(service_context_code)
+        .or_else(|| {
+            cause
+                .downcast_ref::<Context<ErrorContext>>()
+                .map(|ctx| *ctx.get_context())
+                .or_else(|| cause.downcast_ref::<ErrorContext>().cloned())
+                .map(|ctx| ctx.code())
+        })
+}
+
+fn service_context_code(context: ServiceErrorContext) -> &'static str {
+    match context {
+        ServiceErrorContext::NoAuthToken => "no_auth_token",
+        ServiceErrorContext::InvalidToken => "invalid_token",
+        ServiceErrorContext::DisabledUser => "disabled_user",
+        ServiceErrorContext::NoAccount => "account_not_found",
+        ServiceErrorContext::NoTransaction => "transaction_not_found",
+        ServiceErrorContext::NotEnoughFunds => "not_enough_funds",
+        ServiceErrorContext::InvalidCurrency => "invalid_currency",
+        ServiceErrorContext::MissingExchangeRate => "missing_exchange_rate",
+        ServiceErrorContext::ExchangeRateTimeout => "exchange_rate_timeout",
+        ServiceErrorContext::ExchangeRateUnavailable => "exchange_rate_unavailable",
+        ServiceErrorContext::ExchangeRateOutOfBounds => "exchange_rate_out_of_bounds",
+        ServiceErrorContext::UTF8 => "invalid_utf8",
+        ServiceErrorContext::Json => "invalid_json",
+        ServiceErrorContext::BalanceOverflow => "balance_overflow",
+        ServiceErrorContext::InvalidTransaction => "invalid_transaction",
+        ServiceErrorContext::InvalidUuid => "invalid_uuid",
+        ServiceErrorContext::NotSupported => "not_supported",
+        ServiceErrorContext::InvalidValue => "invalid_value",
+        ServiceErrorContext::InvalidBlockchainTransactionStructure => "invalid_blockchain_transaction_structure",
+        ServiceErrorContext::InvalidTransactionStructure => "invalid_transaction_structure",
+        ServiceErrorContext::Timer => "timer",
+        ServiceErrorContext::LimitExceeded => "limit_exceeded",
+        ServiceErrorContext::MissingAddressInTx => "missing_address_in_tx",
+        ServiceErrorContext::NoWithdrawalAddress => "withdrawal_address_not_found",
+        ServiceErrorContext::AddressNotWhitelisted => "address_not_whitelisted",
+        ServiceErrorContext::InvalidFeePayer => "invalid_fee_payer",
+        ServiceErrorContext::InvalidRateLock => "invalid_rate_lock",
+        ServiceErrorContext::SlippageExceeded => "slippage_exceeded",
+        ServiceErrorContext::ExchangeIdMismatch => "exchange_id_mismatch",
+        ServiceErrorContext::ExchangeNotPerformed => "exchange_not_performed",
+        ServiceErrorContext::LiquidityShortage => "liquidity_shortage",
+        ServiceErrorContext::ExchangePairNotAllowed => "exchange_pair_not_allowed",
+        ServiceErrorContext::LowSystemBalance => "low_system_balance",
+        ServiceErrorContext::FeesTopUpShortage => "fees_top_up_shortage",
+        ServiceErrorContext::SystemAccountExists => "system_account_exists",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_code() {
+        let e: Error = ErrorKind::NotFound.into();
+        assert_eq!((e.status(), e.code()), (404, "not_found"));
+
+        let e: Error = ectx!(err ServiceErrorContext::NoAccount, ErrorKind::NotFound);
+        assert_eq!(e.code(), "account_not_found");
+
+        let service_error: ::services::Error =
+            ectx!(err ServiceErrorContext::NotEnoughFunds, ServiceErrorKind::InvalidInput("{}".to_string()));
+        let e: Error = ectx!(err service_error, ErrorContext::RequestJson, ErrorKind::UnprocessableEntity("{}".to_string()));
+        assert_eq!((e.status(), e.code()), (422, "not_enough_funds"));
+        assert_eq!(e.params(), json!({}));
+
+        let e: Error = ectx!(err ErrorContext::RequestJson, ErrorKind::Internal);
+        assert_eq!(e.code(), "internal_error");
+
+        let e: Error = ErrorKind::UnprocessableEntity("stq".to_string()).into();
+        assert_eq!(e.params(), json!({ "details": "stq" }));
+
+        let e: Error = ectx!(err ErrorContext::PartialPayout, ErrorKind::PartialFailure(r#"{"payouts": []}"#.to_string()));
+        assert_eq!((e.status(), e.code()), (500, "partial_payout"));
+        assert_eq!(e.params(), json!({ "payouts": [] }));
+
+        let kind: ServiceErrorKind = ::repos::ErrorKind::Conflict.into();
+        let e: Error = ErrorKind::from(kind).into();
+        assert_eq!((e.status(), e.code()), (409, "conflict"));
+    }
+}
@@ -1,19 +1,21 @@
 use std::net::SocketAddr;
 use std::sync::Arc;
 
-use diesel::pg::PgConnection;
-use diesel::r2d2::ConnectionManager;
 use failure::{Compat, Fail};
+use futures::future::{self, Either};
 use futures::prelude::*;
 use futures_cpupool::CpuPool;
 use hyper;
+use hyper::body::Payload;
+use hyper::header::HeaderValue;
 use hyper::Server;
 use hyper::{service::Service, Body, Request, Response};
-use r2d2;
 
-use super::config::Config;
+use super::config::{Config, Database, SharedConfig};
+use super::request_id::{self, WithRequestId, REQUEST_ID_HEADER};
 use super::utils::{log_and_capture_error, log_error, log_warn};
 use utils::read_body;
+use uuid::Uuid;
 
 mod controllers;
 mod error;
@@ -23,37 +25,56 @@ pub mod utils;
 
 use self::controllers::*;
 use self::error::*;
+use self::utils::{parse_common_params, response_with_error};
 use client::{
-    BlockchainClient, BlockchainClientImpl, ExchangeClient, ExchangeClientImpl, FeesClient, FeesClientImpl, HttpClientImpl, KeysClient,
+    BlockchainClient, BlockchainClientImpl, ExchangeClient, FailoverExchangeClient, FeesClient, FeesClientImpl, HttpClientImpl, KeysClient,
     KeysClientImpl,
 };
 use models::*;
 use prelude::*;
-use rabbit::TransactionPublisher;
+use rabbit::{RabbitStats, TransactionPublisher};
 use repos::{
-    AccountsRepoImpl, BlockchainTransactionsRepoImpl, DbExecutorImpl, KeyValuesRepoImpl, PendingBlockchainTransactionsRepoImpl,
-    StrangeBlockchainTransactionsRepoImpl, TransactionsRepoImpl, UsersRepoImpl,
+    AccountsRepoImpl, BlockchainTransactionsRepoImpl, DbExecutorImpl, KeyValuesRepoImpl, MonitoredPool,
+    PendingBlockchainTransactionsRepoImpl, PendingDepositsRepoImpl, QueryStats, QueuedWithdrawalsRepoImpl, RateLocksRepoImpl,
+    SmallDepositsRepoImpl, StrangeBlockchainTransactionsRepoImpl, TransactionsRepoImpl, UsersRepoImpl, WithdrawalAddressesRepoImpl,
 };
 use services::{
-    AccountsServiceImpl, AuthServiceImpl, ExchangeServiceImpl, FeesServiceImpl, MetricsServiceImpl, TransactionsServiceImpl,
-    UsersServiceImpl,
+    AccountsServiceImpl, AuthServiceImpl, ConfirmationsServiceImpl, ExchangePairsServiceImpl, ExchangeServiceImpl, FeesCache,
+    FeesServiceImpl, MetricsServiceImpl, PendingDepositsServiceImpl, RateLocksServiceImpl, RatesCache, SmallDepositsServiceImpl,
+    SystemAccountsServiceImpl, TransactionLimitsServiceImpl, TransactionsServiceImpl, UsersServiceImpl, WalletServiceImpl,
+    WithdrawalAddressesServiceImpl,
 };
 
+const REPLICA_CONNECTION_TIMEOUT_SECS: u64 = 1;
+
 #[derive(Clone)]
 pub struct ApiService {
     server_address: SocketAddr,
-    config: Config,
+    config: SharedConfig,
     db_pool: PgPool,
     cpu_pool: CpuPool,
+    replica: Option<(PgPool, CpuPool)>,
+    db_pools: Vec<MonitoredPool>,
+    query_stats: Arc<QueryStats>,
+    rabbit_stats: Arc<RabbitStats>,
     keys_client: Arc<dyn KeysClient>,
     blockchain_client: Arc<dyn BlockchainClient>,
     exchange_client: Arc<dyn ExchangeClient>,
     fees_client: Arc<dyn FeesClient>,
+    fees_cache: FeesCache,
+    rates_cache: RatesCache,
     publisher: Arc<dyn TransactionPublisher>,
 }
 
 impl ApiService {
-    fn from_config(config: &Config, publisher: Arc<dyn TransactionPublisher>) -> Result<Self, Error> {
+    fn from_config(
+        shared_config: SharedConfig,
+        publisher: Arc<dyn TransactionPublisher>,
+        query_stats: Arc<QueryStats>,
+        rabbit_stats: Arc<RabbitStats>,
+    ) -> Result<Self, Error> {
+        // static parts of config are taken only once, dynamic ones - on every request
+        let config: &Config = &shared_config.get();
         let server_address = format!("{}:{}", config.server.host, config.server.port)
             .parse::<SocketAddr>()
             .map_err(ectx!(try
@@ -63,28 +84,54 @@ impl ApiService {
                 config.server.port
             ))?;
         let database_url = config.database.url.clone();
-        let manager = ConnectionManager::<PgConnection>::new(database_url.clone());
-        let db_pool = r2d2::Pool::builder().build(manager).map_err(ectx!(try
+        let db_pool = MonitoredPool::create("primary", &database_url, &config.database).map_err(ectx!(try
             ErrorContext::Config,
             ErrorKind::Internal =>
             database_url
         ))?;
+        let mut db_pools = vec![db_pool.clone()];
+        let db_pool = db_pool.pool;
         let cpu_pool = CpuPool::new(config.cpu_pool.size);
+        let replica = match config.database.replica_url {
+            Some(ref replica_url) => {
+                // replica shouldn't prevent server from starting, and unavailable replica
+                // shouldn't slow down requests much, since they fall back to primary
+                let replica_options = Database {
+                    connection_timeout_secs: config.database.connection_timeout_secs.or(Some(REPLICA_CONNECTION_TIMEOUT_SECS)),
+                    ..config.database.clone()
+                };
+                let replica_db_pool = MonitoredPool::create_unchecked("replica", replica_url, &replica_options);
+                db_pools.push(replica_db_pool.clone());
+                Some((replica_db_pool.pool, CpuPool::new(config.cpu_pool.size)))
+            }
+            None => None,
+        };
         let client = HttpClientImpl::new(config);
         let keys_client = KeysClientImpl::new(&config, client.clone());
         let blockchain_client = BlockchainClientImpl::new(&config, client.clone());
-        let exchange_client = ExchangeClientImpl::new(&config, client.clone());
+        let exchange_client = FailoverExchangeClient::new(
+            &config,
+            client.clone(),
+            DbExecutorImpl::new(db_pool.clone(), cpu_pool.clone()).with_query_stats(query_stats.clone()),
+            Arc::new(KeyValuesRepoImpl),
+        );
         let fees_client = FeesClientImpl::new(&config, client);
 
         Ok(ApiService {
-            config: config.clone(),
+            config: shared_config.clone(),
             server_address,
             db_pool,
             cpu_pool,
+            replica,
+            db_pools,
+            query_stats,
+            rabbit_stats,
             keys_client: Arc::new(keys_client),
             blockchain_client: Arc::new(blockchain_client),
             exchange_client: Arc::new(exchange_client),
             fees_client: Arc::new(fees_client),
+            fees_cache: FeesCache::new(config),
+            rates_cache: RatesCache::new(config),
             publisher,
         })
     }
@@ -98,6 +145,12 @@ impl Service for ApiService {
 
     fn call(&mut self, req: Request<Body>) -> Self::Future {
         let (parts, http_body) = req.into_parts();
+        let request_id = parts
+            .headers
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(request_id::parse)
+            .unwrap_or_else(request_id::generate);
         let db_pool = self.db_pool.clone();
         let cpu_pool = self.cpu_pool.clone();
         let keys_client = self.keys_client.clone();
@@ -105,108 +158,242 @@ impl Service for ApiService {
         let exchange_client = self.exchange_client.clone();
         let publisher = self.publisher.clone();
         let fees_client = self.fees_client.clone();
-        let db_executor = DbExecutorImpl::new(db_pool.clone(), cpu_pool.clone());
-        let config = self.config.clone();
-        Box::new(
-            read_body(http_body)
-                .map_err(ectx!(ErrorSource::Hyper, ErrorKind::Internal))
-                .and_then(move |body| {
-                    let router = router! {
-                        POST /v1/users => post_users,
-                        GET /v1/users/me => get_users_me,
-                        GET /v1/users/{user_id: UserId}/accounts => get_users_accounts,
-                        POST /v1/accounts => post_accounts,
-                        GET /v1/accounts/{account_id: AccountId} => get_accounts,
-                        PUT /v1/accounts/{account_id: AccountId} => put_accounts,
-                        DELETE /v1/accounts/{account_id: AccountId} => delete_accounts,
-                        GET /v1/accounts/{account_id: AccountId}/balances => get_accounts_balances,
-                        GET /v1/accounts/{account_id: AccountId}/transactions => get_accounts_transactions,
-                        GET /v1/users/{user_id: UserId}/transactions => get_users_transactions,
-                        POST /v1/transactions => post_transactions,
-                        GET /v1/transactions/{transaction_id: TransactionId} => get_transactions,
-                        POST /v1/rate => post_rate,
-                        POST /v1/rate/refresh => post_rate_refresh,
-                        POST /v1/fees => post_fees,
-                        GET /v1/metrics => get_metrics,
-                        _ => not_found,
-                    };
+        let fees_cache = self.fees_cache.clone();
+        let rates_cache = self.rates_cache.clone();
+        let db_pools = self.db_pools.clone();
+        let query_stats = self.query_stats.clone();
+        let rabbit_stats = self.rabbit_stats.clone();
+        let db_executor = DbExecutorImpl::new(db_pool.clone(), cpu_pool.clone()).with_query_stats(query_stats.clone());
+        let db_executor = match self.replica {
+            Some((ref replica_db_pool, ref replica_cpu_pool)) => {
+                db_executor.with_replica(replica_db_pool.clone(), replica_cpu_pool.clone())
+            }
+            None => db_executor,
+        };
+        let config = (*self.config.get()).clone();
+        let fut = read_body(http_body)
+            .map_err(ectx!(ErrorSource::Hyper, ErrorKind::Internal))
+            .and_then(move |body| {
+                let common_params = match parse_common_params(parts.uri.query()) {
+                    Ok(common_params) => common_params,
+                    Err(e) => return Box::new(future::err(e)) as ControllerFuture,
+                };
+                let router = router! {
+                    POST /v1/users => post_users,
+                    GET /v1/users/me => get_users_me,
+                    GET /v1/users/{user_id: UserId}/accounts => get_users_accounts,
+                    GET /v1/users/{user_id: UserId}/wallet => get_users_wallet,
+                    GET /v1/users/{user_id: UserId}/withdrawal_addresses => get_users_withdrawal_addresses,
+                    POST /v1/users/{user_id: UserId}/withdrawal_addresses => post_users_withdrawal_addresses,
+                    DELETE /v1/users/{user_id: UserId}/withdrawal_addresses/{address_id: Uuid} => delete_users_withdrawal_addresses,
+                    PUT /v1/users/{user_id: UserId}/withdrawal_whitelist => put_users_withdrawal_whitelist,
+                    POST /v1/accounts => post_accounts,
+                    GET /v1/accounts/{account_id: AccountId} => get_accounts,
+                    PUT /v1/accounts/{account_id: AccountId} => put_accounts,
+                    DELETE /v1/accounts/{account_id: AccountId} => delete_accounts,
+                    GET /v1/accounts/{account_id: AccountId}/balances => get_accounts_balances,
+                    GET /v1/accounts/{account_id: AccountId}/stats => get_accounts_stats,
+                    GET /v1/accounts/{account_id: AccountId}/addresses => get_accounts_addresses,
+                    POST /v1/accounts/{account_id: AccountId}/addresses/rotate => post_accounts_addresses_rotate,
+                    GET /v1/accounts/{account_id: AccountId}/payment_uri => get_accounts_payment_uri,
+                    GET /v1/accounts/{account_id: AccountId}/transactions => get_accounts_transactions,
+                    GET /v1/accounts/{account_id: AccountId}/transactions/export => get_accounts_transactions_export,
+                    GET /v1/users/{user_id: UserId}/transactions => get_users_transactions,
+                    GET /v1/users/{user_id: UserId}/pending_deposits => get_users_pending_deposits,
+                    POST /v1/transactions => post_transactions,
+                    POST /v1/payouts => post_payouts,
+                    GET /v1/transactions/{transaction_id: TransactionId} => get_transactions,
+                    GET /v1/users/{user_id: UserId}/queued_withdrawals => get_users_queued_withdrawals,
+                    GET /v1/queued_withdrawals/{withdrawal_id: TransactionId} => get_queued_withdrawals,
+                    DELETE /v1/queued_withdrawals/{withdrawal_id: TransactionId} => delete_queued_withdrawals,
+                    POST /v1/rate => post_rate,
+                    POST /v1/rate/refresh => post_rate_refresh,
+                    POST /v1/rate/lock => post_rate_lock,
+                    POST /v1/exchange/quote => post_exchange_quote,
+                    POST /v1/fees => post_fees,
+                    GET /v1/metrics => get_metrics,
+                    GET /healthz => get_healthz,
+                    GET /v1/admin/confirmation_thresholds => get_confirmation_thresholds,
+                    PUT /v1/admin/confirmation_thresholds => put_confirmation_thresholds,
+                    DELETE /v1/admin/confirmation_thresholds => delete_confirmation_thresholds,
+                    GET /v1/admin/exchange_pairs => get_exchange_pairs,
+                    PUT /v1/admin/exchange_pairs => put_exchange_pairs,
+                    DELETE /v1/admin/exchange_pairs => delete_exchange_pairs,
+                    GET /v1/admin/users => get_admin_users,
+                    GET /v1/admin/users/{user_id: UserId}/transaction_limits => get_transaction_limits,
+                    PUT /v1/admin/users/{user_id: UserId}/transaction_limits => put_transaction_limits,
+                    DELETE /v1/admin/users/{user_id: UserId}/transaction_limits => delete_transaction_limits,
+                    GET /v1/admin/small_deposits => get_small_deposits,
+                    POST /v1/admin/bounces => post_bounces,
+                    GET /v1/admin/transactions/{transaction_id: TransactionId}/fee => get_admin_transactions_fee,
+                    GET /v1/admin/system_accounts => get_system_accounts,
+                    POST /v1/admin/system_accounts => post_system_accounts,
+                    POST /v1/admin/system_accounts/{account_id: AccountId}/rotate_address => post_system_accounts_rotate_address,
+                    _ => not_found,
+                };
 
-                    let auth_service = Arc::new(AuthServiceImpl::new(
-                        Arc::new(UsersRepoImpl::new(config.system.system_user_id)),
-                        db_executor.clone(),
-                    ));
-                    let users_service = Arc::new(UsersServiceImpl::new(
-                        Arc::new(UsersRepoImpl::new(config.system.system_user_id)),
-                        db_executor.clone(),
-                    ));
+                let auth_service = Arc::new(AuthServiceImpl::new(
+                    Arc::new(UsersRepoImpl::new(config.system.system_user_id)),
+                    db_executor.clone(),
+                ));
+                let users_service = Arc::new(UsersServiceImpl::new(
+                    auth_service.clone(),
+                    Arc::new(UsersRepoImpl::new(config.system.system_user_id)),
+                    config.system.system_user_id,
+                    db_executor.clone(),
+                    publisher.clone(),
+                ));
 
-                    let accounts_service = Arc::new(AccountsServiceImpl::new(
-                        auth_service.clone(),
-                        Arc::new(AccountsRepoImpl),
-                        db_executor.clone(),
-                        keys_client.clone(),
-                    ));
-                    let fees_service = Arc::new(FeesServiceImpl::new(
-                        &config,
-                        Arc::new(AccountsRepoImpl),
-                        db_executor.clone(),
-                        exchange_client.clone(),
-                        fees_client,
-                    ));
-                    let fees_accounts_ids = vec![
-                        config.system.btc_fees_account_id,
-                        config.system.eth_fees_account_id,
-                        config.system.stq_fees_account_id,
-                    ];
-                    let transactions_service = Arc::new(TransactionsServiceImpl::new(
-                        config.clone(),
-                        auth_service.clone(),
-                        Arc::new(TransactionsRepoImpl::new(config.system.system_user_id, fees_accounts_ids.clone())),
-                        Arc::new(PendingBlockchainTransactionsRepoImpl),
-                        Arc::new(BlockchainTransactionsRepoImpl),
-                        Arc::new(AccountsRepoImpl),
-                        Arc::new(KeyValuesRepoImpl),
-                        db_executor.clone(),
-                        keys_client,
-                        blockchain_client.clone(),
-                        exchange_client.clone(),
-                        publisher.clone(),
-                    ));
-                    let exchange_service = Arc::new(ExchangeServiceImpl::new(exchange_client));
-                    let metrics_service = Arc::new(MetricsServiceImpl::new(
-                        Arc::new(config.clone()),
-                        Arc::new(AccountsRepoImpl),
-                        Arc::new(TransactionsRepoImpl::new(config.system.system_user_id, fees_accounts_ids)),
-                        Arc::new(PendingBlockchainTransactionsRepoImpl),
-                        Arc::new(StrangeBlockchainTransactionsRepoImpl),
-                        db_executor.clone(),
-                        blockchain_client.clone(),
-                    ));
+                let accounts_service = Arc::new(AccountsServiceImpl::new(
+                    &config,
+                    auth_service.clone(),
+                    Arc::new(AccountsRepoImpl),
+                    db_executor.clone(),
+                    keys_client.clone(),
+                ));
+                let fees_service = Arc::new(FeesServiceImpl::new(
+                    &config,
+                    Arc::new(AccountsRepoImpl),
+                    db_executor.clone(),
+                    exchange_client.clone(),
+                    fees_client,
+                    fees_cache,
+                ));
+                let transactions_service = Arc::new(TransactionsServiceImpl::new(
+                    config.clone(),
+                    auth_service.clone(),
+                    Arc::new(TransactionsRepoImpl::new(config.system.system_user_id)),
+                    Arc::new(PendingBlockchainTransactionsRepoImpl),
+                    Arc::new(BlockchainTransactionsRepoImpl),
+                    Arc::new(StrangeBlockchainTransactionsRepoImpl),
+                    Arc::new(QueuedWithdrawalsRepoImpl),
+                    Arc::new(RateLocksRepoImpl),
+                    Arc::new(UsersRepoImpl::new(config.system.system_user_id)),
+                    Arc::new(WithdrawalAddressesRepoImpl),
+                    Arc::new(AccountsRepoImpl),
+                    Arc::new(KeyValuesRepoImpl),
+                    db_executor.clone(),
+                    keys_client.clone(),
+                    blockchain_client.clone(),
+                    exchange_client.clone(),
+                    publisher.clone(),
+                ));
+                let system_accounts_service = Arc::new(SystemAccountsServiceImpl::new(
+                    Arc::new(config.clone()),
+                    auth_service.clone(),
+                    Arc::new(AccountsRepoImpl),
+                    Arc::new(TransactionsRepoImpl::new(config.system.system_user_id)),
+                    Arc::new(PendingBlockchainTransactionsRepoImpl),
+                    Arc::new(KeyValuesRepoImpl),
+                    db_executor.clone(),
+                    keys_client,
+                    blockchain_client.clone(),
+                    exchange_client.clone(),
+                ));
+                let exchange_service = Arc::new(ExchangeServiceImpl::new(&config, exchange_client, rates_cache.clone()));
+                let rate_locks_service = Arc::new(RateLocksServiceImpl::new(
+                    &config,
+                    auth_service.clone(),
+                    exchange_service.clone(),
+                    Arc::new(RateLocksRepoImpl),
+                    db_executor.clone(),
+                ));
+                let wallet_service = Arc::new(WalletServiceImpl::new(
+                    auth_service.clone(),
+                    Arc::new(AccountsRepoImpl),
+                    Arc::new(TransactionsRepoImpl::new(config.system.system_user_id)),
+                    rates_cache,
+                    db_executor.clone(),
+                ));
+                let metrics_service = Arc::new(MetricsServiceImpl::new(
+                    Arc::new(config.clone()),
+                    Arc::new(AccountsRepoImpl),
+                    Arc::new(TransactionsRepoImpl::new(config.system.system_user_id)),
+                    Arc::new(PendingBlockchainTransactionsRepoImpl),
+                    Arc::new(StrangeBlockchainTransactionsRepoImpl),
+                    Arc::new(KeyValuesRepoImpl),
+                    db_executor.clone(),
+                    blockchain_client.clone(),
+                    db_pools,
+                    query_stats,
+                    rabbit_stats,
+                ));
+                let confirmations_service = Arc::new(ConfirmationsServiceImpl::new(
+                    auth_service.clone(),
+                    Arc::new(KeyValuesRepoImpl),
+                    config.confirmations.clone(),
+                    config.system.system_user_id,
+                    db_executor.clone(),
+                ));
+                let exchange_pairs_service = Arc::new(ExchangePairsServiceImpl::new(
+                    auth_service.clone(),
+                    Arc::new(KeyValuesRepoImpl),
+                    config.exchange_options.exchange_pairs(),
+                    config.system.system_user_id,
+                    db_executor.clone(),
+                ));
+                let small_deposits_service = Arc::new(SmallDepositsServiceImpl::new(
+                    auth_service.clone(),
+                    Arc::new(SmallDepositsRepoImpl),
+                    config.system.system_user_id,
+                    db_executor.clone(),
+                ));
+                let pending_deposits_service = Arc::new(PendingDepositsServiceImpl::new(
+                    auth_service.clone(),
+                    Arc::new(PendingDepositsRepoImpl),
+                    config.pending_deposits_retention.clone(),
+                    db_executor.clone(),
+                ));
+                let transaction_limits_service = Arc::new(TransactionLimitsServiceImpl::new(
+                    auth_service.clone(),
+                    Arc::new(KeyValuesRepoImpl),
+                    config.limits.transaction_limits(),
+                    config.system.system_user_id,
+                    db_executor.clone(),
+                ));
+                let withdrawal_addresses_service = Arc::new(WithdrawalAddressesServiceImpl::new(
+                    &config,
+                    auth_service.clone(),
+                    Arc::new(UsersRepoImpl::new(config.system.system_user_id)),
+                    Arc::new(WithdrawalAddressesRepoImpl),
+                    db_executor.clone(),
+                ));
 
-                    let ctx = Context {
-                        body,
-                        method: parts.method.clone(),
-                        uri: parts.uri.clone(),
-                        headers: parts.headers,
-                        users_service,
-                        accounts_service,
-                        transactions_service,
-                        exchange_service,
-                        metrics_service,
-                        fees_service,
-                    };
+                let ctx = Context {
+                    body,
+                    method: parts.method.clone(),
+                    uri: parts.uri.clone(),
+                    headers: parts.headers,
+                    amount_format: common_params.amount_format,
+                    users_service,
+                    accounts_service,
+                    transactions_service,
+                    exchange_service,
+                    rate_locks_service,
+                    metrics_service,
+                    fees_service,
+                    confirmations_service,
+                    exchange_pairs_service,
+                    wallet_service,
+                    small_deposits_service,
+                    pending_deposits_service,
+                    withdrawal_addresses_service,
+                    transaction_limits_service,
+                    system_accounts_service,
+                };
 
-                    debug!("Received request {}", ctx);
+                debug!("Received request {}", ctx);
 
-                    router(ctx, parts.method.into(), parts.uri.path())
-                })
-                .and_then(|resp| {
-                    let (parts, body) = resp.into_parts();
-                    read_body(body)
-                        .map_err(ectx!(ErrorSource::Hyper, ErrorKind::Internal))
-                        .map(|body| (parts, body))
-                })
-                .map(|(parts, body)| {
+                router(ctx, parts.method.into(), parts.uri.path())
+            })
+            .and_then(|resp| {
+                // streamed responses have unknown length and must not be buffered
+                if resp.body().content_length().is_none() {
+                    debug!("Sent streamed response with status {}", resp.status().as_u16());
+                    return Either::A(future::ok(resp));
+                }
+                let (parts, body) = resp.into_parts();
+                Either::B(read_body(body).map_err(ectx!(ErrorSource::Hyper, ErrorKind::Internal)).map(|body| {
                     debug!(
                         "Sent response with status {}, headers: {:#?}, body: {:?}",
                         parts.status.as_u16(),
@@ -214,55 +401,34 @@ impl Service for ApiService {
                         String::from_utf8(body.clone()).ok()
                     );
                     Response::from_parts(parts, body.into())
-                })
-                .or_else(|e| match e.kind() {
-                    ErrorKind::BadRequest => {
-                        log_error(&e);
-                        Ok(Response::builder()
-                            .status(400)
-                            .header("Content-Type", "application/json")
-                            .body(Body::from(r#"{"description": "Bad request"}"#))
-                            .unwrap())
-                    }
-                    ErrorKind::Unauthorized => {
-                        log_warn(&e);
-                        Ok(Response::builder()
-                            .status(401)
-                            .header("Content-Type", "application/json")
-                            .body(Body::from(r#"{"description": "Unauthorized"}"#))
-                            .unwrap())
-                    }
-                    ErrorKind::NotFound => {
-                        log_warn(&e);
-                        Ok(Response::builder()
-                            .status(404)
-                            .header("Content-Type", "application/json")
-                            .body(Body::from(r#"{"description": "Not found"}"#))
-                            .unwrap())
-                    }
-                    ErrorKind::UnprocessableEntity(errors) => {
-                        log_warn(&e);
-                        Ok(Response::builder()
-                            .status(422)
-                            .header("Content-Type", "application/json")
-                            .body(Body::from(errors))
-                            .unwrap())
-                    }
-                    ErrorKind::Internal => {
-                        log_and_capture_error(e);
-                        Ok(Response::builder()
-                            .status(500)
-                            .header("Content-Type", "application/json")
-                            .body(Body::from(r#"{"description": "Internal server error"}"#))
-                            .unwrap())
-                    }
-                }),
-        )
+                }))
+            })
+            .or_else(|e| {
+                let resp = response_with_error(&e);
+                match e.kind() {
+                    ErrorKind::BadRequest => log_error(&e),
+                    ErrorKind::Internal => log_and_capture_error(e),
+                    _ => log_warn(&e),
+                }
+                Ok(resp)
+            });
+        // the whole request is handled with its id in logs and in headers of downstream requests
+        Box::new(WithRequestId::new(fut, Some(request_id.clone())).map(move |mut resp| {
+            if let Ok(value) = HeaderValue::from_str(&request_id) {
+                resp.headers_mut().insert(REQUEST_ID_HEADER, value);
+            }
+            resp
+        }))
     }
 }
 
-pub fn server(config: Config, publisher: Arc<dyn TransactionPublisher>) -> Box<Future<Item = (), Error = ()> + Send> {
-    let fut = ApiService::from_config(&config, publisher)
+pub fn server(
+    config: SharedConfig,
+    publisher: Arc<dyn TransactionPublisher>,
+    query_stats: Arc<QueryStats>,
+    rabbit_stats: Arc<RabbitStats>,
+) -> Box<Future<Item = (), Error = ()> + Send> {
+    let fut = ApiService::from_config(config, publisher, query_stats, rabbit_stats)
         .into_future()
         .and_then(move |api| {
             let api_clone = api.clone();
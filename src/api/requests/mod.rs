@@ -1,3 +1,10 @@
+use std::collections::HashMap;
+use std::num::ParseIntError;
+
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+use uuid::Uuid;
+
 use models::*;
 
 #[derive(Debug, Deserialize, Clone)]
@@ -14,6 +21,7 @@ impl From<PostUsersRequest> for NewUser {
             id: req.id,
             name: req.name,
             authentication_token: req.authentication_token,
+            disabled: None,
         }
     }
 }
@@ -42,6 +50,8 @@ pub struct PostAccountsRequest {
     pub currency: Currency,
     pub name: String,
     pub daily_limit_type: Option<DailyLimitType>,
+    pub meta: Option<Value>,
+    pub labels: Option<Vec<String>>,
 }
 
 impl From<PostAccountsRequest> for CreateAccount {
@@ -52,6 +62,8 @@ impl From<PostAccountsRequest> for CreateAccount {
             currency: req.currency,
             user_id: req.user_id,
             daily_limit_type: req.daily_limit_type,
+            meta: req.meta,
+            labels: req.labels,
         }
     }
 }
@@ -60,6 +72,11 @@ impl From<PostAccountsRequest> for CreateAccount {
 #[serde(rename_all = "camelCase")]
 pub struct PutAccountsRequest {
     pub name: Option<String>,
+    pub meta: Option<Value>,
+    pub labels: Option<Vec<String>>,
+    /// Missing field keeps the current value, `null` turns auto conversion off
+    #[serde(default, deserialize_with = "present")]
+    pub auto_convert_to: Option<Option<AccountId>>,
 }
 
 impl From<PutAccountsRequest> for UpdateAccount {
@@ -67,15 +84,127 @@ impl From<PutAccountsRequest> for UpdateAccount {
         Self {
             name: req.name,
             erc20_approved: None,
+            meta: req.meta,
+            labels: req.labels,
+            auto_convert_to: req.auto_convert_to,
         }
     }
 }
 
+// serde maps both missing field and `null` to `None`, this way `null` is `Some(None)`
+fn present<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
 #[derive(Debug, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct GetUsersAccountsParams {
     pub limit: i64,
     pub offset: i64,
+    /// Comma separated labels, accounts must have all of them
+    pub labels: Option<String>,
+    /// Values of meta fields, e.g. `meta[orderId]=42`
+    #[serde(default)]
+    pub meta: HashMap<String, String>,
+}
+
+impl GetUsersAccountsParams {
+    pub fn filter(&self) -> AccountsFilter {
+        let labels = self
+            .labels
+            .iter()
+            .flat_map(|labels| labels.split(','))
+            .filter(|label| !label.is_empty())
+            .map(|label| label.to_string())
+            .collect();
+        let meta = if self.meta.is_empty() {
+            None
+        } else {
+            let fields = self
+                .meta
+                .iter()
+                .map(|(key, value)| (key.clone(), Value::String(value.clone())))
+                .collect();
+            Some(Value::Object(fields))
+        };
+        AccountsFilter { labels, meta }
+    }
+}
+
+/// How amounts are represented in responses
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AmountFormat {
+    /// Integer in the smallest units, i.e. satoshis or wei
+    Raw,
+    /// Decimal string in super units, i.e. btc or eth
+    SuperUnits,
+}
+
+impl Default for AmountFormat {
+    fn default() -> Self {
+        AmountFormat::Raw
+    }
+}
+
+/// Query params accepted by all endpoints
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct CommonParams {
+    #[serde(default)]
+    pub amount_format: AmountFormat,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct GetUsersWalletParams {
+    /// Currency of the approximate total
+    #[serde(default)]
+    pub reference_currency: Currency,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct GetAccountsPaymentUriParams {
+    /// Integer in the smallest units, kept as string, since query params can't hold u128
+    pub amount: Option<String>,
+}
+
+impl GetAccountsPaymentUriParams {
+    pub fn amount(&self) -> Result<Option<Amount>, ParseIntError> {
+        match self.amount {
+            Some(ref amount) => amount.parse::<u128>().map(|amount| Some(Amount::new(amount))),
+            None => Ok(None),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct GetAccountsStatsParams {
+    #[serde(default)]
+    pub window: StatsWindow,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct GetAdminUsersParams {
+    pub limit: i64,
+    pub offset: i64,
+    /// Part of name or the whole id
+    pub query: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct GetSmallDepositsParams {
+    pub limit: i64,
+    pub offset: i64,
+    pub currency: Option<Currency>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -87,11 +216,24 @@ pub struct PostTransactionsRequest {
     pub to: Recepient,
     pub to_type: RecepientType,
     pub to_currency: Currency,
-    pub value: Amount,
+    /// Required unless `sweep` is set, then it's ignored
+    pub value: Option<Amount>,
     pub value_currency: Currency,
     pub fee: Amount,
     pub exchange_id: Option<ExchangeId>,
     pub exchange_rate: Option<f64>,
+    /// Withdrawals can be queued to be sent with others on the next drain of the queue
+    #[serde(default)]
+    pub execution: WithdrawalExecution,
+    #[serde(default)]
+    pub fee_payer: FeePayer,
+    pub fee_payer_account_id: Option<AccountId>,
+    #[serde(default)]
+    pub sweep: bool,
+    /// Lock from `POST /rate/lock`, used instead of `exchange_id` and `exchange_rate`
+    pub rate_lock_id: Option<Uuid>,
+    /// Exchange fails if the current rate deviates from `exchange_rate` by more than this fraction
+    pub max_slippage: Option<f64>,
 }
 
 impl From<PostTransactionsRequest> for CreateTransactionInput {
@@ -108,6 +250,12 @@ impl From<PostTransactionsRequest> for CreateTransactionInput {
             fee,
             exchange_id,
             exchange_rate,
+            execution: _,
+            fee_payer,
+            fee_payer_account_id,
+            sweep,
+            rate_lock_id,
+            max_slippage,
         } = req;
 
         Self {
@@ -117,11 +265,53 @@ impl From<PostTransactionsRequest> for CreateTransactionInput {
             to,
             to_type,
             to_currency,
-            value,
+            // presence is checked by controller, sweep computes value itself
+            value: value.unwrap_or_default(),
             value_currency,
             fee,
             exchange_id,
             exchange_rate,
+            fee_payer,
+            fee_payer_account_id,
+            sweep,
+            rate_lock_id,
+            max_slippage,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PayoutRequest {
+    pub address: BlockchainAddress,
+    pub value: Amount,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PostPayoutsRequest {
+    pub id: TransactionId,
+    pub user_id: UserId,
+    pub from: AccountId,
+    pub currency: Currency,
+    /// Fee for each of the payouts
+    pub fee: Amount,
+    pub payouts: Vec<PayoutRequest>,
+}
+
+impl From<PostPayoutsRequest> for CreatePayoutInput {
+    fn from(req: PostPayoutsRequest) -> Self {
+        Self {
+            id: req.id,
+            user_id: req.user_id,
+            from: req.from,
+            currency: req.currency,
+            fee: req.fee,
+            payouts: req
+                .payouts
+                .into_iter()
+                .map(|PayoutRequest { address, value }| PayoutDestination { address, value })
+                .collect(),
         }
     }
 }
@@ -138,11 +328,70 @@ impl From<PutTransactionsRequest> for TransactionStatus {
     }
 }
 
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PostBouncesRequest {
+    pub hash: BlockchainTransactionId,
+    pub to: Option<BlockchainAddress>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PostSystemAccountsRequest {
+    pub kind: SystemAccountKind,
+    pub currency: Currency,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct GetUsersTransactionsParams {
     pub limit: i64,
     pub offset: i64,
+    #[serde(default)]
+    pub sort: TransactionsSortField,
+    #[serde(default)]
+    pub direction: SortDirection,
+}
+
+impl GetUsersTransactionsParams {
+    pub fn sort(&self) -> TransactionsSort {
+        TransactionsSort {
+            field: self.sort,
+            direction: self.direction,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct GetUsersQueuedWithdrawalsParams {
+    pub limit: i64,
+    pub offset: i64,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PostWithdrawalAddressesRequest {
+    pub currency: Currency,
+    pub address: BlockchainAddress,
+    pub label: Option<String>,
+}
+
+impl From<(UserId, PostWithdrawalAddressesRequest)> for CreateWithdrawalAddress {
+    fn from((user_id, req): (UserId, PostWithdrawalAddressesRequest)) -> Self {
+        Self {
+            user_id,
+            currency: req.currency,
+            address: req.address,
+            label: req.label,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PutWithdrawalWhitelistRequest {
+    pub enabled: bool,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -160,3 +409,55 @@ impl From<PostFeesRequest> for GetFees {
         }
     }
 }
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PutConfirmationThresholdsRequest {
+    pub usd_per_btc: f64,
+    pub usd_per_eth: f64,
+    pub usd_per_stq: f64,
+    pub btc_thresholds: Vec<u64>,
+    pub eth_thresholds: Vec<u64>,
+}
+
+impl From<PutConfirmationThresholdsRequest> for ConfirmationThresholds {
+    fn from(req: PutConfirmationThresholdsRequest) -> Self {
+        Self {
+            usd_per_btc: req.usd_per_btc,
+            usd_per_eth: req.usd_per_eth,
+            usd_per_stq: req.usd_per_stq,
+            btc_thresholds: req.btc_thresholds,
+            eth_thresholds: req.eth_thresholds,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PutExchangePairsRequest {
+    pub pairs: Vec<ExchangePair>,
+}
+
+impl From<PutExchangePairsRequest> for ExchangePairs {
+    fn from(req: PutExchangePairsRequest) -> Self {
+        Self { pairs: req.pairs }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PutTransactionLimitsRequest {
+    pub btc: f64,
+    pub eth: f64,
+    pub stq: f64,
+}
+
+impl From<PutTransactionLimitsRequest> for TransactionLimits {
+    fn from(req: PutTransactionLimitsRequest) -> Self {
+        Self {
+            btc: req.btc,
+            eth: req.eth,
+            stq: req.stq,
+        }
+    }
+}
@@ -1,7 +1,29 @@
+use std::collections::HashMap;
+
 use chrono::NaiveDateTime;
+use serde_json::Value;
+use uuid::Uuid;
 
+use super::requests::AmountFormat;
 use models::*;
 
+/// Amount in the format requested by client
+#[derive(Debug, Serialize, Clone)]
+#[serde(untagged)]
+pub enum AmountResponse {
+    Raw(Amount),
+    SuperUnits(String),
+}
+
+impl AmountResponse {
+    pub fn new(amount: Amount, currency: Currency, format: AmountFormat) -> Self {
+        match format {
+            AmountFormat::Raw => AmountResponse::Raw(amount),
+            AmountFormat::SuperUnits => AmountResponse::SuperUnits(amount.to_super_unit_string(currency)),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct UsersResponse {
@@ -10,6 +32,7 @@ pub struct UsersResponse {
     pub authentication_token: AuthenticationToken,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
+    pub withdrawal_whitelist: bool,
 }
 
 impl From<User> for UsersResponse {
@@ -20,6 +43,66 @@ impl From<User> for UsersResponse {
             authentication_token: user.authentication_token,
             created_at: user.created_at,
             updated_at: user.updated_at,
+            withdrawal_whitelist: user.withdrawal_whitelist,
+        }
+    }
+}
+
+/// User for support, authentication token is never shown
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AdminUserResponse {
+    pub id: UserId,
+    pub name: String,
+    pub created_at: NaiveDateTime,
+    pub disabled: bool,
+    pub withdrawal_whitelist: bool,
+    pub accounts_count: u64,
+    pub balances: HashMap<Currency, AmountResponse>,
+}
+
+impl From<(UserOverview, AmountFormat)> for AdminUserResponse {
+    fn from((overview, format): (UserOverview, AmountFormat)) -> Self {
+        Self {
+            id: overview.user.id,
+            name: overview.user.name,
+            created_at: overview.user.created_at,
+            disabled: overview.user.disabled,
+            withdrawal_whitelist: overview.user.withdrawal_whitelist,
+            accounts_count: overview.accounts_count,
+            balances: overview
+                .balances
+                .into_iter()
+                .map(|(currency, balance)| (currency, AmountResponse::new(balance, currency, format)))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct WithdrawalAddressesResponse {
+    pub id: Uuid,
+    pub user_id: UserId,
+    pub currency: Currency,
+    pub address: BlockchainAddress,
+    pub label: Option<String>,
+    pub active: bool,
+    pub active_from: NaiveDateTime,
+    pub created_at: NaiveDateTime,
+}
+
+impl From<WithdrawalAddress> for WithdrawalAddressesResponse {
+    fn from(address: WithdrawalAddress) -> Self {
+        Self {
+            id: address.id,
+            user_id: address.user_id,
+            currency: address.currency,
+            active: address.is_active(),
+            address: address.address,
+            label: address.label,
+            active_from: address.active_from,
+            created_at: address.created_at,
         }
     }
 }
@@ -35,6 +118,11 @@ pub struct AccountsResponse {
     pub erc20_approved: bool,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
+    pub meta: Value,
+    pub labels: Vec<String>,
+    pub archived: bool,
+    pub auto_convert_to: Option<AccountId>,
+    pub system_role: Option<SystemAccountKind>,
 }
 
 impl From<Account> for AccountsResponse {
@@ -48,6 +136,11 @@ impl From<Account> for AccountsResponse {
             created_at: account.created_at,
             updated_at: account.updated_at,
             erc20_approved: account.erc20_approved,
+            meta: account.meta,
+            labels: account.labels,
+            archived: account.archived,
+            auto_convert_to: account.auto_convert_to,
+            system_role: account.system_role,
         }
     }
 }
@@ -55,14 +148,14 @@ impl From<Account> for AccountsResponse {
 #[derive(Debug, Serialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct BalanceResponse {
-    pub balance: Amount,
+    pub balance: AmountResponse,
     pub account: Account,
 }
 
-impl From<AccountWithBalance> for BalanceResponse {
-    fn from(balance: AccountWithBalance) -> Self {
+impl From<(AccountWithBalance, AmountFormat)> for BalanceResponse {
+    fn from((balance, format): (AccountWithBalance, AmountFormat)) -> Self {
         Self {
-            balance: balance.balance,
+            balance: AmountResponse::new(balance.balance, balance.account.currency, format),
             account: balance.account,
         }
     }
@@ -75,10 +168,133 @@ pub struct BalancesResponse {
     pub data: Vec<BalanceResponse>,
 }
 
-impl From<Vec<AccountWithBalance>> for BalancesResponse {
-    fn from(balances: Vec<AccountWithBalance>) -> Self {
+impl From<(Vec<AccountWithBalance>, AmountFormat)> for BalancesResponse {
+    fn from((balances, format): (Vec<AccountWithBalance>, AmountFormat)) -> Self {
+        Self {
+            data: balances.into_iter().map(|balance| (balance, format).into()).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct CurrencyTotalResponse {
+    pub currency: Currency,
+    pub balance: AmountResponse,
+}
+
+impl From<(CurrencyTotal, AmountFormat)> for CurrencyTotalResponse {
+    fn from((total, format): (CurrencyTotal, AmountFormat)) -> Self {
+        Self {
+            currency: total.currency,
+            balance: AmountResponse::new(total.balance, total.currency, format),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ExpiredAddressResponse {
+    pub address: BlockchainAddress,
+    pub expired_at: NaiveDateTime,
+}
+
+impl From<ExpiredAddress> for ExpiredAddressResponse {
+    fn from(expired: ExpiredAddress) -> Self {
+        Self {
+            address: expired.address,
+            expired_at: expired.expired_at,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AccountAddressesResponse {
+    pub account_id: AccountId,
+    pub active: BlockchainAddress,
+    pub historical: Vec<ExpiredAddressResponse>,
+}
+
+impl From<AccountAddresses> for AccountAddressesResponse {
+    fn from(addresses: AccountAddresses) -> Self {
+        Self {
+            account_id: addresses.account_id,
+            active: addresses.active,
+            historical: addresses.historical.into_iter().map(From::from).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PaymentUriResponse {
+    pub account_id: AccountId,
+    pub currency: Currency,
+    pub address: BlockchainAddress,
+    pub amount: Option<AmountResponse>,
+    pub uri: String,
+}
+
+impl From<(PaymentUri, AmountFormat)> for PaymentUriResponse {
+    fn from((payment_uri, format): (PaymentUri, AmountFormat)) -> Self {
+        let currency = payment_uri.currency;
+        Self {
+            account_id: payment_uri.account_id,
+            currency,
+            address: payment_uri.address,
+            amount: payment_uri.amount.map(|amount| AmountResponse::new(amount, currency, format)),
+            uri: payment_uri.uri,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AccountStatsResponse {
+    pub account_id: AccountId,
+    pub currency: Currency,
+    pub window: StatsWindow,
+    pub deposits_count: u64,
+    pub deposits_value: AmountResponse,
+    pub withdrawals_count: u64,
+    pub withdrawals_value: AmountResponse,
+}
+
+impl From<(AccountStats, AmountFormat)> for AccountStatsResponse {
+    fn from((stats, format): (AccountStats, AmountFormat)) -> Self {
+        let currency = stats.currency;
+        Self {
+            account_id: stats.account_id,
+            currency,
+            window: stats.window,
+            deposits_count: stats.deposits_count,
+            deposits_value: AmountResponse::new(stats.deposits_value, currency, format),
+            withdrawals_count: stats.withdrawals_count,
+            withdrawals_value: AmountResponse::new(stats.withdrawals_value, currency, format),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct WalletResponse {
+    pub accounts: Vec<BalanceResponse>,
+    pub totals: Vec<CurrencyTotalResponse>,
+    pub reference_currency: Currency,
+    pub approximate_total: Option<AmountResponse>,
+}
+
+impl From<(Wallet, AmountFormat)> for WalletResponse {
+    fn from((wallet, format): (Wallet, AmountFormat)) -> Self {
+        let reference_currency = wallet.reference_currency;
         Self {
-            data: balances.into_iter().map(From::from).collect(),
+            accounts: wallet.accounts.into_iter().map(|balance| (balance, format).into()).collect(),
+            totals: wallet.totals.into_iter().map(|total| (total, format).into()).collect(),
+            reference_currency,
+            approximate_total: wallet
+                .approximate_total
+                .map(|total| AmountResponse::new(total, reference_currency, format)),
         }
     }
 }
@@ -89,32 +305,115 @@ pub struct TransactionsResponse {
     pub id: TransactionId,
     pub from: Vec<TransactionAddressInfo>,
     pub to: TransactionAddressInfo,
-    pub from_value: Amount,
+    pub from_value: AmountResponse,
     pub from_currency: Currency,
-    pub to_value: Amount,
+    pub to_value: AmountResponse,
     pub to_currency: Currency,
-    pub fee: Amount,
+    /// Fee is charged in `from_currency`
+    pub fee: AmountResponse,
     pub status: TransactionStatus,
     pub blockchain_tx_ids: Vec<BlockchainTransactionId>,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
+    pub kind: TransactionKind,
+    pub group_kind: TransactionGroupKind,
+    pub related_tx: Option<TransactionId>,
+    pub meta: Value,
+    pub usd_value: Option<f64>,
+    /// Only for pending withdrawals and deposits
+    pub estimated_completion_at: Option<NaiveDateTime>,
 }
 
-impl From<TransactionOut> for TransactionsResponse {
-    fn from(transaction: TransactionOut) -> Self {
+impl From<(TransactionOut, AmountFormat)> for TransactionsResponse {
+    fn from((transaction, format): (TransactionOut, AmountFormat)) -> Self {
         Self {
             id: transaction.id,
             from: transaction.from,
             to: transaction.to,
-            from_value: transaction.from_value,
+            from_value: AmountResponse::new(transaction.from_value, transaction.from_currency, format),
             from_currency: transaction.from_currency,
-            to_value: transaction.to_value,
+            to_value: AmountResponse::new(transaction.to_value, transaction.to_currency, format),
             to_currency: transaction.to_currency,
-            fee: transaction.fee,
+            fee: AmountResponse::new(transaction.fee, transaction.from_currency, format),
             status: transaction.status,
             blockchain_tx_ids: transaction.blockchain_tx_ids,
             created_at: transaction.created_at,
             updated_at: transaction.updated_at,
+            kind: transaction.kind,
+            group_kind: transaction.group_kind,
+            related_tx: transaction.related_tx,
+            meta: transaction.meta,
+            usd_value: transaction.usd_value,
+            estimated_completion_at: transaction.estimated_completion_at,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PayoutResultResponse {
+    pub id: TransactionId,
+    pub address: BlockchainAddress,
+    pub value: AmountResponse,
+    pub status: PayoutStatus,
+}
+
+/// Sent to client as error params, when only some of the payouts are sent
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PartialPayoutResponse {
+    pub transaction: TransactionsResponse,
+    pub payouts: Vec<PayoutResultResponse>,
+}
+
+impl From<(PayoutOut, AmountFormat)> for PartialPayoutResponse {
+    fn from((payout, format): (PayoutOut, AmountFormat)) -> Self {
+        let currency = payout.transaction.from_currency;
+        Self {
+            transaction: (payout.transaction, format).into(),
+            payouts: payout
+                .payouts
+                .into_iter()
+                .map(|result| PayoutResultResponse {
+                    id: result.id,
+                    address: result.address,
+                    value: AmountResponse::new(result.value, currency, format),
+                    status: result.status,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Fee charged by transaction group against network fees paid for it, `margin` is their difference
+/// in super units of network fees currency
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupFeeResponse {
+    pub gid: TransactionId,
+    pub fee_currency: Currency,
+    pub fee: AmountResponse,
+    pub blockchain_fee_currency: Currency,
+    pub charged_blockchain_fee: AmountResponse,
+    pub blockchain_fee: AmountResponse,
+    pub margin: f64,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+impl From<(GroupFee, AmountFormat)> for GroupFeeResponse {
+    fn from((group_fee, format): (GroupFee, AmountFormat)) -> Self {
+        let margin = group_fee.margin();
+        Self {
+            gid: group_fee.gid,
+            fee_currency: group_fee.fee_currency,
+            fee: AmountResponse::new(group_fee.fee, group_fee.fee_currency, format),
+            blockchain_fee_currency: group_fee.blockchain_fee_currency,
+            charged_blockchain_fee: AmountResponse::new(group_fee.charged_blockchain_fee, group_fee.blockchain_fee_currency, format),
+            blockchain_fee: AmountResponse::new(group_fee.blockchain_fee, group_fee.blockchain_fee_currency, format),
+            margin,
+            created_at: group_fee.created_at,
+            updated_at: group_fee.updated_at,
         }
     }
 }
@@ -123,14 +422,375 @@ impl From<TransactionOut> for TransactionsResponse {
 #[serde(rename_all = "camelCase")]
 pub struct FeesResponse {
     pub currency: Currency,
-    pub fees: Vec<Fee>,
+    pub fees: Vec<FeeResponse>,
 }
 
-impl From<Fees> for FeesResponse {
-    fn from(rate: Fees) -> Self {
+impl From<(Fees, AmountFormat)> for FeesResponse {
+    fn from((rate, format): (Fees, AmountFormat)) -> Self {
+        let currency = rate.currency;
         Self {
-            currency: rate.currency,
-            fees: rate.fees,
+            currency,
+            fees: rate
+                .fees
+                .into_iter()
+                .map(|fee| FeeResponse {
+                    value: AmountResponse::new(fee.value, currency, format),
+                    estimated_time: fee.estimated_time,
+                })
+                .collect(),
         }
     }
 }
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct FeeResponse {
+    pub value: AmountResponse,
+    pub estimated_time: u64,
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RateResponse {
+    pub id: ExchangeId,
+    pub from: Currency,
+    pub to: Currency,
+    pub amount: AmountResponse,
+    pub amount_currency: Currency,
+    pub rate: f64,
+    pub expiration: NaiveDateTime,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    pub is_stale: bool,
+}
+
+impl From<(Rate, AmountFormat)> for RateResponse {
+    fn from((rate, format): (Rate, AmountFormat)) -> Self {
+        Self {
+            id: rate.id,
+            from: rate.from,
+            to: rate.to,
+            amount: AmountResponse::new(rate.amount, rate.amount_currency, format),
+            amount_currency: rate.amount_currency,
+            rate: rate.rate,
+            expiration: rate.expiration,
+            created_at: rate.created_at,
+            updated_at: rate.updated_at,
+            is_stale: rate.is_stale,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ExchangeQuoteResponse {
+    pub exchange_id: ExchangeId,
+    pub from: Currency,
+    pub to: Currency,
+    pub rate: f64,
+    pub gateway_rate: f64,
+    pub spread: f64,
+    pub from_value: AmountResponse,
+    pub to_value: AmountResponse,
+    pub gross_to_value: AmountResponse,
+    pub margin: AmountResponse,
+    pub expiration: NaiveDateTime,
+    pub is_stale: bool,
+}
+
+impl From<(ExchangeQuote, AmountFormat)> for ExchangeQuoteResponse {
+    fn from((quote, format): (ExchangeQuote, AmountFormat)) -> Self {
+        Self {
+            exchange_id: quote.exchange_id,
+            from: quote.from,
+            to: quote.to,
+            rate: quote.rate,
+            gateway_rate: quote.gateway_rate,
+            spread: quote.spread,
+            from_value: AmountResponse::new(quote.from_value, quote.from, format),
+            to_value: AmountResponse::new(quote.to_value, quote.to, format),
+            gross_to_value: AmountResponse::new(quote.gross_to_value, quote.to, format),
+            margin: AmountResponse::new(quote.margin, quote.to, format),
+            expiration: quote.expiration,
+            is_stale: quote.is_stale,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RateLockResponse {
+    pub id: Uuid,
+    pub exchange_id: ExchangeId,
+    pub from: Currency,
+    pub to: Currency,
+    pub amount: AmountResponse,
+    pub amount_currency: Currency,
+    pub rate: f64,
+    pub expires_at: NaiveDateTime,
+    pub created_at: NaiveDateTime,
+}
+
+impl From<(RateLock, AmountFormat)> for RateLockResponse {
+    fn from((rate_lock, format): (RateLock, AmountFormat)) -> Self {
+        Self {
+            id: rate_lock.id,
+            exchange_id: rate_lock.exchange_id,
+            from: rate_lock.from_currency,
+            to: rate_lock.to_currency,
+            amount: AmountResponse::new(rate_lock.amount, rate_lock.amount_currency, format),
+            amount_currency: rate_lock.amount_currency,
+            rate: rate_lock.rate,
+            expires_at: rate_lock.expires_at,
+            created_at: rate_lock.created_at,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RateRefreshResponse {
+    pub exchange: RateResponse,
+    pub is_new_rate: bool,
+}
+
+impl From<(RateRefresh, AmountFormat)> for RateRefreshResponse {
+    fn from((refresh, format): (RateRefresh, AmountFormat)) -> Self {
+        Self {
+            exchange: (refresh.exchange, format).into(),
+            is_new_rate: refresh.is_new_rate,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ConfirmationThresholdsResponse {
+    pub usd_per_btc: f64,
+    pub usd_per_eth: f64,
+    pub usd_per_stq: f64,
+    pub btc_thresholds: Vec<u64>,
+    pub eth_thresholds: Vec<u64>,
+    pub overridden: bool,
+}
+
+impl From<ConfirmationThresholdsSettings> for ConfirmationThresholdsResponse {
+    fn from(settings: ConfirmationThresholdsSettings) -> Self {
+        let thresholds = settings.thresholds;
+        Self {
+            usd_per_btc: thresholds.usd_per_btc,
+            usd_per_eth: thresholds.usd_per_eth,
+            usd_per_stq: thresholds.usd_per_stq,
+            btc_thresholds: thresholds.btc_thresholds,
+            eth_thresholds: thresholds.eth_thresholds,
+            overridden: settings.overridden,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ExchangePairsResponse {
+    pub pairs: Vec<ExchangePair>,
+    pub overridden: bool,
+}
+
+impl From<ExchangePairsSettings> for ExchangePairsResponse {
+    fn from(settings: ExchangePairsSettings) -> Self {
+        Self {
+            pairs: settings.pairs.pairs,
+            overridden: settings.overridden,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TransactionLimitsResponse {
+    pub user_id: UserId,
+    pub btc: f64,
+    pub eth: f64,
+    pub stq: f64,
+    pub overridden: bool,
+}
+
+impl From<TransactionLimitsSettings> for TransactionLimitsResponse {
+    fn from(settings: TransactionLimitsSettings) -> Self {
+        Self {
+            user_id: settings.user_id,
+            btc: settings.limits.btc,
+            eth: settings.limits.eth,
+            stq: settings.limits.stq,
+            overridden: settings.overridden,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SmallDepositResponse {
+    pub blockchain_tx_id: BlockchainTransactionId,
+    pub account_id: AccountId,
+    pub user_id: UserId,
+    pub currency: Currency,
+    pub value: AmountResponse,
+    pub created_at: NaiveDateTime,
+}
+
+impl From<(SmallDeposit, AmountFormat)> for SmallDepositResponse {
+    fn from((deposit, format): (SmallDeposit, AmountFormat)) -> Self {
+        Self {
+            blockchain_tx_id: deposit.blockchain_tx_id,
+            account_id: deposit.account_id,
+            user_id: deposit.user_id,
+            currency: deposit.currency,
+            value: AmountResponse::new(deposit.value, deposit.currency, format),
+            created_at: deposit.created_at,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct QueuedWithdrawalResponse {
+    pub id: TransactionId,
+    pub user_id: UserId,
+    pub account_id: AccountId,
+    pub currency: Currency,
+    pub address: BlockchainAddress,
+    pub value: AmountResponse,
+    pub fee: AmountResponse,
+    pub status: QueuedWithdrawalStatus,
+    pub transaction_id: Option<TransactionId>,
+    pub error_message: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+impl From<(QueuedWithdrawal, AmountFormat)> for QueuedWithdrawalResponse {
+    fn from((withdrawal, format): (QueuedWithdrawal, AmountFormat)) -> Self {
+        Self {
+            id: withdrawal.id,
+            user_id: withdrawal.user_id,
+            account_id: withdrawal.account_id,
+            currency: withdrawal.currency,
+            address: withdrawal.address,
+            value: AmountResponse::new(withdrawal.value, withdrawal.currency, format),
+            fee: AmountResponse::new(withdrawal.fee, withdrawal.currency, format),
+            status: withdrawal.status,
+            transaction_id: withdrawal.transaction_id,
+            error_message: withdrawal.error_message,
+            created_at: withdrawal.created_at,
+            updated_at: withdrawal.updated_at,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SmallDepositsTotalResponse {
+    pub currency: Currency,
+    pub count: i64,
+    pub value: AmountResponse,
+}
+
+impl From<(SmallDepositsTotal, AmountFormat)> for SmallDepositsTotalResponse {
+    fn from((total, format): (SmallDepositsTotal, AmountFormat)) -> Self {
+        Self {
+            currency: total.currency,
+            count: total.count,
+            value: AmountResponse::new(total.value, total.currency, format),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SmallDepositsReportResponse {
+    pub deposits: Vec<SmallDepositResponse>,
+    pub totals: Vec<SmallDepositsTotalResponse>,
+}
+
+impl From<(SmallDepositsReport, AmountFormat)> for SmallDepositsReportResponse {
+    fn from((report, format): (SmallDepositsReport, AmountFormat)) -> Self {
+        Self {
+            deposits: report.deposits.into_iter().map(|deposit| (deposit, format).into()).collect(),
+            totals: report.totals.into_iter().map(|total| (total, format).into()).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SystemAddressSweepResponse {
+    pub id: TransactionId,
+    pub value: AmountResponse,
+    pub blockchain_tx_id: Option<BlockchainTransactionId>,
+    pub status: TransactionStatus,
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SystemAccountOverviewResponse {
+    pub account_id: AccountId,
+    pub kind: SystemAccountKind,
+    pub currency: Currency,
+    pub address: BlockchainAddress,
+    pub balance: AmountResponse,
+    pub reconciliation: Option<SystemAccountReconciliation>,
+}
+
+impl From<(SystemAccountOverview, AmountFormat)> for SystemAccountOverviewResponse {
+    fn from((overview, format): (SystemAccountOverview, AmountFormat)) -> Self {
+        let currency = overview.account.currency;
+        Self {
+            account_id: overview.account.id,
+            kind: overview.kind,
+            currency,
+            address: overview.account.address,
+            balance: AmountResponse::new(overview.balance, currency, format),
+            reconciliation: overview.reconciliation,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SystemAddressRotationResponse {
+    pub account_id: AccountId,
+    pub kind: SystemAccountKind,
+    pub currency: Currency,
+    pub address: BlockchainAddress,
+    pub former_address: BlockchainAddress,
+    pub former_address_account_id: AccountId,
+    pub sweep: Option<SystemAddressSweepResponse>,
+}
+
+impl From<(SystemAddressRotation, AmountFormat)> for SystemAddressRotationResponse {
+    fn from((rotation, format): (SystemAddressRotation, AmountFormat)) -> Self {
+        let currency = rotation.currency;
+        Self {
+            account_id: rotation.account_id,
+            kind: rotation.kind,
+            currency,
+            address: rotation.address,
+            former_address: rotation.former_address,
+            former_address_account_id: rotation.former_address_account_id,
+            sweep: rotation.sweep.map(|tx| SystemAddressSweepResponse {
+                id: tx.gid,
+                value: AmountResponse::new(tx.value, currency, format),
+                blockchain_tx_id: tx.blockchain_tx_id,
+                status: tx.status,
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorResponse {
+    pub code: &'static str,
+    pub message: &'static str,
+    pub params: Value,
+    pub request_id: Option<String>,
+}
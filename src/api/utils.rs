@@ -1,14 +1,25 @@
 use std::fmt::Debug;
 
 use failure::Fail;
+use futures;
 use futures::prelude::*;
-use hyper::Response;
+use futures::sync::mpsc;
+use hyper;
+use hyper::{Body, Response};
 use serde::Deserialize;
 use serde::Serialize;
 use serde_json;
+use serde_qs;
 
 use super::error::*;
+use super::requests::CommonParams;
+use super::responses::ErrorResponse;
 use super::ControllerFuture;
+use request_id;
+use utils::log_error;
+
+/// Number of serialized elements of streamed response buffered while client is reading previous ones
+const STREAM_CHANNEL_SIZE: usize = 100;
 
 pub fn parse_body<T>(body: Vec<u8>) -> impl Future<Item = T, Error = Error> + Send
 where
@@ -20,6 +31,17 @@ where
         .and_then(|string| serde_json::from_str::<T>(&string).map_err(ectx!(ErrorContext::RequestJson, ErrorKind::BadRequest => string)))
 }
 
+/// Parses query params accepted by all endpoints, the rest of params are left to controllers
+pub fn parse_common_params(query: Option<&str>) -> Result<CommonParams, Error> {
+    match query {
+        None => Ok(CommonParams::default()),
+        Some(query) => serde_qs::from_str::<CommonParams>(query).map_err(|e| {
+            let e = format_err!("{}", e);
+            ectx!(err e, ErrorContext::RequestQueryParams, ErrorKind::BadRequest => query)
+        }),
+    }
+}
+
 pub fn response_with_model<M>(model: &M) -> ControllerFuture
 where
     M: Debug + Serialize,
@@ -37,3 +59,58 @@ where
             }),
     )
 }
+
+/// Responds with error body, that client apps can branch on by `code`
+pub fn response_with_error(e: &Error) -> Response<Body> {
+    let error = ErrorResponse {
+        code: e.code(),
+        message: e.message(),
+        params: e.params(),
+        request_id: request_id::current(),
+    };
+    let body = serde_json::to_string(&error).unwrap_or_else(|_| format!(r#"{{"code": "{}"}}"#, error.code));
+    Response::builder()
+        .status(e.status())
+        .header("Content-Type", "application/json")
+        .body(body.into())
+        .unwrap()
+}
+
+/// Responds with json array, which elements are sent to client as soon as they are produced by `items`
+/// instead of buffering the whole response. Response status is sent before the first element,
+/// so if `items` fail in the middle, the only thing we can do is to abort the response.
+pub fn response_with_json_stream<S>(items: S) -> Response<Body>
+where
+    S: Stream<Item = String, Error = Error> + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel(STREAM_CHANNEL_SIZE);
+    let mut is_first = true;
+    let chunks = futures::stream::once(Ok("[".to_string()))
+        .chain(items.map(move |item| {
+            if is_first {
+                is_first = false;
+                item
+            } else {
+                format!(",{}", item)
+            }
+        }))
+        .chain(futures::stream::once(Ok("]".to_string())));
+    // sink fails only if client has gone and receiver is dropped
+    let producer = chunks
+        .then(|chunk| Ok::<_, ()>(chunk))
+        .forward(sender.sink_map_err(|_| ()))
+        .map(|_| ());
+    hyper::rt::spawn(producer);
+    let body = receiver
+        .map_err(|_| ectx!(err ErrorContext::ResponseStream, ErrorKind::Internal))
+        .and_then(|chunk| chunk)
+        .map_err(|e| {
+            log_error(&e);
+            e.compat()
+        });
+    Response::builder()
+        .status(200)
+        .header("Content-Type", "application/json")
+        .body(Body::wrap_stream(body))
+        .unwrap()
+}
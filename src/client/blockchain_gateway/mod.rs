@@ -1,7 +1,7 @@
 mod error;
 mod responses;
 
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 use failure::Fail;
 use futures::prelude::*;
@@ -13,7 +13,7 @@ use serde_json;
 
 pub use self::error::*;
 use self::responses::*;
-use super::HttpClient;
+use super::{gateway_client, HttpClient};
 use config::Config;
 use utils::read_body;
 
@@ -29,6 +29,12 @@ pub trait BlockchainClient: Send + Sync + 'static {
     fn get_bitcoin_utxos(&self, address: BlockchainAddress) -> Box<Future<Item = Vec<BitcoinUtxos>, Error = Error> + Send>;
     fn get_ethereum_nonce(&self, address: BlockchainAddress) -> Box<Future<Item = u64, Error = Error> + Send>;
     fn get_balance(&self, address: BlockchainAddress, currency: Currency) -> Box<Future<Item = Amount, Error = Error> + Send>;
+    /// `None` if the transaction is unknown to blockchain, i.e. it was never broadcasted or was dropped from mempool
+    fn get_transaction(
+        &self,
+        hash: BlockchainTransactionId,
+        currency: Currency,
+    ) -> Box<Future<Item = Option<BlockchainTransaction>, Error = Error> + Send>;
 }
 
 #[derive(Clone)]
@@ -40,7 +46,7 @@ pub struct BlockchainClientImpl {
 impl BlockchainClientImpl {
     pub fn new<C: HttpClient>(config: &Config, cli: C) -> Self {
         Self {
-            cli: Arc::new(cli),
+            cli: gateway_client(cli, "blockchain", &config.client.blockchain),
             blockchain_url: config.client.blockchain_url.clone(),
         }
     }
@@ -63,7 +69,7 @@ impl BlockchainClientImpl {
             .body(Body::from(body))
             .map_err(ectx!(ErrorSource::Hyper, ErrorKind::MalformedInput => query3))
             .into_future()
-            .and_then(move |req| cli.request(req).map_err(ectx!(ErrorKind::Internal => query1)))
+            .and_then(move |req| cli.request(req).map_err(ectx!(convert => query1)))
             .and_then(move |resp| read_body(resp.into_body()).map_err(ectx!(ErrorSource::Hyper, ErrorKind::Internal => query2)))
             .and_then(|bytes| {
                 let bytes_clone = bytes.clone();
@@ -79,7 +85,7 @@ impl BlockchainClientImpl {
         let cli = self.cli.clone();
         let url = format!("{}{}", self.blockchain_url, query);
         cli.get(url)
-            .map_err(ectx!(ErrorKind::Internal => query1))
+            .map_err(ectx!(convert => query1))
             .and_then(move |resp| read_body(resp.into_body()).map_err(ectx!(ErrorSource::Hyper, ErrorKind::Internal => query2)))
             .and_then(|bytes| {
                 let bytes_clone = bytes.clone();
@@ -98,6 +104,24 @@ impl BlockchainClient for BlockchainClientImpl {
         };
         Box::new(self.exec_query_get::<GetBalanceResponse>(&url).map(|resp| resp.balance))
     }
+    fn get_transaction(
+        &self,
+        hash: BlockchainTransactionId,
+        currency: Currency,
+    ) -> Box<Future<Item = Option<BlockchainTransaction>, Error = Error> + Send> {
+        // erc-20 transfers are identified by tx hash with log index, gateway knows only the former
+        let tx_hash = hash.inner().split(':').next().unwrap_or_default().to_string();
+        let url = match currency {
+            Currency::Btc => format!("/bitcoin/transactions/{}", tx_hash),
+            Currency::Eth => format!("/ethereum/transactions/{}", tx_hash),
+            Currency::Stq => format!("/storiqa/transactions/{}", tx_hash),
+        };
+        Box::new(self.exec_query_get::<BlockchainTransaction>(&url).then(|res| match res {
+            Ok(transaction) => Ok(Some(transaction)),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }))
+    }
     fn post_ethereum_transaction(
         &self,
         raw: BlockchainTransactionRaw,
@@ -133,13 +157,25 @@ impl BlockchainClient for BlockchainClientImpl {
     }
 }
 
-#[derive(Default)]
-pub struct BlockchainClientMock;
+/// Gateway that accepts every transaction and knows only `transactions`
+#[derive(Clone, Default)]
+pub struct BlockchainClientMock {
+    pub transactions: Arc<Mutex<Vec<BlockchainTransaction>>>,
+}
 
 impl BlockchainClient for BlockchainClientMock {
     fn get_balance(&self, _address: BlockchainAddress, _currency: Currency) -> Box<Future<Item = Amount, Error = Error> + Send> {
         unimplemented!()
     }
+    fn get_transaction(
+        &self,
+        hash: BlockchainTransactionId,
+        currency: Currency,
+    ) -> Box<Future<Item = Option<BlockchainTransaction>, Error = Error> + Send> {
+        let transactions = self.transactions.lock().unwrap();
+        let transaction = transactions.iter().find(|tx| tx.hash == hash && tx.currency == currency).cloned();
+        Box::new(Ok(transaction).into_future())
+    }
     fn post_ethereum_transaction(
         &self,
         _post_transaction: BlockchainTransactionRaw,
@@ -159,3 +195,40 @@ impl BlockchainClient for BlockchainClientMock {
         Box::new(Ok(0).into_future())
     }
 }
+
+/// Gateway that rejects every transaction, behaves like `BlockchainClientMock` otherwise
+#[derive(Clone, Default)]
+pub struct RejectingBlockchainClient {
+    pub client: BlockchainClientMock,
+}
+
+impl BlockchainClient for RejectingBlockchainClient {
+    fn get_balance(&self, address: BlockchainAddress, currency: Currency) -> Box<Future<Item = Amount, Error = Error> + Send> {
+        self.client.get_balance(address, currency)
+    }
+    fn get_transaction(
+        &self,
+        hash: BlockchainTransactionId,
+        currency: Currency,
+    ) -> Box<Future<Item = Option<BlockchainTransaction>, Error = Error> + Send> {
+        self.client.get_transaction(hash, currency)
+    }
+    fn post_ethereum_transaction(
+        &self,
+        _post_transaction: BlockchainTransactionRaw,
+    ) -> Box<Future<Item = BlockchainTransactionId, Error = Error> + Send> {
+        Box::new(Err(Error::from(ErrorKind::Rejected)).into_future())
+    }
+    fn post_bitcoin_transaction(
+        &self,
+        _post_transaction: BlockchainTransactionRaw,
+    ) -> Box<Future<Item = BlockchainTransactionId, Error = Error> + Send> {
+        Box::new(Err(Error::from(ErrorKind::Rejected)).into_future())
+    }
+    fn get_bitcoin_utxos(&self, address: BlockchainAddress) -> Box<Future<Item = Vec<BitcoinUtxos>, Error = Error> + Send> {
+        self.client.get_bitcoin_utxos(address)
+    }
+    fn get_ethereum_nonce(&self, address: BlockchainAddress) -> Box<Future<Item = u64, Error = Error> + Send> {
+        self.client.get_ethereum_nonce(address)
+    }
+}
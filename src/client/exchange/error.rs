@@ -19,6 +19,10 @@ pub enum ErrorKind {
     Unauthorized,
     #[fail(display = "exchange client error - internal error")]
     Internal,
+    #[fail(display = "exchange client error - gateway is unavailable")]
+    Unavailable,
+    #[fail(display = "exchange client error - gateway didn't respond in time")]
+    GatewayTimeout,
     #[fail(display = "exchange client error - bad request")]
     Validation(String),
 }
@@ -32,6 +36,8 @@ pub enum ErrorSource {
     Utf8,
     #[fail(display = "exchange client source - error parsing string to json")]
     Json,
+    #[fail(display = "exchange client source - rates of exchange gateways diverge")]
+    Divergence,
 }
 
 derive_error_impls!();
@@ -40,6 +46,8 @@ impl From<HttpClientErrorKind> for ErrorKind {
     fn from(err: HttpClientErrorKind) -> Self {
         match err {
             HttpClientErrorKind::Validation(s) => ErrorKind::Validation(s),
+            HttpClientErrorKind::BadGateway | HttpClientErrorKind::CircuitOpen => ErrorKind::Unavailable,
+            HttpClientErrorKind::GatewayTimeout => ErrorKind::GatewayTimeout,
             _ => ErrorKind::Internal,
         }
     }
@@ -0,0 +1,430 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use chrono::{Duration, NaiveDateTime};
+use futures::future::{self, Either};
+use futures::prelude::*;
+
+use super::error::*;
+use super::{ExchangeClient, ExchangeClientImpl};
+use client::HttpClient;
+use config::Config;
+use models::*;
+use repos::{DbExecutor, KeyValuesRepo, KeyValuesRepoExt};
+use utils::{log_and_capture_error, log_warn};
+
+/// Gateway of exchanges is remembered for that long after its rate expires
+const OWNER_RETENTION_SECS: i64 = 600;
+/// Unavailable gateway is skipped for that long, then it's tried again
+const HEALTH_RETRY_SECS: i64 = 30;
+/// Rates of other gateways are compared to the served one if they are issued that recently
+const DIVERGENCE_WINDOW_SECS: i64 = 300;
+
+/// Exchange client over several gateways. Rates are taken from the first available gateway in order,
+/// while exchanges and refreshes go to the gateway that issued the rate, since exchange id is bound to it.
+/// Gateways of rates issued by fallbacks are kept in key values, so that they survive restarts.
+/// Each served rate is compared to the recent rates of other gateways for the same pair,
+/// the ones that differ by more than the threshold are reported
+#[derive(Clone)]
+pub struct FailoverExchangeClient<E: DbExecutor> {
+    providers: Arc<Vec<ExchangeProvider>>,
+    db_executor: E,
+    key_values_repo: Arc<dyn KeyValuesRepo>,
+    // the latest rate served by each gateway, by gateway index and currency pair
+    last_rates: Arc<Mutex<HashMap<(usize, Currency, Currency), Rate>>>,
+    divergence_threshold: f64,
+}
+
+#[derive(Clone)]
+struct ExchangeProvider {
+    name: String,
+    url: String,
+    client: Arc<dyn ExchangeClient>,
+    unavailable_since: Arc<Mutex<Option<NaiveDateTime>>>,
+}
+
+/// Served rate that differs from the recent rate of another gateway by more than the threshold
+#[derive(Debug, Clone)]
+pub struct RateDivergence {
+    pub gateway: String,
+    pub rate: Rate,
+    pub other_gateway: String,
+    pub other_rate: Rate,
+}
+
+impl ExchangeProvider {
+    fn new(name: String, url: String, client: Arc<dyn ExchangeClient>) -> Self {
+        Self {
+            name,
+            url,
+            client,
+            unavailable_since: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    // Unavailable gateway is given another try once in a while, so that it's used again after it recovers
+    fn is_available(&self, now: NaiveDateTime) -> bool {
+        match *self.unavailable_since.lock().unwrap() {
+            Some(since) => since + Duration::seconds(HEALTH_RETRY_SECS) <= now,
+            None => true,
+        }
+    }
+
+    // Only unavailability changes health, errors like bad request say nothing about the gateway
+    fn record<T>(&self, res: &Result<T, Error>) {
+        let mut unavailable_since = self.unavailable_since.lock().unwrap();
+        match res {
+            Ok(_) => {
+                if unavailable_since.take().is_some() {
+                    info!("Exchange gateway {} is available again", self.name);
+                }
+            }
+            Err(ref e) if is_unavailable(e.kind()) => {
+                if unavailable_since.is_none() {
+                    warn!("Exchange gateway {} is unavailable", self.name);
+                }
+                *unavailable_since = Some(::chrono::Utc::now().naive_utc());
+            }
+            Err(_) => (),
+        }
+    }
+}
+
+impl<E: DbExecutor> FailoverExchangeClient<E> {
+    /// Primary gateway is `exchange_gateway_url`, followed by `exchange_gateway_fallback_urls`
+    pub fn new<C: HttpClient + Clone>(config: &Config, cli: C, db_executor: E, key_values_repo: Arc<dyn KeyValuesRepo>) -> Self {
+        let mut providers = vec![ExchangeProvider::new(
+            "exchange_gateway".to_string(),
+            config.client.exchange_gateway_url.clone(),
+            Arc::new(ExchangeClientImpl::new(config, cli.clone())),
+        )];
+        for (i, url) in config.client.exchange_gateway_fallback_urls.iter().enumerate() {
+            let name = format!("exchange_gateway_fallback_{}", i + 1);
+            let client = ExchangeClientImpl::with_url(config, cli.clone(), &name, url.clone());
+            providers.push(ExchangeProvider::new(name, url.clone(), Arc::new(client)));
+        }
+        Self::with_providers(
+            providers,
+            config.exchange_options.rate_divergence_threshold,
+            db_executor,
+            key_values_repo,
+        )
+    }
+
+    fn with_providers(
+        providers: Vec<ExchangeProvider>,
+        divergence_threshold: f64,
+        db_executor: E,
+        key_values_repo: Arc<dyn KeyValuesRepo>,
+    ) -> Self {
+        Self {
+            providers: Arc::new(providers),
+            db_executor,
+            key_values_repo,
+            last_rates: Arc::new(Mutex::new(HashMap::new())),
+            divergence_threshold,
+        }
+    }
+
+    // Rates of the primary gateway are not stored, since unknown rates are considered to be issued by it
+    fn remember_owner(&self, owner: usize, rate: &Rate) -> Box<Future<Item = (), Error = Error> + Send> {
+        if owner == 0 {
+            return Box::new(future::ok(()));
+        }
+        let key_values_repo = self.key_values_repo.clone();
+        let exchange_id = rate.id;
+        let url = self.providers[owner].url.clone();
+        let ttl = rate.expiration - ::chrono::Utc::now().naive_utc() + Duration::seconds(OWNER_RETENTION_SECS);
+        Box::new(
+            self.db_executor
+                .execute(move || key_values_repo.set_exchange_gateway(exchange_id, &url, ttl))
+                .map_err(ectx!(ErrorKind::Internal => exchange_id)),
+        )
+    }
+
+    // Gateways that are no longer configured fall back to the primary one as well
+    fn owner(&self, exchange_id: ExchangeId) -> Box<Future<Item = usize, Error = Error> + Send> {
+        let key_values_repo = self.key_values_repo.clone();
+        let providers = self.providers.clone();
+        Box::new(
+            self.db_executor
+                .execute(move || key_values_repo.get_exchange_gateway(exchange_id))
+                .map_err(ectx!(ErrorKind::Internal => exchange_id))
+                .map(move |url| {
+                    url.and_then(|url| providers.iter().position(|provider| provider.url == url))
+                        .unwrap_or(0)
+                }),
+        )
+    }
+
+    // Available gateways in order, followed by unavailable ones as the last resort
+    fn rate_order(&self) -> Vec<usize> {
+        let now = ::chrono::Utc::now().naive_utc();
+        let (available, unavailable): (Vec<usize>, Vec<usize>) =
+            (0..self.providers.len()).partition(|index| self.providers[*index].is_available(now));
+        available.into_iter().chain(unavailable).collect()
+    }
+
+    // Takes rate from the first gateway of `order`, or the next ones if it's unavailable
+    fn rate_from(&self, mut order: Vec<usize>, input: RateInput, role: Role) -> Box<Future<Item = (usize, Rate), Error = Error> + Send> {
+        let self_clone = self.clone();
+        let index = order.remove(0);
+        let provider = self.providers[index].clone();
+        Box::new(provider.client.rate(input.clone(), role).then(move |res| {
+            provider.record(&res);
+            match res {
+                Ok(rate) => Either::A(future::ok((index, rate))),
+                Err(ref e) if is_unavailable(e.kind()) && !order.is_empty() => {
+                    log_warn(e);
+                    Either::B(self_clone.rate_from(order, input, role))
+                }
+                Err(e) => Either::A(future::err(e)),
+            }
+        }))
+    }
+
+    // Remembers the rate served by `owner` and compares it to the recent rates of other gateways for the same pair.
+    // No quotes are requested for that, since gateways would reserve liquidity for rates nobody uses
+    fn check_divergence(&self, owner: usize, rate: &Rate) -> Vec<RateDivergence> {
+        let now = ::chrono::Utc::now().naive_utc();
+        let mut last_rates = self.last_rates.lock().unwrap();
+        last_rates.insert((owner, rate.from, rate.to), rate.clone());
+        last_rates
+            .iter()
+            .filter(|&(&(index, from, to), other)| {
+                index != owner
+                    && (from, to) == (rate.from, rate.to)
+                    && other.created_at + Duration::seconds(DIVERGENCE_WINDOW_SECS) > now
+                    && diverges(rate.rate, other.rate, self.divergence_threshold)
+            })
+            .map(|(&(index, _, _), other)| RateDivergence {
+                gateway: self.providers[owner].name.clone(),
+                rate: rate.clone(),
+                other_gateway: self.providers[index].name.clone(),
+                other_rate: other.clone(),
+            })
+            .collect()
+    }
+}
+
+impl<E: DbExecutor> ExchangeClient for FailoverExchangeClient<E> {
+    fn exchange(&self, exchange: ExchangeInput, role: Role) -> Box<Future<Item = Exchange, Error = Error> + Send> {
+        let providers = self.providers.clone();
+        Box::new(self.owner(exchange.id).and_then(move |owner| {
+            let provider = providers[owner].clone();
+            provider.client.exchange(exchange, role).then(move |res| {
+                provider.record(&res);
+                res
+            })
+        }))
+    }
+
+    fn rate(&self, exchange: RateInput, role: Role) -> Box<Future<Item = Rate, Error = Error> + Send> {
+        let self_clone = self.clone();
+        Box::new(self.rate_from(self.rate_order(), exchange, role).and_then(move |(owner, rate)| {
+            for divergence in self_clone.check_divergence(owner, &rate) {
+                let e: Error = ectx!(err ErrorSource::Divergence, ErrorKind::Internal => divergence);
+                log_and_capture_error(e);
+            }
+            self_clone.remember_owner(owner, &rate).map(move |_| rate)
+        }))
+    }
+
+    fn refresh_rate(&self, input: RateRefreshInput, role: Role) -> Box<Future<Item = RateRefresh, Error = Error> + Send> {
+        let self_clone = self.clone();
+        Box::new(self.owner(input.exchange_id).and_then(move |owner| {
+            let provider = self_clone.providers[owner].clone();
+            provider
+                .client
+                .refresh_rate(input, role)
+                .then(move |res| {
+                    provider.record(&res);
+                    res
+                })
+                // new rate may be issued instead of the refreshed one
+                .and_then(move |refresh| self_clone.remember_owner(owner, &refresh.exchange).map(move |_| refresh))
+        }))
+    }
+}
+
+fn is_unavailable(kind: ErrorKind) -> bool {
+    kind == ErrorKind::Unavailable || kind == ErrorKind::GatewayTimeout
+}
+
+fn diverges(rate: f64, other: f64, threshold: f64) -> bool {
+    !((rate - other).abs() <= threshold * rate.abs())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use repos::{DbExecutorMock, KeyValuesRepoMock};
+    use tokio_core::reactor::Core;
+
+    // Gateway that issues rates with the given value or fails with the given error, and counts the quotes
+    struct StaticExchangeClient {
+        rate: Result<f64, ErrorKind>,
+        quotes: AtomicUsize,
+    }
+
+    impl StaticExchangeClient {
+        fn new(rate: Result<f64, ErrorKind>) -> Arc<Self> {
+            Arc::new(Self {
+                rate,
+                quotes: AtomicUsize::new(0),
+            })
+        }
+
+        fn quotes(&self) -> usize {
+            self.quotes.load(Ordering::SeqCst)
+        }
+    }
+
+    impl ExchangeClient for StaticExchangeClient {
+        fn exchange(&self, _exchange: ExchangeInput, _role: Role) -> Box<Future<Item = Exchange, Error = Error> + Send> {
+            Box::new(self.rate.clone().map(|_| Exchange::default()).map_err(Error::from).into_future())
+        }
+
+        fn rate(&self, input: RateInput, _role: Role) -> Box<Future<Item = Rate, Error = Error> + Send> {
+            self.quotes.fetch_add(1, Ordering::SeqCst);
+            let now = ::chrono::Utc::now().naive_utc();
+            let res = self.rate.clone().map_err(Error::from).map(|rate| Rate {
+                id: input.id,
+                from: input.from,
+                to: input.to,
+                amount: input.amount,
+                amount_currency: input.amount_currency,
+                rate,
+                expiration: now + Duration::seconds(60),
+                created_at: now,
+                updated_at: now,
+                is_stale: false,
+            });
+            Box::new(res.into_future())
+        }
+
+        fn refresh_rate(&self, _input: RateRefreshInput, _role: Role) -> Box<Future<Item = RateRefresh, Error = Error> + Send> {
+            Box::new(future::err(ErrorKind::Internal.into()))
+        }
+    }
+
+    fn provider(name: &str, client: Arc<StaticExchangeClient>) -> ExchangeProvider {
+        ExchangeProvider::new(name.to_string(), format!("http://{}:8000/v1", name), client)
+    }
+
+    fn create_client(providers: Vec<ExchangeProvider>, key_values_repo: Arc<KeyValuesRepoMock>) -> FailoverExchangeClient<DbExecutorMock> {
+        FailoverExchangeClient::with_providers(providers, 0.02, DbExecutorMock::default(), key_values_repo)
+    }
+
+    fn rate_input() -> RateInput {
+        RateInput::new(Currency::Stq, Currency::Eth, Amount::new(100), Currency::Stq)
+    }
+
+    fn exchange_input(rate: &Rate) -> ExchangeInput {
+        ExchangeInput {
+            id: rate.id,
+            from: rate.from,
+            to: rate.to,
+            rate: rate.rate,
+            actual_amount: rate.amount,
+            amount_currency: rate.amount_currency,
+        }
+    }
+
+    #[test]
+    fn test_diverges() {
+        assert!(!diverges(100.0, 101.0, 0.02));
+        assert!(!diverges(100.0, 98.0, 0.02));
+        assert!(diverges(100.0, 103.0, 0.02));
+        assert!(diverges(100.0, ::std::f64::NAN, 0.02));
+    }
+
+    #[test]
+    fn test_failover() {
+        let mut core = Core::new().unwrap();
+        let key_values_repo = Arc::new(KeyValuesRepoMock::default());
+        let primary = StaticExchangeClient::new(Err(ErrorKind::Unavailable));
+        let secondary = StaticExchangeClient::new(Ok(0.5));
+        let client = create_client(
+            vec![provider("primary", primary.clone()), provider("secondary", secondary.clone())],
+            key_values_repo.clone(),
+        );
+        let rate = core.run(client.rate(rate_input(), Role::User)).unwrap();
+        assert_eq!(rate.rate, 0.5);
+        assert_eq!(
+            key_values_repo.get_exchange_gateway(rate.id).unwrap(),
+            Some("http://secondary:8000/v1".to_string())
+        );
+        // unavailable gateway is skipped until it's time to try it again
+        assert!(core.run(client.rate(rate_input(), Role::User)).is_ok());
+        assert_eq!((primary.quotes(), secondary.quotes()), (1, 2));
+        *client.providers[0].unavailable_since.lock().unwrap() =
+            Some(::chrono::Utc::now().naive_utc() - Duration::seconds(HEALTH_RETRY_SECS));
+        assert!(core.run(client.rate(rate_input(), Role::User)).is_ok());
+        assert_eq!((primary.quotes(), secondary.quotes()), (2, 3));
+
+        // exchange goes to the gateway that issued the rate, even after restart
+        let restarted_client = create_client(
+            vec![provider("primary", primary.clone()), provider("secondary", secondary.clone())],
+            key_values_repo.clone(),
+        );
+        let exchange = exchange_input(&rate);
+        assert!(core.run(restarted_client.exchange(exchange.clone(), Role::User)).is_ok());
+        let unknown_exchange = ExchangeInput {
+            id: ExchangeId::generate(),
+            ..exchange
+        };
+        assert!(core.run(restarted_client.exchange(unknown_exchange, Role::User)).is_err());
+
+        // errors other than unavailability are not failed over
+        let primary = StaticExchangeClient::new(Err(ErrorKind::Validation("{}".to_string())));
+        let client = create_client(
+            vec![provider("primary", primary), provider("secondary", secondary.clone())],
+            key_values_repo,
+        );
+        assert!(core.run(client.rate(rate_input(), Role::User)).is_err());
+        assert!(client.providers[0].is_available(::chrono::Utc::now().naive_utc()));
+    }
+
+    #[test]
+    fn test_check_divergence() {
+        let mut core = Core::new().unwrap();
+        let primary = StaticExchangeClient::new(Ok(0.5));
+        let secondary = StaticExchangeClient::new(Ok(0.6));
+        let tertiary = StaticExchangeClient::new(Ok(0.505));
+        let client = create_client(
+            vec![
+                provider("primary", primary.clone()),
+                provider("secondary", secondary.clone()),
+                provider("tertiary", tertiary.clone()),
+            ],
+            Arc::new(KeyValuesRepoMock::default()),
+        );
+        let rate = core.run(primary.rate(rate_input(), Role::User)).unwrap();
+        // nothing to compare with yet
+        assert!(client.check_divergence(0, &rate).is_empty());
+        let other_rate = core.run(secondary.rate(rate_input(), Role::User)).unwrap();
+        let report = client.check_divergence(1, &other_rate);
+        assert_eq!(report.len(), 1);
+        assert_eq!(report[0].gateway, "secondary");
+        assert_eq!(report[0].rate.rate, 0.6);
+        assert_eq!(report[0].other_gateway, "primary");
+        assert_eq!(report[0].other_rate.rate, 0.5);
+        // rates within the threshold and of other pairs are not reported
+        let close_rate = core.run(tertiary.rate(rate_input(), Role::User)).unwrap();
+        let report = client.check_divergence(2, &close_rate);
+        assert_eq!(report.len(), 1);
+        assert_eq!(report[0].other_gateway, "secondary");
+        let other_pair = core
+            .run(secondary.rate(
+                RateInput::new(Currency::Btc, Currency::Eth, Amount::new(100), Currency::Btc),
+                Role::User,
+            ))
+            .unwrap();
+        assert!(client.check_divergence(1, &other_pair).is_empty());
+        // no quotes are requested by the check
+        assert_eq!((primary.quotes(), secondary.quotes(), tertiary.quotes()), (1, 2, 1));
+    }
+}
@@ -1,6 +1,7 @@
 mod error;
+mod failover;
 
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 use failure::Fail;
 use futures::prelude::*;
@@ -11,7 +12,8 @@ use serde::Deserialize;
 use serde_json;
 
 pub use self::error::*;
-use super::HttpClient;
+pub use self::failover::*;
+use super::{gateway_client, HttpClient, Idempotent};
 use config::Config;
 use utils::read_body;
 
@@ -33,9 +35,14 @@ pub struct ExchangeClientImpl {
 
 impl ExchangeClientImpl {
     pub fn new<C: HttpClient>(config: &Config, cli: C) -> Self {
+        Self::with_url(config, cli, "exchange_gateway", config.client.exchange_gateway_url.clone())
+    }
+
+    /// Client of another gateway with the same api and credentials, `gateway` names it in logs
+    pub fn with_url<C: HttpClient>(config: &Config, cli: C, gateway: &str, exchange_gateway_url: String) -> Self {
         Self {
-            cli: Arc::new(cli),
-            exchange_gateway_url: config.client.exchange_gateway_url.clone(),
+            cli: gateway_client(cli, gateway, &config.client.exchange_gateway),
+            exchange_gateway_url,
             exchange_gateway_user_id: config.auth.exchange_gateway_user_id.clone(),
             exchange_gateway_token: config.auth.exchange_gateway_token.clone(),
             exchange_gateway_system_user_id: config.system.exchange_gateway_system_user_id,
@@ -49,6 +56,8 @@ impl ExchangeClientImpl {
         body: String,
         method: Method,
         role: Role,
+        idempotent: bool,
+        idempotency_key: Option<ExchangeId>,
     ) -> impl Future<Item = T, Error = Error> + Send {
         let query = query.to_string();
         let query1 = query.clone();
@@ -63,8 +72,19 @@ impl ExchangeClientImpl {
         };
         builder.uri(url).method(method);
         builder.header("Authorization", format!("Bearer {}", token.raw()));
+        // gateway executes request with the same key once and replies with the result of the first one to the rest
+        if let Some(idempotency_key) = idempotency_key {
+            builder.header("Idempotency-Key", idempotency_key.inner().to_string());
+        }
         builder
             .body(Body::from(body))
+            .map(|mut req| {
+                // such requests are retried on transient errors
+                if idempotent {
+                    req.extensions_mut().insert(Idempotent);
+                }
+                req
+            })
             .map_err(ectx!(ErrorSource::Hyper, ErrorKind::MalformedInput => query3))
             .into_future()
             .and_then(move |req| cli.request(req).map_err(ectx!(convert => query1)))
@@ -80,13 +100,14 @@ impl ExchangeClientImpl {
 impl ExchangeClient for ExchangeClientImpl {
     fn exchange(&self, create_exchange: ExchangeInput, role: Role) -> Box<Future<Item = Exchange, Error = Error> + Send> {
         let client = self.clone();
+        let exchange_id = create_exchange.id;
         Box::new(
             serde_json::to_string(&create_exchange)
                 .map_err(ectx!(ErrorSource::Json, ErrorKind::Internal => create_exchange))
                 .into_future()
                 .and_then(move |body| {
                     let url = "/exchange";
-                    client.exec_query::<Exchange>(&url, body, Method::POST, role)
+                    client.exec_query::<Exchange>(&url, body, Method::POST, role, true, Some(exchange_id))
                 }),
         )
     }
@@ -99,7 +120,7 @@ impl ExchangeClient for ExchangeClientImpl {
                 .into_future()
                 .and_then(move |body| {
                     let url = "/rate";
-                    client.exec_query::<Rate>(&url, body, Method::POST, role)
+                    client.exec_query::<Rate>(&url, body, Method::POST, role, true, None)
                 }),
         )
     }
@@ -112,32 +133,49 @@ impl ExchangeClient for ExchangeClientImpl {
                 .into_future()
                 .and_then(move |body| {
                     let url = "/rate/refresh";
-                    client.exec_query::<RateRefresh>(&url, body, Method::POST, role)
+                    client.exec_query::<RateRefresh>(&url, body, Method::POST, role, false, None)
                 }),
         )
     }
 }
 
-#[derive(Default)]
-pub struct ExchangeClientMock;
+/// Quotes the same `rate` for every pair, zero is the rate of unknown pair. Exchanges are made
+/// at any rate and remembered, so that tests can check them. Replies echo exchange id, unless
+/// `replied_id` is set
+#[derive(Clone, Default)]
+pub struct ExchangeClientMock {
+    pub rate: Arc<Mutex<f64>>,
+    pub exchanges: Arc<Mutex<Vec<ExchangeInput>>>,
+    pub replied_id: Arc<Mutex<Option<ExchangeId>>>,
+}
 
 impl ExchangeClient for ExchangeClientMock {
-    fn exchange(&self, _exchange: ExchangeInput, _role: Role) -> Box<Future<Item = Exchange, Error = Error> + Send> {
-        Box::new(Ok(Exchange::default()).into_future())
+    fn exchange(&self, exchange: ExchangeInput, _role: Role) -> Box<Future<Item = Exchange, Error = Error> + Send> {
+        self.exchanges.lock().unwrap().push(exchange.clone());
+        Box::new(
+            Ok(Exchange {
+                id: Some(self.replied_id.lock().unwrap().unwrap_or(exchange.id)),
+                from: exchange.from,
+                to: exchange.to,
+                amount: exchange.actual_amount,
+            })
+            .into_future(),
+        )
     }
 
-    fn rate(&self, _exchange: RateInput, _role: Role) -> Box<Future<Item = Rate, Error = Error> + Send> {
+    fn rate(&self, exchange: RateInput, _role: Role) -> Box<Future<Item = Rate, Error = Error> + Send> {
         Box::new(
             Ok(Rate {
                 expiration: ::chrono::Utc::now().naive_utc(),
                 created_at: ::chrono::Utc::now().naive_utc(),
                 updated_at: ::chrono::Utc::now().naive_utc(),
-                amount_currency: Default::default(),
-                id: Default::default(),
-                from: Default::default(),
-                to: Default::default(),
-                amount: Default::default(),
-                rate: Default::default(),
+                amount_currency: exchange.amount_currency,
+                id: exchange.id,
+                from: exchange.from,
+                to: exchange.to,
+                amount: exchange.amount,
+                rate: *self.rate.lock().unwrap(),
+                is_stale: false,
             })
             .into_future(),
         )
@@ -156,6 +194,7 @@ impl ExchangeClient for ExchangeClientMock {
                     to: Default::default(),
                     amount: Default::default(),
                     rate: Default::default(),
+                    is_stale: false,
                 },
                 is_new_rate: false,
             })
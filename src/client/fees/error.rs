@@ -2,6 +2,8 @@ use failure::{Backtrace, Context, Fail};
 use std::fmt;
 use std::fmt::Display;
 
+use client::http_client::error::ErrorKind as HttpClientErrorKind;
+
 #[derive(Debug)]
 pub struct Error {
     inner: Context<ErrorKind>,
@@ -16,6 +18,10 @@ pub enum ErrorKind {
     Unauthorized,
     #[fail(display = "fees client error - internal error")]
     Internal,
+    #[fail(display = "fees client error - gateway didn't respond in time")]
+    GatewayTimeout,
+    #[fail(display = "fees client error - gateway is unavailable")]
+    Unavailable,
 }
 
 #[allow(dead_code)]
@@ -30,3 +36,13 @@ pub enum ErrorSource {
 }
 
 derive_error_impls!();
+
+impl From<HttpClientErrorKind> for ErrorKind {
+    fn from(err: HttpClientErrorKind) -> Self {
+        match err {
+            HttpClientErrorKind::GatewayTimeout => ErrorKind::GatewayTimeout,
+            HttpClientErrorKind::CircuitOpen => ErrorKind::Unavailable,
+            _ => ErrorKind::Internal,
+        }
+    }
+}
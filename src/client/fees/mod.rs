@@ -13,7 +13,7 @@ use serde_json;
 
 pub use self::error::*;
 use self::responses::*;
-use super::HttpClient;
+use super::{gateway_client, HttpClient};
 use config::Config;
 use utils::read_body;
 
@@ -36,7 +36,7 @@ pub struct FeesClientImpl {
 impl FeesClientImpl {
     pub fn new<C: HttpClient>(config: &Config, cli: C) -> Self {
         Self {
-            cli: Arc::new(cli),
+            cli: gateway_client(cli, "fees", &config.client.fees),
             btc_fees_collect_url: config.fees_options.btc_fees_collect_url.clone(),
             eth_fees_collect_url: config.fees_options.eth_fees_collect_url.clone(),
             btc_transaction_size: config.fees_options.btc_transaction_size,
@@ -57,7 +57,7 @@ impl FeesClientImpl {
             .body(Body::empty())
             .map_err(ectx!(ErrorSource::Hyper, ErrorKind::MalformedInput))
             .into_future()
-            .and_then(move |req| cli.request(req).map_err(ectx!(ErrorKind::Internal => query1)))
+            .and_then(move |req| cli.request(req).map_err(ectx!(convert => query1)))
             .and_then(move |resp| read_body(resp.into_body()).map_err(ectx!(ErrorSource::Hyper, ErrorKind::Internal => query2)))
             .and_then(|bytes| {
                 let bytes_clone = bytes.clone();
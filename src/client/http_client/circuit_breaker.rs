@@ -0,0 +1,151 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use failure::Fail;
+use futures::future;
+use futures::prelude::*;
+use hyper::{Body, Request, Response};
+
+use super::error::*;
+use super::HttpClient;
+
+/// Http client decorator, that stops sending requests to the gateway for `cooldown` after
+/// `failures_to_open` consecutive failures, failing fast with `CircuitOpen` instead.
+/// After cooldown requests are let through again, the circuit is closed on the first success
+/// and opened again on the first failure.
+#[derive(Clone)]
+pub struct CircuitBreakerHttpClient<C: HttpClient> {
+    cli: Arc<C>,
+    gateway: String,
+    state: Arc<Mutex<CircuitState>>,
+}
+
+impl<C: HttpClient> CircuitBreakerHttpClient<C> {
+    pub fn new(cli: C, gateway: &str, failures_to_open: u32, cooldown: Duration) -> Self {
+        Self {
+            cli: Arc::new(cli),
+            gateway: gateway.to_string(),
+            state: Arc::new(Mutex::new(CircuitState::new(failures_to_open, cooldown))),
+        }
+    }
+
+    fn guard<F>(&self, send: F) -> Box<Future<Item = Response<Body>, Error = Error> + Send>
+    where
+        F: FnOnce() -> Box<Future<Item = Response<Body>, Error = Error> + Send>,
+    {
+        let gateway = self.gateway.clone();
+        if !self.state.lock().unwrap().is_closed(Instant::now()) {
+            return Box::new(future::err(
+                ectx!(err ErrorSource::CircuitBreaker, ErrorKind::CircuitOpen => gateway),
+            ));
+        }
+        let state = self.state.clone();
+        Box::new(send().then(move |res| {
+            let success = match res {
+                Ok(_) => true,
+                Err(ref e) => !is_gateway_failure(e.kind()),
+            };
+            let opened = state.lock().unwrap().record(success, Instant::now());
+            if opened {
+                warn!("Circuit breaker for {} gateway is open after consecutive failures", gateway);
+            }
+            res
+        }))
+    }
+}
+
+impl<C: HttpClient> HttpClient for CircuitBreakerHttpClient<C> {
+    fn request(&self, req: Request<Body>) -> Box<Future<Item = Response<Body>, Error = Error> + Send> {
+        let cli = self.cli.clone();
+        self.guard(move || cli.request(req))
+    }
+    fn get(&self, uri: String) -> Box<Future<Item = Response<Body>, Error = Error> + Send> {
+        let cli = self.cli.clone();
+        self.guard(move || cli.get(uri))
+    }
+}
+
+/// Errors meaning that gateway is down or overloaded, client errors like
+/// bad request say nothing about gateway health
+fn is_gateway_failure(kind: ErrorKind) -> bool {
+    match kind {
+        ErrorKind::InternalServer
+        | ErrorKind::BadGateway
+        | ErrorKind::GatewayTimeout
+        | ErrorKind::UnknownServerError
+        | ErrorKind::Connection
+        | ErrorKind::Internal => true,
+        _ => false,
+    }
+}
+
+struct CircuitState {
+    failures_to_open: u32,
+    cooldown: Duration,
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl CircuitState {
+    fn new(failures_to_open: u32, cooldown: Duration) -> Self {
+        Self {
+            failures_to_open,
+            cooldown,
+            consecutive_failures: 0,
+            open_until: None,
+        }
+    }
+
+    fn is_closed(&self, now: Instant) -> bool {
+        match self.open_until {
+            Some(open_until) => open_until <= now,
+            None => true,
+        }
+    }
+
+    /// Returns true if the circuit has just been opened
+    fn record(&mut self, success: bool, now: Instant) -> bool {
+        if success {
+            self.consecutive_failures = 0;
+            self.open_until = None;
+            return false;
+        }
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        if self.consecutive_failures < self.failures_to_open {
+            return false;
+        }
+        let was_closed = self.open_until.is_none();
+        self.open_until = Some(now + self.cooldown);
+        was_closed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_circuit_state() {
+        let cooldown = Duration::from_secs(10);
+        let mut state = CircuitState::new(3, cooldown);
+        let now = Instant::now();
+        assert!(!state.record(false, now));
+        assert!(!state.record(false, now));
+        // success resets consecutive failures
+        assert!(!state.record(true, now));
+        assert!(!state.record(false, now));
+        assert!(!state.record(false, now));
+        assert!(state.is_closed(now));
+        assert!(state.record(false, now));
+        assert!(!state.is_closed(now));
+        // after cooldown a single failure opens the circuit again
+        let later = now + cooldown;
+        assert!(state.is_closed(later));
+        assert!(!state.record(false, later));
+        assert!(!state.is_closed(later));
+        // and a single success closes it
+        let even_later = later + cooldown;
+        assert!(!state.record(true, even_later));
+        assert!(state.is_closed(even_later));
+    }
+}
@@ -30,6 +30,10 @@ pub enum ErrorKind {
     Internal,
     #[fail(display = "http client error - bad request")]
     Validation(String),
+    #[fail(display = "http client error - circuit is open, gateway is failing")]
+    CircuitOpen,
+    #[fail(display = "http client error - failed to connect or send request")]
+    Connection,
 }
 
 #[allow(dead_code)]
@@ -39,6 +43,12 @@ pub enum ErrorSource {
     Hyper,
     #[fail(display = "http client source - server returned response with error")]
     Server,
+    #[fail(display = "http client source - server didn't respond in time")]
+    Timeout,
+    #[fail(display = "http client source - error in tokio timer")]
+    Timer,
+    #[fail(display = "http client source - circuit breaker")]
+    CircuitBreaker,
 }
 
 derive_error_impls!();
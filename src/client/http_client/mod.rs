@@ -1,15 +1,25 @@
+mod circuit_breaker;
 pub mod error;
+mod retry;
 
-use config::Config;
+use std::sync::Arc;
+use std::time::Duration;
+
+use config::{Config, GatewayOptions};
 use failure::Fail;
 use futures::future::{self, Either};
 use futures::prelude::*;
 use hyper;
+use hyper::header::HeaderValue;
 use hyper::{client::HttpConnector, Body, Request, Response};
 use hyper_tls::HttpsConnector;
 use log::{self, Level};
+use tokio::timer::Timeout;
 
+pub use self::circuit_breaker::*;
 pub use self::error::*;
+pub use self::retry::*;
+use request_id::{self, REQUEST_ID_HEADER};
 use utils::read_body;
 
 pub trait HttpClient: Send + Sync + 'static {
@@ -17,6 +27,21 @@ pub trait HttpClient: Send + Sync + 'static {
     fn get(&self, uri: String) -> Box<Future<Item = Response<Body>, Error = Error> + Send>;
 }
 
+/// Wraps http client with layers configured for requests to a single gateway.
+/// Timed out requests count as failures for circuit breaker, and every retry
+/// is a separate attempt for both of them.
+pub fn gateway_client<C: HttpClient>(cli: C, gateway: &str, options: &GatewayOptions) -> Arc<HttpClient> {
+    let cli = TimeoutHttpClient::new(cli, Duration::from_secs(options.timeout_secs));
+    let cli = CircuitBreakerHttpClient::new(
+        cli,
+        gateway,
+        options.failures_to_open_circuit,
+        Duration::from_secs(options.circuit_cooldown_secs),
+    );
+    let cli = RetryHttpClient::new(cli, options.retries, options.retry_base_delay_ms);
+    Arc::new(cli)
+}
+
 #[derive(Clone)]
 pub struct HttpClientImpl {
     cli: hyper::Client<HttpsConnector<HttpConnector>>,
@@ -32,8 +57,9 @@ impl HttpClientImpl {
 }
 
 impl HttpClient for HttpClientImpl {
-    fn request(&self, req: Request<Body>) -> Box<Future<Item = Response<Body>, Error = Error> + Send> {
+    fn request(&self, mut req: Request<Body>) -> Box<Future<Item = Response<Body>, Error = Error> + Send> {
         let cli = self.cli.clone();
+        add_request_id_header(&mut req, request_id::current());
         let level = log::max_level();
         let fut = if level == Level::Debug || level == Level::Trace {
             let (parts, body) = req.into_parts();
@@ -49,7 +75,7 @@ impl HttpClient for HttpClientImpl {
                             String::from_utf8(body.clone()).ok()
                         );
                         let req = Request::from_parts(parts, body.into());
-                        cli.request(req).map_err(ectx!(ErrorSource::Hyper, ErrorKind::Internal))
+                        cli.request(req).map_err(ectx!(ErrorSource::Hyper, ErrorKind::Connection))
                     })
                     .and_then(|resp| {
                         let (parts, body) = resp.into_parts();
@@ -68,7 +94,7 @@ impl HttpClient for HttpClientImpl {
                     }),
             )
         } else {
-            Either::B(cli.request(req).map_err(ectx!(ErrorSource::Hyper, ErrorKind::Internal)))
+            Either::B(cli.request(req).map_err(ectx!(ErrorSource::Hyper, ErrorKind::Connection)))
         };
 
         Box::new(fut.and_then(|resp| {
@@ -95,12 +121,18 @@ impl HttpClient for HttpClientImpl {
     }
     fn get(&self, uri: String) -> Box<Future<Item = Response<Body>, Error = Error> + Send> {
         let cli = self.cli.clone();
+        let request_id = request_id::current();
         Box::new(
             uri.clone()
                 .parse()
                 .map_err(|_| ectx!(err ErrorSource::Hyper, ErrorKind::Internal => uri))
                 .into_future()
-                .and_then(move |uri| cli.get(uri).map_err(|_| ectx!(err ErrorSource::Hyper, ErrorKind::Internal)))
+                .and_then(move |uri| {
+                    let mut req = Request::new(Body::empty());
+                    *req.uri_mut() = uri;
+                    add_request_id_header(&mut req, request_id);
+                    cli.request(req).map_err(ectx!(ErrorSource::Hyper, ErrorKind::Connection))
+                })
                 .and_then(|resp| {
                     if resp.status().is_client_error() || resp.status().is_server_error() {
                         match resp.status().as_u16() {
@@ -119,3 +151,55 @@ impl HttpClient for HttpClientImpl {
         )
     }
 }
+
+fn add_request_id_header(req: &mut Request<Body>, request_id: Option<String>) {
+    if let Some(value) = request_id.and_then(|request_id| HeaderValue::from_str(&request_id).ok()) {
+        req.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+}
+
+/// Http client decorator, that fails with `GatewayTimeout` unless the whole response including body
+/// is received in time. Otherwise hanging upstream keeps db transactions of the caller open.
+#[derive(Clone)]
+pub struct TimeoutHttpClient<C: HttpClient> {
+    cli: Arc<C>,
+    timeout: Duration,
+}
+
+impl<C: HttpClient> TimeoutHttpClient<C> {
+    pub fn new(cli: C, timeout: Duration) -> Self {
+        Self {
+            cli: Arc::new(cli),
+            timeout,
+        }
+    }
+
+    fn read_response(
+        &self,
+        fut: Box<Future<Item = Response<Body>, Error = Error> + Send>,
+    ) -> Box<Future<Item = Response<Body>, Error = Error> + Send> {
+        let timeout = self.timeout;
+        let fut = fut.and_then(|resp| {
+            let (parts, body) = resp.into_parts();
+            read_body(body)
+                .map_err(ectx!(ErrorSource::Hyper, ErrorKind::Internal))
+                .map(move |body| Response::from_parts(parts, body.into()))
+        });
+        Box::new(Timeout::new(fut, timeout).map_err(move |e| {
+            if e.is_elapsed() {
+                ectx!(err ErrorSource::Timeout, ErrorKind::GatewayTimeout => timeout)
+            } else {
+                e.into_inner().unwrap_or_else(|| ectx!(err ErrorSource::Timer, ErrorKind::Internal))
+            }
+        }))
+    }
+}
+
+impl<C: HttpClient> HttpClient for TimeoutHttpClient<C> {
+    fn request(&self, req: Request<Body>) -> Box<Future<Item = Response<Body>, Error = Error> + Send> {
+        self.read_response(self.cli.request(req))
+    }
+    fn get(&self, uri: String) -> Box<Future<Item = Response<Body>, Error = Error> + Send> {
+        self.read_response(self.cli.get(uri))
+    }
+}
@@ -0,0 +1,142 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use failure::Fail;
+use futures::future::{self, Either, Loop};
+use futures::prelude::*;
+use hyper::{Body, HeaderMap, Method, Request, Response, Uri, Version};
+use rand::prelude::*;
+use tokio::timer::Delay;
+
+use super::error::*;
+use super::HttpClient;
+use utils::read_body;
+
+/// Marks POST request as safe to send several times, e.g. signing or getting a rate quote.
+/// Put it into request extensions.
+#[derive(Debug, Clone, Copy)]
+pub struct Idempotent;
+
+/// Http client decorator, that retries idempotent requests failed with transient errors
+/// with exponential backoff. GET requests are considered idempotent, others are retried
+/// only if marked with `Idempotent`.
+#[derive(Clone)]
+pub struct RetryHttpClient<C: HttpClient> {
+    cli: Arc<C>,
+    retries: u32,
+    base_delay_ms: u64,
+}
+
+impl<C: HttpClient> RetryHttpClient<C> {
+    pub fn new(cli: C, retries: u32, base_delay_ms: u64) -> Self {
+        Self {
+            cli: Arc::new(cli),
+            retries,
+            base_delay_ms,
+        }
+    }
+}
+
+impl<C: HttpClient> HttpClient for RetryHttpClient<C> {
+    fn request(&self, req: Request<Body>) -> Box<Future<Item = Response<Body>, Error = Error> + Send> {
+        if *req.method() != Method::GET && req.extensions().get::<Idempotent>().is_none() {
+            return self.cli.request(req);
+        }
+        // body is a stream, so it's read once to be sent with every attempt
+        let (parts, body) = req.into_parts();
+        let cli = self.cli.clone();
+        let retries = self.retries;
+        let base_delay_ms = self.base_delay_ms;
+        Box::new(
+            read_body(body)
+                .map_err(ectx!(ErrorSource::Hyper, ErrorKind::Internal))
+                .and_then(move |body| {
+                    let template = RequestTemplate {
+                        method: parts.method,
+                        uri: parts.uri,
+                        version: parts.version,
+                        headers: parts.headers,
+                        body,
+                    };
+                    retry(retries, base_delay_ms, move || cli.request(template.build()))
+                }),
+        )
+    }
+    fn get(&self, uri: String) -> Box<Future<Item = Response<Body>, Error = Error> + Send> {
+        let cli = self.cli.clone();
+        retry(self.retries, self.base_delay_ms, move || cli.get(uri.clone()))
+    }
+}
+
+fn retry<F>(retries: u32, base_delay_ms: u64, send: F) -> Box<Future<Item = Response<Body>, Error = Error> + Send>
+where
+    F: Fn() -> Box<Future<Item = Response<Body>, Error = Error> + Send> + Send + 'static,
+{
+    Box::new(future::loop_fn(0, move |attempt| {
+        send().then(move |res| match res {
+            Err(ref e) if attempt < retries && is_transient(e.kind()) => {
+                let delay = backoff_ms(base_delay_ms, attempt);
+                warn!("Request failed at attempt {}, retrying in {} ms: {}", attempt + 1, delay, e);
+                Either::A(
+                    Delay::new(Instant::now() + Duration::from_millis(delay))
+                        .then(move |_| Ok::<Loop<Response<Body>, u32>, Error>(Loop::Continue(attempt + 1))),
+                )
+            }
+            res => Either::B(future::result(res.map(Loop::Break))),
+        })
+    }))
+}
+
+struct RequestTemplate {
+    method: Method,
+    uri: Uri,
+    version: Version,
+    headers: HeaderMap,
+    body: Vec<u8>,
+}
+
+impl RequestTemplate {
+    fn build(&self) -> Request<Body> {
+        let mut req = Request::new(Body::from(self.body.clone()));
+        *req.method_mut() = self.method.clone();
+        *req.uri_mut() = self.uri.clone();
+        *req.version_mut() = self.version;
+        *req.headers_mut() = self.headers.clone();
+        req
+    }
+}
+
+/// Errors that are likely to disappear on their own, e.g. network blips or restarting gateway
+fn is_transient(kind: ErrorKind) -> bool {
+    match kind {
+        ErrorKind::BadGateway | ErrorKind::GatewayTimeout | ErrorKind::Connection => true,
+        _ => false,
+    }
+}
+
+/// Delay doubles with every attempt, jitter spreads retries of concurrent requests
+fn backoff_ms(base_delay_ms: u64, attempt: u32) -> u64 {
+    let max_delay_ms = base_delay_ms.saturating_mul(1 << attempt.min(16));
+    thread_rng().gen_range(max_delay_ms / 2, max_delay_ms.saturating_add(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        for attempt in 0..5 {
+            let max_delay_ms = 100 * (1 << attempt);
+            let delay = backoff_ms(100, attempt);
+            assert!(
+                delay >= max_delay_ms / 2 && delay <= max_delay_ms,
+                "attempt {}, delay {}",
+                attempt,
+                delay
+            );
+        }
+        assert_eq!(backoff_ms(0, 3), 0);
+        assert!(backoff_ms(u64::max_value(), 100) >= u64::max_value() / 2);
+    }
+}
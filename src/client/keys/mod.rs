@@ -13,7 +13,7 @@ use serde_json;
 
 pub use self::error::*;
 use self::responses::*;
-use super::HttpClient;
+use super::{gateway_client, HttpClient, Idempotent};
 use config::Config;
 use utils::read_body;
 
@@ -47,7 +47,7 @@ pub struct KeysClientImpl {
 impl KeysClientImpl {
     pub fn new<C: HttpClient>(config: &Config, cli: C) -> Self {
         Self {
-            cli: Arc::new(cli),
+            cli: gateway_client(cli, "keys", &config.client.keys),
             keys_url: config.client.keys_url.clone(),
             keys_user_id: config.auth.keys_user_id.clone(),
             keys_token: config.auth.keys_token.clone(),
@@ -64,6 +64,7 @@ impl KeysClientImpl {
         body: String,
         method: Method,
         role: Role,
+        idempotent: bool,
     ) -> impl Future<Item = T, Error = Error> + Send {
         let query = query.to_string();
         let query1 = query.clone();
@@ -80,9 +81,16 @@ impl KeysClientImpl {
         builder.header("Authorization", format!("Bearer {}", token.raw()));
         builder
             .body(Body::from(body))
+            .map(|mut req| {
+                // such requests are retried on transient errors
+                if idempotent {
+                    req.extensions_mut().insert(Idempotent);
+                }
+                req
+            })
             .map_err(ectx!(ErrorSource::Hyper, ErrorKind::MalformedInput => query3))
             .into_future()
-            .and_then(move |req| cli.request(req).map_err(ectx!(ErrorKind::Internal => query1)))
+            .and_then(move |req| cli.request(req).map_err(ectx!(convert => query1)))
             .and_then(move |resp| read_body(resp.into_body()).map_err(ectx!(ErrorSource::Hyper, ErrorKind::Internal => query2)))
             .and_then(|bytes| {
                 let bytes_clone = bytes.clone();
@@ -110,7 +118,7 @@ impl KeysClient for KeysClientImpl {
                 .and_then(move |body| {
                     let url = format!("/users/{}/keys", user_id);
                     client
-                        .exec_query::<CreateAccountAddressResponse>(&url, body, Method::POST, role)
+                        .exec_query::<CreateAccountAddressResponse>(&url, body, Method::POST, role, false)
                         .map(|resp_data| resp_data.blockchain_address)
                 }),
         )
@@ -123,7 +131,7 @@ impl KeysClient for KeysClientImpl {
                 .into_future()
                 .and_then(move |body| {
                     client
-                        .exec_query::<CreateBlockchainTxResponse>("/approve", body, Method::POST, role)
+                        .exec_query::<CreateBlockchainTxResponse>("/approve", body, Method::POST, role, true)
                         .map(|resp_data| resp_data.raw)
                 }),
         )
@@ -140,7 +148,7 @@ impl KeysClient for KeysClientImpl {
                 .into_future()
                 .and_then(move |body| {
                     client
-                        .exec_query::<CreateBlockchainTxResponse>("/transactions", body, Method::POST, role)
+                        .exec_query::<CreateBlockchainTxResponse>("/transactions", body, Method::POST, role, true)
                         .map(|resp_data| resp_data.raw)
                 }),
         )
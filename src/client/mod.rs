@@ -3,9 +3,11 @@ pub mod exchange;
 pub mod fees;
 pub mod http_client;
 pub mod keys;
+pub mod vault;
 
 pub use self::blockchain_gateway::*;
 pub use self::exchange::*;
 pub use self::fees::*;
 pub use self::http_client::*;
 pub use self::keys::*;
+pub use self::vault::*;
@@ -0,0 +1,82 @@
+mod error;
+mod responses;
+
+use std::sync::Arc;
+
+use failure::Fail;
+use futures::prelude::*;
+use hyper::Method;
+use hyper::{Body, Request};
+use serde::Deserialize;
+use serde_json;
+
+pub use self::error::*;
+use self::responses::*;
+use super::HttpClient;
+use config::{Vault, VaultSecrets};
+use models::*;
+use utils::read_body;
+
+pub trait VaultClient: Send + Sync + 'static {
+    /// Secrets stored at configured path of kv secrets engine
+    fn get_secrets(&self) -> Box<Future<Item = VaultSecrets, Error = Error> + Send>;
+    /// Extends lease of our vault token, returns new lease duration in seconds
+    fn renew_token(&self) -> Box<Future<Item = u64, Error = Error> + Send>;
+}
+
+#[derive(Clone)]
+pub struct VaultClientImpl {
+    cli: Arc<HttpClient>,
+    url: String,
+    token: AuthenticationToken,
+    secrets_path: String,
+}
+
+impl VaultClientImpl {
+    pub fn new<C: HttpClient>(vault: &Vault, cli: C) -> Self {
+        Self {
+            cli: Arc::new(cli),
+            url: vault.url.clone(),
+            token: vault.token.clone(),
+            secrets_path: vault.secrets_path.clone(),
+        }
+    }
+
+    fn exec_query<T: for<'de> Deserialize<'de> + Send>(&self, query: &str, method: Method) -> impl Future<Item = T, Error = Error> + Send {
+        let query = query.to_string();
+        let query1 = query.clone();
+        let query2 = query.clone();
+        let query3 = query.clone();
+        let cli = self.cli.clone();
+        let mut builder = Request::builder();
+        let url = format!("{}{}", self.url, query);
+        builder.uri(url).method(method);
+        builder.header("X-Vault-Token", self.token.raw());
+        builder
+            .body(Body::empty())
+            .map_err(ectx!(ErrorSource::Hyper, ErrorKind::MalformedInput => query3))
+            .into_future()
+            .and_then(move |req| cli.request(req).map_err(ectx!(ErrorKind::Internal => query1)))
+            .and_then(move |resp| read_body(resp.into_body()).map_err(ectx!(ErrorSource::Hyper, ErrorKind::Internal => query2)))
+            .and_then(|bytes| {
+                let bytes_clone = bytes.clone();
+                String::from_utf8(bytes).map_err(ectx!(ErrorSource::Utf8, ErrorKind::Internal => bytes_clone))
+            })
+            // response body is not logged, since it contains secrets
+            .and_then(|string| serde_json::from_str::<T>(&string).map_err(ectx!(ErrorSource::Json, ErrorKind::Internal)))
+    }
+}
+
+impl VaultClient for VaultClientImpl {
+    fn get_secrets(&self) -> Box<Future<Item = VaultSecrets, Error = Error> + Send> {
+        let query = format!("/v1/{}", self.secrets_path.trim_matches('/'));
+        Box::new(self.exec_query::<SecretResponse>(&query, Method::GET).map(|resp| resp.data.data))
+    }
+
+    fn renew_token(&self) -> Box<Future<Item = u64, Error = Error> + Send> {
+        Box::new(
+            self.exec_query::<RenewResponse>("/v1/auth/token/renew-self", Method::POST)
+                .map(|resp| resp.auth.lease_duration),
+        )
+    }
+}
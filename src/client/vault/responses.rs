@@ -0,0 +1,22 @@
+use config::VaultSecrets;
+
+// Response of kv secrets engine (version 2)
+#[derive(Debug, Deserialize, Clone)]
+pub struct SecretResponse {
+    pub data: SecretData,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct SecretData {
+    pub data: VaultSecrets,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct RenewResponse {
+    pub auth: RenewAuth,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct RenewAuth {
+    pub lease_duration: u64,
+}
@@ -5,10 +5,13 @@ extern crate failure;
 extern crate futures;
 #[macro_use]
 extern crate diesel;
+#[macro_use]
+extern crate diesel_migrations;
 extern crate env_logger;
 extern crate futures_cpupool;
 extern crate gelf;
 extern crate hyper;
+extern crate libc;
 extern crate r2d2;
 extern crate serde;
 #[macro_use]
@@ -49,52 +52,102 @@ mod models;
 mod prelude;
 mod rabbit;
 mod repos;
+mod request_id;
 mod schema;
 mod sentry_integration;
 mod services;
 mod utils;
 
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
-use diesel::pg::PgConnection;
-use diesel::r2d2::ConnectionManager;
+use chrono::NaiveDate;
+use diesel::Connection;
 use futures::future::{self, Either};
 use futures_cpupool::CpuPool;
 use tokio::prelude::*;
 use tokio::runtime::Runtime;
-use tokio::timer::{Delay, Timeout};
-use tokio_core::reactor::Core;
+use tokio::timer::{Interval, Timeout};
 
 use self::client::HttpClientImpl;
 use self::models::*;
 use self::prelude::*;
 use self::repos::{
-    AccountsRepo, AccountsRepoImpl, BlockchainTransactionsRepo, BlockchainTransactionsRepoImpl, DbExecutor, DbExecutorImpl,
-    Error as ReposError, ErrorKind as ReposErrorKind, Isolation, KeyValuesRepoImpl, PendingBlockchainTransactionsRepo,
-    PendingBlockchainTransactionsRepoImpl, SeenHashesRepoImpl, StrangeBlockchainTransactionsRepoImpl, TransactionsRepo,
-    TransactionsRepoImpl, UsersRepo, UsersRepoImpl,
+    AccountsRepo, AccountsRepoImpl, BlockchainTransactionsRepoImpl, DbExecutor, DbExecutorImpl, Error as ReposError,
+    ErrorKind as ReposErrorKind, Isolation, KeyValuesRepoImpl, MonitoredPool, PendingBlockchainTransactionsRepo,
+    PendingBlockchainTransactionsRepoImpl, PendingDepositsRepoImpl, QuarantinedMessagesRepo, QuarantinedMessagesRepoImpl, QueryStats,
+    QueuedWithdrawalsRepoImpl, RateLocksRepoImpl, SeenHashesRepoImpl, SmallDepositsRepoImpl, StrangeBlockchainTransactionsRepoImpl,
+    TransactionsRepo, TransactionsRepoImpl, UsersRepo, UsersRepoImpl, WithdrawalAddressesRepoImpl,
+};
+use client::{BlockchainClient, BlockchainClientImpl, FailoverExchangeClient, KeysClient, KeysClientImpl, VaultClient, VaultClientImpl};
+use config::{Config, SharedConfig, System};
+use rabbit::{
+    delivery_attempts, delivery_trace, Acker, FailedDeliveryAction, KeyedSequencer, ManageRabbitConnection, RabbitConnectionManager,
+    RabbitStats, Topology, TransactionConsumerImpl, TransactionPublisher, TransactionPublisherImpl,
+    DEFAULT_RABBIT_HEALTH_CHECK_INTERVAL_SECS,
+};
+use request_id::WithRequestId;
+use services::{
+    group_transactions, message_addresses, parse_transaction, AuthServiceImpl, BalanceAlertsService, BalanceAlertsServiceImpl,
+    BlockchainFetcher, ColdStorageService, ColdStorageServiceImpl, ConverterService, ConverterServiceImpl, Error as ServiceError,
+    ErrorKind as ServiceErrorKind, FeesTopUpService, FeesTopUpServiceImpl, LiquidityService, LiquidityServiceImpl, MetricsService,
+    MetricsServiceImpl, PendingDepositsService, PendingDepositsServiceImpl, RatesServiceImpl, RepairService, RepairServiceImpl,
+    SeenHashesService, SeenHashesServiceImpl, SystemAccountsServiceImpl, SystemServiceImpl, TransactionsService, TransactionsServiceImpl,
 };
-use client::{BlockchainClientImpl, KeysClient, KeysClientImpl};
-use config::{Config, System};
-use rabbit::{RabbitConnectionManager, TransactionConsumerImpl, TransactionPublisherImpl};
-use services::BlockchainFetcher;
-use utils::log_error;
+use utils::{format_error, log_error};
+
+// rabbit doesn't deliver more than prefetch count of unacked messages anyway
+pub const MESSAGES_CONCURRENCY: usize = 10;
+pub const EXPORT_BATCH_SIZE: i64 = 100;
+pub const SEED_RUN_MODES: &[&str] = &["development", "test", "sandbox"];
+pub const SIGHUP_CHECK_INTERVAL: u64 = 1000;
+
+static SIGHUP_RECEIVED: AtomicBool = ATOMIC_BOOL_INIT;
+
+extern "C" fn handle_sighup(_: libc::c_int) {
+    // only async-signal-safe operations are allowed here, config is reloaded by the checking task
+    SIGHUP_RECEIVED.store(true, Ordering::SeqCst);
+}
 
-pub const DELAY_BEFORE_NACK: u64 = 1000;
-pub const DELAY_BEFORE_RECONNECT: u64 = 1000;
+embed_migrations!("migrations");
 
 pub fn hello() {
     println!("Hello world");
 }
 
+pub fn check_config() {
+    // logger from config is not available yet, so errors are logged to stderr
+    let _ = env_logger::try_init();
+    let config = match Config::new() {
+        Ok(config) => config,
+        Err(e) => {
+            log_error(&e);
+            std::process::exit(1);
+        }
+    };
+    let errors = config.validate();
+    if errors.is_empty() {
+        println!("Config is ok");
+    } else {
+        for error in errors.iter() {
+            println!("{}", error);
+        }
+        println!("Found {} problems in config", errors.len());
+        std::process::exit(1);
+    }
+}
+
 pub fn print_config() {
-    println!("Parsed config: {:?}", get_config());
+    println!("Parsed config: {:?}", get_config().unwrap_or_else(exit_on_config_error).redacted());
 }
 
 pub fn start_server() {
-    let config = get_config();
+    let config = get_config().unwrap_or_else(exit_on_config_error);
     // Prepare sentry integration
     let _sentry = sentry_integration::init(config.sentry.as_ref());
     // Prepare logger
@@ -104,23 +157,28 @@ pub fn start_server() {
 
     let db_pool = create_db_pool(&config_clone);
     let cpu_pool = CpuPool::new(config_clone.rabbit.thread_pool_size);
-    let db_executor = DbExecutorImpl::new(db_pool, cpu_pool);
-    let fees_accounts_ids = vec![
-        config.system.btc_fees_account_id,
-        config.system.eth_fees_account_id,
-        config.system.stq_fees_account_id,
-    ];
-    let transactions_repo = Arc::new(TransactionsRepoImpl::new(config_clone.system.system_user_id, fees_accounts_ids));
+    // repo calls of consumers and api are reported together in metrics
+    let query_stats = Arc::new(QueryStats::new(&config_clone.database));
+    let db_executor = DbExecutorImpl::new(db_pool, cpu_pool).with_query_stats(query_stats.clone());
+    let transactions_repo = Arc::new(TransactionsRepoImpl::new(config_clone.system.system_user_id));
     let accounts_repo = Arc::new(AccountsRepoImpl);
     let seen_hashes_repo = Arc::new(SeenHashesRepoImpl);
     let blockchain_transactions_repo = Arc::new(BlockchainTransactionsRepoImpl);
-    let users_repo = UsersRepoImpl::new(config.system.system_user_id);
     let strange_blockchain_transactions_repo = Arc::new(StrangeBlockchainTransactionsRepoImpl);
+    let small_deposits_repo = Arc::new(SmallDepositsRepoImpl);
+    let pending_deposits_repo = Arc::new(PendingDepositsRepoImpl);
     let pending_blockchain_transactions_repo = Arc::new(PendingBlockchainTransactionsRepoImpl);
     let key_values_repo = Arc::new(KeyValuesRepoImpl);
+    let quarantined_messages_repo = Arc::new(QuarantinedMessagesRepoImpl);
     let client = HttpClientImpl::new(&config_clone);
     let blockchain_client = Arc::new(BlockchainClientImpl::new(&config_clone, client.clone()));
     let keys_client = Arc::new(KeysClientImpl::new(&config_clone, client.clone()));
+    let exchange_client = Arc::new(FailoverExchangeClient::new(
+        &config_clone,
+        client.clone(),
+        db_executor.clone(),
+        key_values_repo.clone(),
+    ));
 
     debug!("Started creating rabbit connection pool");
 
@@ -132,95 +190,832 @@ pub fn start_server() {
         })
         .expect("Can not create rabbit connection manager");
     debug!("Finished creating rabbit connection manager");
-    let channel = Arc::new(rabbit_connection_manager.get_channel().expect("Can not get channel from pool"));
-    let publisher = rt
-        .block_on(
-            db_executor
-                .execute(move || -> Result<Vec<UserId>, ReposError> { users_repo.get_all().map(|u| u.into_iter().map(|u| u.id).collect()) })
-                .map_err(|e| {
-                    log_error(&e);
-                })
-                .and_then(move |users| {
-                    TransactionPublisherImpl::init(channel, users).map_err(|e| {
-                        log_error(&e);
-                    })
-                }),
-        )
-        .expect("Can not create queue for transactions in rabbit");
-    let publisher = Arc::new(publisher);
+    let channel = rabbit_connection_manager.get_channel().expect("Can not get channel from pool");
+    let topology = Topology::new(config_clone.rabbit.topology.clone());
+    rt.block_on(topology.declare(&channel))
+        .map_err(|e| {
+            log_error(&e);
+        })
+        .expect("Can not declare rabbit topology");
+    // queues of users are declared on their first publish
+    let publisher = Arc::new(TransactionPublisherImpl::new(
+        rabbit_connection_manager.clone(),
+        topology.clone(),
+        &config_clone.rabbit,
+    ));
     let publisher_clone = publisher.clone();
 
+    let seen_hashes_service = SeenHashesServiceImpl::new(
+        seen_hashes_repo.clone(),
+        key_values_repo.clone(),
+        config_clone.seen_hashes_retention.clone(),
+        db_executor.clone(),
+    );
+    let pending_deposits_service = PendingDepositsServiceImpl::new(
+        Arc::new(AuthServiceImpl::new(
+            Arc::new(UsersRepoImpl::new(config.system.system_user_id)),
+            db_executor.clone(),
+        )),
+        pending_deposits_repo.clone(),
+        config_clone.pending_deposits_retention.clone(),
+        db_executor.clone(),
+    );
+    let shared_config = SharedConfig::new(config.clone());
+    // deposits are auto converted on behalf of their owners, so the service authenticates nobody
+    let transactions_service = Arc::new(
+        TransactionsServiceImpl::new(
+            config.clone(),
+            Arc::new(AuthServiceImpl::new(
+                Arc::new(UsersRepoImpl::new(config.system.system_user_id)),
+                db_executor.clone(),
+            )),
+            transactions_repo.clone(),
+            pending_blockchain_transactions_repo.clone(),
+            blockchain_transactions_repo.clone(),
+            strange_blockchain_transactions_repo.clone(),
+            Arc::new(QueuedWithdrawalsRepoImpl),
+            Arc::new(RateLocksRepoImpl),
+            Arc::new(UsersRepoImpl::new(config.system.system_user_id)),
+            Arc::new(WithdrawalAddressesRepoImpl),
+            accounts_repo.clone(),
+            key_values_repo.clone(),
+            db_executor.clone(),
+            keys_client.clone(),
+            blockchain_client.clone(),
+            exchange_client.clone(),
+            publisher.clone(),
+        )
+        .with_rates_service(Arc::new(RatesServiceImpl::new(key_values_repo.clone(), shared_config.clone()))),
+    );
+    let system_service = Arc::new(SystemServiceImpl::new(accounts_repo.clone()));
+    let liquidity_service = LiquidityServiceImpl::new(
+        Arc::new(config.clone()),
+        transactions_repo.clone(),
+        system_service.clone(),
+        db_executor.clone(),
+    );
+    let balance_alerts_service = BalanceAlertsServiceImpl::new(
+        Arc::new(config.clone()),
+        accounts_repo.clone(),
+        transactions_repo.clone(),
+        db_executor.clone(),
+    );
+    let cold_storage_service = ColdStorageServiceImpl::new(
+        Arc::new(config.clone()),
+        accounts_repo.clone(),
+        transactions_repo.clone(),
+        pending_blockchain_transactions_repo.clone(),
+        key_values_repo.clone(),
+        db_executor.clone(),
+        keys_client.clone(),
+        blockchain_client.clone(),
+        exchange_client.clone(),
+    );
+    let fees_top_up_service = FeesTopUpServiceImpl::new(
+        Arc::new(config.clone()),
+        accounts_repo.clone(),
+        transactions_repo.clone(),
+        pending_blockchain_transactions_repo.clone(),
+        key_values_repo.clone(),
+        db_executor.clone(),
+        keys_client.clone(),
+        blockchain_client.clone(),
+        exchange_client,
+    );
+    let repair_service = RepairServiceImpl::new(
+        Arc::new(config.clone()),
+        transactions_repo.clone(),
+        accounts_repo.clone(),
+        blockchain_transactions_repo.clone(),
+        pending_blockchain_transactions_repo.clone(),
+        seen_hashes_repo.clone(),
+        system_service.clone(),
+        Arc::new(ConverterServiceImpl::new(
+            accounts_repo.clone(),
+            pending_blockchain_transactions_repo.clone(),
+            blockchain_transactions_repo.clone(),
+            system_service,
+        )),
+        blockchain_client.clone(),
+        db_executor.clone(),
+    );
+    let checkpoints_transactions_repo = transactions_repo.clone();
+    let checkpoints_db_executor = db_executor.clone();
     let fetcher = BlockchainFetcher::new(
-        Arc::new(config_clone.clone()),
+        shared_config.clone(),
         transactions_repo,
         accounts_repo,
         seen_hashes_repo,
         blockchain_transactions_repo,
         strange_blockchain_transactions_repo,
+        small_deposits_repo,
+        pending_deposits_repo,
         pending_blockchain_transactions_repo,
         key_values_repo,
+        quarantined_messages_repo,
         blockchain_client,
         keys_client,
         db_executor,
         publisher_clone,
+        transactions_service.clone(),
+    );
+    let rabbit_stats = rabbit_connection_manager.stats();
+    // result is reported by /healthz, broken connection is reestablished by consumer once its subscriptions end
+    let health_check_interval = Duration::from_secs(
+        config_clone
+            .rabbit
+            .health_check_interval_secs
+            .unwrap_or(DEFAULT_RABBIT_HEALTH_CHECK_INTERVAL_SECS),
     );
-    let consumer = TransactionConsumerImpl::new(rabbit_connection_manager);
-    let consumer_and_chans = rt
-        .block_on(consumer.subscribe())
-        .expect("Can not create subscribers for transactions in rabbit");
+    let rabbit_pool_clone = rabbit_connection_manager.clone();
+    rt.spawn(
+        Interval::new(Instant::now() + health_check_interval, health_check_interval)
+            .map_err(|e| {
+                error!("rabbit health check timer error: {}", e);
+            })
+            .for_each(move |_| {
+                rabbit_pool_clone.is_valid().then(|res| {
+                    if let Err(e) = res {
+                        log_error(&e);
+                    }
+                    Ok(())
+                })
+            }),
+    );
+    let consumer = TransactionConsumerImpl::new(rabbit_connection_manager, topology.clone());
     debug!("Subscribing to rabbit");
     let fetcher_clone = fetcher.clone();
     let timeout = config_clone.rabbit.restart_subscription_secs as u64;
-    for (stream, channel) in consumer_and_chans {
+    // messages touching the same address are handled in order, the others - concurrently.
+    // Sequencer is shared between currencies, since eth and stq transactions share addresses
+    let sequencer = KeyedSequencer::new();
+    let rabbit_options = config_clone.rabbit.clone();
+    let max_delivery_attempts = config_clone.rabbit.max_delivery_attempts;
+    rt.spawn(consumer.consume(move |stream, channel, currency| {
         let fetcher_clone = fetcher_clone.clone();
-        rt.spawn(
-            stream
-                .for_each(move |message| {
-                    trace!("got message: {}", MessageDelivery::new(message.clone()));
-                    let delivery_tag = message.delivery_tag;
-                    let channel = channel.clone();
-                    let fetcher_future = fetcher_clone.handle_message(message.data);
+        let sequencer = sequencer.clone();
+        let acker = Acker::new(channel, &rabbit_options);
+        let retry_queue_name = topology.currency_retry_queue_name(currency);
+        let flush_acks = acker.flush_periodically(&rabbit_options);
+        stream
+            .map_err(|e| {
+                error!("stream error: {}", e);
+            })
+            .map(move |message| {
+                trace!("got message: {}", MessageDelivery::new(message.clone()));
+                let delivery_tag = message.delivery_tag;
+                acker.received(delivery_tag);
+                let acker = acker.clone();
+                let fetcher_clone = fetcher_clone.clone();
+                let retry_queue_name = retry_queue_name.clone();
+                // parsed once here, malformed messages touch no addresses and fail on handling
+                let tx = parse_transaction(message.data.clone());
+                let addresses = tx.as_ref().map(message_addresses).unwrap_or_default();
+                let attempts = delivery_attempts(&message, &retry_queue_name);
+                let trace = delivery_trace(&message);
+                // messages from blockchain gateway usually have no request id, then every message gets its own
+                let request_id = trace.request_id.clone().unwrap_or_else(request_id::generate);
+                sequencer.schedule(addresses, move || {
+                    let payload = message.data;
+                    let properties = message.properties;
+                    let fetcher_clone2 = fetcher_clone.clone();
+                    let request_id_clone = request_id.clone();
+                    let fetcher_future = future::lazy(move || {
+                        debug!("Handling {} message, attempt {}, {}", currency, attempts, trace);
+                        tx.into_future().and_then(move |tx| fetcher_clone.handle_message(tx))
+                    });
                     let timeout = Duration::from_secs(timeout);
-                    Timeout::new(fetcher_future, timeout)
+                    let handled = Timeout::new(fetcher_future, timeout)
                         .then(move |res| match res {
-                            Ok(_) => Either::A(channel.basic_ack(delivery_tag, false).map_err(|e| {
+                            Ok(_) => Either::A(acker.ack(delivery_tag).map_err(|e| {
                                 error!("Error sending ack: {}", e);
-                                e
                             })),
-                            Err(e) => {
-                                error!("Error during message handling: {}", e);
-                                Either::B(
-                                    Delay::new(Instant::now() + Duration::from_millis(DELAY_BEFORE_NACK)).then(move |_| {
-                                        channel.basic_nack(0, true, true).map_err(|e| {
-                                            error!("Error sending nack: {}", e);
-                                            e
-                                        })
+                            Err(ref e) if FailedDeliveryAction::new(attempts, max_delivery_attempts) == FailedDeliveryAction::Retry => {
+                                error!("Error during message handling, attempt {}: {}", attempts, e);
+                                // the message goes through retry queue and is delivered again after retry delay,
+                                // other messages may be in flight, so only this one is settled
+                                Either::B(Either::A(
+                                    acker.retry(delivery_tag, &retry_queue_name, payload, properties).map_err(|e| {
+                                        error!("Error sending ack: {}", e);
                                     }),
-                                )
+                                ))
+                            }
+                            Err(e) => {
+                                error!("Error during message handling, quarantining after {} attempts: {}", attempts, e);
+                                let error = match e.into_inner() {
+                                    Some(e) => format_error(&e),
+                                    None => format!("Message handling timed out after {} s", timeout.as_secs()),
+                                };
+                                let quarantined = fetcher_clone2
+                                    .quarantine_message(currency, payload.clone(), error, attempts, Some(request_id_clone))
+                                    .then(move |res| match res {
+                                        Ok(_) => Either::A(acker.ack(delivery_tag).map_err(|e| {
+                                            error!("Error sending ack: {}", e);
+                                        })),
+                                        Err(e) => {
+                                            // the message is retried and quarantined on the next attempt
+                                            log_error(&e);
+                                            Either::B(acker.retry(delivery_tag, &retry_queue_name, payload, properties).map_err(|e| {
+                                                error!("Error sending ack: {}", e);
+                                            }))
+                                        }
+                                    });
+                                Either::B(Either::B(quarantined))
                             }
                         })
                         .then(move |res| {
                             trace!("send result: {:?}", res);
-                            Ok(())
+                            Ok::<(), ()>(())
+                        });
+                    // errors of handling, acking and quarantining are logged with the request id as well
+                    WithRequestId::new(handled, Some(request_id))
+                })
+            })
+            .buffer_unordered(MESSAGES_CONCURRENCY)
+            .for_each(|_| Ok(()))
+            // acks not flushed by then are lost with the channel, so these messages are delivered again
+            .select(flush_acks)
+            .then(|_| Ok(()))
+    }));
+
+    let prune_interval = Duration::from_secs(config_clone.seen_hashes_retention.prune_interval_secs);
+    rt.spawn(
+        Interval::new(Instant::now() + prune_interval, prune_interval)
+            .map_err(|e| {
+                error!("seen hashes pruning timer error: {}", e);
+            })
+            .for_each(move |_| {
+                seen_hashes_service.prune().then(|res| {
+                    match res {
+                        Ok(results) => {
+                            for result in results.iter().filter(|result| result.pruned_count > 0) {
+                                info!("Pruned {} seen {} hashes", result.pruned_count, result.currency);
+                            }
+                        }
+                        Err(e) => log_error(&e),
+                    }
+                    Ok(())
+                })
+            }),
+    );
+
+    // deposits dropped from blockchain would stay unconfirmed forever, so they are removed from listings
+    let pending_deposits_prune_interval = Duration::from_secs(config_clone.pending_deposits_retention.prune_interval_secs);
+    rt.spawn(
+        Interval::new(Instant::now() + pending_deposits_prune_interval, pending_deposits_prune_interval)
+            .map_err(|e| {
+                error!("pending deposits pruning timer error: {}", e);
+            })
+            .for_each(move |_| {
+                pending_deposits_service.prune_stale().then(|res| {
+                    match res {
+                        Ok(pruned) => {
+                            for deposit in &pruned {
+                                warn!(
+                                    "Pending {} deposit {} of user {} dropped without confirmation",
+                                    deposit.currency, deposit.blockchain_tx_id, deposit.user_id
+                                );
+                            }
+                        }
+                        Err(e) => log_error(&e),
+                    }
+                    Ok(())
+                })
+            }),
+    );
+
+    // queued withdrawals are sent in batches, one drain at a time, so a slow drain delays the next one
+    let transactions_service_clone = transactions_service.clone();
+    let drain_interval = Duration::from_secs(config_clone.withdrawal_queue.drain_interval_secs);
+    rt.spawn(
+        Interval::new(Instant::now() + drain_interval, drain_interval)
+            .map_err(|e| {
+                error!("withdrawal queue timer error: {}", e);
+            })
+            .for_each(move |_| {
+                transactions_service.drain_withdrawal_queue().then(|res| {
+                    match res {
+                        Ok(withdrawals) => {
+                            if !withdrawals.is_empty() {
+                                let failed = withdrawals
+                                    .iter()
+                                    .filter(|withdrawal| withdrawal.status == QueuedWithdrawalStatus::Failed)
+                                    .count();
+                                info!("Drained {} queued withdrawals, {} failed", withdrawals.len(), failed);
+                            }
+                        }
+                        Err(e) => log_error(&e),
+                    }
+                    Ok(())
+                })
+            }),
+    );
+
+    // signed transactions that blockchain gateway failed to accept are posted again when their attempt is due
+    let broadcast_retry_interval = Duration::from_secs(config_clone.broadcast_retries.interval_secs);
+    rt.spawn(
+        Interval::new(Instant::now() + broadcast_retry_interval, broadcast_retry_interval)
+            .map_err(|e| {
+                error!("broadcast retries timer error: {}", e);
+            })
+            .for_each(move |_| {
+                transactions_service_clone.retry_failed_broadcasts().then(|res| {
+                    match res {
+                        Ok(pending_txs) => {
+                            if !pending_txs.is_empty() {
+                                let broadcasted = pending_txs.iter().filter(|pending_tx| !pending_tx.hash.is_unbroadcasted()).count();
+                                info!(
+                                    "Retried broadcasting of {} transactions, {} accepted",
+                                    pending_txs.len(),
+                                    broadcasted
+                                );
+                            }
+                        }
+                        Err(e) => log_error(&e),
+                    }
+                    Ok(())
+                })
+            }),
+    );
+
+    // withdrawal groups with lost blockchain txs are reversed without waiting for manual `repair`
+    let recovery_interval = Duration::from_secs(config_clone.withdrawal_recovery.interval_secs);
+    let recovery_age = chrono::Duration::seconds(config_clone.withdrawal_recovery.min_age_secs as i64);
+    let recovery_publisher = publisher.clone();
+    rt.spawn(
+        Interval::new(Instant::now() + recovery_interval, recovery_interval)
+            .map_err(|e| {
+                error!("withdrawal recovery timer error: {}", e);
+            })
+            .for_each(move |_| {
+                let publisher = recovery_publisher.clone();
+                repair_service
+                    .recover_withdrawals(recovery_age)
+                    .and_then(move |txs| {
+                        if !txs.is_empty() {
+                            info!("Recovered {} partially failed withdrawals", txs.len());
+                        }
+                        futures::stream::iter_ok(txs).for_each(move |tx| {
+                            publisher.publish(tx).then(|res| {
+                                if let Err(e) = res {
+                                    log_error(&e);
+                                }
+                                Ok(())
+                            })
                         })
+                    })
+                    .then(|res| {
+                        if let Err(e) = res {
+                            log_error(&e);
+                        }
+                        Ok(())
+                    })
+            }),
+    );
+
+    // verification of balances aggregates only transactions after the latest checkpoint
+    let checkpoint_interval = Duration::from_secs(config_clone.balance_checkpoints.interval_secs);
+    let checkpoint_lag = chrono::Duration::seconds(config_clone.balance_checkpoints.lag_secs as i64);
+    rt.spawn(
+        Interval::new(Instant::now() + checkpoint_interval, checkpoint_interval)
+            .map_err(|e| {
+                error!("balance checkpoints timer error: {}", e);
+            })
+            .for_each(move |_| {
+                let transactions_repo = checkpoints_transactions_repo.clone();
+                let as_of = chrono::Utc::now().naive_utc() - checkpoint_lag;
+                checkpoints_db_executor
+                    .execute_transaction_with_isolation(Isolation::RepeatableRead, move || {
+                        transactions_repo.create_balance_checkpoint(as_of)
+                    })
+                    .then(move |res| {
+                        match res {
+                            Ok(Some(accounts_count)) => info!("Stored balance checkpoint as of {} for {} accounts", as_of, accounts_count),
+                            Ok(None) => (),
+                            Err(e) => log_error(&e),
+                        }
+                        Ok(())
+                    })
+            }),
+    );
+
+    // exchanges fail when liquidity account of `to` currency runs out, so it's kept within target range
+    let rebalancing_interval = Duration::from_secs(config_clone.liquidity_rebalancing.interval_secs);
+    rt.spawn(
+        Interval::new(Instant::now() + rebalancing_interval, rebalancing_interval)
+            .map_err(|e| {
+                error!("liquidity rebalancing timer error: {}", e);
+            })
+            .for_each(move |_| {
+                liquidity_service.rebalance().then(|res| {
+                    match res {
+                        Ok(rebalances) => {
+                            for rebalance in rebalances {
+                                if let Some(tx) = rebalance.transaction {
+                                    info!(
+                                        "Rebalanced {} liquidity of {}, transaction {}",
+                                        rebalance.currency,
+                                        rebalance.balance.to_super_unit_string(rebalance.currency),
+                                        tx.id
+                                    );
+                                }
+                            }
+                        }
+                        Err(e) => log_error(&e),
+                    }
+                    Ok(())
                 })
+            }),
+    );
+
+    // low balances are reported before they break anything, e.g. stq approvals paid from eth fees account
+    let balance_alerts_interval = Duration::from_secs(config_clone.balance_alerts.interval_secs);
+    rt.spawn(
+        Interval::new(Instant::now() + balance_alerts_interval, balance_alerts_interval)
+            .map_err(|e| {
+                error!("balance alerts timer error: {}", e);
+            })
+            .for_each(move |_| {
+                balance_alerts_service.check_balances().then(|res| {
+                    if let Err(e) = res {
+                        log_error(&e);
+                    }
+                    Ok(())
+                })
+            }),
+    );
+
+    // stq withdrawals and approvals fail once eth fees account runs dry, so it's topped up from eth liquidity
+    let fees_top_up_interval = Duration::from_secs(config_clone.eth_fees_top_up.interval_secs);
+    rt.spawn(
+        Interval::new(Instant::now() + fees_top_up_interval, fees_top_up_interval)
+            .map_err(|e| {
+                error!("eth fees top up timer error: {}", e);
+            })
+            .for_each(move |_| {
+                fees_top_up_service.top_up().then(|res| {
+                    match res {
+                        Ok(top_up) => {
+                            if let Some(tx) = top_up.transaction {
+                                info!(
+                                    "Topped up eth fees account of {} with {}, transaction {}",
+                                    top_up.balance.to_super_unit_string(Currency::Eth),
+                                    tx.value.to_super_unit_string(Currency::Eth),
+                                    tx.id
+                                );
+                            }
+                        }
+                        Err(e) => log_error(&e),
+                    }
+                    Ok(())
+                })
+            }),
+    );
+
+    // most of the float is kept on cold addresses, hot ones hold just enough for withdrawals
+    let cold_storage_interval = Duration::from_secs(config_clone.cold_storage.interval_secs);
+    rt.spawn(
+        Interval::new(Instant::now() + cold_storage_interval, cold_storage_interval)
+            .map_err(|e| {
+                error!("cold storage sweep timer error: {}", e);
+            })
+            .for_each(move |_| {
+                cold_storage_service.sweep().then(|res| {
+                    match res {
+                        Ok(sweeps) => {
+                            for tx in sweeps {
+                                info!(
+                                    "Sent {} {} from account {} to cold storage, transaction {}",
+                                    tx.value.to_super_unit_string(tx.currency),
+                                    tx.currency,
+                                    tx.cr_account_id,
+                                    tx.id
+                                );
+                            }
+                        }
+                        Err(e) => log_error(&e),
+                    }
+                    Ok(())
+                })
+            }),
+    );
+
+    // secrets are fetched only on start, the token is renewed so that leases of secrets issued to it don't expire
+    if let Some(vault) = config_clone.vault.clone() {
+        let vault_client = VaultClientImpl::new(&vault, client);
+        let renew_interval = Duration::from_secs(vault.renew_interval_secs);
+        rt.spawn(
+            Interval::new(Instant::now() + renew_interval, renew_interval)
                 .map_err(|e| {
-                    error!("stream error: {}", e);
+                    error!("vault token renewal timer error: {}", e);
+                })
+                .for_each(move |_| {
+                    vault_client.renew_token().then(|res| {
+                        match res {
+                            Ok(lease_duration) => debug!("Renewed vault token, lease duration {} secs", lease_duration),
+                            Err(e) => log_error(&e),
+                        }
+                        Ok(())
+                    })
                 }),
         );
     }
 
-    rt.spawn(api::server(config, publisher));
+    // limits, fee prices and other dynamic settings are reloaded from config files without restart
+    let reload_interval = Duration::from_secs(config_clone.config_reload.poll_interval_secs);
+    let shared_config_clone = shared_config.clone();
+    rt.spawn(
+        Interval::new(Instant::now() + reload_interval, reload_interval)
+            .map_err(|e| {
+                error!("config reload timer error: {}", e);
+            })
+            .for_each(move |_| {
+                reload_config(&shared_config_clone);
+                Ok(())
+            }),
+    );
+    unsafe {
+        libc::signal(libc::SIGHUP, handle_sighup as libc::sighandler_t);
+    }
+    let shared_config_clone = shared_config.clone();
+    let sighup_check_interval = Duration::from_millis(SIGHUP_CHECK_INTERVAL);
+    rt.spawn(
+        Interval::new(Instant::now() + sighup_check_interval, sighup_check_interval)
+            .map_err(|e| {
+                error!("SIGHUP check timer error: {}", e);
+            })
+            .for_each(move |_| {
+                if SIGHUP_RECEIVED.swap(false, Ordering::SeqCst) {
+                    info!("Received SIGHUP, reloading config");
+                    reload_config(&shared_config_clone);
+                }
+                Ok(())
+            }),
+    );
+
+    rt.spawn(api::server(shared_config, publisher, query_stats, rabbit_stats));
 
     rt.shutdown_on_idle().wait().expect("Tokio runtime shutdown failed");
 }
 
-fn get_config() -> Config {
-    config::Config::new().unwrap_or_else(|e| panic!("Error parsing config: {}", e))
+/// Runs all embedded migrations that are not yet applied to the database.
+/// With `check` set, migrations are applied inside a transaction that is rolled back,
+/// pending ones are printed and the process exits with non-zero code if there are any.
+pub fn migrate(check: bool) {
+    let config = get_config().unwrap_or_else(exit_on_config_error);
+    let db_pool = create_db_pool(&config);
+    let conn = db_pool.get().expect("Failed to get connection from db pool");
+    if !check {
+        embedded_migrations::run_with_output(&*conn, &mut std::io::stdout()).expect("Failed to run migrations");
+        return;
+    }
+    let mut output = Vec::new();
+    let res = conn.transaction::<(), diesel_migrations::RunMigrationsError, _>(|| {
+        embedded_migrations::run_with_output(&*conn, &mut output)?;
+        // rolling back everything that was applied
+        Err(diesel_migrations::RunMigrationsError::QueryError(
+            diesel::result::Error::RollbackTransaction,
+        ))
+    });
+    match res {
+        Err(diesel_migrations::RunMigrationsError::QueryError(diesel::result::Error::RollbackTransaction)) => (),
+        Err(e) => panic!("Failed to check migrations: {}", e),
+        Ok(_) => unreachable!(),
+    }
+    let output = String::from_utf8_lossy(&output);
+    let pending: Vec<_> = output
+        .lines()
+        .filter_map(|line| line.trim().splitn(2, "Running migration ").nth(1))
+        .collect();
+    if pending.is_empty() {
+        println!("Database is up to date");
+    } else {
+        println!("Pending migrations:");
+        for version in pending {
+            println!("{}", version);
+        }
+        std::process::exit(1);
+    }
+}
+
+pub fn rebroadcast_pending(older_than_mins: i64, currency: Option<&str>) {
+    let config = get_config().unwrap_or_else(exit_on_config_error);
+    let db_pool = create_db_pool(&config);
+    let cpu_pool = CpuPool::new(1);
+    let db_executor = DbExecutorImpl::new(db_pool, cpu_pool);
+    let client = HttpClientImpl::new(&config);
+    let blockchain_client: Arc<BlockchainClient> = Arc::new(BlockchainClientImpl::new(&config, client.clone()));
+    let keys_client: Arc<KeysClient> = Arc::new(KeysClientImpl::new(&config, client));
+    let pending_blockchain_transactions_repo = PendingBlockchainTransactionsRepoImpl;
+    let age = chrono::Duration::minutes(older_than_mins);
+    let currency = currency.map(|currency| match currency {
+        "btc" => Currency::Btc,
+        "eth" => Currency::Eth,
+        "stq" => Currency::Stq,
+        _ => panic!("Unknown currency: {}", currency),
+    });
+    let config = Arc::new(config);
+
+    let fut = db_executor
+        .execute(move || pending_blockchain_transactions_repo.list_older_than(age, currency))
+        .map_err(|e| log_error(&e))
+        .and_then(move |stale_transactions| {
+            let pending_transactions: Vec<_> = stale_transactions.into_iter().map(|stale| stale.pending).collect();
+            println!(
+                "Found {} pending transactions older than {} minutes",
+                pending_transactions.len(),
+                older_than_mins
+            );
+            futures::stream::iter_ok(pending_transactions).for_each(move |pending_transaction| {
+                let hash = pending_transaction.hash.clone();
+                rebroadcast_pending_transaction(
+                    pending_transaction,
+                    config.clone(),
+                    db_executor.clone(),
+                    blockchain_client.clone(),
+                    keys_client.clone(),
+                )
+                .then(move |res| {
+                    match res {
+                        Ok(ref new_hash) if *new_hash == hash => println!("{}: rebroadcasted", hash),
+                        Ok(new_hash) => println!("{}: re-signed and rebroadcasted as {}", hash, new_hash),
+                        Err(e) => {
+                            println!("{}: failed - {}", hash, e);
+                            log_error(&e);
+                        }
+                    };
+                    Ok(())
+                })
+            })
+        });
+    hyper::rt::run(fut);
+}
+
+// Posts stored raw transaction once again. If raw transaction was not stored (txs created before
+// raw_tx column was introduced), it is signed once again in keys service with the current nonce / utxos
+// and ledger and pending transactions are moved to the new hash.
+fn rebroadcast_pending_transaction(
+    pending_transaction: PendingBlockchainTransactionDB,
+    config: Arc<Config>,
+    db_executor: DbExecutorImpl,
+    blockchain_client: Arc<BlockchainClient>,
+    keys_client: Arc<KeysClient>,
+) -> Box<Future<Item = BlockchainTransactionId, Error = ReposError> + Send> {
+    let currency = pending_transaction.currency;
+    if let Some(raw_tx) = pending_transaction.raw_tx.clone() {
+        return post_raw_transaction(blockchain_client, currency, raw_tx);
+    }
+    if pending_transaction.erc20_operation_kind.is_some() {
+        return Box::new(future::err(
+            ectx!(err format_err!("Erc-20 operation without raw transaction can not be re-signed"), ReposErrorKind::Internal => pending_transaction),
+        ));
+    }
+
+    let transactions_repo = Arc::new(TransactionsRepoImpl::new(config.system.system_user_id));
+    let transactions_repo_clone = transactions_repo.clone();
+    let accounts_repo = AccountsRepoImpl;
+    let pending_blockchain_transactions_repo = PendingBlockchainTransactionsRepoImpl;
+    let fee_price = config.fee_price.clone();
+    let db_executor_clone = db_executor.clone();
+    let blockchain_client_clone = blockchain_client.clone();
+    let PendingBlockchainTransactionDB {
+        hash: old_hash,
+        from_: from,
+        to_: to,
+        value,
+        ..
+    } = pending_transaction;
+    let old_hash_clone = old_hash.clone();
+
+    Box::new(
+        db_executor
+            .execute(move || -> Result<_, ReposError> {
+                let ledger_tx_id = transactions_repo.get_by_blockchain_tx(old_hash_clone)?.map(|tx| tx.id);
+                let tx_initiator = match currency {
+                    // stq transactions are sent on behalf of system eth fees account, see BlockchainService
+                    Currency::Stq => accounts_repo
+                        .get_system_account(SystemAccountKind::Fees, Currency::Eth, AccountKind::Cr)?
+                        .map(|account| account.address)
+                        .ok_or(ectx!(try err format_err!("Eth fees account not found"), ReposErrorKind::Internal))?,
+                    _ => from.clone(),
+                };
+                Ok((ledger_tx_id, tx_initiator, from))
+            })
+            .and_then(move |(ledger_tx_id, tx_initiator, from)| {
+                let input_fut = match currency {
+                    Currency::Btc => Either::A(
+                        blockchain_client
+                            .get_bitcoin_utxos(from.clone())
+                            .map(move |utxos| CreateBlockchainTx::new(from, to, currency, value, fee_price.bitcoin, None, Some(utxos))),
+                    ),
+                    _ => Either::B(
+                        blockchain_client
+                            .get_ethereum_nonce(tx_initiator.clone())
+                            .map(move |nonce| CreateBlockchainTx::new(from, to, currency, value, fee_price.ethereum, Some(nonce), None)),
+                    ),
+                };
+                input_fut
+                    .map_err(ectx!(ReposErrorKind::Internal => tx_initiator))
+                    .map(move |mut input| {
+                        if let Some(ledger_tx_id) = ledger_tx_id {
+                            input.id = ledger_tx_id;
+                        }
+                        input
+                    })
+            })
+            .and_then(move |input| {
+                let input_clone = input.clone();
+                keys_client
+                    .sign_transaction(input.clone(), Role::User)
+                    .map_err(ectx!(ReposErrorKind::Internal => input_clone))
+                    .map(move |raw_tx| (input, raw_tx))
+            })
+            .and_then(move |(input, raw_tx)| {
+                post_raw_transaction(blockchain_client_clone, currency, raw_tx.clone()).map(move |new_hash| (input, raw_tx, new_hash))
+            })
+            .and_then(move |(input, raw_tx, new_hash)| {
+                db_executor_clone.execute_transaction(move || -> Result<BlockchainTransactionId, ReposError> {
+                    if new_hash == old_hash {
+                        return Ok(new_hash);
+                    }
+                    pending_blockchain_transactions_repo.delete(old_hash.clone())?;
+                    pending_blockchain_transactions_repo.create((input, new_hash.clone(), raw_tx).into())?;
+                    if let Some(tx) = transactions_repo_clone.get_by_blockchain_tx(old_hash)? {
+                        transactions_repo_clone.update_blockchain_tx(tx.id, new_hash.clone())?;
+                    }
+                    Ok(new_hash)
+                })
+            }),
+    )
+}
+
+fn post_raw_transaction(
+    blockchain_client: Arc<BlockchainClient>,
+    currency: Currency,
+    raw_tx: BlockchainTransactionRaw,
+) -> Box<Future<Item = BlockchainTransactionId, Error = ReposError> + Send> {
+    let raw_tx_clone = raw_tx.clone();
+    let fut = match currency {
+        Currency::Btc => blockchain_client.post_bitcoin_transaction(raw_tx),
+        _ => blockchain_client.post_ethereum_transaction(raw_tx),
+    };
+    Box::new(
+        fut.map_err(ectx!(ReposErrorKind::Internal => raw_tx_clone))
+            .map(move |tx_id| match currency {
+                // Erc-20 token, we need event log number here, to make a tx_id unique
+                Currency::Stq => tx_id.with_log_index(0),
+                _ => tx_id,
+            }),
+    )
+}
+
+/// Rereads config files and applies their dynamic part, invalid config is ignored.
+/// Secrets are not refetched from vault, since they are not reloaded anyway
+fn reload_config(shared_config: &SharedConfig) {
+    let config = match Config::new() {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Failed to reload config, keeping the current one: {}", e);
+            return;
+        }
+    };
+    match shared_config.reload(config) {
+        Ok(Some(dynamic)) => info!("Applied reloaded config: {:?}", dynamic),
+        Ok(None) => (),
+        Err(errors) => error!("Reloaded config is invalid, keeping the current one: {}", errors.join("; ")),
+    }
+}
+
+/// Loads config with secrets and validates it, so that misconfiguration is reported
+/// on start with all problems at once instead of failing later somewhere deep in services
+fn get_config() -> Result<Config, failure::Error> {
+    let config = config::Config::new().map_err(|e| format_err!("Error parsing config: {}", e))?;
+    let config = match config.vault.clone() {
+        Some(vault) => {
+            let mut rt = Runtime::new().map_err(|e| format_err!("Could not create tokio runtime: {}", e))?;
+            let vault_client = VaultClientImpl::new(&vault, HttpClientImpl::new(&config));
+            let secrets = rt
+                .block_on(vault_client.get_secrets())
+                .map_err(|e| format_err!("Error fetching secrets from vault: {}", e))?;
+            config.with_secrets(secrets)
+        }
+        None => config,
+    };
+    let errors = config.validate();
+    if !errors.is_empty() {
+        return Err(format_err!("Found {} problems in config:\n{}", errors.len(), errors.join("\n")));
+    }
+    Ok(config)
+}
+
+/// Commands can't do anything without config, so they exit with the reason it failed to load
+fn exit_on_config_error(e: failure::Error) -> Config {
+    eprintln!("{}", e);
+    std::process::exit(1);
 }
 
 pub fn create_user(name: &str) {
-    let config = get_config();
+    let config = get_config().unwrap_or_else(exit_on_config_error);
     let db_pool = create_db_pool(&config);
     let cpu_pool = CpuPool::new(1);
     let users_repo = UsersRepoImpl::new(config.system.system_user_id);
@@ -235,199 +1030,825 @@ pub fn create_user(name: &str) {
     hyper::rt::run(fut.map(|_| ()).map_err(|_| ()));
 }
 
-pub fn repair_approval_pending_transaction(id: &str) {
-    let config = get_config();
+pub fn rotate_user_token(id: &str) {
+    let config = get_config().unwrap_or_else(exit_on_config_error);
     let db_pool = create_db_pool(&config);
     let cpu_pool = CpuPool::new(1);
-    let fees_accounts_ids = vec![
-        config.system.btc_fees_account_id,
-        config.system.eth_fees_account_id,
-        config.system.stq_fees_account_id,
-    ];
-    let transactions_repo = Arc::new(TransactionsRepoImpl::new(config.system.system_user_id, fees_accounts_ids));
-    let blockchain_transactions_repo = BlockchainTransactionsRepoImpl;
-    let pending_blockchain_transactions_repo = PendingBlockchainTransactionsRepoImpl;
+    let users_repo = UsersRepoImpl::new(config.system.system_user_id);
     let db_executor = DbExecutorImpl::new(db_pool, cpu_pool);
-    let id = TransactionId::from_str(id).expect("Failed to parse transaction id");
-    let fut = db_executor.execute_transaction_with_isolation(Isolation::Serializable, move || -> Result<(), ReposError> {
-        let transaction = transactions_repo.get(id).expect("Failed to get transaction");
-        let transaction = transaction.expect("Failed to find transaction");
-        if transaction.kind != TransactionKind::ApprovalTransfer {
-            panic!("Transaction kind is not approval");
-        }
-        if transaction.status != TransactionStatus::Pending {
-            panic!("Transaction status is not pending");
+    let user_id = UserId::from_str(id).unwrap_or_else(|e| {
+        eprintln!("Failed to parse user id {}: {}", id, e);
+        std::process::exit(1);
+    });
+    let payload = UpdateUser {
+        authentication_token: Some(AuthenticationToken::default()),
+        ..Default::default()
+    };
+    let fut = db_executor.execute(move || -> Result<(), ReposError> {
+        let user = users_repo.update(user_id, payload)?;
+        println!("{}", user.authentication_token.raw());
+        Ok(())
+    });
+    let mut rt = Runtime::new().expect("Could not create tokio runtime");
+    if let Err(e) = rt.block_on(fut) {
+        log_error(&e);
+        std::process::exit(1);
+    }
+}
+
+pub fn disable_user(id: &str, enable: bool) {
+    let config = get_config().unwrap_or_else(exit_on_config_error);
+    let db_pool = create_db_pool(&config);
+    let cpu_pool = CpuPool::new(1);
+    let users_repo = UsersRepoImpl::new(config.system.system_user_id);
+    let db_executor = DbExecutorImpl::new(db_pool, cpu_pool);
+    let user_id = UserId::from_str(id).unwrap_or_else(|e| {
+        eprintln!("Failed to parse user id {}: {}", id, e);
+        std::process::exit(1);
+    });
+    let payload = UpdateUser {
+        disabled: Some(!enable),
+        ..Default::default()
+    };
+    let fut = db_executor.execute(move || -> Result<(), ReposError> {
+        let user = users_repo.update(user_id, payload)?;
+        println!(
+            "User {} ({}) is {}",
+            user.id,
+            user.name,
+            if user.disabled { "disabled" } else { "enabled" }
+        );
+        Ok(())
+    });
+    let mut rt = Runtime::new().expect("Could not create tokio runtime");
+    if let Err(e) = rt.block_on(fut) {
+        log_error(&e);
+        std::process::exit(1);
+    }
+}
+
+pub fn seed(force: bool) {
+    let run_mode = std::env::var("RUN_MODE").unwrap_or_else(|_| "development".to_string());
+    if !force && !SEED_RUN_MODES.contains(&run_mode.as_str()) {
+        eprintln!(
+            "Refusing to seed data with RUN_MODE={}, allowed modes are {:?}. Use --force to override.",
+            run_mode, SEED_RUN_MODES
+        );
+        std::process::exit(1);
+    }
+    let config = get_config().unwrap_or_else(exit_on_config_error);
+    let db_pool = create_db_pool(&config);
+    let cpu_pool = CpuPool::new(1);
+    let transactions_repo = TransactionsRepoImpl::new(config.system.system_user_id);
+    let users_repo = UsersRepoImpl::new(config.system.system_user_id);
+    let accounts_repo = AccountsRepoImpl;
+    let db_executor = DbExecutorImpl::new(db_pool, cpu_pool);
+    let fut = db_executor.execute_transaction(move || -> Result<(), ReposError> {
+        let user = users_repo.create(NewUser {
+            name: "Demo".to_string(),
+            ..Default::default()
+        })?;
+        println!("Created user {} with token {}", user.id, user.authentication_token.raw());
+        for (currency, deposit_value) in vec![
+            (Currency::Btc, 100_000_000),
+            (Currency::Eth, 1_000_000_000_000_000_000),
+            (Currency::Stq, 1_000_000_000_000_000_000_000),
+        ] {
+            let (main, main_dr) = create_seed_account(&accounts_repo, user.id, currency, "main")?;
+            let (savings, _) = create_seed_account(&accounts_repo, user.id, currency, "savings")?;
+            let deposit_id = TransactionId::generate();
+            transactions_repo.create(NewTransaction {
+                id: deposit_id,
+                gid: deposit_id,
+                user_id: user.id,
+                dr_account_id: main_dr.id,
+                cr_account_id: main.id,
+                currency,
+                value: Amount::new(deposit_value),
+                status: TransactionStatus::Done,
+                blockchain_tx_id: Some(BlockchainTransactionId::new(format!("seed{}", uuid::Uuid::new_v4().simple()))),
+                kind: TransactionKind::Deposit,
+                group_kind: TransactionGroupKind::Deposit,
+                related_tx: None,
+                meta: Some(serde_json::Value::String("seed deposit".to_string())),
+            })?;
+            // a few transfers back and forth, so that both accounts have some history
+            for (from, to, value) in vec![
+                (&main, &savings, deposit_value / 2),
+                (&savings, &main, deposit_value / 10),
+                (&main, &savings, deposit_value / 20),
+            ] {
+                let id = TransactionId::generate();
+                transactions_repo.create(NewTransaction {
+                    id,
+                    gid: id,
+                    user_id: user.id,
+                    dr_account_id: from.id,
+                    cr_account_id: to.id,
+                    currency,
+                    value: Amount::new(value),
+                    status: TransactionStatus::Done,
+                    blockchain_tx_id: None,
+                    kind: TransactionKind::Internal,
+                    group_kind: TransactionGroupKind::Internal,
+                    related_tx: None,
+                    meta: None,
+                })?;
+            }
+            println!("Created {} accounts {} (main) and {} (savings)", currency, main.id, savings.id);
         }
-        let hash = transaction.blockchain_tx_id.expect("Failed to get blockchain tx hash");
-        let pending_transaction = pending_blockchain_transactions_repo
-            .delete(hash.clone())
-            .expect("Failed to delete pending blockchain transaction");
-        let pending_transaction = pending_transaction.expect("Failed to find pending blockchain transaction");
-        let payload: NewBlockchainTransactionDB = pending_transaction.into();
-        blockchain_transactions_repo
-            .create(payload)
-            .expect("Failed to create blockchain transaction");
-        let payload = NewTransaction {
-            id: TransactionId::generate(),
-            gid: transaction.gid,
-            user_id: transaction.user_id,
-            dr_account_id: transaction.cr_account_id,
-            cr_account_id: transaction.dr_account_id,
-            currency: transaction.currency,
-            value: transaction.value,
-            status: TransactionStatus::Done,
-            blockchain_tx_id: Some(hash.clone()),
-            kind: TransactionKind::Reversal,
-            group_kind: TransactionGroupKind::Approval,
-            related_tx: Some(id),
-            meta: Some(serde_json::Value::String(format!(
-                "revers of approval transaction with id {}",
-                transaction.id
-            ))),
-        };
-        transactions_repo.create(payload).expect("Failed to create transaction");
-        transactions_repo
-            .update_status(hash, TransactionStatus::Done)
-            .expect("Failed to create transaction");
         Ok(())
     });
-    hyper::rt::run(fut.map(|_| ()).map_err(|_| ()));
+    hyper::rt::run(fut.map_err(|e| log_error(&e)));
+}
+
+// Creates a pair of user's cr account and its dr account with mock address
+fn create_seed_account(
+    accounts_repo: &AccountsRepo,
+    user_id: UserId,
+    currency: Currency,
+    name: &str,
+) -> Result<(Account, Account), ReposError> {
+    let hex = format!("{}{}", uuid::Uuid::new_v4().simple(), uuid::Uuid::new_v4().simple());
+    let address = match currency {
+        Currency::Btc => format!("mock{}", &hex[..30]),
+        Currency::Eth | Currency::Stq => hex[..40].to_string(),
+    };
+    let new_account_cr = NewAccount {
+        user_id,
+        currency,
+        address: BlockchainAddress::new(address),
+        name: Some(format!("Demo {} {}", currency, name)),
+        kind: AccountKind::Cr,
+        ..Default::default()
+    };
+    let new_account_dr = new_account_cr.create_debit();
+    let account_cr = accounts_repo.create(new_account_cr)?;
+    let account_dr = accounts_repo.create(new_account_dr)?;
+    Ok((account_cr, account_dr))
 }
 
-pub fn repair_withdrawal_pending_transaction(id: &str) {
-    let config = get_config();
+pub fn list_stuck_transactions(older_than_hours: i64, json_output: bool) {
+    let config = get_config().unwrap_or_else(exit_on_config_error);
     let db_pool = create_db_pool(&config);
     let cpu_pool = CpuPool::new(1);
-    let fees_accounts_ids = vec![
-        config.system.btc_fees_account_id,
-        config.system.eth_fees_account_id,
-        config.system.stq_fees_account_id,
-    ];
-    let transactions_repo = Arc::new(TransactionsRepoImpl::new(config.system.system_user_id, fees_accounts_ids));
-    let blockchain_transactions_repo = BlockchainTransactionsRepoImpl;
     let pending_blockchain_transactions_repo = PendingBlockchainTransactionsRepoImpl;
     let db_executor = DbExecutorImpl::new(db_pool, cpu_pool);
-    let id = TransactionId::from_str(id).expect("Failed to parse transaction id");
-    let fut = db_executor.execute_transaction_with_isolation(Isolation::Serializable, move || -> Result<(), ReposError> {
-        let transaction = transactions_repo.get(id).expect("Failed to get transaction");
-        let transaction = transaction.expect("Failed to find transaction");
-        if transaction.kind != TransactionKind::Withdrawal {
-            panic!("Transaction kind is not approval");
+    let age = chrono::Duration::hours(older_than_hours);
+    let fut = db_executor.execute(move || -> Result<(), ReposError> {
+        let stuck_transactions = pending_blockchain_transactions_repo.list_older_than(age, None)?;
+        // transactions without ledger counterpart, e.g. erc20 approvals, have no kind
+        let mut groups: Vec<((Option<TransactionKind>, Currency), Vec<StalePendingBlockchainTransaction>)> = Vec::new();
+        for stale in stuck_transactions {
+            let key = (stale.transaction.as_ref().map(|tx| tx.kind), stale.pending.currency);
+            match groups.iter().position(|(group_key, _)| *group_key == key) {
+                Some(idx) => groups[idx].1.push(stale),
+                None => groups.push((key, vec![stale])),
+            }
         }
-        if transaction.status != TransactionStatus::Pending {
-            panic!("Transaction status is not pending");
+        if json_output {
+            let output: Vec<_> = groups
+                .iter()
+                .map(|((kind, currency), txs)| {
+                    let txs: Vec<_> = txs
+                        .iter()
+                        .map(|stale| {
+                            let tx = stale.transaction.as_ref();
+                            json!({
+                                "id": tx.map(|tx| tx.id),
+                                "gid": tx.map(|tx| tx.gid),
+                                "userId": tx.map(|tx| tx.user_id),
+                                "drAccountId": tx.map(|tx| tx.dr_account_id),
+                                "crAccountId": tx.map(|tx| tx.cr_account_id),
+                                "value": stale.pending.value,
+                                "blockchainTxId": stale.pending.hash,
+                                "broadcastAttempts": stale.pending.broadcast_attempts,
+                                "createdAt": stale.created_at(),
+                            })
+                        })
+                        .collect();
+                    json!({
+                        "kind": kind.map(|kind| format!("{:?}", kind)),
+                        "currency": currency,
+                        "transactions": txs,
+                    })
+                })
+                .collect();
+            let output = serde_json::to_string_pretty(&output).map_err(ectx!(try ReposErrorKind::Internal))?;
+            println!("{}", output);
+        } else {
+            for ((kind, currency), txs) in groups {
+                let kind = kind.map(|kind| format!("{:?}", kind)).unwrap_or_else(|| "Unknown".to_string());
+                println!("{} / {} - {} transactions", kind, currency, txs.len());
+                println!(
+                    "{:<36} | {:<36} | {:<36} | {:<36} | {:<19} | {}",
+                    "gid", "dr_account_id", "cr_account_id", "value", "created_at", "blockchain_tx_id"
+                );
+                for stale in txs {
+                    let (gid, dr_account_id, cr_account_id) = match stale.transaction {
+                        Some(ref tx) => (tx.gid.to_string(), tx.dr_account_id.to_string(), tx.cr_account_id.to_string()),
+                        None => ("-".to_string(), "-".to_string(), "-".to_string()),
+                    };
+                    println!(
+                        "{:<36} | {:<36} | {:<36} | {:<36} | {:<19} | {}",
+                        gid,
+                        dr_account_id,
+                        cr_account_id,
+                        stale.pending.value.raw(),
+                        stale.created_at().format("%Y-%m-%d %H:%M:%S").to_string(),
+                        stale.pending.hash
+                    );
+                }
+                println!();
+            }
         }
+        Ok(())
+    });
+    let mut rt = Runtime::new().expect("Could not create tokio runtime");
+    if let Err(e) = rt.block_on(fut) {
+        log_error(&e);
+        std::process::exit(1);
+    }
+}
 
-        // get all group transactions
-        let all_withdrawal_transactions = transactions_repo
-            .get_by_gid(transaction.gid)
-            .expect("Failed to find transactions by gid");
-
-        // get fee group transactions
-        let fee_transaction = all_withdrawal_transactions
-            .iter()
-            .filter(|t| t.kind == TransactionKind::Fee)
-            .next()
-            .cloned()
-            .expect("Failed to get fee transaction from gid");
-
-        // get withdrawal transactions in group
-        let withdrawal_transactions: Vec<Transaction> = all_withdrawal_transactions
-            .into_iter()
-            .filter(|t| t.kind == TransactionKind::Withdrawal)
-            .collect();
-
-        // get withdrawal transactions total amount
-        let total_amount = withdrawal_transactions.iter().fold(Amount::new(0), |acc, elem| {
-            acc.checked_add(elem.value).expect("Overflow on collecting total amount")
-        });
+/// Recomputes all account balances from the latest balance checkpoint and transactions after it and checks ledger invariants:
+/// cr accounts are non-negative and dr accounts aggregated by address match blockchain balances.
+/// Exits with non-zero code if any violation is found.
+pub fn verify_balances() {
+    let config = get_config().unwrap_or_else(exit_on_config_error);
+    let db_pool = create_db_pool(&config);
+    let cpu_pool = CpuPool::new(1);
+    let transactions_repo = TransactionsRepoImpl::new(config.system.system_user_id);
+    let db_executor = DbExecutorImpl::new(db_pool, cpu_pool);
+    let fut = db_executor.execute_transaction_with_isolation(Isolation::RepeatableRead, move || -> Result<usize, ReposError> {
+        let turnovers = transactions_repo.get_accounts_turnovers()?;
+        let blockchain_balances = transactions_repo.get_blockchain_balances()?;
+        let mut violations = 0;
+        for turnover in transactions_repo.get_stale_account_balances()? {
+            violations += 1;
+            println!(
+                "Materialized balance of {:?} account {} ({} {}) is stale: actual cr turnover {}, dr turnover {}",
+                turnover.kind,
+                turnover.account_id,
+                turnover.currency,
+                turnover.address,
+                turnover.cr_turnover.raw(),
+                turnover.dr_turnover.raw()
+            );
+        }
+        let mut dr_aggregates: HashMap<(BlockchainAddress, Currency), (Amount, Amount)> = HashMap::new();
+        for turnover in turnovers {
+            match turnover.kind {
+                AccountKind::Cr => {
+                    if turnover.cr_turnover.checked_sub(turnover.dr_turnover).is_none() {
+                        violations += 1;
+                        let gids = get_negative_balance_gids(&transactions_repo, turnover.account_id)?;
+                        println!(
+                            "Negative balance of cr account {} ({} {}): cr turnover {}, dr turnover {}, offending gids: {}",
+                            turnover.account_id,
+                            turnover.currency,
+                            turnover.address,
+                            turnover.cr_turnover.raw(),
+                            turnover.dr_turnover.raw(),
+                            gids.join(", ")
+                        );
+                    }
+                }
+                AccountKind::Dr => {
+                    let entry = dr_aggregates
+                        .entry((turnover.address, turnover.currency))
+                        .or_insert((Amount::new(0), Amount::new(0)));
+                    entry.0 = entry
+                        .0
+                        .checked_add(turnover.dr_turnover)
+                        .expect("Overflow on collecting dr turnovers");
+                    entry.1 = entry
+                        .1
+                        .checked_add(turnover.cr_turnover)
+                        .expect("Overflow on collecting cr turnovers");
+                }
+            }
+        }
+        for ((address, currency), (dr_turnover, cr_turnover)) in dr_aggregates.iter() {
+            if dr_turnover.checked_sub(*cr_turnover).is_none() {
+                violations += 1;
+                println!(
+                    "Negative balance of dr accounts with address {} ({}): dr turnover {}, cr turnover {}",
+                    address,
+                    currency,
+                    dr_turnover.raw(),
+                    cr_turnover.raw()
+                );
+            }
+            // addresses without blockchain transactions are absent from blockchain balances
+            let blockchain_balance = blockchain_balances
+                .get(&(address.clone(), *currency))
+                .cloned()
+                .unwrap_or((Amount::new(0), Amount::new(0)));
+            if blockchain_balance != (*dr_turnover, *cr_turnover) {
+                violations += 1;
+                println!(
+                    "Dr accounts with address {} ({}) don't match blockchain balances: recomputed {:?}, blockchain balances {:?}",
+                    address,
+                    currency,
+                    (dr_turnover.raw(), cr_turnover.raw()),
+                    (blockchain_balance.0.raw(), blockchain_balance.1.raw())
+                );
+            }
+        }
+        for (address, currency) in blockchain_balances.keys() {
+            if !dr_aggregates.contains_key(&(address.clone(), *currency)) {
+                violations += 1;
+                println!("Blockchain balance of address {} ({}) has no dr accounts", address, currency);
+            }
+        }
+        Ok(violations)
+    });
+    let mut rt = Runtime::new().expect("Could not create tokio runtime");
+    match rt.block_on(fut) {
+        Ok(0) => println!("All balances are consistent"),
+        Ok(violations) => {
+            println!("Found {} violations", violations);
+            std::process::exit(1);
+        }
+        Err(e) => {
+            log_error(&e);
+            println!("Failed to verify balances: {}", e);
+            std::process::exit(2);
+        }
+    }
+}
 
-        // get pending withdrawal transactions in group
-        let pending_withdrawal_transactions: Vec<Transaction> = withdrawal_transactions
-            .clone()
-            .into_iter()
-            .filter(|t| t.status == TransactionStatus::Pending)
-            .collect();
+// Walks transactions of cr account in chronological order and returns gids of those
+// that were applied while account balance was negative.
+fn get_negative_balance_gids(transactions_repo: &TransactionsRepo, account_id: AccountId) -> Result<Vec<String>, ReposError> {
+    let mut txs = transactions_repo.list_for_account(account_id, 0, i64::max_value())?;
+    txs.sort_by_key(|tx| tx.created_at);
+    let mut cr_turnover = Amount::new(0);
+    let mut dr_turnover = Amount::new(0);
+    let mut gids: Vec<String> = Vec::new();
+    for tx in txs {
+        if tx.cr_account_id == account_id {
+            cr_turnover = cr_turnover.checked_add(tx.value).expect("Overflow on collecting cr turnover");
+        }
+        if tx.dr_account_id == account_id {
+            dr_turnover = dr_turnover.checked_add(tx.value).expect("Overflow on collecting dr turnover");
+        }
+        let gid = tx.gid.to_string();
+        if cr_turnover.checked_sub(dr_turnover).is_none() && !gids.contains(&gid) {
+            gids.push(gid);
+        }
+    }
+    Ok(gids)
+}
 
-        // get pending withdrawal transactions total amount
-        let pending_total_amount = pending_withdrawal_transactions.iter().fold(Amount::new(0), |acc, elem| {
-            acc.checked_add(elem.value).expect("Overflow on collecting pending total amount")
-        });
+pub fn reconcile(currency: Option<&str>, output: &str) {
+    let config = get_config().unwrap_or_else(exit_on_config_error);
+    let client = HttpClientImpl::new(&config);
+    let blockchain_client = Arc::new(BlockchainClientImpl::new(&config, client));
+    let db_pool = create_db_pool(&config);
+    let cpu_pool = CpuPool::new(1);
+    let db_executor = DbExecutorImpl::new(db_pool, cpu_pool);
+    let metrics_service = MetricsServiceImpl::new(
+        Arc::new(config.clone()),
+        Arc::new(AccountsRepoImpl),
+        Arc::new(TransactionsRepoImpl::new(config.system.system_user_id)),
+        Arc::new(PendingBlockchainTransactionsRepoImpl),
+        Arc::new(StrangeBlockchainTransactionsRepoImpl),
+        Arc::new(KeyValuesRepoImpl),
+        db_executor,
+        blockchain_client,
+        vec![],
+        Arc::new(QueryStats::new(&config.database)),
+        Arc::new(RabbitStats::default()),
+    );
+    let currency = currency.map(|currency| match currency {
+        "btc" => Currency::Btc,
+        "eth" => Currency::Eth,
+        "stq" => Currency::Stq,
+        _ => panic!("Unknown currency: {}", currency),
+    });
+    let output = output.to_string();
+    let mut rt = Runtime::new().expect("Could not create tokio runtime");
+    let exit_code = match rt.block_on(metrics_service.reconcile(currency)) {
+        Ok(report) => {
+            let file = File::create(&output).unwrap_or_else(|e| panic!("Failed to create file {}: {}", output, e));
+            serde_json::to_writer_pretty(file, &report).expect("Failed to write report");
+            println!(
+                "Checked {} addresses: {} diverging balances, {} negative balances. Report is written to {}",
+                report.checked_addresses_count,
+                report.diverging_balances.len(),
+                report.negative_balances.len(),
+                output
+            );
+            if report.diverging_balances.is_empty() && report.negative_balances.is_empty() {
+                0
+            } else {
+                1
+            }
+        }
+        Err(e) => {
+            log_error(&e);
+            2
+        }
+    };
+    std::process::exit(exit_code);
+}
 
-        // get fee reversal amount
-        let fee_reversal_amount = if pending_total_amount == total_amount {
-            fee_transaction.value
+pub fn export_transactions(user_id: &str, from: &str, to: &str, format: &str, output: &str) {
+    let config = get_config().unwrap_or_else(exit_on_config_error);
+    let db_pool = create_db_pool(&config);
+    let cpu_pool = CpuPool::new(1);
+    let transactions_repo = TransactionsRepoImpl::new(config.system.system_user_id);
+    let accounts_repo = Arc::new(AccountsRepoImpl);
+    let system_service = Arc::new(SystemServiceImpl::new(accounts_repo.clone()));
+    let converter_service = ConverterServiceImpl::new(
+        accounts_repo,
+        Arc::new(PendingBlockchainTransactionsRepoImpl),
+        Arc::new(BlockchainTransactionsRepoImpl),
+        system_service,
+    );
+    let db_executor = DbExecutorImpl::new(db_pool, cpu_pool);
+    let user_id = UserId::from_str(user_id).unwrap_or_else(|e| {
+        eprintln!("Failed to parse user id {}: {}", user_id, e);
+        std::process::exit(1);
+    });
+    let from = NaiveDate::parse_from_str(from, "%Y-%m-%d")
+        .unwrap_or_else(|e| {
+            eprintln!("Failed to parse from date {}: {}", from, e);
+            std::process::exit(1);
+        })
+        .and_hms(0, 0, 0);
+    // `to` date is inclusive
+    let to = NaiveDate::parse_from_str(to, "%Y-%m-%d")
+        .unwrap_or_else(|e| {
+            eprintln!("Failed to parse to date {}: {}", to, e);
+            std::process::exit(1);
+        })
+        .succ()
+        .and_hms(0, 0, 0);
+    let csv = match format {
+        "csv" => true,
+        "json" => false,
+        _ => {
+            eprintln!("Unknown export format: {}", format);
+            std::process::exit(1);
+        }
+    };
+    // Export goes to a temp file first, so that a failed export never leaves a truncated file at `output`
+    let tmp_output = format!("{}.tmp", output);
+    let mut file = File::create(&tmp_output).unwrap_or_else(|e| {
+        eprintln!("Failed to create file {}: {}", tmp_output, e);
+        std::process::exit(1);
+    });
+    let fut = db_executor.execute(move || -> Result<usize, ServiceError> {
+        if csv {
+            writeln!(
+                file,
+                "id,user_id,from,to,from_value,from_currency,to_value,to_currency,fee,status,blockchain_tx_ids,created_at,updated_at"
+            )
+            .map_err(ectx!(try ServiceErrorKind::Internal))?;
         } else {
-            let value = (fee_transaction.value.raw() as f64) * (pending_total_amount.raw() as f64 / total_amount.raw() as f64);
-            Amount::new(value as u128)
-        };
-
-        let reversal_gid = TransactionId::generate();
-
-        // reverse of withdrawal transactions
-        for transaction in pending_withdrawal_transactions {
-            let hash = transaction.blockchain_tx_id.expect("Failed to get blockchain tx hash");
-            let payload = NewTransaction {
-                id: TransactionId::generate(),
-                gid: reversal_gid,
-                user_id: transaction.user_id,
-                dr_account_id: transaction.cr_account_id,
-                cr_account_id: transaction.dr_account_id,
-                currency: transaction.currency,
-                value: transaction.value,
-                status: TransactionStatus::Done,
-                blockchain_tx_id: Some(hash.clone()),
-                kind: TransactionKind::Withdrawal,
-                group_kind: TransactionGroupKind::Reversal,
-                related_tx: Some(transaction.id),
-                meta: Some(serde_json::Value::String(format!(
-                    "revers of approval transaction with id {}",
-                    transaction.id
-                ))),
-            };
-            transactions_repo.create(payload).expect("Failed to create transaction");
-            transactions_repo
-                .update_status(hash.clone(), TransactionStatus::Done)
-                .expect("Failed to create transaction");
-
-            let pending_transaction = pending_blockchain_transactions_repo
-                .delete(hash.clone())
-                .expect("Failed to delete pending blockchain transaction");
-            let pending_transaction = pending_transaction.expect("Failed to find pending blockchain transaction");
-            let payload: NewBlockchainTransactionDB = pending_transaction.into();
-            blockchain_transactions_repo
-                .create(payload)
-                .expect("Failed to create blockchain transaction");
+            write!(file, "[").map_err(ectx!(try ServiceErrorKind::Internal))?;
         }
+        let mut offset = 0;
+        let mut count = 0;
+        loop {
+            let txs = transactions_repo.list_groups_for_user_in_period(user_id, from, to, offset, EXPORT_BATCH_SIZE)?;
+            if txs.is_empty() {
+                break;
+            }
+            let mut txs_out = group_transactions(&txs)
+                .into_iter()
+                .map(|tx_group| converter_service.convert_transaction(tx_group))
+                .collect::<Result<Vec<TransactionOut>, ServiceError>>()?;
+            txs_out.sort_by_key(|tx| tx.created_at);
+            for tx in txs_out {
+                if csv {
+                    writeln!(file, "{}", transaction_out_to_csv(&tx)).map_err(ectx!(try ServiceErrorKind::Internal))?;
+                } else {
+                    let separator = if count > 0 { "," } else { "" };
+                    let json = serde_json::to_string(&tx).map_err(ectx!(try ServiceErrorKind::Internal => tx.id))?;
+                    write!(file, "{}\n{}", separator, json).map_err(ectx!(try ServiceErrorKind::Internal))?;
+                }
+                count += 1;
+            }
+            offset += EXPORT_BATCH_SIZE;
+        }
+        if !csv {
+            writeln!(file, "\n]").map_err(ectx!(try ServiceErrorKind::Internal))?;
+        }
+        file.sync_all().map_err(ectx!(try ServiceErrorKind::Internal))?;
+        Ok(count)
+    });
+    let mut rt = Runtime::new().expect("Could not create tokio runtime");
+    match rt.block_on(fut) {
+        Ok(count) => {
+            if let Err(e) = std::fs::rename(&tmp_output, output) {
+                eprintln!("Failed to move {} to {}: {}", tmp_output, output, e);
+                let _ = std::fs::remove_file(&tmp_output);
+                std::process::exit(1);
+            }
+            println!("Exported {} transactions to {}", count, output);
+        }
+        Err(e) => {
+            log_error(&e);
+            let _ = std::fs::remove_file(&tmp_output);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn transaction_out_to_csv(tx: &TransactionOut) -> String {
+    let from: Vec<String> = tx.from.iter().map(|info| info.blockchain_address.to_string()).collect();
+    let blockchain_tx_ids: Vec<String> = tx.blockchain_tx_ids.iter().map(|hash| hash.to_string()).collect();
+    [
+        tx.id.to_string(),
+        tx.user_id.to_string(),
+        from.join(";"),
+        tx.to.blockchain_address.to_string(),
+        tx.from_value.raw().to_string(),
+        tx.from_currency.to_string(),
+        tx.to_value.raw().to_string(),
+        tx.to_currency.to_string(),
+        tx.fee.raw().to_string(),
+        format!("{:?}", tx.status).to_lowercase(),
+        blockchain_tx_ids.join(";"),
+        tx.created_at.to_string(),
+        tx.updated_at.to_string(),
+    ]
+    .iter()
+    .map(|field| escape_csv_field(field))
+    .collect::<Vec<_>>()
+    .join(",")
+}
 
-        // reverse of fee transactions
-        let payload = NewTransaction {
-            id: TransactionId::generate(),
-            gid: reversal_gid,
-            user_id: fee_transaction.user_id,
-            dr_account_id: fee_transaction.cr_account_id,
-            cr_account_id: fee_transaction.dr_account_id,
-            currency: fee_transaction.currency,
-            value: fee_reversal_amount,
-            status: TransactionStatus::Done,
-            blockchain_tx_id: fee_transaction.blockchain_tx_id,
-            kind: TransactionKind::Fee,
-            group_kind: TransactionGroupKind::Reversal,
-            related_tx: Some(fee_transaction.id),
-            meta: Some(serde_json::Value::String(format!(
-                "revers of approval fee_transaction with id {}",
-                fee_transaction.id
-            ))),
-        };
-        transactions_repo.create(payload).expect("Failed to create transaction");
+/// Quotes field as per RFC 4180 if it contains separators, quotes or line breaks
+fn escape_csv_field(field: &str) -> String {
+    if field.contains(|c: char| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
 
-        Ok(())
+pub fn repair(older_than_mins: i64, apply: bool) {
+    let config = get_config().unwrap_or_else(exit_on_config_error);
+    let db_pool = create_db_pool(&config);
+    let cpu_pool = CpuPool::new(1);
+    let transactions_repo = Arc::new(TransactionsRepoImpl::new(config.system.system_user_id));
+    let accounts_repo = Arc::new(AccountsRepoImpl);
+    let system_service = Arc::new(SystemServiceImpl::new(accounts_repo.clone()));
+    let converter_service = Arc::new(ConverterServiceImpl::new(
+        accounts_repo.clone(),
+        Arc::new(PendingBlockchainTransactionsRepoImpl),
+        Arc::new(BlockchainTransactionsRepoImpl),
+        system_service.clone(),
+    ));
+    let blockchain_client = Arc::new(BlockchainClientImpl::new(&config, HttpClientImpl::new(&config)));
+    let db_executor = DbExecutorImpl::new(db_pool, cpu_pool);
+    let repair_service = RepairServiceImpl::new(
+        Arc::new(config.clone()),
+        transactions_repo,
+        accounts_repo,
+        Arc::new(BlockchainTransactionsRepoImpl),
+        Arc::new(PendingBlockchainTransactionsRepoImpl),
+        Arc::new(SeenHashesRepoImpl),
+        system_service,
+        converter_service,
+        blockchain_client,
+        db_executor,
+    );
+    let age = chrono::Duration::minutes(older_than_mins);
+    let fut = repair_service.scan(age).and_then(move |actions| {
+        if actions.is_empty() {
+            println!("Nothing to repair");
+        }
+        for action in actions.iter() {
+            println!("{}", action);
+        }
+        if !apply {
+            if !actions.is_empty() {
+                println!("Dry run, use --apply to repair");
+            }
+            return Either::A(future::ok(()));
+        }
+        Either::B(futures::stream::iter_ok(actions).for_each(move |action| {
+            let description = action.to_string();
+            repair_service.apply(action).then(move |res| {
+                match res {
+                    Ok(_) => println!("Repaired: {}", description),
+                    Err(e) => {
+                        log_error(&e);
+                        println!("Failed: {}", description);
+                    }
+                }
+                Ok(())
+            })
+        }))
     });
-    hyper::rt::run(fut.map(|_| ()).map_err(|_| ()));
+    hyper::rt::run(fut.map_err(|e| log_error(&e)));
+}
+
+/// Moves done transaction groups older than `older_than_days` and the latest balance checkpoint to archive,
+/// `batch_size` groups per db transaction, until there's nothing left to archive
+pub fn archive_transactions(older_than_days: i64, batch_size: i64) {
+    let config = get_config().unwrap_or_else(exit_on_config_error);
+    let db_pool = create_db_pool(&config);
+    let cpu_pool = CpuPool::new(1);
+    let transactions_repo = TransactionsRepoImpl::new(config.system.system_user_id);
+    let db_executor = DbExecutorImpl::new(db_pool, cpu_pool);
+    let horizon = chrono::Utc::now().naive_utc() - chrono::Duration::days(older_than_days);
+    let fut = future::loop_fn(0, move |total| {
+        let transactions_repo = transactions_repo.clone();
+        db_executor
+            .execute_transaction(move || transactions_repo.archive_groups(horizon, batch_size))
+            .map(move |archived_count| {
+                let total = total + archived_count;
+                if archived_count == 0 {
+                    println!("Archived {} transaction groups created before {}", total, horizon);
+                    future::Loop::Break(())
+                } else {
+                    println!("Archived {} transaction groups", total);
+                    future::Loop::Continue(total)
+                }
+            })
+    });
+    hyper::rt::run(fut.map_err(|e: ReposError| log_error(&e)));
+}
+
+/// Prunes seen hashes behind retention configured in `seen_hashes_retention`,
+/// the same as background job of the server does
+pub fn prune_seen_hashes() {
+    let config = get_config().unwrap_or_else(exit_on_config_error);
+    let db_pool = create_db_pool(&config);
+    let cpu_pool = CpuPool::new(1);
+    let db_executor = DbExecutorImpl::new(db_pool, cpu_pool);
+    let seen_hashes_service = SeenHashesServiceImpl::new(
+        Arc::new(SeenHashesRepoImpl),
+        Arc::new(KeyValuesRepoImpl),
+        config.seen_hashes_retention.clone(),
+        db_executor,
+    );
+    let fut = seen_hashes_service.prune().map(|results| {
+        for result in results {
+            match result.watermark {
+                Some(watermark) => println!(
+                    "{}: pruned {} hashes before block {}",
+                    result.currency, result.pruned_count, watermark
+                ),
+                None => println!("{}: nothing to prune", result.currency),
+            }
+        }
+    });
+    hyper::rt::run(fut.map_err(|e| log_error(&e)));
+}
+
+pub fn list_quarantined_messages(include_replayed: bool) {
+    let config = get_config().unwrap_or_else(exit_on_config_error);
+    let db_pool = create_db_pool(&config);
+    let cpu_pool = CpuPool::new(1);
+    let db_executor = DbExecutorImpl::new(db_pool, cpu_pool);
+    let quarantined_messages_repo = QuarantinedMessagesRepoImpl;
+    let fut = db_executor
+        .execute(move || quarantined_messages_repo.list(0, i64::max_value(), include_replayed))
+        .map(|messages| {
+            println!(
+                "{:<36} | {:<8} | {:<8} | {:<19} | {:<19} | {}",
+                "id", "currency", "attempts", "created_at", "replayed_at", "error"
+            );
+            for message in messages {
+                let replayed_at = message
+                    .replayed_at
+                    .map(|replayed_at| replayed_at.format("%Y-%m-%d %H:%M:%S").to_string())
+                    .unwrap_or_default();
+                // the last line of error chain is the root cause
+                let error = message.error.lines().rev().find(|line| !line.is_empty()).unwrap_or_default();
+                println!(
+                    "{:<36} | {:<8} | {:<8} | {:<19} | {:<19} | {}",
+                    message.id.to_string(),
+                    message.currency.to_string(),
+                    message.attempts,
+                    message.created_at.format("%Y-%m-%d %H:%M:%S").to_string(),
+                    replayed_at,
+                    error
+                );
+            }
+        });
+    hyper::rt::run(fut.map_err(|e: ReposError| log_error(&e)));
 }
 
+/// Publishes quarantined messages back to their currency queues, either the one with `id` or all not replayed yet
+pub fn replay_quarantined_messages(id: Option<&str>) {
+    let config = get_config().unwrap_or_else(exit_on_config_error);
+    let db_pool = create_db_pool(&config);
+    let cpu_pool = CpuPool::new(1);
+    let db_executor = DbExecutorImpl::new(db_pool, cpu_pool);
+    let quarantined_messages_repo = QuarantinedMessagesRepoImpl;
+    let id = id.map(|id| {
+        uuid::Uuid::parse_str(id).unwrap_or_else(|e| {
+            eprintln!("Failed to parse message id {}: {}", id, e);
+            std::process::exit(1);
+        })
+    });
+    let mut rt = Runtime::new().expect("Could not create tokio runtime");
+    let rabbit_connection_manager = rt
+        .block_on(RabbitConnectionManager::create(&config))
+        .map_err(|e| {
+            log_error(&e);
+        })
+        .expect("Can not create rabbit connection manager");
+    let consumer = TransactionConsumerImpl::new(rabbit_connection_manager, Topology::new(config.rabbit.topology.clone()));
+    let quarantined_messages_repo_clone = quarantined_messages_repo.clone();
+    let messages = rt
+        .block_on(db_executor.execute(move || -> Result<Vec<QuarantinedMessage>, ReposError> {
+            match id {
+                Some(id) => Ok(quarantined_messages_repo_clone.get(id)?.into_iter().collect()),
+                None => quarantined_messages_repo_clone.list(0, i64::max_value(), false),
+            }
+        }))
+        .unwrap_or_else(|e| {
+            log_error(&e);
+            std::process::exit(1);
+        });
+    if messages.is_empty() {
+        println!("No quarantined messages to replay");
+    }
+    for message in messages {
+        let message_id = message.id;
+        // marked after publishing, so that message is never lost. At worst it's replayed twice,
+        // that blockchain fetcher handles as already seen transaction
+        let quarantined_messages_repo = quarantined_messages_repo.clone();
+        let db_executor = db_executor.clone();
+        let fut = consumer
+            .republish(message.currency, message.payload)
+            .map_err(|e| format_error(&e))
+            .and_then(move |_| {
+                db_executor
+                    .execute(move || quarantined_messages_repo.mark_replayed(message_id, chrono::Utc::now().naive_utc()))
+                    .map_err(|e: ReposError| format_error(&e))
+            });
+        match rt.block_on(fut) {
+            Ok(_) => println!("{}: replayed", message_id),
+            Err(e) => println!("{}: failed - {}", message_id, e),
+        }
+    }
+}
+
+/// Moves system account to a fresh address and sends its balance from the former one
+pub fn rotate_system_account_address(account_id: &str) {
+    let config = get_config().unwrap_or_else(exit_on_config_error);
+    let db_pool = create_db_pool(&config);
+    let cpu_pool = CpuPool::new(1);
+    let db_executor = DbExecutorImpl::new(db_pool, cpu_pool);
+    let client = HttpClientImpl::new(&config);
+    let account_id = AccountId::from_str(account_id).expect("Failed to parse account id");
+    let system_accounts_service = SystemAccountsServiceImpl::new(
+        Arc::new(config.clone()),
+        Arc::new(AuthServiceImpl::new(
+            Arc::new(UsersRepoImpl::new(config.system.system_user_id)),
+            db_executor.clone(),
+        )),
+        Arc::new(AccountsRepoImpl),
+        Arc::new(TransactionsRepoImpl::new(config.system.system_user_id)),
+        Arc::new(PendingBlockchainTransactionsRepoImpl),
+        Arc::new(KeyValuesRepoImpl),
+        db_executor.clone(),
+        Arc::new(KeysClientImpl::new(&config, client.clone())),
+        Arc::new(BlockchainClientImpl::new(&config, client.clone())),
+        Arc::new(FailoverExchangeClient::new(
+            &config,
+            client,
+            db_executor,
+            Arc::new(KeyValuesRepoImpl),
+        )),
+    );
+    let fut = system_accounts_service
+        .rotate(account_id)
+        .map(|rotation| {
+            let currency = rotation.currency;
+            println!(
+                "{} {} account {}: {} -> {}",
+                currency, rotation.kind, rotation.account_id, rotation.former_address, rotation.address
+            );
+            println!("Former address is kept by account {}", rotation.former_address_account_id);
+            match rotation.sweep {
+                Some(tx) => println!(
+                    "Sending {} to the new address, transaction {}",
+                    tx.value.to_super_unit_string(currency),
+                    tx.gid
+                ),
+                None => println!("Nothing is sent from the former address"),
+            }
+        })
+        .map_err(|e| log_error(&e));
+    hyper::rt::run(fut);
+}
+
+/// Creates system accounts with ids from config, that are missing, and gives roles to the ones set up
+/// before roles were stored. Other system accounts are added with admin api
 pub fn upsert_system_accounts() {
-    let config = get_config();
+    let config = get_config().unwrap_or_else(exit_on_config_error);
     let client = HttpClientImpl::new(&config);
     let keys_client = KeysClientImpl::new(&config, client.clone());
     let db_pool = create_db_pool(&config);
@@ -470,95 +1891,100 @@ pub fn upsert_system_accounts() {
         .and_then(move |user| {
             let keys_client = keys_client.clone();
             let inputs = [
-                (btc_transfer_account_id, user.id, Currency::Btc, "btc_transfer_account"),
-                (eth_transfer_account_id, user.id, Currency::Eth, "eth_transfer_account"),
-                (stq_transfer_account_id, user.id, Currency::Stq, "stq_transfer_account"),
-                (btc_liquidity_account_id, user.id, Currency::Btc, "btc_liquidity_account"),
-                (eth_liquidity_account_id, user.id, Currency::Eth, "eth_liquidity_account"),
-                (stq_liquidity_account_id, user.id, Currency::Stq, "stq_liquidity_account"),
-                (btc_fees_account_id, user.id, Currency::Btc, "btc_fees_account"),
-                (eth_fees_account_id, user.id, Currency::Eth, "eth_fees_account"),
-                (stq_fees_account_id, user.id, Currency::Stq, "stq_fees_account"),
+                (btc_transfer_account_id, SystemAccountKind::Transfer, Currency::Btc),
+                (eth_transfer_account_id, SystemAccountKind::Transfer, Currency::Eth),
+                (stq_transfer_account_id, SystemAccountKind::Transfer, Currency::Stq),
+                (btc_liquidity_account_id, SystemAccountKind::Liquidity, Currency::Btc),
+                (eth_liquidity_account_id, SystemAccountKind::Liquidity, Currency::Eth),
+                (stq_liquidity_account_id, SystemAccountKind::Liquidity, Currency::Stq),
+                (btc_fees_account_id, SystemAccountKind::Fees, Currency::Btc),
+                (eth_fees_account_id, SystemAccountKind::Fees, Currency::Eth),
+                (stq_fees_account_id, SystemAccountKind::Fees, Currency::Stq),
             ];
+            let user_id = user.id;
             let fs: Vec<_> = inputs
                 .into_iter()
-                .map(move |(account_id, user_id, currency, name)| {
+                .filter_map(move |(account_id, role, currency)| {
                     let keys_client = keys_client.clone();
                     let db_executor = db_executor.clone();
 
-                    upsert_system_account(*account_id, *user_id, *currency, name, keys_client, db_executor)
+                    account_id.map(|account_id| upsert_system_account(account_id, user_id, *role, *currency, keys_client, db_executor))
                 })
                 .collect();
             futures::future::join_all(fs)
         });
 
-    let mut core = ::tokio_core::reactor::Core::new().unwrap();
-    let _ = core.run(f);
+    hyper::rt::run(f.map(|_| ()));
 }
 
 fn upsert_system_account(
     account_id: AccountId,
     user_id: UserId,
+    role: SystemAccountKind,
     currency: Currency,
-    name: &str,
     keys_client: KeysClientImpl,
     db_executor: DbExecutorImpl,
 ) -> impl Future<Item = (), Error = ()> {
-    let name = name.to_string();
+    let name = format!("{}_{}_account", currency, role);
+    let db_executor_ = db_executor.clone();
     db_executor
-        .execute(move || -> Result<(), ReposError> {
-            let accounts_repo = AccountsRepoImpl::default();
-            match accounts_repo.get(account_id)? {
-                Some(_) => Ok(()),
-                None => {
-                    let input = CreateAccountAddress {
-                        id: account_id.inner().clone(),
-                        currency,
-                    };
-                    let mut core = Core::new().unwrap();
-                    let account_address_res = core.run(
-                        keys_client
-                            .create_account_address(input, Role::System)
-                            .map_err(ectx!(try ReposErrorKind::Internal)),
-                    );
-                    if let Err(_) = account_address_res {
-                        // just skip if smth is wrong, like account is already created
-                        return Ok(());
-                    }
-                    let account_address = account_address_res.unwrap();
-                    let new_cr_account = NewAccount {
-                        id: account_id,
-                        user_id,
-                        currency,
-                        address: account_address.clone(),
-                        name: Some(name.clone()),
-                        kind: AccountKind::Cr,
-                        daily_limit_type: Some(DailyLimitType::Unlimited),
-                    };
-                    let dr_account_id = account_id.derive_system_dr_id();
-                    let new_dr_account = NewAccount {
-                        id: dr_account_id,
-                        user_id,
-                        currency,
-                        address: account_address.clone(),
-                        name: Some(format!("{}_deposit", name.clone())),
-                        kind: AccountKind::Dr,
-                        daily_limit_type: Some(DailyLimitType::Unlimited),
-                    };
-                    accounts_repo.create(new_cr_account)?;
-                    accounts_repo.create(new_dr_account)?;
-                    Ok(())
-                }
-            }
+        .execute(move || -> Result<Option<Account>, ReposError> { AccountsRepoImpl::default().get(account_id) })
+        .and_then(move |account| match account {
+            Some(ref account) if account.system_role.is_some() => Either::A(future::ok(())),
+            Some(_) => Either::B(Either::A(db_executor_.execute_transaction(move || -> Result<(), ReposError> {
+                let accounts_repo = AccountsRepoImpl::default();
+                accounts_repo.set_system_role(account_id, Some(role))?;
+                accounts_repo.set_system_role(account_id.derive_system_dr_id(), Some(role))?;
+                Ok(())
+            }))),
+            None => Either::B(Either::B({
+                let input = CreateAccountAddress {
+                    id: account_id.inner().clone(),
+                    currency,
+                };
+                keys_client.create_account_address(input, Role::System).then(move |res| match res {
+                    // just skip if smth is wrong, like account is already created
+                    Err(_) => Either::A(future::ok(())),
+                    Ok(account_address) => Either::B(db_executor_.execute_transaction(move || -> Result<(), ReposError> {
+                        let accounts_repo = AccountsRepoImpl::default();
+                        let new_cr_account = NewAccount {
+                            id: account_id,
+                            user_id,
+                            currency,
+                            address: account_address.clone(),
+                            name: Some(name.clone()),
+                            kind: AccountKind::Cr,
+                            daily_limit_type: Some(DailyLimitType::Unlimited),
+                            meta: None,
+                            labels: None,
+                        };
+                        let dr_account_id = account_id.derive_system_dr_id();
+                        let new_dr_account = NewAccount {
+                            id: dr_account_id,
+                            user_id,
+                            currency,
+                            address: account_address,
+                            name: Some(format!("{}_deposit", name)),
+                            kind: AccountKind::Dr,
+                            daily_limit_type: Some(DailyLimitType::Unlimited),
+                            meta: None,
+                            labels: None,
+                        };
+                        accounts_repo.create(new_cr_account)?;
+                        accounts_repo.create(new_dr_account)?;
+                        accounts_repo.set_system_role(account_id, Some(role))?;
+                        accounts_repo.set_system_role(dr_account_id, Some(role))?;
+                        Ok(())
+                    })),
+                })
+            })),
         })
-        .map(|_| ())
         .map_err(|e| log_error(&e))
 }
 
 fn create_db_pool(config: &Config) -> PgPool {
     let database_url = config.database.url.clone();
-    let manager = ConnectionManager::<PgConnection>::new(database_url.clone());
-    r2d2::Pool::builder()
-        .build(manager)
+    MonitoredPool::create("primary", &database_url, &config.database)
+        .map(|monitored_pool| monitored_pool.pool)
         .unwrap_or_else(|_| panic!("Failed to connect to db with url: {}", database_url))
 }
@@ -10,6 +10,7 @@ use log::{self, LevelFilter as LogLevelFilter, Log, Metadata, Record};
 use simplelog::{Config as SimpleLoggerConfig, WriteLogger};
 
 use config::Config;
+use request_id;
 
 pub struct CombinedLogger {
     pub inner: Vec<Arc<Log>>,
@@ -25,16 +26,34 @@ impl Default for CombinedLogger {
     }
 }
 
+impl CombinedLogger {
+    fn log_to_inner(&self, record: &Record) {
+        for logger in &self.inner {
+            logger.log(record);
+        }
+    }
+}
+
 impl Log for CombinedLogger {
     fn enabled(&self, metadata: &Metadata) -> bool {
         self.inner.iter().any(|logger| logger.enabled(metadata))
     }
 
     fn log(&self, record: &Record) {
-        if (self.filter)(record) {
-            for logger in &self.inner {
-                logger.log(record);
-            }
+        if !(self.filter)(record) {
+            return;
+        }
+        match request_id::current() {
+            Some(request_id) => self.log_to_inner(
+                &Record::builder()
+                    .args(format_args!("[{}] {}", request_id, record.args()))
+                    .metadata(record.metadata().clone())
+                    .module_path(record.module_path())
+                    .file(record.file())
+                    .line(record.line())
+                    .build(),
+            ),
+            None => self.log_to_inner(record),
         }
     }
 
@@ -11,17 +11,61 @@ fn main() {
 
     if let Some(_) = matches.subcommand_matches("config") {
         transactions_lib::print_config();
+    } else if let Some(_) = matches.subcommand_matches("check_config") {
+        transactions_lib::check_config();
     } else if let Some(_) = matches.subcommand_matches("server") {
         transactions_lib::start_server();
+    } else if let Some(matches) = matches.subcommand_matches("migrate") {
+        transactions_lib::migrate(matches.is_present("check"));
+    } else if let Some(matches) = matches.subcommand_matches("rebroadcast_pending") {
+        let older_than = value_t!(matches, "older_than", i64).unwrap_or_else(|e| e.exit());
+        transactions_lib::rebroadcast_pending(older_than, matches.value_of("currency"));
+    } else if let Some(matches) = matches.subcommand_matches("list_stuck_transactions") {
+        let older_than = value_t!(matches, "older_than", i64).unwrap_or_else(|e| e.exit());
+        transactions_lib::list_stuck_transactions(older_than, matches.is_present("json"));
+    } else if let Some(_) = matches.subcommand_matches("verify_balances") {
+        transactions_lib::verify_balances();
+    } else if let Some(matches) = matches.subcommand_matches("reconcile") {
+        let currency = matches.value_of("currency");
+        let output = matches.value_of("output").unwrap();
+        transactions_lib::reconcile(currency, &output);
+    } else if let Some(matches) = matches.subcommand_matches("export_transactions") {
+        let user = matches.value_of("user").unwrap();
+        let from = matches.value_of("from").unwrap();
+        let to = matches.value_of("to").unwrap();
+        let format = matches.value_of("format").unwrap();
+        let output = matches.value_of("output").unwrap();
+        transactions_lib::export_transactions(&user, &from, &to, &format, &output);
+    } else if let Some(matches) = matches.subcommand_matches("rotate_user_token") {
+        let id = matches.value_of("id").unwrap();
+        transactions_lib::rotate_user_token(&id);
+    } else if let Some(matches) = matches.subcommand_matches("disable_user") {
+        let id = matches.value_of("id").unwrap();
+        let enable = matches.is_present("enable");
+        transactions_lib::disable_user(&id, enable);
+    } else if let Some(matches) = matches.subcommand_matches("seed") {
+        let force = matches.is_present("force");
+        transactions_lib::seed(force);
     } else if let Some(matches) = matches.subcommand_matches("create_user") {
         let name = matches.value_of("name").unwrap();
         transactions_lib::create_user(&name);
-    } else if let Some(matches) = matches.subcommand_matches("repair_approval_pending_transaction") {
-        let id = matches.value_of("id").unwrap();
-        transactions_lib::repair_approval_pending_transaction(&id);
-    } else if let Some(matches) = matches.subcommand_matches("repair_withdrawal_pending_transaction") {
-        let id = matches.value_of("id").unwrap();
-        transactions_lib::repair_withdrawal_pending_transaction(&id);
+    } else if let Some(matches) = matches.subcommand_matches("repair") {
+        let older_than = value_t!(matches, "older_than", i64).unwrap_or_else(|e| e.exit());
+        let apply = matches.is_present("apply");
+        transactions_lib::repair(older_than, apply);
+    } else if let Some(_) = matches.subcommand_matches("prune_seen_hashes") {
+        transactions_lib::prune_seen_hashes();
+    } else if let Some(matches) = matches.subcommand_matches("list_quarantined_messages") {
+        transactions_lib::list_quarantined_messages(matches.is_present("all"));
+    } else if let Some(matches) = matches.subcommand_matches("replay_quarantined_messages") {
+        transactions_lib::replay_quarantined_messages(matches.value_of("id"));
+    } else if let Some(matches) = matches.subcommand_matches("rotate_system_account_address") {
+        let account_id = matches.value_of("account_id").unwrap();
+        transactions_lib::rotate_system_account_address(&account_id);
+    } else if let Some(matches) = matches.subcommand_matches("archive_transactions") {
+        let older_than = value_t!(matches, "older_than", i64).unwrap_or_else(|e| e.exit());
+        let batch_size = value_t!(matches, "batch_size", i64).unwrap_or_else(|e| e.exit());
+        transactions_lib::archive_transactions(older_than, batch_size);
     } else {
         let _ = app.print_help();
         println!("\n")
@@ -1,6 +1,6 @@
 use chrono::NaiveDateTime;
-
-use validator::Validate;
+use serde_json::Value;
+use validator::{Validate, ValidationError};
 
 use models::*;
 use schema::accounts;
@@ -18,6 +18,15 @@ pub struct Account {
     pub updated_at: NaiveDateTime,
     pub erc20_approved: bool,
     pub daily_limit_type: DailyLimitType,
+    pub meta: Value,
+    pub labels: Vec<String>,
+    pub archived: bool,
+    /// Confirmed deposits to the account are exchanged to this one of another currency
+    pub auto_convert_to: Option<AccountId>,
+    /// Purpose of system account, accounts of users have none
+    pub system_role: Option<SystemAccountKind>,
+    /// When a withdrawal was last sent from the account, hot accounts are spent from round robin by it
+    pub last_spent_at: Option<NaiveDateTime>,
 }
 
 impl Default for Account {
@@ -33,6 +42,12 @@ impl Default for Account {
             updated_at: ::chrono::Utc::now().naive_utc(),
             erc20_approved: false,
             daily_limit_type: DailyLimitType::DefaultLimit,
+            meta: json!({}),
+            labels: vec![],
+            archived: false,
+            auto_convert_to: None,
+            system_role: None,
+            last_spent_at: None,
         }
     }
 }
@@ -46,6 +61,8 @@ impl From<NewAccount> for Account {
             currency: new_account.currency,
             address: new_account.address,
             kind: new_account.kind,
+            meta: new_account.meta.unwrap_or_else(|| json!({})),
+            labels: new_account.labels.unwrap_or_default(),
             ..Default::default()
         }
     }
@@ -63,6 +80,10 @@ pub struct NewAccount {
     pub name: Option<String>,
     pub kind: AccountKind,
     pub daily_limit_type: Option<DailyLimitType>,
+    #[validate(custom = "valid_meta")]
+    pub meta: Option<Value>,
+    #[validate(custom = "valid_labels")]
+    pub labels: Option<Vec<String>>,
 }
 
 impl Default for NewAccount {
@@ -75,6 +96,8 @@ impl Default for NewAccount {
             address: BlockchainAddress::default(),
             kind: AccountKind::Cr,
             daily_limit_type: None,
+            meta: None,
+            labels: None,
         }
     }
 }
@@ -89,6 +112,8 @@ impl NewAccount {
             address: self.address.clone(),
             kind: AccountKind::Dr,
             daily_limit_type: self.daily_limit_type,
+            meta: None,
+            labels: None,
         }
     }
 }
@@ -100,6 +125,12 @@ pub struct UpdateAccount {
     #[validate(length(min = "1", max = "40", message = "Name must not be empty "))]
     pub name: Option<String>,
     pub erc20_approved: Option<bool>,
+    #[validate(custom = "valid_meta")]
+    pub meta: Option<Value>,
+    #[validate(custom = "valid_labels")]
+    pub labels: Option<Vec<String>>,
+    /// `Some(None)` turns auto conversion off
+    pub auto_convert_to: Option<Option<AccountId>>,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -116,6 +147,10 @@ pub struct CreateAccount {
     #[validate(length(min = "1", max = "40", message = "Name must not be empty "))]
     pub name: String,
     pub daily_limit_type: Option<DailyLimitType>,
+    #[validate(custom = "valid_meta")]
+    pub meta: Option<Value>,
+    #[validate(custom = "valid_labels")]
+    pub labels: Option<Vec<String>>,
 }
 
 impl Default for CreateAccount {
@@ -126,6 +161,8 @@ impl Default for CreateAccount {
             currency: Currency::Eth,
             name: String::default(),
             daily_limit_type: None,
+            meta: None,
+            labels: None,
         }
     }
 }
@@ -140,6 +177,65 @@ impl From<(CreateAccount, BlockchainAddress)> for NewAccount {
             kind: AccountKind::Cr,
             address: create.1,
             daily_limit_type: create.0.daily_limit_type,
+            meta: create.0.meta,
+            labels: create.0.labels,
         }
     }
 }
+
+/// Filter of account listings: accounts having all of `labels` and containing `meta`,
+/// e.g. `{"orderId": "42"}` matches accounts with this order id in meta
+#[derive(Debug, Clone, Default)]
+pub struct AccountsFilter {
+    pub labels: Vec<String>,
+    pub meta: Option<Value>,
+}
+
+/// Whether lookups by address return archived accounts. Deposits are still credited to archived accounts,
+/// so blockchain transactions are matched including them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchivedAccounts {
+    Include,
+    Exclude,
+}
+
+impl ArchivedAccounts {
+    pub fn matches(self, account: &Account) -> bool {
+        self == ArchivedAccounts::Include || !account.archived
+    }
+}
+
+const MAX_META_LEN: usize = 4096;
+const MAX_LABELS: usize = 20;
+const MAX_LABEL_LEN: usize = 40;
+
+fn valid_meta(meta: &Value) -> Result<(), ValidationError> {
+    if !meta.is_object() {
+        let mut error = ValidationError::new("not_object");
+        error.message = Some("Meta must be a json object".into());
+        return Err(error);
+    }
+    if meta.to_string().len() > MAX_META_LEN {
+        let mut error = ValidationError::new("too_long");
+        error.message = Some("Meta is too long".into());
+        error.add_param("max".into(), &MAX_META_LEN);
+        return Err(error);
+    }
+    Ok(())
+}
+
+fn valid_labels(labels: &Vec<String>) -> Result<(), ValidationError> {
+    if labels.len() > MAX_LABELS {
+        let mut error = ValidationError::new("too_many");
+        error.message = Some("Too many labels".into());
+        error.add_param("max".into(), &MAX_LABELS);
+        return Err(error);
+    }
+    if labels.iter().any(|label| label.is_empty() || label.chars().count() > MAX_LABEL_LEN) {
+        let mut error = ValidationError::new("length");
+        error.message = Some("Label must not be empty or longer than 40 characters".into());
+        error.add_param("max".into(), &MAX_LABEL_LEN);
+        return Err(error);
+    }
+    Ok(())
+}
@@ -0,0 +1,23 @@
+use chrono::NaiveDateTime;
+
+use models::*;
+
+/// Turnovers of account, maintained by db trigger on every insert into transactions
+#[derive(Debug, Queryable, Clone)]
+pub struct AccountBalance {
+    pub account_id: AccountId,
+    pub dr_turnover: Amount,
+    pub cr_turnover: Amount,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+impl AccountBalance {
+    /// Balance of account of certain kind, `None` if it is negative
+    pub fn balance(&self, kind: AccountKind) -> Option<Amount> {
+        match kind {
+            AccountKind::Cr => self.cr_turnover.checked_sub(self.dr_turnover),
+            AccountKind::Dr => self.dr_turnover.checked_sub(self.cr_turnover),
+        }
+    }
+}
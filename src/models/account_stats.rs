@@ -0,0 +1,43 @@
+use chrono::Duration;
+
+use models::*;
+
+/// Period ending now, that account statistics are computed over
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+pub enum StatsWindow {
+    #[serde(rename = "24h")]
+    Day,
+    #[serde(rename = "7d")]
+    Week,
+    #[serde(rename = "30d")]
+    Month,
+}
+
+impl Default for StatsWindow {
+    fn default() -> Self {
+        StatsWindow::Day
+    }
+}
+
+impl StatsWindow {
+    pub fn duration(&self) -> Duration {
+        match self {
+            StatsWindow::Day => Duration::hours(24),
+            StatsWindow::Week => Duration::days(7),
+            StatsWindow::Month => Duration::days(30),
+        }
+    }
+}
+
+/// Deposits and withdrawals of account over the window. Withdrawal sent from several
+/// of our addresses is counted once
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccountStats {
+    pub account_id: AccountId,
+    pub currency: Currency,
+    pub window: StatsWindow,
+    pub deposits_count: u64,
+    pub deposits_value: Amount,
+    pub withdrawals_count: u64,
+    pub withdrawals_value: Amount,
+}
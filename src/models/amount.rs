@@ -98,6 +98,35 @@ impl Amount {
         let converted: f64 = (amount as f64) / divisor_f64;
         converted
     }
+
+    /// Inverse of `to_super_unit` with the same precision, negative values become zero
+    pub fn from_super_unit(currency: Currency, value: f64) -> Amount {
+        let (precision, rest) = match currency {
+            Currency::Btc => (MAX_SATOSHIS_PRECISION, SATOSHIS_IN_BTC - MAX_SATOSHIS_PRECISION),
+            Currency::Eth => (MAX_WEI_PRECISION, WEI_IN_ETH - MAX_WEI_PRECISION),
+            Currency::Stq => (MAX_WEI_PRECISION, WEI_IN_ETH - MAX_WEI_PRECISION),
+        };
+        let amount = (value * 10u128.pow(precision) as f64).round().max(0.0) as u128;
+        Amount::new(amount * 10u128.pow(rest))
+    }
+
+    /// Exact decimal representation in super units, e.g. `0.1` for 10^17 wei.
+    /// Unlike `to_super_unit` it doesn't lose precision, so it's safe to show to clients
+    pub fn to_super_unit_string(&self, current_currency: Currency) -> String {
+        let decimals = match current_currency {
+            Currency::Btc => SATOSHIS_IN_BTC,
+            Currency::Eth => WEI_IN_ETH,
+            Currency::Stq => WEI_IN_ETH,
+        };
+        let divisor = 10u128.pow(decimals);
+        let fraction = format!("{:0width$}", self.0 % divisor, width = decimals as usize);
+        let fraction = fraction.trim_end_matches('0');
+        if fraction.is_empty() {
+            format!("{}", self.0 / divisor)
+        } else {
+            format!("{}.{}", self.0 / divisor, fraction)
+        }
+    }
 }
 
 impl<'a> From<&'a Amount> for PgNumeric {
@@ -284,6 +313,17 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_from_super_unit() {
+        assert_eq!(Amount::from_super_unit(Currency::Eth, 0.1), Amount::new(100_000_000_000_000_000));
+        assert_eq!(
+            Amount::from_super_unit(Currency::Stq, 1500.0),
+            Amount::new(1_500_000_000_000_000_000_000)
+        );
+        assert_eq!(Amount::from_super_unit(Currency::Btc, 0.01), Amount::new(1_000_000));
+        assert_eq!(Amount::from_super_unit(Currency::Btc, -1.0), Amount::new(0));
+    }
+
     #[test]
     fn test_to_super_unit() {
         let cases = [
@@ -309,6 +349,21 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_to_super_unit_string() {
+        let cases = [
+            (100_000_000_000_000_000, Currency::Eth, "0.1"),
+            (1_000_000_000_000_000_001, Currency::Stq, "1.000000000000000001"),
+            (123_456_789, Currency::Btc, "1.23456789"),
+            (100_000, Currency::Btc, "0.001"),
+            (0, Currency::Btc, "0"),
+            (u128::max_value(), Currency::Eth, "340282366920938463463.374607431768211455"),
+        ];
+        for (amount, currency, expected) in cases.into_iter() {
+            assert_eq!(Amount::new(*amount).to_super_unit_string(*currency), *expected);
+        }
+    }
+
     #[test]
     fn test_pg_numeric_happy_conversions() {
         let cases = [
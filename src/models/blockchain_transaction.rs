@@ -55,6 +55,26 @@ impl ToSql<VarChar, Pg> for Erc20OperationKind {
     }
 }
 
+/// Value transfer made by contract while executing eth transaction,
+/// taken by blockchain gateway from transaction traces
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct BlockchainInternalTransfer {
+    pub from: BlockchainAddress,
+    pub to: BlockchainAddress,
+    pub value: Amount,
+}
+
+/// Erc-20 Transfer event of transaction
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct BlockchainTransferLog {
+    pub log_index: u64,
+    pub from: BlockchainAddress,
+    pub to: BlockchainAddress,
+    pub value: Amount,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
 pub struct BlockchainTransaction {
@@ -66,6 +86,10 @@ pub struct BlockchainTransaction {
     pub fee: Amount,
     pub confirmations: usize,
     pub erc20_operation_kind: Option<Erc20OperationKind>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub internal_transfers: Vec<BlockchainInternalTransfer>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub logs: Vec<BlockchainTransferLog>,
 }
 
 impl BlockchainTransaction {
@@ -119,6 +143,42 @@ impl BlockchainTransaction {
         Some(BlockchainTransaction { from, to, ..self.clone() })
     }
 
+    /// Transaction with internal transfers merged into `from` and `to`, so that
+    /// contract initiated transfers are handled the same way as direct ones
+    pub fn with_internal_transfers(&self) -> BlockchainTransaction {
+        let mut tx = self.clone();
+        for transfer in tx.internal_transfers.drain(..) {
+            tx.from.push(transfer.from);
+            tx.to.push(BlockchainTransactionEntryTo {
+                address: transfer.to,
+                value: transfer.value,
+            });
+        }
+        tx
+    }
+
+    /// Splits erc-20 transaction with several transfer logs into one transaction per log
+    /// with `{hash}:{log index}` ids, the same as if the logs were published separately.
+    /// Transaction without logs is returned as is
+    pub fn split_logs(&self) -> Vec<BlockchainTransaction> {
+        if self.logs.is_empty() {
+            return vec![self.clone()];
+        }
+        self.logs
+            .iter()
+            .map(|log| BlockchainTransaction {
+                hash: self.hash.with_log_index(log.log_index),
+                from: vec![log.from.clone()],
+                to: vec![BlockchainTransactionEntryTo {
+                    address: log.to.clone(),
+                    value: log.value,
+                }],
+                logs: vec![],
+                ..self.clone()
+            })
+            .collect()
+    }
+
     pub fn value(&self) -> Option<Amount> {
         self.to
             .iter()
@@ -172,6 +232,8 @@ impl From<BlockchainTransactionDB> for BlockchainTransaction {
             fee: transaction.fee,
             confirmations: transaction.confirmations as usize,
             erc20_operation_kind: transaction.erc20_operation_kind,
+            internal_transfers: vec![],
+            logs: vec![],
         }
     }
 }
@@ -308,4 +370,49 @@ mod tests {
         };
         assert_eq!(tx.value(), None);
     }
+
+    #[test]
+    fn test_with_internal_transfers() {
+        let user = BlockchainAddress::new("user".to_string());
+        let contract = BlockchainAddress::new("contract".to_string());
+        let deposit = BlockchainAddress::new("deposit".to_string());
+        let tx: BlockchainTransaction = serde_json::from_str(
+            r#"{"hash":"1","from":["user"],"to":[{"address":"contract","value":0}],"blockNumber":1,"currency":"eth","fee":0,"confirmations":0,"erc20OperationKind":null,"internalTransfers":[{"from":"contract","to":"deposit","value":100}]}"#,
+        )
+        .unwrap();
+        let normalized_tx = tx.with_internal_transfers().normalized().unwrap();
+        assert_eq!(normalized_tx.from, vec![contract, user]);
+        assert_eq!(
+            normalized_tx.to,
+            vec![BlockchainTransactionEntryTo {
+                address: deposit,
+                value: Amount::new(100),
+            }]
+        );
+        assert!(normalized_tx.internal_transfers.is_empty());
+    }
+
+    #[test]
+    fn test_split_logs() {
+        let tx: BlockchainTransaction = serde_json::from_str(
+            r#"{"hash":"0xab","from":["a"],"to":[{"address":"b","value":30}],"blockNumber":1,"currency":"stq","fee":5,"confirmations":0,"erc20OperationKind":null,"logs":[{"logIndex":0,"from":"a","to":"b","value":10},{"logIndex":3,"from":"a","to":"c","value":20}]}"#,
+        )
+        .unwrap();
+        let txs = tx.split_logs();
+        assert_eq!(txs.len(), 2);
+        assert_eq!(txs[0].hash, BlockchainTransactionId::new("0xab:0".to_string()));
+        assert_eq!(txs[1].hash, BlockchainTransactionId::new("0xab:3".to_string()));
+        assert_eq!(txs[1].from, vec![BlockchainAddress::new("a".to_string())]);
+        assert_eq!(
+            txs[1].to,
+            vec![BlockchainTransactionEntryTo {
+                address: BlockchainAddress::new("c".to_string()),
+                value: Amount::new(20),
+            }]
+        );
+        assert!(txs.iter().all(|tx| tx.logs.is_empty() && tx.block_number == 1));
+
+        let tx = BlockchainTransaction::default();
+        assert_eq!(tx.split_logs(), vec![tx]);
+    }
 }
@@ -3,7 +3,9 @@ use std::fmt::{self, Display};
 use diesel::sql_types::Varchar;
 use uuid::Uuid;
 
-#[derive(Debug, Serialize, Deserialize, FromSqlRow, AsExpression, Clone, PartialEq, Eq)]
+const UNBROADCASTED_PREFIX: &str = "unbroadcasted:";
+
+#[derive(Debug, Serialize, Deserialize, FromSqlRow, AsExpression, Clone, PartialEq, Eq, Hash)]
 #[sql_type = "Varchar"]
 pub struct BlockchainTransactionId(String);
 derive_newtype_sql!(blockchain_transaction_id, Varchar, BlockchainTransactionId, BlockchainTransactionId);
@@ -16,6 +18,21 @@ impl BlockchainTransactionId {
     pub fn inner(&self) -> &str {
         &self.0
     }
+
+    /// Placeholder for transaction that is signed, but was not accepted by blockchain gateway yet.
+    /// It's replaced with the real hash once the transaction is rebroadcasted
+    pub fn unbroadcasted() -> Self {
+        BlockchainTransactionId(format!("{}{}", UNBROADCASTED_PREFIX, Uuid::new_v4()))
+    }
+
+    pub fn is_unbroadcasted(&self) -> bool {
+        self.0.starts_with(UNBROADCASTED_PREFIX)
+    }
+
+    /// Id of erc-20 transfer, blockchain gateway publishes transfers as `{tx hash}:{log index}`
+    pub fn with_log_index(&self, log_index: u64) -> Self {
+        BlockchainTransactionId(format!("{}:{}", self.0, log_index))
+    }
 }
 
 impl Default for BlockchainTransactionId {
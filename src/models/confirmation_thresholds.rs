@@ -0,0 +1,144 @@
+use validator::{Validate, ValidationError};
+
+use models::*;
+
+const BTC_DECIMALS: u128 = 100_000_000u128;
+const ETH_DECIMALS: u128 = 1_000_000_000_000_000_000u128;
+const STQ_DECIMALS: u128 = 1_000_000_000_000_000_000u128;
+
+/// Number of blockchain confirmations required for transaction depends on its approximate usd value:
+/// i-th threshold is the max value that requires i confirmations, values above the last one require
+/// as many confirmations as there are thresholds. Eth thresholds are used for stq as well.
+#[derive(Debug, Serialize, Deserialize, Validate, Clone, PartialEq)]
+pub struct ConfirmationThresholds {
+    #[validate(custom = "valid_usd_price")]
+    pub usd_per_btc: f64,
+    #[validate(custom = "valid_usd_price")]
+    pub usd_per_eth: f64,
+    #[validate(custom = "valid_usd_price")]
+    pub usd_per_stq: f64,
+    #[validate(custom = "valid_thresholds")]
+    pub btc_thresholds: Vec<u64>,
+    #[validate(custom = "valid_thresholds")]
+    pub eth_thresholds: Vec<u64>,
+}
+
+impl Default for ConfirmationThresholds {
+    fn default() -> Self {
+        Self {
+            usd_per_btc: 6500.0,
+            usd_per_eth: 200.0,
+            usd_per_stq: 0.0025,
+            btc_thresholds: vec![100, 500, 1000],
+            eth_thresholds: vec![20, 50, 200, 500, 1000, 2000, 3000, 4000, 5000],
+        }
+    }
+}
+
+impl ConfirmationThresholds {
+    pub fn required_confirmations(&self, currency: Currency, value: Amount) -> u64 {
+        let usd_value = self.to_usd_approx(currency, value);
+        let thresholds = match currency {
+            Currency::Btc => &self.btc_thresholds,
+            _ => &self.eth_thresholds,
+        };
+        let mut res = None;
+        for (i, threshold) in thresholds.iter().enumerate() {
+            if *threshold >= usd_value {
+                res = Some(i as u64);
+                break;
+            }
+        }
+        res.unwrap_or(thresholds.len() as u64)
+    }
+
+    fn to_usd_approx(&self, currency: Currency, value: Amount) -> u64 {
+        let (rate, decimals) = match currency {
+            Currency::Btc => (self.usd_per_btc, BTC_DECIMALS),
+            Currency::Eth => (self.usd_per_eth, ETH_DECIMALS),
+            Currency::Stq => (self.usd_per_stq, STQ_DECIMALS),
+        };
+        // Max of all rates
+        let max_rate = self.usd_per_btc.max(self.usd_per_eth).max(self.usd_per_stq).max(1.0).ceil() as u128;
+        // first multiply by max_rate and then divide by it
+        // that is made so that we can use integer division of u128 (f64 is not enough)
+        // and be sure that our error is less that 1 dollar
+        let crypto_value_times_rate: u128 = value.raw().saturating_mul(max_rate) / decimals;
+        // after dividing by decimals we have value small enough to be used as f64
+        let usd_value: f64 = (crypto_value_times_rate as f64) * rate / (max_rate as f64);
+        usd_value as u64
+    }
+}
+
+/// Confirmation thresholds in effect, either from config or overridden in runtime
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfirmationThresholdsSettings {
+    pub thresholds: ConfirmationThresholds,
+    pub overridden: bool,
+}
+
+fn valid_usd_price(input: f64) -> Result<(), ValidationError> {
+    if input.is_finite() && input > 0f64 {
+        Ok(())
+    } else {
+        let mut error = ValidationError::new("le_zero");
+        error.message = Some("Value is less or equal zero".into());
+        error.add_param("value".into(), &input.to_string());
+        Err(error)
+    }
+}
+
+fn valid_thresholds(input: &Vec<u64>) -> Result<(), ValidationError> {
+    if input.windows(2).all(|pair| pair[0] < pair[1]) {
+        Ok(())
+    } else {
+        let mut error = ValidationError::new("not_ascending");
+        error.message = Some("Thresholds must be in ascending order".into());
+        Err(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_required_confirmations() {
+        let thresholds = ConfirmationThresholds::default();
+        let cases = [
+            (Currency::Btc, Amount::new(100_000_000), 3),                       // 6500
+            (Currency::Btc, Amount::new(10_000_000), 2),                        // 650
+            (Currency::Btc, Amount::new(5_000_000), 1),                         // 325
+            (Currency::Btc, Amount::new(1_000_000), 0),                         // 65
+            (Currency::Eth, Amount::new(21_000_000_000_000_000_000), 8),        // 4400
+            (Currency::Eth, Amount::new(2_000_000_000_000_000_000), 3),         // 400
+            (Currency::Eth, Amount::new(500_000_000_000_000_000), 2),           // 100
+            (Currency::Eth, Amount::new(50_000_000_000_000_000), 0),            // 10
+            (Currency::Stq, Amount::new(2_100_000_000_000_000_000_000_000), 9), // 5250
+            (Currency::Stq, Amount::new(210_000_000_000_000_000_000_000), 4),   // 525
+            (Currency::Stq, Amount::new(100_000_000_000_000_000_000_000), 3),   // 250
+            (Currency::Stq, Amount::new(10_000_000_000_000_000_000_000), 1),    // 25
+            (Currency::Stq, Amount::new(5_000_000_000_000_000_000_000), 0),     // 12
+        ];
+        for (currency, value, confirms) in cases.iter() {
+            assert_eq!(
+                thresholds.required_confirmations(*currency, *value),
+                *confirms,
+                "Currency: {:?}, value: {:?}, confirms: {:?}",
+                *currency,
+                *value,
+                *confirms
+            );
+        }
+    }
+
+    #[test]
+    fn test_raised_thresholds() {
+        let mut thresholds = ConfirmationThresholds::default();
+        thresholds.btc_thresholds = vec![0, 0, 0];
+        assert!(thresholds.validate().is_err());
+        thresholds.btc_thresholds = vec![10, 20, 50, 100, 500, 1000];
+        assert!(thresholds.validate().is_ok());
+        assert_eq!(thresholds.required_confirmations(Currency::Btc, Amount::new(10_000_000)), 5);
+    }
+}
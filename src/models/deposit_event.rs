@@ -0,0 +1,35 @@
+use models::*;
+
+/// Deposit to user's account, published on its own routing key on every new confirmation
+/// and once it is credited, so that notifications don't have to classify all transactions.
+/// Pending and credited deposit have the same id
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct DepositEvent {
+    pub id: TransactionId,
+    pub user_id: UserId,
+    pub account_id: AccountId,
+    pub currency: Currency,
+    pub address: BlockchainAddress,
+    pub value: Amount,
+    pub blockchain_tx_id: BlockchainTransactionId,
+    pub confirmations: i32,
+    pub required_confirmations: i32,
+    pub status: TransactionStatus,
+}
+
+impl From<PendingDeposit> for DepositEvent {
+    fn from(deposit: PendingDeposit) -> Self {
+        Self {
+            id: deposit.id,
+            user_id: deposit.user_id,
+            account_id: deposit.account_id,
+            currency: deposit.currency,
+            address: deposit.address,
+            value: deposit.value,
+            blockchain_tx_id: deposit.blockchain_tx_id,
+            confirmations: deposit.confirmations,
+            required_confirmations: deposit.required_confirmations,
+            status: TransactionStatus::Pending,
+        }
+    }
+}
@@ -51,6 +51,9 @@ pub struct ExchangeInput {
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct Exchange {
+    /// Echoes id of `ExchangeInput`, gateways that don't send it can't be checked
+    #[serde(default)]
+    pub id: Option<ExchangeId>,
     pub from: Currency,
     pub to: Currency,
     pub amount: Amount,
@@ -90,6 +93,84 @@ pub struct Rate {
     pub expiration: NaiveDateTime,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
+    /// Rate is taken from cache, because exchange gateway is unavailable
+    #[serde(default)]
+    pub is_stale: bool,
+}
+
+impl Rate {
+    /// Rate quoted to users, `spread` of the exchanged value is kept by us
+    pub fn with_spread(self, spread: f64) -> Self {
+        Self {
+            rate: self.rate * (1.0 - spread),
+            ..self
+        }
+    }
+}
+
+/// Rate of exchange gateway, that the rate quoted to users with `spread` is derived from
+pub fn gateway_rate(rate: f64, spread: f64) -> f64 {
+    rate / (1.0 - spread)
+}
+
+/// Values of both sides of exchange of `value` in `value_currency`, that must be one of the sides
+pub fn exchanged_values(from: Currency, to: Currency, value: Amount, value_currency: Currency, rate: f64) -> Option<(Amount, Amount)> {
+    if from == value_currency {
+        Some((value, value.convert(from, to, rate)))
+    } else if to == value_currency {
+        Some((value.convert(to, from, 1.0 / rate), value))
+    } else {
+        None
+    }
+}
+
+/// Part of exchange kept in liquidity account in `to` currency, when `to_value` is less than `from_value`
+/// exchanged at gateway rate
+pub fn exchange_margin(from: Currency, to: Currency, from_value: Amount, to_value: Amount, gateway_rate: f64) -> Amount {
+    from_value.convert(from, to, gateway_rate).checked_sub(to_value).unwrap_or_default()
+}
+
+/// Preview of exchange at the current rate, computed the same way as the exchange itself
+#[derive(Debug, Clone)]
+pub struct ExchangeQuote {
+    pub exchange_id: ExchangeId,
+    pub from: Currency,
+    pub to: Currency,
+    /// Rate quoted to user, less the spread
+    pub rate: f64,
+    pub gateway_rate: f64,
+    pub spread: f64,
+    pub from_value: Amount,
+    /// Value credited to user
+    pub to_value: Amount,
+    /// Value that would be credited without spread
+    pub gross_to_value: Amount,
+    /// Kept in liquidity account, in `to` currency
+    pub margin: Amount,
+    pub expiration: NaiveDateTime,
+    pub is_stale: bool,
+}
+
+impl ExchangeQuote {
+    /// `rate` is the one quoted to user, `None` if amount currency is not one of the pair
+    pub fn new(rate: Rate, spread: f64) -> Option<Self> {
+        let (from_value, to_value) = exchanged_values(rate.from, rate.to, rate.amount, rate.amount_currency, rate.rate)?;
+        let gateway_rate = gateway_rate(rate.rate, spread);
+        Some(Self {
+            exchange_id: rate.id,
+            from: rate.from,
+            to: rate.to,
+            rate: rate.rate,
+            gateway_rate,
+            spread,
+            from_value,
+            to_value,
+            gross_to_value: from_value.convert(rate.from, rate.to, gateway_rate),
+            margin: exchange_margin(rate.from, rate.to, from_value, to_value, gateway_rate),
+            expiration: rate.expiration,
+            is_stale: rate.is_stale,
+        })
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -104,3 +185,60 @@ pub struct RateRefresh {
     pub exchange: Rate,
     pub is_new_rate: bool,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    #[test]
+    fn test_exchange_quote() {
+        let now = ::chrono::Utc::now().naive_utc();
+        let rate = Rate {
+            id: ExchangeId::generate(),
+            from: Currency::Stq,
+            to: Currency::Eth,
+            amount: Amount::new(1_000_000_000_000_000_000_000),
+            amount_currency: Currency::Stq,
+            rate: 0.001,
+            expiration: now,
+            created_at: now,
+            updated_at: now,
+            is_stale: false,
+        };
+        let quote = ExchangeQuote::new(rate.clone().with_spread(0.1), 0.1).unwrap();
+        assert_eq!(quote.from_value, Amount::new(1_000_000_000_000_000_000_000));
+        assert_eq!(quote.to_value, Amount::new(900_000_000_000_000_000));
+        assert_eq!(quote.gross_to_value, Amount::new(1_000_000_000_000_000_000));
+        assert_eq!(quote.margin, Amount::new(100_000_000_000_000_000));
+
+        // value in `to` currency is what user gets
+        let quote = ExchangeQuote::new(
+            Rate {
+                amount: Amount::new(900_000_000_000_000_000),
+                amount_currency: Currency::Eth,
+                ..rate.clone().with_spread(0.1)
+            },
+            0.1,
+        )
+        .unwrap();
+        assert_eq!(quote.from_value, Amount::new(1_000_000_000_000_000_000_000));
+        assert_eq!(quote.margin, Amount::new(100_000_000_000_000_000));
+
+        let other_currency = Rate {
+            amount_currency: Currency::Btc,
+            ..rate
+        };
+        assert!(ExchangeQuote::new(other_currency, 0.1).is_none());
+    }
+
+    #[test]
+    fn test_exchange_id_is_optional() {
+        let exchange_id = ExchangeId::generate();
+        let payload = format!(r#"{{"id":"{}","from":"stq","to":"eth","amount":100}}"#, exchange_id.inner());
+        let exchange: Exchange = serde_json::from_str(&payload).unwrap();
+        assert_eq!(exchange.id, Some(exchange_id));
+        let exchange: Exchange = serde_json::from_str(r#"{"from":"stq","to":"eth","amount":100}"#).unwrap();
+        assert_eq!(exchange.id, None);
+    }
+}
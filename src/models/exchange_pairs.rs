@@ -0,0 +1,45 @@
+use validator::{Validate, ValidationError};
+
+use models::*;
+
+/// Direction in which users can exchange currencies
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct ExchangePair {
+    pub from: Currency,
+    pub to: Currency,
+}
+
+/// Currency pairs users can exchange, other pairs are rejected. Each direction is listed separately,
+/// so that e.g. stq to btc can be paused while btc to stq is still allowed
+#[derive(Debug, Serialize, Deserialize, Validate, Clone, PartialEq)]
+pub struct ExchangePairs {
+    #[validate(custom = "valid_pairs")]
+    pub pairs: Vec<ExchangePair>,
+}
+
+impl ExchangePairs {
+    pub fn allows(&self, from: Currency, to: Currency) -> bool {
+        self.pairs.contains(&ExchangePair { from, to })
+    }
+}
+
+/// Allowed exchange pairs, either from config or overridden in runtime
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExchangePairsSettings {
+    pub pairs: ExchangePairs,
+    pub overridden: bool,
+}
+
+fn valid_pairs(input: &Vec<ExchangePair>) -> Result<(), ValidationError> {
+    if input.iter().any(|pair| pair.from == pair.to) {
+        let mut error = ValidationError::new("same_currency");
+        error.message = Some("Currencies of a pair must differ".into());
+        return Err(error);
+    }
+    if input.iter().enumerate().any(|(i, pair)| input[..i].contains(pair)) {
+        let mut error = ValidationError::new("duplicate_pair");
+        error.message = Some("Pairs must not repeat".into());
+        return Err(error);
+    }
+    Ok(())
+}
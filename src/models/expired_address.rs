@@ -0,0 +1,30 @@
+use chrono::NaiveDateTime;
+
+use models::*;
+use schema::expired_addresses;
+
+/// Former address of an account. Funds that still arrive to it are credited to the account
+#[derive(Debug, Queryable, Clone, PartialEq)]
+pub struct ExpiredAddress {
+    pub address: BlockchainAddress,
+    pub currency: Currency,
+    pub account_id: AccountId,
+    pub expired_at: NaiveDateTime,
+}
+
+#[derive(Debug, Insertable, Clone)]
+#[table_name = "expired_addresses"]
+pub struct NewExpiredAddress {
+    pub address: BlockchainAddress,
+    pub currency: Currency,
+    pub account_id: AccountId,
+}
+
+/// Address that deposits should be made to, and the ones that were used before
+#[derive(Debug, Clone)]
+pub struct AccountAddresses {
+    pub account_id: AccountId,
+    pub active: BlockchainAddress,
+    /// Newest first
+    pub historical: Vec<ExpiredAddress>,
+}
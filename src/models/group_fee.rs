@@ -0,0 +1,99 @@
+use chrono::NaiveDateTime;
+
+use models::*;
+use schema::group_fees;
+
+/// Fee charged by transaction group and network fees actually paid for its blockchain transactions
+#[derive(Debug, Queryable, Clone, PartialEq)]
+pub struct GroupFee {
+    pub gid: TransactionId,
+    pub fee_currency: Currency,
+    /// Charged from user, zero for groups that are not charged, e.g. sweeps
+    pub fee: Amount,
+    pub blockchain_fee_currency: Currency,
+    /// Charged fee in the currency of network fees, at the rate it was estimated with
+    pub charged_blockchain_fee: Amount,
+    /// Accrued as blockchain transactions of the group are confirmed
+    pub blockchain_fee: Amount,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+impl GroupFee {
+    /// Charged less actually paid, in super units of network fees currency. Negative if the group costs us
+    pub fn margin(&self) -> f64 {
+        let currency = self.blockchain_fee_currency;
+        self.charged_blockchain_fee.to_super_unit(currency) - self.blockchain_fee.to_super_unit(currency)
+    }
+
+    /// Share of charged fee to return, if network fee paid is lower than the estimate by more than `threshold`
+    /// of it. Estimate is charged fee less `fee_upside`, the upside is kept for the refunded part as well
+    pub fn refund_share(&self, fee_upside: f64, threshold: f64) -> Option<f64> {
+        if self.fee.raw() == 0 || self.charged_blockchain_fee.raw() == 0 {
+            return None;
+        }
+        let estimate = self.charged_blockchain_fee.raw() as f64 / fee_upside;
+        let share = 1.0 - self.blockchain_fee.raw() as f64 / estimate;
+        if share > threshold {
+            Some(share)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Insertable, Clone)]
+#[table_name = "group_fees"]
+pub struct NewGroupFee {
+    pub gid: TransactionId,
+    pub fee_currency: Currency,
+    pub fee: Amount,
+    pub blockchain_fee_currency: Currency,
+    pub charged_blockchain_fee: Amount,
+    pub blockchain_fee: Amount,
+}
+
+impl NewGroupFee {
+    /// Network fee of group, that wasn't charged for
+    pub fn uncharged(gid: TransactionId, blockchain_fee_currency: Currency, blockchain_fee: Amount) -> Self {
+        Self {
+            gid,
+            fee_currency: blockchain_fee_currency,
+            fee: Amount::new(0),
+            blockchain_fee_currency,
+            charged_blockchain_fee: Amount::new(0),
+            blockchain_fee,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn group_fee(charged_blockchain_fee: u128, blockchain_fee: u128) -> GroupFee {
+        let now = ::chrono::Utc::now().naive_utc();
+        GroupFee {
+            gid: TransactionId::generate(),
+            fee_currency: Currency::Stq,
+            fee: Amount::new(1_000),
+            blockchain_fee_currency: Currency::Eth,
+            charged_blockchain_fee: Amount::new(charged_blockchain_fee),
+            blockchain_fee: Amount::new(blockchain_fee),
+            created_at: now,
+            updated_at: now,
+        }
+    }
+
+    #[test]
+    fn test_refund_share() {
+        // estimated 100 with upside 2, paid 25
+        assert_eq!(group_fee(200, 25).refund_share(2.0, 0.5), Some(0.75));
+        // paid 60, 40% less than estimated
+        assert_eq!(group_fee(200, 60).refund_share(2.0, 0.5), None);
+        // paid more than estimated
+        assert_eq!(group_fee(200, 150).refund_share(2.0, 0.0), None);
+        // not charged
+        assert_eq!(group_fee(0, 25).refund_share(2.0, 0.5), None);
+    }
+}
@@ -1,4 +1,6 @@
-use chrono::NaiveDateTime;
+use std::fmt;
+
+use chrono::{Duration, NaiveDateTime};
 
 use schema::key_values;
 
@@ -8,11 +10,81 @@ pub struct KeyValue {
     pub value: serde_json::Value,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
+    pub namespace: String,
+    pub version: i64,
+    pub expires_at: Option<NaiveDateTime>,
+}
+
+impl KeyValue {
+    pub fn is_expired(&self, now: NaiveDateTime) -> bool {
+        self.expires_at.map(|expires_at| expires_at <= now).unwrap_or(false)
+    }
 }
 
 #[derive(Debug, Insertable, Clone)]
 #[table_name = "key_values"]
 pub struct NewKeyValue {
+    pub namespace: String,
     pub key: String,
     pub value: serde_json::Value,
+    pub expires_at: Option<NaiveDateTime>,
+}
+
+impl NewKeyValue {
+    pub fn new(namespace: KeyNamespace, key: &str, value: serde_json::Value, ttl: Option<Duration>) -> Self {
+        Self {
+            namespace: namespace.to_string(),
+            key: key.to_string(),
+            value,
+            expires_at: ttl.map(|ttl| ::chrono::Utc::now().naive_utc() + ttl),
+        }
+    }
+}
+
+/// Part of key values owned by one feature, keys of different namespaces never collide
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyNamespace {
+    /// Next nonce of ethereum address, keyed by address
+    Nonce,
+    /// Block number, hashes of transactions before which are pruned from seen hashes, keyed by currency
+    SeenHashesWatermark,
+    /// Per user override of transaction limits from config, keyed by user id
+    TransactionLimits,
+    /// Runtime overrides of config, keyed by setting name
+    Settings,
+    /// Outcome of the latest reconciliation of addresses, keyed by currency
+    Reconciliation,
+    /// Url of fallback exchange gateway that issued the rate, keyed by exchange id
+    ExchangeGateway,
+}
+
+impl KeyNamespace {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KeyNamespace::Nonce => "nonce",
+            KeyNamespace::SeenHashesWatermark => "seen_hashes_watermark",
+            KeyNamespace::TransactionLimits => "transaction_limits",
+            KeyNamespace::Settings => "settings",
+            KeyNamespace::Reconciliation => "reconciliation",
+            KeyNamespace::ExchangeGateway => "exchange_gateway",
+        }
+    }
+}
+
+impl fmt::Display for KeyNamespace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Version of the stored value a write is based on. Write fails with conflict
+/// if the value was changed by someone else since it was read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExpectedVersion {
+    /// Unconditional write
+    Any,
+    /// Key must not exist or be expired
+    Absent,
+    /// Value must still have this version
+    Exact(i64),
 }
@@ -1,3 +1,5 @@
+use chrono::NaiveDateTime;
+
 use models::*;
 use std::collections::HashMap;
 
@@ -10,6 +12,10 @@ pub struct Metrics {
     pub total_blockchain_balances: HashMap<Currency, f64>,
     pub fees_balances: HashMap<Currency, f64>,
     pub liquidity_balances: HashMap<Currency, f64>,
+    /// Part of liquidity balances earned with exchange spreads
+    pub exchange_margins: HashMap<Currency, f64>,
+    /// Network fees charged from users less actually paid, by network fees currency. Negative if fees cost us
+    pub fee_margins: HashMap<Currency, f64>,
     pub limits: HashMap<Currency, f64>,
     pub diverging_blockchain_balances: Vec<DivergingBalance>,
     pub diverging_blockchain_balances_total: HashMap<Currency, f64>,
@@ -19,6 +25,65 @@ pub struct Metrics {
     pub pending_blockchain_transactions_count: u64,
     pub invalid_blockchain_transactions_count: u64,
     pub eth_fee_account_blockchain_balance: f64,
+    /// System accounts below their minimums from `balance_alerts` config
+    pub low_system_balances: Vec<LowSystemBalance>,
+    pub low_system_balances_count: u64,
+    pub db_pools: HashMap<String, PoolMetrics>,
+    /// By repo method, e.g. `transactions.create`
+    pub db_queries: HashMap<String, QueryMetrics>,
+    pub rabbit: RabbitMetrics,
+}
+
+#[derive(Debug, Clone, Serialize, Default)]
+pub struct PoolMetrics {
+    pub max_size: u32,
+    pub connections: u32,
+    pub idle_connections: u32,
+    pub in_use_connections: u32,
+    /// Number of connections checked out since start
+    pub checkouts_count: u64,
+    /// Number of times a connection was not checked out within connection timeout
+    pub timeouts_count: u64,
+    pub avg_wait_ms: f64,
+    pub max_wait_ms: f64,
+}
+
+/// Repo calls of one method since start
+#[derive(Debug, Clone, Serialize, Default)]
+pub struct QueryMetrics {
+    pub calls_count: u64,
+    pub errors_count: u64,
+    /// Calls made by retries of transactions that failed with serialization conflict
+    pub retried_calls_count: u64,
+    /// Calls slower than `database.slow_query_ms`
+    pub slow_calls_count: u64,
+    /// Rows returned or changed by successful calls
+    pub rows_count: u64,
+    pub avg_duration_ms: f64,
+    pub max_duration_ms: f64,
+}
+
+/// Rabbit connection events since start
+#[derive(Debug, Clone, Serialize, Default)]
+pub struct RabbitMetrics {
+    /// Times the connection was reestablished after consumers stopped receiving messages
+    pub reconnects_count: u64,
+    pub failed_reconnects_count: u64,
+    pub last_reconnect_at: Option<NaiveDateTime>,
+    pub connected: bool,
+    /// Periodic checks that a channel can be opened on the connection
+    pub failed_health_checks_count: u64,
+    pub last_health_check_at: Option<NaiveDateTime>,
+}
+
+/// Whether the service can serve requests and handle messages, reported by `/healthz`
+#[derive(Debug, Clone, Serialize)]
+pub struct Health {
+    pub healthy: bool,
+    pub rabbit_connected: bool,
+    pub rabbit_last_health_check_at: Option<NaiveDateTime>,
+    /// By pool name, db pool is healthy when it has at least one open connection
+    pub db_pools: HashMap<String, bool>,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -37,3 +102,44 @@ pub struct NegativeBalance {
     pub currency: Currency,
     pub value: Amount,
 }
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReconciliationReport {
+    pub currency: Option<Currency>,
+    pub created_at: NaiveDateTime,
+    pub checked_addresses_count: u64,
+    pub diverging_balances: Vec<DivergingBalance>,
+    pub negative_balances: Vec<NegativeBalance>,
+}
+
+/// Outcome of the latest reconciliation of addresses in a currency, kept after the report is written
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ReconciliationStatus {
+    pub checked_at: NaiveDateTime,
+    /// Addresses with ledger balances diverging from blockchain ones or negative
+    pub failed_addresses: Vec<BlockchainAddress>,
+}
+
+impl ReconciliationStatus {
+    /// Status of `currency` addresses checked by `report`
+    pub fn from_report(report: &ReconciliationReport, currency: Currency) -> Self {
+        let diverging = report
+            .diverging_balances
+            .iter()
+            .filter(|balance| balance.currency == currency)
+            .map(|balance| balance.address.clone());
+        let negative = report
+            .negative_balances
+            .iter()
+            .filter(|balance| balance.currency == currency)
+            .map(|balance| balance.address.clone());
+        let mut failed_addresses: Vec<BlockchainAddress> = diverging.chain(negative).collect();
+        failed_addresses.sort();
+        failed_addresses.dedup();
+        Self {
+            checked_at: report.created_at,
+            failed_addresses,
+        }
+    }
+}
@@ -1,59 +1,99 @@
 mod account;
 mod account_address;
+mod account_balance;
 mod account_id;
 mod account_kind;
+mod account_stats;
 mod amount;
 mod approve;
 mod authentication_token;
 mod blockchain_transaction;
 mod blockchain_transaction_id;
 mod blockchain_transaction_raw;
+mod confirmation_thresholds;
 mod currency;
 mod daily_limit_type;
 mod delivery;
+mod deposit_event;
 mod exchange;
+mod exchange_pairs;
+mod expired_address;
 mod fees;
+mod group_fee;
 mod key_value;
 mod metrics;
 mod oauth_token;
+mod payment_uri;
 mod pending_blockchain_transaction;
+mod pending_deposit;
+mod quarantined_message;
+mod queued_withdrawal;
+mod rate_lock;
 mod recepient;
 mod role;
 mod seen_hashes;
+mod small_deposit;
 mod strange_blockchain_transaction;
+mod system_account;
 mod transaction;
+mod transaction_group;
 mod transaction_id;
 mod transaction_kind;
+mod transaction_limits;
 mod transaction_status;
+mod transactions_sort;
+mod tx_group;
 mod user;
 mod user_id;
+mod wallet;
+mod withdrawal_address;
 
 pub use self::account::*;
 pub use self::account_address::*;
+pub use self::account_balance::*;
 pub use self::account_id::*;
 pub use self::account_kind::*;
+pub use self::account_stats::*;
 pub use self::amount::*;
 pub use self::approve::*;
 pub use self::authentication_token::*;
 pub use self::blockchain_transaction::*;
 pub use self::blockchain_transaction_id::*;
 pub use self::blockchain_transaction_raw::*;
+pub use self::confirmation_thresholds::*;
 pub use self::currency::*;
 pub use self::daily_limit_type::*;
 pub use self::delivery::*;
+pub use self::deposit_event::*;
 pub use self::exchange::*;
+pub use self::exchange_pairs::*;
+pub use self::expired_address::*;
 pub use self::fees::*;
+pub use self::group_fee::*;
 pub use self::key_value::*;
 pub use self::metrics::*;
 pub use self::oauth_token::*;
+pub use self::payment_uri::*;
 pub use self::pending_blockchain_transaction::*;
+pub use self::pending_deposit::*;
+pub use self::quarantined_message::*;
+pub use self::queued_withdrawal::*;
+pub use self::rate_lock::*;
 pub use self::recepient::*;
 pub use self::role::*;
 pub use self::seen_hashes::*;
+pub use self::small_deposit::*;
 pub use self::strange_blockchain_transaction::*;
+pub use self::system_account::*;
 pub use self::transaction::*;
+pub use self::transaction_group::*;
 pub use self::transaction_id::*;
 pub use self::transaction_kind::*;
+pub use self::transaction_limits::*;
 pub use self::transaction_status::*;
+pub use self::transactions_sort::*;
+pub use self::tx_group::*;
 pub use self::user::*;
 pub use self::user_id::*;
+pub use self::wallet::*;
+pub use self::withdrawal_address::*;
@@ -0,0 +1,104 @@
+use models::*;
+
+/// Payment to account's address in the form wallets understand when scanning a qr code:
+/// BIP-21 for btc, EIP-681 for eth and EIP-681 `transfer` call of the token contract for stq
+#[derive(Debug, Clone, PartialEq)]
+pub struct PaymentUri {
+    pub account_id: AccountId,
+    pub currency: Currency,
+    pub address: BlockchainAddress,
+    pub amount: Option<Amount>,
+    pub uri: String,
+}
+
+impl PaymentUri {
+    pub fn new(account: &Account, amount: Option<Amount>, stq_contract_address: &BlockchainAddress) -> Self {
+        let uri = match account.currency {
+            // BIP-21 amount is a decimal in btc
+            Currency::Btc => match amount {
+                Some(amount) => format!("bitcoin:{}?amount={}", account.address, amount.to_super_unit_string(Currency::Btc)),
+                None => format!("bitcoin:{}", account.address),
+            },
+            // EIP-681 values are integers in wei, or in token's smallest units for erc-20 transfers
+            Currency::Eth => match amount {
+                Some(amount) => format!("ethereum:{}?value={}", ethereum_address(&account.address), amount.raw()),
+                None => format!("ethereum:{}", ethereum_address(&account.address)),
+            },
+            Currency::Stq => {
+                let transfer = format!(
+                    "ethereum:{}/transfer?address={}",
+                    ethereum_address(stq_contract_address),
+                    ethereum_address(&account.address)
+                );
+                match amount {
+                    Some(amount) => format!("{}&uint256={}", transfer, amount.raw()),
+                    None => transfer,
+                }
+            }
+        };
+        Self {
+            account_id: account.id,
+            currency: account.currency,
+            address: account.address.clone(),
+            amount,
+            uri,
+        }
+    }
+}
+
+// ethereum addresses are stored without `0x`, that is required in uris
+fn ethereum_address(address: &BlockchainAddress) -> String {
+    let address = address.raw();
+    if address.starts_with("0x") {
+        address.to_string()
+    } else {
+        format!("0x{}", address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn account(currency: Currency, address: &str) -> Account {
+        let mut account = Account::default();
+        account.currency = currency;
+        account.address = BlockchainAddress::new(address.to_string());
+        account
+    }
+
+    #[test]
+    fn test_payment_uri() {
+        let contract = BlockchainAddress::new("5c3a228510d246b78a3765c20221cbf3082b44a4".to_string());
+        let cases = vec![
+            (
+                account(Currency::Btc, "1BoatSLRHtKNngkdXEeobR76b53LETtpyT"),
+                Some(Amount::new(150_000_000)),
+                "bitcoin:1BoatSLRHtKNngkdXEeobR76b53LETtpyT?amount=1.5",
+            ),
+            (
+                account(Currency::Btc, "1BoatSLRHtKNngkdXEeobR76b53LETtpyT"),
+                None,
+                "bitcoin:1BoatSLRHtKNngkdXEeobR76b53LETtpyT",
+            ),
+            (
+                account(Currency::Eth, "fb6916095ca1df60bb79ce92ce3ea74c37c5d359"),
+                Some(Amount::new(2_014_000_000_000_000_000)),
+                "ethereum:0xfb6916095ca1df60bb79ce92ce3ea74c37c5d359?value=2014000000000000000",
+            ),
+            (
+                account(Currency::Stq, "0xfb6916095ca1df60bb79ce92ce3ea74c37c5d359"),
+                Some(Amount::new(1_000_000_000_000_000_000)),
+                "ethereum:0x5c3a228510d246b78a3765c20221cbf3082b44a4/transfer?address=0xfb6916095ca1df60bb79ce92ce3ea74c37c5d359&uint256=1000000000000000000",
+            ),
+            (
+                account(Currency::Stq, "fb6916095ca1df60bb79ce92ce3ea74c37c5d359"),
+                None,
+                "ethereum:0x5c3a228510d246b78a3765c20221cbf3082b44a4/transfer?address=0xfb6916095ca1df60bb79ce92ce3ea74c37c5d359",
+            ),
+        ];
+        for (account, amount, expected) in cases {
+            assert_eq!(PaymentUri::new(&account, amount, &contract).uri, expected);
+        }
+    }
+}
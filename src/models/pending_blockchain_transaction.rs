@@ -14,6 +14,26 @@ pub struct PendingBlockchainTransactionDB {
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
     pub erc20_operation_kind: Option<Erc20OperationKind>,
+    pub raw_tx: Option<BlockchainTransactionRaw>,
+    /// Failed attempts to post `raw_tx` to blockchain gateway
+    pub broadcast_attempts: i32,
+    /// If set, `raw_tx` is not in blockchain yet and `hash` is a placeholder, see `BlockchainTransactionId::unbroadcasted`
+    pub next_broadcast_at: Option<NaiveDateTime>,
+}
+
+/// Pending blockchain transaction that waits for confirmation longer than expected,
+/// with the ledger transaction that is completed by it
+#[derive(Debug, Clone)]
+pub struct StalePendingBlockchainTransaction {
+    pub pending: PendingBlockchainTransactionDB,
+    /// Absent for erc20 approvals, that are not reflected in ledger, and for orphaned pending transactions
+    pub transaction: Option<Transaction>,
+}
+
+impl StalePendingBlockchainTransaction {
+    pub fn created_at(&self) -> NaiveDateTime {
+        self.pending.created_at
+    }
 }
 
 impl From<PendingBlockchainTransactionDB> for BlockchainTransaction {
@@ -30,12 +50,14 @@ impl From<PendingBlockchainTransactionDB> for BlockchainTransaction {
             fee: transaction.fee,
             confirmations: 0 as usize,
             erc20_operation_kind: transaction.erc20_operation_kind,
+            internal_transfers: vec![],
+            logs: vec![],
         }
     }
 }
 
-impl From<(CreateBlockchainTx, BlockchainTransactionId)> for NewPendingBlockchainTransactionDB {
-    fn from(transaction: (CreateBlockchainTx, BlockchainTransactionId)) -> Self {
+impl From<(CreateBlockchainTx, BlockchainTransactionId, BlockchainTransactionRaw)> for NewPendingBlockchainTransactionDB {
+    fn from(transaction: (CreateBlockchainTx, BlockchainTransactionId, BlockchainTransactionRaw)) -> Self {
         Self {
             hash: transaction.1,
             from_: transaction.0.from,
@@ -44,12 +66,14 @@ impl From<(CreateBlockchainTx, BlockchainTransactionId)> for NewPendingBlockchai
             value: transaction.0.value,
             fee: Amount::new(0),
             erc20_operation_kind: None,
+            raw_tx: Some(transaction.2),
+            next_broadcast_at: None,
         }
     }
 }
 
-impl From<(ApproveInput, BlockchainTransactionId)> for NewPendingBlockchainTransactionDB {
-    fn from(transaction: (ApproveInput, BlockchainTransactionId)) -> Self {
+impl From<(ApproveInput, BlockchainTransactionId, BlockchainTransactionRaw)> for NewPendingBlockchainTransactionDB {
+    fn from(transaction: (ApproveInput, BlockchainTransactionId, BlockchainTransactionRaw)) -> Self {
         Self {
             hash: transaction.1,
             from_: transaction.0.address,
@@ -58,6 +82,8 @@ impl From<(ApproveInput, BlockchainTransactionId)> for NewPendingBlockchainTrans
             value: transaction.0.value,
             fee: Amount::new(0),
             erc20_operation_kind: Some(Erc20OperationKind::Approve),
+            raw_tx: Some(transaction.2),
+            next_broadcast_at: None,
         }
     }
 }
@@ -72,6 +98,8 @@ pub struct NewPendingBlockchainTransactionDB {
     pub value: Amount,
     pub fee: Amount,
     pub erc20_operation_kind: Option<Erc20OperationKind>,
+    pub raw_tx: Option<BlockchainTransactionRaw>,
+    pub next_broadcast_at: Option<NaiveDateTime>,
 }
 
 impl Default for NewPendingBlockchainTransactionDB {
@@ -84,6 +112,8 @@ impl Default for NewPendingBlockchainTransactionDB {
             value: Amount::default(),
             fee: Amount::default(),
             erc20_operation_kind: None,
+            raw_tx: None,
+            next_broadcast_at: None,
         }
     }
 }
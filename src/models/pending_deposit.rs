@@ -0,0 +1,102 @@
+use chrono::NaiveDateTime;
+use serde_json::{self, Value};
+
+use models::*;
+use schema::pending_deposits;
+
+/// Deposit seen in blockchain, that doesn't have enough confirmations yet. It's not in the ledger,
+/// so it doesn't count in balances, but users can see that their funds are on the way.
+/// Once confirmed, the deposit transaction gets the same id
+#[derive(Debug, Queryable, Clone)]
+pub struct PendingDeposit {
+    pub id: TransactionId,
+    pub blockchain_tx_id: BlockchainTransactionId,
+    /// Credit account of the user
+    pub account_id: AccountId,
+    pub user_id: UserId,
+    pub currency: Currency,
+    pub from_: Value,
+    pub address: BlockchainAddress,
+    pub value: Amount,
+    pub confirmations: i32,
+    pub required_confirmations: i32,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    /// When the deposit is expected to get required confirmations, updated as confirmations arrive
+    pub estimated_completion_at: NaiveDateTime,
+}
+
+#[derive(Debug, Insertable, Clone)]
+#[table_name = "pending_deposits"]
+pub struct NewPendingDeposit {
+    pub id: TransactionId,
+    pub blockchain_tx_id: BlockchainTransactionId,
+    pub account_id: AccountId,
+    pub user_id: UserId,
+    pub currency: Currency,
+    pub from_: Value,
+    pub address: BlockchainAddress,
+    pub value: Amount,
+    pub confirmations: i32,
+    pub required_confirmations: i32,
+    pub estimated_completion_at: NaiveDateTime,
+}
+
+impl Default for NewPendingDeposit {
+    fn default() -> Self {
+        Self {
+            id: TransactionId::generate(),
+            blockchain_tx_id: BlockchainTransactionId::default(),
+            account_id: AccountId::generate(),
+            user_id: UserId::generate(),
+            currency: Currency::Eth,
+            from_: Value::Array(vec![]),
+            address: BlockchainAddress::default(),
+            value: Amount::default(),
+            confirmations: 0,
+            required_confirmations: 0,
+            estimated_completion_at: ::chrono::Utc::now().naive_utc(),
+        }
+    }
+}
+
+impl From<PendingDeposit> for TransactionOut {
+    fn from(deposit: PendingDeposit) -> Self {
+        let from: Vec<BlockchainAddress> = serde_json::from_value(deposit.from_).unwrap_or_default();
+        let from = from
+            .into_iter()
+            .map(|blockchain_address| TransactionAddressInfo {
+                account_id: None,
+                blockchain_address,
+            })
+            .collect();
+        Self {
+            id: deposit.id,
+            user_id: deposit.user_id,
+            from,
+            to: TransactionAddressInfo {
+                account_id: Some(deposit.account_id),
+                blockchain_address: deposit.address,
+            },
+            from_value: deposit.value,
+            from_currency: deposit.currency,
+            to_value: deposit.value,
+            to_currency: deposit.currency,
+            fee: Amount::new(0),
+            // deposits in the ledger are always done, so pending one is the unconfirmed
+            status: TransactionStatus::Pending,
+            blockchain_tx_ids: vec![deposit.blockchain_tx_id],
+            created_at: deposit.created_at,
+            updated_at: deposit.updated_at,
+            kind: TransactionKind::Deposit,
+            group_kind: TransactionGroupKind::Deposit,
+            related_tx: None,
+            meta: json!({
+                "confirmations": deposit.confirmations,
+                "requiredConfirmations": deposit.required_confirmations,
+            }),
+            usd_value: None,
+            estimated_completion_at: Some(deposit.estimated_completion_at),
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use chrono::NaiveDateTime;
+use uuid::Uuid;
+
+use models::*;
+use schema::quarantined_messages;
+
+/// Message from blockchain gateway, that failed to be handled `attempts` times in a row.
+/// It's kept with the last error instead of being redelivered forever and can be replayed
+/// to its currency queue once the cause is fixed
+#[derive(Debug, Queryable, Clone)]
+pub struct QuarantinedMessage {
+    pub id: Uuid,
+    pub currency: Currency,
+    pub payload: Vec<u8>,
+    pub error: String,
+    pub attempts: i32,
+    pub request_id: Option<String>,
+    pub replayed_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+impl Default for QuarantinedMessage {
+    fn default() -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            currency: Currency::Eth,
+            payload: vec![],
+            error: String::new(),
+            attempts: 1,
+            request_id: None,
+            replayed_at: None,
+            created_at: ::chrono::Utc::now().naive_utc(),
+            updated_at: ::chrono::Utc::now().naive_utc(),
+        }
+    }
+}
+
+#[derive(Debug, Insertable, Clone)]
+#[table_name = "quarantined_messages"]
+pub struct NewQuarantinedMessage {
+    pub id: Uuid,
+    pub currency: Currency,
+    pub payload: Vec<u8>,
+    pub error: String,
+    pub attempts: i32,
+    pub request_id: Option<String>,
+}
+
+impl Default for NewQuarantinedMessage {
+    fn default() -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            currency: Currency::Eth,
+            payload: vec![],
+            error: String::new(),
+            attempts: 1,
+            request_id: None,
+        }
+    }
+}
+
+impl From<NewQuarantinedMessage> for QuarantinedMessage {
+    fn from(new_message: NewQuarantinedMessage) -> Self {
+        Self {
+            id: new_message.id,
+            currency: new_message.currency,
+            payload: new_message.payload,
+            error: new_message.error,
+            attempts: new_message.attempts,
+            request_id: new_message.request_id,
+            ..Default::default()
+        }
+    }
+}
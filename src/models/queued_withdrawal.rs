@@ -0,0 +1,153 @@
+use std::io::Write;
+
+use chrono::NaiveDateTime;
+use diesel::deserialize::{self, FromSql};
+use diesel::pg::Pg;
+use diesel::serialize::{self, IsNull, Output, ToSql};
+use diesel::sql_types::VarChar;
+
+use models::*;
+use schema::queued_withdrawals;
+
+/// How withdrawal is executed - sent to blockchain right away or put into queue,
+/// that is drained periodically
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum WithdrawalExecution {
+    Immediate,
+    Queued,
+}
+
+impl Default for WithdrawalExecution {
+    fn default() -> Self {
+        WithdrawalExecution::Immediate
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, FromSqlRow, AsExpression, Clone, Copy, Eq, PartialEq)]
+#[sql_type = "VarChar"]
+#[serde(rename_all = "lowercase")]
+pub enum QueuedWithdrawalStatus {
+    /// Waiting for the next drain, can be cancelled
+    Queued,
+    /// Taken by the drain that is sending it
+    Processing,
+    /// Sent, as a part of `transaction_id`
+    Done,
+    /// Not sent, e.g. because of insufficient funds by the time of drain
+    Failed,
+    Cancelled,
+}
+
+impl FromSql<VarChar, Pg> for QueuedWithdrawalStatus {
+    fn from_sql(data: Option<&[u8]>) -> deserialize::Result<Self> {
+        match data {
+            Some(b"queued") => Ok(QueuedWithdrawalStatus::Queued),
+            Some(b"processing") => Ok(QueuedWithdrawalStatus::Processing),
+            Some(b"done") => Ok(QueuedWithdrawalStatus::Done),
+            Some(b"failed") => Ok(QueuedWithdrawalStatus::Failed),
+            Some(b"cancelled") => Ok(QueuedWithdrawalStatus::Cancelled),
+            Some(v) => Err(format!(
+                "Unrecognized enum variant: {:?}",
+                String::from_utf8(v.to_vec()).unwrap_or_else(|_| "Non - UTF8 value".to_string())
+            )
+            .to_string()
+            .into()),
+            None => Err("Unexpected null for non-null column".into()),
+        }
+    }
+}
+
+impl ToSql<VarChar, Pg> for QueuedWithdrawalStatus {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, Pg>) -> serialize::Result {
+        match self {
+            QueuedWithdrawalStatus::Queued => out.write_all(b"queued")?,
+            QueuedWithdrawalStatus::Processing => out.write_all(b"processing")?,
+            QueuedWithdrawalStatus::Done => out.write_all(b"done")?,
+            QueuedWithdrawalStatus::Failed => out.write_all(b"failed")?,
+            QueuedWithdrawalStatus::Cancelled => out.write_all(b"cancelled")?,
+        };
+        Ok(IsNull::No)
+    }
+}
+
+/// Withdrawal waiting to be sent by the next drain of the queue. Its id becomes the id
+/// of the transaction it is sent in, unless it's batched with older ones
+#[derive(Debug, Queryable, Clone)]
+pub struct QueuedWithdrawal {
+    pub id: TransactionId,
+    pub user_id: UserId,
+    pub account_id: AccountId,
+    pub currency: Currency,
+    pub address: BlockchainAddress,
+    pub value: Amount,
+    pub fee: Amount,
+    pub status: QueuedWithdrawalStatus,
+    pub transaction_id: Option<TransactionId>,
+    pub error_message: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+impl Default for QueuedWithdrawal {
+    fn default() -> Self {
+        Self {
+            id: TransactionId::generate(),
+            user_id: UserId::generate(),
+            account_id: AccountId::generate(),
+            currency: Currency::Eth,
+            address: BlockchainAddress::default(),
+            value: Amount::default(),
+            fee: Amount::default(),
+            status: QueuedWithdrawalStatus::Queued,
+            transaction_id: None,
+            error_message: None,
+            created_at: ::chrono::Utc::now().naive_utc(),
+            updated_at: ::chrono::Utc::now().naive_utc(),
+        }
+    }
+}
+
+#[derive(Debug, Insertable, Clone)]
+#[table_name = "queued_withdrawals"]
+pub struct NewQueuedWithdrawal {
+    pub id: TransactionId,
+    pub user_id: UserId,
+    pub account_id: AccountId,
+    pub currency: Currency,
+    pub address: BlockchainAddress,
+    pub value: Amount,
+    pub fee: Amount,
+    pub status: QueuedWithdrawalStatus,
+}
+
+impl Default for NewQueuedWithdrawal {
+    fn default() -> Self {
+        Self {
+            id: TransactionId::generate(),
+            user_id: UserId::generate(),
+            account_id: AccountId::generate(),
+            currency: Currency::Eth,
+            address: BlockchainAddress::default(),
+            value: Amount::default(),
+            fee: Amount::default(),
+            status: QueuedWithdrawalStatus::Queued,
+        }
+    }
+}
+
+impl From<NewQueuedWithdrawal> for QueuedWithdrawal {
+    fn from(new_withdrawal: NewQueuedWithdrawal) -> Self {
+        Self {
+            id: new_withdrawal.id,
+            user_id: new_withdrawal.user_id,
+            account_id: new_withdrawal.account_id,
+            currency: new_withdrawal.currency,
+            address: new_withdrawal.address,
+            value: new_withdrawal.value,
+            fee: new_withdrawal.fee,
+            status: new_withdrawal.status,
+            ..Default::default()
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use chrono::NaiveDateTime;
+use uuid::Uuid;
+
+use models::*;
+use schema::rate_locks;
+
+/// Exchange rate quoted to the user and kept until `expires_at`. A transaction with exchange
+/// can reference it instead of passing the rate, each lock can be used once
+#[derive(Debug, Queryable, Clone)]
+pub struct RateLock {
+    pub id: Uuid,
+    pub user_id: UserId,
+    pub exchange_id: ExchangeId,
+    pub from_currency: Currency,
+    pub to_currency: Currency,
+    pub amount: Amount,
+    pub amount_currency: Currency,
+    pub rate: f64,
+    pub expires_at: NaiveDateTime,
+    pub used_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+impl Default for RateLock {
+    fn default() -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            user_id: UserId::generate(),
+            exchange_id: ExchangeId::generate(),
+            from_currency: Currency::Stq,
+            to_currency: Currency::Eth,
+            amount: Amount::default(),
+            amount_currency: Currency::Stq,
+            rate: 1.0,
+            expires_at: ::chrono::Utc::now().naive_utc(),
+            used_at: None,
+            created_at: ::chrono::Utc::now().naive_utc(),
+            updated_at: ::chrono::Utc::now().naive_utc(),
+        }
+    }
+}
+
+#[derive(Debug, Insertable, Clone)]
+#[table_name = "rate_locks"]
+pub struct NewRateLock {
+    pub id: Uuid,
+    pub user_id: UserId,
+    pub exchange_id: ExchangeId,
+    pub from_currency: Currency,
+    pub to_currency: Currency,
+    pub amount: Amount,
+    pub amount_currency: Currency,
+    pub rate: f64,
+    pub expires_at: NaiveDateTime,
+}
+
+impl Default for NewRateLock {
+    fn default() -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            user_id: UserId::generate(),
+            exchange_id: ExchangeId::generate(),
+            from_currency: Currency::Stq,
+            to_currency: Currency::Eth,
+            amount: Amount::default(),
+            amount_currency: Currency::Stq,
+            rate: 1.0,
+            expires_at: ::chrono::Utc::now().naive_utc(),
+        }
+    }
+}
+
+impl From<NewRateLock> for RateLock {
+    fn from(new_lock: NewRateLock) -> Self {
+        Self {
+            id: new_lock.id,
+            user_id: new_lock.user_id,
+            exchange_id: new_lock.exchange_id,
+            from_currency: new_lock.from_currency,
+            to_currency: new_lock.to_currency,
+            amount: new_lock.amount,
+            amount_currency: new_lock.amount_currency,
+            rate: new_lock.rate,
+            expires_at: new_lock.expires_at,
+            ..Default::default()
+        }
+    }
+}
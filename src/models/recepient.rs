@@ -13,7 +13,6 @@ pub enum RecepientType {
 pub struct Recepient(String);
 
 impl Recepient {
-    #[cfg(test)]
     pub fn new(rec: String) -> Self {
         Recepient(rec)
     }
@@ -0,0 +1,58 @@
+use chrono::NaiveDateTime;
+
+use diesel::sql_types::{BigInt, Numeric, VarChar};
+
+use models::*;
+use schema::small_deposits;
+
+/// Deposit below configured minimum. It's kept apart from the ledger,
+/// so it doesn't count in balances and can't be withdrawn
+#[derive(Debug, Queryable, Clone)]
+pub struct SmallDeposit {
+    pub blockchain_tx_id: BlockchainTransactionId,
+    pub account_id: AccountId,
+    pub user_id: UserId,
+    pub currency: Currency,
+    pub value: Amount,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Debug, Insertable, Clone)]
+#[table_name = "small_deposits"]
+pub struct NewSmallDeposit {
+    pub blockchain_tx_id: BlockchainTransactionId,
+    pub account_id: AccountId,
+    pub user_id: UserId,
+    pub currency: Currency,
+    pub value: Amount,
+}
+
+impl Default for NewSmallDeposit {
+    fn default() -> Self {
+        Self {
+            blockchain_tx_id: BlockchainTransactionId::default(),
+            account_id: AccountId::generate(),
+            user_id: UserId::generate(),
+            currency: Currency::Eth,
+            value: Amount::default(),
+        }
+    }
+}
+
+#[derive(Debug, Queryable, QueryableByName, Clone, PartialEq)]
+pub struct SmallDepositsTotal {
+    #[sql_type = "VarChar"]
+    pub currency: Currency,
+    #[sql_type = "BigInt"]
+    pub count: i64,
+    #[sql_type = "Numeric"]
+    pub value: Amount,
+}
+
+#[derive(Debug, Clone)]
+pub struct SmallDepositsReport {
+    pub deposits: Vec<SmallDeposit>,
+    /// Totals of all small deposits by currency, regardless of pagination
+    pub totals: Vec<SmallDepositsTotal>,
+}
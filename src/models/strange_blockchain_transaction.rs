@@ -1,11 +1,91 @@
-use chrono::NaiveDateTime;
+use std::io::Write;
 
+use chrono::NaiveDateTime;
+use diesel::deserialize::{self, FromSql};
+use diesel::pg::Pg;
+use diesel::serialize::{self, IsNull, Output, ToSql};
+use diesel::sql_types::VarChar;
 use serde_json;
 
 use models::*;
 use schema::strange_blockchain_transactions;
 
-#[derive(Debug, Queryable, Clone)]
+/// Reason the blockchain transaction was put to strange ones instead of being handled
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Fail, Serialize, Deserialize, FromSqlRow, AsExpression)]
+#[sql_type = "VarChar"]
+#[serde(rename_all = "snake_case")]
+#[allow(dead_code)]
+pub enum InvariantViolation {
+    #[fail(display = "blockchain transaction invariant violation - unexpected number of addresses")]
+    WithdrawalAdressesCount,
+    #[fail(display = "blockchain transaction invariant violation - address of transaction not found in our database")]
+    WithdrawalAdressesNotFound,
+    #[fail(display = "blockchain transaction invariant violation - transaction referred to non-existing account")]
+    NotExistingAccount,
+    #[fail(display = "blockchain transaction invariant violation - withdrawal happened to internal account, which shouldn't be the case")]
+    WithdrawalAdressesInternal,
+    #[fail(
+        display = "blockchain transaction invariant violation - withdrawal transaction should be in pending state when blockchain tx arrives"
+    )]
+    WithdrawalNotPendingAddress,
+    #[fail(display = "blockchain transaction invariant violation - withdrawal blockchain tx doesn't have corresponding pending part")]
+    WithdrawalNoPendingTx,
+    #[fail(display = "blockchain transaction invariant violation - withdrawal blockchain tx value is not equal to pending tx value")]
+    WithdrawalValue,
+    #[fail(display = "blockchain transaction invariant violation - withdrawal blockchain tx fee exceeds balance of the account paying it")]
+    WithdrawalFee,
+    #[fail(display = "blockchain transaction invariant violation - deposit arrived from internal address")]
+    DepositAddressInternal,
+}
+
+impl InvariantViolation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            InvariantViolation::WithdrawalAdressesCount => "withdrawal_adresses_count",
+            InvariantViolation::WithdrawalAdressesNotFound => "withdrawal_adresses_not_found",
+            InvariantViolation::NotExistingAccount => "not_existing_account",
+            InvariantViolation::WithdrawalAdressesInternal => "withdrawal_adresses_internal",
+            InvariantViolation::WithdrawalNotPendingAddress => "withdrawal_not_pending_address",
+            InvariantViolation::WithdrawalNoPendingTx => "withdrawal_no_pending_tx",
+            InvariantViolation::WithdrawalValue => "withdrawal_value",
+            InvariantViolation::WithdrawalFee => "withdrawal_fee",
+            InvariantViolation::DepositAddressInternal => "deposit_address_internal",
+        }
+    }
+}
+
+impl FromSql<VarChar, Pg> for InvariantViolation {
+    fn from_sql(data: Option<&[u8]>) -> deserialize::Result<Self> {
+        match data {
+            Some(b"withdrawal_adresses_count") => Ok(InvariantViolation::WithdrawalAdressesCount),
+            Some(b"withdrawal_adresses_not_found") => Ok(InvariantViolation::WithdrawalAdressesNotFound),
+            Some(b"not_existing_account") => Ok(InvariantViolation::NotExistingAccount),
+            Some(b"withdrawal_adresses_internal") => Ok(InvariantViolation::WithdrawalAdressesInternal),
+            Some(b"withdrawal_not_pending_address") => Ok(InvariantViolation::WithdrawalNotPendingAddress),
+            Some(b"withdrawal_no_pending_tx") => Ok(InvariantViolation::WithdrawalNoPendingTx),
+            Some(b"withdrawal_value") => Ok(InvariantViolation::WithdrawalValue),
+            Some(b"withdrawal_fee") => Ok(InvariantViolation::WithdrawalFee),
+            Some(b"deposit_address_internal") => Ok(InvariantViolation::DepositAddressInternal),
+            Some(v) => Err(format!(
+                "Unrecognized enum variant: {:?}",
+                String::from_utf8(v.to_vec()).unwrap_or_else(|_| "Non - UTF8 value".to_string())
+            )
+            .to_string()
+            .into()),
+            None => Err("Unexpected null for non-null column".into()),
+        }
+    }
+}
+
+impl ToSql<VarChar, Pg> for InvariantViolation {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, Pg>) -> serialize::Result {
+        out.write_all(self.as_str().as_bytes())?;
+        Ok(IsNull::No)
+    }
+}
+
+#[derive(Debug, Queryable, QueryableByName, Clone)]
+#[table_name = "strange_blockchain_transactions"]
 pub struct StrangeBlockchainTransactionDB {
     pub hash: BlockchainTransactionId,
     pub from_: serde_json::Value,
@@ -18,10 +98,12 @@ pub struct StrangeBlockchainTransactionDB {
     pub updated_at: NaiveDateTime,
     pub commentary: String,
     pub erc20_operation_kind: Option<Erc20OperationKind>,
+    /// Absent for transactions put to strange ones by hand
+    pub violation_kind: Option<InvariantViolation>,
 }
 
-impl From<(BlockchainTransaction, String)> for NewStrangeBlockchainTransactionDB {
-    fn from(transaction: (BlockchainTransaction, String)) -> Self {
+impl From<(BlockchainTransaction, InvariantViolation)> for NewStrangeBlockchainTransactionDB {
+    fn from(transaction: (BlockchainTransaction, InvariantViolation)) -> Self {
         Self {
             hash: transaction.0.hash,
             from_: serde_json::to_value(transaction.0.from).unwrap_or_default(),
@@ -30,8 +112,9 @@ impl From<(BlockchainTransaction, String)> for NewStrangeBlockchainTransactionDB
             currency: transaction.0.currency,
             fee: transaction.0.fee,
             confirmations: transaction.0.confirmations as i32,
-            commentary: transaction.1,
+            commentary: transaction.1.to_string(),
             erc20_operation_kind: transaction.0.erc20_operation_kind,
+            violation_kind: Some(transaction.1),
         }
     }
 }
@@ -48,6 +131,7 @@ pub struct NewStrangeBlockchainTransactionDB {
     pub confirmations: i32,
     pub commentary: String,
     pub erc20_operation_kind: Option<Erc20OperationKind>,
+    pub violation_kind: Option<InvariantViolation>,
 }
 
 impl Default for NewStrangeBlockchainTransactionDB {
@@ -62,6 +146,7 @@ impl Default for NewStrangeBlockchainTransactionDB {
             confirmations: 0,
             commentary: "comment".to_string(),
             erc20_operation_kind: None,
+            violation_kind: None,
         }
     }
 }
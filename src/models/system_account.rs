@@ -0,0 +1,110 @@
+use std::fmt::{self, Display};
+use std::io::Write;
+
+use chrono::NaiveDateTime;
+use diesel::deserialize::{self, FromSql};
+use diesel::pg::Pg;
+use diesel::serialize::{self, IsNull, Output, ToSql};
+use diesel::sql_types::VarChar;
+
+use models::*;
+
+/// Purpose of system account, stored as its role. There's one credit account of each kind per currency,
+/// with debit account of the same address
+#[derive(Debug, Serialize, Deserialize, FromSqlRow, AsExpression, Clone, Copy, Eq, PartialEq, Hash)]
+#[sql_type = "VarChar"]
+#[serde(rename_all = "lowercase")]
+pub enum SystemAccountKind {
+    Transfer,
+    Liquidity,
+    Fees,
+}
+
+impl SystemAccountKind {
+    pub fn all() -> Vec<SystemAccountKind> {
+        vec![SystemAccountKind::Transfer, SystemAccountKind::Liquidity, SystemAccountKind::Fees]
+    }
+}
+
+impl Display for SystemAccountKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SystemAccountKind::Transfer => f.write_str("transfer"),
+            SystemAccountKind::Liquidity => f.write_str("liquidity"),
+            SystemAccountKind::Fees => f.write_str("fees"),
+        }
+    }
+}
+
+impl FromSql<VarChar, Pg> for SystemAccountKind {
+    fn from_sql(data: Option<&[u8]>) -> deserialize::Result<Self> {
+        match data {
+            Some(b"transfer") => Ok(SystemAccountKind::Transfer),
+            Some(b"liquidity") => Ok(SystemAccountKind::Liquidity),
+            Some(b"fees") => Ok(SystemAccountKind::Fees),
+            Some(v) => Err(format!(
+                "Unrecognized enum variant: {:?}",
+                String::from_utf8(v.to_vec()).unwrap_or_else(|_| "Non - UTF8 value".to_string())
+            )
+            .to_string()
+            .into()),
+            None => Err("Unexpected null for non-null column".into()),
+        }
+    }
+}
+
+impl ToSql<VarChar, Pg> for SystemAccountKind {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, Pg>) -> serialize::Result {
+        match self {
+            SystemAccountKind::Transfer => out.write_all(b"transfer")?,
+            SystemAccountKind::Liquidity => out.write_all(b"liquidity")?,
+            SystemAccountKind::Fees => out.write_all(b"fees")?,
+        };
+        Ok(IsNull::No)
+    }
+}
+
+/// System account moved to a fresh address
+#[derive(Debug, Clone)]
+pub struct SystemAddressRotation {
+    pub account_id: AccountId,
+    pub kind: SystemAccountKind,
+    pub currency: Currency,
+    pub address: BlockchainAddress,
+    pub former_address: BlockchainAddress,
+    /// Debit account that keeps the former address, so that deposits still arriving there are credited
+    /// to the system account, and funds left there can be tracked
+    pub former_address_account_id: AccountId,
+    /// Transfer of the balance from the former address. None if the balance doesn't cover network fee,
+    /// stq address is not approved or it failed to be sent - the balance stays with the former address account then
+    pub sweep: Option<Transaction>,
+}
+
+/// System account with its ledger balance and the outcome of the latest reconciliation of its address
+#[derive(Debug, Clone)]
+pub struct SystemAccountOverview {
+    /// Credit account of system account
+    pub account: Account,
+    pub kind: SystemAccountKind,
+    pub balance: Amount,
+    /// None if addresses of the currency were never reconciled
+    pub reconciliation: Option<SystemAccountReconciliation>,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SystemAccountReconciliation {
+    pub checked_at: NaiveDateTime,
+    /// Ledger balance of the address matched the blockchain one and wasn't negative
+    pub consistent: bool,
+}
+
+/// System account with balance below its minimum from `balance_alerts` config, in super units
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct LowSystemBalance {
+    pub account_id: AccountId,
+    pub kind: SystemAccountKind,
+    pub currency: Currency,
+    pub balance: f64,
+    pub min: f64,
+}
@@ -2,7 +2,9 @@ use chrono::NaiveDateTime;
 
 use diesel::sql_types::Numeric;
 use diesel::sql_types::Uuid as SqlUuid;
+use diesel::sql_types::VarChar;
 use serde_json::Value;
+use uuid::Uuid;
 use validator::{Validate, ValidationError};
 
 use models::*;
@@ -35,6 +37,23 @@ pub struct TransactionSum {
     pub sum: Amount,
 }
 
+/// Total debit and credit turnovers of an account, used to recompute balances from scratch
+#[derive(Debug, Queryable, Clone, QueryableByName)]
+pub struct AccountTurnover {
+    #[sql_type = "SqlUuid"]
+    pub account_id: AccountId,
+    #[sql_type = "VarChar"]
+    pub kind: AccountKind,
+    #[sql_type = "VarChar"]
+    pub address: BlockchainAddress,
+    #[sql_type = "VarChar"]
+    pub currency: Currency,
+    #[sql_type = "Numeric"]
+    pub dr_turnover: Amount,
+    #[sql_type = "Numeric"]
+    pub cr_turnover: Amount,
+}
+
 impl Default for Transaction {
     fn default() -> Self {
         let id = TransactionId::generate();
@@ -108,6 +127,17 @@ fn valid_rate(input: f64) -> Result<(), ValidationError> {
     }
 }
 
+fn valid_slippage(input: f64) -> Result<(), ValidationError> {
+    if input >= 0f64 && input < 1f64 {
+        Ok(())
+    } else {
+        let mut error = ValidationError::new("out_of_range");
+        error.message = Some("Value must be between 0 and 1".into());
+        error.add_param("value".into(), &input.to_string());
+        Err(error)
+    }
+}
+
 fn valid_exchange(input: &CreateTransactionInput) -> Result<(), ValidationError> {
     if input.exchange_id.is_some() {
         if input.exchange_rate.is_some() {
@@ -126,6 +156,24 @@ fn valid_exchange(input: &CreateTransactionInput) -> Result<(), ValidationError>
     }
 }
 
+/// Who pays the network fee of a withdrawal
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FeePayer {
+    /// Fee is written off the sending account on top of the value
+    Sender,
+    /// Fee is written off `fee_payer_account_id` - another account of the same user
+    Account,
+    /// Fee is deducted from the value, so that the recipient gets less
+    Recipient,
+}
+
+impl Default for FeePayer {
+    fn default() -> Self {
+        FeePayer::Sender
+    }
+}
+
 #[derive(Debug, Clone, Validate)]
 #[validate(schema(function = "valid_exchange", skip_on_field_errors = "false"))]
 pub struct CreateTransactionInput {
@@ -141,6 +189,65 @@ pub struct CreateTransactionInput {
     pub exchange_id: Option<ExchangeId>,
     #[validate(custom = "valid_rate")]
     pub exchange_rate: Option<f64>,
+    pub fee_payer: FeePayer,
+    pub fee_payer_account_id: Option<AccountId>,
+    /// Send the whole balance of `from` account, `value` is computed when the transaction is created
+    pub sweep: bool,
+    /// Exchange id and rate are taken from this lock, it's checked and used up when the transaction is created
+    pub rate_lock_id: Option<Uuid>,
+    /// Max relative deviation of the current rate from `exchange_rate` the client agrees to exchange at
+    #[validate(custom = "valid_slippage")]
+    pub max_slippage: Option<f64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PayoutDestination {
+    pub address: BlockchainAddress,
+    pub value: Amount,
+}
+
+/// Withdrawal to several addresses at once, sent as one transaction group
+#[derive(Debug, Clone, Validate)]
+pub struct CreatePayoutInput {
+    pub id: TransactionId,
+    pub user_id: UserId,
+    pub from: AccountId,
+    pub currency: Currency,
+    /// Fee paid for each of the destinations
+    pub fee: Amount,
+    #[validate(length(min = "1", message = "Payouts must not be empty"))]
+    pub payouts: Vec<PayoutDestination>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PayoutStatus {
+    Sent,
+    /// Nothing is withdrawn to this destination
+    Failed,
+    /// Not tried, since one of the previous destinations failed
+    Skipped,
+}
+
+#[derive(Debug, Clone)]
+pub struct PayoutResult {
+    pub id: TransactionId,
+    pub address: BlockchainAddress,
+    pub value: Amount,
+    pub status: PayoutStatus,
+}
+
+/// Payout group with the result of each of the destinations in the order of the input
+#[derive(Debug, Clone)]
+pub struct PayoutOut {
+    pub transaction: TransactionOut,
+    pub payouts: Vec<PayoutResult>,
+}
+
+impl PayoutOut {
+    pub fn is_complete(&self) -> bool {
+        self.payouts.iter().all(|payout| payout.status == PayoutStatus::Sent)
+    }
 }
 
 #[derive(Debug, Validate, Clone, Serialize)]
@@ -212,6 +319,15 @@ impl Default for BitcoinUtxos {
     }
 }
 
+/// Key of transaction meta field with usd price of transaction currency at the time of creation
+pub const USD_RATE_META_KEY: &str = "usdRate";
+/// Key of transaction meta field with the time pending withdrawal is expected to get required
+/// confirmations, updated as confirmations arrive
+pub const ESTIMATED_COMPLETION_AT_META_KEY: &str = "estimatedCompletionAt";
+/// Key of meta field of the credit part of exchange with the value kept in liquidity account because of spread,
+/// in the currency of that part
+pub const EXCHANGE_MARGIN_META_KEY: &str = "exchangeMargin";
+
 #[derive(Debug, Clone, Serialize)]
 pub struct TransactionOut {
     pub id: TransactionId,
@@ -227,6 +343,15 @@ pub struct TransactionOut {
     pub blockchain_tx_ids: Vec<BlockchainTransactionId>,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
+    /// Kind of the main transaction in the group, e.g. `withdrawal` for withdrawal with fees
+    pub kind: TransactionKind,
+    pub group_kind: TransactionGroupKind,
+    pub related_tx: Option<TransactionId>,
+    pub meta: Value,
+    /// Approximate usd value of `from_value` at the time of creation, if the rate was stored
+    pub usd_value: Option<f64>,
+    /// For pending transactions, when they are expected to get required confirmations
+    pub estimated_completion_at: Option<NaiveDateTime>,
 }
 
 // impl TransactionOut {
@@ -0,0 +1,129 @@
+use std::collections::HashMap;
+
+use chrono::NaiveDateTime;
+
+use models::*;
+
+/// Transactions of a group together with the groups that refer to them by `related_tx`,
+/// e.g. reversals of lost withdrawals and refunds of fees, which may be referred to in turn
+#[derive(Debug, Clone)]
+pub struct TransactionGroupTree {
+    pub gid: TransactionId,
+    pub transactions: Vec<Transaction>,
+    /// Oldest first
+    pub related: Vec<TransactionGroupTree>,
+}
+
+impl TransactionGroupTree {
+    /// Tree rooted at `gid` out of transactions of that group and the groups related to it, in any order.
+    /// Each group is put in the tree once, even if it refers to several groups of the tree
+    pub fn build(gid: TransactionId, transactions: Vec<Transaction>) -> Self {
+        let mut groups: HashMap<TransactionId, Vec<Transaction>> = HashMap::new();
+        for tx in transactions {
+            groups.entry(tx.gid).or_insert_with(Vec::new).push(tx);
+        }
+        Self::build_node(gid, &mut groups)
+    }
+
+    fn build_node(gid: TransactionId, groups: &mut HashMap<TransactionId, Vec<Transaction>>) -> Self {
+        let transactions = groups.remove(&gid).unwrap_or_default();
+        let mut related_groups: Vec<(TransactionId, Option<NaiveDateTime>)> = groups
+            .iter()
+            .filter(|(_, group)| {
+                group
+                    .iter()
+                    .any(|tx| tx.related_tx.map(|related| transactions.iter().any(|parent| parent.id == related)) == Some(true))
+            })
+            .map(|(related_gid, group)| (*related_gid, group.iter().map(|tx| tx.created_at).min()))
+            .collect();
+        related_groups.sort_by_key(|(_, created_at)| *created_at);
+        let mut related = vec![];
+        for (related_gid, _) in related_groups {
+            // might have been put deeper into the tree by a previous sibling
+            if groups.contains_key(&related_gid) {
+                related.push(Self::build_node(related_gid, groups));
+            }
+        }
+        Self {
+            gid,
+            transactions,
+            related,
+        }
+    }
+
+    /// Transactions of all groups of the tree, the root group first
+    pub fn flatten(&self) -> Vec<Transaction> {
+        let mut transactions = self.transactions.clone();
+        for related in &self.related {
+            transactions.extend(related.flatten());
+        }
+        transactions
+    }
+
+    /// Whether any group of the tree, including the root, is of the given kind
+    pub fn contains_group_kind(&self, kind: TransactionGroupKind) -> bool {
+        self.transactions.iter().any(|tx| tx.group_kind == kind) || self.related.iter().any(|related| related.contains_group_kind(kind))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn tx(gid: TransactionId, related_tx: Option<TransactionId>, created_at: NaiveDateTime) -> Transaction {
+        Transaction {
+            gid,
+            related_tx,
+            created_at,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_build_group_tree() {
+        let now = ::chrono::Utc::now().naive_utc();
+        let (gid, reversal_gid, refund_gid, reversal_refund_gid) = (
+            TransactionId::generate(),
+            TransactionId::generate(),
+            TransactionId::generate(),
+            TransactionId::generate(),
+        );
+        let withdrawal = tx(gid, None, now);
+        let fee = tx(gid, None, now);
+        let refund = tx(refund_gid, Some(fee.id), now + Duration::seconds(10));
+        let reversal = tx(reversal_gid, Some(withdrawal.id), now + Duration::seconds(20));
+        let reversal_fee = tx(reversal_gid, Some(fee.id), now + Duration::seconds(20));
+        let reversal_refund = tx(reversal_refund_gid, Some(reversal_fee.id), now + Duration::seconds(30));
+        let unrelated = tx(TransactionId::generate(), None, now);
+        let transactions = vec![
+            reversal_refund.clone(),
+            reversal.clone(),
+            unrelated,
+            withdrawal.clone(),
+            refund.clone(),
+            reversal_fee.clone(),
+            fee.clone(),
+        ];
+
+        let tree = TransactionGroupTree::build(gid, transactions);
+        assert_eq!(tree.gid, gid);
+        assert_eq!(tree.transactions.len(), 2);
+        // reversal refers to both transactions of the group, but appears once
+        assert_eq!(
+            tree.related.iter().map(|group| group.gid).collect::<Vec<_>>(),
+            vec![refund_gid, reversal_gid]
+        );
+        assert_eq!(tree.related[1].transactions.len(), 2);
+        assert_eq!(
+            tree.related[1].related.iter().map(|group| group.gid).collect::<Vec<_>>(),
+            vec![reversal_refund_gid]
+        );
+        assert_eq!(tree.flatten().len(), 6);
+        assert!(tree.contains_group_kind(TransactionGroupKind::Internal));
+        assert!(!tree.contains_group_kind(TransactionGroupKind::Reversal));
+
+        let empty = TransactionGroupTree::build(TransactionId::generate(), vec![]);
+        assert!(empty.transactions.is_empty() && empty.related.is_empty());
+    }
+}
@@ -44,6 +44,17 @@ impl TransactionId {
         TransactionId(uuid)
     }
 
+    /// Id of `index`-th item of a batch with this id, the first item gets the id itself.
+    /// The last byte is left to `next`, so transactions of different items don't collide
+    pub fn derive(&self, index: u32) -> Self {
+        let mut bytes = self.0.as_bytes().to_vec();
+        for i in 0..4 {
+            bytes[11 + i] ^= (index >> (8 * (3 - i))) as u8;
+        }
+        let uuid = Uuid::from_bytes(&bytes).unwrap();
+        TransactionId(uuid)
+    }
+
     pub fn last_byte(&self) -> u8 {
         let bytes = self.0.as_bytes().to_vec();
         let last = bytes.len() - 1;
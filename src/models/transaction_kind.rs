@@ -4,8 +4,9 @@ use diesel::serialize::{self, IsNull, Output, ToSql};
 use diesel::sql_types::VarChar;
 use std::io::Write;
 
-#[derive(Debug, FromSqlRow, AsExpression, Clone, Copy, Eq, PartialEq, Hash)]
+#[derive(Debug, Serialize, FromSqlRow, AsExpression, Clone, Copy, Eq, PartialEq, Hash)]
 #[sql_type = "VarChar"]
+#[serde(rename_all = "snake_case")]
 pub enum TransactionGroupKind {
     Deposit,
     Internal,
@@ -14,6 +15,12 @@ pub enum TransactionGroupKind {
     WithdrawalMulti,
     Approval,
     Reversal,
+    Bounce,
+    /// Funds moved between our own addresses, e.g. from former address of system account after rotation
+    /// or from hot addresses to cold storage
+    Sweep,
+    /// Part of withdrawal fee returned to user, when network fee turned out lower than estimated
+    FeeRefund,
 }
 
 impl FromSql<VarChar, Pg> for TransactionGroupKind {
@@ -26,6 +33,9 @@ impl FromSql<VarChar, Pg> for TransactionGroupKind {
             Some(b"withdrawal_multi") => Ok(TransactionGroupKind::WithdrawalMulti),
             Some(b"approval") => Ok(TransactionGroupKind::Approval),
             Some(b"reversal") => Ok(TransactionGroupKind::Reversal),
+            Some(b"bounce") => Ok(TransactionGroupKind::Bounce),
+            Some(b"sweep") => Ok(TransactionGroupKind::Sweep),
+            Some(b"fee_refund") => Ok(TransactionGroupKind::FeeRefund),
             Some(v) => Err(format!(
                 "Unrecognized enum variant: {:?}",
                 String::from_utf8(v.to_vec()).unwrap_or_else(|_| "Non - UTF8 value".to_string())
@@ -47,13 +57,17 @@ impl ToSql<VarChar, Pg> for TransactionGroupKind {
             TransactionGroupKind::WithdrawalMulti => out.write_all(b"withdrawal_multi")?,
             TransactionGroupKind::Approval => out.write_all(b"approval")?,
             TransactionGroupKind::Reversal => out.write_all(b"reversal")?,
+            TransactionGroupKind::Bounce => out.write_all(b"bounce")?,
+            TransactionGroupKind::Sweep => out.write_all(b"sweep")?,
+            TransactionGroupKind::FeeRefund => out.write_all(b"fee_refund")?,
         };
         Ok(IsNull::No)
     }
 }
 
-#[derive(Debug, FromSqlRow, AsExpression, Clone, Copy, Eq, PartialEq, Hash)]
+#[derive(Debug, Serialize, FromSqlRow, AsExpression, Clone, Copy, Eq, PartialEq, Hash)]
 #[sql_type = "VarChar"]
+#[serde(rename_all = "snake_case")]
 pub enum TransactionKind {
     Fee,
     BlockchainFee,
@@ -65,6 +79,8 @@ pub enum TransactionKind {
     ApprovalTransfer,
     ApprovalCall,
     Reversal,
+    Bounce,
+    Sweep,
 }
 
 impl FromSql<VarChar, Pg> for TransactionKind {
@@ -80,6 +96,8 @@ impl FromSql<VarChar, Pg> for TransactionKind {
             Some(b"approval_transfer") => Ok(TransactionKind::ApprovalTransfer),
             Some(b"approval_call") => Ok(TransactionKind::ApprovalCall),
             Some(b"reversal") => Ok(TransactionKind::Reversal),
+            Some(b"bounce") => Ok(TransactionKind::Bounce),
+            Some(b"sweep") => Ok(TransactionKind::Sweep),
             Some(v) => Err(format!(
                 "Unrecognized enum variant: {:?}",
                 String::from_utf8(v.to_vec()).unwrap_or_else(|_| "Non - UTF8 value".to_string())
@@ -104,6 +122,8 @@ impl ToSql<VarChar, Pg> for TransactionKind {
             TransactionKind::ApprovalCall => out.write_all(b"approval_call")?,
             TransactionKind::ApprovalTransfer => out.write_all(b"approval_transfer")?,
             TransactionKind::Reversal => out.write_all(b"reversal")?,
+            TransactionKind::Bounce => out.write_all(b"bounce")?,
+            TransactionKind::Sweep => out.write_all(b"sweep")?,
         };
         Ok(IsNull::No)
     }
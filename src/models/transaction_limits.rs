@@ -0,0 +1,43 @@
+use validator::{Validate, ValidationError};
+
+use models::*;
+
+/// Max value of a single transaction from an account. Values are in btc/eth/stq, like daily limits
+#[derive(Debug, Serialize, Deserialize, Validate, Clone, PartialEq)]
+pub struct TransactionLimits {
+    #[validate(custom = "valid_limit")]
+    pub btc: f64,
+    #[validate(custom = "valid_limit")]
+    pub eth: f64,
+    #[validate(custom = "valid_limit")]
+    pub stq: f64,
+}
+
+impl TransactionLimits {
+    pub fn limit(&self, currency: Currency) -> f64 {
+        match currency {
+            Currency::Btc => self.btc,
+            Currency::Eth => self.eth,
+            Currency::Stq => self.stq,
+        }
+    }
+}
+
+/// Transaction limits of a user, either from config or overridden for the user in runtime
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransactionLimitsSettings {
+    pub user_id: UserId,
+    pub limits: TransactionLimits,
+    pub overridden: bool,
+}
+
+fn valid_limit(input: f64) -> Result<(), ValidationError> {
+    if input.is_finite() && input >= 0f64 {
+        Ok(())
+    } else {
+        let mut error = ValidationError::new("lt_zero");
+        error.message = Some("Value is less than zero".into());
+        error.add_param("value".into(), &input.to_string());
+        Err(error)
+    }
+}
@@ -0,0 +1,35 @@
+/// Field transaction groups are ordered by in listings
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TransactionsSortField {
+    CreatedAt,
+    /// Value sent by the group, in base units of its currency
+    Value,
+    Status,
+}
+
+impl Default for TransactionsSortField {
+    fn default() -> Self {
+        TransactionsSortField::CreatedAt
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SortDirection {
+    Asc,
+    Desc,
+}
+
+impl Default for SortDirection {
+    fn default() -> Self {
+        SortDirection::Desc
+    }
+}
+
+/// Order of transaction groups in listings, newest first by default
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct TransactionsSort {
+    pub field: TransactionsSortField,
+    pub direction: SortDirection,
+}
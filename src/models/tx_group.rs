@@ -0,0 +1,24 @@
+use chrono::NaiveDateTime;
+
+use models::*;
+
+/// Summary of a transaction group, kept up to date by trigger on transactions, so that listings
+/// of groups don't have to aggregate the ledger
+#[derive(Debug, Queryable, Clone)]
+pub struct TxGroup {
+    pub gid: TransactionId,
+    pub user_id: UserId,
+    pub group_kind: TransactionGroupKind,
+    /// Creation time of the oldest transaction of the group
+    pub created_at: NaiveDateTime,
+    /// Pending while any of the transactions is pending
+    pub status: TransactionStatus,
+    /// Debit and credit accounts of the transactions
+    pub account_ids: Vec<AccountId>,
+    /// What's sent by the group, the same way converter computes `from_value`
+    pub value: Amount,
+    /// Creation time of the newest transaction of the group
+    pub last_created_at: NaiveDateTime,
+    /// Transactions of the group are moved to archive
+    pub archived: bool,
+}
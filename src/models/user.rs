@@ -1,8 +1,10 @@
-use chrono::NaiveDateTime;
+use std::collections::HashMap;
 
+use chrono::NaiveDateTime;
+use diesel::sql_types::{BigInt, Numeric, Uuid as SqlUuid, VarChar};
 use validator::Validate;
 
-use models::{AuthenticationToken, UserId};
+use models::{Amount, AuthenticationToken, Currency, UserId};
 use schema::users;
 
 #[derive(Debug, Queryable, Clone)]
@@ -12,6 +14,9 @@ pub struct User {
     pub authentication_token: AuthenticationToken,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
+    pub disabled: bool,
+    /// Withdrawals are allowed only to active addresses from the user's whitelist
+    pub withdrawal_whitelist: bool,
 }
 
 impl Default for User {
@@ -22,6 +27,8 @@ impl Default for User {
             authentication_token: AuthenticationToken::default(),
             created_at: ::chrono::Utc::now().naive_utc(),
             updated_at: ::chrono::Utc::now().naive_utc(),
+            disabled: false,
+            withdrawal_whitelist: false,
         }
     }
 }
@@ -54,4 +61,38 @@ pub struct UpdateUser {
     pub name: Option<String>,
     #[validate]
     pub authentication_token: Option<AuthenticationToken>,
+    pub disabled: Option<bool>,
+    pub withdrawal_whitelist: Option<bool>,
+}
+
+/// Active credit accounts of user in one currency
+#[derive(Debug, Queryable, QueryableByName, Clone, PartialEq)]
+pub struct UserAccountsSummary {
+    #[sql_type = "SqlUuid"]
+    pub user_id: UserId,
+    #[sql_type = "VarChar"]
+    pub currency: Currency,
+    #[sql_type = "BigInt"]
+    pub accounts_count: i64,
+    #[sql_type = "Numeric"]
+    pub balance: Amount,
+}
+
+/// User as seen by support, with totals of their accounts
+#[derive(Debug, Clone)]
+pub struct UserOverview {
+    pub user: User,
+    pub accounts_count: u64,
+    pub balances: HashMap<Currency, Amount>,
+}
+
+impl UserOverview {
+    pub fn new(user: User, summaries: &[UserAccountsSummary]) -> Self {
+        let summaries: Vec<_> = summaries.iter().filter(|summary| summary.user_id == user.id).collect();
+        Self {
+            accounts_count: summaries.iter().map(|summary| summary.accounts_count as u64).sum(),
+            balances: summaries.iter().map(|summary| (summary.currency, summary.balance)).collect(),
+            user,
+        }
+    }
 }
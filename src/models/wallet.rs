@@ -0,0 +1,18 @@
+use models::*;
+
+/// All user's accounts with balances, summed up by currency
+#[derive(Debug, Clone, Serialize)]
+pub struct Wallet {
+    pub accounts: Vec<AccountWithBalance>,
+    pub totals: Vec<CurrencyTotal>,
+    pub reference_currency: Currency,
+    /// Sum of all totals converted to reference currency with rates cached lately,
+    /// `None` if some of the rates are not cached
+    pub approximate_total: Option<Amount>,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct CurrencyTotal {
+    pub currency: Currency,
+    pub balance: Amount,
+}
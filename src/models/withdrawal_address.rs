@@ -0,0 +1,89 @@
+use chrono::NaiveDateTime;
+use uuid::Uuid;
+use validator::Validate;
+
+use models::*;
+use schema::withdrawal_addresses;
+
+/// Address from the user's withdrawal whitelist. It can be used only after `active_from`,
+/// so that stolen credentials are not enough to withdraw funds right away
+#[derive(Debug, Queryable, Clone)]
+pub struct WithdrawalAddress {
+    pub id: Uuid,
+    pub user_id: UserId,
+    pub currency: Currency,
+    pub address: BlockchainAddress,
+    pub label: Option<String>,
+    pub active_from: NaiveDateTime,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+impl WithdrawalAddress {
+    pub fn is_active(&self) -> bool {
+        self.active_from <= ::chrono::Utc::now().naive_utc()
+    }
+}
+
+impl Default for WithdrawalAddress {
+    fn default() -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            user_id: UserId::generate(),
+            currency: Currency::Eth,
+            address: BlockchainAddress::default(),
+            label: None,
+            active_from: ::chrono::Utc::now().naive_utc(),
+            created_at: ::chrono::Utc::now().naive_utc(),
+            updated_at: ::chrono::Utc::now().naive_utc(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Validate)]
+pub struct CreateWithdrawalAddress {
+    pub user_id: UserId,
+    pub currency: Currency,
+    #[validate]
+    pub address: BlockchainAddress,
+    #[validate(length(min = "1", max = "40", message = "Label must not be empty "))]
+    pub label: Option<String>,
+}
+
+#[derive(Debug, Insertable, Clone)]
+#[table_name = "withdrawal_addresses"]
+pub struct NewWithdrawalAddress {
+    pub id: Uuid,
+    pub user_id: UserId,
+    pub currency: Currency,
+    pub address: BlockchainAddress,
+    pub label: Option<String>,
+    pub active_from: NaiveDateTime,
+}
+
+impl Default for NewWithdrawalAddress {
+    fn default() -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            user_id: UserId::generate(),
+            currency: Currency::Eth,
+            address: BlockchainAddress::default(),
+            label: None,
+            active_from: ::chrono::Utc::now().naive_utc(),
+        }
+    }
+}
+
+impl From<NewWithdrawalAddress> for WithdrawalAddress {
+    fn from(new_address: NewWithdrawalAddress) -> Self {
+        Self {
+            id: new_address.id,
+            user_id: new_address.user_id,
+            currency: new_address.currency,
+            address: new_address.address,
+            label: new_address.label,
+            active_from: new_address.active_from,
+            ..Default::default()
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::future::{self, Either};
+use lapin_futures::channel::{BasicProperties, Channel};
+use lapin_futures::error::Error as LapinError;
+use tokio::net::tcp::TcpStream;
+use tokio::timer::Interval;
+
+use super::transactions_consumer::publish_for_retry;
+use config::Rabbit;
+use prelude::*;
+
+/// Acks are flushed at least that often, unless `rabbit.ack_flush_interval_ms` is set
+pub const DEFAULT_ACK_FLUSH_INTERVAL_MS: u64 = 200;
+
+/// Delivery tags of one channel, that are being handled or handled and not acked yet
+#[derive(Debug, Default)]
+struct AckWatermark {
+    in_flight: BTreeSet<u64>,
+    pending_acks: BTreeSet<u64>,
+}
+
+impl AckWatermark {
+    fn received(&mut self, delivery_tag: u64) {
+        self.in_flight.insert(delivery_tag);
+    }
+
+    fn acked(&mut self, delivery_tag: u64) {
+        self.in_flight.remove(&delivery_tag);
+        self.pending_acks.insert(delivery_tag);
+    }
+
+    // nack is sent right away, so the tag is just forgotten
+    fn nacked(&mut self, delivery_tag: u64) {
+        self.in_flight.remove(&delivery_tag);
+    }
+
+    fn pending_count(&self) -> usize {
+        self.pending_acks.len()
+    }
+
+    /// Highest tag, that can be acked with `multiple` without acking any message still being handled.
+    /// Tags up to it are forgotten, so the caller must send the ack
+    fn take_watermark(&mut self) -> Option<u64> {
+        let watermark = match self.in_flight.iter().next() {
+            Some(&lowest_in_flight) => self.pending_acks.range(..lowest_in_flight).next_back().cloned(),
+            None => self.pending_acks.iter().next_back().cloned(),
+        }?;
+        self.pending_acks = self.pending_acks.split_off(&(watermark + 1));
+        Some(watermark)
+    }
+}
+
+/// Acks deliveries of the channel. With `rabbit.ack_batch_size` set, acks of handled messages are
+/// collected and sent with `multiple` up to the highest tag below all messages still being handled,
+/// once the batch is full or on `flush`. Unflushed acks are lost with the channel, so these messages
+/// are delivered again. Nacks are always sent one by one
+#[derive(Clone)]
+pub struct Acker {
+    channel: Channel<TcpStream>,
+    batch_size: Option<usize>,
+    watermark: Arc<Mutex<AckWatermark>>,
+}
+
+impl Acker {
+    pub fn new(channel: Channel<TcpStream>, options: &Rabbit) -> Self {
+        Self {
+            channel,
+            batch_size: options.ack_batch_size,
+            watermark: Arc::new(Mutex::new(AckWatermark::default())),
+        }
+    }
+
+    /// Must be called in order of delivery, before the message is handled
+    pub fn received(&self, delivery_tag: u64) {
+        if self.batch_size.is_some() {
+            self.watermark.lock().unwrap().received(delivery_tag);
+        }
+    }
+
+    pub fn ack(&self, delivery_tag: u64) -> impl Future<Item = (), Error = LapinError> {
+        match self.batch_size {
+            None => Either::A(self.channel.basic_ack(delivery_tag, false)),
+            Some(batch_size) => {
+                let is_full = {
+                    let mut watermark = self.watermark.lock().unwrap();
+                    watermark.acked(delivery_tag);
+                    watermark.pending_count() >= batch_size
+                };
+                if is_full {
+                    Either::B(Either::A(self.flush()))
+                } else {
+                    Either::B(Either::B(future::ok(())))
+                }
+            }
+        }
+    }
+
+    pub fn nack(&self, delivery_tag: u64, requeue: bool) -> impl Future<Item = (), Error = LapinError> {
+        if self.batch_size.is_some() {
+            self.watermark.lock().unwrap().nacked(delivery_tag);
+        }
+        self.channel.basic_nack(delivery_tag, false, requeue)
+    }
+
+    /// Settles failed delivery by publishing it to retry queue, see `publish_for_retry`.
+    /// If it can't be published, the delivery is requeued and handled again right away
+    pub fn retry(
+        &self,
+        delivery_tag: u64,
+        retry_queue_name: &str,
+        data: Vec<u8>,
+        properties: BasicProperties,
+    ) -> impl Future<Item = (), Error = LapinError> {
+        let self_clone = self.clone();
+        publish_for_retry(&self.channel, retry_queue_name, data, properties).then(move |res| match res {
+            Ok(_) => Either::A(self_clone.ack(delivery_tag)),
+            Err(e) => {
+                error!("Error publishing message for retry: {}", e);
+                Either::B(self_clone.nack(delivery_tag, true))
+            }
+        })
+    }
+
+    /// Sends acks collected so far, as far as messages still being handled allow
+    pub fn flush(&self) -> impl Future<Item = (), Error = LapinError> {
+        let watermark = self.watermark.lock().unwrap().take_watermark();
+        match watermark {
+            Some(delivery_tag) => {
+                trace!("Acking rabbit deliveries up to {}", delivery_tag);
+                Either::A(self.channel.basic_ack(delivery_tag, true))
+            }
+            None => Either::B(future::ok(())),
+        }
+    }
+
+    /// Flushes acks every `rabbit.ack_flush_interval_ms`, so that batches that don't get full are acked as well.
+    /// Never resolves, meant to be selected with the subscription it acks for
+    pub fn flush_periodically(&self, options: &Rabbit) -> impl Future<Item = (), Error = ()> {
+        let interval = Duration::from_millis(options.ack_flush_interval_ms.unwrap_or(DEFAULT_ACK_FLUSH_INTERVAL_MS));
+        let self_clone = self.clone();
+        let enabled = self.batch_size.is_some();
+        Interval::new(Instant::now() + interval, interval)
+            .map_err(|e| {
+                error!("Ack flush timer error: {}", e);
+            })
+            .filter(move |_| enabled)
+            .for_each(move |_| {
+                self_clone.flush().map_err(|e| {
+                    error!("Error sending ack: {}", e);
+                })
+            })
+            .then(|_| future::empty::<(), ()>())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ack_watermark() {
+        let mut watermark = AckWatermark::default();
+        for tag in 1..=5 {
+            watermark.received(tag);
+        }
+        // 1 is still being handled, so nothing can be acked
+        watermark.acked(2);
+        watermark.acked(3);
+        assert_eq!(watermark.take_watermark(), None);
+        // nacked 1 is settled, 4 is still being handled
+        watermark.nacked(1);
+        assert_eq!(watermark.take_watermark(), Some(3));
+        assert_eq!(watermark.take_watermark(), None);
+        watermark.acked(5);
+        assert_eq!(watermark.take_watermark(), None);
+        watermark.received(6);
+        watermark.acked(4);
+        assert_eq!(watermark.pending_count(), 2);
+        assert_eq!(watermark.take_watermark(), Some(5));
+        // nacked tag is not used as watermark
+        watermark.nacked(6);
+        assert_eq!(watermark.take_watermark(), None);
+    }
+}
@@ -49,6 +49,12 @@ pub enum ErrorContext {
     AlreadyConnecting,
     #[fail(display = "rabbit error context - attempted to close the channel, but failed")]
     ChannelClose,
+    #[fail(display = "rabbit error context - connection health check failed")]
+    HealthCheck,
+    #[fail(display = "rabbit error context - connection is broken, waiting for reconnect")]
+    ConnectionBroken,
+    #[fail(display = "rabbit error context - error declaring user queues")]
+    UserQueues,
 }
 
 derive_error_impls!();
@@ -1,9 +1,17 @@
+mod acker;
 mod error;
 mod r2d2;
+mod sequencer;
+mod stats;
+mod topology;
 mod transactions_consumer;
 mod transactions_publisher;
 
+pub use self::acker::*;
 pub use self::error::*;
 pub use self::r2d2::*;
+pub use self::sequencer::*;
+pub use self::stats::*;
+pub use self::topology::*;
 pub use self::transactions_consumer::*;
 pub use self::transactions_publisher::*;
@@ -1,9 +1,11 @@
 use std::fmt::{self, Debug};
 use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 use failure;
+use futures::future::{self, Either};
 use lapin_futures::channel::{BasicQosOptions, Channel, ConfirmSelectOptions};
 use lapin_futures::client::{Client, ConnectionOptions, HeartbeatHandle};
 
@@ -13,12 +15,30 @@ use tokio::net::tcp::TcpStream;
 use tokio::timer::timeout::Timeout;
 
 use super::error::*;
+use super::stats::RabbitStats;
 use config::Config;
 use utils::log_error;
 
 // large limits may force RabbitMQ to close connection
 // (in case of socket buffer overflow)
 const CONSUMER_PREFETCH_COUNT: u16 = 10;
+/// Connection is checked that often, unless `rabbit.health_check_interval_secs` is set
+pub const DEFAULT_RABBIT_HEALTH_CHECK_INTERVAL_SECS: u64 = 30;
+
+/// Rabbit counterpart of r2d2 `ManageConnection`. The connection is shared and replaced on reconnect
+/// rather than pooled, so channels are checked against its generation instead of being validated one by one
+pub trait ManageRabbitConnection: Clone + Send + Sync + 'static {
+    type Channel: Clone + Send + 'static;
+
+    /// Opens a channel on the current connection
+    fn get_channel(&self) -> Result<Self::Channel, Error>;
+    /// Number of times the connection was replaced. Channels created before it changed are not usable
+    fn generation(&self) -> usize;
+    /// Cheap check, that the connection is known to be dead. It stays so until it's replaced
+    fn has_broken(&self) -> bool;
+    /// Checks that the connection is usable, reaching the broker
+    fn is_valid(&self) -> Box<Future<Item = (), Error = Error> + Send>;
+}
 
 #[derive(Clone)]
 pub struct RabbitConnectionManager {
@@ -27,6 +47,8 @@ pub struct RabbitConnectionManager {
     connection_timeout: Duration,
     connection_options: ConnectionOptions,
     address: SocketAddr,
+    stats: Arc<RabbitStats>,
+    generation: Arc<AtomicUsize>,
 }
 
 impl Debug for RabbitConnectionManager {
@@ -35,17 +57,28 @@ impl Debug for RabbitConnectionManager {
     }
 }
 
-struct RabbitHeartbeatHandle(Option<HeartbeatHandle>);
+struct RabbitHeartbeatHandle {
+    handle: Option<HeartbeatHandle>,
+    // set once heartbeat of the connection fails, the connection is dead after that
+    failed: Arc<AtomicBool>,
+}
 
 impl RabbitHeartbeatHandle {
-    pub fn new(handle: HeartbeatHandle) -> Self {
-        RabbitHeartbeatHandle(Some(handle))
+    pub fn new(handle: HeartbeatHandle, failed: Arc<AtomicBool>) -> Self {
+        RabbitHeartbeatHandle {
+            handle: Some(handle),
+            failed,
+        }
+    }
+
+    pub fn has_failed(&self) -> bool {
+        self.failed.load(Ordering::SeqCst)
     }
 }
 
 impl Drop for RabbitHeartbeatHandle {
     fn drop(&mut self) {
-        let handle = self.0.take();
+        let handle = self.handle.take();
         if let Some(h) = handle {
             h.stop();
         }
@@ -55,18 +88,22 @@ impl Drop for RabbitHeartbeatHandle {
 impl RabbitConnectionManager {
     pub fn create(config: &Config) -> impl Future<Item = Self, Error = Error> {
         let connection_timeout = Duration::from_secs(config.rabbit.connection_timeout_secs as u64);
+        let stats = Arc::new(RabbitStats::default());
         RabbitConnectionManager::extract_options_and_address(config)
             .into_future()
             .and_then(move |(options, address)| {
                 let options_clone = options.clone();
                 Timeout::new(
-                    RabbitConnectionManager::establish_client(address, options).map(move |(client, hearbeat_handle)| {
+                    RabbitConnectionManager::establish_client(address, options, stats.clone()).map(move |(client, hearbeat_handle)| {
+                        stats.set_connected(true);
                         RabbitConnectionManager {
                             client: Arc::new(Mutex::new(client)),
                             heartbeat_handle: Arc::new(Mutex::new(hearbeat_handle)),
                             connection_options: options_clone,
                             connection_timeout,
                             address,
+                            stats,
+                            generation: Arc::new(AtomicUsize::new(0)),
                         }
                     }),
                     connection_timeout,
@@ -78,6 +115,35 @@ impl RabbitConnectionManager {
             })
     }
 
+    /// Replaces the client with a new connection, channels of the old one are not usable after that
+    pub fn reconnect(&self) -> impl Future<Item = (), Error = Error> {
+        let self_clone = self.clone();
+        let stats = self.stats.clone();
+        let connection_timeout = self.connection_timeout;
+        Timeout::new(
+            RabbitConnectionManager::establish_client(self.address, self.connection_options.clone(), self.stats.clone()),
+            connection_timeout,
+        )
+        .map_err(move |e| {
+            let e: failure::Error = e.into_inner().map(|e| e.into()).unwrap_or(format_err!("Timeout error"));
+            ectx!(err e, ErrorSource::Timeout, ErrorContext::ConnectionTimeout, ErrorKind::Internal => connection_timeout)
+        })
+        .map(move |(client, heartbeat_handle)| {
+            *self_clone.client.lock().unwrap() = client;
+            // heartbeat of the old connection is stopped when its handle is dropped
+            *self_clone.heartbeat_handle.lock().unwrap() = heartbeat_handle;
+            self_clone.generation.fetch_add(1, Ordering::SeqCst);
+        })
+        .then(move |res| {
+            stats.record_reconnect(res.is_ok());
+            res
+        })
+    }
+
+    pub fn stats(&self) -> Arc<RabbitStats> {
+        self.stats.clone()
+    }
+
     fn extract_options_and_address(config: &Config) -> Result<(ConnectionOptions, SocketAddr), Error> {
         let url = config.rabbit.url.clone();
         let url_clone = config.rabbit.url.clone();
@@ -108,6 +174,7 @@ impl RabbitConnectionManager {
     fn establish_client(
         address: SocketAddr,
         options: ConnectionOptions,
+        stats: Arc<RabbitStats>,
     ) -> impl Future<Item = (Client<TcpStream>, RabbitHeartbeatHandle), Error = Error> {
         let address_clone2 = address.clone();
         let address_clone3 = address.clone();
@@ -122,17 +189,25 @@ impl RabbitConnectionManager {
             .and_then(move |(client, mut heartbeat)| {
                 info!("Connected to rabbit");
                 let handle = heartbeat.handle();
+                let failed = Arc::new(AtomicBool::new(false));
+                let failed_clone = failed.clone();
                 tokio::spawn(heartbeat.map_err(move |e| {
+                    failed_clone.store(true, Ordering::SeqCst);
+                    stats.set_connected(false);
                     let e: Error = ectx!(err e, ErrorContext::Heartbeat, ErrorKind::Internal);
                     log_error(&e);
                 }));
                 handle
                     .ok_or(ectx!(err ErrorContext::HeartbeatHandle, ErrorKind::Internal))
-                    .map(move |handle| (client, RabbitHeartbeatHandle::new(handle)))
+                    .map(move |handle| (client, RabbitHeartbeatHandle::new(handle, failed)))
             })
     }
+}
+
+impl ManageRabbitConnection for RabbitConnectionManager {
+    type Channel = Channel<TcpStream>;
 
-    pub fn get_channel(&self) -> Result<Channel<TcpStream>, Error> {
+    fn get_channel(&self) -> Result<Channel<TcpStream>, Error> {
         trace!("Creating rabbit channel...");
         let cli = self.client.lock().unwrap();
         let ch = cli
@@ -150,4 +225,34 @@ impl RabbitConnectionManager {
         trace!("Rabbit channel is created");
         Ok(ch)
     }
+
+    fn generation(&self) -> usize {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// Heartbeat of the connection has failed, it stays so until `reconnect`
+    fn has_broken(&self) -> bool {
+        self.heartbeat_handle.lock().unwrap().has_failed()
+    }
+
+    /// Checks that a channel can be opened and closed on the connection within connection timeout
+    fn is_valid(&self) -> Box<Future<Item = (), Error = Error> + Send> {
+        let stats = self.stats.clone();
+        let connection_timeout = self.connection_timeout;
+        let check = if self.has_broken() {
+            Either::A(future::err(ectx!(err ErrorContext::ConnectionBroken, ErrorKind::Internal)))
+        } else {
+            let channel = self.client.lock().unwrap().create_channel();
+            Either::B(
+                Timeout::new(channel.and_then(|channel| channel.close(200, "health check")), connection_timeout).map_err(move |e| {
+                    let e: failure::Error = e.into_inner().map(|e| e.into()).unwrap_or(format_err!("Timeout error"));
+                    ectx!(err e, ErrorSource::Timeout, ErrorContext::HealthCheck, ErrorKind::Internal => connection_timeout)
+                }),
+            )
+        };
+        Box::new(check.then(move |res| {
+            stats.record_health_check(res.is_ok());
+            res
+        }))
+    }
 }
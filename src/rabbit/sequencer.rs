@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+
+use futures::future::{self, Shared};
+use futures::sync::oneshot;
+
+use prelude::*;
+
+type Done = Shared<Box<Future<Item = (), Error = ()> + Send>>;
+
+/// Runs jobs so that the ones sharing at least one key are run one after another
+/// in the order they were scheduled, while jobs with distinct keys are run concurrently.
+#[derive(Clone)]
+pub struct KeyedSequencer<K: Hash + Eq> {
+    state: Arc<Mutex<SequencerState<K>>>,
+}
+
+struct SequencerState<K: Hash + Eq> {
+    next_id: u64,
+    // last scheduled job for every key
+    last_jobs: HashMap<K, (u64, Done)>,
+}
+
+impl<K: Hash + Eq + Clone + Send + 'static> KeyedSequencer<K> {
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(SequencerState {
+                next_id: 0,
+                last_jobs: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Job is started only after all previously scheduled jobs with any of `keys` are finished
+    pub fn schedule<F, R>(&self, keys: Vec<K>, job: F) -> Box<Future<Item = R::Item, Error = R::Error> + Send>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: IntoFuture + 'static,
+        R::Future: Send,
+        R::Item: Send,
+        R::Error: Send,
+    {
+        // done is resolved when the job is finished or dropped
+        let (done_sender, done_receiver) = oneshot::channel::<()>();
+        let done: Box<Future<Item = (), Error = ()> + Send> = Box::new(done_receiver.then(|_| Ok(())));
+        let done = done.shared();
+
+        let (id, previous_jobs) = {
+            let mut state = self.state.lock().unwrap();
+            let id = state.next_id;
+            state.next_id += 1;
+            let mut previous_jobs = vec![];
+            for key in keys.iter() {
+                if let Some((_, previous_job)) = state.last_jobs.insert(key.clone(), (id, done.clone())) {
+                    previous_jobs.push(previous_job);
+                }
+            }
+            (id, previous_jobs)
+        };
+
+        let state = self.state.clone();
+        Box::new(future::join_all(previous_jobs).then(move |_| job()).then(move |res| {
+            let _ = done_sender.send(());
+            let mut state = state.lock().unwrap();
+            for key in keys {
+                let is_last = state.last_jobs.get(&key).map(|(last_id, _)| *last_id == id).unwrap_or(false);
+                if is_last {
+                    state.last_jobs.remove(&key);
+                }
+            }
+            res
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_core::reactor::Core;
+
+    #[test]
+    fn test_sequencer_orders_jobs_with_same_key() {
+        let mut core = Core::new().unwrap();
+        let sequencer = KeyedSequencer::new();
+        let log = Arc::new(Mutex::new(vec![]));
+        // the first job is blocked until the signal, so the third one, with distinct key, finishes first
+        let (signal_sender, signal_receiver) = oneshot::channel::<()>();
+        let log1 = log.clone();
+        let job1 = sequencer.schedule(vec!["a"], move || {
+            signal_receiver.map_err(|_| ()).map(move |_| log1.lock().unwrap().push(1))
+        });
+        let log2 = log.clone();
+        let job2 = sequencer.schedule(vec!["a", "b"], move || {
+            log2.lock().unwrap().push(2);
+            Ok::<(), ()>(())
+        });
+        let log3 = log.clone();
+        let job3 = sequencer.schedule(vec!["c"], move || {
+            log3.lock().unwrap().push(3);
+            let _ = signal_sender.send(());
+            Ok::<(), ()>(())
+        });
+        core.run(future::join_all(vec![job1, job2, job3])).unwrap();
+        assert_eq!(*log.lock().unwrap(), vec![3, 1, 2]);
+        assert!(sequencer.state.lock().unwrap().last_jobs.is_empty());
+    }
+}
@@ -0,0 +1,57 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use chrono::{NaiveDateTime, Utc};
+
+use models::*;
+
+/// Connection events of rabbit connection manager, shared with metrics
+#[derive(Debug, Default)]
+pub struct RabbitStats {
+    reconnects_count: AtomicUsize,
+    failed_reconnects_count: AtomicUsize,
+    last_reconnect_at: Mutex<Option<NaiveDateTime>>,
+    connected: AtomicBool,
+    failed_health_checks_count: AtomicUsize,
+    last_health_check_at: Mutex<Option<NaiveDateTime>>,
+}
+
+impl RabbitStats {
+    pub fn record_reconnect(&self, succeeded: bool) {
+        if succeeded {
+            self.reconnects_count.fetch_add(1, Ordering::SeqCst);
+            *self.last_reconnect_at.lock().unwrap() = Some(Utc::now().naive_utc());
+        } else {
+            self.failed_reconnects_count.fetch_add(1, Ordering::SeqCst);
+        }
+        self.set_connected(succeeded);
+    }
+
+    pub fn record_health_check(&self, succeeded: bool) {
+        if !succeeded {
+            self.failed_health_checks_count.fetch_add(1, Ordering::SeqCst);
+        }
+        *self.last_health_check_at.lock().unwrap() = Some(Utc::now().naive_utc());
+        self.set_connected(succeeded);
+    }
+
+    /// Connection is considered lost after failed heartbeat or health check, until it's reestablished
+    pub fn set_connected(&self, connected: bool) {
+        self.connected.store(connected, Ordering::SeqCst);
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::SeqCst)
+    }
+
+    pub fn metrics(&self) -> RabbitMetrics {
+        RabbitMetrics {
+            reconnects_count: self.reconnects_count.load(Ordering::SeqCst) as u64,
+            failed_reconnects_count: self.failed_reconnects_count.load(Ordering::SeqCst) as u64,
+            last_reconnect_at: *self.last_reconnect_at.lock().unwrap(),
+            connected: self.is_connected(),
+            failed_health_checks_count: self.failed_health_checks_count.load(Ordering::SeqCst) as u64,
+            last_health_check_at: *self.last_health_check_at.lock().unwrap(),
+        }
+    }
+}
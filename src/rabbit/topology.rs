@@ -0,0 +1,254 @@
+use futures::future;
+use lapin_futures::channel::{BasicProperties, Channel, ExchangeDeclareOptions, QueueDeclareOptions};
+use lapin_futures::error::Error as LapinError;
+use lapin_futures::types::{AMQPValue, FieldTable};
+use tokio::net::tcp::TcpStream;
+
+use super::error::*;
+use config::{RabbitNames, RabbitTopology};
+use models::*;
+use prelude::*;
+
+pub const DEFAULT_EXCHANGE_NAME: &str = "transactions";
+pub const DEFAULT_USER_TRANSACTIONS_QUEUE_PREFIX: &str = "transactions_";
+pub const DEFAULT_USER_DEPOSITS_QUEUE_PREFIX: &str = "deposits_";
+pub const DEFAULT_RETRY_QUEUE_SUFFIX: &str = "_retry";
+const PERSISTENT_DELIVERY_MODE: u8 = 2;
+
+/// Queues of one user that transactions and deposits are published to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserQueueKind {
+    Transactions,
+    Deposits,
+}
+
+impl UserQueueKind {
+    pub fn all() -> Vec<UserQueueKind> {
+        vec![UserQueueKind::Transactions, UserQueueKind::Deposits]
+    }
+}
+
+/// Declares everything the service consumes from and publishes to, with options and names from config
+#[derive(Debug, Clone)]
+pub struct Topology {
+    options: RabbitTopology,
+    names: RabbitNames,
+}
+
+impl Topology {
+    pub fn new(options: RabbitTopology) -> Self {
+        let names = options.names.clone().unwrap_or_default();
+        Self { options, names }
+    }
+
+    /// Exchange that transactions and deposits are published to
+    pub fn exchange_name(&self) -> &str {
+        self.names
+            .exchange
+            .as_ref()
+            .map(|name| name.as_str())
+            .unwrap_or(DEFAULT_EXCHANGE_NAME)
+    }
+
+    /// Queue name, that is also its routing key in the exchange
+    pub fn user_queue_name(&self, kind: UserQueueKind, user_id: UserId) -> String {
+        let prefix = match kind {
+            UserQueueKind::Transactions => self
+                .names
+                .user_transactions_queue_prefix
+                .as_ref()
+                .map(|prefix| prefix.as_str())
+                .unwrap_or(DEFAULT_USER_TRANSACTIONS_QUEUE_PREFIX),
+            UserQueueKind::Deposits => self
+                .names
+                .user_deposits_queue_prefix
+                .as_ref()
+                .map(|prefix| prefix.as_str())
+                .unwrap_or(DEFAULT_USER_DEPOSITS_QUEUE_PREFIX),
+        };
+        format!("{}{}", prefix, user_id)
+    }
+
+    /// Queue of blockchain transactions published by blockchain gateway, `{currency}_transactions` by default
+    pub fn currency_queue_name(&self, currency: Currency) -> String {
+        let name = match currency {
+            Currency::Btc => self.names.btc_transactions_queue.clone(),
+            Currency::Eth => self.names.eth_transactions_queue.clone(),
+            Currency::Stq => self.names.stq_transactions_queue.clone(),
+        };
+        name.unwrap_or_else(|| format!("{}_transactions", currency))
+    }
+
+    /// Queue that failed messages of currency queue are published to. They expire after retry delay
+    /// and are dead lettered back to currency queue, with the number of attempts in `x-death` header
+    pub fn currency_retry_queue_name(&self, currency: Currency) -> String {
+        let suffix = self
+            .names
+            .retry_queue_suffix
+            .as_ref()
+            .map(|suffix| suffix.as_str())
+            .unwrap_or(DEFAULT_RETRY_QUEUE_SUFFIX);
+        format!("{}{}", self.currency_queue_name(currency), suffix)
+    }
+
+    /// Declares transactions exchange and queues of all currencies
+    pub fn declare(&self, channel: &Channel<TcpStream>) -> impl Future<Item = (), Error = Error> + Send {
+        let mut fs: Vec<Box<Future<Item = (), Error = LapinError> + Send>> = vec![];
+        fs.push(Box::new(channel.exchange_declare(
+            self.exchange_name(),
+            "direct",
+            ExchangeDeclareOptions {
+                durable: self.options.durable,
+                ..Default::default()
+            },
+            FieldTable::new(),
+        )));
+        for currency in vec![Currency::Btc, Currency::Eth, Currency::Stq] {
+            fs.push(Box::new(
+                channel
+                    .queue_declare(&self.currency_queue_name(currency), self.queue_options(), FieldTable::new())
+                    .map(|_| ()),
+            ));
+            fs.push(Box::new(
+                channel
+                    .queue_declare(
+                        &self.currency_retry_queue_name(currency),
+                        self.queue_options(),
+                        self.currency_retry_queue_arguments(currency),
+                    )
+                    .map(|_| ()),
+            ));
+        }
+        future::join_all(fs)
+            .map(|_| ())
+            .map_err(ectx!(ErrorSource::Lapin, ErrorKind::Internal))
+    }
+
+    /// Declares queues of users and binds them to transactions exchange
+    pub fn declare_user_queues(&self, channel: &Channel<TcpStream>, users: Vec<UserId>) -> impl Future<Item = (), Error = Error> + Send {
+        let mut fs: Vec<Box<Future<Item = (), Error = LapinError> + Send>> = vec![];
+        let queue_names = users
+            .into_iter()
+            .flat_map(|user| UserQueueKind::all().into_iter().map(move |kind| self.user_queue_name(kind, user)));
+        for queue_name in queue_names {
+            let arguments = self.user_queue_arguments();
+            fs.push(Box::new(
+                channel.queue_declare(&queue_name, self.queue_options(), arguments).map(|_| ()),
+            ));
+            fs.push(Box::new(channel.queue_bind(
+                &queue_name,
+                self.exchange_name(),
+                &queue_name,
+                Default::default(),
+                FieldTable::new(),
+            )));
+        }
+        future::join_all(fs)
+            .map(|_| ())
+            .map_err(ectx!(ErrorSource::Lapin, ErrorKind::Internal))
+    }
+
+    /// Properties of published messages, that survive broker restarts in durable queues
+    pub fn message_properties(&self) -> BasicProperties {
+        if self.options.durable {
+            BasicProperties::default().with_delivery_mode(PERSISTENT_DELIVERY_MODE)
+        } else {
+            BasicProperties::default()
+        }
+    }
+
+    pub fn queue_options(&self) -> QueueDeclareOptions {
+        QueueDeclareOptions {
+            durable: self.options.durable,
+            ..Default::default()
+        }
+    }
+
+    /// Lazy mode and ttl are only set for user queues. Currency queues are owned by blockchain gateway,
+    /// redeclaring them with other arguments would fail
+    pub fn user_queue_arguments(&self) -> FieldTable {
+        let mut arguments = FieldTable::new();
+        if self.options.lazy_queues {
+            arguments.insert("x-queue-mode".to_string(), AMQPValue::LongString("lazy".to_string()));
+        }
+        if let Some(ttl_secs) = self.options.user_message_ttl_secs {
+            arguments.insert("x-message-ttl".to_string(), AMQPValue::LongLongInt((ttl_secs * 1000) as i64));
+        }
+        arguments
+    }
+
+    /// Messages expire after retry delay and are dead lettered back to currency queue
+    pub fn currency_retry_queue_arguments(&self, currency: Currency) -> FieldTable {
+        let mut arguments = FieldTable::new();
+        arguments.insert(
+            "x-message-ttl".to_string(),
+            AMQPValue::LongLongInt(self.options.retry_delay_ms as i64),
+        );
+        arguments.insert("x-dead-letter-exchange".to_string(), AMQPValue::LongString(String::new()));
+        arguments.insert(
+            "x-dead-letter-routing-key".to_string(),
+            AMQPValue::LongString(self.currency_queue_name(currency)),
+        );
+        arguments
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn topology(names: Option<RabbitNames>) -> Topology {
+        Topology::new(RabbitTopology {
+            durable: true,
+            lazy_queues: false,
+            user_message_ttl_secs: None,
+            retry_delay_ms: 1000,
+            names,
+        })
+    }
+
+    #[test]
+    fn test_lazy_user_queues_only() {
+        let lazy = Topology::new(RabbitTopology {
+            durable: true,
+            lazy_queues: true,
+            user_message_ttl_secs: Some(60),
+            retry_delay_ms: 1000,
+            names: None,
+        });
+        let arguments = lazy.user_queue_arguments();
+        assert_eq!(arguments.get("x-queue-mode"), Some(&AMQPValue::LongString("lazy".to_string())));
+        assert_eq!(arguments.get("x-message-ttl"), Some(&AMQPValue::LongLongInt(60_000)));
+        assert_eq!(lazy.currency_retry_queue_arguments(Currency::Btc).get("x-queue-mode"), None);
+    }
+
+    #[test]
+    fn test_names() {
+        let user_id = UserId::generate();
+        let default = topology(None);
+        assert_eq!(default.exchange_name(), "transactions");
+        assert_eq!(
+            default.user_queue_name(UserQueueKind::Deposits, user_id),
+            format!("deposits_{}", user_id)
+        );
+        assert_eq!(default.currency_retry_queue_name(Currency::Btc), "btc_transactions_retry");
+        let staging = topology(Some(RabbitNames {
+            exchange: Some("staging_transactions".to_string()),
+            user_transactions_queue_prefix: Some("staging_transactions_".to_string()),
+            btc_transactions_queue: Some("staging_btc_transactions".to_string()),
+            ..Default::default()
+        }));
+        assert_eq!(staging.exchange_name(), "staging_transactions");
+        assert_eq!(
+            staging.user_queue_name(UserQueueKind::Transactions, user_id),
+            format!("staging_transactions_{}", user_id)
+        );
+        // unset names keep defaults
+        assert_eq!(
+            staging.user_queue_name(UserQueueKind::Deposits, user_id),
+            format!("deposits_{}", user_id)
+        );
+        assert_eq!(staging.currency_retry_queue_name(Currency::Btc), "staging_btc_transactions_retry");
+        assert_eq!(staging.currency_queue_name(Currency::Eth), "eth_transactions");
+    }
+}
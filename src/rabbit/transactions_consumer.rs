@@ -1,25 +1,189 @@
-use futures::future;
-use lapin_futures::channel::{BasicConsumeOptions, Channel, QueueDeclareOptions};
+use std::fmt::{self, Display};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::future::{self, Either, Loop};
+use lapin_async::message::Delivery;
+use lapin_futures::channel::{BasicConsumeOptions, BasicProperties, Channel};
 use lapin_futures::consumer::Consumer;
-use lapin_futures::types::FieldTable;
+use lapin_futures::error::Error as LapinError;
+use lapin_futures::types::{AMQPValue, FieldTable};
 use tokio::net::tcp::TcpStream;
+use tokio::timer::Delay;
 
 use super::error::*;
-use super::r2d2::RabbitConnectionManager;
+use super::r2d2::{ManageRabbitConnection, RabbitConnectionManager};
+use super::topology::Topology;
 use models::*;
 use prelude::*;
+use request_id::{self, INSTANCE_HEADER, REQUEST_ID_HEADER, TRANSACTION_GID_HEADER};
+use utils::log_error;
+
+const MIN_DELAY_BEFORE_RECONNECT_MS: u64 = 1000;
+const MAX_DELAY_BEFORE_RECONNECT_MS: u64 = 60_000;
+
+/// Tracing headers the message was published with, as far as publisher sent them
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MessageTrace {
+    pub request_id: Option<String>,
+    pub instance: Option<String>,
+    pub gid: Option<String>,
+}
+
+impl Display for MessageTrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "published by instance {} for transaction group {}",
+            self.instance.as_ref().map(|s| s.as_str()).unwrap_or("-"),
+            self.gid.as_ref().map(|s| s.as_str()).unwrap_or("-")
+        )
+    }
+}
+
+pub fn delivery_trace(delivery: &Delivery) -> MessageTrace {
+    delivery.properties.headers().as_ref().map(trace_from_headers).unwrap_or_default()
+}
+
+// values come from other services, so they are only accepted if they are fine to log and forward
+pub fn trace_from_headers(headers: &FieldTable) -> MessageTrace {
+    let header = |name: &str| match headers.get(name) {
+        Some(AMQPValue::LongString(value)) => request_id::parse(value),
+        _ => None,
+    };
+    MessageTrace {
+        request_id: header(REQUEST_ID_HEADER),
+        instance: header(INSTANCE_HEADER),
+        gid: header(TRANSACTION_GID_HEADER),
+    }
+}
+
+/// Number of the current attempt to handle the message, counting previous retries from `x-death` header
+pub fn delivery_attempts(delivery: &Delivery, retry_queue_name: &str) -> u32 {
+    let retries = delivery
+        .properties
+        .headers()
+        .as_ref()
+        .map(|headers| retries_count(headers, retry_queue_name))
+        .unwrap_or(0);
+    retries.saturating_add(1)
+}
+
+/// What is done with the message that failed to be handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailedDeliveryAction {
+    /// Published to retry queue, see `publish_for_retry`
+    Retry,
+    /// Kept in quarantined messages, see `BlockchainFetcher::quarantine_message`
+    Quarantine,
+}
+
+impl FailedDeliveryAction {
+    pub fn new(attempts: u32, max_delivery_attempts: u32) -> Self {
+        if attempts < max_delivery_attempts {
+            FailedDeliveryAction::Retry
+        } else {
+            FailedDeliveryAction::Quarantine
+        }
+    }
+}
+
+/// Publishes failed message to retry queue with the headers it came with, so that rabbit counts its
+/// expirations in `x-death`. Currency queues are declared by blockchain gateway, so they can't dead letter
+/// rejected messages themselves. The delivery must be acked once this resolves
+pub fn publish_for_retry(
+    channel: &Channel<TcpStream>,
+    retry_queue_name: &str,
+    data: Vec<u8>,
+    properties: BasicProperties,
+) -> impl Future<Item = (), Error = LapinError> {
+    channel
+        .basic_publish("", retry_queue_name, data, Default::default(), properties)
+        .map(|_| ())
+}
+
+// rabbit keeps one entry per queue and reason with the number of times it happened
+fn retries_count(headers: &FieldTable, retry_queue_name: &str) -> u32 {
+    let entries = match headers.get("x-death") {
+        Some(AMQPValue::FieldArray(entries)) => entries,
+        _ => return 0,
+    };
+    entries
+        .iter()
+        .filter_map(|entry| match entry {
+            AMQPValue::FieldTable(entry) => Some(entry),
+            _ => None,
+        })
+        .filter(|entry| match (entry.get("queue"), entry.get("reason")) {
+            (Some(AMQPValue::LongString(queue)), Some(AMQPValue::LongString(reason))) => queue == retry_queue_name && reason == "expired",
+            _ => false,
+        })
+        .map(|entry| match entry.get("count") {
+            Some(AMQPValue::LongLongInt(count)) => *count as u32,
+            Some(AMQPValue::LongInt(count)) => *count as u32,
+            _ => 0,
+        })
+        .fold(0u32, |acc, count| acc.saturating_add(count))
+}
 
 #[derive(Clone)]
 pub struct TransactionConsumerImpl {
     rabbit_pool: RabbitConnectionManager,
+    topology: Topology,
 }
 
 impl TransactionConsumerImpl {
-    pub fn new(rabbit_pool: RabbitConnectionManager) -> Self {
-        Self { rabbit_pool }
+    pub fn new(rabbit_pool: RabbitConnectionManager, topology: Topology) -> Self {
+        Self { rabbit_pool, topology }
     }
 
-    pub fn subscribe(&self) -> impl Future<Item = Vec<(Consumer<TcpStream>, Channel<TcpStream>)>, Error = Error> {
+    /// Subscribes to transactions of all currencies and runs `handle` for every subscription until it ends.
+    /// Messages published with `publish_for_retry` are delivered again after retry delay.
+    /// Once any of them ends, e.g. when connection is lost, the connection is reestablished with backoff
+    /// and all currencies are subscribed again
+    pub fn consume<H, F>(&self, handle: H) -> impl Future<Item = (), Error = ()>
+    where
+        H: Fn(Consumer<TcpStream>, Channel<TcpStream>, Currency) -> F + Send + Sync + 'static,
+        F: Future<Item = (), Error = ()> + Send + 'static,
+    {
+        let self_clone = self.clone();
+        let handle = Arc::new(handle);
+        future::loop_fn(0, move |failures| {
+            let self_clone2 = self_clone.clone();
+            let handle = handle.clone();
+            let reconnected = if failures == 0 {
+                Either::A(future::ok(()))
+            } else {
+                let delay = reconnect_delay(failures);
+                let rabbit_pool = self_clone.rabbit_pool.clone();
+                warn!("Reconnecting to rabbit in {} s, attempt {}", delay.as_secs(), failures);
+                Either::B(
+                    Delay::new(Instant::now() + delay)
+                        .map_err(ectx!(ErrorSource::Timeout, ErrorKind::Internal))
+                        .and_then(move |_| rabbit_pool.reconnect()),
+                )
+            };
+            reconnected.and_then(move |_| self_clone2.subscribe()).then(move |res| match res {
+                Ok(subscriptions) => {
+                    info!("Subscribed to transactions in rabbit");
+                    let subscriptions = subscriptions
+                        .into_iter()
+                        .map(|(consumer, channel, currency)| handle(consumer, channel, currency));
+                    // channels share the connection, so the others are most likely dead as well
+                    Either::A(future::select_all(subscriptions).then(|_| {
+                        warn!("Rabbit subscription ended");
+                        Ok(Loop::Continue(1))
+                    }))
+                }
+                Err(e) => {
+                    log_error(&e);
+                    Either::B(future::ok(Loop::Continue(failures + 1)))
+                }
+            })
+        })
+    }
+
+    pub fn subscribe(&self) -> impl Future<Item = Vec<(Consumer<TcpStream>, Channel<TcpStream>, Currency)>, Error = Error> {
         let self_clone = self.clone();
         let fs = vec![Currency::Btc, Currency::Eth, Currency::Stq].into_iter().map(move |currency| {
             let self_clone2 = self_clone.clone();
@@ -30,6 +194,18 @@ impl TransactionConsumerImpl {
         future::join_all(fs)
     }
 
+    /// Publishes the message to currency queue, as if it came from blockchain gateway
+    pub fn republish(&self, currency: Currency, payload: Vec<u8>) -> impl Future<Item = (), Error = Error> {
+        let properties = self.topology.message_properties();
+        let queue_name = self.topology.currency_queue_name(currency);
+        self.get_channel().and_then(move |channel| {
+            channel
+                .basic_publish("", &queue_name, payload, Default::default(), properties)
+                .map(|_| ())
+                .map_err(ectx!(ErrorSource::Lapin, ErrorKind::Internal => currency))
+        })
+    }
+
     fn get_channel(&self) -> impl Future<Item = Channel<TcpStream>, Error = Error> {
         self.rabbit_pool
             .get_channel()
@@ -41,24 +217,124 @@ impl TransactionConsumerImpl {
         &self,
         channel: &Channel<TcpStream>,
         currency: Currency,
-    ) -> impl Future<Item = (Consumer<TcpStream>, Channel<TcpStream>), Error = Error> {
-        let queue_name = format!("{}_transactions", currency);
+    ) -> impl Future<Item = (Consumer<TcpStream>, Channel<TcpStream>, Currency), Error = Error> {
+        let queue_name = self.topology.currency_queue_name(currency);
         let channel_clone = channel.clone();
+        // the queue is declared with the same options as on start, in case it was deleted meanwhile
         channel
-            .queue_declare(
-                &queue_name,
-                QueueDeclareOptions {
-                    durable: true,
-                    ..Default::default()
-                },
-                Default::default(),
-            )
+            .queue_declare(&queue_name, self.topology.queue_options(), FieldTable::new())
             .map_err(ectx!(ErrorSource::Lapin, ErrorKind::Internal))
             .and_then(move |queue| {
                 channel_clone
                     .basic_consume(&queue, "", BasicConsumeOptions::default(), FieldTable::new())
-                    .map(move |consumer| (consumer, channel_clone))
+                    .map(move |consumer| (consumer, channel_clone, currency))
                     .map_err(ectx!(ErrorSource::Lapin, ErrorKind::Internal))
             })
     }
 }
+
+// doubles with every failed attempt
+fn reconnect_delay(failures: u32) -> Duration {
+    let multiplier = 1u64.checked_shl(failures.saturating_sub(1)).unwrap_or(u64::max_value());
+    let delay = MIN_DELAY_BEFORE_RECONNECT_MS.saturating_mul(multiplier);
+    Duration::from_millis(delay.min(MAX_DELAY_BEFORE_RECONNECT_MS))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::transactions_publisher::trace_headers;
+    use super::*;
+    use config::RabbitTopology;
+
+    #[test]
+    fn test_reconnect_delay() {
+        assert_eq!(reconnect_delay(1), Duration::from_millis(1000));
+        assert_eq!(reconnect_delay(2), Duration::from_millis(2000));
+        assert_eq!(reconnect_delay(4), Duration::from_millis(8000));
+        assert_eq!(reconnect_delay(7), Duration::from_millis(MAX_DELAY_BEFORE_RECONNECT_MS));
+        assert_eq!(reconnect_delay(100), Duration::from_millis(MAX_DELAY_BEFORE_RECONNECT_MS));
+    }
+
+    #[test]
+    fn test_trace_headers() {
+        let gid = TransactionId::generate();
+        let headers = trace_headers(Some("request".to_string()), "transactions-1", gid);
+        assert_eq!(
+            trace_from_headers(&headers),
+            MessageTrace {
+                request_id: Some("request".to_string()),
+                instance: Some("transactions-1".to_string()),
+                gid: Some(gid.to_string()),
+            }
+        );
+        let mut headers = FieldTable::new();
+        assert_eq!(trace_from_headers(&headers), MessageTrace::default());
+        headers.insert(INSTANCE_HEADER.to_string(), AMQPValue::LongString("with space".to_string()));
+        assert_eq!(trace_from_headers(&headers).instance, None);
+    }
+
+    fn x_death_entry(queue: &str, reason: &str, count: i64) -> AMQPValue {
+        let mut entry = FieldTable::new();
+        entry.insert("queue".to_string(), AMQPValue::LongString(queue.to_string()));
+        entry.insert("reason".to_string(), AMQPValue::LongString(reason.to_string()));
+        entry.insert("count".to_string(), AMQPValue::LongLongInt(count));
+        AMQPValue::FieldTable(entry)
+    }
+
+    fn delivery(x_death: Vec<AMQPValue>) -> Delivery {
+        let mut delivery = Delivery::new(1, String::new(), "btc_transactions".to_string(), false);
+        if !x_death.is_empty() {
+            let mut headers = FieldTable::new();
+            headers.insert("x-death".to_string(), AMQPValue::FieldArray(x_death));
+            delivery.properties = BasicProperties::default().with_headers(headers);
+        }
+        delivery
+    }
+
+    #[test]
+    fn test_retries_count() {
+        let mut headers = FieldTable::new();
+        assert_eq!(retries_count(&headers, "btc_transactions_retry"), 0);
+        headers.insert(
+            "x-death".to_string(),
+            AMQPValue::FieldArray(vec![
+                x_death_entry("btc_transactions_retry", "expired", 2),
+                x_death_entry("btc_transactions", "rejected", 3),
+                x_death_entry("eth_transactions_retry", "expired", 4),
+            ]),
+        );
+        // only expirations in retry queue of the currency are attempts
+        assert_eq!(retries_count(&headers, "btc_transactions_retry"), 2);
+    }
+
+    #[test]
+    fn test_failed_delivery_is_quarantined_after_max_attempts() {
+        let topology = Topology::new(RabbitTopology {
+            durable: true,
+            lazy_queues: false,
+            user_message_ttl_secs: None,
+            retry_delay_ms: 1000,
+            names: None,
+        });
+        let retry_queue_name = topology.currency_retry_queue_name(Currency::Btc);
+        let max_delivery_attempts = 5;
+        let first = delivery(vec![]);
+        assert_eq!(delivery_attempts(&first, &retry_queue_name), 1);
+        assert_eq!(
+            FailedDeliveryAction::new(delivery_attempts(&first, &retry_queue_name), max_delivery_attempts),
+            FailedDeliveryAction::Retry
+        );
+        let retried = delivery(vec![x_death_entry("btc_transactions_retry", "expired", 3)]);
+        assert_eq!(delivery_attempts(&retried, &retry_queue_name), 4);
+        assert_eq!(
+            FailedDeliveryAction::new(delivery_attempts(&retried, &retry_queue_name), max_delivery_attempts),
+            FailedDeliveryAction::Retry
+        );
+        let last = delivery(vec![x_death_entry("btc_transactions_retry", "expired", 4)]);
+        assert_eq!(delivery_attempts(&last, &retry_queue_name), 5);
+        assert_eq!(
+            FailedDeliveryAction::new(delivery_attempts(&last, &retry_queue_name), max_delivery_attempts),
+            FailedDeliveryAction::Quarantine
+        );
+    }
+}
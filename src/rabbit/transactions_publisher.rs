@@ -1,81 +1,325 @@
-use std::sync::Arc;
+use std::collections::{HashSet, VecDeque};
+use std::env;
+use std::sync::{Arc, Mutex};
 
 use futures::future;
-use lapin_futures::channel::{Channel, ExchangeDeclareOptions, QueueDeclareOptions};
-use lapin_futures::error::Error as LapinError;
+use lapin_futures::types::{AMQPValue, FieldTable};
 use serde_json;
-use tokio::net::tcp::TcpStream;
 
 use super::error::*;
+use super::r2d2::{ManageRabbitConnection, RabbitConnectionManager};
+use super::topology::{Topology, UserQueueKind};
+use config::Rabbit;
 use models::*;
 use prelude::*;
+use request_id::{self, INSTANCE_HEADER, REQUEST_ID_HEADER, TRANSACTION_GID_HEADER};
+
+/// Instance name sent with published messages, unless `rabbit.instance_name` or `HOSTNAME` is set
+pub const DEFAULT_INSTANCE_NAME: &str = "transactions";
+/// Users with declared queues are remembered up to that number, unless `rabbit.user_queues_cache_size` is set
+pub const DEFAULT_USER_QUEUES_CACHE_SIZE: usize = 10_000;
 
 pub trait TransactionPublisher: Send + Sync + 'static {
     fn publish(&self, tx: TransactionOut) -> Box<Future<Item = (), Error = Error> + Send>;
+    /// Deposits are published on their own routing key in addition to transactions
+    fn publish_deposit(&self, deposit: DepositEvent) -> Box<Future<Item = (), Error = Error> + Send>;
+    /// Declares queues of the user and binds them to exchange, unless it's known to be done already.
+    /// Publishing does it as well, so that messages are not dropped by exchange for lack of queue
+    fn provision_user(&self, user_id: UserId) -> Box<Future<Item = (), Error = Error> + Send>;
+}
+
+/// Users, whose queues were declared on the connection of `generation`. The oldest ones
+/// are forgotten once it's full, so they are declared again on their next publish
+#[derive(Debug)]
+struct ProvisionedUsers {
+    capacity: usize,
+    generation: usize,
+    order: VecDeque<UserId>,
+    users: HashSet<UserId>,
+}
+
+impl ProvisionedUsers {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            generation: 0,
+            order: VecDeque::new(),
+            users: HashSet::new(),
+        }
+    }
+
+    fn contains(&mut self, user_id: UserId, generation: usize) -> bool {
+        self.reset_if_stale(generation);
+        self.users.contains(&user_id)
+    }
+
+    fn insert(&mut self, user_id: UserId, generation: usize) {
+        self.reset_if_stale(generation);
+        if self.capacity == 0 || !self.users.insert(user_id) {
+            return;
+        }
+        self.order.push_back(user_id);
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.users.remove(&oldest);
+            }
+        }
+    }
+
+    // queues that are not durable are gone if the broker was restarted, so after reconnect they are declared again
+    fn reset_if_stale(&mut self, generation: usize) {
+        if self.generation != generation {
+            self.generation = generation;
+            self.order.clear();
+            self.users.clear();
+        }
+    }
+}
+
+/// Channel of the current connection, shared by publishes. It's replaced once the connection
+/// is reestablished or the channel is invalidated, since rabbit closes the channel on errors
+#[derive(Clone)]
+struct ChannelCache<M: ManageRabbitConnection> {
+    rabbit_pool: M,
+    // channel together with generation of the connection it was created on
+    channel: Arc<Mutex<Option<(usize, M::Channel)>>>,
+}
+
+impl<M: ManageRabbitConnection> ChannelCache<M> {
+    fn new(rabbit_pool: M) -> Self {
+        Self {
+            rabbit_pool,
+            channel: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    fn get(&self) -> Result<M::Channel, Error> {
+        if self.rabbit_pool.has_broken() {
+            return Err(ectx!(err ErrorContext::ConnectionBroken, ErrorKind::Internal));
+        }
+        let generation = self.rabbit_pool.generation();
+        let mut cached = self.channel.lock().unwrap();
+        if let Some((cached_generation, ref channel)) = *cached {
+            if cached_generation == generation {
+                return Ok(channel.clone());
+            }
+        }
+        let channel = self.rabbit_pool.get_channel()?;
+        *cached = Some((generation, channel.clone()));
+        Ok(channel)
+    }
+
+    /// Next `get` opens a new channel
+    fn invalidate(&self) {
+        *self.channel.lock().unwrap() = None;
+    }
+
+    fn generation(&self) -> usize {
+        self.rabbit_pool.generation()
+    }
 }
 
 #[derive(Clone)]
 pub struct TransactionPublisherImpl {
-    channel: Arc<Channel<TcpStream>>,
+    channels: ChannelCache<RabbitConnectionManager>,
+    topology: Topology,
+    instance_name: String,
+    provisioned_users: Arc<Mutex<ProvisionedUsers>>,
 }
 
 impl TransactionPublisherImpl {
-    pub fn init(channel: Arc<Channel<TcpStream>>, users: Vec<UserId>) -> impl Future<Item = Self, Error = Error> + Send {
-        let mut f = vec![];
-        let f1: Box<Future<Item = (), Error = LapinError> + Send> = Box::new(channel.exchange_declare(
-            "transactions",
-            "direct",
-            ExchangeDeclareOptions {
-                durable: true,
-                ..Default::default()
-            },
-            Default::default(),
-        ));
-        f.push(f1);
-        for user in users {
-            let queue_name = format!("transactions_{}", user);
-            let f2: Box<Future<Item = (), Error = LapinError> + Send> = Box::new(
-                channel
-                    .queue_declare(
-                        &queue_name,
-                        QueueDeclareOptions {
-                            durable: true,
-                            ..Default::default()
-                        },
-                        Default::default(),
-                    )
-                    .map(|_| ()),
-            );
-            f.push(f2);
-            let f3: Box<Future<Item = (), Error = LapinError> + Send> =
-                Box::new(channel.queue_bind(&queue_name, "transactions", &queue_name, Default::default(), Default::default()));
-            f.push(f3);
+    /// Exchange of the service is expected to be declared with `topology` already.
+    /// Queues of users are declared on demand
+    pub fn new(rabbit_pool: RabbitConnectionManager, topology: Topology, options: &Rabbit) -> Self {
+        let cache_size = options.user_queues_cache_size.unwrap_or(DEFAULT_USER_QUEUES_CACHE_SIZE);
+        Self {
+            channels: ChannelCache::new(rabbit_pool),
+            topology,
+            instance_name: instance_name(options),
+            provisioned_users: Arc::new(Mutex::new(ProvisionedUsers::new(cache_size))),
         }
-        future::join_all(f)
-            .map(|_| Self { channel })
-            .map_err(ectx!(ErrorSource::Lapin, ErrorKind::Internal))
+    }
+
+    fn publish_payload(&self, routing_key: String, payload: Vec<u8>, gid: TransactionId) -> Box<Future<Item = (), Error = Error> + Send> {
+        let channel = match self.channels.get() {
+            Ok(channel) => channel,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let channels = self.channels.clone();
+        let exchange_name = self.topology.exchange_name().to_string();
+        let request_id = request_id::current();
+        let headers = trace_headers(request_id.clone(), &self.instance_name, gid);
+        let properties = self.topology.message_properties().with_headers(headers);
+        let properties = match request_id {
+            Some(request_id) => properties.with_correlation_id(request_id),
+            None => properties,
+        };
+        Box::new(
+            channel
+                .basic_publish(&exchange_name, &routing_key, payload, Default::default(), properties)
+                .map_err(move |e| {
+                    channels.invalidate();
+                    ectx!(err e, ErrorSource::Lapin, ErrorKind::Internal)
+                })
+                .map(|_| ()),
+        )
     }
 }
 
 impl TransactionPublisher for TransactionPublisherImpl {
     fn publish(&self, tx: TransactionOut) -> Box<Future<Item = (), Error = Error> + Send> {
-        let channel = self.channel.clone();
-        let routing_key = format!("transactions_{}", tx.user_id);
+        let routing_key = self.topology.user_queue_name(UserQueueKind::Transactions, tx.user_id);
         let payload = serde_json::to_string(&tx).unwrap().into_bytes();
+        let self_clone = self.clone();
         Box::new(
-            channel
-                .basic_publish("transactions", &routing_key, payload, Default::default(), Default::default())
-                .map_err(ectx!(ErrorSource::Lapin, ErrorKind::Internal))
-                .map(|_| ()),
+            self.provision_user(tx.user_id)
+                .and_then(move |_| self_clone.publish_payload(routing_key, payload, tx.id)),
+        )
+    }
+
+    fn publish_deposit(&self, deposit: DepositEvent) -> Box<Future<Item = (), Error = Error> + Send> {
+        let routing_key = self.topology.user_queue_name(UserQueueKind::Deposits, deposit.user_id);
+        let payload = serde_json::to_string(&deposit).unwrap().into_bytes();
+        let self_clone = self.clone();
+        Box::new(
+            self.provision_user(deposit.user_id)
+                .and_then(move |_| self_clone.publish_payload(routing_key, payload, deposit.id)),
+        )
+    }
+
+    fn provision_user(&self, user_id: UserId) -> Box<Future<Item = (), Error = Error> + Send> {
+        let generation = self.channels.generation();
+        if self.provisioned_users.lock().unwrap().contains(user_id, generation) {
+            return Box::new(future::ok(()));
+        }
+        let channel = match self.channels.get() {
+            Ok(channel) => channel,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let channels = self.channels.clone();
+        let provisioned_users = self.provisioned_users.clone();
+        Box::new(
+            self.topology
+                .declare_user_queues(&channel, vec![user_id])
+                .map(move |_| provisioned_users.lock().unwrap().insert(user_id, generation))
+                .map_err(move |e| {
+                    channels.invalidate();
+                    ectx!(err e, ErrorContext::UserQueues, ErrorKind::Internal => user_id)
+                }),
         )
     }
 }
 
+pub fn instance_name(options: &Rabbit) -> String {
+    options
+        .instance_name
+        .clone()
+        .or_else(|| env::var("HOSTNAME").ok().filter(|hostname| !hostname.is_empty()))
+        .unwrap_or_else(|| DEFAULT_INSTANCE_NAME.to_string())
+}
+
+/// Headers, that let a message reported missing by its receiver be traced back to the request,
+/// the instance and the transaction group it was published for. Read back with `trace_from_headers`
+pub fn trace_headers(request_id: Option<String>, instance_name: &str, gid: TransactionId) -> FieldTable {
+    let mut headers = FieldTable::new();
+    if let Some(request_id) = request_id {
+        headers.insert(REQUEST_ID_HEADER.to_string(), AMQPValue::LongString(request_id));
+    }
+    headers.insert(INSTANCE_HEADER.to_string(), AMQPValue::LongString(instance_name.to_string()));
+    headers.insert(TRANSACTION_GID_HEADER.to_string(), AMQPValue::LongString(gid.to_string()));
+    headers
+}
+
+/// Remembers published deposits, so that tests can check them
 #[derive(Clone, Default)]
-pub struct TransactionPublisherMock;
+pub struct TransactionPublisherMock {
+    pub deposits: Arc<Mutex<Vec<DepositEvent>>>,
+}
 
 impl TransactionPublisher for TransactionPublisherMock {
     fn publish(&self, _tx: TransactionOut) -> Box<Future<Item = (), Error = Error> + Send> {
         Box::new(future::ok(()))
     }
+    fn publish_deposit(&self, deposit: DepositEvent) -> Box<Future<Item = (), Error = Error> + Send> {
+        self.deposits.lock().unwrap().push(deposit);
+        Box::new(future::ok(()))
+    }
+    fn provision_user(&self, _user_id: UserId) -> Box<Future<Item = (), Error = Error> + Send> {
+        Box::new(future::ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    use super::*;
+
+    // channels are numbered in order of creation
+    #[derive(Clone, Default)]
+    struct RabbitConnectionManagerMock {
+        generation: Arc<AtomicUsize>,
+        broken: Arc<AtomicBool>,
+        channels_count: Arc<AtomicUsize>,
+    }
+
+    impl ManageRabbitConnection for RabbitConnectionManagerMock {
+        type Channel = usize;
+
+        fn get_channel(&self) -> Result<usize, Error> {
+            Ok(self.channels_count.fetch_add(1, Ordering::SeqCst) + 1)
+        }
+
+        fn generation(&self) -> usize {
+            self.generation.load(Ordering::SeqCst)
+        }
+
+        fn has_broken(&self) -> bool {
+            self.broken.load(Ordering::SeqCst)
+        }
+
+        fn is_valid(&self) -> Box<Future<Item = (), Error = Error> + Send> {
+            Box::new(future::ok(()))
+        }
+    }
+
+    #[test]
+    fn test_channel_renewal() {
+        let rabbit_pool = RabbitConnectionManagerMock::default();
+        let channels = ChannelCache::new(rabbit_pool.clone());
+        assert_eq!(channels.get().unwrap(), 1);
+        assert_eq!(channels.get().unwrap(), 1);
+        // failed publish closes the channel
+        channels.invalidate();
+        assert_eq!(channels.get().unwrap(), 2);
+        // channels of the old connection are not usable after reconnect
+        rabbit_pool.generation.fetch_add(1, Ordering::SeqCst);
+        assert_eq!(channels.get().unwrap(), 3);
+        assert_eq!(channels.get().unwrap(), 3);
+        // no channel is opened on the dead connection
+        rabbit_pool.broken.store(true, Ordering::SeqCst);
+        assert!(channels.get().is_err());
+        assert_eq!(rabbit_pool.channels_count.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_provisioned_users() {
+        let mut provisioned = ProvisionedUsers::new(2);
+        let users: Vec<_> = (0..3).map(|_| UserId::generate()).collect();
+        assert!(!provisioned.contains(users[0], 0));
+        provisioned.insert(users[0], 0);
+        provisioned.insert(users[1], 0);
+        provisioned.insert(users[1], 0);
+        assert!(provisioned.contains(users[0], 0));
+        // the oldest one is forgotten
+        provisioned.insert(users[2], 0);
+        assert!(!provisioned.contains(users[0], 0));
+        assert!(provisioned.contains(users[1], 0));
+        assert!(provisioned.contains(users[2], 0));
+        // queues are declared again on the new connection
+        assert!(!provisioned.contains(users[2], 1));
+        provisioned.insert(users[2], 1);
+        assert!(provisioned.contains(users[2], 1));
+        assert!(!provisioned.contains(users[1], 1));
+    }
 }
@@ -1,8 +1,10 @@
 use std::collections::HashMap;
 
 use diesel;
+use diesel::dsl::sql;
+use diesel::pg::Pg;
 use diesel::sql_query;
-use diesel::sql_types::{BigInt, VarChar};
+use diesel::sql_types::{BigInt, Bool, Jsonb, VarChar};
 
 use super::error::*;
 use super::executor::with_tls_connection;
@@ -10,17 +12,46 @@ use super::*;
 use models::*;
 use prelude::*;
 use schema::accounts::dsl::*;
+use schema::expired_addresses::dsl as ExpiredAddresses;
 
 pub trait AccountsRepo: Send + Sync + 'static {
     fn create(&self, payload: NewAccount) -> RepoResult<Account>;
     fn count_by_user(&self) -> RepoResult<HashMap<String, u64>>;
     fn get(&self, account_id: AccountId) -> RepoResult<Option<Account>>;
     fn update(&self, account_id: AccountId, payload: UpdateAccount) -> RepoResult<Account>;
-    fn delete(&self, account_id: AccountId) -> RepoResult<Account>;
-    fn list_for_user(&self, user_id_arg: UserId, offset: i64, limit: i64) -> RepoResult<Vec<Account>>;
-    fn get_by_address(&self, address_: BlockchainAddress, currency: Currency, kind_: AccountKind) -> RepoResult<Option<Account>>;
-    fn filter_by_address(&self, address_: BlockchainAddress) -> RepoResult<Vec<Account>>;
-    fn get_by_addresses(&self, addresses: &[BlockchainAddress], currency_: Currency, kind_: AccountKind) -> RepoResult<Vec<Account>>;
+    fn archive(&self, account_id: AccountId) -> RepoResult<Account>;
+    fn list_for_user(&self, user_id_arg: UserId, offset: i64, limit: i64, filter: AccountsFilter) -> RepoResult<Vec<Account>>;
+    fn get_by_address(
+        &self,
+        address_: BlockchainAddress,
+        currency: Currency,
+        kind_: AccountKind,
+        archived_: ArchivedAccounts,
+    ) -> RepoResult<Option<Account>>;
+    fn filter_by_address(&self, address_: BlockchainAddress, archived_: ArchivedAccounts) -> RepoResult<Vec<Account>>;
+    fn get_by_addresses(
+        &self,
+        addresses: &[BlockchainAddress],
+        currency_: Currency,
+        kind_: AccountKind,
+        archived_: ArchivedAccounts,
+    ) -> RepoResult<Vec<Account>>;
+    /// Replaces address of the account with `new_address`, the current one is kept in expired addresses
+    fn rotate_address(&self, account_id: AccountId, new_address: BlockchainAddress) -> RepoResult<Account>;
+    /// Replaces address of the account without keeping the current one, for debit accounts, whose address
+    /// is taken over by another account. The new address is not approved for erc-20 transfers yet
+    fn set_address(&self, account_id: AccountId, new_address: BlockchainAddress) -> RepoResult<Account>;
+    /// Account that had this address before rotation
+    fn get_by_expired_address(&self, address_: BlockchainAddress, currency_: Currency) -> RepoResult<Option<Account>>;
+    /// Newest first
+    fn list_expired_addresses(&self, account_id: AccountId) -> RepoResult<Vec<ExpiredAddress>>;
+    fn get_system_account(&self, role: SystemAccountKind, currency_: Currency, kind_: AccountKind) -> RepoResult<Option<Account>>;
+    /// Accounts with roles, by currency and role, credit account first
+    fn list_system_accounts(&self) -> RepoResult<Vec<Account>>;
+    /// `None` takes the role away, e.g. to give it to another account
+    fn set_system_role(&self, account_id: AccountId, role: Option<SystemAccountKind>) -> RepoResult<Account>;
+    /// Marks hot accounts that a withdrawal was just sent from, so that the next ones are sent from others
+    fn mark_spent(&self, account_ids: &[AccountId]) -> RepoResult<()>;
 }
 
 #[derive(Clone, Default)]
@@ -36,7 +67,7 @@ struct CountByUserQuery {
 
 impl<'a> AccountsRepo for AccountsRepoImpl {
     fn create(&self, payload: NewAccount) -> RepoResult<Account> {
-        with_tls_connection(|conn| {
+        with_tls_connection("accounts.create", |conn| {
             diesel::insert_into(accounts)
                 .values(payload.clone())
                 .get_result::<Account>(conn)
@@ -47,7 +78,7 @@ impl<'a> AccountsRepo for AccountsRepoImpl {
         })
     }
     fn count_by_user(&self) -> RepoResult<HashMap<String, u64>> {
-        with_tls_connection(|conn| {
+        with_tls_connection("accounts.count_by_user", |conn| {
             let counts: Vec<CountByUserQuery> =
                 sql_query(
                 "SELECT users.name, counts.count FROM (SELECT user_id, count(*) FROM accounts where kind='cr' GROUP BY user_id) AS counts INNER JOIN users ON counts.user_id = users.id")
@@ -64,7 +95,7 @@ impl<'a> AccountsRepo for AccountsRepoImpl {
         })
     }
     fn get(&self, account_id_arg: AccountId) -> RepoResult<Option<Account>> {
-        with_tls_connection(|conn| {
+        with_tls_connection("accounts.get", |conn| {
             accounts
                 .filter(id.eq(account_id_arg))
                 .limit(1)
@@ -77,7 +108,7 @@ impl<'a> AccountsRepo for AccountsRepoImpl {
         })
     }
     fn update(&self, account_id_arg: AccountId, payload: UpdateAccount) -> RepoResult<Account> {
-        with_tls_connection(|conn| {
+        with_tls_connection("accounts.update", |conn| {
             let f = accounts.filter(id.eq(account_id_arg));
             diesel::update(f).set(payload.clone()).get_result(conn).map_err(move |e| {
                 let error_kind = ErrorKind::from(&e);
@@ -85,63 +116,215 @@ impl<'a> AccountsRepo for AccountsRepoImpl {
             })
         })
     }
-    fn delete(&self, account_id_arg: AccountId) -> RepoResult<Account> {
-        with_tls_connection(|conn| {
+    fn archive(&self, account_id_arg: AccountId) -> RepoResult<Account> {
+        with_tls_connection("accounts.archive", |conn| {
+            // accounts are referenced by transactions, so they are never deleted
             let filtered = accounts.filter(id.eq(account_id_arg));
-            diesel::delete(filtered).get_result(conn).map_err(move |e| {
+            diesel::update(filtered).set(archived.eq(true)).get_result(conn).map_err(move |e| {
                 let error_kind = ErrorKind::from(&e);
                 ectx!(err e, error_kind => account_id_arg)
             })
         })
     }
-    fn list_for_user(&self, user_id_arg: UserId, offset: i64, limit: i64) -> RepoResult<Vec<Account>> {
-        with_tls_connection(|conn| {
-            let query = accounts
+    fn list_for_user(&self, user_id_arg: UserId, offset: i64, limit: i64, filter: AccountsFilter) -> RepoResult<Vec<Account>> {
+        with_tls_connection("accounts.list_for_user", |conn| {
+            let mut query = accounts
                 .filter(user_id.eq(user_id_arg))
                 .filter(kind.eq(AccountKind::Cr))
-                .order(id)
-                .offset(offset)
-                .limit(limit);
-            query.get_results(conn).map_err(move |e| {
+                .filter(archived.eq(false))
+                .into_boxed::<Pg>();
+            if !filter.labels.is_empty() {
+                query = query.filter(labels.contains(filter.labels.clone()));
+            }
+            if let Some(ref meta_) = filter.meta {
+                // diesel has no jsonb operators, so containment is written in sql
+                query = query.filter(sql::<Bool>("meta @> ").bind::<Jsonb, _>(meta_.clone()));
+            }
+            query.order(id).offset(offset).limit(limit).get_results(conn).map_err(move |e| {
                 let error_kind = ErrorKind::from(&e);
-                ectx!(err e, error_kind => user_id_arg, offset, limit)
+                ectx!(err e, error_kind => user_id_arg, offset, limit, filter)
             })
         })
     }
-    fn get_by_address(&self, address_: BlockchainAddress, currency_: Currency, kind_: AccountKind) -> RepoResult<Option<Account>> {
-        with_tls_connection(|conn| {
-            accounts
+    fn get_by_address(
+        &self,
+        address_: BlockchainAddress,
+        currency_: Currency,
+        kind_: AccountKind,
+        archived_: ArchivedAccounts,
+    ) -> RepoResult<Option<Account>> {
+        with_tls_connection("accounts.get_by_address", |conn| {
+            let mut query = accounts
                 .filter(address.eq(address_.clone()))
                 .filter(kind.eq(kind_))
                 .filter(currency.eq(currency_))
+                .into_boxed::<Pg>();
+            if archived_ == ArchivedAccounts::Exclude {
+                query = query.filter(archived.eq(false));
+            }
+            query.get_result(conn).optional().map_err(move |e| {
+                let error_kind = ErrorKind::from(&e);
+                ectx!(err e, error_kind => address_, kind_, archived_)
+            })
+        })
+    }
+
+    fn filter_by_address(&self, address_: BlockchainAddress, archived_: ArchivedAccounts) -> RepoResult<Vec<Account>> {
+        with_tls_connection("accounts.filter_by_address", |conn| {
+            let mut query = accounts.filter(address.eq(address_.clone())).into_boxed::<Pg>();
+            if archived_ == ArchivedAccounts::Exclude {
+                query = query.filter(archived.eq(false));
+            }
+            query.get_results(conn).map_err(move |e| {
+                let error_kind = ErrorKind::from(&e);
+                ectx!(err e, error_kind => address_, archived_)
+            })
+        })
+    }
+
+    fn get_by_addresses(
+        &self,
+        addresses: &[BlockchainAddress],
+        currency_: Currency,
+        kind_: AccountKind,
+        archived_: ArchivedAccounts,
+    ) -> RepoResult<Vec<Account>> {
+        with_tls_connection("accounts.get_by_addresses", |conn| {
+            let mut query = accounts
+                .filter(address.eq_any(addresses))
+                .filter(kind.eq(kind_))
+                .filter(currency.eq(currency_))
+                .into_boxed::<Pg>();
+            if archived_ == ArchivedAccounts::Exclude {
+                query = query.filter(archived.eq(false));
+            }
+            query.get_results(conn).map_err(move |e| {
+                let error_kind = ErrorKind::from(&e);
+                ectx!(err e, error_kind => addresses, kind_, archived_)
+            })
+        })
+    }
+
+    fn rotate_address(&self, account_id_arg: AccountId, new_address: BlockchainAddress) -> RepoResult<Account> {
+        with_tls_connection("accounts.rotate_address", |conn| {
+            let account: Account = accounts.filter(id.eq(account_id_arg)).get_result(conn).map_err(move |e| {
+                let error_kind = ErrorKind::from(&e);
+                ectx!(try err e, error_kind => account_id_arg)
+            })?;
+            let expired = NewExpiredAddress {
+                address: account.address,
+                currency: account.currency,
+                account_id: account.id,
+            };
+            diesel::insert_into(ExpiredAddresses::expired_addresses)
+                .values(expired.clone())
+                .execute(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(try err e, error_kind => expired)
+                })?;
+            let filtered = accounts.filter(id.eq(account_id_arg));
+            diesel::update(filtered)
+                .set(address.eq(new_address.clone()))
+                .get_result(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => account_id_arg, new_address)
+                })
+        })
+    }
+
+    fn set_address(&self, account_id_arg: AccountId, new_address: BlockchainAddress) -> RepoResult<Account> {
+        with_tls_connection("accounts.set_address", |conn| {
+            diesel::update(accounts.filter(id.eq(account_id_arg)))
+                .set((address.eq(new_address.clone()), erc20_approved.eq(false)))
+                .get_result(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => account_id_arg, new_address)
+                })
+        })
+    }
+
+    fn get_by_expired_address(&self, address_: BlockchainAddress, currency_: Currency) -> RepoResult<Option<Account>> {
+        with_tls_connection("accounts.get_by_expired_address", |conn| {
+            accounts
+                .inner_join(ExpiredAddresses::expired_addresses)
+                .filter(ExpiredAddresses::address.eq(address_.clone()))
+                .filter(ExpiredAddresses::currency.eq(currency_))
+                .select(schema::accounts::all_columns)
                 .get_result(conn)
                 .optional()
                 .map_err(move |e| {
                     let error_kind = ErrorKind::from(&e);
-                    ectx!(err e, error_kind => address_, kind_)
+                    ectx!(err e, error_kind => address_, currency_)
                 })
         })
     }
 
-    fn filter_by_address(&self, address_: BlockchainAddress) -> RepoResult<Vec<Account>> {
-        with_tls_connection(|conn| {
-            accounts.filter(address.eq(address_.clone())).get_results(conn).map_err(move |e| {
-                let error_kind = ErrorKind::from(&e);
-                ectx!(err e, error_kind => address_)
-            })
+    fn list_expired_addresses(&self, account_id_arg: AccountId) -> RepoResult<Vec<ExpiredAddress>> {
+        with_tls_connection("accounts.list_expired_addresses", |conn| {
+            ExpiredAddresses::expired_addresses
+                .filter(ExpiredAddresses::account_id.eq(account_id_arg))
+                .order(ExpiredAddresses::expired_at.desc())
+                .get_results(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => account_id_arg)
+                })
         })
     }
 
-    fn get_by_addresses(&self, addresses: &[BlockchainAddress], currency_: Currency, kind_: AccountKind) -> RepoResult<Vec<Account>> {
-        with_tls_connection(|conn| {
+    fn get_system_account(&self, role: SystemAccountKind, currency_: Currency, kind_: AccountKind) -> RepoResult<Option<Account>> {
+        with_tls_connection("accounts.get_system_account", |conn| {
             accounts
-                .filter(address.eq_any(addresses))
-                .filter(kind.eq(kind_))
+                .filter(system_role.eq(role))
                 .filter(currency.eq(currency_))
+                .filter(kind.eq(kind_))
+                .get_result(conn)
+                .optional()
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => role, currency_, kind_)
+                })
+        })
+    }
+
+    fn list_system_accounts(&self) -> RepoResult<Vec<Account>> {
+        with_tls_connection("accounts.list_system_accounts", |conn| {
+            accounts
+                .filter(system_role.is_not_null())
+                .order((currency, system_role, kind))
                 .get_results(conn)
                 .map_err(move |e| {
                     let error_kind = ErrorKind::from(&e);
-                    ectx!(err e, error_kind => addresses, kind_)
+                    ectx!(err e, error_kind)
+                })
+        })
+    }
+
+    fn set_system_role(&self, account_id_arg: AccountId, role: Option<SystemAccountKind>) -> RepoResult<Account> {
+        with_tls_connection("accounts.set_system_role", |conn| {
+            diesel::update(accounts.filter(id.eq(account_id_arg)))
+                .set(system_role.eq(role))
+                .get_result(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => account_id_arg, role)
+                })
+        })
+    }
+
+    fn mark_spent(&self, account_ids: &[AccountId]) -> RepoResult<()> {
+        with_tls_connection("accounts.mark_spent", |conn| {
+            let ids = account_ids.to_vec();
+            diesel::update(accounts.filter(id.eq_any(ids)))
+                .set(last_spent_at.eq(Some(::chrono::Utc::now().naive_utc())))
+                .execute(conn)
+                .map(|_| ())
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => account_ids)
                 })
         })
     }
@@ -226,7 +409,7 @@ pub mod tests {
     }
 
     #[test]
-    fn accounts_delete() {
+    fn accounts_archive() {
         let mut core = Core::new().unwrap();
         let db_executor = create_executor();
         let accounts_repo = AccountsRepoImpl::default();
@@ -237,9 +420,116 @@ pub mod tests {
             let mut new_account = NewAccount::default();
             new_account.user_id = user.id;
             let account = accounts_repo.create(new_account).unwrap();
-            let res = accounts_repo.delete(account.id);
-            assert!(res.is_ok());
-            res
+            let archived_account = accounts_repo.archive(account.id)?;
+            assert!(archived_account.archived);
+            let listed = accounts_repo.list_for_user(user.id, 0, 10, AccountsFilter::default())?;
+            assert!(listed.is_empty());
+            let res = accounts_repo.get(account.id)?;
+            assert!(res.is_some());
+            Ok::<_, Error>(())
+        }));
+    }
+
+    #[test]
+    fn accounts_mark_spent() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let accounts_repo = AccountsRepoImpl::default();
+        let users_repo = UsersRepoImpl::default();
+        let new_user = NewUser::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let user = users_repo.create(new_user)?;
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            let spent = accounts_repo.create(new_account).unwrap();
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            let other = accounts_repo.create(new_account).unwrap();
+            assert_eq!(spent.last_spent_at, None);
+            accounts_repo.mark_spent(&[spent.id])?;
+            assert!(accounts_repo.get(spent.id)?.unwrap().last_spent_at.is_some());
+            assert_eq!(accounts_repo.get(other.id)?.unwrap().last_spent_at, None);
+            Ok::<_, Error>(())
+        }));
+    }
+    #[test]
+    fn accounts_rotate_address() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let accounts_repo = AccountsRepoImpl::default();
+        let users_repo = UsersRepoImpl::default();
+        let new_user = NewUser::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let user = users_repo.create(new_user)?;
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            let account = accounts_repo.create(new_account)?;
+            let new_address = BlockchainAddress::default();
+            let rotated = accounts_repo.rotate_address(account.id, new_address.clone())?;
+            assert_eq!(rotated.address, new_address);
+            let res = accounts_repo.get_by_expired_address(account.address.clone(), account.currency)?;
+            assert_eq!(res.map(|x| x.id), Some(account.id));
+            let expired = accounts_repo.list_expired_addresses(account.id)?;
+            assert_eq!(expired.iter().map(|x| x.address.clone()).collect::<Vec<_>>(), vec![account.address]);
+            Ok::<_, Error>(())
+        }));
+    }
+    #[test]
+    fn accounts_set_address() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let accounts_repo = AccountsRepoImpl::default();
+        let users_repo = UsersRepoImpl::default();
+        let new_user = NewUser::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let user = users_repo.create(new_user)?;
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            new_account.kind = AccountKind::Dr;
+            let account = accounts_repo.create(new_account)?;
+            let changeset = UpdateAccount {
+                erc20_approved: Some(true),
+                ..Default::default()
+            };
+            accounts_repo.update(account.id, changeset)?;
+            let new_address = BlockchainAddress::default();
+            let updated = accounts_repo.set_address(account.id, new_address.clone())?;
+            assert_eq!(updated.address, new_address);
+            assert!(!updated.erc20_approved);
+            // the former address is not kept
+            assert!(accounts_repo.list_expired_addresses(account.id)?.is_empty());
+            Ok::<_, Error>(())
+        }));
+    }
+    #[test]
+    fn accounts_set_system_role() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let accounts_repo = AccountsRepoImpl::default();
+        let users_repo = UsersRepoImpl::default();
+        let new_user = NewUser::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let user = users_repo.create(new_user)?;
+            // the role might be taken by system account of the database
+            if let Some(existing) = accounts_repo.get_system_account(SystemAccountKind::Transfer, Currency::Btc, AccountKind::Dr)? {
+                accounts_repo.set_system_role(existing.id, None)?;
+            }
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            new_account.currency = Currency::Btc;
+            new_account.kind = AccountKind::Dr;
+            let account = accounts_repo.create(new_account)?;
+            let updated = accounts_repo.set_system_role(account.id, Some(SystemAccountKind::Transfer))?;
+            assert_eq!(updated.system_role, Some(SystemAccountKind::Transfer));
+            let system_account = accounts_repo.get_system_account(SystemAccountKind::Transfer, Currency::Btc, AccountKind::Dr)?;
+            assert_eq!(system_account.map(|account| account.id), Some(account.id));
+            assert!(accounts_repo.list_system_accounts()?.iter().any(|listed| listed.id == account.id));
+            // credit account of the role is separate
+            assert!(accounts_repo
+                .get_system_account(SystemAccountKind::Transfer, Currency::Btc, AccountKind::Cr)?
+                .map(|other| other.id != account.id)
+                .unwrap_or(true));
+            Ok::<_, Error>(())
         }));
     }
     #[test]
@@ -254,12 +544,45 @@ pub mod tests {
             let mut new_account = NewAccount::default();
             new_account.user_id = user.id;
             let _ = accounts_repo.create(new_account).unwrap();
-            let res = accounts_repo.list_for_user(user.id, 0, 1);
+            let res = accounts_repo.list_for_user(user.id, 0, 1, AccountsFilter::default());
             assert!(res.is_ok());
             res
         }));
     }
     #[test]
+    fn accounts_list_filtered() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let accounts_repo = AccountsRepoImpl::default();
+        let users_repo = UsersRepoImpl::default();
+        let new_user = NewUser::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let user = users_repo.create(new_user)?;
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            new_account.labels = Some(vec!["shop".to_string(), "vip".to_string()]);
+            new_account.meta = Some(json!({"orderId": "42", "customerId": "7"}));
+            let labeled = accounts_repo.create(new_account).unwrap();
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            let _ = accounts_repo.create(new_account).unwrap();
+
+            let filter = AccountsFilter {
+                labels: vec!["vip".to_string()],
+                meta: Some(json!({"orderId": "42"})),
+            };
+            let res = accounts_repo.list_for_user(user.id, 0, 10, filter)?;
+            assert_eq!(res, vec![labeled]);
+            let filter = AccountsFilter {
+                labels: vec![],
+                meta: Some(json!({"orderId": "43"})),
+            };
+            let res = accounts_repo.list_for_user(user.id, 0, 10, filter);
+            assert!(res.as_ref().unwrap().is_empty());
+            res
+        }));
+    }
+    #[test]
     fn accounts_get_by_address() {
         let mut core = Core::new().unwrap();
         let db_executor = create_executor();
@@ -271,8 +594,23 @@ pub mod tests {
             let mut new_account = NewAccount::default();
             new_account.user_id = user.id;
             let account = accounts_repo.create(new_account).unwrap();
-            let res = accounts_repo.get_by_address(account.address, account.currency, AccountKind::Cr);
+            let res = accounts_repo.get_by_address(
+                account.address.clone(),
+                account.currency,
+                AccountKind::Cr,
+                ArchivedAccounts::Include,
+            );
             assert!(res.is_ok());
+            accounts_repo.archive(account.id)?;
+            let archived_account = accounts_repo.get_by_address(
+                account.address.clone(),
+                account.currency,
+                AccountKind::Cr,
+                ArchivedAccounts::Include,
+            )?;
+            assert_eq!(archived_account.map(|account| account.id), Some(account.id));
+            let excluded = accounts_repo.get_by_address(account.address, account.currency, AccountKind::Cr, ArchivedAccounts::Exclude)?;
+            assert!(excluded.is_none());
             res
         }));
     }
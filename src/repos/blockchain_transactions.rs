@@ -9,6 +9,8 @@ use schema::blockchain_transactions::dsl::*;
 
 pub trait BlockchainTransactionsRepo: Send + Sync + 'static {
     fn create(&self, payload: NewBlockchainTransactionDB) -> RepoResult<BlockchainTransactionDB>;
+    /// Creates several transactions with one insert
+    fn create_batch(&self, payload: Vec<NewBlockchainTransactionDB>) -> RepoResult<Vec<BlockchainTransactionDB>>;
     fn upsert(&self, payload: NewBlockchainTransactionDB) -> RepoResult<BlockchainTransactionDB>;
     fn get(&self, hash_: BlockchainTransactionId) -> RepoResult<Option<BlockchainTransactionDB>>;
 }
@@ -18,7 +20,7 @@ pub struct BlockchainTransactionsRepoImpl;
 
 impl BlockchainTransactionsRepo for BlockchainTransactionsRepoImpl {
     fn create(&self, payload: NewBlockchainTransactionDB) -> RepoResult<BlockchainTransactionDB> {
-        with_tls_connection(|conn| {
+        with_tls_connection("blockchain_transactions.create", |conn| {
             diesel::insert_into(blockchain_transactions)
                 .values(payload.clone())
                 .get_result::<BlockchainTransactionDB>(conn)
@@ -28,8 +30,22 @@ impl BlockchainTransactionsRepo for BlockchainTransactionsRepoImpl {
                 })
         })
     }
+    fn create_batch(&self, payload: Vec<NewBlockchainTransactionDB>) -> RepoResult<Vec<BlockchainTransactionDB>> {
+        if payload.is_empty() {
+            return Ok(vec![]);
+        }
+        with_tls_connection("blockchain_transactions.create_batch", |conn| {
+            diesel::insert_into(blockchain_transactions)
+                .values(&payload)
+                .get_results::<BlockchainTransactionDB>(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => payload)
+                })
+        })
+    }
     fn upsert(&self, payload: NewBlockchainTransactionDB) -> RepoResult<BlockchainTransactionDB> {
-        with_tls_connection(|conn| {
+        with_tls_connection("blockchain_transactions.upsert", |conn| {
             diesel::insert_into(blockchain_transactions)
                 .values(payload.clone())
                 .on_conflict(hash)
@@ -45,7 +61,7 @@ impl BlockchainTransactionsRepo for BlockchainTransactionsRepoImpl {
     }
 
     fn get(&self, hash_: BlockchainTransactionId) -> RepoResult<Option<BlockchainTransactionDB>> {
-        with_tls_connection(|conn| {
+        with_tls_connection("blockchain_transactions.get", |conn| {
             blockchain_transactions
                 .filter(hash.eq(hash_.clone()))
                 .limit(1)
@@ -92,6 +108,42 @@ pub mod tests {
         }));
     }
 
+    #[test]
+    fn blockchain_transactions_create_batch() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let blockchain_transactions_repo = BlockchainTransactionsRepoImpl::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            assert!(blockchain_transactions_repo.create_batch(vec![])?.is_empty());
+            let trans = vec![NewBlockchainTransactionDB::default(), NewBlockchainTransactionDB::default()];
+            let res = blockchain_transactions_repo.create_batch(trans.clone())?;
+            assert_eq!(res.len(), 2);
+            for new_transaction in trans {
+                assert!(blockchain_transactions_repo.get(new_transaction.hash)?.is_some());
+            }
+            Ok(())
+        }));
+    }
+
+    #[test]
+    fn blockchain_transactions_create_in_failed_savepoint() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let db_executor_clone = db_executor.clone();
+        let blockchain_transactions_repo = BlockchainTransactionsRepoImpl::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let rolled_back = NewBlockchainTransactionDB::default();
+            let res = db_executor_clone.savepoint(|| -> RepoResult<()> {
+                blockchain_transactions_repo.create(rolled_back.clone())?;
+                Err(ErrorKind::Internal.into())
+            });
+            assert!(res.is_err());
+            // the transaction goes on after the savepoint is rolled back
+            assert!(blockchain_transactions_repo.get(rolled_back.hash)?.is_none());
+            blockchain_transactions_repo.create(NewBlockchainTransactionDB::default())
+        }));
+    }
+
     #[test]
     fn blockchain_transactions_read() {
         let mut core = Core::new().unwrap();
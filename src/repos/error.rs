@@ -3,7 +3,7 @@ use std::fmt::Display;
 
 use diesel::result::{DatabaseErrorKind, Error as DieselError};
 use failure::{Backtrace, Context, Fail};
-use validator::{ValidationError, ValidationErrors};
+use validator::ValidationErrors;
 
 #[derive(Debug)]
 pub struct Error {
@@ -21,6 +21,8 @@ pub enum ErrorKind {
     Internal,
     #[fail(display = "repo error - already in transaction")]
     AlreadyInTransaction,
+    #[fail(display = "repo error - conflict with existing data or concurrent modification")]
+    Conflict,
 }
 
 #[allow(dead_code)]
@@ -45,6 +47,10 @@ pub enum ErrorContext {
     AccountsPair,
     #[fail(display = "repo context - insufficient funds for withdrawal in the system")]
     InsufficientWithdrawalFunds,
+    #[fail(display = "repo context - account balance became negative")]
+    NegativeBalance,
+    #[fail(display = "repo context - value was changed since it was read")]
+    StaleVersion,
 }
 
 derive_error_impls!();
@@ -52,16 +58,8 @@ derive_error_impls!();
 impl<'a> From<&'a DieselError> for ErrorKind {
     fn from(e: &DieselError) -> Self {
         match e {
-            DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, ref info) => {
-                let mut errors = ValidationErrors::new();
-                let mut error = ValidationError::new("not unique");
-                let message: &str = info.message();
-                let details: &str = info.details().unwrap_or("no details");
-                error.add_param("message".into(), &message);
-                error.add_param("details".into(), &details);
-                errors.add("database", error);
-                ErrorKind::Constraints(errors)
-            }
+            DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => ErrorKind::Conflict,
+            DieselError::DatabaseError(DatabaseErrorKind::SerializationFailure, _) => ErrorKind::Conflict,
             DieselError::AlreadyInTransaction => ErrorKind::AlreadyInTransaction,
             _ => ErrorKind::Internal,
         }
@@ -1,17 +1,28 @@
 use std::cell::RefCell;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use diesel::pg::PgConnection;
-use diesel::result::Error as DieselError;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use futures::future::{self, Either};
 use futures_cpupool::CpuPool;
 
 use super::error::*;
+use super::query_stats::{query_stats_scope, record_repo_call, set_query_attempt, QueryStats, RowCount};
 use prelude::*;
+use request_id;
 use utils::log_error;
 
 thread_local! {
     pub static DB_CONN: RefCell<Option<PgPooledConnection>> = RefCell::new(None)
 }
 
+/// Max number of attempts to run transaction in `execute_transaction_with_retry`
+pub const TRANSACTION_ATTEMPTS: u32 = 3;
+const RETRY_DELAY_MS: u64 = 20;
+const RETRY_JITTER_MS: u64 = 30;
+
 /// Transaction isolation level
 #[allow(dead_code)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -34,6 +45,17 @@ pub trait DbExecutor: Clone + Send + Sync + 'static {
         F: FnOnce() -> Result<T, E> + Send + 'static,
         E: From<Error> + Fail;
 
+    /// Execute queries that don't need the most recent data, e.g. listings. These can be
+    /// served by a read replica, if one is configured
+    fn execute_read_only<F, T, E>(&self, f: F) -> Box<Future<Item = T, Error = E> + Send + 'static>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> Result<T, E> + Send + 'static,
+        E: From<Error> + Fail,
+    {
+        self.execute(f)
+    }
+
     /// Execute mutations and queries inside one transaction
     fn execute_transaction<F, T, E>(&self, f: F) -> Box<Future<Item = T, Error = E> + Send + 'static>
     where
@@ -51,6 +73,25 @@ pub trait DbExecutor: Clone + Send + Sync + 'static {
         F: FnOnce() -> Result<T, E> + Send + 'static,
         E: From<Error> + Fail;
 
+    /// Same as `execute_transaction_with_isolation`, but reruns transaction if it failed
+    /// because of conflict with concurrent transaction. Since `f` may be called several times,
+    /// it must not have side effects except for db queries
+    fn execute_transaction_with_retry<F, T, E>(&self, isolation: Isolation, f: F) -> Box<Future<Item = T, Error = E> + Send + 'static>
+    where
+        T: Send + 'static,
+        F: Fn() -> Result<T, E> + Send + 'static,
+        E: From<Error> + Fail,
+    {
+        self.execute_transaction_with_isolation(isolation, f)
+    }
+
+    /// Runs `f` in a savepoint of the current transaction, so that if it fails, only its statements
+    /// are rolled back and the transaction goes on. Must be called inside of `execute_transaction*` closure
+    fn savepoint<F, T, E>(&self, f: F) -> Result<T, E>
+    where
+        F: FnOnce() -> Result<T, E>,
+        E: From<Error> + Fail;
+
     /// Execute mutations that will be rolled back. This is useful for tests, when you
     /// don't want to pollute your database
     #[cfg(test)]
@@ -65,11 +106,45 @@ pub trait DbExecutor: Clone + Send + Sync + 'static {
 pub struct DbExecutorImpl {
     db_pool: PgPool,
     db_thread_pool: CpuPool,
+    replica: Option<Replica>,
+    query_stats: Option<Arc<QueryStats>>,
+}
+
+/// Read replica pools. Replica has its own thread pool, so that
+/// connections in thread local storage of its threads are always replica connections
+#[derive(Clone)]
+struct Replica {
+    db_pool: PgPool,
+    db_thread_pool: CpuPool,
 }
 
 impl DbExecutorImpl {
     pub fn new(db_pool: PgPool, db_thread_pool: CpuPool) -> Self {
-        Self { db_pool, db_thread_pool }
+        Self {
+            db_pool,
+            db_thread_pool,
+            replica: None,
+            query_stats: None,
+        }
+    }
+
+    /// Route `execute_read_only` queries to read replica
+    pub fn with_replica(self, replica_db_pool: PgPool, replica_db_thread_pool: CpuPool) -> Self {
+        Self {
+            replica: Some(Replica {
+                db_pool: replica_db_pool,
+                db_thread_pool: replica_db_thread_pool,
+            }),
+            ..self
+        }
+    }
+
+    /// Record repo calls made by this executor to `query_stats`
+    pub fn with_query_stats(self, query_stats: Arc<QueryStats>) -> Self {
+        Self {
+            query_stats: Some(query_stats),
+            ..self
+        }
     }
 }
 
@@ -81,17 +156,64 @@ impl DbExecutor for DbExecutorImpl {
         E: From<Error> + Fail,
     {
         let db_pool = self.db_pool.clone();
+        let query_stats = self.query_stats.clone();
+        // pool threads don't know which request they're working for
+        let request_id = request_id::current();
         Box::new(self.db_thread_pool.spawn_fn(move || {
-            DB_CONN.with(move |tls_conn_cell| -> Result<T, E> {
-                put_connection_into_tls(&db_pool, tls_conn_cell)?;
-                f().map_err(move |e| {
-                    remove_connection_from_tls_if_broken(tls_conn_cell);
-                    e
+            query_stats_scope(query_stats, move || {
+                request_id::scope(request_id, move || {
+                    DB_CONN.with(move |tls_conn_cell| -> Result<T, E> {
+                        put_connection_into_tls(&db_pool, tls_conn_cell)?;
+                        f().map_err(move |e| {
+                            remove_connection_from_tls_if_broken(tls_conn_cell);
+                            e
+                        })
+                    })
                 })
             })
         }))
     }
 
+    fn execute_read_only<F, T, E>(&self, f: F) -> Box<Future<Item = T, Error = E> + Send + 'static>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> Result<T, E> + Send + 'static,
+        E: From<Error> + Fail,
+    {
+        let replica = match self.replica {
+            Some(ref replica) => replica.clone(),
+            None => return self.execute(f),
+        };
+        let self_clone = self.clone();
+        let query_stats = self.query_stats.clone();
+        let request_id = request_id::current();
+        Box::new(
+            replica
+                .db_thread_pool
+                .spawn_fn(move || {
+                    query_stats_scope(query_stats, move || {
+                        request_id::scope(request_id, move || {
+                            DB_CONN.with(move |tls_conn_cell| -> Result<Result<T, F>, E> {
+                                // replica is unavailable - giving the closure back to run it on primary
+                                if let Err(e) = put_connection_into_tls(&replica.db_pool, tls_conn_cell) {
+                                    log_error(&e);
+                                    return Ok(Err(f));
+                                }
+                                f().map(Ok).map_err(move |e| {
+                                    remove_connection_from_tls_if_broken(tls_conn_cell);
+                                    e
+                                })
+                            })
+                        })
+                    })
+                })
+                .and_then(move |res| match res {
+                    Ok(value) => Either::A(future::ok(value)),
+                    Err(f) => Either::B(self_clone.execute(f)),
+                }),
+        )
+    }
+
     fn execute_transaction_with_isolation<F, T, E>(&self, isolation: Isolation, f: F) -> Box<Future<Item = T, Error = E> + Send + 'static>
     where
         T: Send + 'static,
@@ -99,43 +221,79 @@ impl DbExecutor for DbExecutorImpl {
         E: From<Error> + Fail,
     {
         let db_pool = self.db_pool.clone();
+        let query_stats = self.query_stats.clone();
+        let request_id = request_id::current();
         Box::new(self.db_thread_pool.spawn_fn(move || {
-            DB_CONN.with(move |tls_conn_cell| -> Result<T, E> {
-                put_connection_into_tls(&db_pool, tls_conn_cell)?;
-                let mut err: Option<E> = None;
-                let res = {
-                    let err_ref = &mut err;
-                    with_tls_connection(move |conn| {
-                        let builder = match isolation {
-                            Isolation::ReadCommitted => conn.build_transaction().read_committed(),
-                            Isolation::RepeatableRead => conn.build_transaction().repeatable_read(),
-                            Isolation::Serializable => conn.build_transaction().serializable(),
-                        };
-                        builder
-                            .run(|| {
-                                f().map_err(|e| {
-                                    *err_ref = Some(e);
-                                    DieselError::RollbackTransaction
-                                })
-                            })
-                            .map_err(|e: DieselError| match e {
-                                DieselError::AlreadyInTransaction => ectx!(err ErrorSource::Diesel, ErrorKind::AlreadyInTransaction),
-                                _ => ectx!(err ErrorSource::Diesel, ErrorKind::Internal),
-                            })
+            query_stats_scope(query_stats, move || {
+                request_id::scope(request_id, move || {
+                    DB_CONN.with(move |tls_conn_cell| -> Result<T, E> {
+                        put_connection_into_tls(&db_pool, tls_conn_cell)?;
+                        run_transaction(tls_conn_cell, isolation, f)
                     })
-                };
-                res.map_err(|e| {
-                    if e.kind() == ErrorKind::AlreadyInTransaction {
-                        rollback_transaction(tls_conn_cell);
-                    }
-                    remove_connection_from_tls_if_broken(tls_conn_cell);
-                    let e: E = err.unwrap_or_else(|| e.into());
-                    e
                 })
             })
         }))
     }
 
+    fn execute_transaction_with_retry<F, T, E>(&self, isolation: Isolation, f: F) -> Box<Future<Item = T, Error = E> + Send + 'static>
+    where
+        T: Send + 'static,
+        F: Fn() -> Result<T, E> + Send + 'static,
+        E: From<Error> + Fail,
+    {
+        let db_pool = self.db_pool.clone();
+        let query_stats = self.query_stats.clone();
+        let request_id = request_id::current();
+        Box::new(self.db_thread_pool.spawn_fn(move || {
+            query_stats_scope(query_stats, move || {
+                request_id::scope(request_id, move || {
+                    DB_CONN.with(move |tls_conn_cell| -> Result<T, E> {
+                        let mut attempt = 1;
+                        loop {
+                            put_connection_into_tls(&db_pool, tls_conn_cell)?;
+                            set_query_attempt(attempt);
+                            match run_transaction(tls_conn_cell, isolation, || f()) {
+                                Err(ref e) if attempt < TRANSACTION_ATTEMPTS && is_serialization_failure(e) => {
+                                    // jitter, so that conflicting transactions are not retried at the same moment again
+                                    let delay = RETRY_DELAY_MS * u64::from(attempt) + thread_rng().gen_range(0, RETRY_JITTER_MS);
+                                    warn!("Transaction conflict at attempt {}, retrying in {} ms", attempt, delay);
+                                    thread::sleep(Duration::from_millis(delay));
+                                    attempt += 1;
+                                }
+                                res => return res,
+                            }
+                        }
+                    })
+                })
+            })
+        }))
+    }
+
+    fn savepoint<F, T, E>(&self, f: F) -> Result<T, E>
+    where
+        F: FnOnce() -> Result<T, E>,
+        E: From<Error> + Fail,
+    {
+        let mut err: Option<E> = None;
+        let res = {
+            let err_ref = &mut err;
+            // nested diesel transaction is a savepoint
+            with_raw_tls_connection(move |conn| {
+                conn.transaction(|| {
+                    f().map_err(|e| {
+                        *err_ref = Some(e);
+                        DieselError::RollbackTransaction
+                    })
+                })
+                .map_err(|e: DieselError| {
+                    let kind = ErrorKind::from(&e);
+                    ectx!(err e, ErrorSource::Diesel, kind)
+                })
+            })
+        };
+        res.map_err(move |e| err.unwrap_or_else(|| e.into()))
+    }
+
     #[cfg(test)]
     fn execute_test_transaction<F, T, E>(&self, f: F) -> Box<Future<Item = T, Error = E> + Send + 'static>
     where
@@ -151,9 +309,73 @@ impl DbExecutor for DbExecutorImpl {
     }
 }
 
+/// Runs `f` inside transaction on connection from thread local storage
+fn run_transaction<F, T, E>(tls_conn_cell: &RefCell<Option<PgPooledConnection>>, isolation: Isolation, f: F) -> Result<T, E>
+where
+    F: FnOnce() -> Result<T, E>,
+    E: From<Error> + Fail,
+{
+    let mut err: Option<E> = None;
+    let res = {
+        let err_ref = &mut err;
+        with_raw_tls_connection(move |conn| {
+            let builder = match isolation {
+                Isolation::ReadCommitted => conn.build_transaction().read_committed(),
+                Isolation::RepeatableRead => conn.build_transaction().repeatable_read(),
+                Isolation::Serializable => conn.build_transaction().serializable(),
+            };
+            builder
+                .run(|| {
+                    f().map_err(|e| {
+                        *err_ref = Some(e);
+                        DieselError::RollbackTransaction
+                    })
+                })
+                .map_err(|e: DieselError| match e {
+                    DieselError::AlreadyInTransaction => ectx!(err ErrorSource::Diesel, ErrorKind::AlreadyInTransaction),
+                    _ => {
+                        // serialization failure may happen on commit as well
+                        let kind = ErrorKind::from(&e);
+                        ectx!(err e, ErrorSource::Diesel, kind)
+                    }
+                })
+        })
+    };
+    res.map_err(|e| {
+        if e.kind() == ErrorKind::AlreadyInTransaction {
+            rollback_transaction(tls_conn_cell);
+        }
+        remove_connection_from_tls_if_broken(tls_conn_cell);
+        let e: E = err.unwrap_or_else(|| e.into());
+        e
+    })
+}
+
+/// Checks if error was caused by serialization failure (SQLSTATE 40001) either
+/// in one of the queries or on commit, so that transaction can be rerun
+pub fn is_serialization_failure<E: Fail>(e: &E) -> bool {
+    let e: &Fail = e;
+    e.iter_chain().any(|cause| match cause.downcast_ref::<DieselError>() {
+        Some(DieselError::DatabaseError(DatabaseErrorKind::SerializationFailure, _)) => true,
+        _ => false,
+    })
+}
+
 /// This method should be called inside repos for obtaining connections from
-/// thread local storage
-pub fn with_tls_connection<F, T>(f: F) -> Result<T, Error>
+/// thread local storage. Duration and rows of the call are recorded by `method` name,
+/// e.g. `transactions.create`, if the executor collects query stats
+pub fn with_tls_connection<F, T>(method: &'static str, f: F) -> Result<T, Error>
+where
+    F: FnOnce(&PgConnection) -> Result<T, Error>,
+    T: RowCount,
+{
+    let started_at = Instant::now();
+    let res = with_raw_tls_connection(f);
+    record_repo_call(method, started_at, &res);
+    res
+}
+
+fn with_raw_tls_connection<F, T>(f: F) -> Result<T, Error>
 where
     F: FnOnce(&PgConnection) -> Result<T, Error>,
 {
@@ -209,3 +431,20 @@ fn rollback_transaction(tls_conn_cell: &RefCell<Option<PgPooledConnection>>) {
         }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use services::Error as ServiceError;
+
+    #[test]
+    fn test_is_serialization_failure() {
+        let diesel_error = DieselError::DatabaseError(DatabaseErrorKind::SerializationFailure, Box::new("could not serialize".to_string()));
+        let e: Error = ectx!(err diesel_error, ErrorSource::Diesel, ErrorKind::Internal);
+        let e: ServiceError = e.into();
+        assert!(is_serialization_failure(&e));
+        let diesel_error = DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, Box::new("duplicate key".to_string()));
+        let e: Error = ectx!(err diesel_error, ErrorSource::Diesel, ErrorKind::Internal);
+        assert!(!is_serialization_failure(&e));
+    }
+}
@@ -1,4 +1,8 @@
+use chrono::Duration;
 use diesel;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json;
 
 use super::error::*;
 use super::executor::with_tls_connection;
@@ -8,40 +12,275 @@ use prelude::*;
 use schema::key_values::dsl::*;
 
 pub trait KeyValuesRepo: Send + Sync + 'static {
-    fn get_nonce(&self, address: BlockchainAddress) -> RepoResult<Option<KeyValue>>;
-    fn set_nonce(&self, address: BlockchainAddress, nonce: u64) -> RepoResult<u64>;
+    /// Expired entries are not returned
+    fn get_entry(&self, namespace_: KeyNamespace, key_: &str) -> RepoResult<Option<KeyValue>>;
+    /// Writes the value and bumps its version, fails with `ErrorKind::Conflict`
+    /// if the stored version doesn't match `expected_version`
+    fn put_entry(&self, payload: NewKeyValue, expected_version: ExpectedVersion) -> RepoResult<KeyValue>;
+    fn delete_entry(&self, namespace_: KeyNamespace, key_: &str) -> RepoResult<()>;
 }
 
+/// Typed access to key values on top of `KeyValuesRepo`. Values are stored as json
+pub trait KeyValuesRepoExt: KeyValuesRepo {
+    fn get<T: DeserializeOwned>(&self, namespace_: KeyNamespace, key_: &str) -> RepoResult<Option<T>> {
+        self.get_versioned(namespace_, key_)
+            .map(|maybe_value| maybe_value.map(|(value_, _)| value_))
+    }
+
+    /// Value with its version, that can be passed to `set_versioned` as expected one
+    fn get_versioned<T: DeserializeOwned>(&self, namespace_: KeyNamespace, key_: &str) -> RepoResult<Option<(T, i64)>> {
+        match self.get_entry(namespace_, key_)? {
+            Some(kv) => {
+                let version_ = kv.version;
+                serde_json::from_value(kv.value.clone())
+                    .map(|value_| Some((value_, version_)))
+                    .map_err(move |e| ectx!(err e, ErrorKind::Internal => kv))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn set<T: Serialize>(&self, namespace_: KeyNamespace, key_: &str, value_: &T) -> RepoResult<()> {
+        self.set_versioned(namespace_, key_, value_, ExpectedVersion::Any, None).map(|_| ())
+    }
+
+    /// Returns new version of the value. Value with `ttl` is treated as absent after it passes
+    fn set_versioned<T: Serialize>(
+        &self,
+        namespace_: KeyNamespace,
+        key_: &str,
+        value_: &T,
+        expected_version: ExpectedVersion,
+        ttl: Option<Duration>,
+    ) -> RepoResult<i64> {
+        let json_value = serde_json::to_value(value_).map_err(ectx!(try ErrorKind::Internal => namespace_, key_))?;
+        let payload = NewKeyValue::new(namespace_, key_, json_value, ttl);
+        self.put_entry(payload, expected_version).map(|kv| kv.version)
+    }
+
+    fn delete(&self, namespace_: KeyNamespace, key_: &str) -> RepoResult<()> {
+        self.delete_entry(namespace_, key_)
+    }
+
+    fn get_nonce(&self, address: BlockchainAddress) -> RepoResult<Option<KeyValue>> {
+        self.get_entry(KeyNamespace::Nonce, &address.to_string())
+    }
+    fn set_nonce(&self, address: BlockchainAddress, nonce: u64) -> RepoResult<u64> {
+        self.set(KeyNamespace::Nonce, &address.to_string(), &nonce).map(|_| nonce)
+    }
+
+    // Block number, hashes of transactions before which are pruned from seen hashes
+    fn get_seen_hashes_watermark(&self, currency: Currency) -> RepoResult<Option<i64>> {
+        self.get(KeyNamespace::SeenHashesWatermark, &currency.to_string())
+    }
+    fn set_seen_hashes_watermark(&self, currency: Currency, block_number: i64) -> RepoResult<i64> {
+        self.set(KeyNamespace::SeenHashesWatermark, &currency.to_string(), &block_number)
+            .map(|_| block_number)
+    }
+
+    // Outcome of the latest reconciliation of addresses in the currency
+    fn get_reconciliation_status(&self, currency: Currency) -> RepoResult<Option<ReconciliationStatus>> {
+        self.get(KeyNamespace::Reconciliation, &currency.to_string())
+    }
+    fn set_reconciliation_status(&self, currency: Currency, status: ReconciliationStatus) -> RepoResult<ReconciliationStatus> {
+        self.set(KeyNamespace::Reconciliation, &currency.to_string(), &status)
+            .map(|_| status)
+    }
+
+    // Runtime override of confirmation thresholds from config
+    fn get_confirmation_thresholds(&self) -> RepoResult<Option<ConfirmationThresholds>> {
+        self.get(KeyNamespace::Settings, CONFIRMATION_THRESHOLDS_KEY)
+    }
+    fn set_confirmation_thresholds(&self, thresholds: ConfirmationThresholds) -> RepoResult<ConfirmationThresholds> {
+        self.set(KeyNamespace::Settings, CONFIRMATION_THRESHOLDS_KEY, &thresholds)
+            .map(|_| thresholds)
+    }
+    fn delete_confirmation_thresholds(&self) -> RepoResult<()> {
+        self.delete(KeyNamespace::Settings, CONFIRMATION_THRESHOLDS_KEY)
+    }
+
+    // Per user override of transaction limits from config
+    fn get_transaction_limits(&self, user_id: UserId) -> RepoResult<Option<TransactionLimits>> {
+        self.get(KeyNamespace::TransactionLimits, &user_id.to_string())
+    }
+    fn set_transaction_limits(&self, user_id: UserId, limits: TransactionLimits) -> RepoResult<TransactionLimits> {
+        self.set(KeyNamespace::TransactionLimits, &user_id.to_string(), &limits)
+            .map(|_| limits)
+    }
+    fn delete_transaction_limits(&self, user_id: UserId) -> RepoResult<()> {
+        self.delete(KeyNamespace::TransactionLimits, &user_id.to_string())
+    }
+
+    // Runtime override of allowed exchange pairs from config
+    fn get_exchange_pairs(&self) -> RepoResult<Option<ExchangePairs>> {
+        self.get(KeyNamespace::Settings, EXCHANGE_PAIRS_KEY)
+    }
+    fn set_exchange_pairs(&self, pairs: ExchangePairs) -> RepoResult<ExchangePairs> {
+        self.set(KeyNamespace::Settings, EXCHANGE_PAIRS_KEY, &pairs).map(|_| pairs)
+    }
+    fn delete_exchange_pairs(&self) -> RepoResult<()> {
+        self.delete(KeyNamespace::Settings, EXCHANGE_PAIRS_KEY)
+    }
+
+    // Gateway that issued the rate, exchanges with its id can only be made there
+    fn get_exchange_gateway(&self, exchange_id: ExchangeId) -> RepoResult<Option<String>> {
+        self.get(KeyNamespace::ExchangeGateway, &exchange_id.inner().to_string())
+    }
+    fn set_exchange_gateway(&self, exchange_id: ExchangeId, gateway_url: &str, ttl: Duration) -> RepoResult<()> {
+        self.set_versioned(
+            KeyNamespace::ExchangeGateway,
+            &exchange_id.inner().to_string(),
+            &gateway_url,
+            ExpectedVersion::Any,
+            Some(ttl),
+        )
+        .map(|_| ())
+    }
+}
+
+impl<R: KeyValuesRepo + ?Sized> KeyValuesRepoExt for R {}
+
+const CONFIRMATION_THRESHOLDS_KEY: &str = "confirmation_thresholds";
+const EXCHANGE_PAIRS_KEY: &str = "exchange_pairs";
+
 #[derive(Clone, Default)]
 pub struct KeyValuesRepoImpl;
 
 impl KeyValuesRepo for KeyValuesRepoImpl {
-    fn get_nonce(&self, address: BlockchainAddress) -> RepoResult<Option<KeyValue>> {
-        with_tls_connection(|conn| {
-            let key_ = format!("nonce:{}", address);
-            key_values.filter(key.eq(key_)).first(conn).optional().map_err(move |e| {
-                let error_kind = ErrorKind::from(&e);
-                ectx!(err e, error_kind => address)
-            })
+    fn get_entry(&self, namespace_: KeyNamespace, key_: &str) -> RepoResult<Option<KeyValue>> {
+        with_tls_connection("key_values.get_entry", |conn| {
+            let now = ::chrono::Utc::now().naive_utc();
+            key_values
+                .filter(namespace.eq(namespace_.as_str()))
+                .filter(key.eq(key_))
+                .filter(expires_at.is_null().or(expires_at.gt(now)))
+                .first(conn)
+                .optional()
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => namespace_, key_)
+                })
         })
     }
-    fn set_nonce(&self, address: BlockchainAddress, nonce: u64) -> RepoResult<u64> {
-        with_tls_connection(|conn| {
-            let key_ = format!("nonce:{}", address);
-            diesel::insert_into(key_values)
-                .values(&NewKeyValue {
-                    key: key_,
-                    value: json!(nonce),
-                })
-                .on_conflict(key)
-                .do_update()
-                .set(value.eq(json!(nonce)))
+
+    fn put_entry(&self, payload: NewKeyValue, expected_version: ExpectedVersion) -> RepoResult<KeyValue> {
+        with_tls_connection("key_values.put_entry", |conn| {
+            let now = ::chrono::Utc::now().naive_utc();
+            let payload_clone = payload.clone();
+            let entry = key_values
+                .filter(namespace.eq(payload.namespace.clone()))
+                .filter(key.eq(payload.key.clone()));
+            let res = match expected_version {
+                ExpectedVersion::Any => diesel::insert_into(key_values)
+                    .values(&payload)
+                    .on_conflict((namespace, key))
+                    .do_update()
+                    .set((
+                        value.eq(payload.value.clone()),
+                        version.eq(version + 1),
+                        expires_at.eq(payload.expires_at),
+                    ))
+                    .get_result::<KeyValue>(conn)
+                    .optional(),
+                ExpectedVersion::Absent => {
+                    // expired entry is absent for readers, so it's replaced
+                    diesel::delete(entry.filter(expires_at.le(now))).execute(conn).and_then(|_| {
+                        diesel::insert_into(key_values)
+                            .values(&payload)
+                            .on_conflict_do_nothing()
+                            .get_result::<KeyValue>(conn)
+                            .optional()
+                    })
+                }
+                ExpectedVersion::Exact(expected) => diesel::update(
+                    entry
+                        .filter(version.eq(expected))
+                        .filter(expires_at.is_null().or(expires_at.gt(now))),
+                )
+                .set((
+                    value.eq(payload.value.clone()),
+                    version.eq(version + 1),
+                    expires_at.eq(payload.expires_at),
+                ))
                 .get_result::<KeyValue>(conn)
-                .map(|kv| kv.value.as_u64().unwrap())
+                .optional(),
+            };
+            res.map_err(move |e| {
+                let error_kind = ErrorKind::from(&e);
+                ectx!(err e, error_kind => payload_clone, expected_version)
+            })?
+            .ok_or_else(|| ectx!(err ErrorContext::StaleVersion, ErrorKind::Conflict => payload, expected_version))
+        })
+    }
+
+    fn delete_entry(&self, namespace_: KeyNamespace, key_: &str) -> RepoResult<()> {
+        with_tls_connection("key_values.delete_entry", |conn| {
+            diesel::delete(key_values.filter(namespace.eq(namespace_.as_str())).filter(key.eq(key_)))
+                .execute(conn)
+                .map(|_| ())
                 .map_err(move |e| {
                     let error_kind = ErrorKind::from(&e);
-                    ectx!(err e, error_kind => address, nonce)
+                    ectx!(err e, error_kind => namespace_, key_)
                 })
         })
     }
 }
+
+#[cfg(test)]
+pub mod tests {
+    use diesel::r2d2::ConnectionManager;
+    use diesel::PgConnection;
+    use futures_cpupool::CpuPool;
+    use r2d2;
+    use tokio_core::reactor::Core;
+
+    use super::*;
+    use config::Config;
+    use repos::DbExecutorImpl;
+
+    fn create_executor() -> DbExecutorImpl {
+        let config = Config::new().unwrap();
+        let manager = ConnectionManager::<PgConnection>::new(config.database.url);
+        let db_pool = r2d2::Pool::builder().build(manager).unwrap();
+        let cpu_pool = CpuPool::new(1);
+        DbExecutorImpl::new(db_pool.clone(), cpu_pool.clone())
+    }
+
+    #[test]
+    fn key_values_versioned_set() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let key_values_repo = KeyValuesRepoImpl::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let key_ = UserId::generate().to_string();
+            let version_ = key_values_repo.set_versioned(KeyNamespace::TransactionLimits, &key_, &1, ExpectedVersion::Absent, None)?;
+            assert!(key_values_repo
+                .set_versioned(KeyNamespace::TransactionLimits, &key_, &2, ExpectedVersion::Absent, None)
+                .is_err());
+            // stale version is rejected, current one is accepted
+            let next_version =
+                key_values_repo.set_versioned(KeyNamespace::TransactionLimits, &key_, &2, ExpectedVersion::Exact(version_), None)?;
+            assert!(key_values_repo
+                .set_versioned(KeyNamespace::TransactionLimits, &key_, &3, ExpectedVersion::Exact(version_), None)
+                .is_err());
+            assert_eq!(
+                key_values_repo.get_versioned::<i64>(KeyNamespace::TransactionLimits, &key_)?,
+                Some((2, next_version))
+            );
+            // namespaces don't share keys
+            assert_eq!(key_values_repo.get::<i64>(KeyNamespace::Nonce, &key_)?, None);
+            // expired value is absent and can be written again
+            key_values_repo.set_versioned(
+                KeyNamespace::TransactionLimits,
+                &key_,
+                &4,
+                ExpectedVersion::Any,
+                Some(Duration::seconds(-1)),
+            )?;
+            assert_eq!(key_values_repo.get::<i64>(KeyNamespace::TransactionLimits, &key_)?, None);
+            key_values_repo.set_versioned(KeyNamespace::TransactionLimits, &key_, &5, ExpectedVersion::Absent, None)?;
+            assert_eq!(key_values_repo.get::<i64>(KeyNamespace::TransactionLimits, &key_)?, Some(5));
+            Ok::<_, Error>(())
+        }));
+    }
+}
@@ -2,7 +2,9 @@ use std::collections::HashMap;
 use std::collections::HashSet;
 use std::sync::{Arc, Mutex};
 
-use chrono::Duration;
+use chrono::{Duration, NaiveDateTime};
+use serde_json;
+use uuid::Uuid;
 
 use super::accounts::*;
 use super::blockchain_transactions::*;
@@ -10,9 +12,17 @@ use super::error::*;
 use super::executor::{DbExecutor, Isolation};
 use super::key_values::*;
 use super::pending_blockchain_transactions::*;
+use super::pending_deposits::*;
+use super::quarantined_messages::*;
+use super::queued_withdrawals::*;
+use super::rate_locks::*;
+use super::seen_hashes::*;
+use super::small_deposits::*;
+use super::strange_blockchain_transactions::*;
 use super::transactions::*;
 use super::types::RepoResult;
 use super::users::*;
+use super::withdrawal_addresses::*;
 use models::*;
 use prelude::*;
 
@@ -35,6 +45,8 @@ impl UsersRepo for UsersRepoMock {
             authentication_token: payload.authentication_token,
             created_at: ::chrono::Utc::now().naive_utc(),
             updated_at: ::chrono::Utc::now().naive_utc(),
+            disabled: false,
+            withdrawal_whitelist: false,
         };
         data.push(res.clone());
         Ok(res)
@@ -60,6 +72,12 @@ impl UsersRepo for UsersRepoMock {
                     if let Some(ref authentication_token) = payload.authentication_token {
                         x.authentication_token = authentication_token.clone();
                     }
+                    if let Some(disabled) = payload.disabled {
+                        x.disabled = disabled;
+                    }
+                    if let Some(withdrawal_whitelist) = payload.withdrawal_whitelist {
+                        x.withdrawal_whitelist = withdrawal_whitelist;
+                    }
                     Some(x)
                 } else {
                     None
@@ -73,11 +91,28 @@ impl UsersRepo for UsersRepoMock {
         let data = self.data.lock().unwrap();
         Ok(data.iter().filter(|x| x.id == user_id).nth(0).cloned().unwrap())
     }
+    fn search(&self, query: Option<String>, offset: i64, limit: i64) -> RepoResult<Vec<User>> {
+        let data = self.data.lock().unwrap();
+        let mut found: Vec<User> = data
+            .iter()
+            .filter(|x| match query {
+                Some(ref query) => x.id.to_string() == *query || x.name.to_lowercase().contains(&query.to_lowercase()),
+                None => true,
+            })
+            .cloned()
+            .collect();
+        found.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+        Ok(found.into_iter().skip(offset as usize).take(limit as usize).collect())
+    }
+    fn get_accounts_summaries(&self, _user_ids: &[UserId]) -> RepoResult<Vec<UserAccountsSummary>> {
+        Ok(vec![])
+    }
 }
 
 #[derive(Clone, Default)]
 pub struct AccountsRepoMock {
     data: Arc<Mutex<Vec<Account>>>,
+    expired_addresses: Arc<Mutex<Vec<ExpiredAddress>>>,
 }
 
 impl AccountsRepo for AccountsRepoMock {
@@ -103,6 +138,18 @@ impl AccountsRepo for AccountsRepoMock {
             .filter_map(|x| {
                 if x.id == account_id {
                     x.name = payload.name.clone();
+                    if let Some(erc20_approved) = payload.erc20_approved {
+                        x.erc20_approved = erc20_approved;
+                    }
+                    if let Some(ref meta) = payload.meta {
+                        x.meta = meta.clone();
+                    }
+                    if let Some(ref labels) = payload.labels {
+                        x.labels = labels.clone();
+                    }
+                    if let Some(auto_convert_to) = payload.auto_convert_to {
+                        x.auto_convert_to = auto_convert_to;
+                    }
                     Some(x)
                 } else {
                     None
@@ -112,45 +159,139 @@ impl AccountsRepo for AccountsRepoMock {
             .cloned();
         Ok(u.unwrap())
     }
-    fn delete(&self, account_id: AccountId) -> RepoResult<Account> {
-        let data = self.data.lock().unwrap();
-        Ok(data.iter().filter(|x| x.id == account_id).nth(0).cloned().unwrap())
+    fn archive(&self, account_id: AccountId) -> RepoResult<Account> {
+        let mut data = self.data.lock().unwrap();
+        let u = data.iter_mut().filter(|x| x.id == account_id).nth(0).unwrap();
+        u.archived = true;
+        Ok(u.clone())
     }
-    fn list_for_user(&self, user_id_arg: UserId, _offset: i64, _limit: i64) -> RepoResult<Vec<Account>> {
+    fn list_for_user(&self, user_id_arg: UserId, _offset: i64, _limit: i64, filter: AccountsFilter) -> RepoResult<Vec<Account>> {
         let data = self.data.lock().unwrap();
-        Ok(data.clone().into_iter().filter(|x| x.user_id == user_id_arg).collect())
+        Ok(data
+            .clone()
+            .into_iter()
+            .filter(|x| x.user_id == user_id_arg)
+            .filter(|x| !x.archived)
+            .filter(|x| filter.labels.iter().all(|label| x.labels.contains(label)))
+            .filter(|x| filter.meta.as_ref().map(|meta| json_contains(&x.meta, meta)).unwrap_or(true))
+            .collect())
     }
-    fn get_by_address(&self, address_: BlockchainAddress, currency_: Currency, kind_: AccountKind) -> RepoResult<Option<Account>> {
+    fn get_by_address(
+        &self,
+        address_: BlockchainAddress,
+        currency_: Currency,
+        kind_: AccountKind,
+        archived_: ArchivedAccounts,
+    ) -> RepoResult<Option<Account>> {
         let data = self.data.lock().unwrap();
         let u = data
             .iter()
-            .filter(|x| x.address == address_ && x.kind == kind_ && x.currency == currency_)
+            .filter(|x| x.address == address_ && x.kind == kind_ && x.currency == currency_ && archived_.matches(x))
             .nth(0)
             .cloned();
         Ok(u)
     }
 
-    fn filter_by_address(&self, address_: BlockchainAddress) -> RepoResult<Vec<Account>> {
+    fn filter_by_address(&self, address_: BlockchainAddress, archived_: ArchivedAccounts) -> RepoResult<Vec<Account>> {
         let data = self.data.lock().unwrap();
-        let u = data.iter().filter(|x| x.address == address_).cloned().collect();
+        let u = data
+            .iter()
+            .filter(|x| x.address == address_ && archived_.matches(x))
+            .cloned()
+            .collect();
         Ok(u)
     }
 
-    fn get_by_addresses(&self, addresses: &[BlockchainAddress], currency_: Currency, kind_: AccountKind) -> RepoResult<Vec<Account>> {
+    fn get_by_addresses(
+        &self,
+        addresses: &[BlockchainAddress],
+        currency_: Currency,
+        kind_: AccountKind,
+        archived_: ArchivedAccounts,
+    ) -> RepoResult<Vec<Account>> {
         let addresses: HashSet<_> = addresses.iter().collect();
         let data = self.data.lock().unwrap();
         let u = data
             .iter()
-            .filter(|x| addresses.contains(&x.address) && x.kind == kind_ && x.currency == currency_)
+            .filter(|x| addresses.contains(&x.address) && x.kind == kind_ && x.currency == currency_ && archived_.matches(x))
             .cloned()
             .collect();
         Ok(u)
     }
+
+    fn rotate_address(&self, account_id: AccountId, new_address: BlockchainAddress) -> RepoResult<Account> {
+        let mut data = self.data.lock().unwrap();
+        let u = data.iter_mut().filter(|x| x.id == account_id).nth(0).unwrap();
+        self.expired_addresses.lock().unwrap().push(ExpiredAddress {
+            address: u.address.clone(),
+            currency: u.currency,
+            account_id,
+            expired_at: ::chrono::Utc::now().naive_utc(),
+        });
+        u.address = new_address;
+        Ok(u.clone())
+    }
+
+    fn set_address(&self, account_id: AccountId, new_address: BlockchainAddress) -> RepoResult<Account> {
+        let mut data = self.data.lock().unwrap();
+        let u = data.iter_mut().filter(|x| x.id == account_id).nth(0).unwrap();
+        u.address = new_address;
+        u.erc20_approved = false;
+        Ok(u.clone())
+    }
+
+    fn get_system_account(&self, role: SystemAccountKind, currency_: Currency, kind_: AccountKind) -> RepoResult<Option<Account>> {
+        let data = self.data.lock().unwrap();
+        Ok(data
+            .iter()
+            .find(|x| x.system_role == Some(role) && x.currency == currency_ && x.kind == kind_)
+            .cloned())
+    }
+
+    fn list_system_accounts(&self) -> RepoResult<Vec<Account>> {
+        let data = self.data.lock().unwrap();
+        Ok(data.iter().filter(|x| x.system_role.is_some()).cloned().collect())
+    }
+
+    fn set_system_role(&self, account_id: AccountId, role: Option<SystemAccountKind>) -> RepoResult<Account> {
+        let mut data = self.data.lock().unwrap();
+        let u = data.iter_mut().filter(|x| x.id == account_id).nth(0).unwrap();
+        u.system_role = role;
+        Ok(u.clone())
+    }
+
+    fn mark_spent(&self, account_ids: &[AccountId]) -> RepoResult<()> {
+        let mut data = self.data.lock().unwrap();
+        let now = ::chrono::Utc::now().naive_utc();
+        for account in data.iter_mut().filter(|x| account_ids.contains(&x.id)) {
+            account.last_spent_at = Some(now);
+        }
+        Ok(())
+    }
+
+    fn get_by_expired_address(&self, address_: BlockchainAddress, currency_: Currency) -> RepoResult<Option<Account>> {
+        let expired_addresses = self.expired_addresses.lock().unwrap();
+        match expired_addresses.iter().find(|x| x.address == address_ && x.currency == currency_) {
+            Some(expired) => self.get(expired.account_id),
+            None => Ok(None),
+        }
+    }
+
+    fn list_expired_addresses(&self, account_id: AccountId) -> RepoResult<Vec<ExpiredAddress>> {
+        let expired_addresses = self.expired_addresses.lock().unwrap();
+        Ok(expired_addresses
+            .iter()
+            .rev()
+            .filter(|x| x.account_id == account_id)
+            .cloned()
+            .collect())
+    }
 }
 
 #[derive(Clone, Default)]
 pub struct TransactionsRepoMock {
     data: Arc<Mutex<Vec<Transaction>>>,
+    group_fees: Arc<Mutex<Vec<GroupFee>>>,
 }
 
 impl TransactionsRepo for TransactionsRepoMock {
@@ -158,7 +299,7 @@ impl TransactionsRepo for TransactionsRepoMock {
         let mut data = self.data.lock().unwrap();
         let res = Transaction {
             id: payload.id,
-            gid: payload.id,
+            gid: payload.gid,
             user_id: payload.user_id,
             dr_account_id: payload.dr_account_id,
             cr_account_id: payload.cr_account_id,
@@ -168,14 +309,20 @@ impl TransactionsRepo for TransactionsRepoMock {
             blockchain_tx_id: payload.blockchain_tx_id,
             created_at: ::chrono::Utc::now().naive_utc(),
             updated_at: ::chrono::Utc::now().naive_utc(),
-            kind: TransactionKind::Internal,
-            group_kind: TransactionGroupKind::Internal,
-            related_tx: None,
-            ..Default::default()
+            kind: payload.kind,
+            group_kind: payload.group_kind,
+            related_tx: payload.related_tx,
+            meta: payload.meta.unwrap_or_default(),
         };
         data.push(res.clone());
         Ok(res)
     }
+    fn create_batch(&self, payloads: Vec<NewTransaction>) -> RepoResult<Vec<Transaction>> {
+        payloads.into_iter().map(|payload| self.create(payload)).collect()
+    }
+    fn assert_non_negative_balances(&self, _account_ids: &[AccountId]) -> RepoResult<()> {
+        Ok(())
+    }
     fn get(&self, transaction_id: TransactionId) -> RepoResult<Option<Transaction>> {
         let data = self.data.lock().unwrap();
         Ok(data.iter().filter(|x| x.id == transaction_id).nth(0).cloned())
@@ -184,6 +331,19 @@ impl TransactionsRepo for TransactionsRepoMock {
         let data = self.data.lock().unwrap();
         Ok(data.iter().filter(|x| x.gid == gid).cloned().collect())
     }
+    fn get_group_with_related(&self, gid: TransactionId) -> RepoResult<TransactionGroupTree> {
+        let data = self.data.lock().unwrap();
+        Ok(TransactionGroupTree::build(gid, data.clone()))
+    }
+    fn get_group_summary(&self, _gid: TransactionId) -> RepoResult<Option<TxGroup>> {
+        unimplemented!()
+    }
+    fn create_balance_checkpoint(&self, _as_of: NaiveDateTime) -> RepoResult<Option<usize>> {
+        unimplemented!()
+    }
+    fn archive_groups(&self, _horizon: NaiveDateTime, _limit: i64) -> RepoResult<usize> {
+        unimplemented!()
+    }
     fn get_by_blockchain_tx(&self, blockchain_tx_id: BlockchainTransactionId) -> RepoResult<Option<Transaction>> {
         let data = self.data.lock().unwrap();
         Ok(data
@@ -197,10 +357,22 @@ impl TransactionsRepo for TransactionsRepoMock {
         unimplemented!()
     }
 
+    fn get_exchange_margins(&self) -> RepoResult<HashMap<Currency, Amount>> {
+        unimplemented!()
+    }
+
     fn get_blockchain_balances(&self) -> RepoResult<HashMap<(BlockchainAddress, Currency), (Amount, Amount)>> {
         unimplemented!()
     }
 
+    fn get_accounts_turnovers(&self) -> RepoResult<Vec<AccountTurnover>> {
+        unimplemented!()
+    }
+
+    fn get_stale_account_balances(&self) -> RepoResult<Vec<AccountTurnover>> {
+        unimplemented!()
+    }
+
     fn get_account_spending(&self, account_id: AccountId, _kind: AccountKind, _period: Duration) -> RepoResult<Amount> {
         let data = self.data.lock().unwrap();
         let amount = data
@@ -210,11 +382,76 @@ impl TransactionsRepo for TransactionsRepoMock {
         Ok(amount.unwrap())
     }
 
-    fn list_groups_for_account_skip_approval(&self, _account_id: AccountId, _offset: i64, _limit: i64) -> RepoResult<Vec<Transaction>> {
+    fn get_account_stats(&self, account_id: AccountId, window: StatsWindow) -> RepoResult<AccountStats> {
+        let data = self.data.lock().unwrap();
+        let date = ::chrono::Utc::now().naive_utc() - window.duration();
+        let deposits: Vec<_> = data
+            .iter()
+            .filter(|x| x.created_at >= date)
+            .filter(|x| x.cr_account_id == account_id && x.kind == TransactionKind::Deposit)
+            .collect();
+        let withdrawals: Vec<_> = data
+            .iter()
+            .filter(|x| x.created_at >= date)
+            .filter(|x| x.dr_account_id == account_id && x.kind == TransactionKind::Withdrawal)
+            .collect();
+        let withdrawal_gids: HashSet<_> = withdrawals.iter().map(|x| x.gid).collect();
+        Ok(AccountStats {
+            account_id,
+            currency: deposits
+                .iter()
+                .chain(withdrawals.iter())
+                .map(|x| x.currency)
+                .nth(0)
+                .unwrap_or(Currency::Eth),
+            window,
+            deposits_count: deposits.len() as u64,
+            deposits_value: deposits.iter().try_fold(Amount::new(0), |acc, x| acc.checked_add(x.value)).unwrap(),
+            withdrawals_count: withdrawal_gids.len() as u64,
+            withdrawals_value: withdrawals
+                .iter()
+                .try_fold(Amount::new(0), |acc, x| acc.checked_add(x.value))
+                .unwrap(),
+        })
+    }
+
+    fn list_groups_for_account_skip_approval(
+        &self,
+        _account_id: AccountId,
+        _offset: i64,
+        _limit: i64,
+        _sort: TransactionsSort,
+    ) -> RepoResult<Vec<Transaction>> {
+        unimplemented!()
+    }
+
+    fn list_groups_for_account_before(
+        &self,
+        _account_id: AccountId,
+        _before: Option<(::chrono::NaiveDateTime, TransactionId)>,
+        _limit: i64,
+    ) -> RepoResult<Vec<Transaction>> {
         unimplemented!()
     }
 
-    fn list_groups_for_user_skip_approval(&self, _user_id: UserId, _offset: i64, _limit: i64) -> RepoResult<Vec<Transaction>> {
+    fn list_groups_for_user_skip_approval(
+        &self,
+        _user_id: UserId,
+        _offset: i64,
+        _limit: i64,
+        _sort: TransactionsSort,
+    ) -> RepoResult<Vec<Transaction>> {
+        unimplemented!()
+    }
+
+    fn list_groups_for_user_in_period(
+        &self,
+        _user_id: UserId,
+        _from: ::chrono::NaiveDateTime,
+        _to: ::chrono::NaiveDateTime,
+        _offset: i64,
+        _limit: i64,
+    ) -> RepoResult<Vec<Transaction>> {
         unimplemented!()
     }
 
@@ -238,10 +475,27 @@ impl TransactionsRepo for TransactionsRepoMock {
             .cloned();
         Ok(u.unwrap())
     }
+    fn update_status_by_id(&self, transaction_id: TransactionId, transaction_status: TransactionStatus) -> RepoResult<Transaction> {
+        let mut data = self.data.lock().unwrap();
+        let u = data.iter_mut().find(|x| x.id == transaction_id).map(|x| {
+            x.status = transaction_status;
+            x.clone()
+        });
+        Ok(u.unwrap())
+    }
     fn list_for_user(&self, user_id: UserId, _offset: i64, _limit: i64) -> RepoResult<Vec<Transaction>> {
         let data = self.data.lock().unwrap();
         Ok(data.clone().into_iter().filter(|x| x.user_id == user_id).collect())
     }
+    fn list_pending_older_than(&self, age: Duration) -> RepoResult<Vec<Transaction>> {
+        let data = self.data.lock().unwrap();
+        let date = ::chrono::Utc::now().naive_utc() - age;
+        Ok(data
+            .clone()
+            .into_iter()
+            .filter(|x| x.status == TransactionStatus::Pending && x.created_at < date)
+            .collect())
+    }
     fn get_accounts_balance(&self, _auth_user_id: UserId, accounts: &[Account]) -> RepoResult<Vec<AccountWithBalance>> {
         accounts
             .into_iter()
@@ -321,6 +575,15 @@ impl TransactionsRepo for TransactionsRepoMock {
         Ok(u.unwrap())
     }
 
+    fn update_meta(&self, transaction_id: TransactionId, meta: serde_json::Value) -> RepoResult<Transaction> {
+        let mut data = self.data.lock().unwrap();
+        let u = data.iter_mut().find(|x| x.id == transaction_id).map(|x| {
+            x.meta = meta;
+            x.clone()
+        });
+        Ok(u.unwrap())
+    }
+
     fn get_accounts_for_withdrawal(&self, value_: Amount, currency_: Currency, _fee_per_tx: Amount) -> RepoResult<Vec<AccountWithBalance>> {
         let data = self.data.lock().unwrap();
         Ok(data
@@ -337,6 +600,74 @@ impl TransactionsRepo for TransactionsRepoMock {
             })
             .collect())
     }
+
+    // accounts are not known here, so every one with debit turnover in the currency above credit is taken for debit one
+    fn get_dr_balances(&self, currency_: Currency) -> RepoResult<Vec<AccountWithBalance>> {
+        let account_ids: HashSet<AccountId> = {
+            let data = self.data.lock().unwrap();
+            data.iter().filter(|x| x.currency == currency_).map(|x| x.dr_account_id).collect()
+        };
+        let mut res = vec![];
+        for account_id in account_ids {
+            let balance = match self.get_account_balance(account_id, AccountKind::Dr) {
+                Ok(balance) if balance > Amount::new(0) => balance,
+                _ => continue,
+            };
+            let mut account = Account::default();
+            account.id = account_id;
+            account.currency = currency_;
+            account.kind = AccountKind::Dr;
+            res.push(AccountWithBalance { account, balance });
+        }
+        Ok(res)
+    }
+
+    fn create_group_fee(&self, payload: NewGroupFee) -> RepoResult<GroupFee> {
+        let mut group_fees = self.group_fees.lock().unwrap();
+        let now = ::chrono::Utc::now().naive_utc();
+        let group_fee = GroupFee {
+            gid: payload.gid,
+            fee_currency: payload.fee_currency,
+            fee: payload.fee,
+            blockchain_fee_currency: payload.blockchain_fee_currency,
+            charged_blockchain_fee: payload.charged_blockchain_fee,
+            blockchain_fee: payload.blockchain_fee,
+            created_at: now,
+            updated_at: now,
+        };
+        group_fees.push(group_fee.clone());
+        Ok(group_fee)
+    }
+
+    fn accrue_blockchain_fee(&self, gid: TransactionId, currency: Currency, value: Amount) -> RepoResult<GroupFee> {
+        let existing = self.get_group_fee(gid)?;
+        match existing {
+            Some(_) => {
+                let mut group_fees = self.group_fees.lock().unwrap();
+                let group_fee = group_fees.iter_mut().find(|x| x.gid == gid).unwrap();
+                group_fee.blockchain_fee = group_fee.blockchain_fee.checked_add(value).unwrap();
+                Ok(group_fee.clone())
+            }
+            None => self.create_group_fee(NewGroupFee::uncharged(gid, currency, value)),
+        }
+    }
+
+    fn get_group_fee(&self, gid: TransactionId) -> RepoResult<Option<GroupFee>> {
+        let group_fees = self.group_fees.lock().unwrap();
+        Ok(group_fees.iter().find(|x| x.gid == gid).cloned())
+    }
+
+    fn get_fee_margins(&self) -> RepoResult<HashMap<Currency, (Amount, Amount)>> {
+        unimplemented!()
+    }
+
+    fn deduct_refunded_fee(&self, gid: TransactionId, fee: Amount, charged_blockchain_fee: Amount) -> RepoResult<GroupFee> {
+        let mut group_fees = self.group_fees.lock().unwrap();
+        let group_fee = group_fees.iter_mut().find(|x| x.gid == gid).unwrap();
+        group_fee.fee = group_fee.fee.checked_sub(fee).unwrap();
+        group_fee.charged_blockchain_fee = group_fee.charged_blockchain_fee.checked_sub(charged_blockchain_fee).unwrap();
+        Ok(group_fee.clone())
+    }
 }
 
 #[derive(Clone, Default)]
@@ -349,6 +680,20 @@ impl PendingBlockchainTransactionsRepo for PendingBlockchainTransactionsRepoMock
         let data = self.data.lock().unwrap();
         Ok(data.len() as u64)
     }
+    fn list_older_than(&self, age: Duration, currency: Option<Currency>) -> RepoResult<Vec<StalePendingBlockchainTransaction>> {
+        let data = self.data.lock().unwrap();
+        let date = ::chrono::Utc::now().naive_utc() - age;
+        // ledger transactions are not known to this mock
+        Ok(data
+            .iter()
+            .filter(|x| x.created_at < date)
+            .filter(|x| currency.map(|currency| x.currency == currency).unwrap_or(true))
+            .map(|x| StalePendingBlockchainTransaction {
+                pending: x.clone(),
+                transaction: None,
+            })
+            .collect())
+    }
     fn create(&self, payload: NewPendingBlockchainTransactionDB) -> RepoResult<PendingBlockchainTransactionDB> {
         let mut data = self.data.lock().unwrap();
         let res = PendingBlockchainTransactionDB {
@@ -361,6 +706,9 @@ impl PendingBlockchainTransactionsRepo for PendingBlockchainTransactionsRepoMock
             created_at: ::chrono::Utc::now().naive_utc(),
             updated_at: ::chrono::Utc::now().naive_utc(),
             erc20_operation_kind: None,
+            raw_tx: payload.raw_tx,
+            broadcast_attempts: 0,
+            next_broadcast_at: payload.next_broadcast_at,
         };
         data.push(res.clone());
         Ok(res)
@@ -373,6 +721,29 @@ impl PendingBlockchainTransactionsRepo for PendingBlockchainTransactionsRepoMock
         let data = self.data.lock().unwrap();
         Ok(data.iter().filter(|x| x.hash == hash_).nth(0).cloned())
     }
+    fn list_due_for_broadcast(&self, limit: i64) -> RepoResult<Vec<PendingBlockchainTransactionDB>> {
+        let data = self.data.lock().unwrap();
+        let now = ::chrono::Utc::now().naive_utc();
+        Ok(data
+            .iter()
+            .filter(|x| x.next_broadcast_at.map(|at| at <= now).unwrap_or(false))
+            .take(limit as usize)
+            .cloned()
+            .collect())
+    }
+    fn reschedule_broadcast(
+        &self,
+        hash_: BlockchainTransactionId,
+        next_broadcast_at: Option<NaiveDateTime>,
+    ) -> RepoResult<PendingBlockchainTransactionDB> {
+        let mut data = self.data.lock().unwrap();
+        let u = data.iter_mut().find(|x| x.hash == hash_).map(|x| {
+            x.broadcast_attempts += 1;
+            x.next_broadcast_at = next_broadcast_at;
+            x.clone()
+        });
+        Ok(u.unwrap())
+    }
 }
 
 #[derive(Clone, Default)]
@@ -399,6 +770,10 @@ impl BlockchainTransactionsRepo for BlockchainTransactionsRepoMock {
         Ok(res)
     }
 
+    fn create_batch(&self, payload: Vec<NewBlockchainTransactionDB>) -> RepoResult<Vec<BlockchainTransactionDB>> {
+        payload.into_iter().map(|tx| self.create(tx)).collect()
+    }
+
     fn upsert(&self, payload: NewBlockchainTransactionDB) -> RepoResult<BlockchainTransactionDB> {
         let mut data = self.data.lock().unwrap();
         let res = BlockchainTransactionDB {
@@ -425,26 +800,490 @@ impl BlockchainTransactionsRepo for BlockchainTransactionsRepoMock {
 
 #[derive(Clone, Default)]
 pub struct KeyValuesRepoMock {
-    data: Arc<Mutex<Vec<KeyValue>>>,
+    data: Arc<Mutex<HashMap<(String, String), KeyValue>>>,
 }
 
 impl KeyValuesRepo for KeyValuesRepoMock {
-    fn get_nonce(&self, address: BlockchainAddress) -> RepoResult<Option<KeyValue>> {
+    fn get_entry(&self, namespace: KeyNamespace, key: &str) -> RepoResult<Option<KeyValue>> {
         let data = self.data.lock().unwrap();
-        let key = format!("nonce:{}", address);
-        Ok(data.iter().filter(|x| x.key == key).nth(0).cloned())
+        let now = ::chrono::Utc::now().naive_utc();
+        Ok(data
+            .get(&(namespace.to_string(), key.to_string()))
+            .filter(|kv| !kv.is_expired(now))
+            .cloned())
     }
-    fn set_nonce(&self, address: BlockchainAddress, nonce: u64) -> RepoResult<u64> {
+    fn put_entry(&self, payload: NewKeyValue, expected_version: ExpectedVersion) -> RepoResult<KeyValue> {
         let mut data = self.data.lock().unwrap();
-        let key = format!("nonce:{}", address);
+        let now = ::chrono::Utc::now().naive_utc();
+        let map_key = (payload.namespace.clone(), payload.key.clone());
+        let current = data.get(&map_key).filter(|kv| !kv.is_expired(now)).cloned();
+        let version = match (expected_version, current) {
+            (ExpectedVersion::Any, Some(kv)) => kv.version + 1,
+            (ExpectedVersion::Exact(expected), Some(ref kv)) if kv.version == expected => kv.version + 1,
+            (ExpectedVersion::Any, None) | (ExpectedVersion::Absent, None) => 1,
+            _ => return Err(ectx!(err ErrorContext::StaleVersion, ErrorKind::Conflict => payload, expected_version)),
+        };
         let res = KeyValue {
-            key,
-            value: json!(nonce),
+            key: payload.key,
+            value: payload.value,
+            created_at: now,
+            updated_at: now,
+            namespace: payload.namespace,
+            version,
+            expires_at: payload.expires_at,
+        };
+        data.insert(map_key, res.clone());
+        Ok(res)
+    }
+    fn delete_entry(&self, namespace: KeyNamespace, key: &str) -> RepoResult<()> {
+        let mut data = self.data.lock().unwrap();
+        data.remove(&(namespace.to_string(), key.to_string()));
+        Ok(())
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct SeenHashesRepoMock {
+    data: Arc<Mutex<Vec<SeenHashes>>>,
+}
+
+impl SeenHashesRepo for SeenHashesRepoMock {
+    fn create(&self, payload: NewSeenHashes) -> RepoResult<SeenHashes> {
+        let mut data = self.data.lock().unwrap();
+        let res = SeenHashes {
+            hash: payload.hash,
+            block_number: payload.block_number,
+            currency: payload.currency,
+            created_at: ::chrono::Utc::now().naive_utc(),
+            updated_at: ::chrono::Utc::now().naive_utc(),
+        };
+        data.push(res.clone());
+        Ok(res)
+    }
+    fn upsert(&self, payload: NewSeenHashes) -> RepoResult<SeenHashes> {
+        match self.get(payload.hash.clone(), payload.currency)? {
+            Some(seen_hashes) => Ok(seen_hashes),
+            None => self.create(payload),
+        }
+    }
+    fn try_create(&self, payload: NewSeenHashes) -> RepoResult<Option<SeenHashes>> {
+        match self.get(payload.hash.clone(), payload.currency)? {
+            Some(_) => Ok(None),
+            None => self.create(payload).map(Some),
+        }
+    }
+    fn try_create_batch(&self, payload: Vec<NewSeenHashes>) -> RepoResult<Vec<SeenHashes>> {
+        let mut res = vec![];
+        for seen_hashes in payload {
+            if let Some(seen_hashes) = self.try_create(seen_hashes)? {
+                res.push(seen_hashes);
+            }
+        }
+        Ok(res)
+    }
+    fn get(&self, hash: BlockchainTransactionId, currency: Currency) -> RepoResult<Option<SeenHashes>> {
+        let data = self.data.lock().unwrap();
+        Ok(data.iter().filter(|x| x.hash == hash && x.currency == currency).nth(0).cloned())
+    }
+    fn delete(&self, hash: BlockchainTransactionId, currency: Currency) -> RepoResult<Option<SeenHashes>> {
+        let mut data = self.data.lock().unwrap();
+        let res = data.iter().filter(|x| x.hash == hash && x.currency == currency).nth(0).cloned();
+        data.retain(|x| x.hash != hash || x.currency != currency);
+        Ok(res)
+    }
+    fn max_block_number(&self, currency: Currency) -> RepoResult<Option<i64>> {
+        let data = self.data.lock().unwrap();
+        Ok(data.iter().filter(|x| x.currency == currency).map(|x| x.block_number).max())
+    }
+    fn min_block_number_since(&self, currency: Currency, created_since: NaiveDateTime) -> RepoResult<Option<i64>> {
+        let data = self.data.lock().unwrap();
+        Ok(data
+            .iter()
+            .filter(|x| x.currency == currency && x.created_at >= created_since)
+            .map(|x| x.block_number)
+            .min())
+    }
+    fn prune(&self, currency: Currency, below_block_number: i64, created_before: NaiveDateTime) -> RepoResult<u64> {
+        let mut data = self.data.lock().unwrap();
+        let len = data.len();
+        data.retain(|x| x.currency != currency || x.block_number >= below_block_number || x.created_at >= created_before);
+        Ok((len - data.len()) as u64)
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct StrangeBlockchainTransactionsRepoMock {
+    data: Arc<Mutex<Vec<StrangeBlockchainTransactionDB>>>,
+}
+
+impl StrangeBlockchainTransactionsRepo for StrangeBlockchainTransactionsRepoMock {
+    fn create(&self, payload: NewStrangeBlockchainTransactionDB) -> RepoResult<StrangeBlockchainTransactionDB> {
+        let mut data = self.data.lock().unwrap();
+        let res = StrangeBlockchainTransactionDB {
+            hash: payload.hash,
+            from_: payload.from_,
+            to_: payload.to_,
+            block_number: payload.block_number,
+            currency: payload.currency,
+            fee: payload.fee,
+            confirmations: payload.confirmations,
+            created_at: ::chrono::Utc::now().naive_utc(),
+            updated_at: ::chrono::Utc::now().naive_utc(),
+            commentary: payload.commentary,
+            erc20_operation_kind: payload.erc20_operation_kind,
+            violation_kind: payload.violation_kind,
+        };
+        data.push(res.clone());
+        Ok(res)
+    }
+    fn count(&self) -> RepoResult<u64> {
+        let data = self.data.lock().unwrap();
+        Ok(data.len() as u64)
+    }
+    fn get(&self, hash_: BlockchainTransactionId) -> RepoResult<Option<StrangeBlockchainTransactionDB>> {
+        let data = self.data.lock().unwrap();
+        Ok(data.iter().filter(|x| x.hash == hash_).nth(0).cloned())
+    }
+    fn list(
+        &self,
+        offset: i64,
+        limit: i64,
+        currency: Option<Currency>,
+        violation_kind: Option<InvariantViolation>,
+    ) -> RepoResult<Vec<StrangeBlockchainTransactionDB>> {
+        let data = self.data.lock().unwrap();
+        Ok(data
+            .iter()
+            .rev()
+            .filter(|x| currency.map(|currency| x.currency == currency).unwrap_or(true))
+            .filter(|x| {
+                violation_kind
+                    .map(|violation_kind| x.violation_kind == Some(violation_kind))
+                    .unwrap_or(true)
+            })
+            .skip(offset as usize)
+            .take(limit as usize)
+            .cloned()
+            .collect())
+    }
+    fn search_by_hash(&self, hash_prefix: String, limit: i64) -> RepoResult<Vec<StrangeBlockchainTransactionDB>> {
+        let data = self.data.lock().unwrap();
+        Ok(data
+            .iter()
+            .rev()
+            .filter(|x| x.hash.inner().starts_with(&hash_prefix))
+            .take(limit as usize)
+            .cloned()
+            .collect())
+    }
+    fn search_by_address(&self, address: BlockchainAddress, offset: i64, limit: i64) -> RepoResult<Vec<StrangeBlockchainTransactionDB>> {
+        let data = self.data.lock().unwrap();
+        Ok(data
+            .iter()
+            .rev()
+            .filter(|x| {
+                let senders: Vec<BlockchainAddress> = serde_json::from_value(x.from_.clone()).unwrap_or_default();
+                let receivers: Vec<BlockchainTransactionEntryTo> = serde_json::from_value(x.to_.clone()).unwrap_or_default();
+                senders.contains(&address) || receivers.iter().any(|entry| entry.address == address)
+            })
+            .skip(offset as usize)
+            .take(limit as usize)
+            .cloned()
+            .collect())
+    }
+    fn delete(&self, hash_: BlockchainTransactionId) -> RepoResult<Option<StrangeBlockchainTransactionDB>> {
+        let mut data = self.data.lock().unwrap();
+        let res = data.iter().position(|x| x.hash == hash_).map(|idx| data.remove(idx));
+        Ok(res)
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct SmallDepositsRepoMock {
+    data: Arc<Mutex<Vec<SmallDeposit>>>,
+}
+
+impl SmallDepositsRepo for SmallDepositsRepoMock {
+    fn create(&self, payload: NewSmallDeposit) -> RepoResult<SmallDeposit> {
+        let mut data = self.data.lock().unwrap();
+        let res = SmallDeposit {
+            blockchain_tx_id: payload.blockchain_tx_id,
+            account_id: payload.account_id,
+            user_id: payload.user_id,
+            currency: payload.currency,
+            value: payload.value,
             created_at: ::chrono::Utc::now().naive_utc(),
             updated_at: ::chrono::Utc::now().naive_utc(),
         };
         data.push(res.clone());
-        Ok(nonce)
+        Ok(res)
+    }
+    fn list(&self, currency: Option<Currency>, offset: i64, limit: i64) -> RepoResult<Vec<SmallDeposit>> {
+        let data = self.data.lock().unwrap();
+        Ok(data
+            .iter()
+            .rev()
+            .filter(|x| currency.map(|currency| x.currency == currency).unwrap_or(true))
+            .skip(offset as usize)
+            .take(limit as usize)
+            .cloned()
+            .collect())
+    }
+    fn totals(&self) -> RepoResult<Vec<SmallDepositsTotal>> {
+        let data = self.data.lock().unwrap();
+        let mut totals: Vec<SmallDepositsTotal> = vec![];
+        for deposit in data.iter() {
+            match totals.iter_mut().find(|total| total.currency == deposit.currency) {
+                Some(total) => {
+                    total.count += 1;
+                    total.value = total.value.checked_add(deposit.value).unwrap();
+                }
+                None => totals.push(SmallDepositsTotal {
+                    currency: deposit.currency,
+                    count: 1,
+                    value: deposit.value,
+                }),
+            }
+        }
+        Ok(totals)
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct PendingDepositsRepoMock {
+    data: Arc<Mutex<Vec<PendingDeposit>>>,
+}
+
+impl PendingDepositsRepo for PendingDepositsRepoMock {
+    fn upsert(&self, payload: NewPendingDeposit) -> RepoResult<PendingDeposit> {
+        let mut data = self.data.lock().unwrap();
+        if let Some(existing) = data
+            .iter_mut()
+            .find(|x| x.blockchain_tx_id == payload.blockchain_tx_id && x.account_id == payload.account_id)
+        {
+            existing.confirmations = payload.confirmations;
+            existing.required_confirmations = payload.required_confirmations;
+            existing.estimated_completion_at = payload.estimated_completion_at;
+            existing.updated_at = ::chrono::Utc::now().naive_utc();
+            return Ok(existing.clone());
+        }
+        let res = PendingDeposit {
+            id: payload.id,
+            blockchain_tx_id: payload.blockchain_tx_id,
+            account_id: payload.account_id,
+            user_id: payload.user_id,
+            currency: payload.currency,
+            from_: payload.from_,
+            address: payload.address,
+            value: payload.value,
+            confirmations: payload.confirmations,
+            required_confirmations: payload.required_confirmations,
+            created_at: ::chrono::Utc::now().naive_utc(),
+            updated_at: ::chrono::Utc::now().naive_utc(),
+            estimated_completion_at: payload.estimated_completion_at,
+        };
+        data.push(res.clone());
+        Ok(res)
+    }
+    fn delete(&self, blockchain_tx_id: BlockchainTransactionId, account_id: AccountId) -> RepoResult<Option<PendingDeposit>> {
+        let mut data = self.data.lock().unwrap();
+        let position = data
+            .iter()
+            .position(|x| x.blockchain_tx_id == blockchain_tx_id && x.account_id == account_id);
+        Ok(position.map(|i| data.remove(i)))
+    }
+    fn list_for_user(&self, user_id: UserId) -> RepoResult<Vec<PendingDeposit>> {
+        let data = self.data.lock().unwrap();
+        Ok(data.iter().rev().filter(|x| x.user_id == user_id).cloned().collect())
+    }
+    fn delete_stale(&self, updated_before: NaiveDateTime) -> RepoResult<Vec<PendingDeposit>> {
+        let mut data = self.data.lock().unwrap();
+        let (stale, fresh): (Vec<_>, Vec<_>) = data.drain(..).partition(|x| x.updated_at < updated_before);
+        *data = fresh;
+        Ok(stale)
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct QueuedWithdrawalsRepoMock {
+    data: Arc<Mutex<Vec<QueuedWithdrawal>>>,
+}
+
+impl QueuedWithdrawalsRepo for QueuedWithdrawalsRepoMock {
+    fn create(&self, payload: NewQueuedWithdrawal) -> RepoResult<QueuedWithdrawal> {
+        let mut data = self.data.lock().unwrap();
+        let res: QueuedWithdrawal = payload.into();
+        data.push(res.clone());
+        Ok(res)
+    }
+    fn get(&self, id: TransactionId) -> RepoResult<Option<QueuedWithdrawal>> {
+        let data = self.data.lock().unwrap();
+        Ok(data.iter().find(|x| x.id == id).cloned())
+    }
+    fn list_for_user(&self, user_id: UserId, offset: i64, limit: i64) -> RepoResult<Vec<QueuedWithdrawal>> {
+        let data = self.data.lock().unwrap();
+        Ok(data
+            .iter()
+            .rev()
+            .filter(|x| x.user_id == user_id)
+            .skip(offset as usize)
+            .take(limit as usize)
+            .cloned()
+            .collect())
+    }
+    fn take_queued(&self, limit: i64) -> RepoResult<Vec<QueuedWithdrawal>> {
+        let mut data = self.data.lock().unwrap();
+        Ok(data
+            .iter_mut()
+            .filter(|x| x.status == QueuedWithdrawalStatus::Queued)
+            .take(limit as usize)
+            .map(|x| {
+                x.status = QueuedWithdrawalStatus::Processing;
+                x.clone()
+            })
+            .collect())
+    }
+    fn list_stale_processing(&self, updated_before: NaiveDateTime) -> RepoResult<Vec<QueuedWithdrawal>> {
+        let data = self.data.lock().unwrap();
+        Ok(data
+            .iter()
+            .filter(|x| x.status == QueuedWithdrawalStatus::Processing && x.updated_at < updated_before)
+            .cloned()
+            .collect())
+    }
+    fn cancel(&self, id: TransactionId) -> RepoResult<Option<QueuedWithdrawal>> {
+        let mut data = self.data.lock().unwrap();
+        Ok(data
+            .iter_mut()
+            .find(|x| x.id == id && x.status == QueuedWithdrawalStatus::Queued)
+            .map(|x| {
+                x.status = QueuedWithdrawalStatus::Cancelled;
+                x.clone()
+            }))
+    }
+    fn finish(
+        &self,
+        ids: &[TransactionId],
+        status: QueuedWithdrawalStatus,
+        transaction_id: Option<TransactionId>,
+        error_message: Option<String>,
+    ) -> RepoResult<Vec<QueuedWithdrawal>> {
+        let mut data = self.data.lock().unwrap();
+        Ok(data
+            .iter_mut()
+            .filter(|x| ids.contains(&x.id))
+            .map(|x| {
+                x.status = status;
+                x.transaction_id = transaction_id;
+                x.error_message = error_message.clone();
+                x.updated_at = ::chrono::Utc::now().naive_utc();
+                x.clone()
+            })
+            .collect())
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct WithdrawalAddressesRepoMock {
+    data: Arc<Mutex<Vec<WithdrawalAddress>>>,
+}
+
+impl WithdrawalAddressesRepo for WithdrawalAddressesRepoMock {
+    fn create(&self, payload: NewWithdrawalAddress) -> RepoResult<WithdrawalAddress> {
+        let mut data = self.data.lock().unwrap();
+        let res: WithdrawalAddress = payload.into();
+        data.push(res.clone());
+        Ok(res)
+    }
+    fn get(&self, id: Uuid) -> RepoResult<Option<WithdrawalAddress>> {
+        let data = self.data.lock().unwrap();
+        Ok(data.iter().find(|x| x.id == id).cloned())
+    }
+    fn list_for_user(&self, user_id: UserId) -> RepoResult<Vec<WithdrawalAddress>> {
+        let data = self.data.lock().unwrap();
+        Ok(data.iter().rev().filter(|x| x.user_id == user_id).cloned().collect())
+    }
+    fn find(&self, user_id: UserId, currency: Currency, address: BlockchainAddress) -> RepoResult<Option<WithdrawalAddress>> {
+        let data = self.data.lock().unwrap();
+        Ok(data
+            .iter()
+            .find(|x| x.user_id == user_id && x.currency == currency && x.address == address)
+            .cloned())
+    }
+    fn delete(&self, id: Uuid) -> RepoResult<Option<WithdrawalAddress>> {
+        let mut data = self.data.lock().unwrap();
+        let position = data.iter().position(|x| x.id == id);
+        Ok(position.map(|i| data.remove(i)))
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct RateLocksRepoMock {
+    data: Arc<Mutex<Vec<RateLock>>>,
+}
+
+impl RateLocksRepo for RateLocksRepoMock {
+    fn create(&self, payload: NewRateLock) -> RepoResult<RateLock> {
+        let mut data = self.data.lock().unwrap();
+        let res: RateLock = payload.into();
+        data.push(res.clone());
+        Ok(res)
+    }
+    fn get(&self, id: Uuid) -> RepoResult<Option<RateLock>> {
+        let data = self.data.lock().unwrap();
+        Ok(data.iter().find(|x| x.id == id).cloned())
+    }
+    fn use_lock(&self, id: Uuid, now: NaiveDateTime) -> RepoResult<Option<RateLock>> {
+        let mut data = self.data.lock().unwrap();
+        Ok(data
+            .iter_mut()
+            .find(|x| x.id == id && x.used_at.is_none() && x.expires_at > now)
+            .map(|x| {
+                x.used_at = Some(now);
+                x.clone()
+            }))
+    }
+    fn release_lock(&self, id: Uuid) -> RepoResult<Option<RateLock>> {
+        let mut data = self.data.lock().unwrap();
+        Ok(data.iter_mut().find(|x| x.id == id && x.used_at.is_some()).map(|x| {
+            x.used_at = None;
+            x.clone()
+        }))
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct QuarantinedMessagesRepoMock {
+    data: Arc<Mutex<Vec<QuarantinedMessage>>>,
+}
+
+impl QuarantinedMessagesRepo for QuarantinedMessagesRepoMock {
+    fn create(&self, payload: NewQuarantinedMessage) -> RepoResult<QuarantinedMessage> {
+        let mut data = self.data.lock().unwrap();
+        let res: QuarantinedMessage = payload.into();
+        data.push(res.clone());
+        Ok(res)
+    }
+    fn get(&self, id: Uuid) -> RepoResult<Option<QuarantinedMessage>> {
+        let data = self.data.lock().unwrap();
+        Ok(data.iter().find(|x| x.id == id).cloned())
+    }
+    fn list(&self, offset: i64, limit: i64, include_replayed: bool) -> RepoResult<Vec<QuarantinedMessage>> {
+        let data = self.data.lock().unwrap();
+        Ok(data
+            .iter()
+            .filter(|x| include_replayed || x.replayed_at.is_none())
+            .skip(offset as usize)
+            .take(limit as usize)
+            .cloned()
+            .collect())
+    }
+    fn mark_replayed(&self, id: Uuid, now: NaiveDateTime) -> RepoResult<Option<QuarantinedMessage>> {
+        let mut data = self.data.lock().unwrap();
+        Ok(data.iter_mut().find(|x| x.id == id && x.replayed_at.is_none()).map(|x| {
+            x.replayed_at = Some(now);
+            x.clone()
+        }))
     }
 }
 
@@ -468,6 +1307,13 @@ impl DbExecutor for DbExecutorMock {
     {
         Box::new(f().into_future())
     }
+    fn savepoint<F, T, E>(&self, f: F) -> Result<T, E>
+    where
+        F: FnOnce() -> Result<T, E>,
+        E: From<Error> + Fail,
+    {
+        f()
+    }
     fn execute_test_transaction<F, T, E>(&self, f: F) -> Box<Future<Item = T, Error = E> + Send + 'static>
     where
         T: Send + 'static,
@@ -477,3 +1323,16 @@ impl DbExecutor for DbExecutorMock {
         Box::new(f().into_future())
     }
 }
+
+// Same as `@>` of jsonb in postgres
+fn json_contains(value: &serde_json::Value, pattern: &serde_json::Value) -> bool {
+    match (value, pattern) {
+        (serde_json::Value::Object(value), serde_json::Value::Object(pattern)) => pattern
+            .iter()
+            .all(|(key, pattern)| value.get(key).map(|value| json_contains(value, pattern)).unwrap_or(false)),
+        (serde_json::Value::Array(value), serde_json::Value::Array(pattern)) => pattern
+            .iter()
+            .all(|pattern| value.iter().any(|value| json_contains(value, pattern))),
+        _ => value == pattern,
+    }
+}
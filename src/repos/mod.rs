@@ -8,12 +8,20 @@ pub mod key_values;
 #[cfg(test)]
 mod mocks;
 pub mod pending_blockchain_transactions;
+pub mod pending_deposits;
+pub mod pool;
+pub mod quarantined_messages;
+pub mod query_stats;
+pub mod queued_withdrawals;
+pub mod rate_locks;
 pub mod repo;
 pub mod seen_hashes;
+pub mod small_deposits;
 pub mod strange_blockchain_transactions;
 pub mod transactions;
 pub mod types;
 pub mod users;
+pub mod withdrawal_addresses;
 
 pub use self::accounts::*;
 pub use self::blockchain_transactions::*;
@@ -23,9 +31,17 @@ pub use self::key_values::*;
 #[cfg(test)]
 pub use self::mocks::*;
 pub use self::pending_blockchain_transactions::*;
+pub use self::pending_deposits::*;
+pub use self::pool::*;
+pub use self::quarantined_messages::*;
+pub use self::query_stats::*;
+pub use self::queued_withdrawals::*;
+pub use self::rate_locks::*;
 pub use self::repo::*;
 pub use self::seen_hashes::*;
+pub use self::small_deposits::*;
 pub use self::strange_blockchain_transactions::*;
 pub use self::transactions::*;
 pub use self::types::*;
 pub use self::users::*;
+pub use self::withdrawal_addresses::*;
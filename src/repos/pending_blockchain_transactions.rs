@@ -1,5 +1,8 @@
+use std::collections::HashMap;
+
+use chrono::{Duration, NaiveDateTime, Utc};
 use diesel;
-use diesel::dsl::count;
+use diesel::dsl::{any, count};
 
 use super::error::*;
 use super::executor::with_tls_connection;
@@ -7,12 +10,23 @@ use super::*;
 use models::*;
 use prelude::*;
 use schema::pending_blockchain_transactions::dsl::*;
+use schema::transactions::dsl as Transactions;
 
 pub trait PendingBlockchainTransactionsRepo: Send + Sync + 'static {
     fn create(&self, payload: NewPendingBlockchainTransactionDB) -> RepoResult<PendingBlockchainTransactionDB>;
     fn get(&self, hash_: BlockchainTransactionId) -> RepoResult<Option<PendingBlockchainTransactionDB>>;
     fn count(&self) -> RepoResult<u64>;
+    /// Pending transactions created more than `age` ago with their ledger transactions, oldest first
+    fn list_older_than(&self, age: Duration, currency_: Option<Currency>) -> RepoResult<Vec<StalePendingBlockchainTransaction>>;
     fn delete(&self, hash_: BlockchainTransactionId) -> RepoResult<Option<PendingBlockchainTransactionDB>>;
+    /// Transactions that failed to be posted to blockchain gateway and are due for another attempt. Oldest first
+    fn list_due_for_broadcast(&self, limit: i64) -> RepoResult<Vec<PendingBlockchainTransactionDB>>;
+    /// Records failed broadcast attempt, `None` stops further attempts
+    fn reschedule_broadcast(
+        &self,
+        hash_: BlockchainTransactionId,
+        next_broadcast_at_: Option<NaiveDateTime>,
+    ) -> RepoResult<PendingBlockchainTransactionDB>;
 }
 
 #[derive(Clone, Default)]
@@ -20,7 +34,7 @@ pub struct PendingBlockchainTransactionsRepoImpl;
 
 impl PendingBlockchainTransactionsRepo for PendingBlockchainTransactionsRepoImpl {
     fn count(&self) -> RepoResult<u64> {
-        with_tls_connection(|conn| {
+        with_tls_connection("pending_blockchain_transactions.count", |conn| {
             pending_blockchain_transactions
                 .select(count(hash))
                 .first(conn)
@@ -33,7 +47,7 @@ impl PendingBlockchainTransactionsRepo for PendingBlockchainTransactionsRepoImpl
     }
 
     fn create(&self, payload: NewPendingBlockchainTransactionDB) -> RepoResult<PendingBlockchainTransactionDB> {
-        with_tls_connection(|conn| {
+        with_tls_connection("pending_blockchain_transactions.create", |conn| {
             diesel::insert_into(pending_blockchain_transactions)
                 .values(payload.clone())
                 .get_result::<PendingBlockchainTransactionDB>(conn)
@@ -44,7 +58,7 @@ impl PendingBlockchainTransactionsRepo for PendingBlockchainTransactionsRepoImpl
         })
     }
     fn get(&self, hash_: BlockchainTransactionId) -> RepoResult<Option<PendingBlockchainTransactionDB>> {
-        with_tls_connection(|conn| {
+        with_tls_connection("pending_blockchain_transactions.get", |conn| {
             pending_blockchain_transactions
                 .filter(hash.eq(hash_.clone()))
                 .limit(1)
@@ -56,8 +70,42 @@ impl PendingBlockchainTransactionsRepo for PendingBlockchainTransactionsRepoImpl
                 })
         })
     }
+    fn list_older_than(&self, age: Duration, currency_: Option<Currency>) -> RepoResult<Vec<StalePendingBlockchainTransaction>> {
+        with_tls_connection("pending_blockchain_transactions.list_older_than", |conn| {
+            let date = Utc::now().naive_utc() - age;
+            let mut query = pending_blockchain_transactions.filter(created_at.lt(date)).into_boxed();
+            if let Some(currency_) = currency_ {
+                query = query.filter(currency.eq(currency_));
+            }
+            let pending: Vec<PendingBlockchainTransactionDB> = query.order(created_at).get_results(conn).map_err(move |e| {
+                let error_kind = ErrorKind::from(&e);
+                ectx!(try err e, error_kind => date, currency_)
+            })?;
+            let hashes: Vec<_> = pending.iter().map(|pending| pending.hash.clone()).collect();
+            // the earliest ledger transaction is the one blockchain transaction was sent for, e.g. withdrawal and not its fee
+            let ledger_txs: Vec<Transaction> = Transactions::transactions
+                .filter(Transactions::blockchain_tx_id.eq(any(hashes.clone())))
+                .order(Transactions::created_at.desc())
+                .get_results(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(try err e, error_kind => hashes)
+                })?;
+            let mut ledger_txs: HashMap<_, _> = ledger_txs
+                .into_iter()
+                .filter_map(|tx| tx.blockchain_tx_id.clone().map(|hash_| (hash_, tx)))
+                .collect();
+            Ok(pending
+                .into_iter()
+                .map(|pending| StalePendingBlockchainTransaction {
+                    transaction: ledger_txs.remove(&pending.hash),
+                    pending,
+                })
+                .collect())
+        })
+    }
     fn delete(&self, hash_: BlockchainTransactionId) -> RepoResult<Option<PendingBlockchainTransactionDB>> {
-        with_tls_connection(|conn| {
+        with_tls_connection("pending_blockchain_transactions.delete", |conn| {
             let filtered = pending_blockchain_transactions.filter(hash.eq(hash_.clone()));
             diesel::delete(filtered).get_result(conn).optional().map_err(move |e| {
                 let error_kind = ErrorKind::from(&e);
@@ -65,6 +113,39 @@ impl PendingBlockchainTransactionsRepo for PendingBlockchainTransactionsRepoImpl
             })
         })
     }
+    fn list_due_for_broadcast(&self, limit: i64) -> RepoResult<Vec<PendingBlockchainTransactionDB>> {
+        with_tls_connection("pending_blockchain_transactions.list_due_for_broadcast", |conn| {
+            let now = Utc::now().naive_utc();
+            pending_blockchain_transactions
+                .filter(next_broadcast_at.le(now))
+                .order(created_at)
+                .limit(limit)
+                .get_results(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => now, limit)
+                })
+        })
+    }
+    fn reschedule_broadcast(
+        &self,
+        hash_: BlockchainTransactionId,
+        next_broadcast_at_: Option<NaiveDateTime>,
+    ) -> RepoResult<PendingBlockchainTransactionDB> {
+        with_tls_connection("pending_blockchain_transactions.reschedule_broadcast", |conn| {
+            let filtered = pending_blockchain_transactions.filter(hash.eq(hash_.clone()));
+            diesel::update(filtered)
+                .set((
+                    broadcast_attempts.eq(broadcast_attempts + 1),
+                    next_broadcast_at.eq(next_broadcast_at_),
+                ))
+                .get_result(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => hash_, next_broadcast_at_)
+                })
+        })
+    }
 }
 
 #[cfg(test)]
@@ -127,4 +208,52 @@ pub mod tests {
             res
         }));
     }
+
+    #[test]
+    fn pending_blockchain_transactions_reschedule_broadcast() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let pending_blockchain_transactions_repo = PendingBlockchainTransactionsRepoImpl::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let trans = NewPendingBlockchainTransactionDB {
+                hash: BlockchainTransactionId::unbroadcasted(),
+                next_broadcast_at: Some(Utc::now().naive_utc() - Duration::minutes(1)),
+                ..Default::default()
+            };
+            let transaction = pending_blockchain_transactions_repo.create(trans)?;
+            let due = pending_blockchain_transactions_repo.list_due_for_broadcast(10)?;
+            assert!(due.iter().any(|due| due.hash == transaction.hash));
+            let next_broadcast_at_ = Utc::now().naive_utc() + Duration::minutes(1);
+            let rescheduled =
+                pending_blockchain_transactions_repo.reschedule_broadcast(transaction.hash.clone(), Some(next_broadcast_at_))?;
+            assert_eq!(rescheduled.broadcast_attempts, 1);
+            let due = pending_blockchain_transactions_repo.list_due_for_broadcast(10)?;
+            assert!(due.iter().all(|due| due.hash != transaction.hash));
+            Ok::<_, Error>(())
+        }));
+    }
+
+    #[test]
+    fn pending_blockchain_transactions_list_older_than() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let pending_blockchain_transactions_repo = PendingBlockchainTransactionsRepoImpl::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let trans = NewPendingBlockchainTransactionDB {
+                currency: Currency::Btc,
+                ..Default::default()
+            };
+            let transaction = pending_blockchain_transactions_repo.create(trans)?;
+            // negative age lists the ones just created
+            let stale = pending_blockchain_transactions_repo.list_older_than(Duration::minutes(-1), Some(Currency::Btc))?;
+            let stale = stale.into_iter().find(|stale| stale.pending.hash == transaction.hash).unwrap();
+            assert_eq!(stale.created_at(), transaction.created_at);
+            assert!(stale.transaction.is_none());
+            let stale = pending_blockchain_transactions_repo.list_older_than(Duration::minutes(-1), Some(Currency::Eth))?;
+            assert!(stale.iter().all(|stale| stale.pending.hash != transaction.hash));
+            let stale = pending_blockchain_transactions_repo.list_older_than(Duration::minutes(1), None)?;
+            assert!(stale.iter().all(|stale| stale.pending.hash != transaction.hash));
+            Ok::<_, Error>(())
+        }));
+    }
 }
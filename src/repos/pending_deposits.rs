@@ -0,0 +1,166 @@
+use chrono::NaiveDateTime;
+use diesel;
+
+use super::error::*;
+use super::executor::with_tls_connection;
+use super::*;
+use models::*;
+use prelude::*;
+use schema::pending_deposits::dsl::*;
+
+pub trait PendingDepositsRepo: Send + Sync + 'static {
+    /// Creates pending deposit or updates confirmations of the existing one, keeping its id
+    fn upsert(&self, payload: NewPendingDeposit) -> RepoResult<PendingDeposit>;
+    fn delete(&self, blockchain_tx_id_: BlockchainTransactionId, account_id_: AccountId) -> RepoResult<Option<PendingDeposit>>;
+    /// Newest first
+    fn list_for_user(&self, user_id_: UserId) -> RepoResult<Vec<PendingDeposit>>;
+    /// Deletes deposits that got no new confirmations since `updated_before`
+    fn delete_stale(&self, updated_before: NaiveDateTime) -> RepoResult<Vec<PendingDeposit>>;
+}
+
+#[derive(Clone, Default)]
+pub struct PendingDepositsRepoImpl;
+
+impl PendingDepositsRepo for PendingDepositsRepoImpl {
+    fn upsert(&self, payload: NewPendingDeposit) -> RepoResult<PendingDeposit> {
+        with_tls_connection("pending_deposits.upsert", |conn| {
+            diesel::insert_into(pending_deposits)
+                .values(payload.clone())
+                .on_conflict((blockchain_tx_id, account_id))
+                .do_update()
+                .set((
+                    confirmations.eq(payload.confirmations),
+                    required_confirmations.eq(payload.required_confirmations),
+                    estimated_completion_at.eq(payload.estimated_completion_at),
+                ))
+                .get_result::<PendingDeposit>(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => payload)
+                })
+        })
+    }
+
+    fn delete(&self, blockchain_tx_id_: BlockchainTransactionId, account_id_: AccountId) -> RepoResult<Option<PendingDeposit>> {
+        with_tls_connection("pending_deposits.delete", |conn| {
+            let filtered = pending_deposits
+                .filter(blockchain_tx_id.eq(blockchain_tx_id_.clone()))
+                .filter(account_id.eq(account_id_));
+            diesel::delete(filtered)
+                .get_result::<PendingDeposit>(conn)
+                .optional()
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => blockchain_tx_id_, account_id_)
+                })
+        })
+    }
+
+    fn list_for_user(&self, user_id_: UserId) -> RepoResult<Vec<PendingDeposit>> {
+        with_tls_connection("pending_deposits.list_for_user", |conn| {
+            pending_deposits
+                .filter(user_id.eq(user_id_))
+                .order((created_at.desc(), id.desc()))
+                .get_results(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => user_id_)
+                })
+        })
+    }
+
+    fn delete_stale(&self, updated_before: NaiveDateTime) -> RepoResult<Vec<PendingDeposit>> {
+        with_tls_connection("pending_deposits.delete_stale", |conn| {
+            let filtered = pending_deposits.filter(updated_at.lt(updated_before));
+            diesel::delete(filtered).get_results(conn).map_err(move |e| {
+                let error_kind = ErrorKind::from(&e);
+                ectx!(err e, error_kind => updated_before)
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use diesel::r2d2::ConnectionManager;
+    use diesel::PgConnection;
+    use futures_cpupool::CpuPool;
+    use r2d2;
+    use tokio_core::reactor::Core;
+
+    use super::*;
+    use config::Config;
+    use repos::DbExecutorImpl;
+
+    fn create_executor() -> DbExecutorImpl {
+        let config = Config::new().unwrap();
+        let manager = ConnectionManager::<PgConnection>::new(config.database.url);
+        let db_pool = r2d2::Pool::builder().build(manager).unwrap();
+        let cpu_pool = CpuPool::new(1);
+        DbExecutorImpl::new(db_pool.clone(), cpu_pool.clone())
+    }
+
+    #[test]
+    fn pending_deposits_upsert_and_delete() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let users_repo = UsersRepoImpl::default();
+        let accounts_repo = AccountsRepoImpl::default();
+        let pending_deposits_repo = PendingDepositsRepoImpl::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let user = users_repo.create(NewUser::default())?;
+            let account = accounts_repo.create(NewAccount {
+                user_id: user.id,
+                ..Default::default()
+            })?;
+            let new_deposit = NewPendingDeposit {
+                account_id: account.id,
+                user_id: user.id,
+                confirmations: 1,
+                required_confirmations: 12,
+                ..Default::default()
+            };
+            let created = pending_deposits_repo.upsert(new_deposit.clone())?;
+            // the same deposit seen again keeps its id
+            let updated = pending_deposits_repo.upsert(NewPendingDeposit {
+                id: TransactionId::generate(),
+                confirmations: 5,
+                ..new_deposit.clone()
+            })?;
+            assert_eq!(updated.id, created.id);
+            assert_eq!(updated.confirmations, 5);
+            assert_eq!(pending_deposits_repo.list_for_user(user.id)?.len(), 1);
+            let deleted = pending_deposits_repo.delete(new_deposit.blockchain_tx_id.clone(), account.id)?;
+            assert_eq!(deleted.map(|deposit| deposit.id), Some(created.id));
+            assert!(pending_deposits_repo.list_for_user(user.id)?.is_empty());
+            Ok::<_, Error>(())
+        }));
+    }
+
+    #[test]
+    fn pending_deposits_delete_stale() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let users_repo = UsersRepoImpl::default();
+        let accounts_repo = AccountsRepoImpl::default();
+        let pending_deposits_repo = PendingDepositsRepoImpl::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let user = users_repo.create(NewUser::default())?;
+            let account = accounts_repo.create(NewAccount {
+                user_id: user.id,
+                ..Default::default()
+            })?;
+            let created = pending_deposits_repo.upsert(NewPendingDeposit {
+                account_id: account.id,
+                user_id: user.id,
+                ..Default::default()
+            })?;
+            let deleted = pending_deposits_repo.delete_stale(created.updated_at)?;
+            assert!(deleted.is_empty());
+            let deleted = pending_deposits_repo.delete_stale(created.updated_at + ::chrono::Duration::seconds(1))?;
+            assert_eq!(deleted.into_iter().map(|deposit| deposit.id).collect::<Vec<_>>(), vec![created.id]);
+            assert!(pending_deposits_repo.list_for_user(user.id)?.is_empty());
+            Ok::<_, Error>(())
+        }));
+    }
+}
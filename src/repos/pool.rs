@@ -0,0 +1,123 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use diesel::pg::PgConnection;
+use diesel::r2d2::ConnectionManager;
+use r2d2;
+use r2d2::event::{CheckoutEvent, HandleEvent, TimeoutEvent};
+
+use super::error::*;
+use config::Database;
+use models::PoolMetrics;
+use prelude::*;
+
+/// Counters of connection checkouts, updated by r2d2 events
+#[derive(Debug, Default)]
+pub struct PoolStats {
+    checkouts_count: AtomicUsize,
+    timeouts_count: AtomicUsize,
+    total_wait_micros: AtomicUsize,
+    max_wait_micros: AtomicUsize,
+}
+
+#[derive(Debug)]
+struct PoolStatsHandler(Arc<PoolStats>);
+
+impl HandleEvent for PoolStatsHandler {
+    fn handle_checkout(&self, event: &CheckoutEvent) {
+        let wait_micros = duration_to_micros(event.duration());
+        self.0.checkouts_count.fetch_add(1, Ordering::Relaxed);
+        self.0.total_wait_micros.fetch_add(wait_micros, Ordering::Relaxed);
+        let mut max = self.0.max_wait_micros.load(Ordering::Relaxed);
+        while wait_micros > max {
+            match self
+                .0
+                .max_wait_micros
+                .compare_exchange_weak(max, wait_micros, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => break,
+                Err(current) => max = current,
+            }
+        }
+    }
+
+    fn handle_timeout(&self, _event: &TimeoutEvent) {
+        self.0.timeouts_count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Db pool together with its statistics, used for reporting in metrics
+#[derive(Clone)]
+pub struct MonitoredPool {
+    pub name: String,
+    pub pool: PgPool,
+    stats: Arc<PoolStats>,
+}
+
+impl MonitoredPool {
+    /// Creates pool using tuning options from config. Unset options have r2d2 defaults.
+    /// Fails if db is unreachable.
+    pub fn create(name: &str, url: &str, options: &Database) -> Result<Self, Error> {
+        let stats = Arc::new(PoolStats::default());
+        let manager = ConnectionManager::<PgConnection>::new(url.to_string());
+        let pool = pool_builder(options, stats.clone())
+            .build(manager)
+            .map_err(ectx!(try ErrorSource::R2D2, ErrorKind::Internal => name, url))?;
+        Ok(Self {
+            name: name.to_string(),
+            pool,
+            stats,
+        })
+    }
+
+    /// Same as `create`, but doesn't establish connections until they are needed
+    pub fn create_unchecked(name: &str, url: &str, options: &Database) -> Self {
+        let stats = Arc::new(PoolStats::default());
+        let manager = ConnectionManager::<PgConnection>::new(url.to_string());
+        let pool = pool_builder(options, stats.clone()).build_unchecked(manager);
+        Self {
+            name: name.to_string(),
+            pool,
+            stats,
+        }
+    }
+
+    pub fn metrics(&self) -> PoolMetrics {
+        let state = self.pool.state();
+        let checkouts_count = self.stats.checkouts_count.load(Ordering::Relaxed) as u64;
+        let total_wait_micros = self.stats.total_wait_micros.load(Ordering::Relaxed) as u64;
+        PoolMetrics {
+            max_size: self.pool.max_size(),
+            connections: state.connections,
+            idle_connections: state.idle_connections,
+            in_use_connections: state.connections - state.idle_connections,
+            checkouts_count,
+            timeouts_count: self.stats.timeouts_count.load(Ordering::Relaxed) as u64,
+            avg_wait_ms: if checkouts_count == 0 {
+                0.0
+            } else {
+                total_wait_micros as f64 / checkouts_count as f64 / 1000.0
+            },
+            max_wait_ms: self.stats.max_wait_micros.load(Ordering::Relaxed) as f64 / 1000.0,
+        }
+    }
+}
+
+fn pool_builder(options: &Database, stats: Arc<PoolStats>) -> r2d2::Builder<ConnectionManager<PgConnection>> {
+    let mut builder = r2d2::Pool::builder()
+        .max_size(options.pool_max_size())
+        .min_idle(options.min_idle)
+        .event_handler(Box::new(PoolStatsHandler(stats)));
+    if let Some(connection_timeout_secs) = options.connection_timeout_secs {
+        builder = builder.connection_timeout(Duration::from_secs(connection_timeout_secs));
+    }
+    if let Some(max_lifetime_secs) = options.max_lifetime_secs {
+        builder = builder.max_lifetime(Some(Duration::from_secs(max_lifetime_secs)));
+    }
+    builder
+}
+
+fn duration_to_micros(duration: Duration) -> usize {
+    (duration.as_secs() as usize) * 1_000_000 + (duration.subsec_micros() as usize)
+}
@@ -0,0 +1,129 @@
+use chrono::NaiveDateTime;
+use diesel;
+use uuid::Uuid;
+
+use super::error::*;
+use super::executor::with_tls_connection;
+use super::*;
+use models::*;
+use prelude::*;
+use schema::quarantined_messages::dsl::*;
+
+pub trait QuarantinedMessagesRepo: Send + Sync + 'static {
+    fn create(&self, payload: NewQuarantinedMessage) -> RepoResult<QuarantinedMessage>;
+    fn get(&self, id_: Uuid) -> RepoResult<Option<QuarantinedMessage>>;
+    /// Oldest first, replayed ones are skipped unless `include_replayed` is set
+    fn list(&self, offset: i64, limit: i64, include_replayed: bool) -> RepoResult<Vec<QuarantinedMessage>>;
+    /// Marks the message as replayed at `now`. Returns `None` if it's already replayed
+    fn mark_replayed(&self, id_: Uuid, now: NaiveDateTime) -> RepoResult<Option<QuarantinedMessage>>;
+}
+
+#[derive(Clone, Default)]
+pub struct QuarantinedMessagesRepoImpl;
+
+impl QuarantinedMessagesRepo for QuarantinedMessagesRepoImpl {
+    fn create(&self, payload: NewQuarantinedMessage) -> RepoResult<QuarantinedMessage> {
+        with_tls_connection("quarantined_messages.create", |conn| {
+            diesel::insert_into(quarantined_messages)
+                .values(payload.clone())
+                .get_result::<QuarantinedMessage>(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => payload)
+                })
+        })
+    }
+
+    fn get(&self, id_: Uuid) -> RepoResult<Option<QuarantinedMessage>> {
+        with_tls_connection("quarantined_messages.get", |conn| {
+            quarantined_messages
+                .filter(id.eq(id_))
+                .get_result(conn)
+                .optional()
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => id_)
+                })
+        })
+    }
+
+    fn list(&self, offset: i64, limit: i64, include_replayed: bool) -> RepoResult<Vec<QuarantinedMessage>> {
+        with_tls_connection("quarantined_messages.list", |conn| {
+            let mut query = quarantined_messages.into_boxed();
+            if !include_replayed {
+                query = query.filter(replayed_at.is_null());
+            }
+            query
+                .order((created_at, id))
+                .offset(offset)
+                .limit(limit)
+                .get_results(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => offset, limit, include_replayed)
+                })
+        })
+    }
+
+    fn mark_replayed(&self, id_: Uuid, now: NaiveDateTime) -> RepoResult<Option<QuarantinedMessage>> {
+        with_tls_connection("quarantined_messages.mark_replayed", |conn| {
+            diesel::update(quarantined_messages.filter(id.eq(id_)).filter(replayed_at.is_null()))
+                .set(replayed_at.eq(now))
+                .get_result(conn)
+                .optional()
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => id_, now)
+                })
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use diesel::r2d2::ConnectionManager;
+    use diesel::PgConnection;
+    use futures_cpupool::CpuPool;
+    use r2d2;
+    use tokio_core::reactor::Core;
+
+    use super::*;
+    use config::Config;
+    use repos::DbExecutorImpl;
+
+    fn create_executor() -> DbExecutorImpl {
+        let config = Config::new().unwrap();
+        let manager = ConnectionManager::<PgConnection>::new(config.database.url);
+        let db_pool = r2d2::Pool::builder().build(manager).unwrap();
+        let cpu_pool = CpuPool::new(1);
+        DbExecutorImpl::new(db_pool.clone(), cpu_pool.clone())
+    }
+
+    #[test]
+    fn quarantined_messages_mark_replayed() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let quarantined_messages_repo = QuarantinedMessagesRepoImpl::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let message = quarantined_messages_repo.create(NewQuarantinedMessage {
+                payload: b"{}".to_vec(),
+                error: "handling error".to_string(),
+                attempts: 5,
+                ..Default::default()
+            })?;
+            assert_eq!(message.payload, b"{}".to_vec());
+            let listed = quarantined_messages_repo.list(0, 100, false)?;
+            assert!(listed.iter().any(|listed| listed.id == message.id));
+            let now = ::chrono::Utc::now().naive_utc();
+            let replayed = quarantined_messages_repo.mark_replayed(message.id, now)?.unwrap();
+            assert_eq!(replayed.replayed_at, Some(now));
+            // can't be replayed twice
+            assert!(quarantined_messages_repo.mark_replayed(message.id, now)?.is_none());
+            let listed = quarantined_messages_repo.list(0, 100, false)?;
+            assert!(listed.iter().all(|listed| listed.id != message.id));
+            let listed = quarantined_messages_repo.list(0, 100, true)?;
+            assert!(listed.iter().any(|listed| listed.id == message.id));
+            Ok::<_, Error>(())
+        }));
+    }
+}
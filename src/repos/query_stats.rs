@@ -0,0 +1,260 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::error::*;
+use config::Database;
+use models::*;
+
+/// Calls slower than that are logged, unless `database.slow_query_ms` is set
+pub const DEFAULT_SLOW_QUERY_MS: u64 = 1000;
+
+thread_local! {
+    // stats of the executor running on this thread and the number of attempt of its transaction
+    static CURRENT_STATS: RefCell<Option<(Arc<QueryStats>, u32)>> = RefCell::new(None)
+}
+
+/// Number of rows that repo call returned or changed, as far as it can be told by the result
+pub trait RowCount {
+    fn row_count(&self) -> u64;
+}
+
+impl<T> RowCount for Vec<T> {
+    fn row_count(&self) -> u64 {
+        self.len() as u64
+    }
+}
+
+impl<T> RowCount for Option<T> {
+    fn row_count(&self) -> u64 {
+        if self.is_some() {
+            1
+        } else {
+            0
+        }
+    }
+}
+
+impl<K, V, S> RowCount for HashMap<K, V, S> {
+    fn row_count(&self) -> u64 {
+        self.len() as u64
+    }
+}
+
+impl RowCount for () {
+    fn row_count(&self) -> u64 {
+        0
+    }
+}
+
+// counts of changed or matching rows
+impl RowCount for u64 {
+    fn row_count(&self) -> u64 {
+        *self
+    }
+}
+
+impl RowCount for usize {
+    fn row_count(&self) -> u64 {
+        *self as u64
+    }
+}
+
+impl RowCount for TransactionGroupTree {
+    fn row_count(&self) -> u64 {
+        self.transactions.len() as u64 + self.related.iter().map(|tree| tree.row_count()).sum::<u64>()
+    }
+}
+
+macro_rules! impl_single_row {
+    ($($t:ty),*) => {
+        $(
+            impl RowCount for $t {
+                fn row_count(&self) -> u64 {
+                    1
+                }
+            }
+        )*
+    };
+}
+
+impl_single_row!(
+    Account,
+    AccountStats,
+    Amount,
+    BlockchainTransactionDB,
+    KeyValue,
+    PendingBlockchainTransactionDB,
+    PendingDeposit,
+    QuarantinedMessage,
+    QueuedWithdrawal,
+    RateLock,
+    SeenHashes,
+    SmallDeposit,
+    StrangeBlockchainTransactionDB,
+    Transaction,
+    User,
+    WithdrawalAddress
+);
+
+#[derive(Debug, Clone, Default)]
+struct MethodStats {
+    calls_count: u64,
+    errors_count: u64,
+    retried_calls_count: u64,
+    slow_calls_count: u64,
+    rows_count: u64,
+    total_duration: Duration,
+    max_duration: Duration,
+}
+
+/// Durations, rows and retries of repo calls by repo method, shared by db executors
+#[derive(Debug)]
+pub struct QueryStats {
+    slow_query_threshold: Duration,
+    methods: Mutex<HashMap<&'static str, MethodStats>>,
+}
+
+impl QueryStats {
+    pub fn new(options: &Database) -> Self {
+        Self {
+            slow_query_threshold: Duration::from_millis(options.slow_query_ms.unwrap_or(DEFAULT_SLOW_QUERY_MS)),
+            methods: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn record(&self, method: &'static str, duration: Duration, rows: Option<u64>, attempt: u32) {
+        let is_slow = duration >= self.slow_query_threshold;
+        if is_slow {
+            warn!(
+                "Slow repo call {}: {} ms, rows: {}, attempt: {}",
+                method,
+                duration_to_millis(duration),
+                rows.map(|rows| rows.to_string()).unwrap_or_else(|| "error".to_string()),
+                attempt
+            );
+        }
+        let mut methods = self.methods.lock().unwrap();
+        let stats = methods.entry(method).or_insert_with(MethodStats::default);
+        stats.calls_count += 1;
+        match rows {
+            Some(rows) => stats.rows_count += rows,
+            None => stats.errors_count += 1,
+        }
+        if attempt > 1 {
+            stats.retried_calls_count += 1;
+        }
+        if is_slow {
+            stats.slow_calls_count += 1;
+        }
+        stats.total_duration += duration;
+        if duration > stats.max_duration {
+            stats.max_duration = duration;
+        }
+    }
+
+    pub fn metrics(&self) -> HashMap<String, QueryMetrics> {
+        let methods = self.methods.lock().unwrap();
+        methods
+            .iter()
+            .map(|(method, stats)| {
+                let metrics = QueryMetrics {
+                    calls_count: stats.calls_count,
+                    errors_count: stats.errors_count,
+                    retried_calls_count: stats.retried_calls_count,
+                    slow_calls_count: stats.slow_calls_count,
+                    rows_count: stats.rows_count,
+                    avg_duration_ms: duration_to_millis(stats.total_duration) / stats.calls_count as f64,
+                    max_duration_ms: duration_to_millis(stats.max_duration),
+                };
+                (method.to_string(), metrics)
+            })
+            .collect()
+    }
+}
+
+/// Runs `f` with repo calls recorded to `stats`, if any
+pub fn query_stats_scope<T, F: FnOnce() -> T>(stats: Option<Arc<QueryStats>>, f: F) -> T {
+    let _guard = StatsGuard {
+        previous: CURRENT_STATS.with(|current| current.replace(stats.map(|stats| (stats, 1)))),
+    };
+    f()
+}
+
+/// Repo calls made after that are recorded as made by `attempt` of transaction
+pub fn set_query_attempt(attempt: u32) {
+    CURRENT_STATS.with(|current| {
+        if let Some((_, ref mut current_attempt)) = *current.borrow_mut() {
+            *current_attempt = attempt;
+        }
+    });
+}
+
+pub(crate) fn record_repo_call<T: RowCount>(method: &'static str, started_at: Instant, res: &Result<T, Error>) {
+    let duration = started_at.elapsed();
+    CURRENT_STATS.with(|current| {
+        if let Some((ref stats, attempt)) = *current.borrow() {
+            let rows = res.as_ref().ok().map(|value| value.row_count());
+            stats.record(method, duration, rows, attempt);
+        }
+    });
+}
+
+// restores previous stats even if `f` panics
+struct StatsGuard {
+    previous: Option<(Arc<QueryStats>, u32)>,
+}
+
+impl Drop for StatsGuard {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CURRENT_STATS.with(|current| current.replace(previous));
+    }
+}
+
+fn duration_to_millis(duration: Duration) -> f64 {
+    duration.as_secs() as f64 * 1000.0 + f64::from(duration.subsec_micros()) / 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn database(slow_query_ms: u64) -> Database {
+        Database {
+            url: String::new(),
+            replica_url: None,
+            max_size: None,
+            min_idle: None,
+            connection_timeout_secs: None,
+            max_lifetime_secs: None,
+            slow_query_ms: Some(slow_query_ms),
+        }
+    }
+
+    #[test]
+    fn test_record_repo_call() {
+        let stats = Arc::new(QueryStats::new(&database(0)));
+        // calls outside of executor are not recorded
+        record_repo_call("accounts.get", Instant::now(), &Ok(Some(Account::default())));
+        query_stats_scope(Some(stats.clone()), || {
+            record_repo_call(
+                "accounts.list_for_user",
+                Instant::now(),
+                &Ok(vec![Account::default(), Account::default()]),
+            );
+            set_query_attempt(2);
+            record_repo_call::<Vec<Account>>("accounts.list_for_user", Instant::now(), &Err(ErrorKind::Internal.into()));
+        });
+        record_repo_call("accounts.list_for_user", Instant::now(), &Ok(vec![Account::default()]));
+        let metrics = stats.metrics();
+        assert_eq!(metrics.len(), 1);
+        let list_metrics = &metrics["accounts.list_for_user"];
+        assert_eq!(list_metrics.calls_count, 2);
+        assert_eq!(list_metrics.errors_count, 1);
+        assert_eq!(list_metrics.retried_calls_count, 1);
+        assert_eq!(list_metrics.slow_calls_count, 2);
+        assert_eq!(list_metrics.rows_count, 2);
+    }
+}
@@ -0,0 +1,246 @@
+use chrono::NaiveDateTime;
+use diesel;
+
+use super::error::*;
+use super::executor::with_tls_connection;
+use super::*;
+use models::*;
+use prelude::*;
+use schema::queued_withdrawals::dsl::*;
+
+pub trait QueuedWithdrawalsRepo: Send + Sync + 'static {
+    fn create(&self, payload: NewQueuedWithdrawal) -> RepoResult<QueuedWithdrawal>;
+    fn get(&self, id_: TransactionId) -> RepoResult<Option<QueuedWithdrawal>>;
+    /// Newest first
+    fn list_for_user(&self, user_id_: UserId, offset: i64, limit: i64) -> RepoResult<Vec<QueuedWithdrawal>>;
+    /// Marks up to `limit` oldest queued withdrawals as processing, so that they can't be cancelled
+    /// or taken by another drain. Oldest first
+    fn take_queued(&self, limit: i64) -> RepoResult<Vec<QueuedWithdrawal>>;
+    /// Processing withdrawals not updated since `updated_before`, locked until the end of transaction
+    fn list_stale_processing(&self, updated_before: NaiveDateTime) -> RepoResult<Vec<QueuedWithdrawal>>;
+    /// Returns `None` if the withdrawal is not queued anymore
+    fn cancel(&self, id_: TransactionId) -> RepoResult<Option<QueuedWithdrawal>>;
+    fn finish(
+        &self,
+        ids: &[TransactionId],
+        status_: QueuedWithdrawalStatus,
+        transaction_id_: Option<TransactionId>,
+        error_message_: Option<String>,
+    ) -> RepoResult<Vec<QueuedWithdrawal>>;
+}
+
+#[derive(Clone, Default)]
+pub struct QueuedWithdrawalsRepoImpl;
+
+impl QueuedWithdrawalsRepo for QueuedWithdrawalsRepoImpl {
+    fn create(&self, payload: NewQueuedWithdrawal) -> RepoResult<QueuedWithdrawal> {
+        with_tls_connection("queued_withdrawals.create", |conn| {
+            diesel::insert_into(queued_withdrawals)
+                .values(payload.clone())
+                .get_result::<QueuedWithdrawal>(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => payload)
+                })
+        })
+    }
+
+    fn get(&self, id_: TransactionId) -> RepoResult<Option<QueuedWithdrawal>> {
+        with_tls_connection("queued_withdrawals.get", |conn| {
+            queued_withdrawals.filter(id.eq(id_)).get_result(conn).optional().map_err(move |e| {
+                let error_kind = ErrorKind::from(&e);
+                ectx!(err e, error_kind => id_)
+            })
+        })
+    }
+
+    fn list_for_user(&self, user_id_: UserId, offset: i64, limit: i64) -> RepoResult<Vec<QueuedWithdrawal>> {
+        with_tls_connection("queued_withdrawals.list_for_user", |conn| {
+            queued_withdrawals
+                .filter(user_id.eq(user_id_))
+                .order((created_at.desc(), id.desc()))
+                .offset(offset)
+                .limit(limit)
+                .get_results(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => user_id_, offset, limit)
+                })
+        })
+    }
+
+    fn take_queued(&self, limit: i64) -> RepoResult<Vec<QueuedWithdrawal>> {
+        with_tls_connection("queued_withdrawals.take_queued", |conn| {
+            let ids: Vec<TransactionId> = queued_withdrawals
+                .filter(status.eq(QueuedWithdrawalStatus::Queued))
+                .order((created_at, id))
+                .limit(limit)
+                .select(id)
+                .for_update()
+                .get_results(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => limit)
+                })?;
+            let mut taken: Vec<QueuedWithdrawal> = diesel::update(queued_withdrawals.filter(id.eq_any(ids.clone())))
+                .set(status.eq(QueuedWithdrawalStatus::Processing))
+                .get_results(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => ids)
+                })?;
+            taken.sort_by_key(|withdrawal| withdrawal.created_at);
+            Ok(taken)
+        })
+    }
+
+    fn list_stale_processing(&self, updated_before: NaiveDateTime) -> RepoResult<Vec<QueuedWithdrawal>> {
+        with_tls_connection("queued_withdrawals.list_stale_processing", |conn| {
+            queued_withdrawals
+                .filter(status.eq(QueuedWithdrawalStatus::Processing))
+                .filter(updated_at.lt(updated_before))
+                .order((created_at, id))
+                .for_update()
+                .get_results(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => updated_before)
+                })
+        })
+    }
+
+    fn cancel(&self, id_: TransactionId) -> RepoResult<Option<QueuedWithdrawal>> {
+        with_tls_connection("queued_withdrawals.cancel", |conn| {
+            let filtered = queued_withdrawals
+                .filter(id.eq(id_))
+                .filter(status.eq(QueuedWithdrawalStatus::Queued));
+            diesel::update(filtered)
+                .set(status.eq(QueuedWithdrawalStatus::Cancelled))
+                .get_result(conn)
+                .optional()
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => id_)
+                })
+        })
+    }
+
+    fn finish(
+        &self,
+        ids: &[TransactionId],
+        status_: QueuedWithdrawalStatus,
+        transaction_id_: Option<TransactionId>,
+        error_message_: Option<String>,
+    ) -> RepoResult<Vec<QueuedWithdrawal>> {
+        with_tls_connection("queued_withdrawals.finish", |conn| {
+            let ids = ids.to_vec();
+            diesel::update(queued_withdrawals.filter(id.eq_any(ids.clone())))
+                .set((
+                    status.eq(status_),
+                    transaction_id.eq(transaction_id_),
+                    error_message.eq(error_message_.clone()),
+                ))
+                .get_results(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => ids, status_, transaction_id_, error_message_)
+                })
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use diesel::r2d2::ConnectionManager;
+    use diesel::PgConnection;
+    use futures_cpupool::CpuPool;
+    use r2d2;
+    use tokio_core::reactor::Core;
+
+    use super::*;
+    use config::Config;
+    use repos::DbExecutorImpl;
+
+    fn create_executor() -> DbExecutorImpl {
+        let config = Config::new().unwrap();
+        let manager = ConnectionManager::<PgConnection>::new(config.database.url);
+        let db_pool = r2d2::Pool::builder().build(manager).unwrap();
+        let cpu_pool = CpuPool::new(1);
+        DbExecutorImpl::new(db_pool.clone(), cpu_pool.clone())
+    }
+
+    #[test]
+    fn queued_withdrawals_take_and_cancel() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let users_repo = UsersRepoImpl::default();
+        let accounts_repo = AccountsRepoImpl::default();
+        let queued_withdrawals_repo = QueuedWithdrawalsRepoImpl::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let user = users_repo.create(NewUser::default())?;
+            let account = accounts_repo.create(NewAccount {
+                user_id: user.id,
+                ..Default::default()
+            })?;
+            let first = queued_withdrawals_repo.create(NewQueuedWithdrawal {
+                user_id: user.id,
+                account_id: account.id,
+                ..Default::default()
+            })?;
+            let second = queued_withdrawals_repo.create(NewQueuedWithdrawal {
+                user_id: user.id,
+                account_id: account.id,
+                ..Default::default()
+            })?;
+            let cancelled = queued_withdrawals_repo.cancel(second.id)?.unwrap();
+            assert_eq!(cancelled.status, QueuedWithdrawalStatus::Cancelled);
+            // cancelled withdrawal is not taken and can't be cancelled again
+            let taken = queued_withdrawals_repo.take_queued(10)?;
+            assert_eq!(taken.iter().map(|withdrawal| withdrawal.id).collect::<Vec<_>>(), vec![first.id]);
+            assert!(queued_withdrawals_repo.cancel(second.id)?.is_none());
+            // the one being processed can't be cancelled either
+            assert!(queued_withdrawals_repo.cancel(first.id)?.is_none());
+            let tx_id = TransactionId::generate();
+            let finished = queued_withdrawals_repo.finish(&[first.id], QueuedWithdrawalStatus::Done, Some(tx_id), None)?;
+            assert_eq!(finished[0].transaction_id, Some(tx_id));
+            assert_eq!(queued_withdrawals_repo.list_for_user(user.id, 0, 10)?.len(), 2);
+            Ok::<_, Error>(())
+        }));
+    }
+
+    #[test]
+    fn queued_withdrawals_list_stale_processing() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let users_repo = UsersRepoImpl::default();
+        let accounts_repo = AccountsRepoImpl::default();
+        let queued_withdrawals_repo = QueuedWithdrawalsRepoImpl::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let user = users_repo.create(NewUser::default())?;
+            let account = accounts_repo.create(NewAccount {
+                user_id: user.id,
+                ..Default::default()
+            })?;
+            // queued withdrawal is never stale
+            let _ = queued_withdrawals_repo.create(NewQueuedWithdrawal {
+                user_id: user.id,
+                account_id: account.id,
+                ..Default::default()
+            })?;
+            let processing = queued_withdrawals_repo.create(NewQueuedWithdrawal {
+                user_id: user.id,
+                account_id: account.id,
+                ..Default::default()
+            })?;
+            queued_withdrawals_repo.finish(&[processing.id], QueuedWithdrawalStatus::Processing, None, None)?;
+            let later = ::chrono::Utc::now().naive_utc() + ::chrono::Duration::hours(1);
+            let stale = queued_withdrawals_repo.list_stale_processing(later)?;
+            assert_eq!(
+                stale.iter().map(|withdrawal| withdrawal.id).collect::<Vec<_>>(),
+                vec![processing.id]
+            );
+            assert!(queued_withdrawals_repo.list_stale_processing(processing.created_at)?.is_empty());
+            Ok::<_, Error>(())
+        }));
+    }
+}
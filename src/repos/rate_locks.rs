@@ -0,0 +1,124 @@
+use chrono::NaiveDateTime;
+use diesel;
+use uuid::Uuid;
+
+use super::error::*;
+use super::executor::with_tls_connection;
+use super::*;
+use models::*;
+use prelude::*;
+use schema::rate_locks::dsl::*;
+
+pub trait RateLocksRepo: Send + Sync + 'static {
+    fn create(&self, payload: NewRateLock) -> RepoResult<RateLock>;
+    fn get(&self, id_: Uuid) -> RepoResult<Option<RateLock>>;
+    /// Marks the lock as used at `now`. Returns `None` if it's already used or expired by that time
+    fn use_lock(&self, id_: Uuid, now: NaiveDateTime) -> RepoResult<Option<RateLock>>;
+    /// Makes the used lock usable again, e.g. when the exchange it was used for has failed
+    fn release_lock(&self, id_: Uuid) -> RepoResult<Option<RateLock>>;
+}
+
+#[derive(Clone, Default)]
+pub struct RateLocksRepoImpl;
+
+impl RateLocksRepo for RateLocksRepoImpl {
+    fn create(&self, payload: NewRateLock) -> RepoResult<RateLock> {
+        with_tls_connection("rate_locks.create", |conn| {
+            diesel::insert_into(rate_locks)
+                .values(payload.clone())
+                .get_result::<RateLock>(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => payload)
+                })
+        })
+    }
+
+    fn get(&self, id_: Uuid) -> RepoResult<Option<RateLock>> {
+        with_tls_connection("rate_locks.get", |conn| {
+            rate_locks.filter(id.eq(id_)).get_result(conn).optional().map_err(move |e| {
+                let error_kind = ErrorKind::from(&e);
+                ectx!(err e, error_kind => id_)
+            })
+        })
+    }
+
+    fn use_lock(&self, id_: Uuid, now: NaiveDateTime) -> RepoResult<Option<RateLock>> {
+        with_tls_connection("rate_locks.use_lock", |conn| {
+            let filtered = rate_locks.filter(id.eq(id_)).filter(used_at.is_null()).filter(expires_at.gt(now));
+            diesel::update(filtered)
+                .set(used_at.eq(now))
+                .get_result(conn)
+                .optional()
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => id_, now)
+                })
+        })
+    }
+
+    fn release_lock(&self, id_: Uuid) -> RepoResult<Option<RateLock>> {
+        with_tls_connection("rate_locks.release_lock", |conn| {
+            let filtered = rate_locks.filter(id.eq(id_)).filter(used_at.is_not_null());
+            diesel::update(filtered)
+                .set(used_at.eq(None::<NaiveDateTime>))
+                .get_result(conn)
+                .optional()
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => id_)
+                })
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use diesel::r2d2::ConnectionManager;
+    use diesel::PgConnection;
+    use futures_cpupool::CpuPool;
+    use r2d2;
+    use tokio_core::reactor::Core;
+
+    use super::*;
+    use config::Config;
+    use repos::DbExecutorImpl;
+
+    fn create_executor() -> DbExecutorImpl {
+        let config = Config::new().unwrap();
+        let manager = ConnectionManager::<PgConnection>::new(config.database.url);
+        let db_pool = r2d2::Pool::builder().build(manager).unwrap();
+        let cpu_pool = CpuPool::new(1);
+        DbExecutorImpl::new(db_pool.clone(), cpu_pool.clone())
+    }
+
+    #[test]
+    fn rate_locks_use_lock() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let users_repo = UsersRepoImpl::default();
+        let rate_locks_repo = RateLocksRepoImpl::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let user = users_repo.create(NewUser::default())?;
+            let now = ::chrono::Utc::now().naive_utc();
+            let lock = rate_locks_repo.create(NewRateLock {
+                user_id: user.id,
+                expires_at: now + ::chrono::Duration::seconds(60),
+                ..Default::default()
+            })?;
+            // expired by that time
+            assert!(rate_locks_repo.use_lock(lock.id, now + ::chrono::Duration::seconds(61))?.is_none());
+            let used = rate_locks_repo.use_lock(lock.id, now)?.unwrap();
+            assert_eq!(used.used_at, Some(now));
+            // can't be used twice
+            assert!(rate_locks_repo.use_lock(lock.id, now)?.is_none());
+            assert!(rate_locks_repo.get(lock.id)?.is_some());
+            // released lock can be used again
+            let released = rate_locks_repo.release_lock(lock.id)?.unwrap();
+            assert_eq!(released.used_at, None);
+            assert!(rate_locks_repo.release_lock(lock.id)?.is_none());
+            assert!(rate_locks_repo.use_lock(lock.id, now)?.is_some());
+            Ok::<_, Error>(())
+        }));
+    }
+}
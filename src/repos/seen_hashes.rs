@@ -1,4 +1,6 @@
+use chrono::NaiveDateTime;
 use diesel;
+use diesel::dsl::{max, min};
 
 use super::error::*;
 use super::executor::with_tls_connection;
@@ -10,7 +12,15 @@ use schema::seen_hashes::dsl::*;
 pub trait SeenHashesRepo: Send + Sync + 'static {
     fn create(&self, payload: NewSeenHashes) -> RepoResult<SeenHashes>;
     fn upsert(&self, payload: NewSeenHashes) -> RepoResult<SeenHashes>;
+    fn try_create(&self, payload: NewSeenHashes) -> RepoResult<Option<SeenHashes>>;
+    /// Same as `try_create` for several hashes with one insert, returns the ones that were not seen yet
+    fn try_create_batch(&self, payload: Vec<NewSeenHashes>) -> RepoResult<Vec<SeenHashes>>;
     fn get(&self, hash_: BlockchainTransactionId, currency_: Currency) -> RepoResult<Option<SeenHashes>>;
+    fn delete(&self, hash_: BlockchainTransactionId, currency_: Currency) -> RepoResult<Option<SeenHashes>>;
+    fn max_block_number(&self, currency_: Currency) -> RepoResult<Option<i64>>;
+    /// The lowest block of hashes seen at or after `created_since`
+    fn min_block_number_since(&self, currency_: Currency, created_since: NaiveDateTime) -> RepoResult<Option<i64>>;
+    fn prune(&self, currency_: Currency, below_block_number: i64, created_before: NaiveDateTime) -> RepoResult<u64>;
 }
 
 #[derive(Clone, Default)]
@@ -18,7 +28,7 @@ pub struct SeenHashesRepoImpl;
 
 impl SeenHashesRepo for SeenHashesRepoImpl {
     fn create(&self, payload: NewSeenHashes) -> RepoResult<SeenHashes> {
-        with_tls_connection(|conn| {
+        with_tls_connection("seen_hashes.create", |conn| {
             diesel::insert_into(seen_hashes)
                 .values(payload.clone())
                 .get_result::<SeenHashes>(conn)
@@ -29,7 +39,7 @@ impl SeenHashesRepo for SeenHashesRepoImpl {
         })
     }
     fn upsert(&self, payload: NewSeenHashes) -> RepoResult<SeenHashes> {
-        with_tls_connection(|conn| {
+        with_tls_connection("seen_hashes.upsert", |conn| {
             diesel::insert_into(seen_hashes)
                 .values(payload.clone())
                 .on_conflict((hash, currency))
@@ -44,8 +54,41 @@ impl SeenHashesRepo for SeenHashesRepoImpl {
         })
     }
 
+    // Inserts hash if it is not seen yet, returns None if it is already seen
+    fn try_create(&self, payload: NewSeenHashes) -> RepoResult<Option<SeenHashes>> {
+        with_tls_connection("seen_hashes.try_create", |conn| {
+            diesel::insert_into(seen_hashes)
+                .values(payload.clone())
+                .on_conflict((hash, currency))
+                .do_nothing()
+                .get_result::<SeenHashes>(conn)
+                .optional()
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => payload)
+                })
+        })
+    }
+
+    fn try_create_batch(&self, payload: Vec<NewSeenHashes>) -> RepoResult<Vec<SeenHashes>> {
+        if payload.is_empty() {
+            return Ok(vec![]);
+        }
+        with_tls_connection("seen_hashes.try_create_batch", |conn| {
+            diesel::insert_into(seen_hashes)
+                .values(&payload)
+                .on_conflict((hash, currency))
+                .do_nothing()
+                .get_results::<SeenHashes>(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => payload)
+                })
+        })
+    }
+
     fn get(&self, hash_: BlockchainTransactionId, currency_: Currency) -> RepoResult<Option<SeenHashes>> {
-        with_tls_connection(|conn| {
+        with_tls_connection("seen_hashes.get", |conn| {
             seen_hashes
                 .filter(hash.eq(hash_.clone()))
                 .filter(currency.eq(currency_))
@@ -58,6 +101,57 @@ impl SeenHashesRepo for SeenHashesRepoImpl {
                 })
         })
     }
+
+    fn delete(&self, hash_: BlockchainTransactionId, currency_: Currency) -> RepoResult<Option<SeenHashes>> {
+        with_tls_connection("seen_hashes.delete", |conn| {
+            let filtered = seen_hashes.filter(hash.eq(hash_.clone())).filter(currency.eq(currency_));
+            diesel::delete(filtered).get_result(conn).optional().map_err(move |e| {
+                let error_kind = ErrorKind::from(&e);
+                ectx!(err e, error_kind => hash_, currency_)
+            })
+        })
+    }
+
+    fn max_block_number(&self, currency_: Currency) -> RepoResult<Option<i64>> {
+        with_tls_connection("seen_hashes.max_block_number", |conn| {
+            seen_hashes
+                .filter(currency.eq(currency_))
+                .select(max(block_number))
+                .first(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => currency_)
+                })
+        })
+    }
+
+    fn min_block_number_since(&self, currency_: Currency, created_since: NaiveDateTime) -> RepoResult<Option<i64>> {
+        with_tls_connection("seen_hashes.min_block_number_since", |conn| {
+            seen_hashes
+                .filter(currency.eq(currency_))
+                .filter(created_at.ge(created_since))
+                .select(min(block_number))
+                .first(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => currency_, created_since)
+                })
+        })
+    }
+
+    // Deletes hashes that are both in blocks before `below_block_number` and created before `created_before`
+    fn prune(&self, currency_: Currency, below_block_number: i64, created_before: NaiveDateTime) -> RepoResult<u64> {
+        with_tls_connection("seen_hashes.prune", |conn| {
+            let filtered = seen_hashes
+                .filter(currency.eq(currency_))
+                .filter(block_number.lt(below_block_number))
+                .filter(created_at.lt(created_before));
+            diesel::delete(filtered).execute(conn).map(|count| count as u64).map_err(move |e| {
+                let error_kind = ErrorKind::from(&e);
+                ectx!(err e, error_kind => currency_, below_block_number, created_before)
+            })
+        })
+    }
 }
 
 #[cfg(test)]
@@ -106,4 +200,71 @@ pub mod tests {
             res
         }));
     }
+
+    #[test]
+    fn seen_hashes_try_create() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let seen_hashes_repo = SeenHashesRepoImpl::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let trans = NewSeenHashes::default();
+            assert!(seen_hashes_repo.try_create(trans.clone())?.is_some());
+            let res = seen_hashes_repo.try_create(trans.clone());
+            assert!(res.as_ref().unwrap().is_none());
+            assert!(seen_hashes_repo.delete(trans.hash.clone(), trans.currency)?.is_some());
+            assert!(seen_hashes_repo.try_create(trans)?.is_some());
+            res
+        }));
+    }
+
+    #[test]
+    fn seen_hashes_try_create_batch() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let seen_hashes_repo = SeenHashesRepoImpl::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let mut seen = NewSeenHashes::default();
+            seen.hash = BlockchainTransactionId::new("seen".to_string());
+            seen_hashes_repo.create(seen.clone())?;
+            let mut new = NewSeenHashes::default();
+            new.hash = BlockchainTransactionId::new("new".to_string());
+            let claimed = seen_hashes_repo.try_create_batch(vec![seen, new.clone()])?;
+            assert_eq!(
+                claimed.into_iter().map(|seen_hashes| seen_hashes.hash).collect::<Vec<_>>(),
+                vec![new.hash]
+            );
+            let res = seen_hashes_repo.try_create_batch(vec![]);
+            assert!(res.as_ref().unwrap().is_empty());
+            res
+        }));
+    }
+
+    #[test]
+    fn seen_hashes_prune() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let seen_hashes_repo = SeenHashesRepoImpl::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let mut old = NewSeenHashes::default();
+            old.hash = BlockchainTransactionId::new("old".to_string());
+            old.block_number = 10;
+            let old = seen_hashes_repo.create(old)?;
+            let mut recent = NewSeenHashes::default();
+            recent.hash = BlockchainTransactionId::new("recent".to_string());
+            recent.block_number = 100;
+            let recent = seen_hashes_repo.create(recent)?;
+            assert_eq!(seen_hashes_repo.max_block_number(recent.currency)?, Some(100));
+
+            let now = ::chrono::Utc::now().naive_utc() + ::chrono::Duration::seconds(1);
+            assert_eq!(seen_hashes_repo.min_block_number_since(old.currency, old.created_at)?, Some(10));
+            assert_eq!(seen_hashes_repo.min_block_number_since(old.currency, now)?, None);
+            // too recent by creation time
+            assert_eq!(seen_hashes_repo.prune(old.currency, 50, old.created_at)?, 0);
+            assert_eq!(seen_hashes_repo.prune(old.currency, 50, now)?, 1);
+            assert!(seen_hashes_repo.get(old.hash, old.currency)?.is_none());
+            let res = seen_hashes_repo.get(recent.hash, recent.currency);
+            assert!(res.as_ref().unwrap().is_some());
+            res
+        }));
+    }
 }
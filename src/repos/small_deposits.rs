@@ -0,0 +1,117 @@
+use diesel;
+use diesel::sql_query;
+
+use super::error::*;
+use super::executor::with_tls_connection;
+use super::*;
+use models::*;
+use prelude::*;
+use schema::small_deposits::dsl::*;
+
+pub trait SmallDepositsRepo: Send + Sync + 'static {
+    fn create(&self, payload: NewSmallDeposit) -> RepoResult<SmallDeposit>;
+    /// Newest first
+    fn list(&self, currency_: Option<Currency>, offset: i64, limit: i64) -> RepoResult<Vec<SmallDeposit>>;
+    fn totals(&self) -> RepoResult<Vec<SmallDepositsTotal>>;
+}
+
+#[derive(Clone, Default)]
+pub struct SmallDepositsRepoImpl;
+
+impl SmallDepositsRepo for SmallDepositsRepoImpl {
+    fn create(&self, payload: NewSmallDeposit) -> RepoResult<SmallDeposit> {
+        with_tls_connection("small_deposits.create", |conn| {
+            diesel::insert_into(small_deposits)
+                .values(payload.clone())
+                .get_result::<SmallDeposit>(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => payload)
+                })
+        })
+    }
+
+    fn list(&self, currency_: Option<Currency>, offset: i64, limit: i64) -> RepoResult<Vec<SmallDeposit>> {
+        with_tls_connection("small_deposits.list", |conn| {
+            let mut query = small_deposits.into_boxed();
+            if let Some(currency_) = currency_ {
+                query = query.filter(currency.eq(currency_));
+            }
+            query
+                .order((created_at.desc(), blockchain_tx_id.desc(), account_id.desc()))
+                .offset(offset)
+                .limit(limit)
+                .get_results(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => currency_, offset, limit)
+                })
+        })
+    }
+
+    fn totals(&self) -> RepoResult<Vec<SmallDepositsTotal>> {
+        with_tls_connection("small_deposits.totals", |conn| {
+            sql_query("SELECT currency, COUNT(*) AS count, SUM(value) AS value FROM small_deposits GROUP BY currency ORDER BY currency")
+                .get_results(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind)
+                })
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use diesel::r2d2::ConnectionManager;
+    use diesel::PgConnection;
+    use futures_cpupool::CpuPool;
+    use r2d2;
+    use tokio_core::reactor::Core;
+
+    use super::*;
+    use config::Config;
+    use repos::DbExecutorImpl;
+
+    fn create_executor() -> DbExecutorImpl {
+        let config = Config::new().unwrap();
+        let manager = ConnectionManager::<PgConnection>::new(config.database.url);
+        let db_pool = r2d2::Pool::builder().build(manager).unwrap();
+        let cpu_pool = CpuPool::new(1);
+        DbExecutorImpl::new(db_pool.clone(), cpu_pool.clone())
+    }
+
+    #[test]
+    fn small_deposits_create_and_list() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let users_repo = UsersRepoImpl::default();
+        let accounts_repo = AccountsRepoImpl::default();
+        let small_deposits_repo = SmallDepositsRepoImpl::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let user = users_repo.create(NewUser::default())?;
+            let account = accounts_repo.create(NewAccount {
+                user_id: user.id,
+                currency: Currency::Btc,
+                ..Default::default()
+            })?;
+            for (hash, value) in &[("a", 10), ("b", 20)] {
+                small_deposits_repo.create(NewSmallDeposit {
+                    blockchain_tx_id: BlockchainTransactionId::new(hash.to_string()),
+                    account_id: account.id,
+                    user_id: user.id,
+                    currency: Currency::Btc,
+                    value: Amount::new(*value),
+                })?;
+            }
+            let res = small_deposits_repo.list(Some(Currency::Btc), 0, 10)?;
+            assert_eq!(res.len(), 2);
+            assert!(small_deposits_repo.list(Some(Currency::Eth), 0, 10)?.is_empty());
+            let totals = small_deposits_repo.totals()?;
+            assert!(totals
+                .iter()
+                .any(|total| total.currency == Currency::Btc && total.count >= 2 && total.value >= Amount::new(30)));
+            Ok::<_, Error>(res)
+        }));
+    }
+}
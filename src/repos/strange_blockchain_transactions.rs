@@ -1,5 +1,7 @@
 use diesel;
 use diesel::dsl::count;
+use diesel::sql_query;
+use diesel::sql_types::{BigInt, Jsonb};
 
 use super::error::*;
 use super::executor::with_tls_connection;
@@ -12,6 +14,19 @@ pub trait StrangeBlockchainTransactionsRepo: Send + Sync + 'static {
     fn create(&self, payload: NewStrangeBlockchainTransactionDB) -> RepoResult<StrangeBlockchainTransactionDB>;
     fn count(&self) -> RepoResult<u64>;
     fn get(&self, hash_: BlockchainTransactionId) -> RepoResult<Option<StrangeBlockchainTransactionDB>>;
+    /// Newest first
+    fn list(
+        &self,
+        offset: i64,
+        limit: i64,
+        currency_: Option<Currency>,
+        violation_kind_: Option<InvariantViolation>,
+    ) -> RepoResult<Vec<StrangeBlockchainTransactionDB>>;
+    /// Transactions which hash starts with `hash_prefix`, newest first
+    fn search_by_hash(&self, hash_prefix: String, limit: i64) -> RepoResult<Vec<StrangeBlockchainTransactionDB>>;
+    /// Transactions with `address` among senders or receivers, newest first
+    fn search_by_address(&self, address: BlockchainAddress, offset: i64, limit: i64) -> RepoResult<Vec<StrangeBlockchainTransactionDB>>;
+    fn delete(&self, hash_: BlockchainTransactionId) -> RepoResult<Option<StrangeBlockchainTransactionDB>>;
 }
 
 #[derive(Clone, Default)]
@@ -19,7 +34,7 @@ pub struct StrangeBlockchainTransactionsRepoImpl;
 
 impl StrangeBlockchainTransactionsRepo for StrangeBlockchainTransactionsRepoImpl {
     fn count(&self) -> RepoResult<u64> {
-        with_tls_connection(|conn| {
+        with_tls_connection("strange_blockchain_transactions.count", |conn| {
             strange_blockchain_transactions
                 .select(count(hash))
                 .first(conn)
@@ -32,7 +47,7 @@ impl StrangeBlockchainTransactionsRepo for StrangeBlockchainTransactionsRepoImpl
     }
 
     fn create(&self, payload: NewStrangeBlockchainTransactionDB) -> RepoResult<StrangeBlockchainTransactionDB> {
-        with_tls_connection(|conn| {
+        with_tls_connection("strange_blockchain_transactions.create", |conn| {
             diesel::insert_into(strange_blockchain_transactions)
                 .values(payload.clone())
                 .get_result::<StrangeBlockchainTransactionDB>(conn)
@@ -43,7 +58,7 @@ impl StrangeBlockchainTransactionsRepo for StrangeBlockchainTransactionsRepoImpl
         })
     }
     fn get(&self, hash_: BlockchainTransactionId) -> RepoResult<Option<StrangeBlockchainTransactionDB>> {
-        with_tls_connection(|conn| {
+        with_tls_connection("strange_blockchain_transactions.get", |conn| {
             strange_blockchain_transactions
                 .filter(hash.eq(hash_.clone()))
                 .limit(1)
@@ -55,6 +70,73 @@ impl StrangeBlockchainTransactionsRepo for StrangeBlockchainTransactionsRepoImpl
                 })
         })
     }
+    fn list(
+        &self,
+        offset: i64,
+        limit: i64,
+        currency_: Option<Currency>,
+        violation_kind_: Option<InvariantViolation>,
+    ) -> RepoResult<Vec<StrangeBlockchainTransactionDB>> {
+        with_tls_connection("strange_blockchain_transactions.list", |conn| {
+            let mut query = strange_blockchain_transactions.into_boxed();
+            if let Some(currency_) = currency_ {
+                query = query.filter(currency.eq(currency_));
+            }
+            if let Some(violation_kind_) = violation_kind_ {
+                query = query.filter(violation_kind.eq(violation_kind_));
+            }
+            query
+                .order((created_at.desc(), hash.desc()))
+                .offset(offset)
+                .limit(limit)
+                .get_results(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => offset, limit, currency_, violation_kind_)
+                })
+        })
+    }
+    fn search_by_hash(&self, hash_prefix: String, limit: i64) -> RepoResult<Vec<StrangeBlockchainTransactionDB>> {
+        with_tls_connection("strange_blockchain_transactions.search_by_hash", |conn| {
+            let pattern = format!("{}%", hash_prefix.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_"));
+            strange_blockchain_transactions
+                .filter(hash.like(pattern))
+                .order((created_at.desc(), hash.desc()))
+                .limit(limit)
+                .get_results(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => hash_prefix, limit)
+                })
+        })
+    }
+    fn search_by_address(&self, address: BlockchainAddress, offset: i64, limit: i64) -> RepoResult<Vec<StrangeBlockchainTransactionDB>> {
+        with_tls_connection("strange_blockchain_transactions.search_by_address", |conn| {
+            // senders are stored as array of addresses and receivers as array of entries with address and value
+            sql_query(
+                "SELECT * FROM strange_blockchain_transactions WHERE from_ @> $1 OR to_ @> $2 \
+                 ORDER BY created_at DESC, hash DESC OFFSET $3 LIMIT $4",
+            )
+            .bind::<Jsonb, _>(json!([address]))
+            .bind::<Jsonb, _>(json!([{ "address": address }]))
+            .bind::<BigInt, _>(offset)
+            .bind::<BigInt, _>(limit)
+            .get_results(conn)
+            .map_err(move |e| {
+                let error_kind = ErrorKind::from(&e);
+                ectx!(err e, error_kind => address, offset, limit)
+            })
+        })
+    }
+    fn delete(&self, hash_: BlockchainTransactionId) -> RepoResult<Option<StrangeBlockchainTransactionDB>> {
+        with_tls_connection("strange_blockchain_transactions.delete", |conn| {
+            let filtered = strange_blockchain_transactions.filter(hash.eq(hash_.clone()));
+            diesel::delete(filtered).get_result(conn).optional().map_err(move |e| {
+                let error_kind = ErrorKind::from(&e);
+                ectx!(err e, error_kind => hash_)
+            })
+        })
+    }
 }
 
 #[cfg(test)]
@@ -103,4 +185,57 @@ pub mod tests {
             res
         }));
     }
+
+    #[test]
+    fn strange_blockchain_transactions_delete() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let strange_blockchain_transactions_repo = StrangeBlockchainTransactionsRepoImpl::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let trans = NewStrangeBlockchainTransactionDB::default();
+            let transaction = strange_blockchain_transactions_repo.create(trans)?;
+            let res = strange_blockchain_transactions_repo.delete(transaction.hash.clone())?;
+            assert!(res.is_some());
+            let res = strange_blockchain_transactions_repo.get(transaction.hash)?;
+            assert!(res.is_none());
+            Ok::<_, Error>(())
+        }));
+    }
+
+    #[test]
+    fn strange_blockchain_transactions_list_and_search() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let strange_blockchain_transactions_repo = StrangeBlockchainTransactionsRepoImpl::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let sender = BlockchainAddress::new("0xsender".to_string());
+            let receiver = BlockchainAddress::new("0xreceiver".to_string());
+            let withdrawal = strange_blockchain_transactions_repo.create(NewStrangeBlockchainTransactionDB {
+                hash: BlockchainTransactionId::new("0xabc".to_string()),
+                from_: json!([sender]),
+                violation_kind: Some(InvariantViolation::WithdrawalValue),
+                ..Default::default()
+            })?;
+            let deposit = strange_blockchain_transactions_repo.create(NewStrangeBlockchainTransactionDB {
+                hash: BlockchainTransactionId::new("0xabd".to_string()),
+                to_: json!([{ "address": receiver, "value": "1" }]),
+                currency: Currency::Stq,
+                violation_kind: Some(InvariantViolation::DepositAddressInternal),
+                ..Default::default()
+            })?;
+            let hashes = |txs: Vec<StrangeBlockchainTransactionDB>| txs.into_iter().map(|tx| tx.hash).collect::<Vec<_>>();
+
+            let res = strange_blockchain_transactions_repo.list(0, 10, Some(Currency::Stq), None)?;
+            assert_eq!(hashes(res), vec![deposit.hash.clone()]);
+            let res = strange_blockchain_transactions_repo.list(0, 10, None, Some(InvariantViolation::WithdrawalValue))?;
+            assert_eq!(hashes(res), vec![withdrawal.hash.clone()]);
+            let res = strange_blockchain_transactions_repo.search_by_hash("0xab".to_string(), 10)?;
+            assert_eq!(res.len(), 2);
+            let res = strange_blockchain_transactions_repo.search_by_address(sender, 0, 10)?;
+            assert_eq!(hashes(res), vec![withdrawal.hash]);
+            let res = strange_blockchain_transactions_repo.search_by_address(receiver, 0, 10)?;
+            assert_eq!(hashes(res), vec![deposit.hash]);
+            Ok::<_, Error>(())
+        }));
+    }
 }
@@ -1,19 +1,27 @@
 use std::collections::{HashMap, HashSet};
 
-use chrono::{Duration, Utc};
+use chrono::{Duration, NaiveDateTime, Utc};
 use diesel;
-use diesel::dsl::{any, sum};
+use diesel::dsl::{any, max, sum};
+use diesel::pg::PgConnection;
 use diesel::sql_query;
 use diesel::sql_types::Uuid as SqlUuid;
 use diesel::sql_types::{BigInt, Numeric, Timestamp, VarChar};
+use serde_json::Value;
+use validator::{ValidationError, ValidationErrors};
 
 use super::error::*;
 use super::executor::with_tls_connection;
 use super::*;
 use models::*;
 use prelude::*;
+use schema::account_balances::dsl as AccountBalances;
 use schema::accounts::dsl as Accounts;
+use schema::all_transactions::dsl as AllTransactions;
+use schema::balance_checkpoints::dsl as Checkpoints;
+use schema::group_fees::dsl as GroupFees;
 use schema::transactions::dsl::*;
+use schema::tx_groups::dsl as TxGroups;
 
 // 0.001 BTC
 const MIN_SIGNIFICANT_SATOSHIS: u128 = 1000;
@@ -24,21 +32,83 @@ const MIN_SIGNIFICANT_STQ: u128 = 1_000_000_000_000_000_000;
 
 pub trait TransactionsRepo: Send + Sync + 'static {
     fn create(&self, payload: NewTransaction) -> RepoResult<Transaction>;
+    fn create_batch(&self, payloads: Vec<NewTransaction>) -> RepoResult<Vec<Transaction>>;
+    fn assert_non_negative_balances(&self, account_ids: &[AccountId]) -> RepoResult<()>;
     fn get(&self, transaction_id: TransactionId) -> RepoResult<Option<Transaction>>;
     fn update_status(&self, blockchain_tx_id: BlockchainTransactionId, transaction_status: TransactionStatus) -> RepoResult<Transaction>;
+    /// Status of the transaction that has no blockchain tx id yet, e.g. sweep that failed to be sent
+    fn update_status_by_id(&self, transaction_id: TransactionId, transaction_status: TransactionStatus) -> RepoResult<Transaction>;
     fn get_by_gid(&self, gid: TransactionId) -> RepoResult<Vec<Transaction>>;
+    /// Group `gid` with the groups that refer to it by `related_tx`, e.g. reversals and fee refunds, recursively
+    fn get_group_with_related(&self, gid: TransactionId) -> RepoResult<TransactionGroupTree>;
+    fn get_group_summary(&self, gid: TransactionId) -> RepoResult<Option<TxGroup>>;
     fn get_by_blockchain_tx(&self, blockchain_tx_id: BlockchainTransactionId) -> RepoResult<Option<Transaction>>;
     fn update_blockchain_tx(&self, transaction_id: TransactionId, blockchain_tx_id: BlockchainTransactionId) -> RepoResult<Transaction>;
+    fn update_meta(&self, transaction_id: TransactionId, meta: Value) -> RepoResult<Transaction>;
     fn get_account_balance(&self, account_id: AccountId, kind: AccountKind) -> RepoResult<Amount>;
     fn get_account_spending(&self, account_id: AccountId, kind: AccountKind, period: Duration) -> RepoResult<Amount>;
+    fn get_account_stats(&self, account_id: AccountId, window: StatsWindow) -> RepoResult<AccountStats>;
     fn get_accounts_balance(&self, auth_user_id: UserId, accounts: &[Account]) -> RepoResult<Vec<AccountWithBalance>>;
     fn list_for_user(&self, user_id_arg: UserId, offset: i64, limit: i64) -> RepoResult<Vec<Transaction>>;
     fn list_for_account(&self, account_id: AccountId, offset: i64, limit: i64) -> RepoResult<Vec<Transaction>>;
-    fn list_groups_for_account_skip_approval(&self, account_id: AccountId, offset: i64, limit: i64) -> RepoResult<Vec<Transaction>>;
-    fn list_groups_for_user_skip_approval(&self, user_id: UserId, offset: i64, limit: i64) -> RepoResult<Vec<Transaction>>;
+    fn list_pending_older_than(&self, age: Duration) -> RepoResult<Vec<Transaction>>;
+    fn list_groups_for_account_skip_approval(
+        &self,
+        account_id: AccountId,
+        offset: i64,
+        limit: i64,
+        sort: TransactionsSort,
+    ) -> RepoResult<Vec<Transaction>>;
+    /// Groups of account older than `before` (created_at and gid of the last seen group), newest first.
+    /// Keyset paging for whole account history, that doesn't slow down with depth as offset does
+    fn list_groups_for_account_before(
+        &self,
+        account_id: AccountId,
+        before: Option<(NaiveDateTime, TransactionId)>,
+        limit: i64,
+    ) -> RepoResult<Vec<Transaction>>;
+    fn list_groups_for_user_skip_approval(
+        &self,
+        user_id: UserId,
+        offset: i64,
+        limit: i64,
+        sort: TransactionsSort,
+    ) -> RepoResult<Vec<Transaction>>;
+    fn list_groups_for_user_in_period(
+        &self,
+        user_id: UserId,
+        from: NaiveDateTime,
+        to: NaiveDateTime,
+        offset: i64,
+        limit: i64,
+    ) -> RepoResult<Vec<Transaction>>;
     fn get_system_balances(&self) -> RepoResult<HashMap<AccountId, (Amount, Amount)>>;
+    /// Total value kept in liquidity accounts because of exchange spreads, by currency
+    fn get_exchange_margins(&self) -> RepoResult<HashMap<Currency, Amount>>;
     fn get_blockchain_balances(&self) -> RepoResult<HashMap<(BlockchainAddress, Currency), (Amount, Amount)>>;
+    /// Turnovers of all accounts as of the latest balance checkpoint plus transactions created after it
+    fn get_accounts_turnovers(&self) -> RepoResult<Vec<AccountTurnover>>;
+    fn get_stale_account_balances(&self) -> RepoResult<Vec<AccountTurnover>>;
+    /// Stores turnovers of accounts by transactions created before `as_of`, computed from the previous checkpoint,
+    /// and removes checkpoints older than the previous one. Returns the number of stored accounts,
+    /// `None` if the latest checkpoint is not older than `as_of`
+    fn create_balance_checkpoint(&self, as_of: NaiveDateTime) -> RepoResult<Option<usize>>;
+    /// Moves up to `limit` oldest done groups, that are older than both `horizon` and the latest balance checkpoint,
+    /// to archive. Materialized balances and group summaries stay as they are. Returns the number of archived groups
+    fn archive_groups(&self, horizon: NaiveDateTime, limit: i64) -> RepoResult<usize>;
+    /// Debit accounts to send `value` from with their shares, taken from the pool of hot accounts round robin
     fn get_accounts_for_withdrawal(&self, value: Amount, currency: Currency, total_fee: Amount) -> RepoResult<Vec<AccountWithBalance>>;
+    /// Positive balances of non-archived debit accounts in `currency`, i.e. funds kept on hot addresses
+    fn get_dr_balances(&self, currency: Currency) -> RepoResult<Vec<AccountWithBalance>>;
+    /// Stores fee charged by withdrawal group, network fees are accrued to it later
+    fn create_group_fee(&self, payload: NewGroupFee) -> RepoResult<GroupFee>;
+    /// Adds network fee paid for blockchain transaction of group `gid`. Groups that weren't charged are tracked as well
+    fn accrue_blockchain_fee(&self, gid: TransactionId, currency: Currency, value: Amount) -> RepoResult<GroupFee>;
+    fn get_group_fee(&self, gid: TransactionId) -> RepoResult<Option<GroupFee>>;
+    /// Charged and actually paid network fees of all groups, by currency of network fees
+    fn get_fee_margins(&self) -> RepoResult<HashMap<Currency, (Amount, Amount)>>;
+    /// Subtracts fee refunded to user from the one charged by group, so that margins count only what's kept
+    fn deduct_refunded_fee(&self, gid: TransactionId, fee: Amount, charged_blockchain_fee: Amount) -> RepoResult<GroupFee>;
 }
 
 #[derive(Debug, Clone, Queryable, QueryableByName)]
@@ -59,6 +129,28 @@ struct BalanceQuery {
     sum: Amount,
 }
 
+#[derive(Debug, Clone, Queryable, QueryableByName)]
+struct AccountStatsQuery {
+    #[sql_type = "VarChar"]
+    currency: Currency,
+    #[sql_type = "BigInt"]
+    deposits_count: i64,
+    #[sql_type = "Numeric"]
+    deposits_value: Amount,
+    #[sql_type = "BigInt"]
+    withdrawals_count: i64,
+    #[sql_type = "Numeric"]
+    withdrawals_value: Amount,
+}
+
+#[derive(Debug, Clone, Queryable, QueryableByName)]
+struct CurrencySumQuery {
+    #[sql_type = "VarChar"]
+    currency: Currency,
+    #[sql_type = "Numeric"]
+    sum: Amount,
+}
+
 #[derive(Debug, Clone, Queryable, QueryableByName)]
 struct SystemBalanceQuery {
     #[sql_type = "SqlUuid"]
@@ -67,24 +159,27 @@ struct SystemBalanceQuery {
     sum: Amount,
 }
 
+/// Orders hot accounts, that withdrawals are sent from, so that load is spread across the pool round robin:
+/// the ones without pending transactions come first, and the ones least recently spent from first among them
+fn order_withdrawal_pool(mut accounts: Vec<(Account, Amount)>, pending_accounts: &HashSet<AccountId>) -> Vec<(Account, Amount)> {
+    accounts.sort_by_key(|(acc, _)| (pending_accounts.contains(&acc.id), acc.last_spent_at));
+    accounts
+}
+
 #[derive(Clone, Default)]
 pub struct TransactionsRepoImpl {
     system_user_id: UserId,
-    system_fees_accounts_ids: Vec<AccountId>,
 }
 
 impl TransactionsRepoImpl {
-    pub fn new(system_user_id: UserId, system_fees_accounts_ids: Vec<AccountId>) -> Self {
-        TransactionsRepoImpl {
-            system_user_id,
-            system_fees_accounts_ids,
-        }
+    pub fn new(system_user_id: UserId) -> Self {
+        TransactionsRepoImpl { system_user_id }
     }
 }
 
 impl TransactionsRepo for TransactionsRepoImpl {
     fn create(&self, payload: NewTransaction) -> RepoResult<Transaction> {
-        with_tls_connection(|conn| {
+        with_tls_connection("transactions.create", |conn| {
             diesel::insert_into(transactions)
                 .values(payload.clone())
                 .get_result::<Transaction>(conn)
@@ -95,14 +190,54 @@ impl TransactionsRepo for TransactionsRepoImpl {
         })
     }
 
-    // SELECT cr_account_id as id, SUM(value) FROM transactions JOIN accounts ON transactions.cr_account_id = accounts.id WHERE accounts.user_id = '00000000-0000-4000-8000-010000000000' AND accounts.kind = 'cr' GROUP BY cr_account_id;
-    // SELECT dr_account_id as id, SUM(value) FROM transactions JOIN accounts ON transactions.dr_account_id = accounts.id WHERE accounts.user_id = '00000000-0000-4000-8000-010000000000' AND accounts.kind = 'cr' GROUP BY dr_account_id;
+    // Inserts all transactions with a single statement, returned transactions are in the same order as payloads
+    fn create_batch(&self, payloads: Vec<NewTransaction>) -> RepoResult<Vec<Transaction>> {
+        with_tls_connection("transactions.create_batch", |conn| {
+            diesel::insert_into(transactions)
+                .values(&payloads)
+                .get_results::<Transaction>(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => payloads)
+                })
+        })
+    }
+
+    // Fails if any of cr accounts has negative balance, supposed to be called after spending from accounts
+    // in the same db transaction, so that it is rolled back
+    fn assert_non_negative_balances(&self, account_ids: &[AccountId]) -> RepoResult<()> {
+        with_tls_connection("transactions.assert_non_negative_balances", |conn| {
+            let ids = account_ids.to_vec();
+            let negative_ids: Vec<AccountId> = AccountBalances::account_balances
+                .inner_join(Accounts::accounts)
+                .filter(Accounts::kind.eq(AccountKind::Cr))
+                .filter(AccountBalances::cr_turnover.lt(AccountBalances::dr_turnover))
+                .filter(AccountBalances::account_id.eq_any(ids))
+                .select(AccountBalances::account_id)
+                .get_results(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(try err e, error_kind => account_ids)
+                })?;
+            if negative_ids.is_empty() {
+                return Ok(());
+            }
+            let mut errors = ValidationErrors::new();
+            let mut error = ValidationError::new("not_enough_balance");
+            error.message = Some("account balance is not enough".into());
+            errors.add("value", error);
+            Err(ectx!(err ErrorContext::NegativeBalance, ErrorKind::Constraints(errors) => negative_ids))
+        })
+    }
+
+    // SELECT cr_account_id as id, SUM(value) FROM all_transactions JOIN accounts ON all_transactions.cr_account_id = accounts.id WHERE accounts.user_id = '00000000-0000-4000-8000-010000000000' AND accounts.kind = 'cr' GROUP BY cr_account_id;
+    // SELECT dr_account_id as id, SUM(value) FROM all_transactions JOIN accounts ON all_transactions.dr_account_id = accounts.id WHERE accounts.user_id = '00000000-0000-4000-8000-010000000000' AND accounts.kind = 'cr' GROUP BY dr_account_id;
 
     fn get_system_balances(&self) -> RepoResult<HashMap<AccountId, (Amount, Amount)>> {
-        with_tls_connection(|conn| {
+        with_tls_connection("transactions.get_system_balances", |conn| {
             let dr_turnovers: Vec<SystemBalanceQuery> =
                 sql_query(
-                "SELECT dr_account_id as id, SUM(value) FROM transactions JOIN accounts ON transactions.dr_account_id = accounts.id WHERE accounts.user_id = $1 AND accounts.kind = 'cr' GROUP BY dr_account_id;")
+                "SELECT dr_account_id as id, SUM(value) FROM all_transactions JOIN accounts ON all_transactions.dr_account_id = accounts.id WHERE accounts.user_id = $1 AND accounts.kind = 'cr' GROUP BY dr_account_id;")
                     .bind::<SqlUuid, _>(self.system_user_id)
                     .get_results(conn)
                     .map_err(move |e| {
@@ -115,7 +250,7 @@ impl TransactionsRepo for TransactionsRepoImpl {
                 .collect();
             let cr_turnovers: Vec<SystemBalanceQuery> =
                 sql_query(
-                "SELECT cr_account_id as id, SUM(value) FROM transactions JOIN accounts ON transactions.cr_account_id = accounts.id WHERE accounts.user_id = $1 AND accounts.kind = 'cr' GROUP BY cr_account_id;")
+                "SELECT cr_account_id as id, SUM(value) FROM all_transactions JOIN accounts ON all_transactions.cr_account_id = accounts.id WHERE accounts.user_id = $1 AND accounts.kind = 'cr' GROUP BY cr_account_id;")
                     .bind::<SqlUuid, _>(self.system_user_id)
                     .get_results(conn)
                     .map_err(move |e| {
@@ -140,11 +275,25 @@ impl TransactionsRepo for TransactionsRepoImpl {
         })
     }
 
+    fn get_exchange_margins(&self) -> RepoResult<HashMap<Currency, Amount>> {
+        with_tls_connection("transactions.get_exchange_margins", |conn| {
+            let margins: Vec<CurrencySumQuery> = sql_query(
+                "SELECT currency, SUM((meta->>'exchangeMargin')::numeric) AS sum FROM all_transactions WHERE kind = 'multi_to' AND meta ? 'exchangeMargin' GROUP BY currency",
+            )
+            .get_results(conn)
+            .map_err(move |e| {
+                let error_kind = ErrorKind::from(&e);
+                ectx!(try err e, error_kind)
+            })?;
+            Ok(margins.into_iter().map(|margin| (margin.currency, margin.sum)).collect())
+        })
+    }
+
     fn get_blockchain_balances(&self) -> RepoResult<HashMap<(BlockchainAddress, Currency), (Amount, Amount)>> {
-        with_tls_connection(|conn| {
+        with_tls_connection("transactions.get_blockchain_balances", |conn| {
             let dr_turnovers: Vec<BalanceQuery> =
                 sql_query(
-                "SELECT accounts.address, accounts.currency, sums.sum FROM (SELECT dr_account_id, SUM(value) FROM transactions GROUP BY dr_account_id) AS sums INNER JOIN accounts ON accounts.id = sums.dr_account_id WHERE accounts.kind = 'dr'")
+                "SELECT accounts.address, accounts.currency, sums.sum FROM (SELECT dr_account_id, SUM(value) FROM all_transactions GROUP BY dr_account_id) AS sums INNER JOIN accounts ON accounts.id = sums.dr_account_id WHERE accounts.kind = 'dr'")
                     .get_results(conn)
                     .map_err(move |e| {
                         let error_kind = ErrorKind::from(&e);
@@ -156,7 +305,7 @@ impl TransactionsRepo for TransactionsRepoImpl {
                 .collect();
             let cr_turnovers: Vec<BalanceQuery> =
                 sql_query(
-                "SELECT accounts.address, accounts.currency, sums.sum FROM (SELECT cr_account_id, SUM(value) FROM transactions GROUP BY cr_account_id) AS sums INNER JOIN accounts ON accounts.id = sums.cr_account_id WHERE accounts.kind = 'dr'")
+                "SELECT accounts.address, accounts.currency, sums.sum FROM (SELECT cr_account_id, SUM(value) FROM all_transactions GROUP BY cr_account_id) AS sums INNER JOIN accounts ON accounts.id = sums.cr_account_id WHERE accounts.kind = 'dr'")
                     .get_results(conn)
                     .map_err(move |e| {
                         let error_kind = ErrorKind::from(&e);
@@ -179,10 +328,100 @@ impl TransactionsRepo for TransactionsRepoImpl {
             Ok(res)
         })
     }
+    fn get_accounts_turnovers(&self) -> RepoResult<Vec<AccountTurnover>> {
+        with_tls_connection("transactions.get_accounts_turnovers", |conn| {
+            sql_query(turnovers_sql(false)).get_results(conn).map_err(move |e| {
+                let error_kind = ErrorKind::from(&e);
+                ectx!(err e, error_kind)
+            })
+        })
+    }
+    // Returns actual turnovers of accounts, for which materialized balances diverged from transactions
+    fn get_stale_account_balances(&self) -> RepoResult<Vec<AccountTurnover>> {
+        with_tls_connection("transactions.get_stale_account_balances", |conn| {
+            sql_query(format!(
+                "SELECT turnovers.* FROM ({}) AS turnovers LEFT JOIN account_balances ON account_balances.account_id = turnovers.account_id WHERE turnovers.dr_turnover <> COALESCE(account_balances.dr_turnover, 0) OR turnovers.cr_turnover <> COALESCE(account_balances.cr_turnover, 0)",
+                turnovers_sql(false)
+            ))
+            .get_results(conn)
+            .map_err(move |e| {
+                let error_kind = ErrorKind::from(&e);
+                ectx!(err e, error_kind)
+            })
+        })
+    }
+    fn create_balance_checkpoint(&self, as_of: NaiveDateTime) -> RepoResult<Option<usize>> {
+        with_tls_connection("transactions.create_balance_checkpoint", |conn| {
+            let latest: Option<NaiveDateTime> = Checkpoints::balance_checkpoints
+                .select(max(Checkpoints::as_of))
+                .get_result(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(try err e, error_kind => as_of)
+                })?;
+            if latest.map(|latest| latest >= as_of).unwrap_or(false) {
+                return Ok(None);
+            }
+            let stored = sql_query(format!(
+                "INSERT INTO balance_checkpoints (account_id, as_of, dr_turnover, cr_turnover) SELECT account_id, $1, dr_turnover, cr_turnover FROM ({}) AS turnovers WHERE dr_turnover <> 0 OR cr_turnover <> 0",
+                turnovers_sql(true)
+            ))
+            .bind::<Timestamp, _>(as_of)
+            .execute(conn)
+            .map_err(move |e| {
+                let error_kind = ErrorKind::from(&e);
+                ectx!(try err e, error_kind => as_of)
+            })?;
+            // the previous one is kept to compare with
+            if let Some(latest) = latest {
+                diesel::delete(Checkpoints::balance_checkpoints.filter(Checkpoints::as_of.lt(latest)))
+                    .execute(conn)
+                    .map_err(move |e| {
+                        let error_kind = ErrorKind::from(&e);
+                        ectx!(try err e, error_kind => latest)
+                    })?;
+            }
+            Ok(Some(stored))
+        })
+    }
+    fn archive_groups(&self, horizon: NaiveDateTime, limit: i64) -> RepoResult<usize> {
+        with_tls_connection("transactions.archive_groups", |conn| {
+            // only for the current db transaction
+            sql_query("SELECT set_config('ledger.archiving', 'on', true)")
+                .execute(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(try err e, error_kind => horizon)
+                })?;
+            // turnovers after the latest checkpoint are read from transactions, so archived ones must be before it
+            let archived_count = sql_query(
+                "WITH batch AS (SELECT gid FROM tx_groups WHERE NOT archived AND status = 'done' AND last_created_at < $1 \
+                 AND last_created_at < COALESCE((SELECT max(as_of) FROM balance_checkpoints), '-infinity') \
+                 ORDER BY created_at LIMIT $2 FOR UPDATE), \
+                 moved AS (DELETE FROM transactions WHERE gid IN (SELECT gid FROM batch) RETURNING *), \
+                 inserted AS (INSERT INTO transactions_archive SELECT * FROM moved) \
+                 UPDATE tx_groups SET archived = TRUE WHERE gid IN (SELECT gid FROM batch)",
+            )
+            .bind::<Timestamp, _>(horizon)
+            .bind::<BigInt, _>(limit)
+            .execute(conn)
+            .map_err(move |e| {
+                let error_kind = ErrorKind::from(&e);
+                ectx!(try err e, error_kind => horizon, limit)
+            })?;
+            sql_query("SELECT set_config('ledger.archiving', 'off', true)")
+                .execute(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(try err e, error_kind => horizon)
+                })?;
+            Ok(archived_count)
+        })
+    }
     fn get(&self, transaction_id_arg: TransactionId) -> RepoResult<Option<Transaction>> {
-        with_tls_connection(|conn| {
-            transactions
-                .filter(id.eq(transaction_id_arg))
+        with_tls_connection("transactions.get", |conn| {
+            AllTransactions::all_transactions
+                .filter(AllTransactions::id.eq(transaction_id_arg))
                 .limit(1)
                 .get_result(conn)
                 .optional()
@@ -194,17 +433,72 @@ impl TransactionsRepo for TransactionsRepoImpl {
     }
 
     fn get_by_gid(&self, gid_: TransactionId) -> RepoResult<Vec<Transaction>> {
-        with_tls_connection(|conn| {
-            transactions.filter(gid.eq(gid_)).get_results(conn).map_err(move |e| {
-                let error_kind = ErrorKind::from(&e);
-                ectx!(err e, error_kind => gid_)
-            })
+        with_tls_connection("transactions.get_by_gid", |conn| {
+            AllTransactions::all_transactions
+                .filter(AllTransactions::gid.eq(gid_))
+                .get_results(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => gid_)
+                })
+        })
+    }
+
+    fn get_group_with_related(&self, gid_: TransactionId) -> RepoResult<TransactionGroupTree> {
+        with_tls_connection("transactions.get_group_with_related", |conn| {
+            let mut group: Vec<Transaction> = AllTransactions::all_transactions
+                .filter(AllTransactions::gid.eq(gid_))
+                .get_results(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(try err e, error_kind => gid_)
+                })?;
+            let mut visited_gids = vec![gid_];
+            let mut parent_ids: Vec<TransactionId> = group.iter().map(|tx| tx.id).collect();
+            while !parent_ids.is_empty() {
+                let parent_ids_clone = parent_ids.clone();
+                let related_gids: Vec<TransactionId> = AllTransactions::all_transactions
+                    .filter(AllTransactions::related_tx.eq_any(parent_ids.clone()))
+                    .filter(AllTransactions::gid.ne_all(visited_gids.clone()))
+                    .select(AllTransactions::gid)
+                    .distinct()
+                    .get_results(conn)
+                    .map_err(move |e| {
+                        let error_kind = ErrorKind::from(&e);
+                        ectx!(try err e, error_kind => gid_, parent_ids_clone)
+                    })?;
+                let related_gids_clone = related_gids.clone();
+                let related: Vec<Transaction> = AllTransactions::all_transactions
+                    .filter(AllTransactions::gid.eq_any(related_gids.clone()))
+                    .get_results(conn)
+                    .map_err(move |e| {
+                        let error_kind = ErrorKind::from(&e);
+                        ectx!(try err e, error_kind => gid_, related_gids_clone)
+                    })?;
+                visited_gids.extend(related_gids);
+                parent_ids = related.iter().map(|tx| tx.id).collect();
+                group.extend(related);
+            }
+            Ok(TransactionGroupTree::build(gid_, group))
+        })
+    }
+
+    fn get_group_summary(&self, gid_: TransactionId) -> RepoResult<Option<TxGroup>> {
+        with_tls_connection("transactions.get_group_summary", |conn| {
+            TxGroups::tx_groups
+                .filter(TxGroups::gid.eq(gid_))
+                .get_result(conn)
+                .optional()
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => gid_)
+                })
         })
     }
 
     //Todo - add filtering by user
     fn get_by_blockchain_tx(&self, blockchain_tx_id_: BlockchainTransactionId) -> RepoResult<Option<Transaction>> {
-        with_tls_connection(|conn| {
+        with_tls_connection("transactions.get_by_blockchain_tx", |conn| {
             transactions
                 .filter(blockchain_tx_id.eq(blockchain_tx_id_.clone()))
                 .limit(1)
@@ -218,7 +512,7 @@ impl TransactionsRepo for TransactionsRepoImpl {
     }
 
     fn update_status(&self, blockchain_tx_id_: BlockchainTransactionId, transaction_status: TransactionStatus) -> RepoResult<Transaction> {
-        with_tls_connection(|conn| {
+        with_tls_connection("transactions.update_status", |conn| {
             let f = transactions.filter(blockchain_tx_id.eq(blockchain_tx_id_.clone()));
             diesel::update(f)
                 .set(status.eq(transaction_status))
@@ -229,42 +523,40 @@ impl TransactionsRepo for TransactionsRepoImpl {
                 })
         })
     }
-    fn get_account_balance(&self, account_id: AccountId, kind_: AccountKind) -> RepoResult<Amount> {
-        with_tls_connection(|conn| {
-            let cr_sum: Option<Amount> = transactions
-                .filter(cr_account_id.eq(account_id))
-                .select(sum(value))
+
+    fn update_status_by_id(&self, transaction_id_arg: TransactionId, transaction_status: TransactionStatus) -> RepoResult<Transaction> {
+        with_tls_connection("transactions.update_status_by_id", |conn| {
+            let f = transactions.filter(id.eq(transaction_id_arg));
+            diesel::update(f)
+                .set(status.eq(transaction_status))
                 .get_result(conn)
                 .map_err(move |e| {
                     let error_kind = ErrorKind::from(&e);
-                    ectx!(try err e, error_kind => account_id)
-                })?;
-            //sum will return null if there are no rows in select statement returned
-            let cr_sum = cr_sum.unwrap_or_default();
-
-            let dr_sum: Option<Amount> = transactions
-                .filter(dr_account_id.eq(account_id))
-                .select(sum(value))
+                    ectx!(err e, error_kind => transaction_id_arg, transaction_status)
+                })
+        })
+    }
+    fn get_account_balance(&self, account_id: AccountId, kind_: AccountKind) -> RepoResult<Amount> {
+        with_tls_connection("transactions.get_account_balance", |conn| {
+            let account_balance: Option<AccountBalance> = AccountBalances::account_balances
+                .filter(AccountBalances::account_id.eq(account_id))
                 .get_result(conn)
+                .optional()
                 .map_err(move |e| {
                     let error_kind = ErrorKind::from(&e);
                     ectx!(try err e, error_kind => account_id)
                 })?;
-            //sum will return null if there are no rows in select statement returned
-            let dr_sum = dr_sum.unwrap_or_default();
-
-            match kind_ {
-                AccountKind::Cr => cr_sum
-                    .checked_sub(dr_sum)
-                    .ok_or_else(|| ectx!(err ErrorContext::BalanceOverflow, ErrorKind::Internal => account_id)),
-                AccountKind::Dr => dr_sum
-                    .checked_sub(cr_sum)
+            match account_balance {
+                // there were no transactions with this account yet
+                None => Ok(Amount::new(0)),
+                Some(account_balance) => account_balance
+                    .balance(kind_)
                     .ok_or_else(|| ectx!(err ErrorContext::BalanceOverflow, ErrorKind::Internal => account_id)),
             }
         })
     }
     fn get_account_spending(&self, account_id: AccountId, kind_: AccountKind, period: Duration) -> RepoResult<Amount> {
-        with_tls_connection(|conn| {
+        with_tls_connection("transactions.get_account_spending", |conn| {
             let date = Utc::now().naive_utc() - period;
             let txs: Vec<Transaction> = match kind_ {
                 AccountKind::Dr => transactions
@@ -290,8 +582,35 @@ impl TransactionsRepo for TransactionsRepoImpl {
         })
     }
 
+    // Deposits credit user's account and withdrawals debit it, withdrawal split between
+    // several of our addresses shares the gid, so groups are counted
+    fn get_account_stats(&self, account_id: AccountId, window: StatsWindow) -> RepoResult<AccountStats> {
+        with_tls_connection("transactions.get_account_stats", |conn| {
+            let date = Utc::now().naive_utc() - window.duration();
+            let stats: AccountStatsQuery = sql_query(
+                "SELECT accounts.currency, COUNT(DISTINCT transactions.gid) FILTER (WHERE transactions.kind = 'deposit' AND transactions.cr_account_id = accounts.id) AS deposits_count, COALESCE(SUM(transactions.value) FILTER (WHERE transactions.kind = 'deposit' AND transactions.cr_account_id = accounts.id), 0) AS deposits_value, COUNT(DISTINCT transactions.gid) FILTER (WHERE transactions.kind = 'withdrawal' AND transactions.dr_account_id = accounts.id) AS withdrawals_count, COALESCE(SUM(transactions.value) FILTER (WHERE transactions.kind = 'withdrawal' AND transactions.dr_account_id = accounts.id), 0) AS withdrawals_value FROM accounts LEFT JOIN transactions ON (transactions.cr_account_id = accounts.id OR transactions.dr_account_id = accounts.id) AND transactions.created_at >= $2 WHERE accounts.id = $1 GROUP BY accounts.id, accounts.currency",
+            )
+            .bind::<SqlUuid, _>(account_id)
+            .bind::<Timestamp, _>(date)
+            .get_result(conn)
+            .map_err(move |e| {
+                let error_kind = ErrorKind::from(&e);
+                ectx!(try err e, error_kind => account_id, window)
+            })?;
+            Ok(AccountStats {
+                account_id,
+                currency: stats.currency,
+                window,
+                deposits_count: stats.deposits_count as u64,
+                deposits_value: stats.deposits_value,
+                withdrawals_count: stats.withdrawals_count as u64,
+                withdrawals_value: stats.withdrawals_value,
+            })
+        })
+    }
+
     fn list_for_user(&self, user_id_arg: UserId, offset: i64, limit: i64) -> RepoResult<Vec<Transaction>> {
-        with_tls_connection(|conn| {
+        with_tls_connection("transactions.list_for_user", |conn| {
             let query = transactions.filter(user_id.eq(user_id_arg)).order(id).offset(offset).limit(limit);
             query.get_results(conn).map_err(move |e| {
                 let error_kind = ErrorKind::from(&e);
@@ -299,8 +618,22 @@ impl TransactionsRepo for TransactionsRepoImpl {
             })
         })
     }
+    fn list_pending_older_than(&self, age: Duration) -> RepoResult<Vec<Transaction>> {
+        with_tls_connection("transactions.list_pending_older_than", |conn| {
+            let date = Utc::now().naive_utc() - age;
+            transactions
+                .filter(status.eq(TransactionStatus::Pending))
+                .filter(created_at.lt(date))
+                .order(created_at)
+                .get_results(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => date)
+                })
+        })
+    }
     fn list_for_account(&self, account_id: AccountId, offset: i64, limit: i64) -> RepoResult<Vec<Transaction>> {
-        with_tls_connection(|conn| {
+        with_tls_connection("transactions.list_for_account", |conn| {
             transactions
                 .filter(dr_account_id.eq(account_id).or(cr_account_id.eq(account_id)))
                 .order(created_at.desc())
@@ -313,48 +646,113 @@ impl TransactionsRepo for TransactionsRepoImpl {
                 })
         })
     }
-    fn list_groups_for_account_skip_approval(&self, account_id: AccountId, offset: i64, limit: i64) -> RepoResult<Vec<Transaction>> {
-        with_tls_connection(|conn| {
-            let gids: Vec<GidQuery> =
-                sql_query(
-                "SELECT gid, min(created_at) AS created_at FROM transactions WHERE group_kind <> 'approval' AND (cr_account_id = $1 OR dr_account_id = $1) GROUP BY gid ORDER BY created_at DESC OFFSET $2 LIMIT $3")
-                    .bind::<SqlUuid, _>(account_id)
-                    .bind::<BigInt, _>(offset)
-                    .bind::<BigInt, _>(limit)
-                    .get_results(conn)
-                    .map_err(move |e| {
-                        let error_kind = ErrorKind::from(&e);
-                        ectx!(try err e, error_kind)
-                    })?;
-            let gids: Vec<_> = gids.into_iter().map(|tuple| tuple.gid).collect();
-            transactions
-                .filter(gid.eq(any(gids)))
-                .order(created_at.desc())
+    fn list_groups_for_account_skip_approval(
+        &self,
+        account_id: AccountId,
+        offset: i64,
+        limit: i64,
+        sort: TransactionsSort,
+    ) -> RepoResult<Vec<Transaction>> {
+        with_tls_connection("transactions.list_groups_for_account_skip_approval", |conn| {
+            let query = format!(
+                "SELECT gid, created_at FROM tx_groups WHERE group_kind <> 'approval' AND account_ids @> ARRAY[$1] ORDER BY {} OFFSET $2 LIMIT $3",
+                group_order_by(sort)
+            );
+            let gids: Vec<GidQuery> = sql_query(query)
+                .bind::<SqlUuid, _>(account_id)
+                .bind::<BigInt, _>(offset)
+                .bind::<BigInt, _>(limit)
                 .get_results(conn)
                 .map_err(move |e| {
                     let error_kind = ErrorKind::from(&e);
-                    ectx!(err e, error_kind)
-                })
+                    ectx!(try err e, error_kind)
+                })?;
+            list_groups_in_order(conn, gids.into_iter().map(|tuple| tuple.gid).collect())
         })
     }
 
-    fn list_groups_for_user_skip_approval(&self, user_id_: UserId, offset: i64, limit: i64) -> RepoResult<Vec<Transaction>> {
-        with_tls_connection(|conn| {
+    fn list_groups_for_account_before(
+        &self,
+        account_id: AccountId,
+        before: Option<(NaiveDateTime, TransactionId)>,
+        limit: i64,
+    ) -> RepoResult<Vec<Transaction>> {
+        with_tls_connection("transactions.list_groups_for_account_before", |conn| {
+            let gids: Vec<GidQuery> = match before {
+                Some((created_at_, gid_)) => sql_query(
+                    "SELECT gid, created_at FROM tx_groups WHERE group_kind <> 'approval' AND account_ids @> ARRAY[$1] AND (created_at, gid) < ($2, $3) ORDER BY created_at DESC, gid DESC LIMIT $4",
+                )
+                .bind::<SqlUuid, _>(account_id)
+                .bind::<Timestamp, _>(created_at_)
+                .bind::<SqlUuid, _>(gid_)
+                .bind::<BigInt, _>(limit)
+                .get_results(conn),
+                None => sql_query(
+                    "SELECT gid, created_at FROM tx_groups WHERE group_kind <> 'approval' AND account_ids @> ARRAY[$1] ORDER BY created_at DESC, gid DESC LIMIT $2",
+                )
+                .bind::<SqlUuid, _>(account_id)
+                .bind::<BigInt, _>(limit)
+                .get_results(conn),
+            }
+            .map_err(move |e| {
+                let error_kind = ErrorKind::from(&e);
+                ectx!(try err e, error_kind => account_id, before, limit)
+            })?;
+            list_groups_in_order(conn, gids.into_iter().map(|tuple| tuple.gid).collect())
+        })
+    }
+
+    fn list_groups_for_user_skip_approval(
+        &self,
+        user_id_: UserId,
+        offset: i64,
+        limit: i64,
+        sort: TransactionsSort,
+    ) -> RepoResult<Vec<Transaction>> {
+        with_tls_connection("transactions.list_groups_for_user_skip_approval", |conn| {
+            let query = format!(
+                "SELECT gid, created_at FROM tx_groups WHERE group_kind <> 'approval' AND user_id = $1 ORDER BY {} OFFSET $2 LIMIT $3",
+                group_order_by(sort)
+            );
+            let gids: Vec<GidQuery> = sql_query(query)
+                .bind::<SqlUuid, _>(user_id_)
+                .bind::<BigInt, _>(offset)
+                .bind::<BigInt, _>(limit)
+                .get_results(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(try err e, error_kind)
+                })?;
+            list_groups_in_order(conn, gids.into_iter().map(|tuple| tuple.gid).collect())
+        })
+    }
+
+    fn list_groups_for_user_in_period(
+        &self,
+        user_id_: UserId,
+        from: NaiveDateTime,
+        to: NaiveDateTime,
+        offset: i64,
+        limit: i64,
+    ) -> RepoResult<Vec<Transaction>> {
+        with_tls_connection("transactions.list_groups_for_user_in_period", |conn| {
             let gids: Vec<GidQuery> =
                 sql_query(
-                "SELECT gid, min(created_at) AS created_at FROM transactions WHERE group_kind <> 'approval' AND user_id = $1 GROUP BY gid ORDER BY created_at DESC OFFSET $2 LIMIT $3")
+                "SELECT gid, created_at FROM tx_groups WHERE group_kind <> 'approval' AND user_id = $1 AND created_at >= $2 AND created_at < $3 ORDER BY created_at, gid OFFSET $4 LIMIT $5")
                     .bind::<SqlUuid, _>(user_id_)
+                    .bind::<Timestamp, _>(from)
+                    .bind::<Timestamp, _>(to)
                     .bind::<BigInt, _>(offset)
                     .bind::<BigInt, _>(limit)
                     .get_results(conn)
                     .map_err(move |e| {
                         let error_kind = ErrorKind::from(&e);
-                        ectx!(try err e, error_kind)
+                        ectx!(try err e, error_kind => user_id_, from, to, offset, limit)
                     })?;
             let gids: Vec<_> = gids.into_iter().map(|tuple| tuple.gid).collect();
-            transactions
-                .filter(gid.eq(any(gids)))
-                .order(created_at.desc())
+            AllTransactions::all_transactions
+                .filter(AllTransactions::gid.eq(any(gids)))
+                .order(AllTransactions::created_at)
                 .get_results(conn)
                 .map_err(move |e| {
                     let error_kind = ErrorKind::from(&e);
@@ -368,7 +766,7 @@ impl TransactionsRepo for TransactionsRepoImpl {
         transaction_id_arg: TransactionId,
         blockchain_tx_id_: BlockchainTransactionId,
     ) -> RepoResult<Transaction> {
-        with_tls_connection(|conn| {
+        with_tls_connection("transactions.update_blockchain_tx", |conn| {
             let f = transactions.filter(id.eq(transaction_id_arg));
             diesel::update(f)
                 .set(blockchain_tx_id.eq(blockchain_tx_id_.clone()))
@@ -379,49 +777,40 @@ impl TransactionsRepo for TransactionsRepoImpl {
                 })
         })
     }
+
+    fn update_meta(&self, transaction_id_arg: TransactionId, meta_: Value) -> RepoResult<Transaction> {
+        with_tls_connection("transactions.update_meta", |conn| {
+            let f = transactions.filter(id.eq(transaction_id_arg));
+            diesel::update(f).set(meta.eq(meta_.clone())).get_result(conn).map_err(move |e| {
+                let error_kind = ErrorKind::from(&e);
+                ectx!(err e, error_kind => transaction_id_arg, meta_)
+            })
+        })
+    }
     fn get_accounts_balance(&self, auth_user_id: UserId, accounts: &[Account]) -> RepoResult<Vec<AccountWithBalance>> {
         // assert all accounts in the same workspace with authed user
-        with_tls_connection(|conn| {
+        with_tls_connection("transactions.get_accounts_balance", |conn| {
             let ids: Vec<_> = accounts.into_iter().map(|acc| acc.id).collect();
-            let txs = transactions
-                .filter(dr_account_id.eq(any(ids.clone())).or(cr_account_id.eq(any(ids))))
-                .get_results::<Transaction>(conn)
+            let account_balances: HashMap<AccountId, AccountBalance> = AccountBalances::account_balances
+                .filter(AccountBalances::account_id.eq_any(ids))
+                .get_results::<AccountBalance>(conn)
                 .map_err(move |e| {
                     let error_kind = ErrorKind::from(&e);
                     ectx!(try err e, error_kind => auth_user_id, accounts)
-                })?;
-            let txs_grouped_initial: HashMap<AccountId, Vec<Transaction>> = accounts.into_iter().map(|acc| (acc.id, vec![])).collect();
-            let txs_grouped: HashMap<AccountId, Vec<Transaction>> = txs.into_iter().fold(txs_grouped_initial, |mut acc, elem| {
-                acc.entry(elem.dr_account_id).and_modify(|txs| txs.push(elem.clone()));
-                acc.entry(elem.cr_account_id).and_modify(|txs| txs.push(elem));
-                acc
-            });
+                })?
+                .into_iter()
+                .map(|account_balance| (account_balance.account_id, account_balance))
+                .collect();
             accounts
                 .into_iter()
                 .map(|account| {
-                    let plus = txs_grouped
-                        .get(&account.id)
-                        .unwrap()
-                        .into_iter()
-                        .filter(|tx| match account.kind {
-                            AccountKind::Cr => tx.cr_account_id == account.id,
-                            AccountKind::Dr => tx.dr_account_id == account.id,
-                        })
-                        .fold(Some(Amount::new(0)), |acc, elem| acc.and_then(|val| val.checked_add(elem.value)))
-                        .ok_or(ectx!(try err ErrorContext::BalanceOverflow, ErrorKind::Internal))?;
-                    let minus = txs_grouped
-                        .get(&account.id)
-                        .unwrap()
-                        .into_iter()
-                        .filter(|tx| match account.kind {
-                            AccountKind::Cr => tx.dr_account_id == account.id,
-                            AccountKind::Dr => tx.cr_account_id == account.id,
-                        })
-                        .fold(Some(Amount::new(0)), |acc, elem| acc.and_then(|val| val.checked_add(elem.value)))
-                        .ok_or(ectx!(try err ErrorContext::BalanceOverflow, ErrorKind::Internal))?;
-                    let balance = plus
-                        .checked_sub(minus)
-                        .ok_or(ectx!(try err ErrorContext::BalanceOverflow, ErrorKind::Internal))?;
+                    let balance = match account_balances.get(&account.id) {
+                        // there were no transactions with this account yet
+                        None => Amount::new(0),
+                        Some(account_balance) => account_balance
+                            .balance(account.kind)
+                            .ok_or(ectx!(try err ErrorContext::BalanceOverflow, ErrorKind::Internal => account))?,
+                    };
                     Ok(AccountWithBalance {
                         account: account.clone(),
                         balance,
@@ -440,8 +829,7 @@ impl TransactionsRepo for TransactionsRepoImpl {
         currency_: Currency,
         total_fee: Amount,
     ) -> RepoResult<Vec<AccountWithBalance>> {
-        let system_fees_accounts_ids = self.system_fees_accounts_ids.clone();
-        with_tls_connection(|conn| {
+        with_tls_connection("transactions.get_accounts_for_withdrawal", |conn| {
             let total_fee = match currency_ {
                 // we can drain stq account to 0,
                 Currency::Stq => Amount::new(0),
@@ -456,9 +844,9 @@ impl TransactionsRepo for TransactionsRepoImpl {
                 // i.e. withdrawal will not worth it
                 Currency::Stq => MIN_SIGNIFICANT_STQ,
             };
-            // get all dr accounts
+            // get positive balances of all dr accounts
             let dr_sum_accounts: Vec<TransactionSum> = sql_query(
-                "SELECT SUM(value) as sum, dr_account_id as account_id FROM transactions WHERE currency = $1 GROUP BY dr_account_id",
+                "SELECT account_balances.account_id, account_balances.dr_turnover - account_balances.cr_turnover AS sum FROM account_balances INNER JOIN accounts ON accounts.id = account_balances.account_id WHERE accounts.currency = $1 AND account_balances.dr_turnover > account_balances.cr_turnover",
             )
             .bind::<VarChar, _>(currency_)
             .get_results(conn)
@@ -466,29 +854,11 @@ impl TransactionsRepo for TransactionsRepoImpl {
                 let error_kind = ErrorKind::from(&e);
                 ectx!(try err e, error_kind)
             })?;
-            let mut dr_sum_accounts = dr_sum_accounts
+            let dr_sum_accounts = dr_sum_accounts
                 .into_iter()
                 .map(|r: TransactionSum| (r.account_id, r.sum))
                 .collect::<HashMap<AccountId, Amount>>();
 
-            // get all cr accounts
-            let cr_sum_accounts: Vec<TransactionSum> = sql_query(
-                "SELECT SUM(value) as sum, cr_account_id as account_id FROM transactions WHERE currency = $1 GROUP BY cr_account_id",
-            )
-            .bind::<VarChar, _>(currency_)
-            .get_results(conn)
-            .map_err(move |e| {
-                let error_kind = ErrorKind::from(&e);
-                ectx!(try err e, error_kind)
-            })?;
-
-            // get accounts balance
-            for tr in cr_sum_accounts {
-                if let Some(dr_sum) = dr_sum_accounts.get_mut(&tr.account_id) {
-                    *dr_sum = dr_sum.checked_sub(tr.sum).unwrap_or_default();
-                }
-            }
-
             // filtering accounts with empty balance
             let mut remaining_accounts: HashMap<AccountId, Amount> =
                 dr_sum_accounts.into_iter().filter(|(_, sum)| sum.raw() > minimum_balance).collect();
@@ -503,17 +873,22 @@ impl TransactionsRepo for TransactionsRepoImpl {
                     ectx!(try err e, error_kind => value_, currency_)
                 })?;
 
+            // eth and stq ones are only tried last, as their transactions wait for the pending ones to get nonces
+            let mut pending_accounts: HashSet<AccountId> = HashSet::new();
             for tx in pending_transactions {
                 if currency_ == Currency::Btc {
                     remaining_accounts.remove(&tx.cr_account_id);
                     remaining_accounts.remove(&tx.dr_account_id);
+                } else {
+                    pending_accounts.insert(tx.cr_account_id);
+                    pending_accounts.insert(tx.dr_account_id);
                 }
             }
 
             let res_account_ids: Vec<AccountId> = remaining_accounts.keys().cloned().collect();
 
             let fees_accounts: Vec<Account> = Accounts::accounts
-                .filter(Accounts::id.eq_any(system_fees_accounts_ids))
+                .filter(Accounts::system_role.eq(SystemAccountKind::Fees))
                 .get_results(conn)
                 .map_err(move |e| {
                     let error_kind = ErrorKind::from(&e);
@@ -526,6 +901,7 @@ impl TransactionsRepo for TransactionsRepoImpl {
             let res_accounts: Vec<Account> = Accounts::accounts
                 .filter(Accounts::id.eq_any(res_account_ids))
                 .filter(Accounts::kind.eq(AccountKind::Dr))
+                .filter(Accounts::archived.eq(false))
                 .filter(Accounts::address.ne_all(fees_accounts_addresses)) // removing fees accounts from result
                 .get_results(conn)
                 .map_err(move |e| {
@@ -542,6 +918,8 @@ impl TransactionsRepo for TransactionsRepoImpl {
                 .filter(|(acc, _)| currency_ != Currency::Stq || acc.erc20_approved)
                 .collect();
 
+            let res_accounts = order_withdrawal_pool(res_accounts, &pending_accounts);
+
             // calculating accounts to take
             let mut r = vec![];
             for (acc, balance) in res_accounts.clone() {
@@ -569,39 +947,217 @@ impl TransactionsRepo for TransactionsRepoImpl {
             }
         })
     }
-}
 
-#[cfg(test)]
-pub mod tests {
-    use diesel::r2d2::ConnectionManager;
-    use diesel::PgConnection;
-    use futures_cpupool::CpuPool;
-    use r2d2;
-    use tokio_core::reactor::Core;
+    fn get_dr_balances(&self, currency_: Currency) -> RepoResult<Vec<AccountWithBalance>> {
+        with_tls_connection("transactions.get_dr_balances", |conn| {
+            let dr_sum_accounts: HashMap<AccountId, Amount> = sql_query(
+                "SELECT account_balances.account_id, account_balances.dr_turnover - account_balances.cr_turnover AS sum FROM account_balances INNER JOIN accounts ON accounts.id = account_balances.account_id WHERE accounts.currency = $1 AND accounts.kind = 'dr' AND NOT accounts.archived AND account_balances.dr_turnover > account_balances.cr_turnover",
+            )
+            .bind::<VarChar, _>(currency_)
+            .get_results::<TransactionSum>(conn)
+            .map_err(move |e| {
+                let error_kind = ErrorKind::from(&e);
+                ectx!(try err e, error_kind => currency_)
+            })?
+            .into_iter()
+            .map(|r| (r.account_id, r.sum))
+            .collect();
+            let account_ids: Vec<AccountId> = dr_sum_accounts.keys().cloned().collect();
+            let accounts: Vec<Account> = Accounts::accounts
+                .filter(Accounts::id.eq_any(account_ids))
+                .get_results(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(try err e, error_kind => currency_)
+                })?;
+            Ok(accounts
+                .into_iter()
+                .map(|account| {
+                    let balance = dr_sum_accounts.get(&account.id).cloned().unwrap_or_default();
+                    AccountWithBalance { account, balance }
+                })
+                .collect())
+        })
+    }
 
-    use super::*;
-    use config::Config;
-    use repos::DbExecutorImpl;
+    fn create_group_fee(&self, payload: NewGroupFee) -> RepoResult<GroupFee> {
+        with_tls_connection("transactions.create_group_fee", |conn| {
+            diesel::insert_into(GroupFees::group_fees)
+                .values(payload.clone())
+                .get_result::<GroupFee>(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => payload)
+                })
+        })
+    }
 
-    fn create_executor() -> DbExecutorImpl {
-        let config = Config::new().unwrap();
-        let manager = ConnectionManager::<PgConnection>::new(config.database.url);
-        let db_pool = r2d2::Pool::builder().build(manager).unwrap();
-        let cpu_pool = CpuPool::new(1);
-        DbExecutorImpl::new(db_pool.clone(), cpu_pool.clone())
+    fn accrue_blockchain_fee(&self, gid_: TransactionId, currency_: Currency, value_: Amount) -> RepoResult<GroupFee> {
+        with_tls_connection("transactions.accrue_blockchain_fee", |conn| {
+            diesel::insert_into(GroupFees::group_fees)
+                .values(NewGroupFee::uncharged(gid_, currency_, value_))
+                .on_conflict(GroupFees::gid)
+                .do_update()
+                .set(GroupFees::blockchain_fee.eq(GroupFees::blockchain_fee + value_))
+                .get_result::<GroupFee>(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => gid_, currency_, value_)
+                })
+        })
     }
 
-    #[test]
-    fn transactions_create() {
-        let mut core = Core::new().unwrap();
-        let db_executor = create_executor();
-        let users_repo = UsersRepoImpl::default();
-        let accounts_repo = AccountsRepoImpl::default();
-        let transactions_repo = TransactionsRepoImpl::default();
-        let new_user = NewUser::default();
-        let _ = core.run(db_executor.execute_test_transaction(move || {
-            let user = users_repo.create(new_user)?;
-            let mut new_account = NewAccount::default();
+    fn get_group_fee(&self, gid_: TransactionId) -> RepoResult<Option<GroupFee>> {
+        with_tls_connection("transactions.get_group_fee", |conn| {
+            GroupFees::group_fees
+                .filter(GroupFees::gid.eq(gid_))
+                .get_result::<GroupFee>(conn)
+                .optional()
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => gid_)
+                })
+        })
+    }
+
+    fn get_fee_margins(&self) -> RepoResult<HashMap<Currency, (Amount, Amount)>> {
+        with_tls_connection("transactions.get_fee_margins", |conn| {
+            GroupFees::group_fees
+                .group_by(GroupFees::blockchain_fee_currency)
+                .select((
+                    GroupFees::blockchain_fee_currency,
+                    sum(GroupFees::charged_blockchain_fee),
+                    sum(GroupFees::blockchain_fee),
+                ))
+                .get_results::<(Currency, Option<Amount>, Option<Amount>)>(conn)
+                .map(|sums| {
+                    sums.into_iter()
+                        .map(|(currency_, charged, paid)| (currency_, (charged.unwrap_or_default(), paid.unwrap_or_default())))
+                        .collect()
+                })
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind)
+                })
+        })
+    }
+
+    fn deduct_refunded_fee(&self, gid_: TransactionId, fee_: Amount, charged_blockchain_fee_: Amount) -> RepoResult<GroupFee> {
+        with_tls_connection("transactions.deduct_refunded_fee", |conn| {
+            diesel::update(GroupFees::group_fees.filter(GroupFees::gid.eq(gid_)))
+                .set((
+                    GroupFees::fee.eq(GroupFees::fee - fee_),
+                    GroupFees::charged_blockchain_fee.eq(GroupFees::charged_blockchain_fee - charged_blockchain_fee_),
+                ))
+                .get_result::<GroupFee>(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => gid_, fee_, charged_blockchain_fee_)
+                })
+        })
+    }
+}
+
+/// Turnovers of accounts as of the latest balance checkpoint plus transactions created after it, so that
+/// the whole ledger is not aggregated. If `until` is set, only transactions created before `$1` are counted
+fn turnovers_sql(until: bool) -> String {
+    format!(
+        "WITH checkpoint AS (SELECT max(as_of) AS as_of FROM balance_checkpoints), \
+         recent AS (SELECT dr_account_id, cr_account_id, value FROM transactions WHERE created_at >= COALESCE((SELECT as_of FROM checkpoint), '-infinity'){} ) \
+         SELECT accounts.id AS account_id, accounts.kind, accounts.address, accounts.currency, \
+         COALESCE(balance_checkpoints.dr_turnover, 0) + COALESCE(dr.sum, 0) AS dr_turnover, \
+         COALESCE(balance_checkpoints.cr_turnover, 0) + COALESCE(cr.sum, 0) AS cr_turnover \
+         FROM accounts \
+         LEFT JOIN balance_checkpoints ON balance_checkpoints.account_id = accounts.id AND balance_checkpoints.as_of = (SELECT as_of FROM checkpoint) \
+         LEFT JOIN (SELECT dr_account_id, SUM(value) FROM recent GROUP BY dr_account_id) AS dr ON dr.dr_account_id = accounts.id \
+         LEFT JOIN (SELECT cr_account_id, SUM(value) FROM recent GROUP BY cr_account_id) AS cr ON cr.cr_account_id = accounts.id",
+        if until { " AND created_at < $1" } else { "" }
+    )
+}
+
+/// ORDER BY clause for query over tx_groups, which is maintained by trigger, so pages are read
+/// without aggregating the ledger. Only whitelisted columns get into sql, so it's safe to put the clause into the query text.
+fn group_order_by(sort: TransactionsSort) -> String {
+    let expression = match sort.field {
+        TransactionsSortField::CreatedAt => "created_at",
+        TransactionsSortField::Value => "value",
+        TransactionsSortField::Status => "status",
+    };
+    format!("{0} {1}, gid {1}", expression, direction_sql(sort.direction))
+}
+
+fn direction_sql(direction: SortDirection) -> &'static str {
+    match direction {
+        SortDirection::Asc => "ASC",
+        SortDirection::Desc => "DESC",
+    }
+}
+
+/// Transactions of the groups with groups in the order of `gids`, archived ones included
+fn list_groups_in_order(conn: &PgConnection, gids: Vec<TransactionId>) -> RepoResult<Vec<Transaction>> {
+    let positions: HashMap<TransactionId, usize> = gids.iter().enumerate().map(|(position, gid_)| (*gid_, position)).collect();
+    let mut txs: Vec<Transaction> = AllTransactions::all_transactions
+        .filter(AllTransactions::gid.eq(any(gids)))
+        .order(AllTransactions::created_at.desc())
+        .get_results(conn)
+        .map_err(move |e| {
+            let error_kind = ErrorKind::from(&e);
+            ectx!(try err e, error_kind)
+        })?;
+    // sort is stable, so transactions inside a group are still the newest first
+    txs.sort_by_key(|tx| positions.get(&tx.gid).cloned());
+    Ok(txs)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use diesel::r2d2::ConnectionManager;
+    use diesel::PgConnection;
+    use futures_cpupool::CpuPool;
+    use r2d2;
+    use tokio_core::reactor::Core;
+
+    use super::*;
+    use config::Config;
+    use repos::DbExecutorImpl;
+
+    fn create_executor() -> DbExecutorImpl {
+        let config = Config::new().unwrap();
+        let manager = ConnectionManager::<PgConnection>::new(config.database.url);
+        let db_pool = r2d2::Pool::builder().build(manager).unwrap();
+        let cpu_pool = CpuPool::new(1);
+        DbExecutorImpl::new(db_pool.clone(), cpu_pool.clone())
+    }
+
+    #[test]
+    fn test_order_withdrawal_pool() {
+        let mut accounts: Vec<(Account, Amount)> = (0..4).map(|_| (Account::default(), Amount::new(100))).collect();
+        let ids: Vec<AccountId> = accounts.iter().map(|(acc, _)| acc.id).collect();
+        let now = Utc::now().naive_utc();
+        accounts[0].0.last_spent_at = Some(now);
+        accounts[1].0.last_spent_at = Some(now - Duration::minutes(5));
+        accounts[2].0.last_spent_at = Some(now - Duration::minutes(10));
+        let mut pending_accounts = HashSet::new();
+        pending_accounts.insert(ids[2]);
+        let ordered: Vec<AccountId> = order_withdrawal_pool(accounts, &pending_accounts)
+            .into_iter()
+            .map(|(acc, _)| acc.id)
+            .collect();
+        // never spent from first, then least recently spent from, accounts with pending transactions last
+        assert_eq!(ordered, vec![ids[3], ids[1], ids[0], ids[2]]);
+    }
+
+    #[test]
+    fn transactions_create() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let users_repo = UsersRepoImpl::default();
+        let accounts_repo = AccountsRepoImpl::default();
+        let transactions_repo = TransactionsRepoImpl::default();
+        let new_user = NewUser::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let user = users_repo.create(new_user)?;
+            let mut new_account = NewAccount::default();
             new_account.user_id = user.id;
             let acc1 = accounts_repo.create(new_account)?;
             let mut new_account = NewAccount::default();
@@ -620,6 +1176,82 @@ pub mod tests {
         }));
     }
 
+    #[test]
+    fn transactions_create_batch() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let users_repo = UsersRepoImpl::default();
+        let accounts_repo = AccountsRepoImpl::default();
+        let transactions_repo = TransactionsRepoImpl::default();
+        let new_user = NewUser::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let user = users_repo.create(new_user)?;
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            let acc1 = accounts_repo.create(new_account)?;
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            let acc2 = accounts_repo.create(new_account)?;
+
+            let payloads: Vec<NewTransaction> = (1..4)
+                .map(|i| {
+                    let mut trans = NewTransaction::default();
+                    trans.cr_account_id = acc1.id;
+                    trans.dr_account_id = acc2.id;
+                    trans.user_id = user.id;
+                    trans.value = Amount::new(i);
+                    trans
+                })
+                .collect();
+            let ids: Vec<TransactionId> = payloads.iter().map(|trans| trans.id).collect();
+
+            let res = transactions_repo.create_batch(payloads);
+            assert!(res.is_ok());
+            let created_ids: Vec<TransactionId> = res.as_ref().unwrap().iter().map(|trans| trans.id).collect();
+            assert_eq!(created_ids, ids);
+            res
+        }));
+    }
+
+    #[test]
+    fn transactions_assert_non_negative_balances() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let users_repo = UsersRepoImpl::default();
+        let accounts_repo = AccountsRepoImpl::default();
+        let transactions_repo = TransactionsRepoImpl::default();
+        let new_user = NewUser::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let user = users_repo.create(new_user)?;
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            new_account.kind = AccountKind::Dr;
+            let acc1 = accounts_repo.create(new_account)?;
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            new_account.kind = AccountKind::Cr;
+            let acc2 = accounts_repo.create(new_account)?;
+
+            let mut trans = NewTransaction::default();
+            trans.cr_account_id = acc2.id;
+            trans.dr_account_id = acc1.id;
+            trans.user_id = user.id;
+            trans.value = Amount::new(100);
+            let _ = transactions_repo.create(trans)?;
+            assert!(transactions_repo.assert_non_negative_balances(&[acc1.id, acc2.id]).is_ok());
+
+            let mut trans = NewTransaction::default();
+            trans.cr_account_id = acc1.id;
+            trans.dr_account_id = acc2.id;
+            trans.user_id = user.id;
+            trans.value = Amount::new(101);
+            let _ = transactions_repo.create(trans)?;
+            let res = transactions_repo.assert_non_negative_balances(&[acc2.id]);
+            assert!(res.is_err());
+            Ok::<_, Error>(())
+        }));
+    }
+
     #[test]
     fn transactions_read() {
         let mut core = Core::new().unwrap();
@@ -650,6 +1282,193 @@ pub mod tests {
         }));
     }
 
+    #[test]
+    fn transactions_get_group_with_related() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let users_repo = UsersRepoImpl::default();
+        let accounts_repo = AccountsRepoImpl::default();
+        let transactions_repo = TransactionsRepoImpl::default();
+        let new_user = NewUser::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let user = users_repo.create(new_user)?;
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            let acc1 = accounts_repo.create(new_account)?;
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            let acc2 = accounts_repo.create(new_account)?;
+
+            let mut trans = NewTransaction::default();
+            trans.cr_account_id = acc1.id;
+            trans.dr_account_id = acc2.id;
+            trans.user_id = user.id;
+            trans.value = Amount::new(123);
+            let transaction = transactions_repo.create(trans.clone())?;
+
+            let reversal_id = TransactionId::generate();
+            let mut reversal = trans.clone();
+            reversal.id = reversal_id;
+            reversal.gid = reversal_id;
+            reversal.cr_account_id = acc2.id;
+            reversal.dr_account_id = acc1.id;
+            reversal.group_kind = TransactionGroupKind::Reversal;
+            reversal.related_tx = Some(transaction.id);
+            transactions_repo.create(reversal)?;
+
+            let tree = transactions_repo.get_group_with_related(transaction.gid)?;
+            assert_eq!(tree.transactions.len(), 1);
+            assert_eq!(tree.related.iter().map(|group| group.gid).collect::<Vec<_>>(), vec![reversal_id]);
+            assert!(tree.contains_group_kind(TransactionGroupKind::Reversal));
+            // reversal has nothing that refers to it
+            let tree = transactions_repo.get_group_with_related(reversal_id)?;
+            assert!(tree.related.is_empty());
+            Ok::<_, Error>(())
+        }));
+    }
+
+    #[test]
+    fn transactions_get_group_summary() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let users_repo = UsersRepoImpl::default();
+        let accounts_repo = AccountsRepoImpl::default();
+        let transactions_repo = TransactionsRepoImpl::default();
+        let new_user = NewUser::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let user = users_repo.create(new_user)?;
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            let acc1 = accounts_repo.create(new_account)?;
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            let acc2 = accounts_repo.create(new_account)?;
+
+            let mut trans = NewTransaction::default();
+            trans.cr_account_id = acc1.id;
+            trans.dr_account_id = acc2.id;
+            trans.user_id = user.id;
+            trans.value = Amount::new(123);
+            trans.status = TransactionStatus::Pending;
+            trans.blockchain_tx_id = Some(BlockchainTransactionId::default());
+            let transaction = transactions_repo.create(trans.clone())?;
+            let mut fee = trans.clone();
+            fee.id = TransactionId::generate();
+            fee.kind = TransactionKind::Fee;
+            fee.value = Amount::new(10);
+            fee.status = TransactionStatus::Done;
+            fee.blockchain_tx_id = None;
+            transactions_repo.create(fee)?;
+
+            let summary = transactions_repo.get_group_summary(transaction.gid)?.unwrap();
+            assert_eq!(summary.user_id, user.id);
+            assert_eq!(summary.status, TransactionStatus::Pending);
+            // fee is not a part of the value
+            assert_eq!(summary.value, Amount::new(123));
+            let mut account_ids = summary.account_ids.clone();
+            account_ids.sort_by_key(|account_id| account_id.inner().clone());
+            let mut expected = vec![acc1.id, acc2.id];
+            expected.sort_by_key(|account_id| account_id.inner().clone());
+            assert_eq!(account_ids, expected);
+
+            transactions_repo.update_status(transaction.blockchain_tx_id.unwrap(), TransactionStatus::Done)?;
+            let summary = transactions_repo.get_group_summary(transaction.gid)?.unwrap();
+            assert_eq!(summary.status, TransactionStatus::Done);
+            Ok::<_, Error>(())
+        }));
+    }
+
+    #[test]
+    fn transactions_create_balance_checkpoint() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let users_repo = UsersRepoImpl::default();
+        let accounts_repo = AccountsRepoImpl::default();
+        let transactions_repo = TransactionsRepoImpl::default();
+        let new_user = NewUser::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let user = users_repo.create(new_user)?;
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            let acc1 = accounts_repo.create(new_account)?;
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            let acc2 = accounts_repo.create(new_account)?;
+
+            let mut trans = NewTransaction::default();
+            trans.cr_account_id = acc1.id;
+            trans.dr_account_id = acc2.id;
+            trans.user_id = user.id;
+            trans.value = Amount::new(123);
+            transactions_repo.create(trans.clone())?;
+            let turnovers_before = transactions_repo.get_accounts_turnovers()?;
+
+            let as_of = Utc::now().naive_utc() + Duration::seconds(60);
+            assert!(transactions_repo.create_balance_checkpoint(as_of)?.unwrap() >= 2);
+            // not older than the latest one
+            assert!(transactions_repo.create_balance_checkpoint(as_of)?.is_none());
+            // turnovers are the same when read through the checkpoint
+            let turnovers_after = transactions_repo.get_accounts_turnovers()?;
+            let find = |turnovers: &[AccountTurnover], account_id: AccountId| {
+                turnovers
+                    .iter()
+                    .find(|turnover| turnover.account_id == account_id)
+                    .map(|turnover| (turnover.dr_turnover, turnover.cr_turnover))
+            };
+            assert_eq!(find(&turnovers_after, acc1.id), Some((Amount::new(0), Amount::new(123))));
+            assert_eq!(find(&turnovers_after, acc2.id), find(&turnovers_before, acc2.id));
+            Ok::<_, Error>(())
+        }));
+    }
+
+    #[test]
+    fn transactions_archive_groups() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let users_repo = UsersRepoImpl::default();
+        let accounts_repo = AccountsRepoImpl::default();
+        let transactions_repo = TransactionsRepoImpl::default();
+        let new_user = NewUser::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let user = users_repo.create(new_user)?;
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            let acc1 = accounts_repo.create(new_account)?;
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            let acc2 = accounts_repo.create(new_account)?;
+
+            let mut trans = NewTransaction::default();
+            trans.cr_account_id = acc1.id;
+            trans.dr_account_id = acc2.id;
+            trans.user_id = user.id;
+            trans.value = Amount::new(123);
+            trans.status = TransactionStatus::Done;
+            let transaction = transactions_repo.create(trans)?;
+
+            let system_balances = transactions_repo.get_system_balances()?;
+            let exchange_margins = transactions_repo.get_exchange_margins()?;
+            let blockchain_balances = transactions_repo.get_blockchain_balances()?;
+            let horizon = Utc::now().naive_utc() + Duration::seconds(60);
+            // nothing is archived before a checkpoint covers it
+            assert_eq!(transactions_repo.archive_groups(horizon, 1000)?, 0);
+            transactions_repo.create_balance_checkpoint(horizon + Duration::seconds(1))?;
+            assert!(transactions_repo.archive_groups(horizon, 1000)? >= 1);
+
+            // still read and listed, balances are not changed
+            assert_eq!(transactions_repo.get_by_gid(transaction.gid)?.len(), 1);
+            assert!(transactions_repo.get(transaction.id)?.is_some());
+            assert!(transactions_repo.get_group_summary(transaction.gid)?.unwrap().archived);
+            assert_eq!(transactions_repo.get_account_balance(acc1.id, AccountKind::Cr)?, Amount::new(123));
+            assert_eq!(transactions_repo.get_system_balances()?, system_balances);
+            assert_eq!(transactions_repo.get_exchange_margins()?, exchange_margins);
+            assert_eq!(transactions_repo.get_blockchain_balances()?, blockchain_balances);
+            let groups = transactions_repo.list_groups_for_user_skip_approval(user.id, 0, 10, TransactionsSort::default())?;
+            assert_eq!(groups.len(), 1);
+            Ok::<_, Error>(())
+        }));
+    }
+
     #[test]
     fn transactions_update_status() {
         let mut core = Core::new().unwrap();
@@ -682,6 +1501,69 @@ pub mod tests {
         }));
     }
 
+    #[test]
+    fn transactions_update_status_by_id() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let users_repo = UsersRepoImpl::default();
+        let accounts_repo = AccountsRepoImpl::default();
+        let transactions_repo = TransactionsRepoImpl::default();
+        let new_user = NewUser::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let user = users_repo.create(new_user)?;
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            let acc1 = accounts_repo.create(new_account)?;
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            let acc2 = accounts_repo.create(new_account)?;
+
+            let mut trans = NewTransaction::default();
+            trans.cr_account_id = acc1.id;
+            trans.dr_account_id = acc2.id;
+            trans.user_id = user.id;
+            trans.value = Amount::new(123);
+            trans.status = TransactionStatus::Pending;
+            trans.blockchain_tx_id = None;
+
+            let transaction = transactions_repo.create(trans)?;
+            let res = transactions_repo.update_status_by_id(transaction.id, TransactionStatus::Done)?;
+            assert_eq!(res.id, transaction.id);
+            assert_eq!(res.status, TransactionStatus::Done);
+            Ok(res)
+        }));
+    }
+
+    #[test]
+    fn transactions_accrue_blockchain_fee() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let transactions_repo = TransactionsRepoImpl::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let charged = transactions_repo.create_group_fee(NewGroupFee {
+                gid: TransactionId::generate(),
+                fee_currency: Currency::Stq,
+                fee: Amount::new(1000),
+                blockchain_fee_currency: Currency::Eth,
+                charged_blockchain_fee: Amount::new(200),
+                blockchain_fee: Amount::new(0),
+            })?;
+            transactions_repo.accrue_blockchain_fee(charged.gid, Currency::Eth, Amount::new(30))?;
+            let res = transactions_repo.accrue_blockchain_fee(charged.gid, Currency::Eth, Amount::new(20))?;
+            assert_eq!(res.blockchain_fee, Amount::new(50));
+            assert_eq!(res.fee, Amount::new(1000));
+            assert_eq!(res.charged_blockchain_fee, Amount::new(200));
+            assert_eq!(transactions_repo.get_group_fee(charged.gid)?, Some(res));
+            // group that wasn't charged gets its row with the first accrual
+            let uncharged = transactions_repo.accrue_blockchain_fee(TransactionId::generate(), Currency::Btc, Amount::new(10))?;
+            assert_eq!(uncharged.fee, Amount::new(0));
+            assert_eq!(uncharged.charged_blockchain_fee, Amount::new(0));
+            assert_eq!(uncharged.blockchain_fee, Amount::new(10));
+            assert_eq!(uncharged.blockchain_fee_currency, Currency::Btc);
+            Ok(uncharged)
+        }));
+    }
+
     #[test]
     fn transactions_list_for_user() {
         let mut core = Core::new().unwrap();
@@ -712,6 +1594,38 @@ pub mod tests {
         }));
     }
 
+    #[test]
+    fn transactions_list_pending_older_than() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let users_repo = UsersRepoImpl::default();
+        let accounts_repo = AccountsRepoImpl::default();
+        let transactions_repo = TransactionsRepoImpl::default();
+        let new_user = NewUser::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let user = users_repo.create(new_user)?;
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            let acc1 = accounts_repo.create(new_account)?;
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            let acc2 = accounts_repo.create(new_account)?;
+
+            let mut trans = NewTransaction::default();
+            trans.cr_account_id = acc1.id;
+            trans.dr_account_id = acc2.id;
+            trans.user_id = user.id;
+            trans.value = Amount::new(123);
+            trans.status = TransactionStatus::Pending;
+
+            let _ = transactions_repo.create(trans)?;
+            let res = transactions_repo.list_pending_older_than(Duration::hours(-1));
+            assert!(res.is_ok());
+            assert!(res.as_ref().unwrap().len() > 0);
+            res
+        }));
+    }
+
     #[test]
     fn transactions_get_account_balance() {
         let mut core = Core::new().unwrap();
@@ -741,6 +1655,292 @@ pub mod tests {
             res
         }));
     }
+    #[test]
+    fn transactions_get_dr_balances() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let users_repo = UsersRepoImpl::default();
+        let accounts_repo = AccountsRepoImpl::default();
+        let transactions_repo = TransactionsRepoImpl::default();
+        let new_user = NewUser::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let user = users_repo.create(new_user)?;
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            new_account.currency = Currency::Btc;
+            let cr_account = accounts_repo.create(new_account)?;
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            new_account.currency = Currency::Btc;
+            new_account.kind = AccountKind::Dr;
+            let dr_account = accounts_repo.create(new_account)?;
+
+            let mut trans = NewTransaction::default();
+            trans.cr_account_id = cr_account.id;
+            trans.dr_account_id = dr_account.id;
+            trans.user_id = user.id;
+            trans.currency = Currency::Btc;
+            trans.value = Amount::new(123);
+            transactions_repo.create(trans)?;
+
+            let balances = transactions_repo.get_dr_balances(Currency::Btc)?;
+            let dr_balance = balances.iter().find(|balance| balance.account.id == dr_account.id).unwrap();
+            assert_eq!(dr_balance.balance, Amount::new(123));
+            // credit accounts are not kept on addresses
+            assert!(balances.iter().all(|balance| balance.account.id != cr_account.id));
+            // and archived ones are not used
+            accounts_repo.archive(dr_account.id)?;
+            let balances = transactions_repo.get_dr_balances(Currency::Btc)?;
+            assert!(balances.iter().all(|balance| balance.account.id != dr_account.id));
+            Ok::<_, Error>(())
+        }));
+    }
+    #[test]
+    fn transactions_get_account_stats() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let users_repo = UsersRepoImpl::default();
+        let accounts_repo = AccountsRepoImpl::default();
+        let transactions_repo = TransactionsRepoImpl::default();
+        let new_user = NewUser::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let user = users_repo.create(new_user)?;
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            let acc1 = accounts_repo.create(new_account)?;
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            new_account.kind = AccountKind::Dr;
+            let acc2 = accounts_repo.create(new_account)?;
+
+            let mut trans = NewTransaction::default();
+            trans.cr_account_id = acc1.id;
+            trans.dr_account_id = acc2.id;
+            trans.user_id = user.id;
+            trans.value = Amount::new(100);
+            trans.kind = TransactionKind::Deposit;
+            trans.group_kind = TransactionGroupKind::Deposit;
+            transactions_repo.create(trans)?;
+            // withdrawal sent from two addresses
+            let gid_ = TransactionId::generate();
+            for value_ in &[30, 20] {
+                let mut trans = NewTransaction::default();
+                trans.id = TransactionId::generate();
+                trans.gid = gid_;
+                trans.cr_account_id = acc2.id;
+                trans.dr_account_id = acc1.id;
+                trans.user_id = user.id;
+                trans.value = Amount::new(*value_);
+                trans.kind = TransactionKind::Withdrawal;
+                trans.group_kind = TransactionGroupKind::Withdrawal;
+                transactions_repo.create(trans)?;
+            }
+
+            let stats = transactions_repo.get_account_stats(acc1.id, StatsWindow::Day)?;
+            assert_eq!(stats.deposits_count, 1);
+            assert_eq!(stats.deposits_value, Amount::new(100));
+            assert_eq!(stats.withdrawals_count, 1);
+            assert_eq!(stats.withdrawals_value, Amount::new(50));
+            Ok::<_, Error>(())
+        }));
+    }
+    #[test]
+    fn transactions_get_accounts_turnovers() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let users_repo = UsersRepoImpl::default();
+        let accounts_repo = AccountsRepoImpl::default();
+        let transactions_repo = TransactionsRepoImpl::default();
+        let new_user = NewUser::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let user = users_repo.create(new_user)?;
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            let acc1 = accounts_repo.create(new_account)?;
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            let acc2 = accounts_repo.create(new_account)?;
+
+            let mut trans = NewTransaction::default();
+            trans.cr_account_id = acc1.id;
+            trans.dr_account_id = acc2.id;
+            trans.user_id = user.id;
+            trans.value = Amount::new(123);
+
+            let _ = transactions_repo.create(trans)?;
+            let res = transactions_repo.get_accounts_turnovers();
+            assert!(res.is_ok());
+            let turnovers = res.as_ref().unwrap();
+            let turnover1 = turnovers.iter().find(|t| t.account_id == acc1.id).unwrap();
+            assert_eq!(turnover1.cr_turnover, Amount::new(123));
+            assert_eq!(turnover1.dr_turnover, Amount::new(0));
+            res
+        }));
+    }
+
+    #[test]
+    fn transactions_get_stale_account_balances() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let users_repo = UsersRepoImpl::default();
+        let accounts_repo = AccountsRepoImpl::default();
+        let transactions_repo = TransactionsRepoImpl::default();
+        let new_user = NewUser::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let user = users_repo.create(new_user)?;
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            new_account.kind = AccountKind::Dr;
+            let acc1 = accounts_repo.create(new_account)?;
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            new_account.kind = AccountKind::Cr;
+            let acc2 = accounts_repo.create(new_account)?;
+
+            let mut trans = NewTransaction::default();
+            trans.cr_account_id = acc2.id;
+            trans.dr_account_id = acc1.id;
+            trans.user_id = user.id;
+            trans.value = Amount::new(123);
+
+            let _ = transactions_repo.create(trans)?;
+            assert_eq!(transactions_repo.get_account_balance(acc1.id, AccountKind::Dr)?, Amount::new(123));
+            assert_eq!(transactions_repo.get_account_balance(acc2.id, AccountKind::Cr)?, Amount::new(123));
+            let res = transactions_repo.get_stale_account_balances();
+            assert!(res.as_ref().unwrap().is_empty());
+            res
+        }));
+    }
+
+    #[test]
+    fn transactions_list_groups_for_user_skip_approval() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let users_repo = UsersRepoImpl::default();
+        let accounts_repo = AccountsRepoImpl::default();
+        let transactions_repo = TransactionsRepoImpl::default();
+        let new_user = NewUser::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let user = users_repo.create(new_user)?;
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            let acc1 = accounts_repo.create(new_account)?;
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            let acc2 = accounts_repo.create(new_account)?;
+
+            let mut trans = NewTransaction::default();
+            trans.cr_account_id = acc2.id;
+            trans.dr_account_id = acc1.id;
+            trans.user_id = user.id;
+            let tx1 = transactions_repo.create(trans)?;
+            let mut trans = NewTransaction::default();
+            trans.cr_account_id = acc2.id;
+            trans.dr_account_id = acc1.id;
+            trans.user_id = user.id;
+            trans.gid = tx1.gid;
+            let _ = transactions_repo.create(trans)?;
+            let mut trans = NewTransaction::default();
+            trans.cr_account_id = acc2.id;
+            trans.dr_account_id = acc1.id;
+            trans.user_id = user.id;
+            trans.group_kind = TransactionGroupKind::Approval;
+            let _ = transactions_repo.create(trans)?;
+
+            let res = transactions_repo.list_groups_for_user_skip_approval(user.id, 0, 10, TransactionsSort::default())?;
+            assert_eq!(res.len(), 2);
+            assert!(res.iter().all(|tx| tx.gid == tx1.gid));
+            let res = transactions_repo.list_groups_for_user_skip_approval(user.id, 1, 10, TransactionsSort::default());
+            assert!(res.as_ref().unwrap().is_empty());
+            res
+        }));
+    }
+
+    #[test]
+    fn transactions_list_groups_for_user_sorted() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let users_repo = UsersRepoImpl::default();
+        let accounts_repo = AccountsRepoImpl::default();
+        let transactions_repo = TransactionsRepoImpl::default();
+        let new_user = NewUser::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let user = users_repo.create(new_user)?;
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            let acc1 = accounts_repo.create(new_account)?;
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            let acc2 = accounts_repo.create(new_account)?;
+
+            let mut gids = vec![];
+            for value_ in &[20, 10, 30] {
+                let mut trans = NewTransaction::default();
+                trans.cr_account_id = acc2.id;
+                trans.dr_account_id = acc1.id;
+                trans.user_id = user.id;
+                trans.value = Amount::new(*value_);
+                gids.push(transactions_repo.create(trans)?.gid);
+            }
+
+            let sort = TransactionsSort {
+                field: TransactionsSortField::Value,
+                direction: SortDirection::Asc,
+            };
+            let res = transactions_repo.list_groups_for_user_skip_approval(user.id, 0, 10, sort)?;
+            assert_eq!(res.iter().map(|tx| tx.gid).collect::<Vec<_>>(), vec![gids[1], gids[0], gids[2]]);
+            let res = transactions_repo.list_groups_for_account_skip_approval(acc1.id, 1, 1, sort)?;
+            assert_eq!(res.iter().map(|tx| tx.gid).collect::<Vec<_>>(), vec![gids[0]]);
+            Ok::<_, Error>(res)
+        }));
+    }
+
+    #[test]
+    fn transactions_list_groups_for_account_before() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let users_repo = UsersRepoImpl::default();
+        let accounts_repo = AccountsRepoImpl::default();
+        let transactions_repo = TransactionsRepoImpl::default();
+        let new_user = NewUser::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let user = users_repo.create(new_user)?;
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            let acc1 = accounts_repo.create(new_account)?;
+            let mut new_account = NewAccount::default();
+            new_account.user_id = user.id;
+            let acc2 = accounts_repo.create(new_account)?;
+
+            for _ in 0..3 {
+                let mut trans = NewTransaction::default();
+                trans.cr_account_id = acc2.id;
+                trans.dr_account_id = acc1.id;
+                trans.user_id = user.id;
+                transactions_repo.create(trans)?;
+            }
+
+            // groups created in the same db transaction have the same created_at, so paging relies on gid
+            let all = transactions_repo.list_groups_for_account_skip_approval(acc1.id, 0, 10, TransactionsSort::default())?;
+            let mut paged = vec![];
+            let mut before = None;
+            loop {
+                let page = transactions_repo.list_groups_for_account_before(acc1.id, before, 1)?;
+                match page.last() {
+                    Some(tx) => before = Some((tx.created_at, tx.gid)),
+                    None => break,
+                }
+                paged.extend(page);
+            }
+            assert_eq!(paged.len(), 3);
+            assert_eq!(
+                paged.iter().map(|tx| tx.gid).collect::<Vec<_>>(),
+                all.iter().map(|tx| tx.gid).collect::<Vec<_>>()
+            );
+            Ok::<_, Error>(())
+        }));
+    }
+
     #[test]
     fn transactions_list_for_account() {
         let mut core = Core::new().unwrap();
@@ -1,4 +1,6 @@
 use diesel;
+use diesel::sql_query;
+use diesel::sql_types::{Array, Uuid as SqlUuid};
 
 use super::error::*;
 use super::executor::with_tls_connection;
@@ -14,6 +16,10 @@ pub trait UsersRepo: Send + Sync + 'static {
     fn get_all(&self) -> RepoResult<Vec<User>>;
     fn update(&self, user_id: UserId, payload: UpdateUser) -> RepoResult<User>;
     fn delete(&self, user_id: UserId) -> RepoResult<User>;
+    /// Newest first, without system user. `query` matches name case insensitively or the whole id
+    fn search(&self, query: Option<String>, offset: i64, limit: i64) -> RepoResult<Vec<User>>;
+    /// Active credit accounts of users, by user and currency
+    fn get_accounts_summaries(&self, user_ids: &[UserId]) -> RepoResult<Vec<UserAccountsSummary>>;
 }
 
 #[derive(Clone, Default)]
@@ -29,7 +35,7 @@ impl UsersRepoImpl {
 
 impl<'a> UsersRepo for UsersRepoImpl {
     fn find_user_by_authentication_token(&self, token: AuthenticationToken) -> RepoResult<Option<User>> {
-        with_tls_connection(|conn| {
+        with_tls_connection("users.find_user_by_authentication_token", |conn| {
             users
                 .filter(authentication_token.eq(token))
                 .limit(1)
@@ -44,7 +50,7 @@ impl<'a> UsersRepo for UsersRepoImpl {
 
     fn create(&self, payload: NewUser) -> RepoResult<User> {
         let payload_clone = payload.clone();
-        with_tls_connection(|conn| {
+        with_tls_connection("users.create", |conn| {
             diesel::insert_into(users)
                 .values(payload.clone())
                 .get_result::<User>(conn)
@@ -56,7 +62,7 @@ impl<'a> UsersRepo for UsersRepoImpl {
     }
 
     fn get(&self, user_id_arg: UserId) -> RepoResult<Option<User>> {
-        with_tls_connection(|conn| {
+        with_tls_connection("users.get", |conn| {
             users
                 .filter(id.eq(user_id_arg))
                 .limit(1)
@@ -71,7 +77,7 @@ impl<'a> UsersRepo for UsersRepoImpl {
 
     fn get_all(&self) -> RepoResult<Vec<User>> {
         let system_user_id = self.system_user_id;
-        with_tls_connection(|conn| {
+        with_tls_connection("users.get_all", |conn| {
             users.filter(id.ne(system_user_id)).get_results(conn).map_err(move |e| {
                 let kind = ErrorKind::from(&e);
                 ectx!(err e, kind)
@@ -79,7 +85,7 @@ impl<'a> UsersRepo for UsersRepoImpl {
         })
     }
     fn update(&self, user_id_arg: UserId, payload: UpdateUser) -> RepoResult<User> {
-        with_tls_connection(|conn| {
+        with_tls_connection("users.update", |conn| {
             let f = users.filter(id.eq(user_id_arg));
             diesel::update(f).set(payload.clone()).get_result(conn).map_err(move |e| {
                 let kind = ErrorKind::from(&e);
@@ -88,7 +94,7 @@ impl<'a> UsersRepo for UsersRepoImpl {
         })
     }
     fn delete(&self, user_id_arg: UserId) -> RepoResult<User> {
-        with_tls_connection(|conn| {
+        with_tls_connection("users.delete", |conn| {
             let filtered = users.filter(id.eq(user_id_arg));
             diesel::delete(filtered).get_result(conn).map_err(move |e| {
                 let kind = ErrorKind::from(&e);
@@ -96,6 +102,46 @@ impl<'a> UsersRepo for UsersRepoImpl {
             })
         })
     }
+    fn search(&self, query: Option<String>, offset: i64, limit: i64) -> RepoResult<Vec<User>> {
+        let system_user_id = self.system_user_id;
+        with_tls_connection("users.search", |conn| {
+            let mut filtered = users.filter(id.ne(system_user_id)).into_boxed();
+            if let Some(ref query) = query {
+                let pattern = format!("%{}%", query.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_"));
+                filtered = match query.parse::<UserId>() {
+                    Ok(user_id_arg) => filtered.filter(id.eq(user_id_arg).or(name.ilike(pattern))),
+                    Err(_) => filtered.filter(name.ilike(pattern)),
+                };
+            }
+            filtered
+                .order((created_at.desc(), id.desc()))
+                .offset(offset)
+                .limit(limit)
+                .get_results(conn)
+                .map_err(move |e| {
+                    let kind = ErrorKind::from(&e);
+                    ectx!(err e, kind => query, offset, limit)
+                })
+        })
+    }
+    fn get_accounts_summaries(&self, user_ids: &[UserId]) -> RepoResult<Vec<UserAccountsSummary>> {
+        let user_ids = user_ids.to_vec();
+        with_tls_connection("users.get_accounts_summaries", |conn| {
+            sql_query(
+                "SELECT accounts.user_id, accounts.currency, COUNT(*) AS accounts_count, \
+                 COALESCE(SUM(account_balances.cr_turnover - account_balances.dr_turnover), 0) AS balance \
+                 FROM accounts LEFT JOIN account_balances ON account_balances.account_id = accounts.id \
+                 WHERE accounts.user_id = ANY($1) AND accounts.kind = 'cr' AND NOT accounts.archived \
+                 GROUP BY accounts.user_id, accounts.currency ORDER BY accounts.currency",
+            )
+            .bind::<Array<SqlUuid>, _>(user_ids.clone())
+            .get_results(conn)
+            .map_err(move |e| {
+                let kind = ErrorKind::from(&e);
+                ectx!(err e, kind => user_ids)
+            })
+        })
+    }
 }
 
 #[cfg(test)]
@@ -156,6 +202,8 @@ pub mod tests {
             let payload = UpdateUser {
                 name: Some("test".to_string()),
                 authentication_token: None,
+                disabled: None,
+                withdrawal_whitelist: None,
             };
             let res = users_repo.update(user.id, payload);
             assert!(res.is_ok());
@@ -163,6 +211,26 @@ pub mod tests {
         }));
     }
 
+    #[test]
+    fn users_search() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let users_repo = UsersRepoImpl::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let user = users_repo.create(NewUser {
+                name: "Search_Me".to_string(),
+                ..Default::default()
+            })?;
+            let by_name = users_repo.search(Some("search_me".to_string()), 0, 10)?;
+            assert_eq!(by_name.into_iter().map(|user| user.id).collect::<Vec<_>>(), vec![user.id]);
+            let by_id = users_repo.search(Some(user.id.to_string()), 0, 10)?;
+            assert_eq!(by_id.into_iter().map(|user| user.id).collect::<Vec<_>>(), vec![user.id]);
+            let summaries = users_repo.get_accounts_summaries(&[user.id])?;
+            assert!(summaries.is_empty());
+            Ok(())
+        }));
+    }
+
     #[test]
     fn users_delete() {
         let mut core = Core::new().unwrap();
@@ -0,0 +1,135 @@
+use diesel;
+use uuid::Uuid;
+
+use super::error::*;
+use super::executor::with_tls_connection;
+use super::*;
+use models::*;
+use prelude::*;
+use schema::withdrawal_addresses::dsl::*;
+
+pub trait WithdrawalAddressesRepo: Send + Sync + 'static {
+    fn create(&self, payload: NewWithdrawalAddress) -> RepoResult<WithdrawalAddress>;
+    fn get(&self, id_: Uuid) -> RepoResult<Option<WithdrawalAddress>>;
+    /// Newest first
+    fn list_for_user(&self, user_id_: UserId) -> RepoResult<Vec<WithdrawalAddress>>;
+    fn find(&self, user_id_: UserId, currency_: Currency, address_: BlockchainAddress) -> RepoResult<Option<WithdrawalAddress>>;
+    fn delete(&self, id_: Uuid) -> RepoResult<Option<WithdrawalAddress>>;
+}
+
+#[derive(Clone, Default)]
+pub struct WithdrawalAddressesRepoImpl;
+
+impl WithdrawalAddressesRepo for WithdrawalAddressesRepoImpl {
+    fn create(&self, payload: NewWithdrawalAddress) -> RepoResult<WithdrawalAddress> {
+        with_tls_connection("withdrawal_addresses.create", |conn| {
+            diesel::insert_into(withdrawal_addresses)
+                .values(payload.clone())
+                .get_result::<WithdrawalAddress>(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => payload)
+                })
+        })
+    }
+
+    fn get(&self, id_: Uuid) -> RepoResult<Option<WithdrawalAddress>> {
+        with_tls_connection("withdrawal_addresses.get", |conn| {
+            withdrawal_addresses
+                .filter(id.eq(id_))
+                .get_result(conn)
+                .optional()
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => id_)
+                })
+        })
+    }
+
+    fn list_for_user(&self, user_id_: UserId) -> RepoResult<Vec<WithdrawalAddress>> {
+        with_tls_connection("withdrawal_addresses.list_for_user", |conn| {
+            withdrawal_addresses
+                .filter(user_id.eq(user_id_))
+                .order((created_at.desc(), id.desc()))
+                .get_results(conn)
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => user_id_)
+                })
+        })
+    }
+
+    fn find(&self, user_id_: UserId, currency_: Currency, address_: BlockchainAddress) -> RepoResult<Option<WithdrawalAddress>> {
+        with_tls_connection("withdrawal_addresses.find", |conn| {
+            withdrawal_addresses
+                .filter(user_id.eq(user_id_))
+                .filter(currency.eq(currency_))
+                .filter(address.eq(address_.clone()))
+                .get_result(conn)
+                .optional()
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => user_id_, currency_, address_)
+                })
+        })
+    }
+
+    fn delete(&self, id_: Uuid) -> RepoResult<Option<WithdrawalAddress>> {
+        with_tls_connection("withdrawal_addresses.delete", |conn| {
+            diesel::delete(withdrawal_addresses.filter(id.eq(id_)))
+                .get_result(conn)
+                .optional()
+                .map_err(move |e| {
+                    let error_kind = ErrorKind::from(&e);
+                    ectx!(err e, error_kind => id_)
+                })
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use diesel::r2d2::ConnectionManager;
+    use diesel::PgConnection;
+    use futures_cpupool::CpuPool;
+    use r2d2;
+    use tokio_core::reactor::Core;
+
+    use super::*;
+    use config::Config;
+    use repos::DbExecutorImpl;
+
+    fn create_executor() -> DbExecutorImpl {
+        let config = Config::new().unwrap();
+        let manager = ConnectionManager::<PgConnection>::new(config.database.url);
+        let db_pool = r2d2::Pool::builder().build(manager).unwrap();
+        let cpu_pool = CpuPool::new(1);
+        DbExecutorImpl::new(db_pool.clone(), cpu_pool.clone())
+    }
+
+    #[test]
+    fn withdrawal_addresses_find_and_delete() {
+        let mut core = Core::new().unwrap();
+        let db_executor = create_executor();
+        let users_repo = UsersRepoImpl::default();
+        let withdrawal_addresses_repo = WithdrawalAddressesRepoImpl::default();
+        let _ = core.run(db_executor.execute_test_transaction(move || {
+            let user = users_repo.create(NewUser::default())?;
+            let whitelisted = withdrawal_addresses_repo.create(NewWithdrawalAddress {
+                user_id: user.id,
+                currency: Currency::Btc,
+                ..Default::default()
+            })?;
+            let found = withdrawal_addresses_repo.find(user.id, Currency::Btc, whitelisted.address.clone())?;
+            assert_eq!(found.map(|address_| address_.id), Some(whitelisted.id));
+            // the same address is not whitelisted for other currencies
+            assert!(withdrawal_addresses_repo
+                .find(user.id, Currency::Eth, whitelisted.address.clone())?
+                .is_none());
+            assert_eq!(withdrawal_addresses_repo.list_for_user(user.id)?.len(), 1);
+            assert!(withdrawal_addresses_repo.delete(whitelisted.id)?.is_some());
+            assert!(withdrawal_addresses_repo.get(whitelisted.id)?.is_none());
+            Ok::<_, Error>(())
+        }));
+    }
+}
@@ -0,0 +1,110 @@
+//! Correlation id of the request being handled. It's added to every log record and forwarded
+//! to other services in http and rabbit headers, so that one request can be traced across services.
+
+use std::cell::RefCell;
+
+use futures::prelude::*;
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+/// Name of the service instance, that published rabbit message
+pub const INSTANCE_HEADER: &str = "X-Instance";
+/// Group id of the transaction, that rabbit message was published for
+pub const TRANSACTION_GID_HEADER: &str = "X-Transaction-Gid";
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+thread_local! {
+    static CURRENT_REQUEST_ID: RefCell<Option<String>> = RefCell::new(None)
+}
+
+pub fn generate() -> String {
+    Uuid::new_v4().to_string()
+}
+
+/// Accepts request id received from other service, unless it's too long or can't be sent in a header
+pub fn parse(value: &str) -> Option<String> {
+    if !value.is_empty() && value.len() <= MAX_REQUEST_ID_LEN && value.chars().all(|c| c.is_ascii_graphic()) {
+        Some(value.to_string())
+    } else {
+        None
+    }
+}
+
+/// Request id of the code being run, if any
+pub fn current() -> Option<String> {
+    CURRENT_REQUEST_ID.with(|current| current.borrow().clone())
+}
+
+/// Runs `f` with `request_id` as the current one
+pub fn scope<T, F: FnOnce() -> T>(request_id: Option<String>, f: F) -> T {
+    let _guard = ScopeGuard {
+        previous: CURRENT_REQUEST_ID.with(|current| current.replace(request_id)),
+    };
+    f()
+}
+
+// restores previous request id even if `f` panics
+struct ScopeGuard {
+    previous: Option<String>,
+}
+
+impl Drop for ScopeGuard {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CURRENT_REQUEST_ID.with(|current| current.replace(previous));
+    }
+}
+
+/// Future that has the request id as the current one while it's polled. Work spawned
+/// to other threads should capture `current()` and run in its `scope`.
+pub struct WithRequestId<F> {
+    inner: F,
+    request_id: Option<String>,
+}
+
+impl<F: Future> WithRequestId<F> {
+    pub fn new(inner: F, request_id: Option<String>) -> Self {
+        Self { inner, request_id }
+    }
+}
+
+impl<F: Future> Future for WithRequestId<F> {
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let inner = &mut self.inner;
+        scope(self.request_id.clone(), move || inner.poll())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+
+    #[test]
+    fn test_request_id_scope() {
+        assert_eq!(current(), None);
+        let request_id = generate();
+        let fut = future::lazy(|| Ok::<_, ()>(current()));
+        assert_eq!(
+            WithRequestId::new(fut, Some(request_id.clone())).wait(),
+            Ok(Some(request_id.clone()))
+        );
+        let nested = scope(Some(request_id.clone()), || {
+            let inner = scope(Some("inner".to_string()), current);
+            (inner, current())
+        });
+        assert_eq!(nested, (Some("inner".to_string()), Some(request_id)));
+        assert_eq!(current(), None);
+    }
+
+    #[test]
+    fn test_parse_request_id() {
+        assert_eq!(parse("5b4e2d8c-request"), Some("5b4e2d8c-request".to_string()));
+        assert_eq!(parse(""), None);
+        assert_eq!(parse("with space"), None);
+        assert_eq!(parse(&"a".repeat(MAX_REQUEST_ID_LEN + 1)), None);
+    }
+}
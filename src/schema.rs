@@ -1,3 +1,13 @@
+table! {
+    account_balances (account_id) {
+        account_id -> Uuid,
+        dr_turnover -> Numeric,
+        cr_turnover -> Numeric,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
 table! {
     accounts (id) {
         id -> Uuid,
@@ -10,6 +20,43 @@ table! {
         updated_at -> Timestamp,
         erc20_approved -> Bool,
         daily_limit_type -> Varchar,
+        meta -> Jsonb,
+        labels -> Array<Varchar>,
+        archived -> Bool,
+        auto_convert_to -> Nullable<Uuid>,
+        system_role -> Nullable<Varchar>,
+        last_spent_at -> Nullable<Timestamp>,
+    }
+}
+
+// view over transactions and transactions_archive
+table! {
+    all_transactions (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        dr_account_id -> Uuid,
+        cr_account_id -> Uuid,
+        currency -> Varchar,
+        value -> Numeric,
+        status -> Varchar,
+        blockchain_tx_id -> Nullable<Varchar>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+        gid -> Uuid,
+        kind -> Varchar,
+        group_kind -> Varchar,
+        related_tx -> Nullable<Uuid>,
+        meta -> Jsonb,
+    }
+}
+
+table! {
+    balance_checkpoints (as_of, account_id) {
+        account_id -> Uuid,
+        as_of -> Timestamp,
+        dr_turnover -> Numeric,
+        cr_turnover -> Numeric,
+        created_at -> Timestamp,
     }
 }
 
@@ -29,11 +76,36 @@ table! {
 }
 
 table! {
-    key_values (key) {
+    expired_addresses (address, currency) {
+        address -> Varchar,
+        currency -> Varchar,
+        account_id -> Uuid,
+        expired_at -> Timestamp,
+    }
+}
+
+table! {
+    group_fees (gid) {
+        gid -> Uuid,
+        fee_currency -> Varchar,
+        fee -> Numeric,
+        blockchain_fee_currency -> Varchar,
+        charged_blockchain_fee -> Numeric,
+        blockchain_fee -> Numeric,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    key_values (namespace, key) {
         key -> Varchar,
         value -> Jsonb,
         created_at -> Timestamp,
         updated_at -> Timestamp,
+        namespace -> Varchar,
+        version -> Int8,
+        expires_at -> Nullable<Timestamp>,
     }
 }
 
@@ -48,6 +120,75 @@ table! {
         created_at -> Timestamp,
         updated_at -> Timestamp,
         erc20_operation_kind -> Nullable<Varchar>,
+        raw_tx -> Nullable<Varchar>,
+        broadcast_attempts -> Int4,
+        next_broadcast_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    pending_deposits (id) {
+        id -> Uuid,
+        blockchain_tx_id -> Varchar,
+        account_id -> Uuid,
+        user_id -> Uuid,
+        currency -> Varchar,
+        from_ -> Jsonb,
+        address -> Varchar,
+        value -> Numeric,
+        confirmations -> Int4,
+        required_confirmations -> Int4,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+        estimated_completion_at -> Timestamp,
+    }
+}
+
+table! {
+    quarantined_messages (id) {
+        id -> Uuid,
+        currency -> Varchar,
+        payload -> Bytea,
+        error -> Text,
+        attempts -> Int4,
+        request_id -> Nullable<Varchar>,
+        replayed_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    queued_withdrawals (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        account_id -> Uuid,
+        currency -> Varchar,
+        address -> Varchar,
+        value -> Numeric,
+        fee -> Numeric,
+        status -> Varchar,
+        transaction_id -> Nullable<Uuid>,
+        error_message -> Nullable<Varchar>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    rate_locks (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        exchange_id -> Uuid,
+        from_currency -> Varchar,
+        to_currency -> Varchar,
+        amount -> Numeric,
+        amount_currency -> Varchar,
+        rate -> Float8,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
     }
 }
 
@@ -61,6 +202,18 @@ table! {
     }
 }
 
+table! {
+    small_deposits (blockchain_tx_id, account_id) {
+        blockchain_tx_id -> Varchar,
+        account_id -> Uuid,
+        user_id -> Uuid,
+        currency -> Varchar,
+        value -> Numeric,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
 table! {
     strange_blockchain_transactions (hash) {
         hash -> Varchar,
@@ -74,6 +227,7 @@ table! {
         updated_at -> Timestamp,
         commentary -> Varchar,
         erc20_operation_kind -> Nullable<Varchar>,
+        violation_kind -> Nullable<Varchar>,
     }
 }
 
@@ -97,6 +251,40 @@ table! {
     }
 }
 
+table! {
+    transactions_archive (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        dr_account_id -> Uuid,
+        cr_account_id -> Uuid,
+        currency -> Varchar,
+        value -> Numeric,
+        status -> Varchar,
+        blockchain_tx_id -> Nullable<Varchar>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+        gid -> Uuid,
+        kind -> Varchar,
+        group_kind -> Varchar,
+        related_tx -> Nullable<Uuid>,
+        meta -> Jsonb,
+    }
+}
+
+table! {
+    tx_groups (gid) {
+        gid -> Uuid,
+        user_id -> Uuid,
+        group_kind -> Varchar,
+        created_at -> Timestamp,
+        status -> Varchar,
+        account_ids -> Array<Uuid>,
+        value -> Numeric,
+        last_created_at -> Timestamp,
+        archived -> Bool,
+    }
+}
+
 table! {
     users (id) {
         id -> Uuid,
@@ -104,19 +292,59 @@ table! {
         authentication_token -> Varchar,
         created_at -> Timestamp,
         updated_at -> Timestamp,
+        disabled -> Bool,
+        withdrawal_whitelist -> Bool,
+    }
+}
+
+table! {
+    withdrawal_addresses (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        currency -> Varchar,
+        address -> Varchar,
+        label -> Nullable<Varchar>,
+        active_from -> Timestamp,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
     }
 }
 
+joinable!(account_balances -> accounts (account_id));
 joinable!(accounts -> users (user_id));
+joinable!(balance_checkpoints -> accounts (account_id));
+joinable!(expired_addresses -> accounts (account_id));
+joinable!(pending_deposits -> accounts (account_id));
+joinable!(pending_deposits -> users (user_id));
+joinable!(queued_withdrawals -> accounts (account_id));
+joinable!(queued_withdrawals -> users (user_id));
+joinable!(rate_locks -> users (user_id));
+joinable!(small_deposits -> accounts (account_id));
+joinable!(small_deposits -> users (user_id));
 joinable!(transactions -> users (user_id));
+joinable!(tx_groups -> users (user_id));
+joinable!(withdrawal_addresses -> users (user_id));
 
 allow_tables_to_appear_in_same_query!(
+    account_balances,
     accounts,
+    all_transactions,
+    balance_checkpoints,
     blockchain_transactions,
+    expired_addresses,
+    group_fees,
     key_values,
     pending_blockchain_transactions,
+    pending_deposits,
+    quarantined_messages,
+    queued_withdrawals,
+    rate_locks,
     seen_hashes,
+    small_deposits,
     strange_blockchain_transactions,
     transactions,
+    transactions_archive,
+    tx_groups,
     users,
+    withdrawal_addresses,
 );
@@ -3,11 +3,13 @@ use std::sync::Arc;
 use futures::future::{self, Either};
 use futures::IntoFuture;
 use serde_json;
-use validator::Validate;
+use uuid::Uuid;
+use validator::{Validate, ValidationError, ValidationErrors};
 
 use super::auth::AuthService;
 use super::error::*;
 use client::KeysClient;
+use config::Config;
 use models::*;
 use prelude::*;
 use repos::{AccountsRepo, DbExecutor};
@@ -18,15 +20,23 @@ pub struct AccountsServiceImpl<E: DbExecutor> {
     accounts_repo: Arc<dyn AccountsRepo>,
     db_executor: E,
     keys_client: Arc<dyn KeysClient>,
+    stq_contract_address: BlockchainAddress,
 }
 
 impl<E: DbExecutor> AccountsServiceImpl<E> {
-    pub fn new(auth_service: Arc<AuthService>, accounts_repo: Arc<AccountsRepo>, db_executor: E, keys_client: Arc<dyn KeysClient>) -> Self {
+    pub fn new(
+        config: &Config,
+        auth_service: Arc<AuthService>,
+        accounts_repo: Arc<AccountsRepo>,
+        db_executor: E,
+        keys_client: Arc<dyn KeysClient>,
+    ) -> Self {
         Self {
             auth_service,
             accounts_repo,
             db_executor,
             keys_client,
+            stq_contract_address: config.payment_uri.stq_contract_address.clone(),
         }
     }
 }
@@ -47,7 +57,27 @@ pub trait AccountsService: Send + Sync + 'static {
         user_id: UserId,
         offset: i64,
         limit: i64,
+        filter: AccountsFilter,
     ) -> Box<Future<Item = Vec<Account>, Error = Error> + Send>;
+    /// Issues a fresh deposit address for the account. Funds that still arrive
+    /// to the former addresses are credited to the account as before
+    fn rotate_address(
+        &self,
+        token: AuthenticationToken,
+        account_id: AccountId,
+    ) -> Box<Future<Item = AccountAddresses, Error = Error> + Send>;
+    fn get_addresses(
+        &self,
+        token: AuthenticationToken,
+        account_id: AccountId,
+    ) -> Box<Future<Item = AccountAddresses, Error = Error> + Send>;
+    /// Uri for wallets to pay `amount` to the account's address, e.g. via qr code
+    fn get_payment_uri(
+        &self,
+        token: AuthenticationToken,
+        account_id: AccountId,
+        amount: Option<Amount>,
+    ) -> Box<Future<Item = PaymentUri, Error = Error> + Send>;
 }
 
 impl<E: DbExecutor> AccountsService for AccountsServiceImpl<E> {
@@ -80,10 +110,10 @@ impl<E: DbExecutor> AccountsService for AccountsServiceImpl<E> {
                                 let new_account_dr = new_account_cr.create_debit();
                                 let users_account = accounts_repo
                                     .create(new_account_cr.clone())
-                                    .map_err(ectx!(try ErrorKind::Internal => new_account_cr))?;
+                                    .map_err(ectx!(try convert => new_account_cr))?;
                                 accounts_repo
                                     .create(new_account_dr.clone())
-                                    .map_err(ectx!(try ErrorKind::Internal => new_account_dr))?;
+                                    .map_err(ectx!(try convert => new_account_dr))?;
                                 Ok(users_account)
                             })
                         }),
@@ -95,7 +125,7 @@ impl<E: DbExecutor> AccountsService for AccountsServiceImpl<E> {
         let accounts_repo = self.accounts_repo.clone();
         let db_executor = self.db_executor.clone();
         Box::new(self.auth_service.authenticate(token).and_then(move |user| {
-            db_executor.execute(move || {
+            db_executor.execute_read_only(move || {
                 let account = accounts_repo.get(account_id).map_err(ectx!(try convert => account_id))?;
                 if let Some(ref account) = account {
                     if account.user_id != user.id {
@@ -129,6 +159,9 @@ impl<E: DbExecutor> AccountsService for AccountsServiceImpl<E> {
                             if account.user_id != user.id {
                                 return Err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id));
                             }
+                            if let Some(Some(to_account_id)) = payload.auto_convert_to {
+                                check_auto_convert_to(&*accounts_repo, &account, to_account_id)?;
+                            }
                             Ok(account)
                         })
                     })
@@ -140,10 +173,39 @@ impl<E: DbExecutor> AccountsService for AccountsServiceImpl<E> {
         let db_executor = self.db_executor.clone();
         Box::new(self.auth_service.authenticate(token).and_then(move |user| {
             db_executor.execute_transaction(move || {
-                let account = accounts_repo.delete(account_id).map_err(ectx!(try convert => account_id))?;
+                let account = accounts_repo.archive(account_id).map_err(ectx!(try convert => account_id))?;
                 if account.user_id != user.id {
                     return Err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id));
                 }
+                // debit account shares the address and is archived along with the user's one
+                let dr_account = accounts_repo
+                    .get_by_address(
+                        account.address.clone(),
+                        account.currency,
+                        AccountKind::Dr,
+                        ArchivedAccounts::Exclude,
+                    )
+                    .map_err(ectx!(try convert => account.address, account.currency))?;
+                if let Some(dr_account) = dr_account {
+                    accounts_repo.archive(dr_account.id).map_err(ectx!(try convert => dr_account.id))?;
+                }
+                // as well as debit accounts of its former addresses
+                let expired_addresses = accounts_repo
+                    .list_expired_addresses(account.id)
+                    .map_err(ectx!(try convert => account.id))?;
+                for expired in expired_addresses {
+                    let dr_account = accounts_repo
+                        .get_by_address(
+                            expired.address.clone(),
+                            expired.currency,
+                            AccountKind::Dr,
+                            ArchivedAccounts::Exclude,
+                        )
+                        .map_err(ectx!(try convert => expired.address, expired.currency))?;
+                    if let Some(dr_account) = dr_account {
+                        accounts_repo.archive(dr_account.id).map_err(ectx!(try convert => dr_account.id))?;
+                    }
+                }
                 Ok(account)
             })
         }))
@@ -154,20 +216,174 @@ impl<E: DbExecutor> AccountsService for AccountsServiceImpl<E> {
         user_id: UserId,
         offset: i64,
         limit: i64,
+        filter: AccountsFilter,
     ) -> Box<Future<Item = Vec<Account>, Error = Error> + Send> {
         let accounts_repo = self.accounts_repo.clone();
         let db_executor = self.db_executor.clone();
         Box::new(self.auth_service.authenticate(token).and_then(move |user| {
-            db_executor.execute(move || {
+            db_executor.execute_read_only(move || {
                 if user_id != user.id {
                     return Err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id));
                 }
                 accounts_repo
-                    .list_for_user(user_id, offset, limit)
-                    .map_err(ectx!(convert => user_id, offset, limit))
+                    .list_for_user(user_id, offset, limit, filter.clone())
+                    .map_err(ectx!(convert => user_id, offset, limit, filter))
             })
         }))
     }
+    fn rotate_address(
+        &self,
+        token: AuthenticationToken,
+        account_id: AccountId,
+    ) -> Box<Future<Item = AccountAddresses, Error = Error> + Send> {
+        let accounts_repo = self.accounts_repo.clone();
+        let db_executor = self.db_executor.clone();
+        let accounts_repo_clone = self.accounts_repo.clone();
+        let db_executor_clone = self.db_executor.clone();
+        let keys_client = self.keys_client.clone();
+        Box::new(
+            self.auth_service
+                .authenticate(token)
+                .and_then(move |user| {
+                    db_executor_clone.execute_read_only(move || -> Result<Account, Error> {
+                        let account = accounts_repo_clone
+                            .get(account_id)
+                            .map_err(ectx!(try convert => account_id))?
+                            .ok_or(ectx!(try err ErrorContext::NoAccount, ErrorKind::NotFound => account_id))?;
+                        if account.user_id != user.id {
+                            return Err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id));
+                        }
+                        if account.archived {
+                            return Err(ectx!(err ErrorContext::NoAccount, ErrorKind::NotFound => account_id));
+                        }
+                        Ok(account)
+                    })
+                })
+                .and_then(move |account| {
+                    let create_address = CreateAccountAddress {
+                        id: Uuid::new_v4(),
+                        currency: account.currency,
+                    };
+                    keys_client
+                        .create_account_address(create_address.clone(), Role::User)
+                        .map_err(ectx!(convert => create_address))
+                        .map(move |new_address| (account, new_address))
+                })
+                .and_then(move |(account, new_address)| {
+                    db_executor.execute_transaction(move || {
+                        let rotated = accounts_repo
+                            .rotate_address(account.id, new_address.clone())
+                            .map_err(ectx!(try convert => account.id, new_address))?;
+                        // debit account of the former address keeps its funds and deposits that still arrive there,
+                        // the new address gets its own one
+                        let new_account_dr = NewAccount {
+                            id: AccountId::generate(),
+                            user_id: account.user_id,
+                            currency: account.currency,
+                            address: new_address.clone(),
+                            name: None,
+                            kind: AccountKind::Dr,
+                            daily_limit_type: Some(account.daily_limit_type),
+                            meta: None,
+                            labels: None,
+                        };
+                        accounts_repo
+                            .create(new_account_dr.clone())
+                            .map_err(ectx!(try convert => new_account_dr))?;
+                        let historical = accounts_repo
+                            .list_expired_addresses(account.id)
+                            .map_err(ectx!(try convert => account.id))?;
+                        Ok(AccountAddresses {
+                            account_id: rotated.id,
+                            active: rotated.address,
+                            historical,
+                        })
+                    })
+                }),
+        )
+    }
+    fn get_addresses(
+        &self,
+        token: AuthenticationToken,
+        account_id: AccountId,
+    ) -> Box<Future<Item = AccountAddresses, Error = Error> + Send> {
+        let accounts_repo = self.accounts_repo.clone();
+        let db_executor = self.db_executor.clone();
+        Box::new(self.auth_service.authenticate(token).and_then(move |user| {
+            db_executor.execute_read_only(move || {
+                let account = accounts_repo
+                    .get(account_id)
+                    .map_err(ectx!(try convert => account_id))?
+                    .ok_or(ectx!(try err ErrorContext::NoAccount, ErrorKind::NotFound => account_id))?;
+                if account.user_id != user.id {
+                    return Err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id));
+                }
+                let historical = accounts_repo
+                    .list_expired_addresses(account_id)
+                    .map_err(ectx!(try convert => account_id))?;
+                Ok(AccountAddresses {
+                    account_id,
+                    active: account.address,
+                    historical,
+                })
+            })
+        }))
+    }
+    fn get_payment_uri(
+        &self,
+        token: AuthenticationToken,
+        account_id: AccountId,
+        amount: Option<Amount>,
+    ) -> Box<Future<Item = PaymentUri, Error = Error> + Send> {
+        let accounts_repo = self.accounts_repo.clone();
+        let db_executor = self.db_executor.clone();
+        let stq_contract_address = self.stq_contract_address.clone();
+        Box::new(self.auth_service.authenticate(token).and_then(move |user| {
+            db_executor.execute_read_only(move || {
+                let account = accounts_repo
+                    .get(account_id)
+                    .map_err(ectx!(try convert => account_id))?
+                    .ok_or(ectx!(try err ErrorContext::NoAccount, ErrorKind::NotFound => account_id))?;
+                if account.user_id != user.id {
+                    return Err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id));
+                }
+                // archived account is not credited, so nobody should be asked to pay to it
+                if account.archived {
+                    return Err(ectx!(err ErrorContext::NoAccount, ErrorKind::NotFound => account_id));
+                }
+                Ok(PaymentUri::new(&account, amount, &stq_contract_address))
+            })
+        }))
+    }
+}
+
+// deposits can only be converted to another currency account of the same user, that is still in use
+fn check_auto_convert_to(accounts_repo: &AccountsRepo, account: &Account, to_account_id: AccountId) -> Result<(), Error> {
+    let to_account = accounts_repo.get(to_account_id).map_err(ectx!(try convert => to_account_id))?;
+    let to_account = match to_account {
+        Some(ref to_account) if to_account.user_id == account.user_id && to_account.kind == AccountKind::Cr && !to_account.archived => {
+            to_account
+        }
+        _ => {
+            let mut errors = ValidationErrors::new();
+            let mut error = ValidationError::new("not_found");
+            error.message = Some("account to convert deposits to is not found".into());
+            errors.add("auto_convert_to", error);
+            return Err(
+                ectx!(err ErrorContext::NoAccount, ErrorKind::InvalidInput(serde_json::to_string(&errors).unwrap_or_default()) => to_account_id),
+            );
+        }
+    };
+    if to_account.currency == account.currency {
+        let mut errors = ValidationErrors::new();
+        let mut error = ValidationError::new("same_currency");
+        error.message = Some("deposits can only be converted to account of another currency".into());
+        errors.add("auto_convert_to", error);
+        return Err(
+            ectx!(err ErrorContext::InvalidCurrency, ErrorKind::InvalidInput(serde_json::to_string(&errors).unwrap_or_default()) => to_account_id),
+        );
+    }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -183,7 +399,8 @@ mod tests {
         let accounts_repo = Arc::new(AccountsRepoMock::default());
         let keys_client = Arc::new(KeysClientMock::default());
         let db_executor = DbExecutorMock::default();
-        AccountsServiceImpl::new(auth_service, accounts_repo, db_executor, keys_client)
+        let config = Config::new().unwrap();
+        AccountsServiceImpl::new(&config, auth_service, accounts_repo, db_executor, keys_client)
     }
 
     #[test]
@@ -233,6 +450,38 @@ mod tests {
         assert!(account.is_ok());
     }
     #[test]
+    fn test_account_update_auto_convert_to() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let service = create_account_service(token.clone(), user_id);
+
+        let mut stq_account = CreateAccount::default();
+        stq_account.name = "stq".to_string();
+        stq_account.user_id = user_id;
+        stq_account.currency = Currency::Stq;
+        core.run(service.create_account(token.clone(), stq_account.clone())).unwrap();
+        let mut eth_account = CreateAccount::default();
+        eth_account.name = "eth".to_string();
+        eth_account.user_id = user_id;
+        core.run(service.create_account(token.clone(), eth_account.clone())).unwrap();
+
+        let mut payload = UpdateAccount::default();
+        payload.auto_convert_to = Some(Some(stq_account.id));
+        let account = core.run(service.update_account(token.clone(), stq_account.id, payload));
+        assert!(account.is_err());
+
+        let mut payload = UpdateAccount::default();
+        payload.auto_convert_to = Some(Some(eth_account.id));
+        let account = core.run(service.update_account(token.clone(), stq_account.id, payload)).unwrap();
+        assert_eq!(account.auto_convert_to, Some(eth_account.id));
+
+        let mut payload = UpdateAccount::default();
+        payload.auto_convert_to = Some(None);
+        let account = core.run(service.update_account(token, stq_account.id, payload)).unwrap();
+        assert_eq!(account.auto_convert_to, None);
+    }
+    #[test]
     fn test_account_delete() {
         let mut core = Core::new().unwrap();
         let token = AuthenticationToken::default();
@@ -248,6 +497,57 @@ mod tests {
         assert!(account.is_ok());
     }
     #[test]
+    fn test_account_rotate_address() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let service = create_account_service(token.clone(), user_id);
+
+        let mut new_account = CreateAccount::default();
+        new_account.name = "test test test acc".to_string();
+        new_account.user_id = user_id;
+        let account = core.run(service.create_account(token.clone(), new_account.clone())).unwrap();
+
+        let addresses = core.run(service.rotate_address(token.clone(), account.id)).unwrap();
+        assert_ne!(addresses.active, account.address);
+        assert_eq!(
+            addresses.historical.iter().map(|x| x.address.clone()).collect::<Vec<_>>(),
+            vec![account.address.clone()]
+        );
+        let addresses = core.run(service.rotate_address(token.clone(), account.id)).unwrap();
+        assert_eq!(addresses.historical.len(), 2);
+        let fetched = core.run(service.get_addresses(token.clone(), account.id)).unwrap();
+        assert_eq!(fetched.active, addresses.active);
+
+        let other_token = AuthenticationToken::default();
+        assert!(core.run(service.rotate_address(other_token, account.id)).is_err());
+    }
+    #[test]
+    fn test_account_payment_uri() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let service = create_account_service(token.clone(), user_id);
+
+        let mut new_account = CreateAccount::default();
+        new_account.name = "test test test acc".to_string();
+        new_account.user_id = user_id;
+        let account = core.run(service.create_account(token.clone(), new_account)).unwrap();
+
+        let payment_uri = core
+            .run(service.get_payment_uri(token.clone(), account.id, Some(Amount::new(1000))))
+            .unwrap();
+        assert_eq!(payment_uri.address, account.address);
+        assert_eq!(
+            payment_uri.uri,
+            PaymentUri::new(&account, Some(Amount::new(1000)), &service.stq_contract_address).uri
+        );
+
+        let other_token = AuthenticationToken::default();
+        assert!(core.run(service.get_payment_uri(other_token, account.id, None)).is_err());
+        assert!(core.run(service.get_payment_uri(token, AccountId::generate(), None)).is_err());
+    }
+    #[test]
     fn test_account_get_for_users() {
         let mut core = Core::new().unwrap();
         let token = AuthenticationToken::default();
@@ -258,7 +558,7 @@ mod tests {
         new_account.name = "test test test acc".to_string();
         new_account.user_id = user_id;
 
-        let account = core.run(service.get_accounts_for_user(token, new_account.user_id, 0, 10));
+        let account = core.run(service.get_accounts_for_user(token, new_account.user_id, 0, 10, AccountsFilter::default()));
         assert!(account.is_ok());
     }
     // #[test]
@@ -34,6 +34,13 @@ impl<E: DbExecutor> AuthService for AuthServiceImpl<E> {
                 .and_then(move |maybe_user| {
                     maybe_user.ok_or(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => token_clone2))
                 })
+                .and_then(|user| {
+                    if user.disabled {
+                        Err(ectx!(err ErrorContext::DisabledUser, ErrorKind::Unauthorized => user.id))
+                    } else {
+                        Ok(user)
+                    }
+                })
         }))
     }
 }
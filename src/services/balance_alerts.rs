@@ -0,0 +1,205 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use super::error::*;
+use super::ServiceFuture;
+use config::{BalanceMinimum, Config};
+use models::*;
+use prelude::*;
+use repos::{AccountsRepo, DbExecutor, TransactionsRepo};
+use utils::log_and_capture_error;
+
+pub trait BalanceAlertsService: Send + Sync + 'static {
+    /// Reports system accounts with balances below their minimums from config, so that they are topped up
+    /// before e.g. stq approvals start failing for lack of eth in fees account
+    fn check_balances(&self) -> ServiceFuture<Vec<LowSystemBalance>>;
+}
+
+#[derive(Clone)]
+pub struct BalanceAlertsServiceImpl<E: DbExecutor> {
+    config: Arc<Config>,
+    accounts_repo: Arc<AccountsRepo>,
+    transactions_repo: Arc<TransactionsRepo>,
+    db_executor: E,
+}
+
+impl<E: DbExecutor> BalanceAlertsServiceImpl<E> {
+    pub fn new(config: Arc<Config>, accounts_repo: Arc<AccountsRepo>, transactions_repo: Arc<TransactionsRepo>, db_executor: E) -> Self {
+        Self {
+            config,
+            accounts_repo,
+            transactions_repo,
+            db_executor,
+        }
+    }
+}
+
+impl<E: DbExecutor> BalanceAlertsService for BalanceAlertsServiceImpl<E> {
+    fn check_balances(&self) -> ServiceFuture<Vec<LowSystemBalance>> {
+        let config = self.config.clone();
+        let accounts_repo = self.accounts_repo.clone();
+        let transactions_repo = self.transactions_repo.clone();
+        Box::new(
+            self.db_executor
+                .execute(move || {
+                    let system_accounts = accounts_repo.list_system_accounts().map_err(ectx!(try ErrorKind::Internal))?;
+                    let balances = transactions_repo.get_system_balances().map_err(ectx!(try ErrorKind::Internal))?;
+                    let dr_balances = get_system_dr_balances(&*transactions_repo, config.system.system_user_id, &system_accounts)?;
+                    Ok(low_system_balances(&config, &system_accounts, &balances, &dr_balances))
+                })
+                .map(|low_balances| {
+                    for low_balance in low_balances.iter() {
+                        let LowSystemBalance {
+                            account_id,
+                            kind,
+                            currency,
+                            balance,
+                            min,
+                        } = low_balance.clone();
+                        let e: Error =
+                            ectx!(err ErrorContext::LowSystemBalance, ErrorKind::Internal => account_id, kind, currency, balance, min);
+                        log_and_capture_error(e);
+                    }
+                    low_balances
+                }),
+        )
+    }
+}
+
+/// Credit account of system account with role `kind` in `currency` among `system_accounts`
+pub fn find_system_account(system_accounts: &[Account], kind: SystemAccountKind, currency: Currency) -> Option<&Account> {
+    system_accounts
+        .iter()
+        .find(|account| account.system_role == Some(kind) && account.currency == currency && account.kind == AccountKind::Cr)
+}
+
+/// Balances of debit accounts among `system_accounts`, i.e. funds at the addresses of system accounts
+pub fn get_system_dr_balances(
+    transactions_repo: &TransactionsRepo,
+    system_user_id: UserId,
+    system_accounts: &[Account],
+) -> Result<HashMap<AccountId, Amount>, Error> {
+    let accounts: Vec<Account> = system_accounts
+        .iter()
+        .filter(|account| account.kind == AccountKind::Dr)
+        .cloned()
+        .collect();
+    transactions_repo
+        .get_accounts_balance(system_user_id, &accounts)
+        .map(|balances| balances.into_iter().map(|balance| (balance.account.id, balance.balance)).collect())
+        .map_err(ectx!(ErrorKind::Internal => system_user_id))
+}
+
+/// System accounts with balances below `balance_alerts.minimums`, `balances` are turnovers
+/// of credit accounts as returned by `TransactionsRepo::get_system_balances` and `dr_balances` are balances
+/// of debit accounts as returned by `get_system_dr_balances`. Both accounts of system account are checked,
+/// as funds at its address can run out apart from its ledger balance, e.g. by network fees.
+/// Minimums of system accounts missing in `system_accounts` are skipped
+pub fn low_system_balances(
+    config: &Config,
+    system_accounts: &[Account],
+    balances: &HashMap<AccountId, (Amount, Amount)>,
+    dr_balances: &HashMap<AccountId, Amount>,
+) -> Vec<LowSystemBalance> {
+    let mut low_balances = vec![];
+    for minimum in config.balance_alerts.minimums.iter() {
+        let BalanceMinimum { kind, currency, min } = minimum.clone();
+        let mut check = |account_id: AccountId, balance: f64| {
+            if balance < min {
+                low_balances.push(LowSystemBalance {
+                    account_id,
+                    kind,
+                    currency,
+                    balance,
+                    min,
+                });
+            }
+        };
+        if let Some(account) = find_system_account(system_accounts, kind, currency) {
+            let (cr_turnover, dr_turnover) = balances.get(&account.id).cloned().unwrap_or((Amount::new(0), Amount::new(0)));
+            // negative balances are reported by metrics on their own
+            let balance = cr_turnover
+                .checked_sub(dr_turnover)
+                .map(|balance| balance.to_super_unit(currency))
+                .unwrap_or(0.0);
+            check(account.id, balance);
+        }
+        if let Some(account) = system_accounts
+            .iter()
+            .find(|account| account.system_role == Some(kind) && account.currency == currency && account.kind == AccountKind::Dr)
+        {
+            let balance = dr_balances.get(&account.id).cloned().unwrap_or(Amount::new(0));
+            check(account.id, balance.to_super_unit(currency));
+        }
+    }
+    low_balances
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_low_system_balances() {
+        let mut config = Config::new().unwrap();
+        config.balance_alerts.minimums = vec![
+            BalanceMinimum {
+                kind: SystemAccountKind::Fees,
+                currency: Currency::Eth,
+                min: 1.0,
+            },
+            BalanceMinimum {
+                kind: SystemAccountKind::Liquidity,
+                currency: Currency::Btc,
+                min: 0.5,
+            },
+            BalanceMinimum {
+                kind: SystemAccountKind::Transfer,
+                currency: Currency::Stq,
+                min: 10.0,
+            },
+        ];
+        let system_account = |kind, currency, account_kind| Account {
+            id: AccountId::generate(),
+            kind: account_kind,
+            currency,
+            system_role: Some(kind),
+            ..Default::default()
+        };
+        let system_accounts = vec![
+            system_account(SystemAccountKind::Fees, Currency::Eth, AccountKind::Cr),
+            system_account(SystemAccountKind::Liquidity, Currency::Btc, AccountKind::Cr),
+            system_account(SystemAccountKind::Transfer, Currency::Stq, AccountKind::Cr),
+            system_account(SystemAccountKind::Fees, Currency::Eth, AccountKind::Dr),
+            system_account(SystemAccountKind::Liquidity, Currency::Btc, AccountKind::Dr),
+        ];
+        let eth_fees_account_id = system_accounts[0].id;
+        let btc_liquidity_account_id = system_accounts[1].id;
+        let btc_liquidity_account_dr_id = system_accounts[4].id;
+        let mut balances = HashMap::new();
+        // 0.5 eth left
+        balances.insert(
+            eth_fees_account_id,
+            (Amount::new(2_000_000_000_000_000_000), Amount::new(1_500_000_000_000_000_000)),
+        );
+        // exactly at minimum
+        balances.insert(btc_liquidity_account_id, (Amount::new(50_000_000), Amount::new(0)));
+        let mut dr_balances = HashMap::new();
+        dr_balances.insert(system_accounts[3].id, Amount::new(3_000_000_000_000_000_000));
+        // btc liquidity address is low, though its ledger balance is not
+        dr_balances.insert(btc_liquidity_account_dr_id, Amount::new(20_000_000));
+        // stq transfer account without transactions has zero balance, its debit account is missing
+        let low_balances = low_system_balances(&config, &system_accounts, &balances, &dr_balances);
+        assert_eq!(low_balances.len(), 3);
+        assert_eq!(low_balances[0].account_id, eth_fees_account_id);
+        assert_eq!(low_balances[0].balance, 0.5);
+        assert_eq!(low_balances[1].account_id, btc_liquidity_account_dr_id);
+        assert_eq!(low_balances[1].balance, 0.2);
+        assert_eq!(low_balances[2].account_id, system_accounts[2].id);
+        assert_eq!(low_balances[2].balance, 0.0);
+        // minimums of missing system accounts are skipped
+        assert!(low_system_balances(&config, &system_accounts[1..], &balances, &dr_balances)
+            .iter()
+            .all(|low_balance| low_balance.account_id != eth_fees_account_id));
+    }
+}
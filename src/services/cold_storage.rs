@@ -0,0 +1,374 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use chrono::Duration;
+use futures::future::Either;
+
+use super::error::*;
+use super::system::SystemServiceImpl;
+use super::system_accounts::{reverse_sweep, sweep_fee};
+use super::transactions::{BlockchainService, BlockchainServiceImpl};
+use super::ServiceFuture;
+use client::{BlockchainClient, ExchangeClient, KeysClient};
+use config::{ColdWallet, Config};
+use models::*;
+use prelude::*;
+use repos::{AccountsRepo, DbExecutor, KeyValuesRepo, PendingBlockchainTransactionsRepo, TransactionsRepo};
+use utils::log_and_capture_error;
+
+pub trait ColdStorageService: Send + Sync + 'static {
+    /// Sends funds of debit accounts above hot wallet ceilings to cold addresses. Returns pending sweeps,
+    /// they are completed by blockchain fetcher once confirmed, as withdrawals are
+    fn sweep(&self) -> ServiceFuture<Vec<Transaction>>;
+}
+
+#[derive(Clone)]
+pub struct ColdStorageServiceImpl<E: DbExecutor> {
+    config: Arc<Config>,
+    accounts_repo: Arc<dyn AccountsRepo>,
+    transactions_repo: Arc<dyn TransactionsRepo>,
+    blockchain_service: Arc<dyn BlockchainService>,
+    db_executor: E,
+}
+
+impl<E: DbExecutor> ColdStorageServiceImpl<E> {
+    pub fn new(
+        config: Arc<Config>,
+        accounts_repo: Arc<dyn AccountsRepo>,
+        transactions_repo: Arc<dyn TransactionsRepo>,
+        pending_transactions_repo: Arc<dyn PendingBlockchainTransactionsRepo>,
+        key_values_repo: Arc<dyn KeyValuesRepo>,
+        db_executor: E,
+        keys_client: Arc<dyn KeysClient>,
+        blockchain_client: Arc<dyn BlockchainClient>,
+        exchange_client: Arc<dyn ExchangeClient>,
+    ) -> Self {
+        let system_service = Arc::new(SystemServiceImpl::new(accounts_repo.clone()));
+        let blockchain_service = Arc::new(BlockchainServiceImpl::new(
+            config.clone(),
+            keys_client,
+            blockchain_client,
+            exchange_client,
+            pending_transactions_repo,
+            key_values_repo,
+            system_service,
+            db_executor.clone(),
+        ));
+        Self {
+            config,
+            accounts_repo,
+            transactions_repo,
+            blockchain_service,
+            db_executor,
+        }
+    }
+
+    fn sweep_currency(&self, wallet: ColdWallet) -> impl Future<Item = Vec<Transaction>, Error = Error> {
+        let self_clone = self.clone();
+        let self_clone2 = self.clone();
+        self.db_executor
+            .execute_transaction(move || self_clone.plan_sweeps(&wallet))
+            .and_then(move |(cold_account, sweeps)| {
+                // one failed sweep doesn't stop the others, its balance is just left for the next run
+                futures::stream::iter_ok(sweeps)
+                    .and_then(move |(account, sweep)| {
+                        self_clone2.send_sweep(&cold_account, account, sweep).then(|res| match res {
+                            Ok(tx) => Ok(Some(tx)),
+                            Err(e) => {
+                                log_and_capture_error(e);
+                                Ok(None)
+                            }
+                        })
+                    })
+                    .filter_map(|tx| tx)
+                    .collect()
+            })
+    }
+
+    // Sweeps are recorded as pending before they are sent, so that their funds are not withdrawn meanwhile
+    // and a sweep sent without its id saved is still seen in the ledger
+    fn plan_sweeps(&self, wallet: &ColdWallet) -> Result<(Account, Vec<(Account, Transaction)>), Error> {
+        let currency = wallet.currency;
+        let system_user_id = self.config.system.system_user_id;
+        let cold_account = self.get_or_create_cold_account(wallet)?;
+        let balances = self
+            .transactions_repo
+            .get_dr_balances(currency)
+            .map_err(ectx!(try convert => currency))?;
+        let total = balances
+            .iter()
+            .try_fold(Amount::new(0), |total, balance| total.checked_add(balance.balance))
+            .ok_or(ectx!(try err ErrorContext::BalanceOverflow, ErrorKind::Internal => currency))?;
+        // funds can't be sent from addresses with pending transactions, as for withdrawals
+        let pending_accounts: HashSet<AccountId> = self
+            .transactions_repo
+            .list_pending_older_than(Duration::zero())
+            .map_err(ectx!(try convert => currency))?
+            .into_iter()
+            .flat_map(|tx| vec![tx.dr_account_id, tx.cr_account_id])
+            .collect();
+        // accounts of system user are managed on their own, e.g. eth fees account pays for stq sweeps
+        let candidates = balances
+            .into_iter()
+            .filter(|balance| {
+                let account = &balance.account;
+                account.user_id != system_user_id
+                    && !pending_accounts.contains(&account.id)
+                    && (currency != Currency::Stq || account.erc20_approved)
+            })
+            .collect();
+        let ceiling = Amount::from_super_unit(currency, wallet.ceiling);
+        let (fee, _) = sweep_fee(&self.config, currency);
+        let mut sweeps = vec![];
+        for AccountWithBalance { account, balance } in plan_cold_sweeps(total, candidates, ceiling, fee) {
+            let sweep = self.record_sweep(cold_account.id, &account, balance)?;
+            sweeps.push((account, sweep));
+        }
+        Ok((cold_account, sweeps))
+    }
+
+    // Keys of cold address are not available to the service, so its account is archived to be never used
+    // as a source of withdrawals. It only keeps track of the funds sent there
+    fn get_or_create_cold_account(&self, wallet: &ColdWallet) -> Result<Account, Error> {
+        let ColdWallet { currency, address, .. } = wallet.clone();
+        let address_clone = address.clone();
+        if let Some(account) = self
+            .accounts_repo
+            .get_by_address(address.clone(), currency, AccountKind::Dr, ArchivedAccounts::Include)
+            .map_err(ectx!(try convert => address_clone, currency))?
+        {
+            return Ok(account);
+        }
+        let new_account = NewAccount {
+            id: AccountId::generate(),
+            user_id: self.config.system.system_user_id,
+            currency,
+            address,
+            name: Some(format!("{}_cold_storage_account", currency)),
+            kind: AccountKind::Dr,
+            daily_limit_type: Some(DailyLimitType::Unlimited),
+            meta: None,
+            labels: None,
+        };
+        let account = self
+            .accounts_repo
+            .create(new_account.clone())
+            .map_err(ectx!(try convert => new_account))?;
+        self.accounts_repo.archive(account.id).map_err(ectx!(convert => account.id))
+    }
+
+    // Attaches blockchain tx id to the pending sweep once it's sent, or reverses the sweep if it failed
+    fn send_sweep(&self, cold_account: &Account, account: Account, sweep: Transaction) -> impl Future<Item = Transaction, Error = Error> {
+        let currency = account.currency;
+        let value = sweep.value;
+        let from = account.address.clone();
+        let to = cold_account.address.clone();
+        let (_, fee_price) = sweep_fee(&self.config, currency);
+        let db_executor = self.db_executor.clone();
+        let transactions_repo = self.transactions_repo.clone();
+        match currency {
+            Currency::Btc => Either::A(
+                self.blockchain_service
+                    .create_bitcoin_tx(from.clone(), to.clone(), value, fee_price)
+                    .map_err(ectx!(ErrorKind::Internal => from, to, value, fee_price)),
+            ),
+            currency => Either::B(
+                self.blockchain_service
+                    .create_ethereum_tx(from.clone(), to.clone(), value, fee_price, currency)
+                    .map_err(ectx!(ErrorKind::Internal => from, to, value, fee_price, currency)),
+            ),
+        }
+        .then(move |res| match res {
+            Ok(blockchain_tx_id) => Either::A(db_executor.execute(move || {
+                let sweep_id = sweep.id;
+                transactions_repo
+                    .update_blockchain_tx(sweep_id, blockchain_tx_id.clone())
+                    .map_err(ectx!(convert => sweep_id, blockchain_tx_id))
+            })),
+            Err(e) => Either::B(
+                db_executor
+                    .execute_transaction(move || reverse_sweep(&*transactions_repo, sweep))
+                    .then(move |res| {
+                        // the sweep stays pending then and is to be repaired by hand
+                        if let Err(reverse_error) = res {
+                            log_and_capture_error(reverse_error);
+                        }
+                        Err::<Transaction, Error>(e)
+                    }),
+            ),
+        })
+    }
+
+    // Pending until blockchain fetcher sees it confirmed, network fee is written off from the hot account then
+    fn record_sweep(&self, cold_account_id: AccountId, account: &Account, value: Amount) -> Result<Transaction, Error> {
+        let id = TransactionId::generate();
+        let new_transaction = NewTransaction {
+            id,
+            gid: id,
+            user_id: self.config.system.system_user_id,
+            dr_account_id: cold_account_id,
+            cr_account_id: account.id,
+            currency: account.currency,
+            value,
+            status: TransactionStatus::Pending,
+            blockchain_tx_id: None,
+            kind: TransactionKind::Sweep,
+            group_kind: TransactionGroupKind::Sweep,
+            related_tx: None,
+            meta: None,
+        };
+        self.transactions_repo
+            .create(new_transaction.clone())
+            .map_err(ectx!(convert => new_transaction))
+    }
+}
+
+impl<E: DbExecutor> ColdStorageService for ColdStorageServiceImpl<E> {
+    fn sweep(&self) -> ServiceFuture<Vec<Transaction>> {
+        let self_clone = self.clone();
+        let wallets = self.config.cold_storage.wallets.clone();
+        Box::new(
+            futures::stream::iter_ok(wallets)
+                .and_then(move |wallet| self_clone.sweep_currency(wallet))
+                .concat2(),
+        )
+    }
+}
+
+/// Sweeps that bring `total` balance of debit accounts down to `ceiling`, taken from `candidates` with the largest
+/// balances first. Each sweep costs network `fee` paid from the account, so the ones that can't cover it are skipped
+fn plan_cold_sweeps(total: Amount, mut candidates: Vec<AccountWithBalance>, ceiling: Amount, fee: Amount) -> Vec<AccountWithBalance> {
+    let mut excess = match total.checked_sub(ceiling) {
+        Some(excess) => excess,
+        None => return vec![],
+    };
+    candidates.sort_by(|a, b| b.balance.raw().cmp(&a.balance.raw()));
+    let mut sweeps = vec![];
+    for AccountWithBalance { account, balance } in candidates {
+        if excess == Amount::new(0) {
+            break;
+        }
+        let available = match balance.checked_sub(fee) {
+            Some(available) if available > Amount::new(0) => available,
+            _ => continue,
+        };
+        let value = if available < excess { available } else { excess };
+        excess = Amount::new(excess.raw().saturating_sub(value.raw() + fee.raw()));
+        sweeps.push(AccountWithBalance { account, balance: value });
+    }
+    sweeps
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_core::reactor::Core;
+
+    use super::*;
+    use client::{BlockchainClientMock, ExchangeClientMock, KeysClientMock, RejectingBlockchainClient};
+    use repos::{AccountsRepoMock, DbExecutorMock, KeyValuesRepoMock, PendingBlockchainTransactionsRepoMock, TransactionsRepoMock};
+
+    fn create_service(
+        transactions_repo: Arc<TransactionsRepoMock>,
+        blockchain_client: Arc<dyn BlockchainClient>,
+    ) -> ColdStorageServiceImpl<DbExecutorMock> {
+        let mut config = Config::new().unwrap();
+        config.cold_storage.wallets = vec![ColdWallet {
+            currency: Currency::Btc,
+            address: BlockchainAddress::new("cold_address".to_string()),
+            ceiling: 0.0,
+        }];
+        ColdStorageServiceImpl::new(
+            Arc::new(config),
+            Arc::new(AccountsRepoMock::default()),
+            transactions_repo,
+            Arc::new(PendingBlockchainTransactionsRepoMock::default()),
+            Arc::new(KeyValuesRepoMock::default()),
+            DbExecutorMock::default(),
+            Arc::new(KeysClientMock::default()),
+            blockchain_client,
+            Arc::new(ExchangeClientMock::default()),
+        )
+    }
+
+    // Debit account with `value` deposited to it
+    fn create_hot_account(transactions_repo: &TransactionsRepoMock, value: Amount) -> AccountId {
+        let account_id = AccountId::generate();
+        let mut deposit = NewTransaction::default();
+        deposit.dr_account_id = account_id;
+        deposit.cr_account_id = AccountId::generate();
+        deposit.currency = Currency::Btc;
+        deposit.value = value;
+        deposit.status = TransactionStatus::Done;
+        deposit.kind = TransactionKind::Deposit;
+        transactions_repo.create(deposit).unwrap();
+        account_id
+    }
+
+    #[test]
+    fn test_sweep() {
+        let mut core = Core::new().unwrap();
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let value = Amount::from_super_unit(Currency::Btc, 1.0);
+        let hot_account_id = create_hot_account(&transactions_repo, value);
+        let service = create_service(transactions_repo.clone(), Arc::new(BlockchainClientMock::default()));
+
+        let sweeps = core.run(service.sweep()).unwrap();
+        assert_eq!(sweeps.len(), 1);
+        assert_eq!(sweeps[0].cr_account_id, hot_account_id);
+        assert_eq!(sweeps[0].status, TransactionStatus::Pending);
+        // tx id is attached to the sweep recorded before sending
+        let sweep = transactions_repo.get(sweeps[0].id).unwrap().unwrap();
+        assert_eq!(sweep.blockchain_tx_id, Some(BlockchainTransactionId::default()));
+        let balance = transactions_repo.get_account_balance(hot_account_id, AccountKind::Dr).unwrap();
+        assert_eq!(balance.checked_add(sweep.value), Some(value));
+    }
+
+    #[test]
+    fn test_failed_sweep_is_reversed() {
+        let mut core = Core::new().unwrap();
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let value = Amount::from_super_unit(Currency::Btc, 1.0);
+        let hot_account_id = create_hot_account(&transactions_repo, value);
+        let service = create_service(transactions_repo.clone(), Arc::new(RejectingBlockchainClient::default()));
+
+        let sweeps = core.run(service.sweep()).unwrap();
+        assert!(sweeps.is_empty());
+        let txs = transactions_repo.list_for_account(hot_account_id, 0, 10).unwrap();
+        let sweep = txs.iter().find(|tx| tx.group_kind == TransactionGroupKind::Sweep).unwrap();
+        assert_eq!(sweep.status, TransactionStatus::Done);
+        assert_eq!(sweep.blockchain_tx_id, None);
+        let reversal = txs.iter().find(|tx| tx.group_kind == TransactionGroupKind::Reversal).unwrap();
+        assert_eq!(reversal.related_tx, Some(sweep.id));
+        assert_eq!(reversal.dr_account_id, hot_account_id);
+        // funds are back on the hot account to be swept next run
+        let balance = transactions_repo.get_account_balance(hot_account_id, AccountKind::Dr).unwrap();
+        assert_eq!(balance, value);
+    }
+
+    fn balance(value: u128) -> AccountWithBalance {
+        AccountWithBalance {
+            account: Account::default(),
+            balance: Amount::new(value),
+        }
+    }
+
+    #[test]
+    fn test_plan_cold_sweeps() {
+        // nothing to sweep under the ceiling
+        assert!(plan_cold_sweeps(Amount::new(100), vec![balance(100)], Amount::new(100), Amount::new(1)).is_empty());
+
+        let candidates = vec![balance(30), balance(2), balance(60)];
+        let largest = candidates[2].account.id;
+        let sweeps = plan_cold_sweeps(Amount::new(120), candidates.clone(), Amount::new(50), Amount::new(5));
+        // the largest one is swept whole, the rest of excess is taken from the next one
+        assert_eq!(sweeps.len(), 2);
+        assert_eq!(sweeps[0].account.id, largest);
+        assert_eq!(sweeps[0].balance, Amount::new(55));
+        assert_eq!(sweeps[1].balance, Amount::new(10));
+
+        // accounts that can't cover the fee are skipped
+        let sweeps = plan_cold_sweeps(Amount::new(200), candidates, Amount::new(0), Amount::new(5));
+        assert_eq!(sweeps.len(), 2);
+        assert_eq!(sweeps.iter().fold(0, |total, sweep| total + sweep.balance.raw()), 55 + 25);
+    }
+}
@@ -0,0 +1,174 @@
+use std::sync::Arc;
+
+use futures::future;
+use serde_json;
+use validator::Validate;
+
+use super::auth::AuthService;
+use super::error::*;
+use super::ServiceFuture;
+use models::*;
+use prelude::*;
+use repos::{DbExecutor, KeyValuesRepo, KeyValuesRepoExt};
+
+pub trait ConfirmationsService: Send + Sync + 'static {
+    fn get_thresholds(&self, token: AuthenticationToken) -> ServiceFuture<ConfirmationThresholdsSettings>;
+    /// Overrides thresholds from config until reset, e.g. to require more confirmations during a chain attack
+    fn set_thresholds(
+        &self,
+        token: AuthenticationToken,
+        thresholds: ConfirmationThresholds,
+    ) -> ServiceFuture<ConfirmationThresholdsSettings>;
+    /// Drops runtime override, so that thresholds from config are used again
+    fn reset_thresholds(&self, token: AuthenticationToken) -> ServiceFuture<ConfirmationThresholdsSettings>;
+}
+
+#[derive(Clone)]
+pub struct ConfirmationsServiceImpl<E: DbExecutor> {
+    auth_service: Arc<dyn AuthService>,
+    key_values_repo: Arc<dyn KeyValuesRepo>,
+    defaults: ConfirmationThresholds,
+    system_user_id: UserId,
+    db_executor: E,
+}
+
+impl<E: DbExecutor> ConfirmationsServiceImpl<E> {
+    pub fn new(
+        auth_service: Arc<dyn AuthService>,
+        key_values_repo: Arc<dyn KeyValuesRepo>,
+        defaults: ConfirmationThresholds,
+        system_user_id: UserId,
+        db_executor: E,
+    ) -> Self {
+        Self {
+            auth_service,
+            key_values_repo,
+            defaults,
+            system_user_id,
+            db_executor,
+        }
+    }
+
+    fn authorize(&self, token: AuthenticationToken) -> ServiceFuture<()> {
+        let system_user_id = self.system_user_id;
+        Box::new(self.auth_service.authenticate(token).and_then(move |user| {
+            if user.id == system_user_id {
+                future::ok(())
+            } else {
+                future::err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id))
+            }
+        }))
+    }
+
+    fn settings(&self, maybe_thresholds: Option<ConfirmationThresholds>) -> ConfirmationThresholdsSettings {
+        match maybe_thresholds {
+            Some(thresholds) => ConfirmationThresholdsSettings {
+                thresholds,
+                overridden: true,
+            },
+            None => ConfirmationThresholdsSettings {
+                thresholds: self.defaults.clone(),
+                overridden: false,
+            },
+        }
+    }
+}
+
+impl<E: DbExecutor> ConfirmationsService for ConfirmationsServiceImpl<E> {
+    fn get_thresholds(&self, token: AuthenticationToken) -> ServiceFuture<ConfirmationThresholdsSettings> {
+        let self_clone = self.clone();
+        Box::new(self.authorize(token).and_then(move |_| {
+            self_clone.db_executor.execute(move || {
+                let maybe_thresholds = self_clone.key_values_repo.get_confirmation_thresholds()?;
+                Ok(self_clone.settings(maybe_thresholds))
+            })
+        }))
+    }
+
+    fn set_thresholds(
+        &self,
+        token: AuthenticationToken,
+        thresholds: ConfirmationThresholds,
+    ) -> ServiceFuture<ConfirmationThresholdsSettings> {
+        let self_clone = self.clone();
+        Box::new(
+            self.authorize(token)
+                .and_then(move |_| match thresholds.validate() {
+                    Ok(_) => Ok(thresholds),
+                    Err(e) => {
+                        Err(ectx!(err e.clone(), ErrorKind::InvalidInput(serde_json::to_string(&e).unwrap_or_default()) => thresholds))
+                    }
+                })
+                .and_then(move |thresholds| {
+                    self_clone.db_executor.execute(move || {
+                        let thresholds = self_clone.key_values_repo.set_confirmation_thresholds(thresholds)?;
+                        Ok(self_clone.settings(Some(thresholds)))
+                    })
+                }),
+        )
+    }
+
+    fn reset_thresholds(&self, token: AuthenticationToken) -> ServiceFuture<ConfirmationThresholdsSettings> {
+        let self_clone = self.clone();
+        Box::new(self.authorize(token).and_then(move |_| {
+            self_clone.db_executor.execute(move || {
+                self_clone.key_values_repo.delete_confirmation_thresholds()?;
+                Ok(self_clone.settings(None))
+            })
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use repos::*;
+    use services::*;
+    use tokio_core::reactor::Core;
+
+    fn create_confirmations_service(
+        token: AuthenticationToken,
+        user_id: UserId,
+        system_user_id: UserId,
+    ) -> ConfirmationsServiceImpl<DbExecutorMock> {
+        ConfirmationsServiceImpl::new(
+            Arc::new(AuthServiceMock::new(vec![(token, user_id)])),
+            Arc::new(KeyValuesRepoMock::default()),
+            ConfirmationThresholds::default(),
+            system_user_id,
+            DbExecutorMock::default(),
+        )
+    }
+
+    #[test]
+    fn test_confirmation_thresholds_override() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let system_user_id = UserId::generate();
+        let service = create_confirmations_service(token.clone(), system_user_id, system_user_id);
+        let mut raised = ConfirmationThresholds::default();
+        raised.btc_thresholds = vec![10, 50, 100, 500, 1000];
+
+        let settings = core.run(service.set_thresholds(token.clone(), raised.clone())).unwrap();
+        assert_eq!(settings.thresholds, raised);
+        assert!(settings.overridden);
+        let settings = core.run(service.get_thresholds(token.clone())).unwrap();
+        assert_eq!(settings.thresholds, raised);
+
+        let settings = core.run(service.reset_thresholds(token.clone())).unwrap();
+        assert_eq!(settings.thresholds, ConfirmationThresholds::default());
+        assert!(!settings.overridden);
+        let mut invalid = raised;
+        invalid.usd_per_btc = 0.0;
+        assert!(core.run(service.set_thresholds(token, invalid)).is_err());
+    }
+
+    #[test]
+    fn test_confirmation_thresholds_only_system_user() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let service = create_confirmations_service(token.clone(), UserId::generate(), UserId::generate());
+        assert!(core.run(service.get_thresholds(token.clone())).is_err());
+        assert!(core.run(service.reset_thresholds(token)).is_err());
+    }
+}
@@ -3,6 +3,7 @@ use std::fmt::Display;
 
 use failure::{Backtrace, Context, Fail};
 use serde_json;
+use validator::{ValidationError, ValidationErrors};
 
 use client::blockchain_gateway::ErrorKind as BlockchainClientErrorKind;
 use client::exchange::ErrorKind as ExchangeClientErrorKind;
@@ -28,6 +29,8 @@ pub enum ErrorKind {
     Internal,
     #[fail(display = "service error - not found")]
     NotFound,
+    #[fail(display = "service error - conflict")]
+    Conflict,
 }
 
 #[allow(dead_code)]
@@ -48,6 +51,8 @@ pub enum ErrorContext {
     NoAuthToken,
     #[fail(display = "service error context - invalid auth token")]
     InvalidToken,
+    #[fail(display = "service error context - user is disabled")]
+    DisabledUser,
     #[fail(display = "service error context - no account found")]
     NoAccount,
     #[fail(display = "service error context - no transaction found")]
@@ -58,6 +63,12 @@ pub enum ErrorContext {
     InvalidCurrency,
     #[fail(display = "service error context - exchange rate is required, but not found")]
     MissingExchangeRate,
+    #[fail(display = "service error context - exchange gateway didn't respond with rate in time")]
+    ExchangeRateTimeout,
+    #[fail(display = "service error context - exchange gateway is unavailable and there is no recent rate")]
+    ExchangeRateUnavailable,
+    #[fail(display = "service error context - exchange rate deviates too much from the current one")]
+    ExchangeRateOutOfBounds,
     #[fail(display = "service error context - invalid utf8 bytes")]
     UTF8,
     #[fail(display = "service error context - failed to parse string to json")]
@@ -82,6 +93,30 @@ pub enum ErrorContext {
     LimitExceeded,
     #[fail(display = "service error context - missing address in transaction")]
     MissingAddressInTx,
+    #[fail(display = "service error context - no withdrawal address found")]
+    NoWithdrawalAddress,
+    #[fail(display = "service error context - withdrawal address is not whitelisted")]
+    AddressNotWhitelisted,
+    #[fail(display = "service error context - invalid fee payer")]
+    InvalidFeePayer,
+    #[fail(display = "service error context - rate lock is not found, expired or already used")]
+    InvalidRateLock,
+    #[fail(display = "service error context - exchange rate moved beyond slippage tolerance of the client")]
+    SlippageExceeded,
+    #[fail(display = "service error context - exchange gateway replied with another exchange")]
+    ExchangeIdMismatch,
+    #[fail(display = "service error context - transaction failed before exchange gateway exchanged the funds")]
+    ExchangeNotPerformed,
+    #[fail(display = "service error context - liquidity account is below its target and fees can't cover it, top it up")]
+    LiquidityShortage,
+    #[fail(display = "service error context - exchange between these currencies is not allowed")]
+    ExchangePairNotAllowed,
+    #[fail(display = "service error context - system account balance is below its minimum, top it up")]
+    LowSystemBalance,
+    #[fail(display = "service error context - eth liquidity account can't top up eth fees account to its minimum, top it up")]
+    FeesTopUpShortage,
+    #[fail(display = "service error context - system account of this kind and currency already exists")]
+    SystemAccountExists,
 }
 
 derive_error_impls!();
@@ -98,6 +133,7 @@ impl From<ReposErrorKind> for ErrorKind {
         match e {
             ReposErrorKind::AlreadyInTransaction | ReposErrorKind::Internal => ErrorKind::Internal,
             ReposErrorKind::Unauthorized => ErrorKind::Unauthorized,
+            ReposErrorKind::Conflict => ErrorKind::Conflict,
             ReposErrorKind::Constraints(validation_errors) => {
                 ErrorKind::InvalidInput(serde_json::to_string(&validation_errors).unwrap_or_default())
             }
@@ -111,6 +147,8 @@ impl From<KeysClientErrorKind> for ErrorKind {
             KeysClientErrorKind::Internal => ErrorKind::Internal,
             KeysClientErrorKind::Unauthorized => ErrorKind::Internal,
             KeysClientErrorKind::MalformedInput => ErrorKind::Internal,
+            KeysClientErrorKind::GatewayTimeout => ErrorKind::Internal,
+            KeysClientErrorKind::Unavailable => ErrorKind::Internal,
         }
     }
 }
@@ -121,6 +159,8 @@ impl From<FeesClientErrorKind> for ErrorKind {
             FeesClientErrorKind::Internal => ErrorKind::Internal,
             FeesClientErrorKind::Unauthorized => ErrorKind::Internal,
             FeesClientErrorKind::MalformedInput => ErrorKind::Internal,
+            FeesClientErrorKind::GatewayTimeout => ErrorKind::Internal,
+            FeesClientErrorKind::Unavailable => ErrorKind::Internal,
         }
     }
 }
@@ -131,6 +171,10 @@ impl From<BlockchainClientErrorKind> for ErrorKind {
             BlockchainClientErrorKind::Internal => ErrorKind::Internal,
             BlockchainClientErrorKind::Unauthorized => ErrorKind::Internal,
             BlockchainClientErrorKind::MalformedInput => ErrorKind::Internal,
+            BlockchainClientErrorKind::GatewayTimeout => ErrorKind::Internal,
+            BlockchainClientErrorKind::Unavailable => ErrorKind::Internal,
+            BlockchainClientErrorKind::NotFound => ErrorKind::Internal,
+            BlockchainClientErrorKind::Rejected => ErrorKind::Internal,
         }
     }
 }
@@ -139,9 +183,20 @@ impl From<ExchangeClientErrorKind> for ErrorKind {
     fn from(err: ExchangeClientErrorKind) -> Self {
         match err {
             ExchangeClientErrorKind::Internal => ErrorKind::Internal,
+            ExchangeClientErrorKind::Unavailable => ErrorKind::Internal,
+            ExchangeClientErrorKind::GatewayTimeout => ErrorKind::Internal,
             ExchangeClientErrorKind::Unauthorized => ErrorKind::Internal,
             ExchangeClientErrorKind::MalformedInput => ErrorKind::Internal,
             ExchangeClientErrorKind::Validation(s) => ErrorKind::InvalidInput(s),
         }
     }
 }
+
+/// Invalid input error in the same form as validation errors of requests
+pub fn invalid_input(field: &'static str, code: &'static str, message: &'static str) -> ErrorKind {
+    let mut errors = ValidationErrors::new();
+    let mut error = ValidationError::new(code);
+    error.message = Some(message.into());
+    errors.add(field, error);
+    ErrorKind::InvalidInput(serde_json::to_string(&errors).unwrap_or_default())
+}
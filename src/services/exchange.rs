@@ -1,39 +1,294 @@
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::timer::Timeout;
 
 use super::error::*;
+use client::exchange::ErrorKind as ExchangeClientErrorKind;
 use client::ExchangeClient;
+use config::{Config, ExchangeOptions};
 use models::*;
 use prelude::*;
+use utils::log_warn;
 
 pub trait ExchangeService: Send + Sync + 'static {
     fn rate(&self, token: AuthenticationToken, input: RateInput) -> Box<Future<Item = Rate, Error = Error> + Send>;
 
     fn refresh_rate(&self, input: RateRefreshInput) -> Box<Future<Item = RateRefresh, Error = Error> + Send>;
+
+    /// Reserves rate like `rate` and shows values of both sides of the exchange at it and the spread
+    fn quote(&self, token: AuthenticationToken, input: RateInput) -> Box<Future<Item = ExchangeQuote, Error = Error> + Send>;
+}
+
+/// Last rates received from exchange gateway by currency pair, shared between requests.
+/// These are used only if the gateway is unavailable or for estimates, since a rate is bound to the exchange id
+/// and can't be given to another client.
+#[derive(Clone)]
+pub struct RatesCache {
+    ttl: Duration,
+    entries: Arc<Mutex<HashMap<(Currency, Currency), (Rate, Instant)>>>,
+}
+
+impl RatesCache {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            ttl: Duration::from_secs(config.exchange_options.rate_cache_ttl_secs),
+            entries: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn put(&self, rate: Rate) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.insert((rate.from, rate.to), (rate, Instant::now()));
+    }
+
+    /// Rate of the pair received within ttl, without spread
+    pub fn get(&self, from: Currency, to: Currency) -> Option<Rate> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(&(from, to))
+            .filter(|(_, fetched_at)| fetched_at.elapsed() < self.ttl)
+            .map(|(rate, _)| rate.clone())
+    }
 }
 
 #[derive(Clone)]
 pub struct ExchangeServiceImpl {
     exchange_client: Arc<ExchangeClient>,
+    rates_cache: RatesCache,
+    rate_timeout: Duration,
+    serve_stale_rates: bool,
+    exchange_options: ExchangeOptions,
 }
 
 impl ExchangeServiceImpl {
-    pub fn new(exchange_client: Arc<ExchangeClient>) -> Self {
-        Self { exchange_client }
+    pub fn new(config: &Config, exchange_client: Arc<ExchangeClient>, rates_cache: RatesCache) -> Self {
+        Self {
+            exchange_client,
+            rates_cache,
+            rate_timeout: Duration::from_secs(config.exchange_options.rate_timeout_secs),
+            serve_stale_rates: config.exchange_options.serve_stale_rates,
+            exchange_options: config.exchange_options.clone(),
+        }
+    }
+
+    // Cached rate of the same currency pair, marked as stale
+    fn stale_rate(&self, input: RateInput) -> Result<Rate, Error> {
+        let cached = if self.serve_stale_rates {
+            self.rates_cache.get(input.from, input.to)
+        } else {
+            None
+        };
+        cached
+            .map(|rate| Rate {
+                id: input.id,
+                amount: input.amount,
+                amount_currency: input.amount_currency,
+                is_stale: true,
+                ..rate
+            })
+            .ok_or(ectx!(err ErrorContext::ExchangeRateUnavailable, ErrorKind::Internal => input))
     }
 }
 
 impl ExchangeService for ExchangeServiceImpl {
     fn rate(&self, _token: AuthenticationToken, input: RateInput) -> Box<Future<Item = Rate, Error = Error> + Send> {
         let input_clone = input.clone();
-        Box::new(self.exchange_client.rate(input, Role::User).map_err(ectx!(convert => input_clone)))
+        let input_clone2 = input.clone();
+        let self_clone = self.clone();
+        let spread = self.exchange_options.spread(input.from, input.to);
+        // `None` means the gateway is unavailable
+        let rate = self.exchange_client.rate(input, Role::User).then(move |res| match res {
+            Ok(rate) => Ok(Some(rate)),
+            Err(ref e) if e.kind() == ExchangeClientErrorKind::Unavailable || e.kind() == ExchangeClientErrorKind::GatewayTimeout => {
+                log_warn(e);
+                Ok(None)
+            }
+            Err(e) => {
+                let kind: ErrorKind = e.kind().into();
+                Err(ectx!(err e, kind => input_clone))
+            }
+        });
+        Box::new(Timeout::new(rate, self.rate_timeout).then(move |res| {
+            let rate = match res {
+                Ok(Some(rate)) => {
+                    self_clone.rates_cache.put(rate.clone());
+                    Ok(rate)
+                }
+                Ok(None) => self_clone.stale_rate(input_clone2),
+                Err(ref e) if e.is_elapsed() => {
+                    let e: Error = ectx!(err ErrorContext::ExchangeRateTimeout, ErrorKind::Internal => input_clone2);
+                    log_warn(&e);
+                    self_clone.stale_rate(input_clone2)
+                }
+                Err(e) => Err(e
+                    .into_inner()
+                    .unwrap_or_else(|| ectx!(err ErrorContext::Timer, ErrorKind::Internal => input_clone2))),
+            };
+            // cache keeps gateway rates, so the spread is applied to stale ones as well
+            rate.map(|rate| rate.with_spread(spread))
+        }))
     }
 
     fn refresh_rate(&self, input: RateRefreshInput) -> Box<Future<Item = RateRefresh, Error = Error> + Send> {
         let input_clone = input.clone();
+        let exchange_options = self.exchange_options.clone();
         Box::new(
             self.exchange_client
                 .refresh_rate(input, Role::User)
-                .map_err(ectx!(convert => input_clone)),
+                .map_err(ectx!(convert => input_clone))
+                .map(move |refresh| {
+                    let spread = exchange_options.spread(refresh.exchange.from, refresh.exchange.to);
+                    RateRefresh {
+                        exchange: refresh.exchange.with_spread(spread),
+                        ..refresh
+                    }
+                }),
         )
     }
+
+    fn quote(&self, token: AuthenticationToken, input: RateInput) -> Box<Future<Item = ExchangeQuote, Error = Error> + Send> {
+        let spread = self.exchange_options.spread(input.from, input.to);
+        Box::new(self.rate(token, input).and_then(move |rate| {
+            let rate_clone = rate.clone();
+            ExchangeQuote::new(rate, spread).ok_or(ectx!(err ErrorContext::InvalidCurrency, ErrorKind::MalformedInput => rate_clone))
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use futures::future;
+    use tokio::runtime::current_thread::Runtime;
+
+    use super::*;
+    use client::exchange::{Error as ExchangeClientError, ErrorSource as ExchangeClientErrorSource};
+    use config::ExchangeSpread;
+
+    #[derive(Clone, Default)]
+    struct FlakyExchangeClient {
+        available: Arc<AtomicBool>,
+    }
+
+    impl ExchangeClient for FlakyExchangeClient {
+        fn exchange(&self, _exchange: ExchangeInput, _role: Role) -> Box<Future<Item = Exchange, Error = ExchangeClientError> + Send> {
+            unimplemented!()
+        }
+
+        fn rate(&self, input: RateInput, _role: Role) -> Box<Future<Item = Rate, Error = ExchangeClientError> + Send> {
+            if !self.available.load(Ordering::SeqCst) {
+                return Box::new(future::err(
+                    ectx!(err ExchangeClientErrorSource::Hyper, ExchangeClientErrorKind::Unavailable),
+                ));
+            }
+            let now = ::chrono::Utc::now().naive_utc();
+            Box::new(future::ok(Rate {
+                id: input.id,
+                from: input.from,
+                to: input.to,
+                amount: input.amount,
+                amount_currency: input.amount_currency,
+                rate: 2.0,
+                expiration: now,
+                created_at: now,
+                updated_at: now,
+                is_stale: false,
+            }))
+        }
+
+        fn refresh_rate(
+            &self,
+            _input: RateRefreshInput,
+            _role: Role,
+        ) -> Box<Future<Item = RateRefresh, Error = ExchangeClientError> + Send> {
+            unimplemented!()
+        }
+    }
+
+    fn create_exchange_service(config: &Config, exchange_client: FlakyExchangeClient) -> ExchangeServiceImpl {
+        ExchangeServiceImpl::new(config, Arc::new(exchange_client), RatesCache::new(config))
+    }
+
+    #[test]
+    fn test_rate_stale_when_gateway_unavailable() {
+        let mut runtime = Runtime::new().unwrap();
+        let config = Config::new().unwrap();
+        let exchange_client = FlakyExchangeClient::default();
+        exchange_client.available.store(true, Ordering::SeqCst);
+        let service = create_exchange_service(&config, exchange_client.clone());
+
+        let input = RateInput::new(Currency::Eth, Currency::Btc, Amount::new(100), Currency::Eth);
+        let rate = runtime.block_on(service.rate(AuthenticationToken::default(), input)).unwrap();
+        assert!(!rate.is_stale);
+
+        exchange_client.available.store(false, Ordering::SeqCst);
+        let input = RateInput::new(Currency::Eth, Currency::Btc, Amount::new(200), Currency::Eth);
+        let stale_rate = runtime
+            .block_on(service.rate(AuthenticationToken::default(), input.clone()))
+            .unwrap();
+        assert!(stale_rate.is_stale);
+        assert_eq!(stale_rate.id, input.id);
+        assert_eq!(stale_rate.amount, input.amount);
+        assert_eq!(stale_rate.rate, rate.rate);
+
+        // nothing is cached for the other pair
+        let input = RateInput::new(Currency::Btc, Currency::Eth, Amount::new(100), Currency::Btc);
+        assert!(runtime.block_on(service.rate(AuthenticationToken::default(), input)).is_err());
+    }
+
+    #[test]
+    fn test_rate_unavailable_without_stale_rates() {
+        let mut runtime = Runtime::new().unwrap();
+        let mut config = Config::new().unwrap();
+        config.exchange_options.serve_stale_rates = false;
+        let exchange_client = FlakyExchangeClient::default();
+        exchange_client.available.store(true, Ordering::SeqCst);
+        let service = create_exchange_service(&config, exchange_client.clone());
+
+        let input = RateInput::new(Currency::Eth, Currency::Btc, Amount::new(100), Currency::Eth);
+        assert!(runtime
+            .block_on(service.rate(AuthenticationToken::default(), input.clone()))
+            .is_ok());
+
+        exchange_client.available.store(false, Ordering::SeqCst);
+        let err = runtime.block_on(service.rate(AuthenticationToken::default(), input)).unwrap_err();
+        assert!(match err.kind() {
+            ErrorKind::Internal => true,
+            _ => false,
+        });
+    }
+
+    #[test]
+    fn test_rate_with_spread() {
+        let mut runtime = Runtime::new().unwrap();
+        let mut config = Config::new().unwrap();
+        config.exchange_options.spreads = vec![ExchangeSpread {
+            from: Currency::Eth,
+            to: Currency::Btc,
+            spread: 0.1,
+        }];
+        let exchange_client = FlakyExchangeClient::default();
+        exchange_client.available.store(true, Ordering::SeqCst);
+        let service = create_exchange_service(&config, exchange_client.clone());
+
+        let input = RateInput::new(Currency::Eth, Currency::Btc, Amount::new(100), Currency::Eth);
+        let rate = runtime.block_on(service.rate(AuthenticationToken::default(), input)).unwrap();
+        assert!((rate.rate - 1.8).abs() < 1e-9);
+
+        // cache keeps gateway rate, so spread is not applied twice
+        exchange_client.available.store(false, Ordering::SeqCst);
+        let input = RateInput::new(Currency::Eth, Currency::Btc, Amount::new(100), Currency::Eth);
+        let stale_rate = runtime.block_on(service.rate(AuthenticationToken::default(), input)).unwrap();
+        assert!(stale_rate.is_stale);
+        assert!((stale_rate.rate - 1.8).abs() < 1e-9);
+
+        // the other direction has no spread
+        exchange_client.available.store(true, Ordering::SeqCst);
+        let input = RateInput::new(Currency::Btc, Currency::Eth, Amount::new(100), Currency::Btc);
+        let rate = runtime.block_on(service.rate(AuthenticationToken::default(), input)).unwrap();
+        assert!((rate.rate - 2.0).abs() < 1e-9);
+    }
 }
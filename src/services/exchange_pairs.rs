@@ -0,0 +1,190 @@
+use std::sync::Arc;
+
+use futures::future;
+use serde_json;
+use validator::Validate;
+
+use super::auth::AuthService;
+use super::error::*;
+use super::ServiceFuture;
+use models::*;
+use prelude::*;
+use repos::{DbExecutor, KeyValuesRepo, KeyValuesRepoExt};
+
+pub trait ExchangePairsService: Send + Sync + 'static {
+    fn get_pairs(&self, token: AuthenticationToken) -> ServiceFuture<ExchangePairsSettings>;
+    /// Overrides allowed pairs from config until reset, e.g. to pause a pair during liquidity crunch
+    fn set_pairs(&self, token: AuthenticationToken, pairs: ExchangePairs) -> ServiceFuture<ExchangePairsSettings>;
+    /// Drops runtime override, so that pairs from config are allowed again
+    fn reset_pairs(&self, token: AuthenticationToken) -> ServiceFuture<ExchangePairsSettings>;
+}
+
+#[derive(Clone)]
+pub struct ExchangePairsServiceImpl<E: DbExecutor> {
+    auth_service: Arc<dyn AuthService>,
+    key_values_repo: Arc<dyn KeyValuesRepo>,
+    defaults: ExchangePairs,
+    system_user_id: UserId,
+    db_executor: E,
+}
+
+impl<E: DbExecutor> ExchangePairsServiceImpl<E> {
+    pub fn new(
+        auth_service: Arc<dyn AuthService>,
+        key_values_repo: Arc<dyn KeyValuesRepo>,
+        defaults: ExchangePairs,
+        system_user_id: UserId,
+        db_executor: E,
+    ) -> Self {
+        Self {
+            auth_service,
+            key_values_repo,
+            defaults,
+            system_user_id,
+            db_executor,
+        }
+    }
+
+    fn authorize(&self, token: AuthenticationToken) -> ServiceFuture<()> {
+        let system_user_id = self.system_user_id;
+        Box::new(self.auth_service.authenticate(token).and_then(move |user| {
+            if user.id == system_user_id {
+                future::ok(())
+            } else {
+                future::err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id))
+            }
+        }))
+    }
+
+    fn settings(&self, maybe_pairs: Option<ExchangePairs>) -> ExchangePairsSettings {
+        match maybe_pairs {
+            Some(pairs) => ExchangePairsSettings { pairs, overridden: true },
+            None => ExchangePairsSettings {
+                pairs: self.defaults.clone(),
+                overridden: false,
+            },
+        }
+    }
+}
+
+impl<E: DbExecutor> ExchangePairsService for ExchangePairsServiceImpl<E> {
+    fn get_pairs(&self, token: AuthenticationToken) -> ServiceFuture<ExchangePairsSettings> {
+        let self_clone = self.clone();
+        Box::new(self.authorize(token).and_then(move |_| {
+            self_clone.db_executor.execute(move || {
+                let maybe_pairs = self_clone.key_values_repo.get_exchange_pairs()?;
+                Ok(self_clone.settings(maybe_pairs))
+            })
+        }))
+    }
+
+    fn set_pairs(&self, token: AuthenticationToken, pairs: ExchangePairs) -> ServiceFuture<ExchangePairsSettings> {
+        let self_clone = self.clone();
+        Box::new(
+            self.authorize(token)
+                .and_then(move |_| match pairs.validate() {
+                    Ok(_) => Ok(pairs),
+                    Err(e) => Err(ectx!(err e.clone(), ErrorKind::InvalidInput(serde_json::to_string(&e).unwrap_or_default()) => pairs)),
+                })
+                .and_then(move |pairs| {
+                    self_clone.db_executor.execute(move || {
+                        let pairs = self_clone.key_values_repo.set_exchange_pairs(pairs)?;
+                        Ok(self_clone.settings(Some(pairs)))
+                    })
+                }),
+        )
+    }
+
+    fn reset_pairs(&self, token: AuthenticationToken) -> ServiceFuture<ExchangePairsSettings> {
+        let self_clone = self.clone();
+        Box::new(self.authorize(token).and_then(move |_| {
+            self_clone.db_executor.execute(move || {
+                self_clone.key_values_repo.delete_exchange_pairs()?;
+                Ok(self_clone.settings(None))
+            })
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use repos::*;
+    use services::*;
+    use tokio_core::reactor::Core;
+
+    fn all_pairs() -> ExchangePairs {
+        let currencies = [Currency::Btc, Currency::Eth, Currency::Stq];
+        let mut pairs = vec![];
+        for from in currencies.iter() {
+            for to in currencies.iter().filter(|to| *to != from) {
+                pairs.push(ExchangePair { from: *from, to: *to });
+            }
+        }
+        ExchangePairs { pairs }
+    }
+
+    fn create_exchange_pairs_service(
+        token: AuthenticationToken,
+        user_id: UserId,
+        system_user_id: UserId,
+    ) -> ExchangePairsServiceImpl<DbExecutorMock> {
+        ExchangePairsServiceImpl::new(
+            Arc::new(AuthServiceMock::new(vec![(token, user_id)])),
+            Arc::new(KeyValuesRepoMock::default()),
+            all_pairs(),
+            system_user_id,
+            DbExecutorMock::default(),
+        )
+    }
+
+    #[test]
+    fn test_exchange_pairs_override() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let system_user_id = UserId::generate();
+        let service = create_exchange_pairs_service(token.clone(), system_user_id, system_user_id);
+        let mut paused = all_pairs();
+        paused.pairs.retain(|pair| {
+            *pair
+                != ExchangePair {
+                    from: Currency::Stq,
+                    to: Currency::Btc,
+                }
+        });
+
+        let settings = core.run(service.set_pairs(token.clone(), paused.clone())).unwrap();
+        assert_eq!(settings.pairs, paused);
+        assert!(settings.overridden);
+        assert!(!settings.pairs.allows(Currency::Stq, Currency::Btc));
+        assert!(settings.pairs.allows(Currency::Btc, Currency::Stq));
+        let settings = core.run(service.get_pairs(token.clone())).unwrap();
+        assert_eq!(settings.pairs, paused);
+
+        let settings = core.run(service.reset_pairs(token.clone())).unwrap();
+        assert_eq!(settings.pairs, all_pairs());
+        assert!(!settings.overridden);
+        let invalid = ExchangePairs {
+            pairs: vec![ExchangePair {
+                from: Currency::Eth,
+                to: Currency::Eth,
+            }],
+        };
+        assert!(core.run(service.set_pairs(token.clone(), invalid)).is_err());
+        let pair = ExchangePair {
+            from: Currency::Eth,
+            to: Currency::Btc,
+        };
+        let duplicate = ExchangePairs { pairs: vec![pair, pair] };
+        assert!(core.run(service.set_pairs(token, duplicate)).is_err());
+    }
+
+    #[test]
+    fn test_exchange_pairs_only_system_user() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let service = create_exchange_pairs_service(token.clone(), UserId::generate(), UserId::generate());
+        assert!(core.run(service.get_pairs(token.clone())).is_err());
+        assert!(core.run(service.reset_pairs(token)).is_err());
+    }
+}
@@ -1,7 +1,10 @@
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use futures::future::{self, Either};
 use serde_json;
+use tokio;
 use validator::{ValidationError, ValidationErrors};
 
 use super::error::*;
@@ -10,6 +13,83 @@ use config::Config;
 use models::*;
 use prelude::*;
 use repos::{AccountsRepo, DbExecutor};
+use utils::log_error;
+
+/// Fees fetched from external apis, shared between requests.
+/// Fees younger than `ttl` are served from cache. Fees older than `ttl`, but younger than
+/// `ttl + stale_ttl` are served from cache as well, while being refreshed in background,
+/// so that slow or failing upstream doesn't affect clients. Older fees are fetched synchronously.
+#[derive(Clone)]
+pub struct FeesCache {
+    ttl: Duration,
+    stale_ttl: Duration,
+    entries: Arc<Mutex<HashMap<Currency, FeesCacheEntry>>>,
+}
+
+#[derive(Clone)]
+struct FeesCacheEntry {
+    fees: Vec<Fee>,
+    fetched_at: Instant,
+    refreshing: bool,
+}
+
+enum FeesCacheLookup {
+    Fresh(Vec<Fee>),
+    /// Fees are stale and the caller is responsible for refreshing them
+    NeedsRefresh(Vec<Fee>),
+    Missing,
+}
+
+impl FeesCache {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            ttl: Duration::from_secs(config.fees_options.cache_ttl_secs),
+            stale_ttl: Duration::from_secs(config.fees_options.cache_stale_ttl_secs),
+            entries: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn lookup(&self, currency: Currency) -> FeesCacheLookup {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = match entries.get_mut(&currency) {
+            Some(entry) => entry,
+            None => return FeesCacheLookup::Missing,
+        };
+        let age = entry.fetched_at.elapsed();
+        if age < self.ttl {
+            FeesCacheLookup::Fresh(entry.fees.clone())
+        } else if age < self.ttl + self.stale_ttl {
+            // only one refresh at a time
+            if entry.refreshing {
+                FeesCacheLookup::Fresh(entry.fees.clone())
+            } else {
+                entry.refreshing = true;
+                FeesCacheLookup::NeedsRefresh(entry.fees.clone())
+            }
+        } else {
+            FeesCacheLookup::Missing
+        }
+    }
+
+    fn put(&self, currency: Currency, fees: Vec<Fee>) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.insert(
+            currency,
+            FeesCacheEntry {
+                fees,
+                fetched_at: Instant::now(),
+                refreshing: false,
+            },
+        );
+    }
+
+    fn refresh_failed(&self, currency: Currency) {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.get_mut(&currency) {
+            entry.refreshing = false;
+        }
+    }
+}
 
 pub trait FeesService: Send + Sync + 'static {
     fn get_fees(&self, get_fees: GetFees) -> Box<Future<Item = Fees, Error = Error> + Send>;
@@ -21,6 +101,7 @@ pub struct FeesServiceImpl<E: DbExecutor> {
     db_executor: E,
     exchange_client: Arc<ExchangeClient>,
     fees_client: Arc<FeesClient>,
+    fees_cache: FeesCache,
     fee_upside: f64,
 }
 
@@ -31,12 +112,14 @@ impl<E: DbExecutor> FeesServiceImpl<E> {
         db_executor: E,
         exchange_client: Arc<ExchangeClient>,
         fees_client: Arc<FeesClient>,
+        fees_cache: FeesCache,
     ) -> Self {
         Self {
             accounts_repo,
             db_executor,
             exchange_client,
             fees_client,
+            fees_cache,
             fee_upside: config.fees_options.fee_upside,
         }
     }
@@ -56,13 +139,50 @@ impl<E: DbExecutor> FeesServiceImpl<E> {
                 fees
             })
     }
+
+    fn fetch_fees(&self, currency: Currency) -> Box<Future<Item = Vec<Fee>, Error = Error> + Send> {
+        let service = self.clone();
+        match currency {
+            Currency::Btc => Box::new(self.fees_client.bitcoin_fees().map_err(ectx!(ErrorKind::Internal => currency))),
+            Currency::Eth => Box::new(self.fees_client.eth_fees().map_err(ectx!(ErrorKind::Internal => currency))),
+            Currency::Stq => Box::new(
+                self.fees_client
+                    .stq_fees()
+                    .map_err(ectx!(ErrorKind::Internal => currency))
+                    .and_then(move |fees| service.convert_fees(fees, Currency::Stq, Currency::Eth)),
+            ),
+        }
+    }
+
+    fn get_cached_fees(&self, currency: Currency) -> Box<Future<Item = Vec<Fee>, Error = Error> + Send> {
+        let fees_cache = self.fees_cache.clone();
+        match self.fees_cache.lookup(currency) {
+            FeesCacheLookup::Fresh(fees) => Box::new(future::ok(fees)),
+            FeesCacheLookup::NeedsRefresh(fees) => {
+                tokio::spawn(self.fetch_fees(currency).then(move |res| {
+                    match res {
+                        Ok(fees) => fees_cache.put(currency, fees),
+                        Err(e) => {
+                            log_error(&e);
+                            fees_cache.refresh_failed(currency);
+                        }
+                    }
+                    Ok(())
+                }));
+                Box::new(future::ok(fees))
+            }
+            FeesCacheLookup::Missing => Box::new(self.fetch_fees(currency).map(move |fees| {
+                fees_cache.put(currency, fees.clone());
+                fees
+            })),
+        }
+    }
 }
 
 impl<E: DbExecutor> FeesService for FeesServiceImpl<E> {
     fn get_fees(&self, get_fees: GetFees) -> Box<Future<Item = Fees, Error = Error> + Send> {
         let accounts_repo = self.accounts_repo.clone();
         let db_executor = self.db_executor.clone();
-        let fees_client = self.fees_client.clone();
         let currency = get_fees.currency;
         let fee_upside = self.fee_upside;
         let service = self.clone();
@@ -71,7 +191,7 @@ impl<E: DbExecutor> FeesService for FeesServiceImpl<E> {
             db_executor
                 .execute(move || {
                     accounts_repo
-                        .filter_by_address(address.clone())
+                        .filter_by_address(address.clone(), ArchivedAccounts::Include)
                         .map_err(ectx!(convert => address))
                         .and_then(|accs| {
                             if accs.len() == 0 {
@@ -95,18 +215,7 @@ impl<E: DbExecutor> FeesService for FeesServiceImpl<E> {
                         Either::A(future::ok(Fees::new(currency, vec![Fee::default()])))
                     } else {
                         Either::B(
-                            match currency {
-                                Currency::Btc => Box::new(fees_client.bitcoin_fees().map_err(ectx!(ErrorKind::Internal => currency)))
-                                    as Box<Future<Item = Vec<Fee>, Error = Error> + Send>,
-                                Currency::Eth => Box::new(fees_client.eth_fees().map_err(ectx!(ErrorKind::Internal => currency)))
-                                    as Box<Future<Item = Vec<Fee>, Error = Error> + Send>,
-                                Currency::Stq => Box::new(
-                                    fees_client
-                                        .stq_fees()
-                                        .map_err(ectx!(ErrorKind::Internal => currency))
-                                        .and_then(move |fees| service.convert_fees(fees, Currency::Stq, Currency::Eth)),
-                                ) as Box<Future<Item = Vec<Fee>, Error = Error> + Send>,
-                            }.map(move |mut fees| {
+                            service.get_cached_fees(currency).map(move |mut fees| {
                                 fees.iter_mut()
                                     .for_each(|f| f.value = Amount::new((f.value.raw() as f64 * fee_upside) as u128));
                                 Fees::new(currency, fees)
@@ -117,3 +226,64 @@ impl<E: DbExecutor> FeesService for FeesServiceImpl<E> {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_fees_cache(ttl_secs: u64, stale_ttl_secs: u64) -> FeesCache {
+        FeesCache {
+            ttl: Duration::from_secs(ttl_secs),
+            stale_ttl: Duration::from_secs(stale_ttl_secs),
+            entries: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    #[test]
+    fn test_fees_cache_fresh() {
+        let fees_cache = create_fees_cache(60, 600);
+        assert!(match fees_cache.lookup(Currency::Btc) {
+            FeesCacheLookup::Missing => true,
+            _ => false,
+        });
+        fees_cache.put(Currency::Btc, vec![Fee::default()]);
+        assert!(match fees_cache.lookup(Currency::Btc) {
+            FeesCacheLookup::Fresh(ref fees) => fees.len() == 1,
+            _ => false,
+        });
+        assert!(match fees_cache.lookup(Currency::Eth) {
+            FeesCacheLookup::Missing => true,
+            _ => false,
+        });
+    }
+
+    #[test]
+    fn test_fees_cache_stale() {
+        let fees_cache = create_fees_cache(0, 600);
+        fees_cache.put(Currency::Eth, vec![Fee::default()]);
+        // the first one refreshes, others get stale fees meanwhile
+        assert!(match fees_cache.lookup(Currency::Eth) {
+            FeesCacheLookup::NeedsRefresh(_) => true,
+            _ => false,
+        });
+        assert!(match fees_cache.lookup(Currency::Eth) {
+            FeesCacheLookup::Fresh(_) => true,
+            _ => false,
+        });
+        fees_cache.refresh_failed(Currency::Eth);
+        assert!(match fees_cache.lookup(Currency::Eth) {
+            FeesCacheLookup::NeedsRefresh(_) => true,
+            _ => false,
+        });
+    }
+
+    #[test]
+    fn test_fees_cache_expired() {
+        let fees_cache = create_fees_cache(0, 0);
+        fees_cache.put(Currency::Stq, vec![Fee::default()]);
+        assert!(match fees_cache.lookup(Currency::Stq) {
+            FeesCacheLookup::Missing => true,
+            _ => false,
+        });
+    }
+}
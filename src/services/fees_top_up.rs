@@ -0,0 +1,393 @@
+use std::sync::Arc;
+
+use chrono::Duration;
+use futures::future::{self, Either};
+
+use super::error::*;
+use super::system::{SystemService, SystemServiceImpl};
+use super::system_accounts::{reverse_sweep, sweep_fee};
+use super::transactions::{BlockchainService, BlockchainServiceImpl};
+use super::ServiceFuture;
+use client::{BlockchainClient, ExchangeClient, KeysClient};
+use config::Config;
+use models::*;
+use prelude::*;
+use repos::{AccountsRepo, DbExecutor, Isolation, KeyValuesRepo, PendingBlockchainTransactionsRepo, TransactionsRepo};
+use utils::log_and_capture_error;
+
+/// Result of checking eth balance of fees account
+#[derive(Debug, Clone)]
+pub struct FeesTopUp {
+    /// Balance of fees debit account before top up
+    pub balance: Amount,
+    /// Pending transfer from the address of liquidity account, if the balance was below minimum
+    pub transaction: Option<Transaction>,
+    /// Missing to the minimum after the transfer, has to be topped up externally
+    pub shortage: Amount,
+}
+
+// Eth to send from liquidity address to fees one
+#[derive(Debug, Clone)]
+struct TopUpTransfer {
+    fees_account_dr: Account,
+    liquidity_account_dr: Account,
+    value: Amount,
+}
+
+pub trait FeesTopUpService: Send + Sync + 'static {
+    /// Sends eth from the address of eth liquidity account to the one of fees account, that pays for stq transfers,
+    /// once its balance is below minimum. Shortage that liquidity can't cover is reported
+    fn top_up(&self) -> ServiceFuture<FeesTopUp>;
+}
+
+#[derive(Clone)]
+pub struct FeesTopUpServiceImpl<E: DbExecutor> {
+    config: Arc<Config>,
+    accounts_repo: Arc<dyn AccountsRepo>,
+    transactions_repo: Arc<dyn TransactionsRepo>,
+    system_service: Arc<dyn SystemService>,
+    blockchain_service: Arc<dyn BlockchainService>,
+    db_executor: E,
+}
+
+impl<E: DbExecutor> FeesTopUpServiceImpl<E> {
+    pub fn new(
+        config: Arc<Config>,
+        accounts_repo: Arc<dyn AccountsRepo>,
+        transactions_repo: Arc<dyn TransactionsRepo>,
+        pending_transactions_repo: Arc<dyn PendingBlockchainTransactionsRepo>,
+        key_values_repo: Arc<dyn KeyValuesRepo>,
+        db_executor: E,
+        keys_client: Arc<dyn KeysClient>,
+        blockchain_client: Arc<dyn BlockchainClient>,
+        exchange_client: Arc<dyn ExchangeClient>,
+    ) -> Self {
+        let system_service = Arc::new(SystemServiceImpl::new(accounts_repo.clone()));
+        let blockchain_service = Arc::new(BlockchainServiceImpl::new(
+            config.clone(),
+            keys_client,
+            blockchain_client,
+            exchange_client,
+            pending_transactions_repo,
+            key_values_repo,
+            system_service.clone(),
+            db_executor.clone(),
+        ));
+        Self {
+            config,
+            accounts_repo,
+            transactions_repo,
+            system_service,
+            blockchain_service,
+            db_executor,
+        }
+    }
+
+    fn plan(&self) -> Result<(FeesTopUp, Option<TopUpTransfer>), Error> {
+        let system_user_id = self.config.system.system_user_id;
+        let fees_account_dr = self
+            .system_service
+            .get_system_fees_account_dr(Currency::Eth)
+            .map_err(ectx!(try ErrorKind::Internal))?;
+        let (role, currency, kind) = (SystemAccountKind::Liquidity, Currency::Eth, AccountKind::Dr);
+        let liquidity_account_dr = self
+            .accounts_repo
+            .get_system_account(role, currency, kind)
+            .map_err(ectx!(try convert => role, currency, kind))?
+            .ok_or(ectx!(try err ErrorContext::NoAccount, ErrorKind::Internal => role, currency, kind))?;
+        let accounts = [fees_account_dr.clone(), liquidity_account_dr.clone()];
+        // pending top up is already counted in the balance, so it's not sent twice
+        let balances = self
+            .transactions_repo
+            .get_accounts_balance(system_user_id, &accounts)
+            .map_err(ectx!(try convert => system_user_id, accounts))?;
+        let (balance, liquidity_balance) = (balances[0].balance, balances[1].balance);
+        // a top up still pending is waited for. It's checked in serializable transaction along with recording,
+        // so of two instances running the interval at once only one sends it
+        let pending = self
+            .transactions_repo
+            .list_pending_older_than(Duration::zero())
+            .map_err(ectx!(try convert))?
+            .into_iter()
+            .any(|tx| tx.dr_account_id == fees_account_dr.id && tx.cr_account_id == liquidity_account_dr.id);
+        let min = Amount::from_super_unit(Currency::Eth, self.config.eth_fees_top_up.min);
+        let target = Amount::from_super_unit(Currency::Eth, self.config.eth_fees_top_up.target);
+        let (fee, _) = sweep_fee(&self.config, Currency::Eth);
+        let (value, shortage) = plan_top_up(balance, liquidity_balance, min, target, fee);
+        let top_up = FeesTopUp {
+            balance,
+            transaction: None,
+            shortage,
+        };
+        let transfer = match value {
+            Some(value) if !pending => Some(TopUpTransfer {
+                fees_account_dr,
+                liquidity_account_dr,
+                value,
+            }),
+            _ => None,
+        };
+        Ok((top_up, transfer))
+    }
+
+    // Top up is recorded as pending before it's sent, so that it's never sent twice
+    // and the one sent without its id saved is still seen in the ledger
+    fn plan_and_record(&self) -> Result<(FeesTopUp, Option<(TopUpTransfer, Transaction)>), Error> {
+        let (top_up, transfer) = self.plan()?;
+        match transfer {
+            Some(transfer) => {
+                let tx = self.record_top_up(&transfer)?;
+                Ok((top_up, Some((transfer, tx))))
+            }
+            None => Ok((top_up, None)),
+        }
+    }
+
+    // Attaches blockchain tx id to the pending top up once it's sent, or reverses the top up if it failed
+    fn send_top_up(&self, transfer: TopUpTransfer, top_up: Transaction) -> impl Future<Item = Transaction, Error = Error> {
+        let TopUpTransfer {
+            fees_account_dr,
+            liquidity_account_dr,
+            value,
+        } = transfer;
+        let from = liquidity_account_dr.address;
+        let to = fees_account_dr.address;
+        let (_, fee_price) = sweep_fee(&self.config, Currency::Eth);
+        let db_executor = self.db_executor.clone();
+        let transactions_repo = self.transactions_repo.clone();
+        self.blockchain_service
+            .create_ethereum_tx(from.clone(), to.clone(), value, fee_price, Currency::Eth)
+            .map_err(ectx!(ErrorKind::Internal => from, to, value, fee_price))
+            .then(move |res| match res {
+                Ok(blockchain_tx_id) => Either::A(db_executor.execute(move || {
+                    let top_up_id = top_up.id;
+                    transactions_repo
+                        .update_blockchain_tx(top_up_id, blockchain_tx_id.clone())
+                        .map_err(ectx!(convert => top_up_id, blockchain_tx_id))
+                })),
+                Err(e) => Either::B(
+                    db_executor
+                        .execute_transaction(move || reverse_sweep(&*transactions_repo, top_up))
+                        .then(move |res| {
+                            // the top up stays pending then and is to be repaired by hand
+                            if let Err(reverse_error) = res {
+                                log_and_capture_error(reverse_error);
+                            }
+                            Err::<Transaction, Error>(e)
+                        }),
+                ),
+            })
+    }
+
+    // Sent between our own addresses as sweeps are, so it's completed by blockchain fetcher the same way.
+    // Network fee is written off from the liquidity account then
+    fn record_top_up(&self, transfer: &TopUpTransfer) -> Result<Transaction, Error> {
+        let id = TransactionId::generate();
+        let new_transaction = NewTransaction {
+            id,
+            gid: id,
+            user_id: self.config.system.system_user_id,
+            dr_account_id: transfer.fees_account_dr.id,
+            cr_account_id: transfer.liquidity_account_dr.id,
+            currency: Currency::Eth,
+            value: transfer.value,
+            status: TransactionStatus::Pending,
+            blockchain_tx_id: None,
+            kind: TransactionKind::Sweep,
+            group_kind: TransactionGroupKind::Sweep,
+            related_tx: None,
+            meta: None,
+        };
+        self.transactions_repo
+            .create(new_transaction.clone())
+            .map_err(ectx!(convert => new_transaction))
+    }
+}
+
+impl<E: DbExecutor> FeesTopUpService for FeesTopUpServiceImpl<E> {
+    fn top_up(&self) -> ServiceFuture<FeesTopUp> {
+        let self_clone = self.clone();
+        let self_clone2 = self.clone();
+        Box::new(
+            self.db_executor
+                .execute_transaction_with_retry(Isolation::Serializable, move || self_clone.plan_and_record())
+                .and_then(move |(top_up, transfer)| match transfer {
+                    Some((transfer, tx)) => Either::A(self_clone2.send_top_up(transfer, tx).map(move |tx| FeesTopUp {
+                        transaction: Some(tx),
+                        ..top_up
+                    })),
+                    None => Either::B(future::ok(top_up)),
+                })
+                .map(|top_up| {
+                    if top_up.shortage > Amount::new(0) {
+                        let balance = top_up.balance.to_super_unit_string(Currency::Eth);
+                        let shortage = top_up.shortage.to_super_unit_string(Currency::Eth);
+                        let e: Error = ectx!(err ErrorContext::FeesTopUpShortage, ErrorKind::Internal => balance, shortage);
+                        log_and_capture_error(e);
+                    }
+                    top_up
+                }),
+        )
+    }
+}
+
+/// Value that brings fees `balance` below `min` up to `target`, as far as `liquidity_balance` less network `fee` allows,
+/// and the rest missing to `min`
+fn plan_top_up(balance: Amount, liquidity_balance: Amount, min: Amount, target: Amount, fee: Amount) -> (Option<Amount>, Amount) {
+    if balance >= min {
+        return (None, Amount::new(0));
+    }
+    let needed = Amount::new(target.raw() - balance.raw());
+    let available = Amount::new(liquidity_balance.raw().saturating_sub(fee.raw()));
+    let value = if needed < available { needed } else { available };
+    let shortage = Amount::new(min.raw().saturating_sub(balance.raw() + value.raw()));
+    if value > Amount::new(0) {
+        (Some(value), shortage)
+    } else {
+        (None, shortage)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_core::reactor::Core;
+
+    use super::*;
+    use client::{BlockchainClientMock, ExchangeClientMock, KeysClientMock, RejectingBlockchainClient};
+    use repos::{AccountsRepoMock, DbExecutorMock, KeyValuesRepoMock, PendingBlockchainTransactionsRepoMock, TransactionsRepoMock};
+
+    fn create_service(
+        accounts_repo: Arc<AccountsRepoMock>,
+        transactions_repo: Arc<TransactionsRepoMock>,
+        blockchain_client: Arc<dyn BlockchainClient>,
+    ) -> FeesTopUpServiceImpl<DbExecutorMock> {
+        FeesTopUpServiceImpl::new(
+            Arc::new(Config::new().unwrap()),
+            accounts_repo,
+            transactions_repo,
+            Arc::new(PendingBlockchainTransactionsRepoMock::default()),
+            Arc::new(KeyValuesRepoMock::default()),
+            DbExecutorMock::default(),
+            Arc::new(KeysClientMock::default()),
+            blockchain_client,
+            Arc::new(ExchangeClientMock::default()),
+        )
+    }
+
+    // Debit account of eth system account with `kind` role
+    fn create_system_account_dr(accounts_repo: &AccountsRepoMock, kind: SystemAccountKind) -> Account {
+        let account_id = AccountId::generate();
+        let mut new_account = NewAccount::default();
+        new_account.id = account_id;
+        new_account.currency = Currency::Eth;
+        new_account.address = BlockchainAddress::new(format!("{}_address", kind));
+        new_account.kind = AccountKind::Cr;
+        accounts_repo.create(new_account.clone()).unwrap();
+        accounts_repo.set_system_role(account_id, Some(kind)).unwrap();
+        new_account.id = account_id.derive_system_dr_id();
+        new_account.kind = AccountKind::Dr;
+        accounts_repo.create(new_account).unwrap();
+        accounts_repo.set_system_role(account_id.derive_system_dr_id(), Some(kind)).unwrap()
+    }
+
+    fn deposit(transactions_repo: &TransactionsRepoMock, account_id: AccountId, value: f64) {
+        let mut deposit = NewTransaction::default();
+        deposit.dr_account_id = account_id;
+        deposit.cr_account_id = AccountId::generate();
+        deposit.currency = Currency::Eth;
+        deposit.value = Amount::from_super_unit(Currency::Eth, value);
+        deposit.status = TransactionStatus::Done;
+        deposit.kind = TransactionKind::Deposit;
+        transactions_repo.create(deposit).unwrap();
+    }
+
+    #[test]
+    fn test_top_up() {
+        let mut core = Core::new().unwrap();
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let fees_account_dr = create_system_account_dr(&accounts_repo, SystemAccountKind::Fees);
+        let liquidity_account_dr = create_system_account_dr(&accounts_repo, SystemAccountKind::Liquidity);
+        deposit(&transactions_repo, liquidity_account_dr.id, 0.5);
+        let service = create_service(
+            accounts_repo.clone(),
+            transactions_repo.clone(),
+            Arc::new(BlockchainClientMock::default()),
+        );
+
+        let top_up = core.run(service.top_up()).unwrap();
+        let tx = top_up.transaction.unwrap();
+        assert_eq!(tx.dr_account_id, fees_account_dr.id);
+        assert_eq!(tx.cr_account_id, liquidity_account_dr.id);
+        assert_eq!(tx.status, TransactionStatus::Pending);
+        // tx id is attached to the top up recorded before sending
+        assert_eq!(tx.blockchain_tx_id, Some(BlockchainTransactionId::default()));
+        assert!(top_up.shortage > Amount::new(0));
+
+        // fees balance is still below minimum, but nothing is sent while the top up is pending
+        deposit(&transactions_repo, liquidity_account_dr.id, 10.0);
+        let top_up = core.run(service.top_up()).unwrap();
+        assert!(top_up.transaction.is_none());
+    }
+
+    #[test]
+    fn test_failed_top_up_is_reversed() {
+        let mut core = Core::new().unwrap();
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let fees_account_dr = create_system_account_dr(&accounts_repo, SystemAccountKind::Fees);
+        let liquidity_account_dr = create_system_account_dr(&accounts_repo, SystemAccountKind::Liquidity);
+        deposit(&transactions_repo, liquidity_account_dr.id, 10.0);
+        let service = create_service(
+            accounts_repo.clone(),
+            transactions_repo.clone(),
+            Arc::new(RejectingBlockchainClient::default()),
+        );
+
+        assert!(core.run(service.top_up()).is_err());
+        let txs = transactions_repo.list_for_account(fees_account_dr.id, 0, 10).unwrap();
+        let top_up = txs.iter().find(|tx| tx.group_kind == TransactionGroupKind::Sweep).unwrap();
+        assert_eq!(top_up.status, TransactionStatus::Done);
+        assert_eq!(top_up.blockchain_tx_id, None);
+        let reversal = txs.iter().find(|tx| tx.group_kind == TransactionGroupKind::Reversal).unwrap();
+        assert_eq!(reversal.related_tx, Some(top_up.id));
+        assert_eq!(reversal.dr_account_id, liquidity_account_dr.id);
+        // funds are back on the liquidity account to be sent next run
+        let balance = transactions_repo
+            .get_account_balance(liquidity_account_dr.id, AccountKind::Dr)
+            .unwrap();
+        assert_eq!(balance, Amount::from_super_unit(Currency::Eth, 10.0));
+        let balance = transactions_repo.get_account_balance(fees_account_dr.id, AccountKind::Dr).unwrap();
+        assert_eq!(balance, Amount::new(0));
+    }
+
+    #[test]
+    fn test_plan_top_up() {
+        let (min, target, fee) = (Amount::new(100), Amount::new(500), Amount::new(10));
+        // enough eth left
+        assert_eq!(
+            plan_top_up(Amount::new(100), Amount::new(1000), min, target, fee),
+            (None, Amount::new(0))
+        );
+        // topped up to target
+        assert_eq!(
+            plan_top_up(Amount::new(50), Amount::new(1000), min, target, fee),
+            (Some(Amount::new(450)), Amount::new(0))
+        );
+        // liquidity covers the minimum, but not the target
+        assert_eq!(
+            plan_top_up(Amount::new(50), Amount::new(210), min, target, fee),
+            (Some(Amount::new(200)), Amount::new(0))
+        );
+        // and not even the minimum
+        assert_eq!(
+            plan_top_up(Amount::new(50), Amount::new(40), min, target, fee),
+            (Some(Amount::new(30)), Amount::new(20))
+        );
+        // nothing to send, if liquidity can't pay the fee
+        assert_eq!(
+            plan_top_up(Amount::new(0), Amount::new(5), min, target, fee),
+            (None, Amount::new(100))
+        );
+    }
+}
@@ -0,0 +1,287 @@
+use std::sync::Arc;
+
+use super::error::*;
+use super::system::SystemService;
+use super::ServiceFuture;
+use config::{Config, LiquidityTarget};
+use models::*;
+use prelude::*;
+use repos::{DbExecutor, Isolation, TransactionsRepo};
+use utils::log_and_capture_error;
+
+/// Result of rebalancing liquidity account of one currency
+#[derive(Debug, Clone)]
+pub struct LiquidityRebalance {
+    pub currency: Currency,
+    /// Balance of liquidity account before rebalancing
+    pub balance: Amount,
+    /// Transfer between liquidity and fees accounts, if the balance was out of the target range
+    pub transaction: Option<Transaction>,
+    /// Missing to the lower bound of the target after the transfer, has to be topped up externally
+    pub shortage: Amount,
+}
+
+pub trait LiquidityService: Send + Sync + 'static {
+    /// Brings balances of liquidity accounts out of their target ranges to the middle of the ranges
+    /// by transfers from or to fees accounts. Shortage that fees accounts can't cover is reported.
+    /// Currencies are rebalanced independently, the ones that failed are logged and left out of the result
+    fn rebalance(&self) -> ServiceFuture<Vec<LiquidityRebalance>>;
+}
+
+#[derive(Clone)]
+pub struct LiquidityServiceImpl<E: DbExecutor> {
+    config: Arc<Config>,
+    transactions_repo: Arc<TransactionsRepo>,
+    system_service: Arc<SystemService>,
+    db_executor: E,
+}
+
+impl<E: DbExecutor> LiquidityServiceImpl<E> {
+    pub fn new(config: Arc<Config>, transactions_repo: Arc<TransactionsRepo>, system_service: Arc<SystemService>, db_executor: E) -> Self {
+        Self {
+            config,
+            transactions_repo,
+            system_service,
+            db_executor,
+        }
+    }
+
+    fn rebalance_currency(&self, target: &LiquidityTarget) -> Result<LiquidityRebalance, Error> {
+        let currency = target.currency;
+        let system_user_id = self.config.system.system_user_id;
+        let liquidity_account = self
+            .system_service
+            .get_system_liquidity_account(currency)
+            .map_err(ectx!(try ErrorKind::Internal => currency))?;
+        let fees_account = self
+            .system_service
+            .get_system_fees_account(currency)
+            .map_err(ectx!(try ErrorKind::Internal => currency))?;
+        let accounts = [liquidity_account.clone(), fees_account.clone()];
+        let balances = self
+            .transactions_repo
+            .get_accounts_balance(system_user_id, &accounts)
+            .map_err(ectx!(try convert => system_user_id, accounts))?;
+        let (balance, fees_balance) = (balances[0].balance, balances[1].balance);
+        let min = Amount::from_super_unit(currency, target.min);
+        let max = Amount::from_super_unit(currency, target.max);
+        let (transfer, shortage) = plan_rebalance(balance, fees_balance, min, max);
+        let transaction = match transfer {
+            Some(LiquidityTransfer::TopUp(value)) => Some(self.transfer(&fees_account, &liquidity_account, value)?),
+            Some(LiquidityTransfer::Drain(value)) => Some(self.transfer(&liquidity_account, &fees_account, value)?),
+            None => None,
+        };
+        Ok(LiquidityRebalance {
+            currency,
+            balance,
+            transaction,
+            shortage,
+        })
+    }
+
+    fn transfer(&self, dr_account: &Account, cr_account: &Account, value: Amount) -> Result<Transaction, Error> {
+        let id = TransactionId::generate();
+        let new_transaction = NewTransaction {
+            id,
+            gid: id,
+            user_id: self.config.system.system_user_id,
+            dr_account_id: dr_account.id,
+            cr_account_id: cr_account.id,
+            currency: dr_account.currency,
+            value,
+            status: TransactionStatus::Done,
+            blockchain_tx_id: None,
+            kind: TransactionKind::Internal,
+            group_kind: TransactionGroupKind::Internal,
+            related_tx: None,
+            meta: None,
+        };
+        let new_transaction_clone = new_transaction.clone();
+        let transaction = self
+            .transactions_repo
+            .create(new_transaction.clone())
+            .map_err(ectx!(try convert => new_transaction_clone))?;
+        // balance might have been changed by concurrent transaction after it was read
+        self.transactions_repo
+            .assert_non_negative_balances(&[dr_account.id])
+            .map_err(ectx!(try convert => new_transaction))?;
+        Ok(transaction)
+    }
+}
+
+impl<E: DbExecutor> LiquidityService for LiquidityServiceImpl<E> {
+    fn rebalance(&self) -> ServiceFuture<Vec<LiquidityRebalance>> {
+        let self_clone = self.clone();
+        let targets = self.config.liquidity_rebalancing.targets.clone();
+        Box::new(
+            futures::stream::iter_ok(targets)
+                .and_then(move |target| {
+                    let self_clone2 = self_clone.clone();
+                    let currency = target.currency;
+                    self_clone
+                        .db_executor
+                        .execute_transaction_with_retry(Isolation::Serializable, move || self_clone2.rebalance_currency(&target))
+                        .then(move |res| -> Result<Option<LiquidityRebalance>, Error> {
+                            match res {
+                                Ok(rebalance) => Ok(Some(rebalance)),
+                                Err(e) => {
+                                    error!("Failed to rebalance liquidity account of {}", currency);
+                                    log_and_capture_error(e);
+                                    Ok(None)
+                                }
+                            }
+                        })
+                })
+                .filter_map(|rebalance| rebalance)
+                .map(|rebalance| {
+                    if rebalance.shortage > Amount::new(0) {
+                        let currency = rebalance.currency;
+                        let shortage = rebalance.shortage.to_super_unit_string(currency);
+                        let e: Error = ectx!(err ErrorContext::LiquidityShortage, ErrorKind::Internal => currency, shortage);
+                        log_and_capture_error(e);
+                    }
+                    rebalance
+                })
+                .collect(),
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LiquidityTransfer {
+    /// From fees account to liquidity one
+    TopUp(Amount),
+    /// From liquidity account to fees one
+    Drain(Amount),
+}
+
+/// Transfer that brings `balance` to the middle of `min` and `max`, as far as fees balance allows for top up,
+/// and the rest missing to `min`
+fn plan_rebalance(balance: Amount, fees_balance: Amount, min: Amount, max: Amount) -> (Option<LiquidityTransfer>, Amount) {
+    let middle = Amount::new(min.raw() + (max.raw() - min.raw()) / 2);
+    if balance < min {
+        let needed = Amount::new(middle.raw() - balance.raw());
+        let value = if needed < fees_balance { needed } else { fees_balance };
+        let shortage = Amount::new(min.raw().saturating_sub(balance.raw() + value.raw()));
+        let transfer = if value > Amount::new(0) {
+            Some(LiquidityTransfer::TopUp(value))
+        } else {
+            None
+        };
+        (transfer, shortage)
+    } else if balance > max {
+        (
+            Some(LiquidityTransfer::Drain(Amount::new(balance.raw() - middle.raw()))),
+            Amount::new(0),
+        )
+    } else {
+        (None, Amount::new(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_core::reactor::Core;
+
+    use super::*;
+    use repos::{AccountsRepo, AccountsRepoMock, DbExecutorMock, TransactionsRepoMock};
+    use services::SystemServiceImpl;
+
+    #[test]
+    fn test_rebalance() {
+        let mut core = Core::new().unwrap();
+        let mut config = Config::new().unwrap();
+        // there are no system accounts of btc, so it fails
+        config.liquidity_rebalancing.targets = vec![
+            LiquidityTarget {
+                currency: Currency::Btc,
+                min: 1.0,
+                max: 3.0,
+            },
+            LiquidityTarget {
+                currency: Currency::Eth,
+                min: 1.0,
+                max: 3.0,
+            },
+        ];
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let mut system_accounts = vec![];
+        for role in &[SystemAccountKind::Liquidity, SystemAccountKind::Fees] {
+            let account = accounts_repo
+                .create(NewAccount {
+                    currency: Currency::Eth,
+                    kind: AccountKind::Cr,
+                    ..Default::default()
+                })
+                .unwrap();
+            accounts_repo.set_system_role(account.id, Some(*role)).unwrap();
+            system_accounts.push(account);
+        }
+        let (liquidity_account, fees_account) = (system_accounts[0].clone(), system_accounts[1].clone());
+        transactions_repo
+            .create(NewTransaction {
+                cr_account_id: fees_account.id,
+                currency: Currency::Eth,
+                value: Amount::from_super_unit(Currency::Eth, 10.0),
+                ..Default::default()
+            })
+            .unwrap();
+        let service = LiquidityServiceImpl::new(
+            Arc::new(config),
+            transactions_repo.clone(),
+            Arc::new(SystemServiceImpl::new(accounts_repo)),
+            DbExecutorMock::default(),
+        );
+
+        let rebalances = core.run(service.rebalance()).unwrap();
+        assert_eq!(rebalances.len(), 1);
+        assert_eq!(rebalances[0].currency, Currency::Eth);
+        assert_eq!(rebalances[0].balance, Amount::new(0));
+        assert_eq!(rebalances[0].shortage, Amount::new(0));
+        let transaction = rebalances[0].transaction.clone().unwrap();
+        assert_eq!(transaction.dr_account_id, fees_account.id);
+        assert_eq!(transaction.cr_account_id, liquidity_account.id);
+        assert_eq!(
+            transactions_repo
+                .get_account_balance(liquidity_account.id, AccountKind::Cr)
+                .unwrap(),
+            Amount::from_super_unit(Currency::Eth, 2.0)
+        );
+    }
+
+    #[test]
+    fn test_plan_rebalance() {
+        let (min, max) = (Amount::new(100), Amount::new(200));
+        // within range
+        assert_eq!(
+            plan_rebalance(Amount::new(150), Amount::new(1000), min, max),
+            (None, Amount::new(0))
+        );
+        assert_eq!(
+            plan_rebalance(Amount::new(100), Amount::new(1000), min, max),
+            (None, Amount::new(0))
+        );
+        // topped up to the middle
+        assert_eq!(
+            plan_rebalance(Amount::new(50), Amount::new(1000), min, max),
+            (Some(LiquidityTransfer::TopUp(Amount::new(100))), Amount::new(0))
+        );
+        // fees are not enough to reach the middle, but enough for the lower bound
+        assert_eq!(
+            plan_rebalance(Amount::new(50), Amount::new(70), min, max),
+            (Some(LiquidityTransfer::TopUp(Amount::new(70))), Amount::new(0))
+        );
+        // and not enough for the lower bound
+        assert_eq!(
+            plan_rebalance(Amount::new(50), Amount::new(20), min, max),
+            (Some(LiquidityTransfer::TopUp(Amount::new(20))), Amount::new(30))
+        );
+        assert_eq!(plan_rebalance(Amount::new(50), Amount::new(0), min, max), (None, Amount::new(50)));
+        // excess is drained to the middle
+        assert_eq!(
+            plan_rebalance(Amount::new(260), Amount::new(0), min, max),
+            (Some(LiquidityTransfer::Drain(Amount::new(110))), Amount::new(0))
+        );
+    }
+}
@@ -1,18 +1,30 @@
 use std::collections::HashMap;
 use std::sync::Arc;
 
+use futures::future;
+
 use client::BlockchainClient;
 use config::Config;
 use models::*;
 use prelude::*;
-use repos::{AccountsRepo, DbExecutor, Isolation, PendingBlockchainTransactionsRepo, StrangeBlockchainTransactionsRepo, TransactionsRepo};
+use rabbit::RabbitStats;
+use repos::{
+    AccountsRepo, DbExecutor, Isolation, KeyValuesRepo, KeyValuesRepoExt, MonitoredPool, PendingBlockchainTransactionsRepo, QueryStats,
+    StrangeBlockchainTransactionsRepo, TransactionsRepo,
+};
 
+use super::balance_alerts::{find_system_account, get_system_dr_balances, low_system_balances};
 use super::error::*;
 
 const BLOCKCHAIN_BALANCES_CONCURRENCY: usize = 20;
 
 pub trait MetricsService: Send + Sync + 'static {
     fn get_metrics(&self) -> Box<Future<Item = Metrics, Error = Error> + Send>;
+    /// State of db pools and rabbit connection, without querying the db
+    fn get_health(&self) -> Box<Future<Item = Health, Error = Error> + Send>;
+    /// Compares ledger balances of blockchain addresses with the blockchain ones, optionally for one currency only.
+    /// The outcome is kept by currency, so that it's shown with system accounts
+    fn reconcile(&self, currency: Option<Currency>) -> Box<Future<Item = ReconciliationReport, Error = Error> + Send>;
 }
 
 #[derive(Clone)]
@@ -22,8 +34,12 @@ pub struct MetricsServiceImpl<E: DbExecutor> {
     transactions_repo: Arc<TransactionsRepo>,
     pending_blockchain_transactions_repo: Arc<PendingBlockchainTransactionsRepo>,
     strange_blockchain_transactions_repo: Arc<StrangeBlockchainTransactionsRepo>,
+    key_values_repo: Arc<KeyValuesRepo>,
     blockchain_client: Arc<BlockchainClient>,
     db_executor: E,
+    db_pools: Vec<MonitoredPool>,
+    query_stats: Arc<QueryStats>,
+    rabbit_stats: Arc<RabbitStats>,
 }
 
 impl<E: DbExecutor> MetricsServiceImpl<E> {
@@ -33,8 +49,12 @@ impl<E: DbExecutor> MetricsServiceImpl<E> {
         transactions_repo: Arc<TransactionsRepo>,
         pending_blockchain_transactions_repo: Arc<PendingBlockchainTransactionsRepo>,
         strange_blockchain_transactions_repo: Arc<StrangeBlockchainTransactionsRepo>,
+        key_values_repo: Arc<KeyValuesRepo>,
         db_executor: E,
         blockchain_client: Arc<BlockchainClient>,
+        db_pools: Vec<MonitoredPool>,
+        query_stats: Arc<QueryStats>,
+        rabbit_stats: Arc<RabbitStats>,
     ) -> Self {
         MetricsServiceImpl {
             config,
@@ -42,8 +62,12 @@ impl<E: DbExecutor> MetricsServiceImpl<E> {
             transactions_repo,
             pending_blockchain_transactions_repo,
             strange_blockchain_transactions_repo,
+            key_values_repo,
             blockchain_client,
             db_executor,
+            db_pools,
+            query_stats,
+            rabbit_stats,
         }
     }
 }
@@ -57,6 +81,7 @@ impl<E: DbExecutor> MetricsService for MetricsServiceImpl<E> {
                 .execute_transaction_with_isolation(Isolation::RepeatableRead, move || {
                     let mut metrics: Metrics = Default::default();
                     self_clone.update_counts(&mut metrics)?;
+                    self_clone.update_db_pools(&mut metrics);
                     let balances = self_clone
                         .transactions_repo
                         .get_blockchain_balances()
@@ -86,12 +111,92 @@ impl<E: DbExecutor> MetricsService for MetricsServiceImpl<E> {
                 }),
         )
     }
+
+    fn get_health(&self) -> Box<Future<Item = Health, Error = Error> + Send> {
+        let db_pools: HashMap<String, bool> = self
+            .db_pools
+            .iter()
+            .map(|pool| (pool.name.clone(), pool.metrics().connections > 0))
+            .collect();
+        let rabbit = self.rabbit_stats.metrics();
+        let healthy = rabbit.connected && db_pools.values().all(|healthy| *healthy);
+        Box::new(future::ok(Health {
+            healthy,
+            rabbit_connected: rabbit.connected,
+            rabbit_last_health_check_at: rabbit.last_health_check_at,
+            db_pools,
+        }))
+    }
+
+    fn reconcile(&self, currency: Option<Currency>) -> Box<Future<Item = ReconciliationReport, Error = Error> + Send> {
+        let self_clone = self.clone();
+        let self_2 = self.clone();
+        let key_values_repo = self.key_values_repo.clone();
+        let db_executor = self.db_executor.clone();
+        Box::new(
+            self.db_executor
+                .execute_transaction_with_isolation(Isolation::RepeatableRead, move || {
+                    let mut metrics: Metrics = Default::default();
+                    let balances = self_clone
+                        .transactions_repo
+                        .get_blockchain_balances()
+                        .map_err(ectx!(try ErrorKind::Internal))?
+                        .into_iter()
+                        .filter(|((_, balance_currency), _)| currency.map(|currency| currency == *balance_currency).unwrap_or(true))
+                        .collect();
+                    let reduced_balances = self_clone.update_negative_balances_and_reduce(&mut metrics, balances)?;
+                    Ok((metrics.negative_balances, reduced_balances))
+                })
+                .and_then(move |(negative_balances, reduced_balances)| {
+                    self_2
+                        .fetch_blockchain_balances(&reduced_balances)
+                        .map(move |blockchain_balances| ReconciliationReport {
+                            currency,
+                            created_at: ::chrono::Utc::now().naive_utc(),
+                            checked_addresses_count: reduced_balances.len() as u64,
+                            diverging_balances: get_diverging_balances(&reduced_balances, &blockchain_balances),
+                            negative_balances,
+                        })
+                })
+                .and_then(move |report| {
+                    db_executor.execute(move || {
+                        let currencies = match currency {
+                            Some(currency) => vec![currency],
+                            None => vec![Currency::Btc, Currency::Eth, Currency::Stq],
+                        };
+                        for currency in currencies {
+                            let status = ReconciliationStatus::from_report(&report, currency);
+                            key_values_repo
+                                .set_reconciliation_status(currency, status)
+                                .map_err(ectx!(try ErrorKind::Internal => currency))?;
+                        }
+                        Ok(report)
+                    })
+                }),
+        )
+    }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum SystemAccountKind {
-    Liquidity,
-    Fee,
+fn get_diverging_balances(
+    payments_system_balances: &HashMap<(BlockchainAddress, Currency), Amount>,
+    blockchain_balances: &HashMap<(BlockchainAddress, Currency), Amount>,
+) -> Vec<DivergingBalance> {
+    let mut diverging_blockchain_balances: Vec<DivergingBalance> = Vec::new();
+    for ((address, currency), payments_balance) in payments_system_balances {
+        let blockchain_balance = blockchain_balances
+            .get(&(address.clone(), *currency))
+            .cloned()
+            .unwrap_or(Amount::new(0));
+        if blockchain_balance != *payments_balance {
+            diverging_blockchain_balances.push(DivergingBalance {
+                address: address.clone(),
+                currency: *currency,
+                payments_system_value: payments_balance.to_super_unit(*currency),
+                blockchain_value: blockchain_balance.to_super_unit(*currency),
+            });
+        }
+    }
+    diverging_blockchain_balances
 }
 
 impl<E: DbExecutor> MetricsServiceImpl<E> {
@@ -134,21 +239,7 @@ impl<E: DbExecutor> MetricsServiceImpl<E> {
         }
         metrics.total_blockchain_balances = total_blockchain_balances;
 
-        let mut diverging_blockchain_balances: Vec<DivergingBalance> = Vec::new();
-        for ((address, currency), payments_balance) in payments_system_balances {
-            let blockchain_balance = blockchain_balances
-                .get(&(address.clone(), *currency))
-                .cloned()
-                .unwrap_or(Amount::new(0));
-            if blockchain_balance != *payments_balance {
-                diverging_blockchain_balances.push(DivergingBalance {
-                    address: address.clone(),
-                    currency: *currency,
-                    payments_system_value: payments_balance.to_super_unit(*currency),
-                    blockchain_value: blockchain_balance.to_super_unit(*currency),
-                });
-            }
-        }
+        let diverging_blockchain_balances = get_diverging_balances(payments_system_balances, blockchain_balances);
         metrics.diverging_blockchain_balances_count = diverging_blockchain_balances.len() as u64;
         metrics.diverging_blockchain_balances = diverging_blockchain_balances;
 
@@ -209,11 +300,11 @@ impl<E: DbExecutor> MetricsServiceImpl<E> {
         metrics: &mut Metrics,
         blockchain_balances: &HashMap<(BlockchainAddress, Currency), Amount>,
     ) -> Result<(), Error> {
-        let account_id = self.config.system.eth_fees_account_id;
+        let (role, currency, kind) = (SystemAccountKind::Fees, Currency::Eth, AccountKind::Cr);
         let account = self
             .accounts_repo
-            .get(account_id)
-            .map_err(ectx!(try ErrorKind::Internal => account_id))?
+            .get_system_account(role, currency, kind)
+            .map_err(ectx!(try ErrorKind::Internal => role, currency, kind))?
             .ok_or(ectx!(try err ErrorContext::NoAccount, ErrorKind::NotFound))?;
 
         metrics.eth_fee_account_blockchain_balance = blockchain_balances
@@ -229,20 +320,43 @@ impl<E: DbExecutor> MetricsServiceImpl<E> {
             .transactions_repo
             .get_system_balances()
             .map_err(ectx!(try ErrorKind::Internal))?;
+        let all_system_accounts = self.accounts_repo.list_system_accounts().map_err(ectx!(try ErrorKind::Internal))?;
+        let dr_balances = get_system_dr_balances(&*self.transactions_repo, self.config.system.system_user_id, &all_system_accounts)?;
+        let system_accounts: Vec<Account> = all_system_accounts
+            .iter()
+            .filter(|account| account.kind == AccountKind::Cr)
+            .cloned()
+            .collect();
         let mut liquidity_balances: HashMap<Currency, f64> = HashMap::new();
         let mut fees_balances: HashMap<Currency, f64> = HashMap::new();
         for currency in [Currency::Btc, Currency::Stq, Currency::Eth].into_iter() {
             liquidity_balances.insert(
                 *currency,
-                self.extract_balance(SystemAccountKind::Liquidity, *currency, metrics, &balances)?,
+                self.extract_balance(SystemAccountKind::Liquidity, *currency, metrics, &system_accounts, &balances),
             );
             fees_balances.insert(
                 *currency,
-                self.extract_balance(SystemAccountKind::Fee, *currency, metrics, &balances)?,
+                self.extract_balance(SystemAccountKind::Fees, *currency, metrics, &system_accounts, &balances),
             );
         }
         metrics.fees_balances = fees_balances;
         metrics.liquidity_balances = liquidity_balances;
+        metrics.low_system_balances = low_system_balances(&self.config, &all_system_accounts, &balances, &dr_balances);
+        metrics.low_system_balances_count = metrics.low_system_balances.len() as u64;
+        metrics.exchange_margins = self
+            .transactions_repo
+            .get_exchange_margins()
+            .map_err(ectx!(try ErrorKind::Internal))?
+            .into_iter()
+            .map(|(currency, margin)| (currency, margin.to_super_unit(currency)))
+            .collect();
+        metrics.fee_margins = self
+            .transactions_repo
+            .get_fee_margins()
+            .map_err(ectx!(try ErrorKind::Internal))?
+            .into_iter()
+            .map(|(currency, (charged, paid))| (currency, charged.to_super_unit(currency) - paid.to_super_unit(currency)))
+            .collect();
         Ok(())
     }
 
@@ -251,25 +365,18 @@ impl<E: DbExecutor> MetricsServiceImpl<E> {
         kind: SystemAccountKind,
         currency: Currency,
         metrics: &mut Metrics,
+        system_accounts: &[Account],
         balances: &HashMap<AccountId, (Amount, Amount)>,
-    ) -> Result<f64, Error> {
-        let account_id = match (kind, currency) {
-            (SystemAccountKind::Fee, Currency::Btc) => self.config.system.btc_fees_account_id,
-            (SystemAccountKind::Fee, Currency::Eth) => self.config.system.eth_fees_account_id,
-            (SystemAccountKind::Fee, Currency::Stq) => self.config.system.stq_fees_account_id,
-            (SystemAccountKind::Liquidity, Currency::Btc) => self.config.system.btc_liquidity_account_id,
-            (SystemAccountKind::Liquidity, Currency::Eth) => self.config.system.eth_liquidity_account_id,
-            (SystemAccountKind::Liquidity, Currency::Stq) => self.config.system.stq_liquidity_account_id,
+    ) -> f64 {
+        // currency might have no system account of this kind yet
+        let account = match find_system_account(system_accounts, kind, currency) {
+            Some(account) => account.clone(),
+            None => return 0.0,
         };
-        let balance_pair = balances.get(&account_id).cloned().unwrap_or((Amount::new(0), Amount::new(0)));
+        let balance_pair = balances.get(&account.id).cloned().unwrap_or((Amount::new(0), Amount::new(0)));
         match balance_pair.0.checked_sub(balance_pair.1) {
-            Some(balance) => Ok(balance.to_super_unit(currency)),
+            Some(balance) => balance.to_super_unit(currency),
             None => {
-                let account = self
-                    .accounts_repo
-                    .get(account_id)
-                    .map_err(ectx!(try ErrorKind::Internal => account_id))?
-                    .ok_or(ectx!(try err ErrorContext::NoAccount, ErrorKind::NotFound))?;
                 if metrics
                     .negative_balances
                     .iter()
@@ -282,7 +389,7 @@ impl<E: DbExecutor> MetricsServiceImpl<E> {
                         value: balance_pair.1.checked_sub(balance_pair.0).unwrap(),
                     });
                 }
-                Ok(0.0)
+                0.0
             }
         }
     }
@@ -295,6 +402,13 @@ impl<E: DbExecutor> MetricsServiceImpl<E> {
         metrics.limits = limits;
     }
 
+    // connection used for collecting metrics is counted as in use
+    fn update_db_pools(&self, metrics: &mut Metrics) {
+        metrics.db_pools = self.db_pools.iter().map(|pool| (pool.name.clone(), pool.metrics())).collect();
+        metrics.db_queries = self.query_stats.metrics();
+        metrics.rabbit = self.rabbit_stats.metrics();
+    }
+
     fn update_negative_balances_and_reduce(
         &self,
         metrics: &mut Metrics,
@@ -319,3 +433,59 @@ impl<E: DbExecutor> MetricsServiceImpl<E> {
         Ok(res)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use client::BlockchainClientMock;
+    use repos::{
+        AccountsRepoMock, DbExecutorMock, KeyValuesRepoMock, PendingBlockchainTransactionsRepoMock, StrangeBlockchainTransactionsRepoMock,
+        TransactionsRepoMock,
+    };
+
+    fn create_metrics_service(db_pools: Vec<MonitoredPool>, rabbit_stats: Arc<RabbitStats>) -> MetricsServiceImpl<DbExecutorMock> {
+        let config = Config::new().unwrap();
+        let query_stats = Arc::new(QueryStats::new(&config.database));
+        MetricsServiceImpl::new(
+            Arc::new(config),
+            Arc::new(AccountsRepoMock::default()),
+            Arc::new(TransactionsRepoMock::default()),
+            Arc::new(PendingBlockchainTransactionsRepoMock::default()),
+            Arc::new(StrangeBlockchainTransactionsRepoMock::default()),
+            Arc::new(KeyValuesRepoMock::default()),
+            DbExecutorMock::default(),
+            Arc::new(BlockchainClientMock::default()),
+            db_pools,
+            query_stats,
+            rabbit_stats,
+        )
+    }
+
+    #[test]
+    fn test_get_health() {
+        let rabbit_stats = Arc::new(RabbitStats::default());
+        let service = create_metrics_service(vec![], rabbit_stats.clone());
+        let health = service.get_health().wait().unwrap();
+        assert!(!health.healthy);
+        assert!(!health.rabbit_connected);
+        assert_eq!(health.rabbit_last_health_check_at, None);
+
+        rabbit_stats.record_health_check(true);
+        let health = service.get_health().wait().unwrap();
+        assert!(health.healthy);
+        assert!(health.rabbit_connected);
+        assert!(health.rabbit_last_health_check_at.is_some());
+
+        // pool that can't open a connection makes the service unhealthy
+        let config = Config::new().unwrap();
+        let unreachable_pool = MonitoredPool::create_unchecked("replica", "postgres://localhost:1/transactions", &config.database);
+        let service = create_metrics_service(vec![unreachable_pool], rabbit_stats.clone());
+        let health = service.get_health().wait().unwrap();
+        assert!(!health.healthy);
+        assert_eq!(health.db_pools.get("replica"), Some(&false));
+
+        rabbit_stats.record_health_check(false);
+        let health = service.get_health().wait().unwrap();
+        assert!(!health.rabbit_connected);
+    }
+}
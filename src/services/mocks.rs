@@ -116,4 +116,9 @@ impl SystemService for SystemServiceMock {
         let acc = data.get(acc_id).unwrap();
         Ok(acc.clone())
     }
+
+    fn list_system_accounts(&self) -> Result<Vec<Account>, Error> {
+        let data = self.data.lock().unwrap();
+        Ok(data.values().filter(|acc| acc.kind == AccountKind::Cr).cloned().collect())
+    }
 }
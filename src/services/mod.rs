@@ -1,27 +1,60 @@
 mod accounts;
 mod auth;
+mod balance_alerts;
+mod cold_storage;
+mod confirmations;
 mod error;
 mod exchange;
+mod exchange_pairs;
 mod fee;
+mod fees_top_up;
+mod liquidity;
 mod metrics;
 #[cfg(test)]
 mod mocks;
+mod pending_deposits;
 mod rabbit;
+mod rate_locks;
+mod rates;
+mod repair;
+mod seen_hashes;
+mod small_deposits;
 mod system;
+mod system_accounts;
+mod transaction_limits;
 mod transactions;
 mod users;
+mod wallet;
+mod withdrawal_addresses;
 
 pub use self::accounts::*;
 pub use self::auth::*;
+pub use self::balance_alerts::*;
+pub use self::cold_storage::*;
+pub use self::confirmations::*;
 pub use self::error::*;
 pub use self::exchange::*;
+pub use self::exchange_pairs::*;
 pub use self::fee::*;
+pub use self::fees_top_up::*;
+pub use self::liquidity::*;
 pub use self::metrics::*;
 #[cfg(test)]
 pub use self::mocks::*;
+pub use self::pending_deposits::*;
 pub use self::rabbit::*;
+pub use self::rate_locks::*;
+pub use self::rates::*;
+pub use self::repair::*;
+pub use self::seen_hashes::*;
+pub use self::small_deposits::*;
+pub use self::system::*;
+pub use self::system_accounts::*;
+pub use self::transaction_limits::*;
 pub use self::transactions::*;
 pub use self::users::*;
+pub use self::wallet::*;
+pub use self::withdrawal_addresses::*;
 
 use prelude::*;
 
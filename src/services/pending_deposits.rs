@@ -0,0 +1,144 @@
+use std::sync::Arc;
+
+use chrono::{Duration, Utc};
+
+use super::auth::AuthService;
+use super::error::*;
+use super::ServiceFuture;
+use config::PendingDepositsRetention;
+use models::*;
+use prelude::*;
+use repos::{DbExecutor, PendingDepositsRepo};
+
+pub trait PendingDepositsService: Send + Sync + 'static {
+    /// Deposits seen in blockchain that don't have enough confirmations yet, as pending transactions
+    fn get_pending_deposits_for_user(&self, token: AuthenticationToken, user_id: UserId) -> ServiceFuture<Vec<TransactionOut>>;
+    /// Deletes deposits that got no new confirmations within retention, they were dropped from blockchain.
+    /// Called by scheduler, so there's no token
+    fn prune_stale(&self) -> ServiceFuture<Vec<PendingDeposit>>;
+}
+
+#[derive(Clone)]
+pub struct PendingDepositsServiceImpl<E: DbExecutor> {
+    auth_service: Arc<dyn AuthService>,
+    pending_deposits_repo: Arc<dyn PendingDepositsRepo>,
+    retention: PendingDepositsRetention,
+    db_executor: E,
+}
+
+impl<E: DbExecutor> PendingDepositsServiceImpl<E> {
+    pub fn new(
+        auth_service: Arc<dyn AuthService>,
+        pending_deposits_repo: Arc<dyn PendingDepositsRepo>,
+        retention: PendingDepositsRetention,
+        db_executor: E,
+    ) -> Self {
+        Self {
+            auth_service,
+            pending_deposits_repo,
+            retention,
+            db_executor,
+        }
+    }
+}
+
+impl<E: DbExecutor> PendingDepositsService for PendingDepositsServiceImpl<E> {
+    fn get_pending_deposits_for_user(&self, token: AuthenticationToken, user_id: UserId) -> ServiceFuture<Vec<TransactionOut>> {
+        let pending_deposits_repo = self.pending_deposits_repo.clone();
+        let db_executor = self.db_executor.clone();
+        Box::new(self.auth_service.authenticate(token).and_then(move |user| {
+            db_executor.execute(move || {
+                if user_id != user.id {
+                    return Err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id));
+                }
+                let pending_deposits = pending_deposits_repo
+                    .list_for_user(user_id)
+                    .map_err(ectx!(try convert => user_id))?;
+                Ok(pending_deposits.into_iter().map(From::from).collect())
+            })
+        }))
+    }
+
+    fn prune_stale(&self) -> ServiceFuture<Vec<PendingDeposit>> {
+        let pending_deposits_repo = self.pending_deposits_repo.clone();
+        let updated_before = Utc::now().naive_utc() - Duration::hours(self.retention.keep_hours as i64);
+        Box::new(self.db_executor.execute(move || {
+            pending_deposits_repo
+                .delete_stale(updated_before)
+                .map_err(ectx!(convert => updated_before))
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::Config;
+    use repos::*;
+    use services::*;
+    use tokio_core::reactor::Core;
+
+    #[test]
+    fn test_pending_deposits_for_user() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let pending_deposits_repo = Arc::new(PendingDepositsRepoMock::default());
+        let pending_deposit = pending_deposits_repo
+            .upsert(NewPendingDeposit {
+                user_id,
+                confirmations: 1,
+                required_confirmations: 3,
+                ..Default::default()
+            })
+            .unwrap();
+        let service = PendingDepositsServiceImpl::new(
+            Arc::new(AuthServiceMock::new(vec![(token.clone(), user_id)])),
+            pending_deposits_repo,
+            Config::new().unwrap().pending_deposits_retention,
+            DbExecutorMock::default(),
+        );
+
+        let txs = core.run(service.get_pending_deposits_for_user(token.clone(), user_id)).unwrap();
+        assert_eq!(txs.len(), 1);
+        assert_eq!(txs[0].id, pending_deposit.id);
+        assert_eq!(txs[0].status, TransactionStatus::Pending);
+        assert_eq!(txs[0].meta, json!({ "confirmations": 1, "requiredConfirmations": 3 }));
+
+        assert!(core.run(service.get_pending_deposits_for_user(token, UserId::generate())).is_err());
+    }
+
+    #[test]
+    fn test_prune_stale_pending_deposits() {
+        let mut core = Core::new().unwrap();
+        let pending_deposits_repo = Arc::new(PendingDepositsRepoMock::default());
+        let pending_deposit = pending_deposits_repo.upsert(NewPendingDeposit::default()).unwrap();
+        let retention = PendingDepositsRetention {
+            keep_hours: 1,
+            prune_interval_secs: 1,
+        };
+        let service = PendingDepositsServiceImpl::new(
+            Arc::new(AuthServiceMock::new(vec![])),
+            pending_deposits_repo.clone(),
+            retention.clone(),
+            DbExecutorMock::default(),
+        );
+        // deposit that is still getting confirmations is kept
+        assert!(core.run(service.prune_stale()).unwrap().is_empty());
+        assert_eq!(pending_deposits_repo.list_for_user(pending_deposit.user_id).unwrap().len(), 1);
+
+        let service = PendingDepositsServiceImpl::new(
+            Arc::new(AuthServiceMock::new(vec![])),
+            pending_deposits_repo.clone(),
+            PendingDepositsRetention {
+                keep_hours: 0,
+                ..retention
+            },
+            DbExecutorMock::default(),
+        );
+        let pruned = core.run(service.prune_stale()).unwrap();
+        assert_eq!(pruned.len(), 1);
+        assert_eq!(pruned[0].id, pending_deposit.id);
+        assert!(pending_deposits_repo.list_for_user(pending_deposit.user_id).unwrap().is_empty());
+    }
+}
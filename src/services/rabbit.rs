@@ -5,19 +5,24 @@ use chrono::{Duration as ChronoDuration, Utc};
 use futures::future::{self, Either};
 
 use super::error::*;
+use super::rates::{RatesService, RatesServiceImpl};
 use super::system::{SystemService, SystemServiceImpl};
 use super::transactions::converter::{ConverterService, ConverterServiceImpl};
+use super::transactions::TransactionsService;
 use client::{BlockchainClient, KeysClient};
-use config::Config;
+use config::{Config, SharedConfig};
 use models::*;
 use prelude::*;
 use rabbit::TransactionPublisher;
 use repos::{
-    AccountsRepo, BlockchainTransactionsRepo, DbExecutor, Isolation, KeyValuesRepo, PendingBlockchainTransactionsRepo, SeenHashesRepo,
+    is_serialization_failure, AccountsRepo, BlockchainTransactionsRepo, DbExecutor, Isolation, KeyValuesRepo, KeyValuesRepoExt,
+    PendingBlockchainTransactionsRepo, PendingDepositsRepo, QuarantinedMessagesRepo, SeenHashesRepo, SmallDepositsRepo,
     StrangeBlockchainTransactionsRepo, TransactionsRepo,
 };
-use serde_json;
-use utils::{log_and_capture_error, log_error};
+use request_id;
+use serde_json::{self, Value};
+use utils::{format_error, log_and_capture_error, log_error};
+use uuid::Uuid;
 
 // it's ok to have this low approval threshold, the attack is still not
 // feasible, as an attacker need to spend at least 20000 gas per stq transfer
@@ -28,38 +33,46 @@ const STQ_ALLOWANCE: u128 = 100_000_000_000_000_000_000_000_000_000;
 
 #[derive(Clone)]
 pub struct BlockchainFetcher<E: DbExecutor> {
-    config: Arc<Config>,
+    config: SharedConfig,
     transactions_repo: Arc<TransactionsRepo>,
     accounts_repo: Arc<AccountsRepo>,
     seen_hashes_repo: Arc<SeenHashesRepo>,
     blockchain_transactions_repo: Arc<BlockchainTransactionsRepo>,
     strange_blockchain_transactions_repo: Arc<StrangeBlockchainTransactionsRepo>,
+    small_deposits_repo: Arc<SmallDepositsRepo>,
+    pending_deposits_repo: Arc<PendingDepositsRepo>,
     pending_blockchain_transactions_repo: Arc<PendingBlockchainTransactionsRepo>,
     key_values_repo: Arc<KeyValuesRepo>,
+    quarantined_messages_repo: Arc<QuarantinedMessagesRepo>,
     system_service: Arc<SystemService>,
     converter_service: Arc<ConverterService>,
     blockchain_client: Arc<BlockchainClient>,
     keys_client: Arc<KeysClient>,
     db_executor: E,
     publisher: Arc<dyn TransactionPublisher>,
+    transactions_service: Arc<dyn TransactionsService>,
 }
 
 impl<E: DbExecutor> BlockchainFetcher<E> {
     pub fn new(
-        config: Arc<Config>,
+        config: SharedConfig,
         transactions_repo: Arc<TransactionsRepo>,
         accounts_repo: Arc<AccountsRepo>,
         seen_hashes_repo: Arc<SeenHashesRepo>,
         blockchain_transactions_repo: Arc<BlockchainTransactionsRepo>,
         strange_blockchain_transactions_repo: Arc<StrangeBlockchainTransactionsRepo>,
+        small_deposits_repo: Arc<SmallDepositsRepo>,
+        pending_deposits_repo: Arc<PendingDepositsRepo>,
         pending_blockchain_transactions_repo: Arc<PendingBlockchainTransactionsRepo>,
         key_values_repo: Arc<KeyValuesRepo>,
+        quarantined_messages_repo: Arc<QuarantinedMessagesRepo>,
         blockchain_client: Arc<BlockchainClient>,
         keys_client: Arc<KeysClient>,
         db_executor: E,
         publisher: Arc<dyn TransactionPublisher>,
+        transactions_service: Arc<dyn TransactionsService>,
     ) -> Self {
-        let system_service = Arc::new(SystemServiceImpl::new(accounts_repo.clone(), config.clone()));
+        let system_service = Arc::new(SystemServiceImpl::new(accounts_repo.clone()));
         let converter_service = Arc::new(ConverterServiceImpl::new(
             accounts_repo.clone(),
             pending_blockchain_transactions_repo.clone(),
@@ -73,264 +86,240 @@ impl<E: DbExecutor> BlockchainFetcher<E> {
             seen_hashes_repo,
             blockchain_transactions_repo,
             strange_blockchain_transactions_repo,
+            small_deposits_repo,
+            pending_deposits_repo,
             pending_blockchain_transactions_repo,
             key_values_repo,
+            quarantined_messages_repo,
             system_service,
             converter_service,
             blockchain_client,
             keys_client,
             db_executor,
             publisher,
+            transactions_service,
         }
     }
 }
 
-#[derive(Copy, Clone, Eq, PartialEq, Debug, Fail)]
-#[allow(dead_code)]
-pub enum InvariantViolation {
-    #[fail(display = "blockchain transaction invariant violation - unexpected number of addresses")]
-    WithdrawalAdressesCount,
-    #[fail(display = "blockchain transaction invariant violation - address of transaction not found in our database")]
-    WithdrawalAdressesNotFound,
-    #[fail(display = "blockchain transaction invariant violation - transaction referred to non-existing account")]
-    NotExistingAccount,
-    #[fail(display = "blockchain transaction invariant violation - withdrawal happened to internal account, which shouldn't be the case")]
-    WithdrawalAdressesInternal,
-    #[fail(
-        display = "blockchain transaction invariant violation - withdrawal transaction should be in pending state when blockchain tx arrives"
-    )]
-    WithdrawalNotPendingAddress,
-    #[fail(display = "blockchain transaction invariant violation - withdrawal blockchain tx doesn't have corresponding pending part")]
-    WithdrawalNoPendingTx,
-    #[fail(display = "blockchain transaction invariant violation - withdrawal blockchain tx value is not equal to pending tx value")]
-    WithdrawalValue,
-    #[fail(display = "blockchain transaction invariant violation - deposit arrived from internal address")]
-    DepositAddressInternal,
+/// Results of handling blockchain transactions, that are published after db transaction is committed
+#[derive(Debug, Default)]
+struct HandledTransactions {
+    transactions: Vec<Transaction>,
+    /// Accounts that have to be approved for stq transfers
+    need_approve: Vec<Account>,
+    pending_deposits: Vec<PendingDeposit>,
+    deposit_events: Vec<DepositEvent>,
+}
+
+impl HandledTransactions {
+    fn extend(&mut self, other: HandledTransactions) {
+        self.transactions.extend(other.transactions);
+        self.need_approve.extend(other.need_approve);
+        self.pending_deposits.extend(other.pending_deposits);
+        self.deposit_events.extend(other.deposit_events);
+    }
 }
 
 impl<E: DbExecutor> BlockchainFetcher<E> {
-    pub fn handle_message(&self, data: Vec<u8>) -> impl Future<Item = (), Error = Error> + Send {
+    /// Keeps the message, that failed to be handled `attempts` times, so that it can be replayed later
+    pub fn quarantine_message(
+        &self,
+        currency: Currency,
+        data: Vec<u8>,
+        error: String,
+        attempts: u32,
+        request_id: Option<String>,
+    ) -> impl Future<Item = (), Error = Error> + Send {
+        let quarantined_messages_repo = self.quarantined_messages_repo.clone();
+        let new_message = NewQuarantinedMessage {
+            id: Uuid::new_v4(),
+            currency,
+            payload: data,
+            error,
+            attempts: attempts as i32,
+            request_id,
+        };
+        self.db_executor.execute(move || -> Result<(), Error> {
+            let message = quarantined_messages_repo.create(new_message)?;
+            warn!("Message quarantined after {} attempts with id {}", message.attempts, message.id);
+            Ok(())
+        })
+    }
+
+    /// Handles blockchain transaction from the message, see `parse_transaction`
+    pub fn handle_message(&self, tx: BlockchainTransaction) -> impl Future<Item = (), Error = Error> + Send {
         let db_executor = self.db_executor.clone();
         let converter = self.converter_service.clone();
         let publisher = self.publisher.clone();
-        let self_clone = self.clone();
-        parse_transaction(data)
-            .into_future()
-            .and_then(move |tx| self_clone.handle_transaction(&tx))
-            .and_then(move |txs| {
-                if !txs.is_empty() {
-                    info!("Sending txs: {:?}", txs);
-                    Either::A(
-                        db_executor
-                            .execute(move || converter.convert_transaction(txs))
-                            .and_then(move |tx_out| {
-                                info!("Sending tx after conversion: {:?}", tx_out);
-                                publisher
-                                    .publish(tx_out.clone())
-                                    .map_err(ectx!(ErrorSource::Lapin, ErrorKind::Internal => tx_out))
-                                    .then(|r: Result<(), Error>| match r {
-                                        Err(e) => {
-                                            log_error(&e);
-                                            Ok(())
-                                        }
-                                        Ok(_) => Ok(()),
-                                    })
-                            }),
-                    )
-                } else {
-                    Either::B(future::ok(()))
+        let self_clone2 = self.clone();
+        // every transfer log is handled as a separate transaction in ledger, all of them in one db transaction.
+        // A log that fails is quarantined, the other ones are handled, see `handle_transactions`
+        self.handle_transactions(tx.split_logs()).and_then(move |txs| {
+            if !txs.is_empty() {
+                info!("Sending txs: {:?}", txs);
+                let deposits = txs.clone();
+                Either::A(
+                    db_executor
+                        .execute(move || converter.convert_transaction(txs))
+                        .and_then(move |tx_out| {
+                            info!("Sending tx after conversion: {:?}", tx_out);
+                            publisher
+                                .publish(tx_out.clone())
+                                .map_err(ectx!(ErrorSource::Lapin, ErrorKind::Internal => tx_out))
+                                .then(|r: Result<(), Error>| match r {
+                                    Err(e) => {
+                                        log_error(&e);
+                                        Ok(())
+                                    }
+                                    Ok(_) => Ok(()),
+                                })
+                        })
+                        .and_then(move |_| self_clone2.auto_convert_deposits(deposits)),
+                )
+            } else {
+                Either::B(future::ok(()))
+            }
+        })
+    }
+
+    // Deposit is already credited and its hash is seen, so failed conversion is not retried
+    // with the message, the funds just stay in the account
+    fn auto_convert_deposits(&self, transactions: Vec<Transaction>) -> impl Future<Item = (), Error = Error> + Send {
+        let transactions_service = self.transactions_service.clone();
+        let deposits: Vec<_> = transactions.into_iter().filter(|tx| tx.kind == TransactionKind::Deposit).collect();
+        futures::stream::iter_ok(deposits).for_each(move |deposit| {
+            transactions_service.auto_convert_deposit(deposit).then(|res| {
+                if let Err(e) = res {
+                    log_and_capture_error(e);
                 }
+                Ok(())
             })
+        })
     }
 
-    fn handle_transaction(&self, blockchain_tx: &BlockchainTransaction) -> impl Future<Item = Vec<Transaction>, Error = Error> + Send {
+    /// Handles transfers of one blockchain transaction in one db transaction, so that seen hashes
+    /// and blockchain transactions of all of them are inserted at once. If the transaction has several
+    /// transfers, the failed ones are rolled back to savepoint and quarantined, so that one bad log
+    /// doesn't block the rest of the message
+    fn handle_transactions(
+        &self,
+        blockchain_txs: Vec<BlockchainTransaction>,
+    ) -> impl Future<Item = Vec<Transaction>, Error = Error> + Send {
         let db_executor = self.db_executor.clone();
         let self_clone = self.clone();
         let self_clone2 = self.clone();
-        let transactions_repo = self.transactions_repo.clone();
         let blockchain_transactions_repo = self.blockchain_transactions_repo.clone();
-        let accounts_repo = self.accounts_repo.clone();
-        let pending_blockchain_transactions_repo = self.pending_blockchain_transactions_repo.clone();
         let seen_hashes_repo = self.seen_hashes_repo.clone();
-        let system_service = self.system_service.clone();
-        let blockchain_tx = blockchain_tx.clone();
+        let quarantined_messages_repo = self.quarantined_messages_repo.clone();
+        let publisher = self.publisher.clone();
+        let key_values_repo = self.key_values_repo.clone();
+        let config = self.config.get();
+        let rates_service = RatesServiceImpl::new(key_values_repo.clone(), self.config.clone());
+        // concurrent handling of the same transaction is resolved by primary key of seen hashes:
+        // the second one either finds the hash claimed or gets serialization failure and is retried
         db_executor
-            .execute_transaction_with_isolation(Isolation::Serializable, move || {
-                let normalized_tx = blockchain_tx
-                    .normalized()
-                    .ok_or(ectx!(try err ErrorContext::BalanceOverflow, ErrorKind::Internal => blockchain_tx))?;
-                // already processed this transaction - skipping
-                if let Some(_) = seen_hashes_repo.get(normalized_tx.hash.clone(), normalized_tx.currency)? {
-                    return Ok((vec![], vec![]));
-                }
-
-                if let Some(erc20_op) = blockchain_tx.erc20_operation_kind {
-                    if erc20_op == Erc20OperationKind::Approve {
-                        // skip confirmations, because the value is very large,
-                        // but since it's `approve` operation we don't care
-                        if blockchain_tx.currency != Currency::Stq {
-                            return Err(ectx!(err ErrorContext::InvalidCurrency, ErrorKind::Internal => blockchain_tx));
-                        }
-                        let from = blockchain_tx
-                    .from
-                    .get(0)
-                    .ok_or(
-                        ectx!(try err ErrorContext::InvalidBlockchainTransactionStructure, ErrorKind::Internal => blockchain_tx.clone()),
-                    )?
-                    .clone();
-                        if let Some(account) = accounts_repo.get_by_address(from.clone(), Currency::Stq, AccountKind::Dr)? {
-                            if !account.erc20_approved {
-                                let changeset = UpdateAccount {
-                                    erc20_approved: Some(true),
-                                    ..Default::default()
-                                };
-                                accounts_repo.update(account.id, changeset.clone())?;
-                                // We don't need the notion of approved credit account anymore, as all debit accounts get approved
-                                blockchain_transactions_repo.create(blockchain_tx.clone().into())?;
-                                pending_blockchain_transactions_repo.delete(blockchain_tx.hash.clone())?;
-                                seen_hashes_repo.create(NewSeenHashes {
-                                    hash: blockchain_tx.hash.clone(),
-                                    block_number: blockchain_tx.block_number as i64,
-                                    currency: blockchain_tx.currency,
-                                })?;
-                            }
+            .execute_transaction_with_retry(Isolation::Serializable, move || {
+                // hashes behind watermark are pruned from seen hashes, so these transactions are considered processed
+                let mut new_seen_hashes = vec![];
+                for blockchain_tx in &blockchain_txs {
+                    if let Some(watermark) = key_values_repo.get_seen_hashes_watermark(blockchain_tx.currency)? {
+                        if (blockchain_tx.block_number as i64) < watermark {
+                            continue;
                         }
-                        // don't need to collect fees, etc. - see the comment in that send_erc20_approval
-                        return Ok((vec![], vec![]));
                     }
+                    new_seen_hashes.push(NewSeenHashes::from(blockchain_tx.clone()));
                 }
-
-                if let Some(tx) = transactions_repo.get_by_blockchain_tx(normalized_tx.hash.clone())? {
-                    // The tx is already in our db => it was created by us and waiting for confirmation from blockchain => it's withdrawal tx
-                    let total_tx_value = normalized_tx
-                        .value()
-                        .ok_or(ectx!(try err ErrorContext::BalanceOverflow, ErrorKind::Internal => tx.clone()))?;
-                    if required_confirmations(normalized_tx.currency, total_tx_value) > normalized_tx.confirmations as u64 {
-                        // skipping tx, waiting for more confirms
-                        return Ok((vec![], vec![]));
+                // claiming the hashes, the ones that are already seen are processed, skipping them
+                let claimed: Vec<_> = seen_hashes_repo
+                    .try_create_batch(new_seen_hashes)?
+                    .into_iter()
+                    .map(|seen_hashes| seen_hashes.hash)
+                    .collect();
+                let mut handled = HandledTransactions::default();
+                let mut new_blockchain_txs = vec![];
+                for blockchain_tx in blockchain_txs.iter().filter(|blockchain_tx| claimed.contains(&blockchain_tx.hash)) {
+                    // single transfer is retried with the message as before
+                    if blockchain_txs.len() == 1 {
+                        handled.extend(self_clone.handle_claimed_transaction(
+                            blockchain_tx,
+                            &config,
+                            &rates_service,
+                            &mut new_blockchain_txs,
+                        )?);
+                        continue;
                     }
-                    if let Some(violation) = self_clone.verify_withdrawal_tx(&tx, &normalized_tx)? {
-                        // Here the tx itself is ok, but violates our internal invariants. We just log it here and put it into strange blockchain transactions table
-                        // If we instead returned error - it would nack the rabbit message and return it to queue - smth we don't want here
-                        self_clone.handle_violation(violation, &blockchain_tx)?;
-                        return Ok((vec![], vec![]));
-                    }
-                    let fees_currency = match blockchain_tx.currency {
-                        Currency::Btc => Currency::Btc,
-                        Currency::Eth => Currency::Eth,
-                        Currency::Stq => Currency::Eth,
-                    };
-
-                    let fees_account_dr = match blockchain_tx.currency {
-                        // stq accounts bear eth fees, that are written off from system account
-                        Currency::Stq => system_service.get_system_fees_account_dr(fees_currency)?,
-                        // other accounts make withdrawal from some dr account, which is stored in tx.cr_account_id
-                        // and fees will be written off from them
-                        _ => accounts_repo
-                            .get(tx.cr_account_id)?
-                            .ok_or(ectx!(try err ErrorContext::NoAccount, ErrorKind::Internal => blockchain_tx, fees_currency))?,
-                    };
-                    let fees_account_cr = system_service.get_system_fees_account(fees_currency)?;
-                    blockchain_transactions_repo.create(blockchain_tx.clone().into())?;
-                    pending_blockchain_transactions_repo.delete(blockchain_tx.hash.clone())?;
-                    transactions_repo.update_status(blockchain_tx.hash.clone(), TransactionStatus::Done)?;
-                    let fee_tx = NewTransaction {
-                        id: TransactionId::generate(),
-                        gid: tx.gid,
-                        user_id: tx.user_id,
-                        dr_account_id: fees_account_cr.id,
-                        cr_account_id: fees_account_dr.id,
-                        currency: fees_currency,
-                        value: blockchain_tx.fee,
-                        status: TransactionStatus::Done,
-                        blockchain_tx_id: None,
-                        kind: TransactionKind::BlockchainFee,
-                        group_kind: tx.group_kind,
-                        related_tx: None,
-                        meta: None,
-                    };
-                    transactions_repo.create(fee_tx)?;
-                    seen_hashes_repo.create(NewSeenHashes {
-                        hash: blockchain_tx.hash.clone(),
-                        block_number: blockchain_tx.block_number as i64,
-                        currency: blockchain_tx.currency,
-                    })?;
-                    return Ok((vec![], vec![]));
-                };
-
-                let to_addresses: Vec<_> = normalized_tx.to.iter().map(|entry| entry.address.clone()).collect();
-                let matched_dr_accounts = accounts_repo.get_by_addresses(&to_addresses, blockchain_tx.currency, AccountKind::Dr)?;
-                if matched_dr_accounts.len() == 0 {
-                    seen_hashes_repo.create(NewSeenHashes {
-                        hash: blockchain_tx.hash.clone(),
-                        block_number: blockchain_tx.block_number as i64,
-                        currency: blockchain_tx.currency,
-                    })?;
-                    return Ok((vec![], vec![]));
-                }
-
-                if let Some(violation) = self_clone.verify_deposit_tx(&normalized_tx)? {
-                    self_clone.handle_violation(violation, &blockchain_tx)?;
-                    return Ok((vec![], vec![]));
-                }
-
-                let mut transactions_out = vec![];
-                let mut need_approve = vec![];
-
-                let mut idx = 0;
-                for to_dr_account in matched_dr_accounts {
-                    let Account {
-                        address: to_dr_address,
-                        currency: to_dr_currency,
-                        ..
-                    } = to_dr_account.clone();
-                    let to_entry = blockchain_tx
-                        .to
-                        .iter()
-                        .find(|entry| entry.address == to_dr_address.clone())
-                        .ok_or(ectx!(try err ErrorContext::MissingAddressInTx, ErrorKind::Internal => to_dr_address.clone()))?;
-                    let to_cr_account = accounts_repo
-                        .get_by_address(to_dr_address.clone(), to_dr_currency.clone(), AccountKind::Cr)?
-                        .ok_or(
-                            ectx!(try err ErrorContext::NoAccount, ErrorKind::Internal => to_dr_address, to_dr_currency, AccountKind::Cr),
-                        )?;
-                    let tx_id = TransactionId::generate();
-                    let new_tx = NewTransaction {
-                        id: tx_id,
-                        gid: tx_id,
-                        user_id: to_dr_account.user_id,
-                        dr_account_id: to_dr_account.id,
-                        cr_account_id: to_cr_account.id,
-                        currency: to_dr_account.currency,
-                        value: to_entry.value,
-                        status: TransactionStatus::Done,
-                        blockchain_tx_id: Some(blockchain_tx.hash.clone()),
-                        kind: TransactionKind::Deposit,
-                        group_kind: TransactionGroupKind::Deposit,
-                        related_tx: None,
-                        meta: None,
-                    };
-                    let dr_transaction = transactions_repo.create(new_tx)?;
-                    transactions_out.push(dr_transaction);
-                    // don't need to create these more than one time, or conflict will be o/w
-                    if idx == 0 {
-                        blockchain_transactions_repo.create(blockchain_tx.clone().into())?;
-                        seen_hashes_repo.create(NewSeenHashes {
-                            hash: blockchain_tx.hash.clone(),
-                            block_number: blockchain_tx.block_number as i64,
-                            currency: blockchain_tx.currency,
-                        })?;
-                    };
-                    // approve account if balance has passed threshold
-                    if (to_dr_account.currency == Currency::Stq) && !to_dr_account.erc20_approved {
-                        let balance = transactions_repo.get_accounts_balance(to_dr_account.user_id, &[to_dr_account.clone()])?[0].balance;
-                        if balance >= Amount::new(STQ_BALANCE_THRESHOLD) {
-                            need_approve.push(to_dr_account)
+                    let mut log_blockchain_txs = vec![];
+                    let res = self_clone.db_executor.savepoint(|| {
+                        self_clone.handle_claimed_transaction(blockchain_tx, &config, &rates_service, &mut log_blockchain_txs)
+                    });
+                    match res {
+                        Ok(handled_log) => {
+                            handled.extend(handled_log);
+                            new_blockchain_txs.extend(log_blockchain_txs);
+                        }
+                        Err(e) => {
+                            // conflicting transaction can't go on, it's rerun as a whole
+                            if is_serialization_failure(&e) {
+                                return Err(e);
+                            }
+                            log_error(&e);
+                            // the log is not seen, so that it's handled when replayed from quarantine
+                            seen_hashes_repo.delete(blockchain_tx.hash.clone(), blockchain_tx.currency)?;
+                            let payload = serde_json::to_vec(blockchain_tx)
+                                .map_err(|e| ectx!(try err e, ErrorContext::Json, ErrorKind::Internal => blockchain_tx.clone()))?;
+                            let message = quarantined_messages_repo.create(NewQuarantinedMessage {
+                                id: Uuid::new_v4(),
+                                currency: blockchain_tx.currency,
+                                payload,
+                                error: format_error(&e),
+                                attempts: 1,
+                                request_id: request_id::current(),
+                            })?;
+                            warn!("Log {} of message quarantined with id {}", blockchain_tx.hash, message.id);
                         }
                     }
-                    idx += 1;
                 }
-                Ok((transactions_out, need_approve))
+                blockchain_transactions_repo.create_batch(new_blockchain_txs)?;
+                Ok(handled)
+            })
+            .and_then(move |handled| {
+                let HandledTransactions {
+                    transactions: transactions_out,
+                    need_approve,
+                    pending_deposits,
+                    deposit_events,
+                } = handled;
+                // users are notified of every new confirmation of pending deposit, confirmed one is published as a transaction
+                let publisher_clone = publisher.clone();
+                futures::stream::iter_ok(pending_deposits)
+                    .for_each(move |pending_deposit| {
+                        let tx_out = TransactionOut::from(pending_deposit);
+                        publisher
+                            .publish(tx_out.clone())
+                            .map_err(ectx!(ErrorSource::Lapin, ErrorKind::Internal => tx_out))
+                            .then(|r: Result<(), Error>| {
+                                if let Err(e) = r {
+                                    log_error(&e);
+                                }
+                                Ok(())
+                            })
+                    })
+                    .and_then(move |_| {
+                        // deposits also go to their own routing key, both pending and credited ones
+                        futures::stream::iter_ok(deposit_events).for_each(move |deposit_event| {
+                            publisher_clone
+                                .publish_deposit(deposit_event.clone())
+                                .map_err(ectx!(ErrorSource::Lapin, ErrorKind::Internal => deposit_event))
+                                .then(|r: Result<(), Error>| {
+                                    if let Err(e) = r {
+                                        log_error(&e);
+                                    }
+                                    Ok(())
+                                })
+                        })
+                    })
+                    .map(move |_| (transactions_out, need_approve))
             })
             .and_then(move |(transactions_out, need_approve)| {
                 let self_clone2 = self_clone2.clone();
@@ -345,25 +334,299 @@ impl<E: DbExecutor> BlockchainFetcher<E> {
             })
     }
 
+    /// Handles transfer which hash is claimed in seen hashes. Blockchain transactions to store are added
+    /// to `new_blockchain_txs`, the caller inserts them
+    fn handle_claimed_transaction(
+        &self,
+        blockchain_tx: &BlockchainTransaction,
+        config: &Config,
+        rates_service: &RatesServiceImpl,
+        new_blockchain_txs: &mut Vec<NewBlockchainTransactionDB>,
+    ) -> Result<HandledTransactions, Error> {
+        let transactions_repo = &self.transactions_repo;
+        let blockchain_transactions_repo = &self.blockchain_transactions_repo;
+        let accounts_repo = &self.accounts_repo;
+        let pending_blockchain_transactions_repo = &self.pending_blockchain_transactions_repo;
+        let seen_hashes_repo = &self.seen_hashes_repo;
+        let small_deposits_repo = &self.small_deposits_repo;
+        let pending_deposits_repo = &self.pending_deposits_repo;
+        let key_values_repo = &self.key_values_repo;
+        let system_service = &self.system_service;
+        let normalized_tx = blockchain_tx
+            .normalized()
+            .ok_or(ectx!(try err ErrorContext::BalanceOverflow, ErrorKind::Internal => blockchain_tx))?;
+        if let Some(erc20_op) = blockchain_tx.erc20_operation_kind {
+            if erc20_op == Erc20OperationKind::Approve {
+                // skip confirmations, because the value is very large,
+                // but since it's `approve` operation we don't care
+                if blockchain_tx.currency != Currency::Stq {
+                    return Err(ectx!(err ErrorContext::InvalidCurrency, ErrorKind::Internal => blockchain_tx));
+                }
+                let from = blockchain_tx.from.get(0).cloned().ok_or_else(
+                    || ectx!(err ErrorContext::InvalidBlockchainTransactionStructure, ErrorKind::Internal => blockchain_tx.clone()),
+                )?;
+                if let Some(account) =
+                    accounts_repo.get_by_address(from.clone(), Currency::Stq, AccountKind::Dr, ArchivedAccounts::Include)?
+                {
+                    if !account.erc20_approved {
+                        let changeset = UpdateAccount {
+                            erc20_approved: Some(true),
+                            ..Default::default()
+                        };
+                        accounts_repo.update(account.id, changeset.clone())?;
+                        // We don't need the notion of approved credit account anymore, as all debit accounts get approved
+                        new_blockchain_txs.push(blockchain_tx.clone().into());
+                        pending_blockchain_transactions_repo.delete(blockchain_tx.hash.clone())?;
+                        // don't need to collect fees, etc. - see the comment in that send_erc20_approval
+                        return Ok(HandledTransactions::default());
+                    }
+                }
+                // nothing to approve - not marking the hash as seen
+                seen_hashes_repo.delete(blockchain_tx.hash.clone(), blockchain_tx.currency)?;
+                return Ok(HandledTransactions::default());
+            }
+        }
+
+        if let Some(tx) = transactions_repo.get_by_blockchain_tx(normalized_tx.hash.clone())? {
+            // The tx is already in our db => it was created by us and waiting for confirmation from blockchain => it's withdrawal or bounce tx
+            let total_tx_value = normalized_tx
+                .value()
+                .ok_or(ectx!(try err ErrorContext::BalanceOverflow, ErrorKind::Internal => tx.clone()))?;
+            let confirmation_thresholds = key_values_repo
+                .get_confirmation_thresholds()?
+                .unwrap_or_else(|| config.confirmations.clone());
+            let required_confirmations = confirmation_thresholds.required_confirmations(normalized_tx.currency, total_tx_value);
+            if required_confirmations > normalized_tx.confirmations as u64 {
+                // users see when the transaction is expected to be done, it's recalculated with every new confirmation
+                if let Value::Object(mut fields) = tx.meta.clone() {
+                    let estimated_completion_at = config.block_times.estimate_completion_at(
+                        normalized_tx.currency,
+                        normalized_tx.confirmations as u64,
+                        required_confirmations,
+                    );
+                    fields.insert(ESTIMATED_COMPLETION_AT_META_KEY.to_string(), json!(estimated_completion_at));
+                    transactions_repo.update_meta(tx.id, Value::Object(fields))?;
+                }
+                // skipping tx, waiting for more confirms, so it must be handled again
+                seen_hashes_repo.delete(blockchain_tx.hash.clone(), blockchain_tx.currency)?;
+                return Ok(HandledTransactions::default());
+            }
+            if tx.kind == TransactionKind::Bounce {
+                // Bounced funds were never in the ledger, so btc and eth network fee was paid out of them,
+                // only eth fee of stq transfer is written off from system account, as for withdrawals
+                if blockchain_tx.currency == Currency::Stq {
+                    complete_pending_transaction(
+                        &*transactions_repo,
+                        &*accounts_repo,
+                        &*blockchain_transactions_repo,
+                        &*pending_blockchain_transactions_repo,
+                        &*system_service,
+                        &tx,
+                        blockchain_tx,
+                    )?;
+                } else {
+                    new_blockchain_txs.push(blockchain_tx.clone().into());
+                    pending_blockchain_transactions_repo.delete(blockchain_tx.hash.clone())?;
+                    transactions_repo.update_status(blockchain_tx.hash.clone(), TransactionStatus::Done)?;
+                }
+                return Ok(HandledTransactions::default());
+            }
+            if tx.kind == TransactionKind::Sweep {
+                // Sweep goes to our own address, so it's neither a withdrawal to verify nor a deposit.
+                // Network fee is written off from the account it's sent from, as for withdrawals
+                complete_pending_transaction(
+                    &*transactions_repo,
+                    &*accounts_repo,
+                    &*blockchain_transactions_repo,
+                    &*pending_blockchain_transactions_repo,
+                    &*system_service,
+                    &tx,
+                    blockchain_tx,
+                )?;
+                return Ok(HandledTransactions::default());
+            }
+            if let Some(violation) = self.verify_withdrawal_tx(&tx, &normalized_tx)? {
+                // Here the tx itself is ok, but violates our internal invariants. We just log it here and put it into strange blockchain transactions table
+                // If we instead returned error - it would nack the rabbit message and return it to queue - smth we don't want here
+                self.handle_violation(violation, blockchain_tx)?;
+                return Ok(HandledTransactions::default());
+            }
+            complete_pending_transaction(
+                &*transactions_repo,
+                &*accounts_repo,
+                &*blockchain_transactions_repo,
+                &*pending_blockchain_transactions_repo,
+                &*system_service,
+                &tx,
+                blockchain_tx,
+            )?;
+            refund_overcharged_fee(&*transactions_repo, tx.gid, config)?;
+            return Ok(HandledTransactions::default());
+        };
+
+        // eth sent to our addresses by contracts comes in internal transfers, these are deposits as well
+        let deposit_tx = blockchain_tx.with_internal_transfers();
+        let normalized_deposit_tx = deposit_tx
+            .normalized()
+            .ok_or(ectx!(try err ErrorContext::BalanceOverflow, ErrorKind::Internal => deposit_tx))?;
+        let to_addresses: Vec<_> = normalized_deposit_tx.to.iter().map(|entry| entry.address.clone()).collect();
+        let matched_dr_accounts =
+            accounts_repo.get_by_addresses(&to_addresses, blockchain_tx.currency, AccountKind::Dr, ArchivedAccounts::Include)?;
+        if matched_dr_accounts.len() == 0 {
+            return Ok(HandledTransactions::default());
+        }
+
+        if let Some(violation) = self.verify_deposit_tx(&normalized_deposit_tx)? {
+            self.handle_violation(violation, blockchain_tx)?;
+            return Ok(HandledTransactions::default());
+        }
+
+        // deposit is credited only when it has enough confirmations, until then users see it as pending deposit
+        let total_deposit_value = normalized_deposit_tx
+            .value()
+            .ok_or(ectx!(try err ErrorContext::BalanceOverflow, ErrorKind::Internal => normalized_deposit_tx))?;
+        let confirmation_thresholds = key_values_repo
+            .get_confirmation_thresholds()?
+            .unwrap_or_else(|| config.confirmations.clone());
+        let required_confirmations = confirmation_thresholds.required_confirmations(normalized_deposit_tx.currency, total_deposit_value);
+        let confirmed = normalized_deposit_tx.confirmations as u64 >= required_confirmations;
+
+        let mut transactions_out = vec![];
+        let mut need_approve = vec![];
+        let mut pending_deposits = vec![];
+        let mut deposit_events = vec![];
+
+        let mut idx = 0;
+        for to_dr_account in matched_dr_accounts {
+            let Account {
+                address: to_dr_address,
+                currency: to_dr_currency,
+                ..
+            } = to_dr_account.clone();
+            let to_entry = normalized_deposit_tx
+                .to
+                .iter()
+                .find(|entry| entry.address == to_dr_address.clone())
+                .ok_or(ectx!(try err ErrorContext::MissingAddressInTx, ErrorKind::Internal => to_dr_address.clone()))?;
+            // dust can't ever be withdrawn economically, so it's kept out of the ledger
+            if to_entry.value.to_super_unit(to_dr_currency) < config.min_deposits.min_deposit(to_dr_currency) {
+                // unconfirmed tx is handled again, so dust is recorded only once it's confirmed
+                if confirmed {
+                    small_deposits_repo.create(NewSmallDeposit {
+                        blockchain_tx_id: blockchain_tx.hash.clone(),
+                        account_id: to_dr_account.id,
+                        user_id: to_dr_account.user_id,
+                        currency: to_dr_currency,
+                        value: to_entry.value,
+                    })?;
+                }
+                continue;
+            }
+            // funds sent to a rotated address are credited to the account it belonged to
+            let to_cr_account =
+                match accounts_repo.get_by_address(to_dr_address.clone(), to_dr_currency, AccountKind::Cr, ArchivedAccounts::Include)? {
+                    Some(account) => Some(account),
+                    None => accounts_repo.get_by_expired_address(to_dr_address.clone(), to_dr_currency)?,
+                };
+            let to_cr_account = to_cr_account
+                .ok_or(ectx!(try err ErrorContext::NoAccount, ErrorKind::Internal => to_dr_address, to_dr_currency, AccountKind::Cr))?;
+            if !confirmed {
+                let pending_deposit = pending_deposits_repo.upsert(NewPendingDeposit {
+                    id: TransactionId::generate(),
+                    blockchain_tx_id: blockchain_tx.hash.clone(),
+                    account_id: to_cr_account.id,
+                    user_id: to_cr_account.user_id,
+                    currency: to_dr_currency,
+                    from_: json!(normalized_deposit_tx.from),
+                    address: to_dr_address,
+                    value: to_entry.value,
+                    confirmations: normalized_deposit_tx.confirmations as i32,
+                    required_confirmations: required_confirmations as i32,
+                    estimated_completion_at: config.block_times.estimate_completion_at(
+                        to_dr_currency,
+                        normalized_deposit_tx.confirmations as u64,
+                        required_confirmations,
+                    ),
+                })?;
+                deposit_events.push(DepositEvent::from(pending_deposit.clone()));
+                pending_deposits.push(pending_deposit);
+                continue;
+            }
+            // confirmed deposit keeps id of the pending one, so users see the same transaction become done
+            let tx_id = match pending_deposits_repo.delete(blockchain_tx.hash.clone(), to_cr_account.id)? {
+                Some(pending_deposit) => pending_deposit.id,
+                None => TransactionId::generate(),
+            };
+            let new_tx = NewTransaction {
+                id: tx_id,
+                gid: tx_id,
+                user_id: to_dr_account.user_id,
+                dr_account_id: to_dr_account.id,
+                cr_account_id: to_cr_account.id,
+                currency: to_dr_account.currency,
+                value: to_entry.value,
+                status: TransactionStatus::Done,
+                blockchain_tx_id: Some(blockchain_tx.hash.clone()),
+                kind: TransactionKind::Deposit,
+                group_kind: TransactionGroupKind::Deposit,
+                related_tx: None,
+                meta: None,
+            };
+            let new_tx = rates_service.with_usd_rate(new_tx)?;
+            let dr_transaction = transactions_repo.create(new_tx)?;
+            deposit_events.push(DepositEvent {
+                id: tx_id,
+                user_id: to_cr_account.user_id,
+                account_id: to_cr_account.id,
+                currency: to_dr_currency,
+                address: to_dr_address,
+                value: to_entry.value,
+                blockchain_tx_id: blockchain_tx.hash.clone(),
+                confirmations: normalized_deposit_tx.confirmations as i32,
+                required_confirmations: required_confirmations as i32,
+                status: TransactionStatus::Done,
+            });
+            transactions_out.push(dr_transaction);
+            // don't need to create these more than one time, or conflict will be o/w
+            if idx == 0 {
+                new_blockchain_txs.push(deposit_tx.clone().into());
+            };
+            // approve account if balance has passed threshold
+            if (to_dr_account.currency == Currency::Stq) && !to_dr_account.erc20_approved {
+                let balance = transactions_repo.get_accounts_balance(to_dr_account.user_id, &[to_dr_account.clone()])?[0].balance;
+                if balance >= Amount::new(STQ_BALANCE_THRESHOLD) {
+                    need_approve.push(to_dr_account)
+                }
+            }
+            idx += 1;
+        }
+        if !confirmed {
+            // waiting for more confirms, so it must be handled again
+            seen_hashes_repo.delete(blockchain_tx.hash.clone(), blockchain_tx.currency)?;
+        }
+        Ok(HandledTransactions {
+            transactions: transactions_out,
+            need_approve,
+            pending_deposits,
+            deposit_events,
+        })
+    }
+
     fn handle_violation(&self, violation: InvariantViolation, blockchain_tx: &BlockchainTransaction) -> Result<(), Error> {
         log_error(&ectx!(try err violation => blockchain_tx));
 
-        let message = format!("{}", violation);
-        let new_strange_tx = (blockchain_tx.clone(), message).into();
+        let new_strange_tx = (blockchain_tx.clone(), violation).into();
         self.strange_blockchain_transactions_repo.create(new_strange_tx)?;
-
-        self.seen_hashes_repo.create(NewSeenHashes {
-            hash: blockchain_tx.hash.clone(),
-            block_number: blockchain_tx.block_number as i64,
-            currency: blockchain_tx.currency,
-        })?;
         Ok(())
     }
 
     fn verify_deposit_tx(&self, blockchain_tx: &BlockchainTransaction) -> Result<Option<InvariantViolation>, Error> {
-        let from_accounts = self
-            .accounts_repo
-            .get_by_addresses(&blockchain_tx.from, blockchain_tx.currency, AccountKind::Dr)?;
+        let from_accounts = self.accounts_repo.get_by_addresses(
+            &blockchain_tx.from,
+            blockchain_tx.currency,
+            AccountKind::Dr,
+            ArchivedAccounts::Include,
+        )?;
         if from_accounts.len() > 0 {
             return Ok(Some(InvariantViolation::DepositAddressInternal));
         }
@@ -388,12 +651,16 @@ impl<E: DbExecutor> BlockchainFetcher<E> {
         if tx.status != TransactionStatus::Pending {
             return Ok(Some(InvariantViolation::WithdrawalNotPendingAddress));
         }
-        if self.pending_blockchain_transactions_repo.get(blockchain_tx.hash.clone())?.is_none() {
-            return Ok(Some(InvariantViolation::WithdrawalNoPendingTx));
-        }
+        let pending_tx = match self.pending_blockchain_transactions_repo.get(blockchain_tx.hash.clone())? {
+            Some(pending_tx) => pending_tx,
+            None => return Ok(Some(InvariantViolation::WithdrawalNoPendingTx)),
+        };
 
         let from_address = blockchain_tx.from[0].clone();
-        let BlockchainTransactionEntryTo { address: to_address, .. } = blockchain_tx.to[0].clone();
+        let BlockchainTransactionEntryTo {
+            address: to_address,
+            value: blockchain_value,
+        } = blockchain_tx.to[0].clone();
         // Transaction should have valid account in our db
         if let Some(managed_address) = self.accounts_repo.get(tx.cr_account_id)? {
             // Blockchain tx from_address should be equal to that of manages account address
@@ -405,33 +672,48 @@ impl<E: DbExecutor> BlockchainFetcher<E> {
         };
         // to_address should be external to our system, because in all other cases we should do
         // everything internally
-        if let Some(_) = self
-            .accounts_repo
-            .get_by_address(to_address.clone(), blockchain_tx.currency, AccountKind::Dr)?
-        {
+        if let Some(_) = self.accounts_repo.get_by_address(
+            to_address.clone(),
+            blockchain_tx.currency,
+            AccountKind::Dr,
+            ArchivedAccounts::Include,
+        )? {
             return Ok(Some(InvariantViolation::WithdrawalAdressesInternal));
         }
         // to_address should be external to our system, because in all other cases we should do
         // everything internally
-        if let Some(_) = self
-            .accounts_repo
-            .get_by_address(to_address.clone(), blockchain_tx.currency, AccountKind::Cr)?
-        {
+        if let Some(_) = self.accounts_repo.get_by_address(
+            to_address.clone(),
+            blockchain_tx.currency,
+            AccountKind::Cr,
+            ArchivedAccounts::Include,
+        )? {
             return Ok(Some(InvariantViolation::WithdrawalAdressesInternal));
         }
-        // values in blockchain and our tx must match
-        // TODO - subject to fees
-        // if value != tx.value {
-        //     return Ok(Some(InvariantViolation::WithdrawalValue));
-        // }
+        // values in blockchain, pending tx and our ledger tx must match
+        if blockchain_value != pending_tx.value || blockchain_value != tx.value {
+            return Ok(Some(InvariantViolation::WithdrawalValue));
+        }
+        // network fee is written off on completion from the account that paid it in blockchain, it must
+        // be covered by what is left on this account after the withdrawal, otherwise we've spent more than reserved
+        let fee_payer_account_id = match blockchain_tx.currency {
+            // stq fees are paid in eth from system fees account
+            Currency::Stq => self.system_service.get_system_fees_account_dr(Currency::Eth)?.id,
+            _ => tx.cr_account_id,
+        };
+        let fee_payer_balance = self.transactions_repo.get_account_balance(fee_payer_account_id, AccountKind::Dr)?;
+        if blockchain_tx.fee > fee_payer_balance {
+            return Ok(Some(InvariantViolation::WithdrawalFee));
+        }
         Ok(None)
     }
 
     fn send_erc20_approval(&self, account: &Account) -> Box<Future<Item = (), Error = Error> + Send> {
         let account = account.clone();
         let account_address = account.address.clone();
-        let approve_gas_price = self.config.system.approve_gas_price;
-        let approve_gas_limit = self.config.system.approve_gas_limit;
+        let config = self.config.get();
+        let approve_gas_price = config.system.approve_gas_price;
+        let approve_gas_limit = config.system.approve_gas_limit;
         let db_executor = self.db_executor.clone();
         let db_executor_clone = self.db_executor.clone();
         let db_executor_clone2 = self.db_executor.clone();
@@ -447,7 +729,7 @@ impl<E: DbExecutor> BlockchainFetcher<E> {
         let pending_blockchain_transactions_repo = self.pending_blockchain_transactions_repo.clone();
         let pending_blockchain_transactions_repo_ = self.pending_blockchain_transactions_repo.clone();
         let transactions_repo = self.transactions_repo.clone();
-        let approve_delay_secs = self.config.system.approve_delay_secs;
+        let approve_delay_secs = config.system.approve_delay_secs;
 
         Box::new(
             db_executor
@@ -518,11 +800,13 @@ impl<E: DbExecutor> BlockchainFetcher<E> {
                                 .sign_transaction(eth_transfer_blockchain_tx.clone(), Role::System)
                                 .map_err(move |e| ectx!(err e, ErrorKind::Internal => eth_transfer_blockchain_tx.clone()))
                                 .and_then(move |eth_raw_tx| {
+                                    let eth_raw_tx_clone = eth_raw_tx.clone();
                                     blockchain_client_
                                         .post_ethereum_transaction(eth_raw_tx.clone())
-                                        .map_err(move |e| ectx!(err e, ErrorKind::Internal => eth_raw_tx))
+                                        .map_err(move |e| ectx!(err e, ErrorKind::Internal => eth_raw_tx_clone))
+                                        .map(move |eth_tx_id| (eth_tx_id, eth_raw_tx))
                                 })
-                                .and_then(move |eth_tx_id| {
+                                .and_then(move |(eth_tx_id, eth_raw_tx)| {
                                     db_executor_clone.execute(move || {
                                         let eth_fees_cr_account = system_service_.get_system_fees_account(Currency::Eth)?;
                                         let eth_tx = NewTransaction {
@@ -540,7 +824,7 @@ impl<E: DbExecutor> BlockchainFetcher<E> {
                                             related_tx: None,
                                             meta: None,
                                         };
-                                        let new_pending_eth = (eth_transfer_blockchain_tx_clone, eth_tx_id.clone()).into();
+                                        let new_pending_eth = (eth_transfer_blockchain_tx_clone, eth_tx_id.clone(), eth_raw_tx).into();
                                         // Note - we don't rollback here, because the tx is already in blockchain. so after that just silently
                                         // fail if we couldn't write a pending tx. Not having pending tx in db doesn't do a lot of harm, we could cure
                                         // it later.
@@ -592,14 +876,17 @@ impl<E: DbExecutor> BlockchainFetcher<E> {
                                         .map_err(ectx!(ErrorKind::Internal => eth_approve_blockchain_tx))
                                 })
                                 .and_then(move |approve_raw_tx| {
+                                    let approve_raw_tx_clone = approve_raw_tx.clone();
                                     blockchain_client___
                                         .post_ethereum_transaction(approve_raw_tx.clone())
-                                        .map_err(ectx!(ErrorKind::Internal => approve_raw_tx))
+                                        .map_err(ectx!(ErrorKind::Internal => approve_raw_tx_clone))
+                                        .map(move |approve_tx_id| (approve_tx_id, approve_raw_tx))
                                 })
-                                .and_then(move |approve_tx_id| {
+                                .and_then(move |(approve_tx_id, approve_raw_tx)| {
                                     // logs from blockchain gw erc20 comes with log number in hash
-                                    let approve_tx_id = BlockchainTransactionId::new(format!("{}:0", approve_tx_id.inner()));
-                                    let new_pending_approve = (eth_approve_blockchain_tx_clone2, approve_tx_id.clone()).into();
+                                    let approve_tx_id = approve_tx_id.with_log_index(0);
+                                    let new_pending_approve =
+                                        (eth_approve_blockchain_tx_clone2, approve_tx_id.clone(), approve_raw_tx).into();
                                     db_executor_clone2.execute(move || -> Result<(), Error> {
                                         match pending_blockchain_transactions_repo_.create(new_pending_approve) {
                                             Err(e) => log_and_capture_error(e),
@@ -614,49 +901,146 @@ impl<E: DbExecutor> BlockchainFetcher<E> {
     }
 }
 
-const USD_PER_ETH: f64 = 200.0;
-const USD_PER_BTC: f64 = 6500.0;
-const USD_PER_STQ: f64 = 0.0025;
-const BTC_DECIMALS: u128 = 100_000_000u128;
-const ETH_DECIMALS: u128 = 1_000_000_000_000_000_000u128;
-const STQ_DECIMALS: u128 = 1_000_000_000_000_000_000u128;
-const BTC_CONFIRM_THRESHOLDS: &[u64] = &[100, 500, 1000];
-const ETH_CONFIRM_THRESHOLDS: &[u64] = &[20, 50, 200, 500, 1000, 2000, 3000, 4000, 5000];
-
-fn to_usd_approx(currency: Currency, value: Amount) -> u64 {
-    let (rate, decimals) = match currency {
-        Currency::Btc => (USD_PER_BTC, BTC_DECIMALS),
-        Currency::Eth => (USD_PER_ETH, ETH_DECIMALS),
-        Currency::Stq => (USD_PER_STQ, STQ_DECIMALS),
+/// Finishes our pending ledger transaction, once its blockchain transaction is confirmed:
+/// moves blockchain tx from pending, sets ledger tx status to `done`, writes off blockchain fees
+/// and accrues them to the group to be compared with the charged fee. Used both by fetcher and `repair` command.
+pub fn complete_pending_transaction(
+    transactions_repo: &TransactionsRepo,
+    accounts_repo: &AccountsRepo,
+    blockchain_transactions_repo: &BlockchainTransactionsRepo,
+    pending_blockchain_transactions_repo: &PendingBlockchainTransactionsRepo,
+    system_service: &SystemService,
+    tx: &Transaction,
+    blockchain_tx: &BlockchainTransaction,
+) -> Result<(), Error> {
+    let fees_currency = match blockchain_tx.currency {
+        Currency::Btc => Currency::Btc,
+        Currency::Eth => Currency::Eth,
+        Currency::Stq => Currency::Eth,
+    };
+
+    let fees_account_dr = match blockchain_tx.currency {
+        // stq accounts bear eth fees, that are written off from system account
+        Currency::Stq => system_service.get_system_fees_account_dr(fees_currency)?,
+        // other accounts make withdrawal from some dr account, which is stored in tx.cr_account_id
+        // and fees will be written off from them
+        _ => accounts_repo
+            .get(tx.cr_account_id)?
+            .ok_or(ectx!(try err ErrorContext::NoAccount, ErrorKind::Internal => blockchain_tx, fees_currency))?,
+    };
+    let fees_account_cr = system_service.get_system_fees_account(fees_currency)?;
+    blockchain_transactions_repo.create(blockchain_tx.clone().into())?;
+    pending_blockchain_transactions_repo.delete(blockchain_tx.hash.clone())?;
+    transactions_repo.update_status(blockchain_tx.hash.clone(), TransactionStatus::Done)?;
+    let fee_tx = NewTransaction {
+        id: TransactionId::generate(),
+        gid: tx.gid,
+        user_id: tx.user_id,
+        dr_account_id: fees_account_cr.id,
+        cr_account_id: fees_account_dr.id,
+        currency: fees_currency,
+        value: blockchain_tx.fee,
+        status: TransactionStatus::Done,
+        blockchain_tx_id: None,
+        kind: TransactionKind::BlockchainFee,
+        group_kind: tx.group_kind,
+        related_tx: None,
+        meta: None,
     };
-    // Max of all rates
-    let max_rate = USD_PER_BTC as u128;
-    // first multiply by max_rate and then divide by it
-    // that is made so that we can use integer division of u128 (f64 is not enough)
-    // and be sure that our error is less that 1 dollar
-    let crypto_value_times_rate: u128 = value.raw() * max_rate / decimals;
-    // after dividing by decimals we have value small enough to be used as f64
-    let usd_value: f64 = (crypto_value_times_rate as f64) * rate / (max_rate as f64);
-    usd_value as u64
+    transactions_repo.create(fee_tx)?;
+    transactions_repo.accrue_blockchain_fee(tx.gid, fees_currency, blockchain_tx.fee)?;
+    Ok(())
 }
 
-fn required_confirmations(currency: Currency, value: Amount) -> u64 {
-    let usd_value = to_usd_approx(currency, value);
-    let thresholds = match currency {
-        Currency::Btc => BTC_CONFIRM_THRESHOLDS,
-        _ => ETH_CONFIRM_THRESHOLDS,
+/// Once all withdrawals of group are confirmed, returns part of its fee to the fee payer, if network fees paid
+/// are lower than estimated by more than `refund_threshold`. Refund is a group of its own, related to the fee.
+/// Called after `complete_pending_transaction` both by fetcher and `repair` command
+pub fn refund_overcharged_fee(transactions_repo: &TransactionsRepo, gid: TransactionId, config: &Config) -> Result<(), Error> {
+    if !config.fees_options.refund_enabled {
+        return Ok(());
+    }
+    let group = transactions_repo.get_by_gid(gid)?;
+    if group
+        .iter()
+        .any(|tx| tx.kind == TransactionKind::Withdrawal && tx.status == TransactionStatus::Pending)
+    {
+        return Ok(());
+    }
+    let fee_tx = match group.into_iter().find(|tx| tx.kind == TransactionKind::Fee) {
+        Some(fee_tx) => fee_tx,
+        None => return Ok(()),
     };
-    let mut res = None;
-    for (i, threshold) in thresholds.iter().enumerate() {
-        if *threshold >= usd_value {
-            res = Some(i as u64);
-            break;
-        }
+    let group_fee = match transactions_repo.get_group_fee(gid)? {
+        Some(group_fee) => group_fee,
+        None => return Ok(()),
+    };
+    let share = match group_fee.refund_share(config.fees_options.fee_upside, config.fees_options.refund_threshold) {
+        Some(share) => share,
+        None => return Ok(()),
+    };
+    // the last confirmation of group can be handled again, e.g. by replayed message or `repair`
+    let tree = transactions_repo.get_group_with_related(gid)?;
+    if tree.contains_group_kind(TransactionGroupKind::FeeRefund) {
+        return Ok(());
     }
-    res.unwrap_or(thresholds.len() as u64)
+    // part of the fee might have been returned by reversal of withdrawals lost earlier, so only the rest is refunded
+    let reversed = tree
+        .flatten()
+        .into_iter()
+        .filter(|tx| tx.group_kind == TransactionGroupKind::Reversal && tx.related_tx == Some(fee_tx.id))
+        .fold(0u128, |acc, tx| acc.saturating_add(tx.value.raw()));
+    let share = if reversed > 0 {
+        share.min(fee_tx.value.raw().saturating_sub(reversed) as f64 / fee_tx.value.raw() as f64)
+    } else {
+        share
+    };
+    let value = Amount::new(::std::cmp::min((fee_tx.value.raw() as f64 * share) as u128, fee_tx.value.raw()));
+    let charged_blockchain_fee = Amount::new(::std::cmp::min(
+        (group_fee.charged_blockchain_fee.raw() as f64 * share) as u128,
+        group_fee.charged_blockchain_fee.raw(),
+    ));
+    if value.raw() == 0 {
+        return Ok(());
+    }
+    let payload = NewTransaction {
+        id: TransactionId::generate(),
+        gid: TransactionId::generate(),
+        user_id: fee_tx.user_id,
+        dr_account_id: fee_tx.cr_account_id,
+        cr_account_id: fee_tx.dr_account_id,
+        currency: fee_tx.currency,
+        value,
+        status: TransactionStatus::Done,
+        blockchain_tx_id: None,
+        kind: TransactionKind::Fee,
+        group_kind: TransactionGroupKind::FeeRefund,
+        related_tx: Some(fee_tx.id),
+        meta: Some(Value::String(format!("refund of withdrawal fee transaction with id {}", fee_tx.id))),
+    };
+    transactions_repo.create(payload)?;
+    transactions_repo.deduct_refunded_fee(gid, value, charged_blockchain_fee)?;
+    Ok(())
 }
 
-fn parse_transaction(data: Vec<u8>) -> Result<BlockchainTransaction, Error> {
+/// Addresses touched by blockchain transaction in the message
+pub fn message_addresses(tx: &BlockchainTransaction) -> Vec<BlockchainAddress> {
+    let mut addresses = tx.from.clone();
+    addresses.extend(tx.to.iter().map(|entry| entry.address.clone()));
+    for transfer in &tx.internal_transfers {
+        addresses.push(transfer.from.clone());
+        addresses.push(transfer.to.clone());
+    }
+    for log in &tx.logs {
+        addresses.push(log.from.clone());
+        addresses.push(log.to.clone());
+    }
+    addresses.sort();
+    addresses.dedup();
+    addresses
+}
+
+/// Blockchain transaction sent by blockchain gateway in rabbit message
+pub fn parse_transaction(data: Vec<u8>) -> Result<BlockchainTransaction, Error> {
     let data_clone = data.clone();
     let string = String::from_utf8(data).map_err(|e| ectx!(try err e, ErrorContext::UTF8, ErrorKind::Internal => data_clone))?;
     serde_json::from_str(&string).map_err(ectx!(ErrorContext::Json, ErrorKind::Internal => string))
@@ -665,32 +1049,695 @@ fn parse_transaction(data: Vec<u8>) -> Result<BlockchainTransaction, Error> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use client::*;
+    use config::Config;
+    use rabbit::*;
+    use repos::*;
+    use services::{AuthServiceMock, TransactionsServiceImpl};
+
+    fn create_transactions_service(
+        transactions_repo: Arc<TransactionsRepoMock>,
+        accounts_repo: Arc<AccountsRepoMock>,
+    ) -> Arc<TransactionsService> {
+        Arc::new(TransactionsServiceImpl::new(
+            Config::new().unwrap(),
+            Arc::new(AuthServiceMock::new(vec![])),
+            transactions_repo,
+            Arc::new(PendingBlockchainTransactionsRepoMock::default()),
+            Arc::new(BlockchainTransactionsRepoMock::default()),
+            Arc::new(StrangeBlockchainTransactionsRepoMock::default()),
+            Arc::new(QueuedWithdrawalsRepoMock::default()),
+            Arc::new(RateLocksRepoMock::default()),
+            Arc::new(UsersRepoMock::default()),
+            Arc::new(WithdrawalAddressesRepoMock::default()),
+            accounts_repo,
+            Arc::new(KeyValuesRepoMock::default()),
+            DbExecutorMock::default(),
+            Arc::new(KeysClientMock::default()),
+            Arc::new(BlockchainClientMock::default()),
+            Arc::new(ExchangeClientMock::default()),
+            Arc::new(TransactionPublisherMock::default()),
+        ))
+    }
+
+    /// Pending eth withdrawal of 100 from managed account to external address, that was charged 1000 of fee,
+    /// estimated at 200 of network fee. Returns the withdrawal and its confirmed blockchain transaction that paid `network_fee`
+    fn create_withdrawal_group(
+        transactions_repo: &TransactionsRepoMock,
+        accounts_repo: &AccountsRepoMock,
+        pending_blockchain_transactions_repo: &PendingBlockchainTransactionsRepoMock,
+        network_fee: u128,
+    ) -> (Transaction, BlockchainTransaction) {
+        let from_address = BlockchainAddress::new("0x1".to_string());
+        let to_address = BlockchainAddress::new("0x2".to_string());
+        let hash = BlockchainTransactionId::new("0xabc".to_string());
+        let user_account = accounts_repo.create(NewAccount::default()).unwrap();
+        let managed_account = accounts_repo
+            .create(NewAccount {
+                address: from_address.clone(),
+                kind: AccountKind::Dr,
+                ..Default::default()
+            })
+            .unwrap();
+        let fees_account = accounts_repo.create(NewAccount::default()).unwrap();
+        accounts_repo
+            .set_system_role(fees_account.id, Some(SystemAccountKind::Fees))
+            .unwrap();
+        // managed account keeps 200 after the withdrawal to pay network fee
+        transactions_repo
+            .create(NewTransaction {
+                dr_account_id: managed_account.id,
+                currency: Currency::Eth,
+                value: Amount::new(300),
+                status: TransactionStatus::Done,
+                ..Default::default()
+            })
+            .unwrap();
+        let gid = TransactionId::generate();
+        transactions_repo
+            .create(NewTransaction {
+                gid,
+                user_id: user_account.user_id,
+                dr_account_id: user_account.id,
+                cr_account_id: fees_account.id,
+                currency: Currency::Eth,
+                value: Amount::new(1000),
+                status: TransactionStatus::Done,
+                kind: TransactionKind::Fee,
+                group_kind: TransactionGroupKind::Withdrawal,
+                ..Default::default()
+            })
+            .unwrap();
+        let tx = transactions_repo
+            .create(NewTransaction {
+                gid,
+                user_id: user_account.user_id,
+                dr_account_id: user_account.id,
+                cr_account_id: managed_account.id,
+                currency: Currency::Eth,
+                value: Amount::new(100),
+                blockchain_tx_id: Some(hash.clone()),
+                kind: TransactionKind::Withdrawal,
+                group_kind: TransactionGroupKind::Withdrawal,
+                ..Default::default()
+            })
+            .unwrap();
+        transactions_repo
+            .create_group_fee(NewGroupFee {
+                gid,
+                fee_currency: Currency::Eth,
+                fee: Amount::new(1000),
+                blockchain_fee_currency: Currency::Eth,
+                charged_blockchain_fee: Amount::new(200),
+                blockchain_fee: Amount::new(0),
+            })
+            .unwrap();
+        pending_blockchain_transactions_repo
+            .create(NewPendingBlockchainTransactionDB {
+                hash: hash.clone(),
+                from_: from_address.clone(),
+                to_: to_address.clone(),
+                value: Amount::new(100),
+                ..Default::default()
+            })
+            .unwrap();
+        let blockchain_tx = BlockchainTransaction {
+            hash,
+            from: vec![from_address],
+            to: vec![BlockchainTransactionEntryTo {
+                address: to_address,
+                value: Amount::new(100),
+            }],
+            block_number: 1,
+            currency: Currency::Eth,
+            fee: Amount::new(network_fee),
+            confirmations: 100,
+            erc20_operation_kind: None,
+            internal_transfers: vec![],
+            logs: vec![],
+        };
+        (tx, blockchain_tx)
+    }
+
+    #[test]
+    fn test_confirmed_withdrawal_accrues_network_fee() {
+        let config = Config::new().unwrap();
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let pending_blockchain_transactions_repo = Arc::new(PendingBlockchainTransactionsRepoMock::default());
+        let fetcher = BlockchainFetcher::new(
+            SharedConfig::new(config),
+            transactions_repo.clone(),
+            accounts_repo.clone(),
+            Arc::new(SeenHashesRepoMock::default()),
+            Arc::new(BlockchainTransactionsRepoMock::default()),
+            Arc::new(StrangeBlockchainTransactionsRepoMock::default()),
+            Arc::new(SmallDepositsRepoMock::default()),
+            Arc::new(PendingDepositsRepoMock::default()),
+            pending_blockchain_transactions_repo.clone(),
+            Arc::new(KeyValuesRepoMock::default()),
+            Arc::new(QuarantinedMessagesRepoMock::default()),
+            Arc::new(BlockchainClientMock::default()),
+            Arc::new(KeysClientMock::default()),
+            DbExecutorMock::default(),
+            Arc::new(TransactionPublisherMock::default()),
+            create_transactions_service(transactions_repo.clone(), accounts_repo.clone()),
+        );
+        let (tx, blockchain_tx) = create_withdrawal_group(&transactions_repo, &accounts_repo, &pending_blockchain_transactions_repo, 150);
+
+        fetcher.handle_transactions(vec![blockchain_tx]).wait().unwrap();
+        let group = transactions_repo.get_by_gid(tx.gid).unwrap();
+        assert!(group
+            .iter()
+            .any(|tx| tx.kind == TransactionKind::Withdrawal && tx.status == TransactionStatus::Done));
+        let blockchain_fee_tx = group.iter().find(|tx| tx.kind == TransactionKind::BlockchainFee).unwrap();
+        assert_eq!(blockchain_fee_tx.value, Amount::new(150));
+        let group_fee = transactions_repo.get_group_fee(tx.gid).unwrap().unwrap();
+        assert_eq!(group_fee.fee, Amount::new(1000));
+        assert_eq!(group_fee.charged_blockchain_fee, Amount::new(200));
+        assert_eq!(group_fee.blockchain_fee, Amount::new(150));
+    }
+
+    #[test]
+    fn test_overcharged_fee_is_refunded_once() {
+        let mut config = Config::new().unwrap();
+        config.fees_options.refund_enabled = true;
+        config.fees_options.fee_upside = 2.0;
+        config.fees_options.refund_threshold = 0.2;
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let pending_blockchain_transactions_repo = Arc::new(PendingBlockchainTransactionsRepoMock::default());
+        let fetcher = BlockchainFetcher::new(
+            SharedConfig::new(config.clone()),
+            transactions_repo.clone(),
+            accounts_repo.clone(),
+            Arc::new(SeenHashesRepoMock::default()),
+            Arc::new(BlockchainTransactionsRepoMock::default()),
+            Arc::new(StrangeBlockchainTransactionsRepoMock::default()),
+            Arc::new(SmallDepositsRepoMock::default()),
+            Arc::new(PendingDepositsRepoMock::default()),
+            pending_blockchain_transactions_repo.clone(),
+            Arc::new(KeyValuesRepoMock::default()),
+            Arc::new(QuarantinedMessagesRepoMock::default()),
+            Arc::new(BlockchainClientMock::default()),
+            Arc::new(KeysClientMock::default()),
+            DbExecutorMock::default(),
+            Arc::new(TransactionPublisherMock::default()),
+            create_transactions_service(transactions_repo.clone(), accounts_repo.clone()),
+        );
+        // estimated 100 of network fee, paid 25
+        let (tx, blockchain_tx) = create_withdrawal_group(&transactions_repo, &accounts_repo, &pending_blockchain_transactions_repo, 25);
+        let refunds = || -> Vec<Transaction> {
+            transactions_repo
+                .list_for_account(tx.dr_account_id, 0, 100)
+                .unwrap()
+                .into_iter()
+                .filter(|tx| tx.group_kind == TransactionGroupKind::FeeRefund)
+                .collect()
+        };
+
+        fetcher.handle_transactions(vec![blockchain_tx.clone()]).wait().unwrap();
+        let refunded = refunds();
+        assert_eq!(refunded.len(), 1);
+        assert_eq!(refunded[0].value, Amount::new(750));
+        assert_eq!(refunded[0].cr_account_id, tx.dr_account_id);
+        let group_fee = transactions_repo.get_group_fee(tx.gid).unwrap().unwrap();
+        assert_eq!(group_fee.fee, Amount::new(250));
+        assert_eq!(group_fee.charged_blockchain_fee, Amount::new(50));
+
+        // replayed confirmation and completion of the same group by `repair` don't refund it again
+        fetcher.handle_transactions(vec![blockchain_tx]).wait().unwrap();
+        refund_overcharged_fee(&*transactions_repo, tx.gid, &config).unwrap();
+        assert_eq!(refunds().len(), 1);
+        assert_eq!(transactions_repo.get_group_fee(tx.gid).unwrap().unwrap(), group_fee);
+    }
+
+    #[test]
+    fn test_refund_of_reversed_fee_is_capped() {
+        let mut config = Config::new().unwrap();
+        config.fees_options.refund_enabled = true;
+        config.fees_options.fee_upside = 2.0;
+        config.fees_options.refund_threshold = 0.2;
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let pending_blockchain_transactions_repo = Arc::new(PendingBlockchainTransactionsRepoMock::default());
+        let fetcher = BlockchainFetcher::new(
+            SharedConfig::new(config),
+            transactions_repo.clone(),
+            accounts_repo.clone(),
+            Arc::new(SeenHashesRepoMock::default()),
+            Arc::new(BlockchainTransactionsRepoMock::default()),
+            Arc::new(StrangeBlockchainTransactionsRepoMock::default()),
+            Arc::new(SmallDepositsRepoMock::default()),
+            Arc::new(PendingDepositsRepoMock::default()),
+            pending_blockchain_transactions_repo.clone(),
+            Arc::new(KeyValuesRepoMock::default()),
+            Arc::new(QuarantinedMessagesRepoMock::default()),
+            Arc::new(BlockchainClientMock::default()),
+            Arc::new(KeysClientMock::default()),
+            DbExecutorMock::default(),
+            Arc::new(TransactionPublisherMock::default()),
+            create_transactions_service(transactions_repo.clone(), accounts_repo.clone()),
+        );
+        // estimated 100 of network fee, paid 25, so 750 of 1000 would be refunded
+        let (tx, blockchain_tx) = create_withdrawal_group(&transactions_repo, &accounts_repo, &pending_blockchain_transactions_repo, 25);
+        let fee_tx = transactions_repo
+            .get_by_gid(tx.gid)
+            .unwrap()
+            .into_iter()
+            .find(|tx| tx.kind == TransactionKind::Fee)
+            .unwrap();
+        // but 900 of the fee is already returned by reversal
+        transactions_repo
+            .create(NewTransaction {
+                user_id: fee_tx.user_id,
+                dr_account_id: fee_tx.cr_account_id,
+                cr_account_id: fee_tx.dr_account_id,
+                currency: Currency::Eth,
+                value: Amount::new(900),
+                status: TransactionStatus::Done,
+                kind: TransactionKind::Fee,
+                group_kind: TransactionGroupKind::Reversal,
+                related_tx: Some(fee_tx.id),
+                ..Default::default()
+            })
+            .unwrap();
+
+        fetcher.handle_transactions(vec![blockchain_tx]).wait().unwrap();
+        let refunded: Vec<Transaction> = transactions_repo
+            .list_for_account(tx.dr_account_id, 0, 100)
+            .unwrap()
+            .into_iter()
+            .filter(|tx| tx.group_kind == TransactionGroupKind::FeeRefund)
+            .collect();
+        assert_eq!(refunded.len(), 1);
+        assert_eq!(refunded[0].value, Amount::new(100));
+        let group_fee = transactions_repo.get_group_fee(tx.gid).unwrap().unwrap();
+        assert_eq!(group_fee.fee, Amount::new(900));
+        assert_eq!(group_fee.charged_blockchain_fee, Amount::new(180));
+    }
+
+    #[test]
+    fn test_verify_withdrawal_tx_value_and_fee() {
+        let config = SharedConfig::new(Config::new().unwrap());
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let pending_blockchain_transactions_repo = Arc::new(PendingBlockchainTransactionsRepoMock::default());
+        let fetcher = BlockchainFetcher::new(
+            config,
+            transactions_repo.clone(),
+            accounts_repo.clone(),
+            Arc::new(SeenHashesRepoMock::default()),
+            Arc::new(BlockchainTransactionsRepoMock::default()),
+            Arc::new(StrangeBlockchainTransactionsRepoMock::default()),
+            Arc::new(SmallDepositsRepoMock::default()),
+            Arc::new(PendingDepositsRepoMock::default()),
+            pending_blockchain_transactions_repo.clone(),
+            Arc::new(KeyValuesRepoMock::default()),
+            Arc::new(QuarantinedMessagesRepoMock::default()),
+            Arc::new(BlockchainClientMock::default()),
+            Arc::new(KeysClientMock::default()),
+            DbExecutorMock::default(),
+            Arc::new(TransactionPublisherMock::default()),
+            create_transactions_service(transactions_repo.clone(), accounts_repo.clone()),
+        );
+
+        let from_address = BlockchainAddress::new("0x1".to_string());
+        let to_address = BlockchainAddress::new("0x2".to_string());
+        let hash = BlockchainTransactionId::new("0xabc".to_string());
+        let managed_account = accounts_repo
+            .create(NewAccount {
+                address: from_address.clone(),
+                kind: AccountKind::Dr,
+                ..Default::default()
+            })
+            .unwrap();
+        // deposit of 150 to managed account, then withdrawal of 100 leaves 50 for network fee
+        transactions_repo
+            .create(NewTransaction {
+                dr_account_id: managed_account.id,
+                currency: Currency::Eth,
+                value: Amount::new(150),
+                status: TransactionStatus::Done,
+                ..Default::default()
+            })
+            .unwrap();
+        let tx = transactions_repo
+            .create(NewTransaction {
+                cr_account_id: managed_account.id,
+                currency: Currency::Eth,
+                value: Amount::new(100),
+                blockchain_tx_id: Some(hash.clone()),
+                ..Default::default()
+            })
+            .unwrap();
+        pending_blockchain_transactions_repo
+            .create(NewPendingBlockchainTransactionDB {
+                hash: hash.clone(),
+                from_: from_address.clone(),
+                to_: to_address.clone(),
+                value: Amount::new(100),
+                ..Default::default()
+            })
+            .unwrap();
+        let blockchain_tx = |value: u128, fee: u128| BlockchainTransaction {
+            hash: hash.clone(),
+            from: vec![from_address.clone()],
+            to: vec![BlockchainTransactionEntryTo {
+                address: to_address.clone(),
+                value: Amount::new(value),
+            }],
+            block_number: 1,
+            currency: Currency::Eth,
+            fee: Amount::new(fee),
+            confirmations: 100,
+            erc20_operation_kind: None,
+            internal_transfers: vec![],
+            logs: vec![],
+        };
+
+        assert_eq!(fetcher.verify_withdrawal_tx(&tx, &blockchain_tx(100, 50)).unwrap(), None);
+        assert_eq!(
+            fetcher.verify_withdrawal_tx(&tx, &blockchain_tx(90, 10)).unwrap(),
+            Some(InvariantViolation::WithdrawalValue)
+        );
+        assert_eq!(
+            fetcher.verify_withdrawal_tx(&tx, &blockchain_tx(100, 51)).unwrap(),
+            Some(InvariantViolation::WithdrawalFee)
+        );
+    }
+
     #[test]
-    fn test_required_confirmations() {
-        let cases = [
-            (Currency::Btc, Amount::new(100_000_000), 3),                       // 6500
-            (Currency::Btc, Amount::new(10_000_000), 2),                        // 650
-            (Currency::Btc, Amount::new(5_000_000), 1),                         // 325
-            (Currency::Btc, Amount::new(1_000_000), 0),                         // 65
-            (Currency::Eth, Amount::new(21_000_000_000_000_000_000), 8),        // 4400
-            (Currency::Eth, Amount::new(2_000_000_000_000_000_000), 3),         // 400
-            (Currency::Eth, Amount::new(500_000_000_000_000_000), 2),           // 100
-            (Currency::Eth, Amount::new(50_000_000_000_000_000), 0),            // 10
-            (Currency::Stq, Amount::new(2_100_000_000_000_000_000_000_000), 9), // 5250
-            (Currency::Stq, Amount::new(210_000_000_000_000_000_000_000), 4),   // 525
-            (Currency::Stq, Amount::new(100_000_000_000_000_000_000_000), 3),   // 250
-            (Currency::Stq, Amount::new(10_000_000_000_000_000_000_000), 1),    // 25
-            (Currency::Stq, Amount::new(5_000_000_000_000_000_000_000), 0),     // 12
-        ];
-        for (currency, value, confirms) in cases.iter() {
-            assert_eq!(
-                required_confirmations(*currency, *value),
-                *confirms,
-                "Currency: {:?}, value: {:?}, confirms: {:?}",
-                *currency,
-                *value,
-                *confirms
-            );
+    fn test_small_deposit_kept_out_of_ledger() {
+        let mut config = Config::new().unwrap();
+        config.min_deposits.eth = 0.001;
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let small_deposits_repo = Arc::new(SmallDepositsRepoMock::default());
+        let fetcher = BlockchainFetcher::new(
+            SharedConfig::new(config),
+            transactions_repo.clone(),
+            accounts_repo.clone(),
+            Arc::new(SeenHashesRepoMock::default()),
+            Arc::new(BlockchainTransactionsRepoMock::default()),
+            Arc::new(StrangeBlockchainTransactionsRepoMock::default()),
+            small_deposits_repo.clone(),
+            Arc::new(PendingDepositsRepoMock::default()),
+            Arc::new(PendingBlockchainTransactionsRepoMock::default()),
+            Arc::new(KeyValuesRepoMock::default()),
+            Arc::new(QuarantinedMessagesRepoMock::default()),
+            Arc::new(BlockchainClientMock::default()),
+            Arc::new(KeysClientMock::default()),
+            DbExecutorMock::default(),
+            Arc::new(TransactionPublisherMock::default()),
+            create_transactions_service(transactions_repo.clone(), accounts_repo.clone()),
+        );
+
+        let address = BlockchainAddress::new("0x2".to_string());
+        let user_id = UserId::generate();
+        for kind in &[AccountKind::Cr, AccountKind::Dr] {
+            accounts_repo
+                .create(NewAccount {
+                    user_id,
+                    address: address.clone(),
+                    kind: *kind,
+                    ..Default::default()
+                })
+                .unwrap();
         }
+        let deposit = |hash: &str, value: u128| BlockchainTransaction {
+            hash: BlockchainTransactionId::new(hash.to_string()),
+            from: vec![BlockchainAddress::new("0x1".to_string())],
+            to: vec![BlockchainTransactionEntryTo {
+                address: address.clone(),
+                value: Amount::new(value),
+            }],
+            block_number: 1,
+            currency: Currency::Eth,
+            fee: Amount::new(0),
+            confirmations: 100,
+            erc20_operation_kind: None,
+            internal_transfers: vec![],
+            logs: vec![],
+        };
+
+        // 0.0001 eth
+        let txs = fetcher
+            .handle_transactions(vec![deposit("0xa", 100_000_000_000_000)])
+            .wait()
+            .unwrap();
+        assert!(txs.is_empty());
+        let small_deposits = small_deposits_repo.list(None, 0, 10).unwrap();
+        assert_eq!(small_deposits.len(), 1);
+        assert_eq!(small_deposits[0].user_id, user_id);
+        assert_eq!(small_deposits[0].value, Amount::new(100_000_000_000_000));
+
+        // 0.001 eth
+        let txs = fetcher
+            .handle_transactions(vec![deposit("0xb", 1_000_000_000_000_000)])
+            .wait()
+            .unwrap();
+        assert_eq!(txs.len(), 1);
+        assert_eq!(txs[0].value, Amount::new(1_000_000_000_000_000));
+        assert_eq!(small_deposits_repo.list(None, 0, 10).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_unconfirmed_deposit_is_pending() {
+        let config = Config::new().unwrap();
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let seen_hashes_repo = Arc::new(SeenHashesRepoMock::default());
+        let pending_deposits_repo = Arc::new(PendingDepositsRepoMock::default());
+        let fetcher = BlockchainFetcher::new(
+            SharedConfig::new(config),
+            transactions_repo.clone(),
+            accounts_repo.clone(),
+            seen_hashes_repo.clone(),
+            Arc::new(BlockchainTransactionsRepoMock::default()),
+            Arc::new(StrangeBlockchainTransactionsRepoMock::default()),
+            Arc::new(SmallDepositsRepoMock::default()),
+            pending_deposits_repo.clone(),
+            Arc::new(PendingBlockchainTransactionsRepoMock::default()),
+            Arc::new(KeyValuesRepoMock::default()),
+            Arc::new(QuarantinedMessagesRepoMock::default()),
+            Arc::new(BlockchainClientMock::default()),
+            Arc::new(KeysClientMock::default()),
+            DbExecutorMock::default(),
+            Arc::new(TransactionPublisherMock::default()),
+            create_transactions_service(transactions_repo.clone(), accounts_repo.clone()),
+        );
+
+        let address = BlockchainAddress::new("0x2".to_string());
+        let user_id = UserId::generate();
+        let mut cr_account = None;
+        for kind in &[AccountKind::Cr, AccountKind::Dr] {
+            let account = accounts_repo
+                .create(NewAccount {
+                    user_id,
+                    address: address.clone(),
+                    kind: *kind,
+                    ..Default::default()
+                })
+                .unwrap();
+            if *kind == AccountKind::Cr {
+                cr_account = Some(account);
+            }
+        }
+        let cr_account = cr_account.unwrap();
+        let deposit = |confirmations: usize| BlockchainTransaction {
+            hash: BlockchainTransactionId::new("0xa".to_string()),
+            from: vec![BlockchainAddress::new("0x1".to_string())],
+            to: vec![BlockchainTransactionEntryTo {
+                address: address.clone(),
+                value: Amount::new(1_000_000_000_000_000_000),
+            }],
+            block_number: 1,
+            currency: Currency::Eth,
+            fee: Amount::new(0),
+            confirmations,
+            erc20_operation_kind: None,
+            internal_transfers: vec![],
+            logs: vec![],
+        };
+
+        let txs = fetcher.handle_transactions(vec![deposit(0)]).wait().unwrap();
+        assert!(txs.is_empty());
+        let pending = pending_deposits_repo.list_for_user(user_id).unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].account_id, cr_account.id);
+        assert_eq!(pending[0].confirmations, 0);
+        // more confirmations are still pending, but the deposit is the same
+        let txs = fetcher.handle_transactions(vec![deposit(1)]).wait().unwrap();
+        assert!(txs.is_empty());
+        let updated = pending_deposits_repo.list_for_user(user_id).unwrap();
+        assert_eq!(updated.len(), 1);
+        assert_eq!(updated[0].id, pending[0].id);
+        assert_eq!(updated[0].confirmations, 1);
+
+        let txs = fetcher.handle_transactions(vec![deposit(100)]).wait().unwrap();
+        assert_eq!(txs.len(), 1);
+        assert_eq!(txs[0].id, pending[0].id);
+        assert_eq!(txs[0].cr_account_id, cr_account.id);
+        assert!(pending_deposits_repo.list_for_user(user_id).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_deposit_events_are_published() {
+        let config = Config::new().unwrap();
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let publisher = Arc::new(TransactionPublisherMock::default());
+        let fetcher = BlockchainFetcher::new(
+            SharedConfig::new(config),
+            transactions_repo.clone(),
+            accounts_repo.clone(),
+            Arc::new(SeenHashesRepoMock::default()),
+            Arc::new(BlockchainTransactionsRepoMock::default()),
+            Arc::new(StrangeBlockchainTransactionsRepoMock::default()),
+            Arc::new(SmallDepositsRepoMock::default()),
+            Arc::new(PendingDepositsRepoMock::default()),
+            Arc::new(PendingBlockchainTransactionsRepoMock::default()),
+            Arc::new(KeyValuesRepoMock::default()),
+            Arc::new(QuarantinedMessagesRepoMock::default()),
+            Arc::new(BlockchainClientMock::default()),
+            Arc::new(KeysClientMock::default()),
+            DbExecutorMock::default(),
+            publisher.clone(),
+            create_transactions_service(transactions_repo.clone(), accounts_repo.clone()),
+        );
+
+        let address = BlockchainAddress::new("0x2".to_string());
+        let user_id = UserId::generate();
+        let mut cr_account = None;
+        for kind in &[AccountKind::Cr, AccountKind::Dr] {
+            let account = accounts_repo
+                .create(NewAccount {
+                    user_id,
+                    address: address.clone(),
+                    kind: *kind,
+                    ..Default::default()
+                })
+                .unwrap();
+            if *kind == AccountKind::Cr {
+                cr_account = Some(account);
+            }
+        }
+        let cr_account = cr_account.unwrap();
+        let value = Amount::new(1_000_000_000_000_000_000);
+        let deposit = |confirmations: usize| BlockchainTransaction {
+            hash: BlockchainTransactionId::new("0xa".to_string()),
+            from: vec![BlockchainAddress::new("0x1".to_string())],
+            to: vec![BlockchainTransactionEntryTo {
+                address: address.clone(),
+                value,
+            }],
+            block_number: 1,
+            currency: Currency::Eth,
+            fee: Amount::new(0),
+            confirmations,
+            erc20_operation_kind: None,
+            internal_transfers: vec![],
+            logs: vec![],
+        };
+
+        fetcher.handle_transactions(vec![deposit(0)]).wait().unwrap();
+        let txs = fetcher.handle_transactions(vec![deposit(100)]).wait().unwrap();
+        let deposits = publisher.deposits.lock().unwrap().clone();
+        assert_eq!(deposits.len(), 2);
+        assert_eq!(deposits[0].status, TransactionStatus::Pending);
+        assert_eq!(deposits[0].confirmations, 0);
+        assert_eq!(deposits[1].status, TransactionStatus::Done);
+        assert_eq!(deposits[1].confirmations, 100);
+        for deposit in &deposits {
+            // pending and credited deposit is the same one
+            assert_eq!(deposit.id, txs[0].id);
+            assert_eq!(deposit.user_id, user_id);
+            assert_eq!(deposit.account_id, cr_account.id);
+            assert_eq!(deposit.address, address);
+            assert_eq!(deposit.value, value);
+            assert_eq!(deposit.blockchain_tx_id, BlockchainTransactionId::new("0xa".to_string()));
+        }
+    }
+
+    #[test]
+    fn test_failed_log_is_quarantined() {
+        let config = Config::new().unwrap();
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let seen_hashes_repo = Arc::new(SeenHashesRepoMock::default());
+        let quarantined_messages_repo = Arc::new(QuarantinedMessagesRepoMock::default());
+        let publisher = Arc::new(TransactionPublisherMock::default());
+        let fetcher = BlockchainFetcher::new(
+            SharedConfig::new(config),
+            transactions_repo.clone(),
+            accounts_repo.clone(),
+            seen_hashes_repo.clone(),
+            Arc::new(BlockchainTransactionsRepoMock::default()),
+            Arc::new(StrangeBlockchainTransactionsRepoMock::default()),
+            Arc::new(SmallDepositsRepoMock::default()),
+            Arc::new(PendingDepositsRepoMock::default()),
+            Arc::new(PendingBlockchainTransactionsRepoMock::default()),
+            Arc::new(KeyValuesRepoMock::default()),
+            quarantined_messages_repo.clone(),
+            Arc::new(BlockchainClientMock::default()),
+            Arc::new(KeysClientMock::default()),
+            DbExecutorMock::default(),
+            publisher.clone(),
+            create_transactions_service(transactions_repo.clone(), accounts_repo.clone()),
+        );
+
+        // deposit to the first address is credited, the second one has no cr account, so its log fails
+        let address = BlockchainAddress::new("0x2".to_string());
+        let bad_address = BlockchainAddress::new("0x3".to_string());
+        let user_id = UserId::generate();
+        for (address, kinds) in vec![
+            (address.clone(), vec![AccountKind::Cr, AccountKind::Dr]),
+            (bad_address.clone(), vec![AccountKind::Dr]),
+        ] {
+            for kind in kinds {
+                accounts_repo
+                    .create(NewAccount {
+                        user_id,
+                        address: address.clone(),
+                        currency: Currency::Stq,
+                        kind,
+                        ..Default::default()
+                    })
+                    .unwrap();
+            }
+        }
+        let value = Amount::new(10_000_000_000_000_000_000);
+        let log = |log_index: u64, to: &BlockchainAddress| BlockchainTransferLog {
+            log_index,
+            from: BlockchainAddress::new("0x1".to_string()),
+            to: to.clone(),
+            value,
+        };
+        let tx = BlockchainTransaction {
+            hash: BlockchainTransactionId::new("0xb".to_string()),
+            from: vec![BlockchainAddress::new("0x1".to_string())],
+            to: vec![],
+            block_number: 1,
+            currency: Currency::Stq,
+            fee: Amount::new(0),
+            confirmations: 0,
+            erc20_operation_kind: None,
+            internal_transfers: vec![],
+            logs: vec![log(0, &address), log(1, &bad_address)],
+        };
+
+        fetcher.handle_message(tx).wait().unwrap();
+        let deposits = publisher.deposits.lock().unwrap().clone();
+        assert_eq!(deposits.len(), 1);
+        assert_eq!(deposits[0].address, address);
+        let messages = quarantined_messages_repo.list(0, 10, false).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].currency, Currency::Stq);
+        // the log is replayed as a transaction of its own, so it must not be seen
+        let quarantined = parse_transaction(messages[0].payload.clone()).unwrap();
+        assert_eq!(quarantined.hash, BlockchainTransactionId::new("0xb:1".to_string()));
+        assert_eq!(quarantined.to[0].address, bad_address);
+        assert!(seen_hashes_repo.get(quarantined.hash, Currency::Stq).unwrap().is_none());
     }
 }
@@ -0,0 +1,130 @@
+use std::sync::Arc;
+
+use chrono::{Duration, NaiveDateTime};
+use uuid::Uuid;
+
+use super::auth::AuthService;
+use super::error::*;
+use super::exchange::ExchangeService;
+use super::ServiceFuture;
+use config::Config;
+use models::*;
+use prelude::*;
+use repos::{DbExecutor, RateLocksRepo};
+
+pub trait RateLocksService: Send + Sync + 'static {
+    /// Gets exchange rate and keeps it for the user, so that a transaction with exchange can be
+    /// created by the lock id later, until the lock expires
+    fn lock_rate(&self, token: AuthenticationToken, input: RateInput) -> ServiceFuture<RateLock>;
+}
+
+#[derive(Clone)]
+pub struct RateLocksServiceImpl<E: DbExecutor> {
+    auth_service: Arc<dyn AuthService>,
+    exchange_service: Arc<dyn ExchangeService>,
+    rate_locks_repo: Arc<dyn RateLocksRepo>,
+    ttl: Duration,
+    db_executor: E,
+}
+
+impl<E: DbExecutor> RateLocksServiceImpl<E> {
+    pub fn new(
+        config: &Config,
+        auth_service: Arc<dyn AuthService>,
+        exchange_service: Arc<dyn ExchangeService>,
+        rate_locks_repo: Arc<dyn RateLocksRepo>,
+        db_executor: E,
+    ) -> Self {
+        Self {
+            auth_service,
+            exchange_service,
+            rate_locks_repo,
+            ttl: Duration::seconds(config.exchange_options.rate_lock_ttl_secs as i64),
+            db_executor,
+        }
+    }
+}
+
+impl<E: DbExecutor> RateLocksService for RateLocksServiceImpl<E> {
+    fn lock_rate(&self, token: AuthenticationToken, input: RateInput) -> ServiceFuture<RateLock> {
+        let exchange_service = self.exchange_service.clone();
+        let rate_locks_repo = self.rate_locks_repo.clone();
+        let db_executor = self.db_executor.clone();
+        let ttl = self.ttl;
+        Box::new(self.auth_service.authenticate(token.clone()).and_then(move |user| {
+            let input_clone = input.clone();
+            exchange_service
+                .rate(token, input)
+                .map_err(ectx!(convert => input_clone))
+                .and_then(move |rate| {
+                    db_executor.execute(move || {
+                        let new_lock = new_rate_lock(user.id, rate, ttl, ::chrono::Utc::now().naive_utc())?;
+                        rate_locks_repo.create(new_lock.clone()).map_err(ectx!(convert => new_lock))
+                    })
+                })
+        }))
+    }
+}
+
+/// Lock of the rate that expires no later than the rate itself
+fn new_rate_lock(user_id: UserId, rate: Rate, ttl: Duration, now: NaiveDateTime) -> Result<NewRateLock, Error> {
+    // stale rate is bound to the exchange id of another request, so it can't be used for exchange
+    if rate.is_stale {
+        return Err(ectx!(err ErrorContext::ExchangeRateUnavailable, ErrorKind::Internal => rate));
+    }
+    Ok(NewRateLock {
+        id: Uuid::new_v4(),
+        user_id,
+        exchange_id: rate.id,
+        from_currency: rate.from,
+        to_currency: rate.to,
+        amount: rate.amount,
+        amount_currency: rate.amount_currency,
+        rate: rate.rate,
+        expires_at: ::std::cmp::min(rate.expiration, now + ttl),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rate(expiration: NaiveDateTime) -> Rate {
+        let now = ::chrono::Utc::now().naive_utc();
+        Rate {
+            id: ExchangeId::generate(),
+            from: Currency::Stq,
+            to: Currency::Eth,
+            amount: Amount::new(100),
+            amount_currency: Currency::Stq,
+            rate: 0.5,
+            expiration,
+            created_at: now,
+            updated_at: now,
+            is_stale: false,
+        }
+    }
+
+    #[test]
+    fn test_new_rate_lock() {
+        let user_id = UserId::generate();
+        let now = ::chrono::Utc::now().naive_utc();
+        let ttl = Duration::seconds(60);
+
+        let long_rate = rate(now + Duration::seconds(600));
+        let lock = new_rate_lock(user_id, long_rate.clone(), ttl, now).unwrap();
+        assert_eq!(lock.expires_at, now + ttl);
+        assert_eq!(lock.exchange_id, long_rate.id);
+        assert_eq!(lock.user_id, user_id);
+
+        let short_rate = rate(now + Duration::seconds(10));
+        let lock = new_rate_lock(user_id, short_rate.clone(), ttl, now).unwrap();
+        assert_eq!(lock.expires_at, short_rate.expiration);
+
+        let stale_rate = Rate {
+            is_stale: true,
+            ..long_rate
+        };
+        assert!(new_rate_lock(user_id, stale_rate, ttl, now).is_err());
+    }
+}
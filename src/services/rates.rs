@@ -0,0 +1,109 @@
+use std::sync::Arc;
+
+use serde_json::{Map, Value};
+
+use super::error::*;
+use config::SharedConfig;
+use models::*;
+use prelude::*;
+use repos::{KeyValuesRepo, KeyValuesRepoExt};
+
+pub trait RatesService: Send + Sync + 'static {
+    /// Approximate usd price of one btc, eth or stq. These are the prices kept up to date
+    /// for confirmation thresholds, either overridden in runtime or taken from config
+    fn usd_rate(&self, currency: Currency) -> Result<f64, Error>;
+    /// Stores current usd rate in transaction meta, so that its fiat value stays the same
+    /// as it was at the time of creation
+    fn with_usd_rate(&self, tx: NewTransaction) -> Result<NewTransaction, Error>;
+}
+
+#[derive(Clone)]
+pub struct RatesServiceImpl {
+    key_values_repo: Arc<dyn KeyValuesRepo>,
+    config: SharedConfig,
+}
+
+impl RatesServiceImpl {
+    pub fn new(key_values_repo: Arc<dyn KeyValuesRepo>, config: SharedConfig) -> Self {
+        Self { key_values_repo, config }
+    }
+}
+
+impl RatesService for RatesServiceImpl {
+    fn usd_rate(&self, currency: Currency) -> Result<f64, Error> {
+        let thresholds = self
+            .key_values_repo
+            .get_confirmation_thresholds()
+            .map_err(ectx!(try convert => currency))?
+            .unwrap_or_else(|| self.config.get().confirmations.clone());
+        let usd_rate = match currency {
+            Currency::Btc => thresholds.usd_per_btc,
+            Currency::Eth => thresholds.usd_per_eth,
+            Currency::Stq => thresholds.usd_per_stq,
+        };
+        Ok(usd_rate)
+    }
+
+    fn with_usd_rate(&self, mut tx: NewTransaction) -> Result<NewTransaction, Error> {
+        let mut fields = match tx.meta.take() {
+            None => Map::new(),
+            Some(Value::Object(fields)) => fields,
+            // meta that is not an object is not ours to extend
+            Some(meta) => {
+                tx.meta = Some(meta);
+                return Ok(tx);
+            }
+        };
+        let usd_rate = self.usd_rate(tx.currency)?;
+        fields.insert(USD_RATE_META_KEY.to_string(), Value::from(usd_rate));
+        tx.meta = Some(Value::Object(fields));
+        Ok(tx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::Config;
+    use repos::*;
+
+    #[test]
+    fn test_with_usd_rate() {
+        let config = Config::new().unwrap();
+        let key_values_repo = Arc::new(KeyValuesRepoMock::default());
+        let shared_config = SharedConfig::new(config.clone());
+        let service = RatesServiceImpl::new(key_values_repo.clone(), shared_config.clone());
+        let mut tx = NewTransaction::default();
+        tx.currency = Currency::Btc;
+        let tx = service.with_usd_rate(tx).unwrap();
+        assert_eq!(tx.meta, Some(json!({ "usdRate": config.confirmations.usd_per_btc })));
+
+        // runtime override takes precedence over config
+        let mut thresholds = config.confirmations.clone();
+        thresholds.usd_per_btc = 4000.0;
+        key_values_repo.set_confirmation_thresholds(thresholds).unwrap();
+        let mut tx = NewTransaction::default();
+        tx.currency = Currency::Btc;
+        tx.meta = Some(json!({ "orderId": "42" }));
+        let tx = service.with_usd_rate(tx).unwrap();
+        assert_eq!(tx.meta, Some(json!({ "orderId": "42", "usdRate": 4000.0 })));
+
+        let mut tx = NewTransaction::default();
+        tx.meta = Some(json!("reversal of tx"));
+        let tx = service.with_usd_rate(tx).unwrap();
+        assert_eq!(tx.meta, Some(json!("reversal of tx")));
+    }
+
+    #[test]
+    fn test_usd_rate_follows_reloaded_config() {
+        let config = Config::new().unwrap();
+        let shared_config = SharedConfig::new(config.clone());
+        let service = RatesServiceImpl::new(Arc::new(KeyValuesRepoMock::default()), shared_config.clone());
+        assert_eq!(service.usd_rate(Currency::Eth).unwrap(), config.confirmations.usd_per_eth);
+
+        let mut reloaded = config.clone();
+        reloaded.confirmations.usd_per_eth += 100.0;
+        shared_config.reload(reloaded).unwrap();
+        assert_eq!(service.usd_rate(Currency::Eth).unwrap(), config.confirmations.usd_per_eth + 100.0);
+    }
+}
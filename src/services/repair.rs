@@ -0,0 +1,650 @@
+use std::fmt;
+use std::sync::Arc;
+
+use chrono::Duration;
+use futures::future::{self, Either};
+
+use super::error::*;
+use super::rabbit::{complete_pending_transaction, refund_overcharged_fee};
+use super::system::SystemService;
+use super::transactions::ConverterService;
+use super::ServiceFuture;
+use client::BlockchainClient;
+use config::Config;
+use models::*;
+use prelude::*;
+use repos::{
+    AccountsRepo, BlockchainTransactionsRepo, DbExecutor, Isolation, PendingBlockchainTransactionsRepo, SeenHashesRepo, TransactionsRepo,
+};
+use serde_json;
+use utils::log_error;
+
+/// Broken state found by `repair` scan together with the way to fix it
+#[derive(Debug, Clone)]
+pub enum RepairAction {
+    /// Approval transfer is still pending, though fetcher has already seen its blockchain tx
+    CompleteApproval {
+        transaction: Transaction,
+        pending: PendingBlockchainTransactionDB,
+    },
+    /// Some withdrawals of the group are done, but the rest have lost their pending blockchain tx
+    /// and will never be confirmed, so they need to be reversed along with their part of fees
+    ReverseWithdrawals { gid: TransactionId, lost: Vec<Transaction> },
+    /// Pending blockchain tx that no ledger transaction refers to
+    RemoveOrphanedPending { pending: PendingBlockchainTransactionDB },
+}
+
+impl fmt::Display for RepairAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RepairAction::CompleteApproval { transaction, pending } => write!(
+                f,
+                "complete approval transaction {} confirmed by blockchain tx {} ({})",
+                transaction.id, pending.hash, pending.currency
+            ),
+            RepairAction::ReverseWithdrawals { gid, lost } => {
+                let ids: Vec<String> = lost.iter().map(|tx| tx.id.to_string()).collect();
+                write!(f, "reverse lost withdrawal transactions [{}] of group {}", ids.join(", "), gid)
+            }
+            RepairAction::RemoveOrphanedPending { pending } => {
+                write!(f, "remove orphaned pending blockchain tx {} ({})", pending.hash, pending.currency)
+            }
+        }
+    }
+}
+
+pub trait RepairService: Send + Sync + 'static {
+    /// Finds all repairable states among transactions, that have been pending for more than `age`
+    fn scan(&self, age: Duration) -> ServiceFuture<Vec<RepairAction>>;
+    /// Applies fix in a separate db transaction
+    fn apply(&self, action: RepairAction) -> ServiceFuture<()>;
+    /// Reverses lost withdrawals of partially sent groups, that have been pending for more than `age`.
+    /// A group is reversed only if blockchain gateway doesn't know any of its lost transactions, otherwise
+    /// it's left for manual `repair`. Returns corrected groups to be published
+    fn recover_withdrawals(&self, age: Duration) -> ServiceFuture<Vec<TransactionOut>>;
+}
+
+#[derive(Clone)]
+pub struct RepairServiceImpl<E: DbExecutor> {
+    config: Arc<Config>,
+    transactions_repo: Arc<TransactionsRepo>,
+    accounts_repo: Arc<AccountsRepo>,
+    blockchain_transactions_repo: Arc<BlockchainTransactionsRepo>,
+    pending_blockchain_transactions_repo: Arc<PendingBlockchainTransactionsRepo>,
+    seen_hashes_repo: Arc<SeenHashesRepo>,
+    system_service: Arc<SystemService>,
+    converter_service: Arc<ConverterService>,
+    blockchain_client: Arc<BlockchainClient>,
+    db_executor: E,
+}
+
+impl<E: DbExecutor> RepairServiceImpl<E> {
+    pub fn new(
+        config: Arc<Config>,
+        transactions_repo: Arc<TransactionsRepo>,
+        accounts_repo: Arc<AccountsRepo>,
+        blockchain_transactions_repo: Arc<BlockchainTransactionsRepo>,
+        pending_blockchain_transactions_repo: Arc<PendingBlockchainTransactionsRepo>,
+        seen_hashes_repo: Arc<SeenHashesRepo>,
+        system_service: Arc<SystemService>,
+        converter_service: Arc<ConverterService>,
+        blockchain_client: Arc<BlockchainClient>,
+        db_executor: E,
+    ) -> Self {
+        Self {
+            config,
+            transactions_repo,
+            accounts_repo,
+            blockchain_transactions_repo,
+            pending_blockchain_transactions_repo,
+            seen_hashes_repo,
+            system_service,
+            converter_service,
+            blockchain_client,
+            db_executor,
+        }
+    }
+
+    fn scan_transactions(&self, age: Duration) -> Result<Vec<RepairAction>, Error> {
+        let pending_txs = self.transactions_repo.list_pending_older_than(age)?;
+        let mut actions = vec![];
+        let mut withdrawal_gids = vec![];
+        for tx in pending_txs {
+            match tx.kind {
+                TransactionKind::ApprovalTransfer => {
+                    let hash = match tx.blockchain_tx_id.clone() {
+                        Some(hash) => hash,
+                        None => continue,
+                    };
+                    if let Some(pending) = self.pending_blockchain_transactions_repo.get(hash.clone())? {
+                        if self.seen_hashes_repo.get(hash, pending.currency)?.is_some() {
+                            actions.push(RepairAction::CompleteApproval { transaction: tx, pending });
+                        }
+                    }
+                }
+                TransactionKind::Withdrawal => {
+                    if !withdrawal_gids.contains(&tx.gid) {
+                        withdrawal_gids.push(tx.gid);
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        for gid in withdrawal_gids {
+            let withdrawals: Vec<Transaction> = self
+                .transactions_repo
+                .get_by_gid(gid)?
+                .into_iter()
+                .filter(|tx| tx.kind == TransactionKind::Withdrawal)
+                .collect();
+            if !withdrawals.iter().any(|tx| tx.status == TransactionStatus::Done) {
+                continue;
+            }
+            let mut lost = vec![];
+            for tx in withdrawals {
+                if tx.status != TransactionStatus::Pending {
+                    continue;
+                }
+                if let Some(hash) = tx.blockchain_tx_id.clone() {
+                    if self.pending_blockchain_transactions_repo.get(hash)?.is_none() {
+                        lost.push(tx);
+                    }
+                }
+            }
+            if !lost.is_empty() {
+                actions.push(RepairAction::ReverseWithdrawals { gid, lost });
+            }
+        }
+
+        for stale in self.pending_blockchain_transactions_repo.list_older_than(age, None)? {
+            // erc20 approvals are not reflected in ledger
+            if stale.pending.erc20_operation_kind == Some(Erc20OperationKind::Approve) {
+                continue;
+            }
+            if stale.transaction.is_none() {
+                actions.push(RepairAction::RemoveOrphanedPending { pending: stale.pending });
+            }
+        }
+        Ok(actions)
+    }
+
+    fn apply_action(&self, action: RepairAction) -> Result<(), Error> {
+        match action {
+            RepairAction::CompleteApproval { transaction, pending } => {
+                let blockchain_tx: BlockchainTransaction = pending.into();
+                complete_pending_transaction(
+                    &*self.transactions_repo,
+                    &*self.accounts_repo,
+                    &*self.blockchain_transactions_repo,
+                    &*self.pending_blockchain_transactions_repo,
+                    &*self.system_service,
+                    &transaction,
+                    &blockchain_tx,
+                )?;
+                refund_overcharged_fee(&*self.transactions_repo, transaction.gid, &self.config)
+            }
+            RepairAction::ReverseWithdrawals { gid, lost } => self.reverse_withdrawals(gid, lost),
+            RepairAction::RemoveOrphanedPending { pending } => {
+                self.pending_blockchain_transactions_repo.delete(pending.hash)?;
+                Ok(())
+            }
+        }
+    }
+
+    fn reverse_withdrawals(&self, gid: TransactionId, lost: Vec<Transaction>) -> Result<(), Error> {
+        let tree = self.transactions_repo.get_group_with_related(gid)?;
+        let group = tree.transactions.clone();
+        let total_amount = group
+            .iter()
+            .filter(|tx| tx.kind == TransactionKind::Withdrawal)
+            .fold(Some(Amount::new(0)), |acc, tx| acc.and_then(|acc| acc.checked_add(tx.value)))
+            .ok_or(ectx!(try err ErrorContext::BalanceOverflow, ErrorKind::Internal => gid))?;
+        let lost_amount = lost
+            .iter()
+            .fold(Some(Amount::new(0)), |acc, tx| acc.and_then(|acc| acc.checked_add(tx.value)))
+            .ok_or(ectx!(try err ErrorContext::BalanceOverflow, ErrorKind::Internal => gid))?;
+
+        let reversal_gid = TransactionId::generate();
+        for tx in lost {
+            let hash = tx
+                .blockchain_tx_id
+                .clone()
+                .ok_or(ectx!(try err ErrorContext::InvalidTransactionStructure, ErrorKind::Internal => tx))?;
+            // status may have changed since the scan
+            let current = self.transactions_repo.get(tx.id)?;
+            if current.map(|current| current.status) != Some(TransactionStatus::Pending) {
+                return Err(ectx!(err ErrorContext::InvalidTransactionStructure, ErrorKind::Internal => tx));
+            }
+            let payload = NewTransaction {
+                id: TransactionId::generate(),
+                gid: reversal_gid,
+                user_id: tx.user_id,
+                dr_account_id: tx.cr_account_id,
+                cr_account_id: tx.dr_account_id,
+                currency: tx.currency,
+                value: tx.value,
+                status: TransactionStatus::Done,
+                blockchain_tx_id: Some(hash.clone()),
+                kind: TransactionKind::Withdrawal,
+                group_kind: TransactionGroupKind::Reversal,
+                related_tx: Some(tx.id),
+                meta: Some(serde_json::Value::String(format!(
+                    "reversal of lost withdrawal transaction with id {}",
+                    tx.id
+                ))),
+            };
+            self.transactions_repo.create(payload)?;
+            self.transactions_repo.update_status(hash, TransactionStatus::Done)?;
+        }
+
+        if let Some(fee_tx) = group.into_iter().find(|tx| tx.kind == TransactionKind::Fee) {
+            let value = if lost_amount == total_amount {
+                fee_tx.value
+            } else {
+                let value = (fee_tx.value.raw() as f64) * (lost_amount.raw() as f64 / total_amount.raw() as f64);
+                Amount::new(value as u128)
+            };
+            // part of the fee might have been returned by reversal of withdrawals lost earlier or by fee refund
+            let reversed = tree
+                .flatten()
+                .into_iter()
+                .filter(|tx| {
+                    (tx.group_kind == TransactionGroupKind::Reversal || tx.group_kind == TransactionGroupKind::FeeRefund)
+                        && tx.related_tx == Some(fee_tx.id)
+                })
+                .fold(0u128, |acc, tx| acc.saturating_add(tx.value.raw()));
+            let value = Amount::new(::std::cmp::min(value.raw(), fee_tx.value.raw().saturating_sub(reversed)));
+            let payload = NewTransaction {
+                id: TransactionId::generate(),
+                gid: reversal_gid,
+                user_id: fee_tx.user_id,
+                dr_account_id: fee_tx.cr_account_id,
+                cr_account_id: fee_tx.dr_account_id,
+                currency: fee_tx.currency,
+                value,
+                status: TransactionStatus::Done,
+                blockchain_tx_id: fee_tx.blockchain_tx_id.clone(),
+                kind: TransactionKind::Fee,
+                group_kind: TransactionGroupKind::Reversal,
+                related_tx: Some(fee_tx.id),
+                meta: Some(serde_json::Value::String(format!(
+                    "reversal of withdrawal fee transaction with id {}",
+                    fee_tx.id
+                ))),
+            };
+            self.transactions_repo.create(payload)?;
+        }
+        Ok(())
+    }
+}
+
+impl<E: DbExecutor> RepairService for RepairServiceImpl<E> {
+    fn scan(&self, age: Duration) -> ServiceFuture<Vec<RepairAction>> {
+        let self_clone = self.clone();
+        Box::new(
+            self.db_executor
+                .execute_transaction_with_isolation(Isolation::RepeatableRead, move || self_clone.scan_transactions(age)),
+        )
+    }
+
+    fn apply(&self, action: RepairAction) -> ServiceFuture<()> {
+        let self_clone = self.clone();
+        Box::new(
+            self.db_executor
+                .execute_transaction_with_isolation(Isolation::Serializable, move || self_clone.apply_action(action)),
+        )
+    }
+
+    fn recover_withdrawals(&self, age: Duration) -> ServiceFuture<Vec<TransactionOut>> {
+        let self_clone = self.clone();
+        Box::new(self.scan(age).and_then(move |actions| {
+            let groups: Vec<_> = actions
+                .into_iter()
+                .filter_map(|action| match action {
+                    RepairAction::ReverseWithdrawals { gid, lost } => Some((gid, lost)),
+                    _ => None,
+                })
+                .collect();
+            futures::stream::iter_ok(groups)
+                .and_then(move |(gid, lost)| self_clone.recover_withdrawal_group(gid, lost))
+                .filter_map(|tx_out| tx_out)
+                .collect()
+        }))
+    }
+}
+
+impl<E: DbExecutor> RepairServiceImpl<E> {
+    // Lost withdrawal may still be in blockchain, e.g. if its pending tx was removed by mistake,
+    // so the group is reversed only after gateway confirms that none of them is known
+    fn recover_withdrawal_group(
+        &self,
+        gid: TransactionId,
+        lost: Vec<Transaction>,
+    ) -> impl Future<Item = Option<TransactionOut>, Error = Error> + Send {
+        let blockchain_client = self.blockchain_client.clone();
+        let transactions_repo = self.transactions_repo.clone();
+        let converter_service = self.converter_service.clone();
+        let db_executor = self.db_executor.clone();
+        let self_clone = self.clone();
+        let hashes: Vec<_> = lost
+            .iter()
+            .filter_map(|tx| tx.blockchain_tx_id.clone().map(|hash| (hash, tx.currency)))
+            .collect();
+        futures::stream::iter_ok(hashes)
+            .and_then(move |(hash, currency)| {
+                let hash_clone = hash.clone();
+                blockchain_client
+                    .get_transaction(hash.clone(), currency)
+                    .map_err(ectx!(convert => hash_clone, currency))
+                    .map(move |blockchain_tx| blockchain_tx.map(|_| hash))
+            })
+            .filter_map(|hash| hash)
+            .collect()
+            .and_then(move |in_blockchain| {
+                let action = RepairAction::ReverseWithdrawals { gid, lost };
+                if !in_blockchain.is_empty() {
+                    let hashes: Vec<String> = in_blockchain.iter().map(|hash| hash.to_string()).collect();
+                    warn!(
+                        "Not going to {}, blockchain txs [{}] are known to gateway",
+                        action,
+                        hashes.join(", ")
+                    );
+                    return Either::A(future::ok(None));
+                }
+                Either::B(self_clone.apply(action).and_then(move |_| {
+                    db_executor.execute(move || {
+                        let group = transactions_repo.get_by_gid(gid)?;
+                        // the group is reversed anyway, failing to convert it only means there's nothing to publish
+                        match converter_service.convert_transaction(group) {
+                            Ok(tx_out) => Ok(Some(tx_out)),
+                            Err(e) => {
+                                log_error(&e);
+                                Ok(None)
+                            }
+                        }
+                    })
+                }))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use client::BlockchainClientMock;
+    use repos::*;
+    use services::*;
+    use tokio_core::reactor::Core;
+
+    fn create_repair_service(
+        transactions_repo: Arc<TransactionsRepoMock>,
+        accounts_repo: Arc<AccountsRepoMock>,
+        blockchain_client: BlockchainClientMock,
+    ) -> RepairServiceImpl<DbExecutorMock> {
+        let blockchain_transactions_repo = Arc::new(BlockchainTransactionsRepoMock::default());
+        let pending_blockchain_transactions_repo = Arc::new(PendingBlockchainTransactionsRepoMock::default());
+        let system_service = Arc::new(SystemServiceImpl::new(accounts_repo.clone()));
+        let converter_service = Arc::new(ConverterServiceImpl::new(
+            accounts_repo.clone(),
+            pending_blockchain_transactions_repo.clone(),
+            blockchain_transactions_repo.clone(),
+            system_service.clone(),
+        ));
+        RepairServiceImpl::new(
+            Arc::new(Config::new().unwrap()),
+            transactions_repo,
+            accounts_repo,
+            blockchain_transactions_repo,
+            pending_blockchain_transactions_repo,
+            Arc::new(SeenHashesRepoMock::default()),
+            system_service,
+            converter_service,
+            Arc::new(blockchain_client),
+            DbExecutorMock::default(),
+        )
+    }
+
+    // Withdrawal group of 2 x 50 with a fee of 10, the first one is confirmed,
+    // the second one has lost its pending blockchain tx
+    fn create_partially_lost_withdrawal(
+        service: &RepairServiceImpl<DbExecutorMock>,
+        transactions_repo: &TransactionsRepoMock,
+        accounts_repo: &AccountsRepoMock,
+    ) -> (Account, Transaction) {
+        let user_id = UserId::generate();
+        let account = accounts_repo
+            .create(NewAccount {
+                user_id,
+                currency: Currency::Eth,
+                ..Default::default()
+            })
+            .unwrap();
+        let dr_account = accounts_repo
+            .create(NewAccount {
+                user_id,
+                currency: Currency::Eth,
+                kind: AccountKind::Dr,
+                ..Default::default()
+            })
+            .unwrap();
+        let fees_account = accounts_repo
+            .create(NewAccount {
+                currency: Currency::Eth,
+                ..Default::default()
+            })
+            .unwrap();
+        transactions_repo
+            .create(NewTransaction {
+                id: TransactionId::generate(),
+                gid: TransactionId::generate(),
+                user_id,
+                dr_account_id: dr_account.id,
+                cr_account_id: account.id,
+                currency: Currency::Eth,
+                value: Amount::new(1000),
+                status: TransactionStatus::Done,
+                kind: TransactionKind::Deposit,
+                group_kind: TransactionGroupKind::Deposit,
+                ..Default::default()
+            })
+            .unwrap();
+
+        let gid = TransactionId::generate();
+        transactions_repo
+            .create(NewTransaction {
+                id: gid,
+                gid,
+                user_id,
+                dr_account_id: account.id,
+                cr_account_id: fees_account.id,
+                currency: Currency::Eth,
+                value: Amount::new(10),
+                status: TransactionStatus::Done,
+                kind: TransactionKind::Fee,
+                group_kind: TransactionGroupKind::Withdrawal,
+                ..Default::default()
+            })
+            .unwrap();
+        let withdrawal = |hash: &str, status: TransactionStatus| NewTransaction {
+            id: TransactionId::generate(),
+            gid,
+            user_id,
+            dr_account_id: account.id,
+            cr_account_id: dr_account.id,
+            currency: Currency::Eth,
+            value: Amount::new(50),
+            status,
+            blockchain_tx_id: Some(BlockchainTransactionId::new(hash.to_string())),
+            kind: TransactionKind::Withdrawal,
+            group_kind: TransactionGroupKind::Withdrawal,
+            ..Default::default()
+        };
+        transactions_repo.create(withdrawal("0x1", TransactionStatus::Done)).unwrap();
+        let lost = transactions_repo.create(withdrawal("0x2", TransactionStatus::Pending)).unwrap();
+        service
+            .blockchain_transactions_repo
+            .create(
+                BlockchainTransaction {
+                    hash: BlockchainTransactionId::new("0x1".to_string()),
+                    from: vec![account.address.clone()],
+                    to: vec![BlockchainTransactionEntryTo {
+                        address: BlockchainAddress::new("0xdest".to_string()),
+                        value: Amount::new(50),
+                    }],
+                    block_number: 1,
+                    currency: Currency::Eth,
+                    fee: Amount::new(0),
+                    confirmations: 100,
+                    erc20_operation_kind: None,
+                    internal_transfers: vec![],
+                    logs: vec![],
+                }
+                .into(),
+            )
+            .unwrap();
+        (account, lost)
+    }
+
+    #[test]
+    fn test_completed_transaction_refunds_overcharged_fee() {
+        let mut core = Core::new().unwrap();
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let service = create_repair_service(transactions_repo.clone(), accounts_repo.clone(), BlockchainClientMock::default());
+        let account = accounts_repo.create(NewAccount::default()).unwrap();
+        let dr_account = accounts_repo
+            .create(NewAccount {
+                kind: AccountKind::Dr,
+                ..Default::default()
+            })
+            .unwrap();
+        let fees_account = accounts_repo.create(NewAccount::default()).unwrap();
+        accounts_repo
+            .set_system_role(fees_account.id, Some(SystemAccountKind::Fees))
+            .unwrap();
+        let gid = TransactionId::generate();
+        transactions_repo
+            .create(NewTransaction {
+                gid,
+                dr_account_id: account.id,
+                cr_account_id: fees_account.id,
+                currency: Currency::Eth,
+                value: Amount::new(1000),
+                status: TransactionStatus::Done,
+                kind: TransactionKind::Fee,
+                group_kind: TransactionGroupKind::Withdrawal,
+                ..Default::default()
+            })
+            .unwrap();
+        let hash = BlockchainTransactionId::new("0x1".to_string());
+        let transaction = transactions_repo
+            .create(NewTransaction {
+                gid,
+                dr_account_id: account.id,
+                cr_account_id: dr_account.id,
+                currency: Currency::Eth,
+                value: Amount::new(100),
+                blockchain_tx_id: Some(hash.clone()),
+                kind: TransactionKind::Withdrawal,
+                group_kind: TransactionGroupKind::Withdrawal,
+                ..Default::default()
+            })
+            .unwrap();
+        // estimated 100 of network fee with upside 2, paid 25
+        transactions_repo
+            .create_group_fee(NewGroupFee {
+                gid,
+                fee_currency: Currency::Eth,
+                fee: Amount::new(1000),
+                blockchain_fee_currency: Currency::Eth,
+                charged_blockchain_fee: Amount::new(200),
+                blockchain_fee: Amount::new(0),
+            })
+            .unwrap();
+        let pending = service
+            .pending_blockchain_transactions_repo
+            .create(NewPendingBlockchainTransactionDB {
+                hash,
+                from_: dr_account.address.clone(),
+                to_: BlockchainAddress::new("0xdest".to_string()),
+                value: Amount::new(100),
+                fee: Amount::new(25),
+                ..Default::default()
+            })
+            .unwrap();
+
+        core.run(service.apply(RepairAction::CompleteApproval { transaction, pending }))
+            .unwrap();
+        let refunds: Vec<_> = transactions_repo
+            .list_for_account(account.id, 0, 100)
+            .unwrap()
+            .into_iter()
+            .filter(|tx| tx.group_kind == TransactionGroupKind::FeeRefund)
+            .collect();
+        assert_eq!(refunds.len(), 1);
+        assert_eq!(refunds[0].value, Amount::new(750));
+        assert_eq!(
+            transactions_repo.get_group_fee(gid).unwrap().unwrap().blockchain_fee,
+            Amount::new(25)
+        );
+    }
+
+    #[test]
+    fn test_recover_withdrawals() {
+        let mut core = Core::new().unwrap();
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let service = create_repair_service(transactions_repo.clone(), accounts_repo.clone(), BlockchainClientMock::default());
+        let (account, lost) = create_partially_lost_withdrawal(&service, &transactions_repo, &accounts_repo);
+
+        let recovered = core.run(service.recover_withdrawals(Duration::zero())).unwrap();
+        assert_eq!(recovered.len(), 1);
+        assert_eq!(recovered[0].id, lost.gid);
+        assert_eq!(transactions_repo.get(lost.id).unwrap().unwrap().status, TransactionStatus::Done);
+        let reversals: Vec<_> = transactions_repo
+            .list_for_account(account.id, 0, 100)
+            .unwrap()
+            .into_iter()
+            .filter(|tx| tx.group_kind == TransactionGroupKind::Reversal)
+            .collect();
+        assert_eq!(reversals.len(), 2);
+        assert!(reversals.iter().any(|tx| tx.related_tx == Some(lost.id)));
+        // lost withdrawal and half of the fee are returned
+        assert_eq!(
+            transactions_repo.get_account_balance(account.id, AccountKind::Cr).unwrap(),
+            Amount::new(1000 - 110 + 50 + 5)
+        );
+        // nothing is left to recover
+        assert!(core.run(service.recover_withdrawals(Duration::zero())).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_recover_withdrawals_known_to_gateway() {
+        let mut core = Core::new().unwrap();
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let blockchain_client = BlockchainClientMock::default();
+        let service = create_repair_service(transactions_repo.clone(), accounts_repo.clone(), blockchain_client.clone());
+        let (account, lost) = create_partially_lost_withdrawal(&service, &transactions_repo, &accounts_repo);
+        blockchain_client.transactions.lock().unwrap().push(BlockchainTransaction {
+            hash: lost.blockchain_tx_id.clone().unwrap(),
+            from: vec![account.address.clone()],
+            to: vec![],
+            block_number: 2,
+            currency: Currency::Eth,
+            fee: Amount::new(0),
+            confirmations: 0,
+            erc20_operation_kind: None,
+            internal_transfers: vec![],
+            logs: vec![],
+        });
+
+        assert!(core.run(service.recover_withdrawals(Duration::zero())).unwrap().is_empty());
+        assert_eq!(transactions_repo.get(lost.id).unwrap().unwrap().status, TransactionStatus::Pending);
+        assert_eq!(
+            transactions_repo.get_account_balance(account.id, AccountKind::Cr).unwrap(),
+            Amount::new(1000 - 110)
+        );
+    }
+}
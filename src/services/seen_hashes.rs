@@ -0,0 +1,183 @@
+use std::sync::Arc;
+
+use chrono::{Duration, Utc};
+
+use super::error::*;
+use super::ServiceFuture;
+use config::SeenHashesRetention;
+use models::*;
+use prelude::*;
+use repos::{DbExecutor, KeyValuesRepo, KeyValuesRepoExt, SeenHashesRepo};
+
+const CURRENCIES: [Currency; 3] = [Currency::Btc, Currency::Eth, Currency::Stq];
+
+/// Result of pruning seen hashes of one currency
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrunedSeenHashes {
+    pub currency: Currency,
+    pub watermark: Option<i64>,
+    pub pruned_count: u64,
+}
+
+pub trait SeenHashesService: Send + Sync + 'static {
+    /// Deletes seen hashes that are behind retention of their currency
+    fn prune(&self) -> ServiceFuture<Vec<PrunedSeenHashes>>;
+}
+
+#[derive(Clone)]
+pub struct SeenHashesServiceImpl<E: DbExecutor> {
+    seen_hashes_repo: Arc<SeenHashesRepo>,
+    key_values_repo: Arc<KeyValuesRepo>,
+    retention: SeenHashesRetention,
+    db_executor: E,
+}
+
+impl<E: DbExecutor> SeenHashesServiceImpl<E> {
+    pub fn new(
+        seen_hashes_repo: Arc<SeenHashesRepo>,
+        key_values_repo: Arc<KeyValuesRepo>,
+        retention: SeenHashesRetention,
+        db_executor: E,
+    ) -> Self {
+        Self {
+            seen_hashes_repo,
+            key_values_repo,
+            retention,
+            db_executor,
+        }
+    }
+
+    fn prune_currency(&self, currency: Currency) -> Result<PrunedSeenHashes, Error> {
+        let created_before = Utc::now().naive_utc() - Duration::days(self.retention.keep_days as i64);
+        let tip = self.seen_hashes_repo.max_block_number(currency)?;
+        // blocks with hashes seen within `keep_days` stay above watermark, otherwise fetcher would skip them
+        // while their hashes are still kept for deduplication
+        let oldest_kept = self.seen_hashes_repo.min_block_number_since(currency, created_before)?;
+        let old_watermark = self.key_values_repo.get_seen_hashes_watermark(currency)?;
+        let new_watermark = tip.map(|tip| {
+            let watermark = tip - self.retention.keep_blocks(currency) as i64;
+            oldest_kept.map(|oldest_kept| watermark.min(oldest_kept)).unwrap_or(watermark)
+        });
+        let watermark = match (old_watermark, new_watermark) {
+            (Some(old), Some(new)) if new > old => new,
+            (Some(old), _) => old,
+            (None, Some(new)) if new > 0 => new,
+            _ => {
+                return Ok(PrunedSeenHashes {
+                    currency,
+                    watermark: None,
+                    pruned_count: 0,
+                })
+            }
+        };
+        // watermark is saved in the same db transaction, so that pruned hashes are never lost
+        // for deduplication: transactions before watermark are skipped by fetcher
+        if Some(watermark) != old_watermark {
+            self.key_values_repo.set_seen_hashes_watermark(currency, watermark)?;
+        }
+        let pruned_count = self.seen_hashes_repo.prune(currency, watermark, created_before)?;
+        Ok(PrunedSeenHashes {
+            currency,
+            watermark: Some(watermark),
+            pruned_count,
+        })
+    }
+}
+
+impl<E: DbExecutor> SeenHashesService for SeenHashesServiceImpl<E> {
+    fn prune(&self) -> ServiceFuture<Vec<PrunedSeenHashes>> {
+        let self_clone = self.clone();
+        Box::new(self.db_executor.execute_transaction(move || {
+            CURRENCIES
+                .iter()
+                .map(|currency| self_clone.prune_currency(*currency))
+                .collect::<Result<Vec<_>, Error>>()
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use repos::*;
+    use tokio_core::reactor::Core;
+
+    fn create_retention() -> SeenHashesRetention {
+        SeenHashesRetention {
+            keep_days: 30,
+            btc_keep_blocks: 10,
+            eth_keep_blocks: 10,
+            stq_keep_blocks: 10,
+            prune_interval_secs: 3600,
+        }
+    }
+
+    #[test]
+    fn test_prune_keeps_watermark_monotonic() {
+        let mut core = Core::new().unwrap();
+        let key_values_repo = Arc::new(KeyValuesRepoMock::default());
+        key_values_repo.set_seen_hashes_watermark(Currency::Btc, 100).unwrap();
+        let service = SeenHashesServiceImpl::new(
+            Arc::new(SeenHashesRepoMock::default()),
+            key_values_repo.clone(),
+            create_retention(),
+            DbExecutorMock::default(),
+        );
+        let res = core.run(service.prune()).unwrap();
+        // no hashes are seen, so btc keeps its watermark, and others don't get one
+        assert_eq!(
+            res,
+            vec![
+                PrunedSeenHashes {
+                    currency: Currency::Btc,
+                    watermark: Some(100),
+                    pruned_count: 0,
+                },
+                PrunedSeenHashes {
+                    currency: Currency::Eth,
+                    watermark: None,
+                    pruned_count: 0,
+                },
+                PrunedSeenHashes {
+                    currency: Currency::Stq,
+                    watermark: None,
+                    pruned_count: 0,
+                },
+            ]
+        );
+        assert_eq!(key_values_repo.get_seen_hashes_watermark(Currency::Btc).unwrap(), Some(100));
+    }
+
+    #[test]
+    fn test_prune_watermark_behind_recent_hashes() {
+        let mut core = Core::new().unwrap();
+        let seen_hashes_repo = Arc::new(SeenHashesRepoMock::default());
+        for (hash, block_number) in &[("recent1", 50), ("recent2", 100)] {
+            seen_hashes_repo
+                .create(NewSeenHashes {
+                    hash: BlockchainTransactionId::new(hash.to_string()),
+                    block_number: *block_number,
+                    currency: Currency::Btc,
+                })
+                .unwrap();
+        }
+        let key_values_repo = Arc::new(KeyValuesRepoMock::default());
+        let service = SeenHashesServiceImpl::new(
+            seen_hashes_repo.clone(),
+            key_values_repo.clone(),
+            create_retention(),
+            DbExecutorMock::default(),
+        );
+        let res = core.run(service.prune()).unwrap();
+        // 10 blocks behind the tip would be 90, but hashes of block 50 are younger than keep_days
+        assert_eq!(
+            res[0],
+            PrunedSeenHashes {
+                currency: Currency::Btc,
+                watermark: Some(50),
+                pruned_count: 0,
+            }
+        );
+        assert_eq!(key_values_repo.get_seen_hashes_watermark(Currency::Btc).unwrap(), Some(50));
+    }
+}
@@ -0,0 +1,131 @@
+use std::sync::Arc;
+
+use futures::future;
+
+use super::auth::AuthService;
+use super::error::*;
+use super::ServiceFuture;
+use models::*;
+use prelude::*;
+use repos::{DbExecutor, SmallDepositsRepo};
+
+pub trait SmallDepositsService: Send + Sync + 'static {
+    /// Deposits below `min_deposits` from config, that were kept out of the ledger
+    fn get_report(
+        &self,
+        token: AuthenticationToken,
+        currency: Option<Currency>,
+        offset: i64,
+        limit: i64,
+    ) -> ServiceFuture<SmallDepositsReport>;
+}
+
+#[derive(Clone)]
+pub struct SmallDepositsServiceImpl<E: DbExecutor> {
+    auth_service: Arc<dyn AuthService>,
+    small_deposits_repo: Arc<dyn SmallDepositsRepo>,
+    system_user_id: UserId,
+    db_executor: E,
+}
+
+impl<E: DbExecutor> SmallDepositsServiceImpl<E> {
+    pub fn new(
+        auth_service: Arc<dyn AuthService>,
+        small_deposits_repo: Arc<dyn SmallDepositsRepo>,
+        system_user_id: UserId,
+        db_executor: E,
+    ) -> Self {
+        Self {
+            auth_service,
+            small_deposits_repo,
+            system_user_id,
+            db_executor,
+        }
+    }
+
+    fn authorize(&self, token: AuthenticationToken) -> ServiceFuture<()> {
+        let system_user_id = self.system_user_id;
+        Box::new(self.auth_service.authenticate(token).and_then(move |user| {
+            if user.id == system_user_id {
+                future::ok(())
+            } else {
+                future::err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id))
+            }
+        }))
+    }
+}
+
+impl<E: DbExecutor> SmallDepositsService for SmallDepositsServiceImpl<E> {
+    fn get_report(
+        &self,
+        token: AuthenticationToken,
+        currency: Option<Currency>,
+        offset: i64,
+        limit: i64,
+    ) -> ServiceFuture<SmallDepositsReport> {
+        let self_clone = self.clone();
+        Box::new(self.authorize(token).and_then(move |_| {
+            self_clone.db_executor.execute(move || {
+                let deposits = self_clone
+                    .small_deposits_repo
+                    .list(currency, offset, limit)
+                    .map_err(ectx!(try convert => currency, offset, limit))?;
+                let totals = self_clone.small_deposits_repo.totals().map_err(ectx!(try convert))?;
+                Ok(SmallDepositsReport { deposits, totals })
+            })
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use repos::*;
+    use services::*;
+    use tokio_core::reactor::Core;
+
+    #[test]
+    fn test_small_deposits_report() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let system_user_id = UserId::generate();
+        let small_deposits_repo = Arc::new(SmallDepositsRepoMock::default());
+        for (currency, value) in &[(Currency::Btc, 10), (Currency::Eth, 20), (Currency::Btc, 30)] {
+            small_deposits_repo
+                .create(NewSmallDeposit {
+                    currency: *currency,
+                    value: Amount::new(*value),
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+        let service = SmallDepositsServiceImpl::new(
+            Arc::new(AuthServiceMock::new(vec![(token.clone(), system_user_id)])),
+            small_deposits_repo,
+            system_user_id,
+            DbExecutorMock::default(),
+        );
+
+        let report = core.run(service.get_report(token.clone(), Some(Currency::Btc), 0, 1)).unwrap();
+        assert_eq!(report.deposits.len(), 1);
+        assert_eq!(report.deposits[0].value, Amount::new(30));
+        assert_eq!(
+            report.totals,
+            vec![
+                SmallDepositsTotal {
+                    currency: Currency::Btc,
+                    count: 2,
+                    value: Amount::new(40),
+                },
+                SmallDepositsTotal {
+                    currency: Currency::Eth,
+                    count: 1,
+                    value: Amount::new(20),
+                },
+            ]
+        );
+
+        let other_token = AuthenticationToken::new("other".to_string());
+        assert!(core.run(service.get_report(other_token, None, 0, 10)).is_err());
+    }
+}
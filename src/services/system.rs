@@ -1,7 +1,6 @@
 use std::sync::Arc;
 
 use super::error::*;
-use config::Config;
 use models::*;
 use prelude::*;
 use repos::AccountsRepo;
@@ -11,75 +10,50 @@ pub trait SystemService: Send + Sync + 'static {
     fn get_system_liquidity_account(&self, currency: Currency) -> Result<Account, Error>;
     fn get_system_fees_account(&self, currency: Currency) -> Result<Account, Error>;
     fn get_system_fees_account_dr(&self, currency: Currency) -> Result<Account, Error>;
+    /// Credit accounts of all system accounts, debit ones share their addresses
+    fn list_system_accounts(&self) -> Result<Vec<Account>, Error>;
 }
 
+/// System accounts are found by their roles, that are given to them when they're added
 #[derive(Clone)]
 pub struct SystemServiceImpl {
     accounts_repo: Arc<AccountsRepo>,
-    config: Arc<Config>,
 }
 
 impl SystemServiceImpl {
-    pub fn new(accounts_repo: Arc<AccountsRepo>, config: Arc<Config>) -> Self {
-        Self { accounts_repo, config }
+    pub fn new(accounts_repo: Arc<AccountsRepo>) -> Self {
+        Self { accounts_repo }
+    }
+
+    fn get_system_account(&self, role: SystemAccountKind, currency: Currency, kind: AccountKind) -> Result<Account, Error> {
+        self.accounts_repo
+            .get_system_account(role, currency, kind)
+            .map_err(ectx!(try ErrorKind::Internal => role, currency, kind))?
+            .ok_or(ectx!(err ErrorContext::NoAccount, ErrorKind::NotFound => role, currency, kind))
     }
 }
 
 impl SystemService for SystemServiceImpl {
     fn get_system_transfer_account(&self, currency: Currency) -> Result<Account, Error> {
-        let acc_id = match currency {
-            Currency::Btc => self.config.system.btc_transfer_account_id,
-            Currency::Eth => self.config.system.eth_transfer_account_id,
-            Currency::Stq => self.config.system.stq_transfer_account_id,
-        };
-        let acc = self
-            .accounts_repo
-            .get(acc_id.clone())
-            .map_err(ectx!(try ErrorKind::Internal => acc_id))?
-            .ok_or(ectx!(try err ErrorContext::NoAccount, ErrorKind::NotFound))?;
-        Ok(acc)
+        self.get_system_account(SystemAccountKind::Transfer, currency, AccountKind::Cr)
     }
 
     fn get_system_liquidity_account(&self, currency: Currency) -> Result<Account, Error> {
-        let acc_id = match currency {
-            Currency::Btc => self.config.system.btc_liquidity_account_id,
-            Currency::Eth => self.config.system.eth_liquidity_account_id,
-            Currency::Stq => self.config.system.stq_liquidity_account_id,
-        };
-        let acc = self
-            .accounts_repo
-            .get(acc_id.clone())
-            .map_err(ectx!(try ErrorKind::Internal => acc_id))?
-            .ok_or(ectx!(try err ErrorContext::NoAccount, ErrorKind::NotFound))?;
-        Ok(acc)
+        self.get_system_account(SystemAccountKind::Liquidity, currency, AccountKind::Cr)
     }
 
     fn get_system_fees_account(&self, currency: Currency) -> Result<Account, Error> {
-        let acc_id = match currency {
-            Currency::Btc => self.config.system.btc_fees_account_id,
-            Currency::Eth => self.config.system.eth_fees_account_id,
-            Currency::Stq => self.config.system.stq_fees_account_id,
-        };
-        let acc = self
-            .accounts_repo
-            .get(acc_id.clone())
-            .map_err(ectx!(try ErrorKind::Internal => acc_id))?
-            .ok_or(ectx!(try err ErrorContext::NoAccount, ErrorKind::NotFound => currency))?;
-        Ok(acc)
+        self.get_system_account(SystemAccountKind::Fees, currency, AccountKind::Cr)
     }
 
     fn get_system_fees_account_dr(&self, currency: Currency) -> Result<Account, Error> {
-        let acc_id = match currency {
-            Currency::Btc => self.config.system.btc_fees_account_id,
-            Currency::Eth => self.config.system.eth_fees_account_id,
-            Currency::Stq => self.config.system.stq_fees_account_id,
-        };
-        let dr_acc_id = acc_id.derive_system_dr_id();
-        let acc = self
-            .accounts_repo
-            .get(dr_acc_id.clone())
-            .map_err(ectx!(try ErrorKind::Internal => dr_acc_id))?
-            .ok_or(ectx!(try err ErrorContext::NoAccount, ErrorKind::NotFound))?;
-        Ok(acc)
+        self.get_system_account(SystemAccountKind::Fees, currency, AccountKind::Dr)
+    }
+
+    fn list_system_accounts(&self) -> Result<Vec<Account>, Error> {
+        self.accounts_repo
+            .list_system_accounts()
+            .map(|accounts| accounts.into_iter().filter(|account| account.kind == AccountKind::Cr).collect())
+            .map_err(ectx!(ErrorKind::Internal))
     }
 }
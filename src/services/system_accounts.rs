@@ -0,0 +1,784 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use futures::future::{self, Either};
+use serde_json;
+use uuid::Uuid;
+
+use super::auth::AuthService;
+use super::error::*;
+use super::system::{SystemService, SystemServiceImpl};
+use super::transactions::{BlockchainService, BlockchainServiceImpl};
+use super::ServiceFuture;
+use client::{BlockchainClient, ExchangeClient, KeysClient};
+use config::Config;
+use models::*;
+use prelude::*;
+use repos::{AccountsRepo, DbExecutor, Isolation, KeyValuesRepo, KeyValuesRepoExt, PendingBlockchainTransactionsRepo, TransactionsRepo};
+use utils::log_and_capture_error;
+
+// Balance left at the former address of system account, ready to be sent to the new one
+#[derive(Debug, Clone)]
+struct Sweep {
+    gid: TransactionId,
+    account_dr_id: AccountId,
+    former_account: Account,
+    to: BlockchainAddress,
+    value: Amount,
+    fee_price: f64,
+}
+
+pub trait SystemAccountsService: Send + Sync + 'static {
+    /// System accounts with their balances and the outcome of the latest reconciliation of their addresses
+    fn get_accounts(&self, token: AuthenticationToken) -> ServiceFuture<Vec<SystemAccountOverview>>;
+    /// Adds system account of `kind` in `currency` at a fresh address, e.g. for a newly supported currency.
+    /// There is one system account of each kind per currency
+    fn create_account(&self, token: AuthenticationToken, kind: SystemAccountKind, currency: Currency) -> ServiceFuture<Account>;
+    /// Moves system account to a fresh address, e.g. when the key of the current one might be compromised.
+    /// The former address is kept by a separate debit account, so that deposits still arriving there
+    /// are credited, and the balance of the account is sent from it to the new address
+    fn rotate_address(&self, token: AuthenticationToken, account_id: AccountId) -> ServiceFuture<SystemAddressRotation>;
+}
+
+#[derive(Clone)]
+pub struct SystemAccountsServiceImpl<E: DbExecutor> {
+    config: Arc<Config>,
+    auth_service: Arc<dyn AuthService>,
+    accounts_repo: Arc<dyn AccountsRepo>,
+    transactions_repo: Arc<dyn TransactionsRepo>,
+    key_values_repo: Arc<dyn KeyValuesRepo>,
+    keys_client: Arc<dyn KeysClient>,
+    system_service: Arc<dyn SystemService>,
+    blockchain_service: Arc<dyn BlockchainService>,
+    db_executor: E,
+}
+
+impl<E: DbExecutor> SystemAccountsServiceImpl<E> {
+    pub fn new(
+        config: Arc<Config>,
+        auth_service: Arc<dyn AuthService>,
+        accounts_repo: Arc<dyn AccountsRepo>,
+        transactions_repo: Arc<dyn TransactionsRepo>,
+        pending_transactions_repo: Arc<dyn PendingBlockchainTransactionsRepo>,
+        key_values_repo: Arc<dyn KeyValuesRepo>,
+        db_executor: E,
+        keys_client: Arc<dyn KeysClient>,
+        blockchain_client: Arc<dyn BlockchainClient>,
+        exchange_client: Arc<dyn ExchangeClient>,
+    ) -> Self {
+        let system_service = Arc::new(SystemServiceImpl::new(accounts_repo.clone()));
+        let blockchain_service = Arc::new(BlockchainServiceImpl::new(
+            config.clone(),
+            keys_client.clone(),
+            blockchain_client,
+            exchange_client,
+            pending_transactions_repo,
+            key_values_repo.clone(),
+            system_service.clone(),
+            db_executor.clone(),
+        ));
+        Self {
+            config,
+            auth_service,
+            accounts_repo,
+            transactions_repo,
+            key_values_repo,
+            keys_client,
+            system_service,
+            blockchain_service,
+            db_executor,
+        }
+    }
+
+    /// Rotates address without authorization, for command line
+    pub fn rotate(&self, account_id: AccountId) -> ServiceFuture<SystemAddressRotation> {
+        let accounts_repo = self.accounts_repo.clone();
+        let keys_client = self.keys_client.clone();
+        let db_executor = self.db_executor.clone();
+        let self_clone = self.clone();
+        let self_clone2 = self.clone();
+        Box::new(
+            self.db_executor
+                .execute(move || -> Result<(SystemAccountKind, Currency), Error> {
+                    let account = accounts_repo
+                        .get(account_id)
+                        .map_err(ectx!(try convert => account_id))?
+                        .filter(|account| account.kind == AccountKind::Cr)
+                        .ok_or(ectx!(try err ErrorContext::NoAccount, ErrorKind::NotFound => account_id))?;
+                    let kind = account
+                        .system_role
+                        .ok_or(ectx!(try err ErrorContext::NoAccount, ErrorKind::NotFound => account_id))?;
+                    // erc-20 approvals of all accounts are given to the address of eth fees account,
+                    // so stq couldn't be sent from any of them after it's changed
+                    if kind == SystemAccountKind::Fees && account.currency == Currency::Eth {
+                        return Err(
+                            ectx!(err ErrorContext::NotSupported, invalid_input("accountId", "approvals_spender", "erc-20 approvals are given to the address of eth fees account, it can't be rotated") => account_id),
+                        );
+                    }
+                    Ok((kind, account.currency))
+                })
+                .and_then(move |(kind, currency)| {
+                    let create_address = CreateAccountAddress {
+                        id: Uuid::new_v4(),
+                        currency,
+                    };
+                    keys_client
+                        .create_account_address(create_address.clone(), Role::System)
+                        .map_err(ectx!(convert => create_address))
+                        .and_then(move |new_address| {
+                            db_executor.execute_transaction_with_isolation(Isolation::Serializable, move || {
+                                self_clone.switch_address(account_id, kind, currency, new_address)
+                            })
+                        })
+                })
+                .and_then(move |(rotation, maybe_sweep)| match maybe_sweep {
+                    Some((sweep, tx)) => Either::A(self_clone2.send_sweep(sweep, tx).then(move |res| {
+                        let sweep = match res {
+                            Ok(tx) => Some(tx),
+                            // the address is rotated anyway, the balance stays with the account of former address
+                            Err(e) => {
+                                log_and_capture_error(e);
+                                None
+                            }
+                        };
+                        Ok(SystemAddressRotation { sweep, ..rotation })
+                    })),
+                    None => Either::B(future::ok(rotation)),
+                }),
+        )
+    }
+
+    fn create(&self, kind: SystemAccountKind, currency: Currency) -> ServiceFuture<Account> {
+        let accounts_repo = self.accounts_repo.clone();
+        let keys_client = self.keys_client.clone();
+        let db_executor = self.db_executor.clone();
+        let self_clone = self.clone();
+        Box::new(
+            self.db_executor
+                .execute(move || -> Result<(), Error> {
+                    match accounts_repo
+                        .get_system_account(kind, currency, AccountKind::Cr)
+                        .map_err(ectx!(try convert => kind, currency))?
+                    {
+                        Some(_) => Err(ectx!(err ErrorContext::SystemAccountExists, ErrorKind::Conflict => kind, currency)),
+                        None => Ok(()),
+                    }
+                })
+                .and_then(move |_| {
+                    let account_id = AccountId::generate();
+                    let create_address = CreateAccountAddress {
+                        id: account_id.inner().clone(),
+                        currency,
+                    };
+                    keys_client
+                        .create_account_address(create_address.clone(), Role::System)
+                        .map_err(ectx!(convert => create_address))
+                        .and_then(move |address| {
+                            db_executor.execute_transaction(move || self_clone.create_accounts(account_id, kind, currency, address))
+                        })
+                }),
+        )
+    }
+
+    // Credit and debit accounts of system account share the address, as the ones set up on start do
+    fn create_accounts(
+        &self,
+        account_id: AccountId,
+        kind: SystemAccountKind,
+        currency: Currency,
+        address: BlockchainAddress,
+    ) -> Result<Account, Error> {
+        let name = format!("{}_{}_account", currency, kind);
+        let new_account = NewAccount {
+            id: account_id,
+            user_id: self.config.system.system_user_id,
+            currency,
+            address: address.clone(),
+            name: Some(name.clone()),
+            kind: AccountKind::Cr,
+            daily_limit_type: Some(DailyLimitType::Unlimited),
+            meta: None,
+            labels: None,
+        };
+        let new_account_dr = NewAccount {
+            id: account_id.derive_system_dr_id(),
+            name: Some(format!("{}_deposit", name)),
+            kind: AccountKind::Dr,
+            ..new_account.clone()
+        };
+        let account_dr_id = new_account_dr.id;
+        self.accounts_repo
+            .create(new_account_dr.clone())
+            .map_err(ectx!(try convert => new_account_dr))?;
+        self.accounts_repo
+            .set_system_role(account_dr_id, Some(kind))
+            .map_err(ectx!(try convert => account_dr_id, kind))?;
+        self.accounts_repo
+            .create(new_account.clone())
+            .map_err(ectx!(try convert => new_account))?;
+        self.accounts_repo
+            .set_system_role(account_id, Some(kind))
+            .map_err(ectx!(convert => account_id, kind))
+    }
+
+    fn get_overview(&self) -> Result<Vec<SystemAccountOverview>, Error> {
+        let accounts = self.system_service.list_system_accounts()?;
+        let balances = self
+            .transactions_repo
+            .get_system_balances()
+            .map_err(ectx!(try ErrorKind::Internal))?;
+        let mut statuses = HashMap::new();
+        for currency in [Currency::Btc, Currency::Eth, Currency::Stq].into_iter() {
+            if let Some(status) = self
+                .key_values_repo
+                .get_reconciliation_status(*currency)
+                .map_err(ectx!(try ErrorKind::Internal => currency))?
+            {
+                statuses.insert(*currency, status);
+            }
+        }
+        Ok(system_accounts_overview(accounts, &balances, &statuses))
+    }
+
+    fn authorize(&self, token: AuthenticationToken) -> ServiceFuture<()> {
+        let system_user_id = self.config.system.system_user_id;
+        Box::new(self.auth_service.authenticate(token).and_then(move |user| {
+            if user.id == system_user_id {
+                future::ok(())
+            } else {
+                future::err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id))
+            }
+        }))
+    }
+
+    // Both accounts of system account move to the new address, the former one is kept by a new debit account,
+    // that the balance is moved to. Deposits to the former address are credited to the system account as expired one.
+    // The sweep is recorded as pending before it's sent, so that a sweep sent without its id saved is still seen in the ledger
+    fn switch_address(
+        &self,
+        account_id: AccountId,
+        kind: SystemAccountKind,
+        currency: Currency,
+        new_address: BlockchainAddress,
+    ) -> Result<(SystemAddressRotation, Option<(Sweep, Transaction)>), Error> {
+        let account_dr_id = account_id.derive_system_dr_id();
+        let account_dr = self
+            .accounts_repo
+            .get(account_dr_id)
+            .map_err(ectx!(try convert => account_dr_id))?
+            .ok_or(ectx!(try err ErrorContext::NoAccount, ErrorKind::NotFound => account_dr_id))?;
+        let balance = self
+            .transactions_repo
+            .get_account_balance(account_dr_id, AccountKind::Dr)
+            .map_err(ectx!(try convert => account_dr_id))?;
+        let new_address_clone = new_address.clone();
+        let rotated = self
+            .accounts_repo
+            .rotate_address(account_id, new_address.clone())
+            .map_err(ectx!(try convert => account_id, new_address_clone))?;
+        let new_address_clone = new_address.clone();
+        self.accounts_repo
+            .set_address(account_dr_id, new_address.clone())
+            .map_err(ectx!(try convert => account_dr_id, new_address_clone))?;
+
+        let new_former_account = NewAccount {
+            name: Some(format!("{}_{}_account_former", currency, kind)),
+            ..NewAccount {
+                user_id: account_dr.user_id,
+                currency,
+                address: account_dr.address.clone(),
+                daily_limit_type: Some(DailyLimitType::Unlimited),
+                ..Default::default()
+            }
+            .create_debit()
+        };
+        let mut former_account = self
+            .accounts_repo
+            .create(new_former_account.clone())
+            .map_err(ectx!(try convert => new_former_account))?;
+        // tokens can still be sent from the former address with the approval it has
+        if account_dr.erc20_approved {
+            let changeset = UpdateAccount {
+                erc20_approved: Some(true),
+                ..Default::default()
+            };
+            former_account = self
+                .accounts_repo
+                .update(former_account.id, changeset.clone())
+                .map_err(ectx!(try convert => former_account.id, changeset))?;
+        }
+
+        let gid = TransactionId::generate();
+        if balance > Amount::new(0) {
+            let new_transaction = NewTransaction {
+                id: gid,
+                gid,
+                user_id: account_dr.user_id,
+                dr_account_id: former_account.id,
+                cr_account_id: account_dr_id,
+                currency,
+                value: balance,
+                status: TransactionStatus::Done,
+                blockchain_tx_id: None,
+                kind: TransactionKind::Internal,
+                group_kind: TransactionGroupKind::Sweep,
+                related_tx: None,
+                meta: None,
+            };
+            self.transactions_repo
+                .create(new_transaction.clone())
+                .map_err(ectx!(try convert => new_transaction))?;
+        }
+
+        let (fee, fee_price) = sweep_fee(&self.config, currency);
+        let value = match currency {
+            // eth fee of stq transfer is paid by system account, as for withdrawals
+            Currency::Stq if former_account.erc20_approved => Some(balance),
+            Currency::Stq => None,
+            _ => balance.checked_sub(fee),
+        };
+        let sweep = match value {
+            Some(value) if value > Amount::new(0) => Some(Sweep {
+                gid,
+                account_dr_id,
+                former_account: former_account.clone(),
+                to: new_address.clone(),
+                value,
+                fee_price,
+            }),
+            _ => None,
+        };
+        let sweep = match sweep {
+            Some(sweep) => {
+                let tx = self.record_sweep(&sweep)?;
+                Some((sweep, tx))
+            }
+            None => None,
+        };
+        let rotation = SystemAddressRotation {
+            account_id,
+            kind,
+            currency,
+            address: rotated.address,
+            former_address: former_account.address.clone(),
+            former_address_account_id: former_account.id,
+            sweep: None,
+        };
+        Ok((rotation, sweep))
+    }
+
+    // Attaches blockchain tx id to the pending sweep once it's sent, or reverses the sweep if it failed
+    fn send_sweep(&self, sweep: Sweep, tx: Transaction) -> impl Future<Item = Transaction, Error = Error> {
+        let Sweep {
+            former_account,
+            to,
+            value,
+            fee_price,
+            ..
+        } = sweep;
+        let from = former_account.address.clone();
+        let db_executor = self.db_executor.clone();
+        let transactions_repo = self.transactions_repo.clone();
+        match former_account.currency {
+            Currency::Btc => Either::A(
+                self.blockchain_service
+                    .create_bitcoin_tx(from.clone(), to.clone(), value, fee_price)
+                    .map_err(ectx!(ErrorKind::Internal => from, to, value, fee_price)),
+            ),
+            currency => Either::B(
+                self.blockchain_service
+                    .create_ethereum_tx(from.clone(), to.clone(), value, fee_price, currency)
+                    .map_err(ectx!(ErrorKind::Internal => from, to, value, fee_price, currency)),
+            ),
+        }
+        .then(move |res| match res {
+            Ok(blockchain_tx_id) => Either::A(db_executor.execute(move || {
+                let tx_id = tx.id;
+                transactions_repo
+                    .update_blockchain_tx(tx_id, blockchain_tx_id.clone())
+                    .map_err(ectx!(convert => tx_id, blockchain_tx_id))
+            })),
+            Err(e) => Either::B(
+                db_executor
+                    .execute_transaction(move || reverse_sweep(&*transactions_repo, tx))
+                    .then(move |res| {
+                        // the sweep stays pending then and is to be repaired by hand
+                        if let Err(reverse_error) = res {
+                            log_and_capture_error(reverse_error);
+                        }
+                        Err::<Transaction, Error>(e)
+                    }),
+            ),
+        })
+    }
+
+    // Pending until blockchain fetcher sees it confirmed, network fee is written off from the account of former address then
+    fn record_sweep(&self, sweep: &Sweep) -> Result<Transaction, Error> {
+        let Sweep {
+            gid,
+            account_dr_id,
+            ref former_account,
+            value,
+            ..
+        } = *sweep;
+        let new_transaction = NewTransaction {
+            id: TransactionId::generate(),
+            gid,
+            user_id: former_account.user_id,
+            dr_account_id: account_dr_id,
+            cr_account_id: former_account.id,
+            currency: former_account.currency,
+            value,
+            status: TransactionStatus::Pending,
+            blockchain_tx_id: None,
+            kind: TransactionKind::Sweep,
+            group_kind: TransactionGroupKind::Sweep,
+            related_tx: None,
+            meta: None,
+        };
+        self.transactions_repo
+            .create(new_transaction.clone())
+            .map_err(ectx!(convert => new_transaction))
+    }
+}
+
+/// Network fee and fee price of sending funds between our own addresses. Fee of stq transfer is paid
+/// in eth by system account, so it's zero
+pub(crate) fn sweep_fee(config: &Config, currency: Currency) -> (Amount, f64) {
+    let (fee, fee_price) = match currency {
+        Currency::Btc => {
+            let fee_price = config.fee_price.bitcoin;
+            (fee_price * config.fees_options.btc_transaction_size as f64, fee_price)
+        }
+        Currency::Eth => {
+            let fee_price = config.fee_price.ethereum;
+            (fee_price * config.fees_options.eth_gas_limit as f64, fee_price)
+        }
+        Currency::Stq => (0.0, config.fee_price.ethereum),
+    };
+    (Amount::new(fee.ceil() as u128), fee_price)
+}
+
+/// Returns funds of pending `sweep` that failed to be sent to the account it was taken from and closes the sweep.
+/// Must be run in a db transaction
+pub(crate) fn reverse_sweep(transactions_repo: &TransactionsRepo, sweep: Transaction) -> Result<(), Error> {
+    let sweep_id = sweep.id;
+    let id = TransactionId::generate();
+    let new_transaction = NewTransaction {
+        id,
+        gid: id,
+        user_id: sweep.user_id,
+        dr_account_id: sweep.cr_account_id,
+        cr_account_id: sweep.dr_account_id,
+        currency: sweep.currency,
+        value: sweep.value,
+        status: TransactionStatus::Done,
+        blockchain_tx_id: None,
+        kind: TransactionKind::Sweep,
+        group_kind: TransactionGroupKind::Reversal,
+        related_tx: Some(sweep_id),
+        meta: Some(serde_json::Value::String(format!(
+            "reversal of sweep transaction with id {} that failed to be sent",
+            sweep_id
+        ))),
+    };
+    transactions_repo
+        .create(new_transaction.clone())
+        .map_err(ectx!(try convert => new_transaction))?;
+    transactions_repo
+        .update_status_by_id(sweep_id, TransactionStatus::Done)
+        .map_err(ectx!(try convert => sweep_id))?;
+    Ok(())
+}
+
+/// Overview of system `accounts` with their `balances` as returned by `TransactionsRepo::get_system_balances`
+/// and the latest reconciliation `statuses` by currency
+fn system_accounts_overview(
+    accounts: Vec<Account>,
+    balances: &HashMap<AccountId, (Amount, Amount)>,
+    statuses: &HashMap<Currency, ReconciliationStatus>,
+) -> Vec<SystemAccountOverview> {
+    accounts
+        .into_iter()
+        .filter_map(|account| {
+            let kind = account.system_role?;
+            let (cr_turnover, dr_turnover) = balances.get(&account.id).cloned().unwrap_or((Amount::new(0), Amount::new(0)));
+            // negative balances fail reconciliation of the address
+            let balance = cr_turnover.checked_sub(dr_turnover).unwrap_or(Amount::new(0));
+            let reconciliation = statuses.get(&account.currency).map(|status| SystemAccountReconciliation {
+                checked_at: status.checked_at,
+                consistent: !status.failed_addresses.contains(&account.address),
+            });
+            Some(SystemAccountOverview {
+                account,
+                kind,
+                balance,
+                reconciliation,
+            })
+        })
+        .collect()
+}
+
+impl<E: DbExecutor> SystemAccountsService for SystemAccountsServiceImpl<E> {
+    fn get_accounts(&self, token: AuthenticationToken) -> ServiceFuture<Vec<SystemAccountOverview>> {
+        let self_clone = self.clone();
+        let db_executor = self.db_executor.clone();
+        Box::new(
+            self.authorize(token)
+                .and_then(move |_| db_executor.execute(move || self_clone.get_overview())),
+        )
+    }
+
+    fn create_account(&self, token: AuthenticationToken, kind: SystemAccountKind, currency: Currency) -> ServiceFuture<Account> {
+        let self_clone = self.clone();
+        Box::new(self.authorize(token).and_then(move |_| self_clone.create(kind, currency)))
+    }
+
+    fn rotate_address(&self, token: AuthenticationToken, account_id: AccountId) -> ServiceFuture<SystemAddressRotation> {
+        let self_clone = self.clone();
+        Box::new(self.authorize(token).and_then(move |_| self_clone.rotate(account_id)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_core::reactor::Core;
+
+    use super::*;
+    use client::*;
+    use repos::*;
+    use services::*;
+
+    fn create_service(
+        token: AuthenticationToken,
+        accounts_repo: Arc<AccountsRepoMock>,
+        transactions_repo: Arc<TransactionsRepoMock>,
+        blockchain_client: Arc<dyn BlockchainClient>,
+    ) -> SystemAccountsServiceImpl<DbExecutorMock> {
+        let config = Arc::new(Config::new().unwrap());
+        let auth_service = Arc::new(AuthServiceMock::new(vec![(token, config.system.system_user_id)]));
+        SystemAccountsServiceImpl::new(
+            config,
+            auth_service,
+            accounts_repo,
+            transactions_repo,
+            Arc::new(PendingBlockchainTransactionsRepoMock::default()),
+            Arc::new(KeyValuesRepoMock::default()),
+            DbExecutorMock::default(),
+            Arc::new(KeysClientMock::default()),
+            blockchain_client,
+            Arc::new(ExchangeClientMock::default()),
+        )
+    }
+
+    fn create_system_account(accounts_repo: &AccountsRepoMock, kind: SystemAccountKind, currency: Currency) -> Account {
+        let account_id = AccountId::generate();
+        let address = BlockchainAddress::default();
+        let mut new_account = NewAccount::default();
+        new_account.id = account_id;
+        new_account.currency = currency;
+        new_account.address = address.clone();
+        new_account.kind = AccountKind::Cr;
+        accounts_repo.create(new_account.clone()).unwrap();
+        accounts_repo.set_system_role(account_id, Some(kind)).unwrap();
+        new_account.id = account_id.derive_system_dr_id();
+        new_account.kind = AccountKind::Dr;
+        accounts_repo.create(new_account).unwrap();
+        accounts_repo.set_system_role(account_id.derive_system_dr_id(), Some(kind)).unwrap()
+    }
+
+    #[test]
+    fn test_system_accounts_overview() {
+        let system_account = |kind, currency| Account {
+            currency,
+            address: BlockchainAddress::new(format!("{}_{}", currency, kind)),
+            system_role: Some(kind),
+            ..Default::default()
+        };
+        let btc_fees = system_account(SystemAccountKind::Fees, Currency::Btc);
+        let btc_liquidity = system_account(SystemAccountKind::Liquidity, Currency::Btc);
+        let eth_fees = system_account(SystemAccountKind::Fees, Currency::Eth);
+        let mut balances = HashMap::new();
+        balances.insert(btc_fees.id, (Amount::new(300), Amount::new(100)));
+        let mut statuses = HashMap::new();
+        statuses.insert(
+            Currency::Btc,
+            ReconciliationStatus {
+                checked_at: ::chrono::Utc::now().naive_utc(),
+                failed_addresses: vec![btc_liquidity.address.clone()],
+            },
+        );
+        let accounts = vec![btc_fees.clone(), btc_liquidity.clone(), eth_fees.clone(), Account::default()];
+        let overview = system_accounts_overview(accounts, &balances, &statuses);
+        // accounts without role are not system ones
+        assert_eq!(overview.len(), 3);
+        assert_eq!(overview[0].kind, SystemAccountKind::Fees);
+        assert_eq!(overview[0].balance, Amount::new(200));
+        assert!(overview[0].reconciliation.as_ref().unwrap().consistent);
+        // account without transactions has zero balance
+        assert_eq!(overview[1].balance, Amount::new(0));
+        assert!(!overview[1].reconciliation.as_ref().unwrap().consistent);
+        // eth addresses were never reconciled
+        assert!(overview[2].reconciliation.is_none());
+    }
+
+    #[test]
+    fn test_create_system_account() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let service = create_service(
+            token.clone(),
+            accounts_repo.clone(),
+            transactions_repo,
+            Arc::new(BlockchainClientMock::default()),
+        );
+
+        // only system user can add system accounts
+        assert!(core
+            .run(service.create_account(AuthenticationToken::default(), SystemAccountKind::Liquidity, Currency::Btc))
+            .is_err());
+        let account = core
+            .run(service.create_account(token.clone(), SystemAccountKind::Liquidity, Currency::Btc))
+            .unwrap();
+        assert_eq!(account.kind, AccountKind::Cr);
+        assert_eq!(account.system_role, Some(SystemAccountKind::Liquidity));
+        // debit account shares the address
+        let account_dr = accounts_repo.get(account.id.derive_system_dr_id()).unwrap().unwrap();
+        assert_eq!(account_dr.address, account.address);
+        assert_eq!(account_dr.system_role, Some(SystemAccountKind::Liquidity));
+        assert_eq!(
+            accounts_repo
+                .get_system_account(SystemAccountKind::Liquidity, Currency::Btc, AccountKind::Cr)
+                .unwrap()
+                .unwrap()
+                .id,
+            account.id
+        );
+        // there is one system account of a kind per currency
+        assert!(core
+            .run(service.create_account(token, SystemAccountKind::Liquidity, Currency::Btc))
+            .is_err());
+    }
+
+    #[test]
+    fn test_rotate_system_account_address() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let service = create_service(
+            token.clone(),
+            accounts_repo.clone(),
+            transactions_repo.clone(),
+            Arc::new(BlockchainClientMock::default()),
+        );
+        let config = Config::new().unwrap();
+        let account_dr = create_system_account(&accounts_repo, SystemAccountKind::Fees, Currency::Btc);
+        let account_id = accounts_repo
+            .get_system_account(SystemAccountKind::Fees, Currency::Btc, AccountKind::Cr)
+            .unwrap()
+            .unwrap()
+            .id;
+        create_system_account(&accounts_repo, SystemAccountKind::Fees, Currency::Eth);
+        let mut deposit = NewTransaction::default();
+        deposit.dr_account_id = account_dr.id;
+        deposit.currency = Currency::Btc;
+        deposit.value = Amount::new(100_000_000);
+        transactions_repo.create(deposit).unwrap();
+
+        // only system user can rotate system addresses
+        assert!(core
+            .run(service.rotate_address(AuthenticationToken::default(), account_id))
+            .is_err());
+        // and only of system accounts
+        assert!(core.run(service.rotate_address(token.clone(), AccountId::generate())).is_err());
+        assert!(core.run(service.rotate_address(token.clone(), account_dr.id)).is_err());
+        assert!(core
+            .run(
+                service.rotate_address(
+                    token.clone(),
+                    accounts_repo
+                        .get_system_account(SystemAccountKind::Fees, Currency::Eth, AccountKind::Cr)
+                        .unwrap()
+                        .unwrap()
+                        .id
+                )
+            )
+            .is_err());
+
+        let rotation = core.run(service.rotate_address(token, account_id)).unwrap();
+        assert_eq!(rotation.kind, SystemAccountKind::Fees);
+        assert_eq!(rotation.former_address, account_dr.address);
+        assert_ne!(rotation.address, account_dr.address);
+        // both accounts are at the new address, the former one is kept by its own account
+        assert_eq!(accounts_repo.get(account_id).unwrap().unwrap().address, rotation.address);
+        assert_eq!(accounts_repo.get(account_dr.id).unwrap().unwrap().address, rotation.address);
+        let former_account = accounts_repo.get(rotation.former_address_account_id).unwrap().unwrap();
+        assert_eq!(former_account.address, account_dr.address);
+        // the balance is sent back to the system account less network fee
+        let sweep = rotation.sweep.unwrap();
+        let (fee, _) = sweep_fee(&config, Currency::Btc);
+        assert_eq!(sweep.value, Amount::new(100_000_000).checked_sub(fee).unwrap());
+        assert_eq!(sweep.status, TransactionStatus::Pending);
+        assert_eq!(
+            transactions_repo.get_account_balance(account_dr.id, AccountKind::Dr).unwrap(),
+            sweep.value
+        );
+        assert_eq!(
+            transactions_repo.get_account_balance(former_account.id, AccountKind::Dr).unwrap(),
+            fee
+        );
+    }
+
+    #[test]
+    fn test_failed_rotation_sweep_is_reversed() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let service = create_service(
+            token.clone(),
+            accounts_repo.clone(),
+            transactions_repo.clone(),
+            Arc::new(RejectingBlockchainClient::default()),
+        );
+        let account_dr = create_system_account(&accounts_repo, SystemAccountKind::Fees, Currency::Btc);
+        let account_id = accounts_repo
+            .get_system_account(SystemAccountKind::Fees, Currency::Btc, AccountKind::Cr)
+            .unwrap()
+            .unwrap()
+            .id;
+        let mut deposit = NewTransaction::default();
+        deposit.dr_account_id = account_dr.id;
+        deposit.currency = Currency::Btc;
+        deposit.value = Amount::new(100_000_000);
+        transactions_repo.create(deposit).unwrap();
+
+        // the address is rotated anyway
+        let rotation = core.run(service.rotate_address(token, account_id)).unwrap();
+        assert!(rotation.sweep.is_none());
+        assert_eq!(accounts_repo.get(account_id).unwrap().unwrap().address, rotation.address);
+        let txs = transactions_repo.list_for_account(account_dr.id, 0, 10).unwrap();
+        let sweep = txs
+            .iter()
+            .find(|tx| tx.kind == TransactionKind::Sweep && tx.related_tx.is_none())
+            .unwrap();
+        assert_eq!(sweep.status, TransactionStatus::Done);
+        assert_eq!(sweep.blockchain_tx_id, None);
+        let reversal = txs.iter().find(|tx| tx.group_kind == TransactionGroupKind::Reversal).unwrap();
+        assert_eq!(reversal.related_tx, Some(sweep.id));
+        // the balance stays with the account of former address
+        assert_eq!(
+            transactions_repo.get_account_balance(account_dr.id, AccountKind::Dr).unwrap(),
+            Amount::new(0)
+        );
+        assert_eq!(
+            transactions_repo
+                .get_account_balance(rotation.former_address_account_id, AccountKind::Dr)
+                .unwrap(),
+            Amount::new(100_000_000)
+        );
+    }
+}
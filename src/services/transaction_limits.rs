@@ -0,0 +1,183 @@
+use std::sync::Arc;
+
+use futures::future;
+use serde_json;
+use validator::Validate;
+
+use super::auth::AuthService;
+use super::error::*;
+use super::ServiceFuture;
+use models::*;
+use prelude::*;
+use repos::{DbExecutor, KeyValuesRepo, KeyValuesRepoExt};
+
+pub trait TransactionLimitsService: Send + Sync + 'static {
+    fn get_limits(&self, token: AuthenticationToken, user_id: UserId) -> ServiceFuture<TransactionLimitsSettings>;
+    /// Overrides transaction limits from config for the user until reset
+    fn set_limits(
+        &self,
+        token: AuthenticationToken,
+        user_id: UserId,
+        limits: TransactionLimits,
+    ) -> ServiceFuture<TransactionLimitsSettings>;
+    /// Drops the user's override, so that limits from config are used again
+    fn reset_limits(&self, token: AuthenticationToken, user_id: UserId) -> ServiceFuture<TransactionLimitsSettings>;
+}
+
+#[derive(Clone)]
+pub struct TransactionLimitsServiceImpl<E: DbExecutor> {
+    auth_service: Arc<dyn AuthService>,
+    key_values_repo: Arc<dyn KeyValuesRepo>,
+    defaults: TransactionLimits,
+    system_user_id: UserId,
+    db_executor: E,
+}
+
+impl<E: DbExecutor> TransactionLimitsServiceImpl<E> {
+    pub fn new(
+        auth_service: Arc<dyn AuthService>,
+        key_values_repo: Arc<dyn KeyValuesRepo>,
+        defaults: TransactionLimits,
+        system_user_id: UserId,
+        db_executor: E,
+    ) -> Self {
+        Self {
+            auth_service,
+            key_values_repo,
+            defaults,
+            system_user_id,
+            db_executor,
+        }
+    }
+
+    fn authorize(&self, token: AuthenticationToken) -> ServiceFuture<()> {
+        let system_user_id = self.system_user_id;
+        Box::new(self.auth_service.authenticate(token).and_then(move |user| {
+            if user.id == system_user_id {
+                future::ok(())
+            } else {
+                future::err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id))
+            }
+        }))
+    }
+
+    fn settings(&self, user_id: UserId, maybe_limits: Option<TransactionLimits>) -> TransactionLimitsSettings {
+        match maybe_limits {
+            Some(limits) => TransactionLimitsSettings {
+                user_id,
+                limits,
+                overridden: true,
+            },
+            None => TransactionLimitsSettings {
+                user_id,
+                limits: self.defaults.clone(),
+                overridden: false,
+            },
+        }
+    }
+}
+
+impl<E: DbExecutor> TransactionLimitsService for TransactionLimitsServiceImpl<E> {
+    fn get_limits(&self, token: AuthenticationToken, user_id: UserId) -> ServiceFuture<TransactionLimitsSettings> {
+        let self_clone = self.clone();
+        Box::new(self.authorize(token).and_then(move |_| {
+            self_clone.db_executor.execute(move || {
+                let maybe_limits = self_clone.key_values_repo.get_transaction_limits(user_id)?;
+                Ok(self_clone.settings(user_id, maybe_limits))
+            })
+        }))
+    }
+
+    fn set_limits(
+        &self,
+        token: AuthenticationToken,
+        user_id: UserId,
+        limits: TransactionLimits,
+    ) -> ServiceFuture<TransactionLimitsSettings> {
+        let self_clone = self.clone();
+        Box::new(
+            self.authorize(token)
+                .and_then(move |_| match limits.validate() {
+                    Ok(_) => Ok(limits),
+                    Err(e) => Err(ectx!(err e.clone(), ErrorKind::InvalidInput(serde_json::to_string(&e).unwrap_or_default()) => limits)),
+                })
+                .and_then(move |limits| {
+                    self_clone.db_executor.execute(move || {
+                        let limits = self_clone.key_values_repo.set_transaction_limits(user_id, limits)?;
+                        Ok(self_clone.settings(user_id, Some(limits)))
+                    })
+                }),
+        )
+    }
+
+    fn reset_limits(&self, token: AuthenticationToken, user_id: UserId) -> ServiceFuture<TransactionLimitsSettings> {
+        let self_clone = self.clone();
+        Box::new(self.authorize(token).and_then(move |_| {
+            self_clone.db_executor.execute(move || {
+                self_clone.key_values_repo.delete_transaction_limits(user_id)?;
+                Ok(self_clone.settings(user_id, None))
+            })
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use repos::*;
+    use services::*;
+    use tokio_core::reactor::Core;
+
+    fn defaults() -> TransactionLimits {
+        TransactionLimits {
+            btc: 0.02,
+            eth: 0.5,
+            stq: 50000.0,
+        }
+    }
+
+    #[test]
+    fn test_transaction_limits_override() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let system_user_id = UserId::generate();
+        let user_id = UserId::generate();
+        let service = TransactionLimitsServiceImpl::new(
+            Arc::new(AuthServiceMock::new(vec![(token.clone(), system_user_id)])),
+            Arc::new(KeyValuesRepoMock::default()),
+            defaults(),
+            system_user_id,
+            DbExecutorMock::default(),
+        );
+        let raised = TransactionLimits { btc: 1.0, ..defaults() };
+
+        let settings = core.run(service.set_limits(token.clone(), user_id, raised.clone())).unwrap();
+        assert_eq!(settings.limits, raised);
+        assert!(settings.overridden);
+        // other users keep defaults
+        let settings = core.run(service.get_limits(token.clone(), UserId::generate())).unwrap();
+        assert_eq!(settings.limits, defaults());
+
+        let settings = core.run(service.reset_limits(token.clone(), user_id)).unwrap();
+        assert_eq!(settings.limits, defaults());
+        assert!(!settings.overridden);
+        let invalid = TransactionLimits { eth: -1.0, ..raised };
+        assert!(core.run(service.set_limits(token, user_id, invalid)).is_err());
+    }
+
+    #[test]
+    fn test_transaction_limits_only_system_user() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let service = TransactionLimitsServiceImpl::new(
+            Arc::new(AuthServiceMock::new(vec![(token.clone(), user_id)])),
+            Arc::new(KeyValuesRepoMock::default()),
+            defaults(),
+            UserId::generate(),
+            DbExecutorMock::default(),
+        );
+        assert!(core.run(service.get_limits(token.clone(), user_id)).is_err());
+        assert!(core.run(service.set_limits(token, user_id, defaults())).is_err());
+    }
+}
@@ -6,12 +6,13 @@ use futures::IntoFuture;
 
 use super::super::error::*;
 use super::super::system::SystemService;
+use client::blockchain_gateway::{Error as BlockchainClientError, ErrorKind as BlockchainClientErrorKind};
 use client::{BlockchainClient, ExchangeClient, KeysClient};
 use config::Config;
 use models::*;
 use prelude::*;
-use repos::{DbExecutor, KeyValuesRepo, PendingBlockchainTransactionsRepo};
-use utils::log_and_capture_error;
+use repos::{DbExecutor, KeyValuesRepo, KeyValuesRepoExt, PendingBlockchainTransactionsRepo};
+use utils::{log_and_capture_error, log_error};
 
 pub struct FeeEstimate {
     pub gross_fee: Amount,
@@ -41,6 +42,12 @@ pub trait BlockchainService: Send + Sync + 'static {
         input_fee_currency: Currency,
         withdrawal_currency: Currency,
     ) -> Box<Future<Item = FeeEstimate, Error = Error> + Send>;
+    /// Posts already signed transaction, the id of erc-20 transfer includes its log index
+    fn broadcast_raw_tx(
+        &self,
+        currency: Currency,
+        raw_tx: BlockchainTransactionRaw,
+    ) -> Box<Future<Item = BlockchainTransactionId, Error = Error> + Send>;
 }
 
 #[derive(Clone)]
@@ -77,6 +84,53 @@ impl<E: DbExecutor> BlockchainServiceImpl<E> {
             db_executor,
         }
     }
+
+    // Posts signed transaction and stores it as pending. If blockchain gateway can't be reached, the transaction
+    // is stored under a placeholder id to be posted again in background, and the placeholder is returned,
+    // so that the withdrawal is not lost after the keys service has signed it. The transaction that gateway
+    // rejected would be rejected again, so then the error is returned
+    fn broadcast_and_store(
+        &self,
+        create_blockchain_input: CreateBlockchainTx,
+        raw_tx: BlockchainTransactionRaw,
+    ) -> impl Future<Item = BlockchainTransactionId, Error = Error> + Send {
+        let db_executor = self.db_executor.clone();
+        let pending_blockchain_transactions_repo = self.pending_blockchain_transactions_repo.clone();
+        let broadcast_retries = self.config.broadcast_retries.clone();
+        self.broadcast_raw_tx(create_blockchain_input.currency, raw_tx.clone())
+            .then(move |res| {
+                db_executor.execute(move || match res {
+                    Ok(tx_id) => {
+                        let new_pending = (create_blockchain_input, tx_id.clone(), raw_tx).into();
+                        // Note - we don't rollback here, because the tx is already in blockchain. so after that just silently
+                        // fail if we couldn't write a pending tx. Not having pending tx in db doesn't do a lot of harm, we could cure
+                        // it later.
+                        match pending_blockchain_transactions_repo.create(new_pending) {
+                            Err(e) => log_and_capture_error(e),
+                            _ => (),
+                        };
+                        Ok(tx_id)
+                    }
+                    Err(e) => {
+                        if is_rejected_by_gateway(&e) {
+                            return Err(e);
+                        }
+                        log_error(&e);
+                        let tx_id = BlockchainTransactionId::unbroadcasted();
+                        let mut new_pending: NewPendingBlockchainTransactionDB = (create_blockchain_input, tx_id.clone(), raw_tx).into();
+                        new_pending.next_broadcast_at = broadcast_retries.next_attempt_at(0);
+                        match pending_blockchain_transactions_repo.create(new_pending) {
+                            Ok(_) => Ok(tx_id),
+                            // nothing to retry from, so the withdrawal fails as if it was never signed
+                            Err(store_error) => {
+                                log_and_capture_error(store_error);
+                                Err(e)
+                            }
+                        }
+                    }
+                })
+            })
+    }
 }
 
 impl<E: DbExecutor> BlockchainService for BlockchainServiceImpl<E> {
@@ -148,6 +202,24 @@ impl<E: DbExecutor> BlockchainService for BlockchainServiceImpl<E> {
         )
     }
 
+    fn broadcast_raw_tx(
+        &self,
+        currency: Currency,
+        raw_tx: BlockchainTransactionRaw,
+    ) -> Box<Future<Item = BlockchainTransactionId, Error = Error> + Send> {
+        let raw_tx_clone = raw_tx.clone();
+        let fut = match currency {
+            Currency::Btc => self.blockchain_client.post_bitcoin_transaction(raw_tx),
+            Currency::Eth | Currency::Stq => self.blockchain_client.post_ethereum_transaction(raw_tx),
+        };
+        Box::new(fut.map_err(ectx!(convert => raw_tx_clone)).map(move |tx_id| match currency {
+            // Erc-20 token, we need event log number here, to make a tx_id unique.
+            // Our transfers have the only log
+            Currency::Stq => tx_id.with_log_index(0),
+            _ => tx_id,
+        }))
+    }
+
     fn create_bitcoin_tx(
         &self,
         from: BlockchainAddress,
@@ -156,10 +228,8 @@ impl<E: DbExecutor> BlockchainService for BlockchainServiceImpl<E> {
         fee_price: f64,
     ) -> Box<Future<Item = BlockchainTransactionId, Error = Error> + Send> {
         let from_clone = from.clone();
-        let db_executor = self.db_executor.clone();
-        let blockchain_client = self.blockchain_client.clone();
         let keys_client = self.keys_client.clone();
-        let pending_blockchain_transactions_repo = self.pending_blockchain_transactions_repo.clone();
+        let self_clone = self.clone();
         Box::new(
             self.blockchain_client
                 .get_bitcoin_utxos(from.clone())
@@ -171,25 +241,7 @@ impl<E: DbExecutor> BlockchainService for BlockchainServiceImpl<E> {
                     keys_client
                         .sign_transaction(create_blockchain_input.clone(), Role::User)
                         .map_err(ectx!(convert => create_blockchain_input_clone, Role::User))
-                        .and_then(move |raw_tx| {
-                            blockchain_client
-                                .post_bitcoin_transaction(raw_tx.clone())
-                                .map_err(ectx!(convert => raw_tx))
-                        })
-                        .and_then(move |blockchain_tx_id| {
-                            db_executor.execute(move || {
-                                let new_pending = (create_blockchain_input, blockchain_tx_id.clone()).into();
-                                // Note - we don't rollback here, because the tx is already in blockchain. so after that just silently
-                                // fail if we couldn't write a pending tx. Not having pending tx in db doesn't do a lot of harm, we could cure
-                                // it later.
-                                match pending_blockchain_transactions_repo.create(new_pending) {
-                                    Err(e) => log_and_capture_error(e),
-                                    _ => (),
-                                };
-
-                                Ok(blockchain_tx_id)
-                            })
-                        })
+                        .and_then(move |raw_tx| self_clone.broadcast_and_store(create_blockchain_input, raw_tx))
                 }),
         )
     }
@@ -203,11 +255,9 @@ impl<E: DbExecutor> BlockchainService for BlockchainServiceImpl<E> {
         currency: Currency,
     ) -> Box<Future<Item = BlockchainTransactionId, Error = Error> + Send> {
         let db_executor = self.db_executor.clone();
-        let db_executor_clone = self.db_executor.clone();
         let blockchain_client = self.blockchain_client.clone();
-        let blockchain_client_clone = self.blockchain_client.clone();
         let keys_client = self.keys_client.clone();
-        let pending_blockchain_transactions_repo = self.pending_blockchain_transactions_repo.clone();
+        let self_clone = self.clone();
         let key_values_repo = self.key_values_repo.clone();
         let system_service = self.system_service.clone();
 
@@ -289,34 +339,22 @@ impl<E: DbExecutor> BlockchainService for BlockchainServiceImpl<E> {
                     keys_client
                         .sign_transaction(create_blockchain_input.clone(), Role::User)
                         .map_err(ectx!(convert => create_blockchain_input))
-                        .and_then(move |raw_tx| {
-                            blockchain_client_clone
-                                .post_ethereum_transaction(raw_tx.clone())
-                                .map_err(ectx!(convert => raw_tx))
-                        })
-                        .and_then(move |tx_id| {
-                            db_executor_clone.execute(move || {
-                                let tx_id = match currency {
-                                    Currency::Eth => tx_id,
-                                    // Erc-20 token, we need event log number here, to make a tx_id unique
-                                    _ => BlockchainTransactionId::new(format!("{}:0", tx_id)),
-                                };
-                                let new_pending = (create_blockchain, tx_id.clone()).into();
-                                // Note - we don't rollback here, because the tx is already in blockchain. so after that just silently
-                                // fail if we couldn't write a pending tx. Not having pending tx in db doesn't do a lot of harm, we could cure
-                                // it later.
-                                match pending_blockchain_transactions_repo.create(new_pending) {
-                                    Err(e) => log_and_capture_error(e),
-                                    _ => (),
-                                };
-                                Ok(tx_id)
-                            })
-                        })
+                        .and_then(move |raw_tx| self_clone.broadcast_and_store(create_blockchain, raw_tx))
                 }),
         )
     }
 }
 
+/// Gateway got the transaction and refused it, e.g. as malformed or double spending,
+/// as opposed to not being able to deliver it at all
+fn is_rejected_by_gateway(e: &Error) -> bool {
+    let e: &Fail = e;
+    e.iter_chain().any(|cause| match cause.downcast_ref::<BlockchainClientError>() {
+        Some(client_error) => client_error.kind() == BlockchainClientErrorKind::Rejected,
+        None => false,
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -327,9 +365,12 @@ mod tests {
     use tokio_core::reactor::Core;
 
     fn create_blockchain_service() -> BlockchainServiceImpl<DbExecutorMock> {
+        create_blockchain_service_with_client(Arc::new(BlockchainClientMock::default()))
+    }
+
+    fn create_blockchain_service_with_client(blockchain_client: Arc<dyn BlockchainClient>) -> BlockchainServiceImpl<DbExecutorMock> {
         let config = Arc::new(Config::new().unwrap());
         let keys_client = Arc::new(KeysClientMock::default());
-        let blockchain_client = Arc::new(BlockchainClientMock::default());
         let exchange_client = Arc::new(ExchangeClientMock::default());
         let pending_blockchain_transactions_repo = Arc::new(PendingBlockchainTransactionsRepoMock::default());
         let key_values_repo = Arc::new(KeyValuesRepoMock::default());
@@ -356,6 +397,84 @@ mod tests {
         )
     }
 
+    /// Fails to post transactions with `kind`, behaves like `BlockchainClientMock` otherwise
+    struct FailingBlockchainClient {
+        kind: BlockchainClientErrorKind,
+    }
+
+    impl BlockchainClient for FailingBlockchainClient {
+        fn get_balance(
+            &self,
+            address: BlockchainAddress,
+            currency: Currency,
+        ) -> Box<Future<Item = Amount, Error = BlockchainClientError> + Send> {
+            BlockchainClientMock::default().get_balance(address, currency)
+        }
+        fn get_transaction(
+            &self,
+            hash: BlockchainTransactionId,
+            currency: Currency,
+        ) -> Box<Future<Item = Option<BlockchainTransaction>, Error = BlockchainClientError> + Send> {
+            BlockchainClientMock::default().get_transaction(hash, currency)
+        }
+        fn post_ethereum_transaction(
+            &self,
+            _transaction: BlockchainTransactionRaw,
+        ) -> Box<Future<Item = BlockchainTransactionId, Error = BlockchainClientError> + Send> {
+            Box::new(Err(BlockchainClientError::from(self.kind)).into_future())
+        }
+        fn post_bitcoin_transaction(
+            &self,
+            _transaction: BlockchainTransactionRaw,
+        ) -> Box<Future<Item = BlockchainTransactionId, Error = BlockchainClientError> + Send> {
+            Box::new(Err(BlockchainClientError::from(self.kind)).into_future())
+        }
+        fn get_bitcoin_utxos(
+            &self,
+            address: BlockchainAddress,
+        ) -> Box<Future<Item = Vec<BitcoinUtxos>, Error = BlockchainClientError> + Send> {
+            BlockchainClientMock::default().get_bitcoin_utxos(address)
+        }
+        fn get_ethereum_nonce(&self, address: BlockchainAddress) -> Box<Future<Item = u64, Error = BlockchainClientError> + Send> {
+            BlockchainClientMock::default().get_ethereum_nonce(address)
+        }
+    }
+
+    #[test]
+    fn test_broadcast_failures() {
+        let mut core = Core::new().unwrap();
+        // gateway is down - transaction is kept to be posted again
+        let service = create_blockchain_service_with_client(Arc::new(FailingBlockchainClient {
+            kind: BlockchainClientErrorKind::Unavailable,
+        }));
+        let tx_id = core
+            .run(service.create_ethereum_tx(
+                BlockchainAddress::default(),
+                BlockchainAddress::default(),
+                Amount::new(100500),
+                0f64,
+                Currency::Eth,
+            ))
+            .unwrap();
+        assert!(tx_id.is_unbroadcasted());
+        let pending = service.pending_blockchain_transactions_repo.get(tx_id).unwrap().unwrap();
+        assert!(pending.next_broadcast_at.is_some());
+        // gateway refused it - the error is returned and nothing is left to retry
+        let service = create_blockchain_service_with_client(Arc::new(FailingBlockchainClient {
+            kind: BlockchainClientErrorKind::Rejected,
+        }));
+        assert!(core
+            .run(service.create_ethereum_tx(
+                BlockchainAddress::default(),
+                BlockchainAddress::default(),
+                Amount::new(100500),
+                0f64,
+                Currency::Eth,
+            ))
+            .is_err());
+        assert_eq!(service.pending_blockchain_transactions_repo.count().unwrap(), 0);
+    }
+
     #[test]
     fn test_blockchain_create_btc_happy() {
         let service = create_blockchain_service();
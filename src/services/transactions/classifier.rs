@@ -8,7 +8,7 @@ use super::super::error::*;
 use config::Config;
 use models::*;
 use prelude::*;
-use repos::{AccountsRepo, TransactionsRepo};
+use repos::{AccountsRepo, KeyValuesRepo, KeyValuesRepoExt, TransactionsRepo, UsersRepo, WithdrawalAddressesRepo};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum TransactionType {
@@ -20,34 +20,61 @@ pub enum TransactionType {
 
 pub trait ClassifierService: Send + Sync + 'static {
     fn validate_and_classify_transaction(&self, input: &CreateTransactionInput) -> Result<TransactionType, Error>;
+    /// Validates the fee payer of a classified transaction. Returns the account the fee is
+    /// written off, if it's not the sending one
+    fn get_fee_payer_account(&self, input: &CreateTransactionInput, tx_type: &TransactionType) -> Result<Option<Account>, Error>;
+    /// Validates payout as a whole - its total value against limits and balance of the sending
+    /// account, and each of the destinations. Returns the sending account
+    fn validate_payout(&self, input: &CreatePayoutInput) -> Result<Account, Error>;
+    /// Whether exchange from `from` to `to` is allowed now, by runtime override or config
+    fn is_exchange_pair_allowed(&self, from: Currency, to: Currency) -> Result<bool, Error>;
 }
 
 #[derive(Clone)]
 pub struct ClassifierServiceImpl {
     accounts_repo: Arc<AccountsRepo>,
     transactions_repo: Arc<TransactionsRepo>,
+    users_repo: Arc<UsersRepo>,
+    withdrawal_addresses_repo: Arc<WithdrawalAddressesRepo>,
+    key_values_repo: Arc<KeyValuesRepo>,
     stq_wei_limit: Amount,
     eth_wei_limit: Amount,
     btc_satoshi_limit: Amount,
     limit_period: Duration,
+    transaction_limits: TransactionLimits,
+    exchange_pairs: ExchangePairs,
 }
 
 const WEI_IN_ETH: u128 = 1_000_000_000_000_000_000;
 const SATOSHI_IN_BTC: u128 = 100_000_000;
 
 impl ClassifierServiceImpl {
-    pub fn new(config: &Config, accounts_repo: Arc<AccountsRepo>, transactions_repo: Arc<TransactionsRepo>) -> Self {
+    pub fn new(
+        config: &Config,
+        accounts_repo: Arc<AccountsRepo>,
+        transactions_repo: Arc<TransactionsRepo>,
+        users_repo: Arc<UsersRepo>,
+        withdrawal_addresses_repo: Arc<WithdrawalAddressesRepo>,
+        key_values_repo: Arc<KeyValuesRepo>,
+    ) -> Self {
         let stq_wei_limit = Amount::new((config.limits.stq_limit as u128) * WEI_IN_ETH);
         let eth_wei_limit = Amount::new(((config.limits.eth_limit * 1000.0) as u128) * WEI_IN_ETH / 1000);
         let btc_satoshi_limit = Amount::new(((config.limits.btc_limit * 1000.0) as u128) * SATOSHI_IN_BTC / 1000);
         let limit_period = Duration::seconds(config.limits.period_secs as i64);
+        let transaction_limits = config.limits.transaction_limits();
+        let exchange_pairs = config.exchange_options.exchange_pairs();
         Self {
             accounts_repo,
             transactions_repo,
+            users_repo,
+            withdrawal_addresses_repo,
+            key_values_repo,
             stq_wei_limit,
             eth_wei_limit,
             btc_satoshi_limit,
             limit_period,
+            transaction_limits,
+            exchange_pairs,
         }
     }
 
@@ -57,20 +84,7 @@ impl ClassifierServiceImpl {
             .transactions_repo
             .get_account_spending(acct_id.clone(), acct_kind.clone(), limit_period.clone())
             .map_err(ectx!(try ErrorKind::Internal => acct_id, acct_kind, limit_period))?;
-        let from_currency = account.currency;
-        let to_currency = input.to_currency;
-        let from_value = match input.value_currency {
-            currency if currency == from_currency => input.value,
-            currency if currency == to_currency => {
-                if let Some(rate) = input.exchange_rate {
-                    // we trust user input here, since o/w the exchange will fail anyway
-                    input.value.convert(to_currency, from_currency, 1.0 / rate)
-                } else {
-                    return Err(ectx!(err ErrorContext::MissingExchangeRate, ErrorKind::MalformedInput));
-                }
-            }
-            _ => return Err(ectx!(err ErrorContext::InvalidCurrency, ErrorKind::MalformedInput)),
-        };
+        let from_value = get_from_value(input, account)?;
         let spending = spending
             .checked_add(from_value)
             .ok_or(ectx!(try err ErrorContext::BalanceOverflow, ErrorKind::Internal))?;
@@ -95,6 +109,83 @@ impl ClassifierServiceImpl {
         Ok(())
     }
 
+    fn check_account_transaction_limit(&self, input: &CreateTransactionInput, account: &Account) -> Result<(), Error> {
+        if account.daily_limit_type != DailyLimitType::DefaultLimit {
+            return Ok(());
+        }
+        let user_id = account.user_id;
+        let limits = self
+            .key_values_repo
+            .get_transaction_limits(user_id)
+            .map_err(ectx!(try convert => user_id))?
+            .unwrap_or_else(|| self.transaction_limits.clone());
+        let from_value = get_from_value(input, account)?;
+        let limit = limits.limit(account.currency);
+        if from_value.to_super_unit(account.currency) > limit {
+            let mut errors = ValidationErrors::new();
+            let mut error = ValidationError::new("exceeded_transaction_limit");
+            error.message = Some("transaction limit for the account exceeded".into());
+            error.add_param("limit".into(), &limit.to_string());
+            error.add_param("currency".into(), &account.currency.to_string().to_uppercase());
+            errors.add("value", error);
+            return Err(
+                ectx!(err ErrorContext::LimitExceeded, ErrorKind::InvalidInput(serde_json::to_string(&errors).unwrap_or_default()) => from_value, limit),
+            );
+        }
+        Ok(())
+    }
+
+    fn check_exchange_pair(&self, from: Currency, to: Currency) -> Result<(), Error> {
+        if self.is_exchange_pair_allowed(from, to)? {
+            Ok(())
+        } else {
+            Err(
+                ectx!(err ErrorContext::ExchangePairNotAllowed, invalid_input("toCurrency", "pair_not_allowed", "exchange between these currencies is not allowed now") => from, to),
+            )
+        }
+    }
+
+    fn check_withdrawal_whitelist(
+        &self,
+        from_account: &Account,
+        to_address: &BlockchainAddress,
+        to_currency: Currency,
+    ) -> Result<(), Error> {
+        let user_id = from_account.user_id;
+        let whitelist_enabled = self
+            .users_repo
+            .get(user_id)
+            .map_err(ectx!(try convert => user_id))?
+            .map(|user| user.withdrawal_whitelist)
+            .unwrap_or(false);
+        if !whitelist_enabled {
+            return Ok(());
+        }
+        let whitelisted = self
+            .withdrawal_addresses_repo
+            .find(user_id, to_currency, to_address.clone())
+            .map_err(ectx!(try convert => user_id, to_currency, to_address))?;
+        let error = match whitelisted {
+            Some(ref address) if address.is_active() => return Ok(()),
+            Some(address) => {
+                let mut error = ValidationError::new("not_active");
+                error.message = Some("whitelisted address is not active yet".into());
+                error.add_param("activeFrom".into(), &address.active_from.to_string());
+                error
+            }
+            None => {
+                let mut error = ValidationError::new("not_whitelisted");
+                error.message = Some("address is not in the withdrawal whitelist".into());
+                error
+            }
+        };
+        let mut errors = ValidationErrors::new();
+        errors.add("to", error);
+        Err(
+            ectx!(err ErrorContext::AddressNotWhitelisted, ErrorKind::InvalidInput(serde_json::to_string(&errors).unwrap_or_default()) => user_id, to_currency, to_address),
+        )
+    }
+
     fn get_from_account(&self, input: &CreateTransactionInput) -> Result<Account, Error> {
         self.accounts_repo
             .get(input.from)
@@ -122,8 +213,16 @@ impl ClassifierServiceImpl {
             }
             RecepientType::Address => {
                 let to_address = input.to.clone().to_account_address();
+                let to_account = self
+                    .accounts_repo
+                    .get_by_address(to_address.clone(), input.to_currency, AccountKind::Cr, ArchivedAccounts::Include)
+                    .map_err(ectx!(try convert => to_address, input.to_currency))?;
+                if to_account.is_some() {
+                    return Ok(to_account);
+                }
+                // rotated addresses still belong to their accounts, so transfers to them stay internal
                 self.accounts_repo
-                    .get_by_address(to_address.clone(), input.to_currency, AccountKind::Cr)
+                    .get_by_expired_address(to_address.clone(), input.to_currency)
                     .map_err(ectx!(convert => to_address, input.to_currency))
             }
         }
@@ -156,10 +255,13 @@ impl ClassifierServiceImpl {
                 // check that we don't own any other accounts with this address
                 // eg a user accidentially put ether address to receive stq tokens
                 let to_address = input.to.clone().to_account_address();
-                let accounts = self.accounts_repo.filter_by_address(to_address.clone()).map_err({
-                    let to_address = to_address.clone();
-                    ectx!(try convert => to_address)
-                })?;
+                let accounts = self
+                    .accounts_repo
+                    .filter_by_address(to_address.clone(), ArchivedAccounts::Include)
+                    .map_err({
+                        let to_address = to_address.clone();
+                        ectx!(try convert => to_address)
+                    })?;
                 if accounts.len() != 0 {
                     return Err(ectx!(err ErrorContext::InvalidCurrency, ErrorKind::MalformedInput => input.clone()));
                 }
@@ -190,9 +292,178 @@ impl ClassifierService for ClassifierServiceImpl {
             .validate()
             .map_err(|e| ectx!(try err e.clone(), ErrorKind::InvalidInput(serde_json::to_string(&e).unwrap_or_default()) => input))?;
         let from_account = self.get_from_account(input)?;
+        self.check_account_transaction_limit(input, &from_account)?;
         self.check_account_daily_limit(input, &from_account)?;
         let to_account = self.get_to_account(input)?;
-        self.get_transaction_type(input, from_account, to_account)
+        let tx_type = self.get_transaction_type(input, from_account, to_account)?;
+        match tx_type {
+            TransactionType::InternalExchange(ref from_account, ref to_account, _, _) => {
+                self.check_exchange_pair(from_account.currency, to_account.currency)?
+            }
+            TransactionType::WithdrawalExchange(ref from_account, _, to_currency, _, _) => {
+                self.check_exchange_pair(from_account.currency, to_currency)?
+            }
+            _ => (),
+        };
+        match tx_type {
+            TransactionType::Withdrawal(ref from_account, ref to_address, to_currency)
+            | TransactionType::WithdrawalExchange(ref from_account, ref to_address, to_currency, _, _) => {
+                self.check_withdrawal_whitelist(from_account, to_address, to_currency)?
+            }
+            _ => (),
+        };
+        Ok(tx_type)
+    }
+
+    fn get_fee_payer_account(&self, input: &CreateTransactionInput, tx_type: &TransactionType) -> Result<Option<Account>, Error> {
+        if input.fee_payer != FeePayer::Account && input.fee_payer_account_id.is_some() {
+            return Err(
+                ectx!(err ErrorContext::InvalidFeePayer, invalid_input("feePayerAccountId", "unexpected", "fee payer account is only allowed with `account` fee payer") => input),
+            );
+        }
+        if input.fee_payer == FeePayer::Sender {
+            return Ok(None);
+        }
+        let from_account = match tx_type {
+            TransactionType::Withdrawal(from_account, _, _) => from_account,
+            _ => {
+                return Err(
+                    ectx!(err ErrorContext::InvalidFeePayer, invalid_input("feePayer", "not_withdrawal", "fee payer can only be chosen for withdrawals") => input),
+                );
+            }
+        };
+        match input.fee_payer {
+            FeePayer::Recipient => {
+                if input.fee >= input.value {
+                    return Err(
+                        ectx!(err ErrorContext::InvalidFeePayer, invalid_input("fee", "exceeds_value", "fee paid by recipient must be less than the value") => input),
+                    );
+                }
+                Ok(None)
+            }
+            _ => {
+                let fee_payer_account_id = input.fee_payer_account_id.ok_or(
+                    ectx!(try err ErrorContext::InvalidFeePayer, invalid_input("feePayerAccountId", "required", "fee payer account is required with `account` fee payer") => input),
+                )?;
+                let fee_payer_account = self
+                    .accounts_repo
+                    .get(fee_payer_account_id)
+                    .map_err(ectx!(try convert => fee_payer_account_id))?
+                    .filter(|account| account.user_id == from_account.user_id && account.kind == AccountKind::Cr && !account.archived)
+                    .ok_or(ectx!(try err ErrorContext::NoAccount, ErrorKind::NotFound => input))?;
+                if fee_payer_account.currency != from_account.currency {
+                    return Err(
+                        ectx!(err ErrorContext::InvalidFeePayer, invalid_input("feePayerAccountId", "invalid_currency", "fee payer account must be of the same currency") => input),
+                    );
+                }
+                Ok(Some(fee_payer_account))
+            }
+        }
+    }
+
+    fn validate_payout(&self, input: &CreatePayoutInput) -> Result<Account, Error> {
+        input
+            .validate()
+            .map_err(|e| ectx!(try err e.clone(), ErrorKind::InvalidInput(serde_json::to_string(&e).unwrap_or_default()) => input))?;
+        let from_account = self
+            .accounts_repo
+            .get(input.from)
+            .map_err(ectx!(try convert => input.from))?
+            .ok_or(ectx!(try err ErrorContext::NoAccount, ErrorKind::NotFound => input))?;
+        if from_account.user_id != input.user_id {
+            return Err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => input.user_id));
+        }
+        if from_account.currency != input.currency {
+            return Err(ectx!(err ErrorContext::InvalidCurrency, ErrorKind::MalformedInput => input));
+        }
+        let value = input
+            .payouts
+            .iter()
+            .fold(Some(Amount::new(0)), |total, payout| {
+                total.and_then(|total| total.checked_add(payout.value))
+            })
+            .ok_or(ectx!(try err ErrorContext::BalanceOverflow, ErrorKind::Internal => input))?;
+        let fee = input
+            .fee
+            .checked_mul(Amount::new(input.payouts.len() as u128))
+            .ok_or(ectx!(try err ErrorContext::BalanceOverflow, ErrorKind::Internal => input))?;
+        // limits are checked against the payout as a single withdrawal
+        let total_input = CreateTransactionInput {
+            id: input.id,
+            user_id: input.user_id,
+            from: input.from,
+            to: Recepient::new(String::new()),
+            to_type: RecepientType::Address,
+            to_currency: input.currency,
+            value,
+            value_currency: input.currency,
+            fee,
+            exchange_id: None,
+            exchange_rate: None,
+            fee_payer: FeePayer::Sender,
+            fee_payer_account_id: None,
+            sweep: false,
+            rate_lock_id: None,
+            max_slippage: None,
+        };
+        self.check_account_transaction_limit(&total_input, &from_account)?;
+        self.check_account_daily_limit(&total_input, &from_account)?;
+        let balance = self
+            .transactions_repo
+            .get_accounts_balance(input.user_id, &[from_account.clone()])
+            .map_err(ectx!(try convert => input.user_id))?
+            .get(0)
+            .map(|account| account.balance)
+            .unwrap_or_default();
+        let total = value
+            .checked_add(fee)
+            .ok_or(ectx!(try err ErrorContext::BalanceOverflow, ErrorKind::Internal => value, fee))?;
+        if balance < total {
+            return Err(
+                ectx!(err ErrorContext::NotEnoughFunds, invalid_input("payouts", "not_enough_balance", "account balance is not enough") => balance, total),
+            );
+        }
+        for payout in &input.payouts {
+            let address = payout.address.clone();
+            let accounts = self
+                .accounts_repo
+                .filter_by_address(address.clone(), ArchivedAccounts::Include)
+                .map_err(ectx!(try convert => address))?;
+            if !accounts.is_empty() {
+                return Err(
+                    ectx!(err ErrorContext::InvalidTransaction, invalid_input("payouts", "internal_address", "payouts can only be sent to external addresses") => payout),
+                );
+            }
+            self.check_withdrawal_whitelist(&from_account, &payout.address, input.currency)?;
+        }
+        Ok(from_account)
+    }
+
+    fn is_exchange_pair_allowed(&self, from: Currency, to: Currency) -> Result<bool, Error> {
+        let exchange_pairs = self
+            .key_values_repo
+            .get_exchange_pairs()
+            .map_err(ectx!(try convert))?
+            .unwrap_or_else(|| self.exchange_pairs.clone());
+        Ok(exchange_pairs.allows(from, to))
+    }
+}
+
+/// Value of transaction in the currency of the account it's sent from
+fn get_from_value(input: &CreateTransactionInput, account: &Account) -> Result<Amount, Error> {
+    let from_currency = account.currency;
+    let to_currency = input.to_currency;
+    match input.value_currency {
+        currency if currency == from_currency => Ok(input.value),
+        currency if currency == to_currency => {
+            if let Some(rate) = input.exchange_rate {
+                // we trust user input here, since o/w the exchange will fail anyway
+                Ok(input.value.convert(to_currency, from_currency, 1.0 / rate))
+            } else {
+                Err(ectx!(err ErrorContext::MissingExchangeRate, ErrorKind::MalformedInput))
+            }
+        }
+        _ => Err(ectx!(err ErrorContext::InvalidCurrency, ErrorKind::MalformedInput)),
     }
 }
 
@@ -203,9 +474,29 @@ mod tests {
     use repos::*;
 
     fn create_classifier_service(accounts_repo: Arc<dyn AccountsRepo>) -> ClassifierServiceImpl {
+        create_classifier_service_with_whitelist(
+            accounts_repo,
+            Arc::new(UsersRepoMock::default()),
+            Arc::new(WithdrawalAddressesRepoMock::default()),
+        )
+    }
+
+    fn create_classifier_service_with_whitelist(
+        accounts_repo: Arc<dyn AccountsRepo>,
+        users_repo: Arc<dyn UsersRepo>,
+        withdrawal_addresses_repo: Arc<dyn WithdrawalAddressesRepo>,
+    ) -> ClassifierServiceImpl {
         let config = Config::new().unwrap();
         let transactions_repo = Arc::new(TransactionsRepoMock::default());
-        ClassifierServiceImpl::new(&config, accounts_repo, transactions_repo)
+        let key_values_repo = Arc::new(KeyValuesRepoMock::default());
+        ClassifierServiceImpl::new(
+            &config,
+            accounts_repo,
+            transactions_repo,
+            users_repo,
+            withdrawal_addresses_repo,
+            key_values_repo,
+        )
     }
 
     fn create_internal_transaction_input(
@@ -229,6 +520,11 @@ mod tests {
             fee: Amount::default(),
             exchange_id: None,
             exchange_rate: None,
+            fee_payer: FeePayer::Sender,
+            fee_payer_account_id: None,
+            sweep: false,
+            rate_lock_id: None,
+            max_slippage: None,
         }
     }
 
@@ -255,6 +551,11 @@ mod tests {
             fee: Amount::default(),
             exchange_id,
             exchange_rate,
+            fee_payer: FeePayer::Sender,
+            fee_payer_account_id: None,
+            sweep: false,
+            rate_lock_id: None,
+            max_slippage: None,
         }
     }
 
@@ -278,6 +579,11 @@ mod tests {
             fee: Amount::default(),
             exchange_id: None,
             exchange_rate: None,
+            fee_payer: FeePayer::Sender,
+            fee_payer_account_id: None,
+            sweep: false,
+            rate_lock_id: None,
+            max_slippage: None,
         }
     }
 
@@ -303,6 +609,11 @@ mod tests {
             fee: Amount::default(),
             exchange_id,
             exchange_rate,
+            fee_payer: FeePayer::Sender,
+            fee_payer_account_id: None,
+            sweep: false,
+            rate_lock_id: None,
+            max_slippage: None,
         }
     }
 
@@ -589,6 +900,48 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_classify_internal_exchange_paused_pair() {
+        let config = Config::new().unwrap();
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let key_values_repo = Arc::new(KeyValuesRepoMock::default());
+        let service = ClassifierServiceImpl::new(
+            &config,
+            accounts_repo.clone(),
+            Arc::new(TransactionsRepoMock::default()),
+            Arc::new(UsersRepoMock::default()),
+            Arc::new(WithdrawalAddressesRepoMock::default()),
+            key_values_repo.clone(),
+        );
+        let user_id = UserId::generate();
+        let mut new_account = NewAccount::default();
+        new_account.user_id = user_id;
+        new_account.currency = Currency::Stq;
+        let acc1 = accounts_repo.create(new_account).unwrap();
+        let mut new_account = NewAccount::default();
+        new_account.user_id = user_id;
+        new_account.currency = Currency::Btc;
+        let acc2 = accounts_repo.create(new_account).unwrap();
+        let exchange_id = Some(ExchangeId::generate());
+        let input = create_internal_exchange_transaction_input(
+            user_id,
+            acc1.id,
+            acc1.currency,
+            Recepient::new(acc2.id.to_string()),
+            RecepientType::Account,
+            acc2.currency,
+            Amount::new(0),
+            exchange_id,
+            Some(1f64),
+        );
+        assert!(service.validate_and_classify_transaction(&input).is_ok());
+
+        let mut pairs = config.exchange_options.exchange_pairs();
+        pairs.pairs.retain(|pair| pair.from != Currency::Stq || pair.to != Currency::Btc);
+        key_values_repo.set_exchange_pairs(pairs).unwrap();
+        assert!(service.validate_and_classify_transaction(&input).is_err());
+    }
+
     #[test]
     fn test_classify_internal_exchange_wrong_exchange_data() {
         let accounts_repo = Arc::new(AccountsRepoMock::default());
@@ -786,6 +1139,191 @@ mod tests {
         assert_eq!(res, TransactionType::Withdrawal(acc1.clone(), address, acc1.currency));
     }
 
+    #[test]
+    fn test_classify_withdraw_fee_payer() {
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let user_id = UserId::generate();
+        let service = create_classifier_service(accounts_repo.clone());
+        let mut new_account = NewAccount::default();
+        new_account.user_id = user_id;
+        new_account.currency = Currency::Eth;
+        let acc1 = accounts_repo.create(new_account.clone()).unwrap();
+        new_account.id = AccountId::generate();
+        let acc2 = accounts_repo.create(new_account.clone()).unwrap();
+        new_account.id = AccountId::generate();
+        new_account.currency = Currency::Stq;
+        let stq_account = accounts_repo.create(new_account.clone()).unwrap();
+        let address = BlockchainAddress::default();
+        let mut input = create_withdraw_transaction_input(user_id, acc1.id, acc1.currency, address, acc1.currency, Amount::new(100));
+        input.fee = Amount::new(10);
+        let tx_type = service.validate_and_classify_transaction(&input).unwrap();
+
+        assert_eq!(service.get_fee_payer_account(&input, &tx_type).unwrap(), None);
+        input.fee_payer_account_id = Some(acc2.id);
+        assert!(service.get_fee_payer_account(&input, &tx_type).is_err());
+
+        input.fee_payer = FeePayer::Account;
+        assert_eq!(service.get_fee_payer_account(&input, &tx_type).unwrap(), Some(acc2.clone()));
+        input.fee_payer_account_id = Some(stq_account.id);
+        assert!(service.get_fee_payer_account(&input, &tx_type).is_err());
+        input.fee_payer_account_id = None;
+        assert!(service.get_fee_payer_account(&input, &tx_type).is_err());
+
+        input.fee_payer = FeePayer::Recipient;
+        assert_eq!(service.get_fee_payer_account(&input, &tx_type).unwrap(), None);
+        input.fee = Amount::new(100);
+        assert!(service.get_fee_payer_account(&input, &tx_type).is_err());
+    }
+
+    #[test]
+    fn test_classify_withdraw_transaction_limit() {
+        let config = Config::new().unwrap();
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let key_values_repo = Arc::new(KeyValuesRepoMock::default());
+        let service = ClassifierServiceImpl::new(
+            &config,
+            accounts_repo.clone(),
+            Arc::new(TransactionsRepoMock::default()),
+            Arc::new(UsersRepoMock::default()),
+            Arc::new(WithdrawalAddressesRepoMock::default()),
+            key_values_repo.clone(),
+        );
+        let user_id = UserId::generate();
+        let mut new_account = NewAccount::default();
+        new_account.user_id = user_id;
+        new_account.currency = Currency::Eth;
+        let acc1 = accounts_repo.create(new_account.clone()).unwrap();
+        let address = BlockchainAddress::default();
+        // 0.6 eth is within daily limit, but above transaction limit
+        let value = Amount::new(600_000_000_000_000_000);
+        let input = create_withdraw_transaction_input(user_id, acc1.id, acc1.currency, address.clone(), acc1.currency, value);
+
+        assert!(service.validate_and_classify_transaction(&input).is_err());
+
+        let limits = TransactionLimits {
+            eth: 0.7,
+            ..config.limits.transaction_limits()
+        };
+        key_values_repo.set_transaction_limits(user_id, limits).unwrap();
+        let res = service.validate_and_classify_transaction(&input).unwrap();
+        assert_eq!(res, TransactionType::Withdrawal(acc1.clone(), address, acc1.currency));
+    }
+
+    #[test]
+    fn test_classify_withdraw_whitelist() {
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let users_repo = Arc::new(UsersRepoMock::default());
+        let withdrawal_addresses_repo = Arc::new(WithdrawalAddressesRepoMock::default());
+        let service =
+            create_classifier_service_with_whitelist(accounts_repo.clone(), users_repo.clone(), withdrawal_addresses_repo.clone());
+        let user = users_repo.create(NewUser::default()).unwrap();
+        users_repo
+            .update(
+                user.id,
+                UpdateUser {
+                    withdrawal_whitelist: Some(true),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let mut new_account = NewAccount::default();
+        new_account.user_id = user.id;
+        let acc1 = accounts_repo.create(new_account.clone()).unwrap();
+        let address = BlockchainAddress::new("0x89595fa59d69d696d9d96".to_string());
+        let input = create_withdraw_transaction_input(user.id, acc1.id, acc1.currency, address.clone(), acc1.currency, Amount::new(0));
+
+        assert!(service.validate_and_classify_transaction(&input).is_err());
+
+        let whitelisted = withdrawal_addresses_repo
+            .create(NewWithdrawalAddress {
+                user_id: user.id,
+                currency: acc1.currency,
+                address: address.clone(),
+                active_from: ::chrono::Utc::now().naive_utc() + Duration::hours(1),
+                ..Default::default()
+            })
+            .unwrap();
+        // not active yet
+        assert!(service.validate_and_classify_transaction(&input).is_err());
+
+        withdrawal_addresses_repo.delete(whitelisted.id).unwrap();
+        withdrawal_addresses_repo
+            .create(NewWithdrawalAddress {
+                user_id: user.id,
+                currency: acc1.currency,
+                address: address.clone(),
+                active_from: ::chrono::Utc::now().naive_utc() - Duration::hours(1),
+                ..Default::default()
+            })
+            .unwrap();
+        let res = service.validate_and_classify_transaction(&input).unwrap();
+        assert_eq!(res, TransactionType::Withdrawal(acc1.clone(), address, acc1.currency));
+    }
+
+    #[test]
+    fn test_validate_payout() {
+        let config = Config::new().unwrap();
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let service = ClassifierServiceImpl::new(
+            &config,
+            accounts_repo.clone(),
+            transactions_repo.clone(),
+            Arc::new(UsersRepoMock::default()),
+            Arc::new(WithdrawalAddressesRepoMock::default()),
+            Arc::new(KeyValuesRepoMock::default()),
+        );
+        let user_id = UserId::generate();
+        let mut new_account = NewAccount::default();
+        new_account.user_id = user_id;
+        new_account.currency = Currency::Eth;
+        let acc1 = accounts_repo.create(new_account.clone()).unwrap();
+        new_account.id = AccountId::generate();
+        new_account.address = BlockchainAddress::new("0x89595fa59d69d696d9d96".to_string());
+        let acc2 = accounts_repo.create(new_account.clone()).unwrap();
+        transactions_repo
+            .create(NewTransaction {
+                cr_account_id: acc1.id,
+                currency: Currency::Eth,
+                value: Amount::new(100),
+                ..Default::default()
+            })
+            .unwrap();
+        let mut input = CreatePayoutInput {
+            id: TransactionId::generate(),
+            user_id,
+            from: acc1.id,
+            currency: Currency::Eth,
+            fee: Amount::new(10),
+            payouts: vec![],
+        };
+        assert!(service.validate_payout(&input).is_err());
+
+        input.payouts = vec![
+            PayoutDestination {
+                address: BlockchainAddress::new("0x1".to_string()),
+                value: Amount::new(40),
+            },
+            PayoutDestination {
+                address: BlockchainAddress::new("0x2".to_string()),
+                value: Amount::new(40),
+            },
+        ];
+        assert_eq!(service.validate_payout(&input).unwrap(), acc1.clone());
+
+        // total of values and fees exceeds balance
+        input.fee = Amount::new(11);
+        assert!(service.validate_payout(&input).is_err());
+
+        input.fee = Amount::new(10);
+        input.payouts[1].address = acc2.address.clone();
+        assert!(service.validate_payout(&input).is_err());
+
+        input.payouts.pop();
+        input.user_id = UserId::generate();
+        assert!(service.validate_payout(&input).is_err());
+    }
+
     #[test]
     fn test_classify_withdraw_exceed_limit() {
         let accounts_repo = Arc::new(AccountsRepoMock::default());
@@ -1,5 +1,7 @@
 use std::sync::Arc;
 
+use chrono::NaiveDateTime;
+
 use super::super::error::*;
 use super::super::system::*;
 use models::*;
@@ -94,6 +96,12 @@ impl ConverterServiceImpl {
             blockchain_tx_ids: tx.blockchain_tx_id.iter().cloned().collect(),
             created_at: tx.created_at,
             updated_at: tx.updated_at,
+            kind: tx.kind,
+            group_kind: tx.group_kind,
+            related_tx: tx.related_tx,
+            meta: tx.meta,
+            usd_value: None,
+            estimated_completion_at: None,
         })
     }
 
@@ -145,12 +153,26 @@ impl ConverterServiceImpl {
             blockchain_tx_ids: tx.blockchain_tx_id.iter().cloned().collect(),
             created_at: tx.created_at,
             updated_at: tx.updated_at,
+            kind: tx.kind,
+            group_kind: tx.group_kind,
+            related_tx: tx.related_tx,
+            meta: tx.meta,
+            usd_value: None,
+            estimated_completion_at: None,
         })
     }
 
     // 7) Reversal -
     //   a) Withdrawal - Done, Fee - Done
+    //   b) MultiFrom - Done, MultiTo - Done, exchange of withdrawal that failed to send
+    //   c) Sweep - Done, sweep to cold address that failed to send
     fn convert_reversal_transaction(&self, transactions: Vec<Transaction>) -> Result<TransactionOut, Error> {
+        if transactions.iter().any(|tx| tx.kind == TransactionKind::MultiFrom) {
+            return self.convert_internal_multi_transaction(transactions);
+        }
+        if transactions.iter().any(|tx| tx.kind == TransactionKind::Sweep) {
+            return self.convert_sweep_transaction(transactions);
+        }
         let fee_tx = transactions
             .iter()
             .find(|tx| tx.kind == TransactionKind::Fee)
@@ -228,6 +250,154 @@ impl ConverterServiceImpl {
             blockchain_tx_ids,
             created_at,
             updated_at,
+            kind: withdrawal_tx.kind,
+            group_kind: withdrawal_tx.group_kind,
+            related_tx: withdrawal_tx.related_tx,
+            meta: withdrawal_tx.meta,
+            usd_value: None,
+            estimated_completion_at: None,
+        })
+    }
+
+    // 8) Bounce:
+    //   Funds of strange transaction returned from our address, destination is kept in meta
+    fn convert_bounce_transaction(&self, transactions: Vec<Transaction>) -> Result<TransactionOut, Error> {
+        let bounce_tx = transactions
+            .iter()
+            .find(|tx| tx.kind == TransactionKind::Bounce)
+            .cloned()
+            .ok_or(ectx!(try err ErrorContext::InvalidTransactionStructure, ErrorKind::Internal => transactions))?;
+        let account = self
+            .accounts_repo
+            .get(bounce_tx.dr_account_id)?
+            .ok_or(ectx!(try err ErrorContext::InvalidTransactionStructure, ErrorKind::Internal => transactions))?;
+        let to_address: BlockchainAddress = bounce_tx
+            .meta
+            .as_ref()
+            .and_then(|meta| meta.get("to"))
+            .and_then(|to| serde_json::from_value(to.clone()).ok())
+            .ok_or(ectx!(try err ErrorContext::InvalidTransactionStructure, ErrorKind::Internal => transactions))?;
+        let from = vec![TransactionAddressInfo {
+            account_id: Some(account.id),
+            blockchain_address: account.address,
+        }];
+        let to = TransactionAddressInfo {
+            account_id: None,
+            blockchain_address: to_address,
+        };
+        Ok(TransactionOut {
+            id: bounce_tx.gid,
+            user_id: account.user_id,
+            from,
+            to,
+            from_value: bounce_tx.value,
+            from_currency: bounce_tx.currency,
+            to_value: bounce_tx.value,
+            to_currency: bounce_tx.currency,
+            fee: Amount::new(0),
+            status: bounce_tx.status,
+            blockchain_tx_ids: bounce_tx.blockchain_tx_id.iter().cloned().collect(),
+            created_at: bounce_tx.created_at,
+            updated_at: bounce_tx.updated_at,
+            kind: bounce_tx.kind,
+            group_kind: bounce_tx.group_kind,
+            related_tx: bounce_tx.related_tx,
+            meta: bounce_tx.meta,
+            usd_value: None,
+            estimated_completion_at: None,
+        })
+    }
+
+    // 9) Sweep - from the former address of system account to its current one, once it's sent
+    fn convert_sweep_transaction(&self, transactions: Vec<Transaction>) -> Result<TransactionOut, Error> {
+        let sweep_tx = transactions
+            .iter()
+            .find(|tx| tx.kind == TransactionKind::Sweep)
+            .or_else(|| transactions.iter().find(|tx| tx.kind == TransactionKind::Internal))
+            .cloned()
+            .ok_or(ectx!(try err ErrorContext::InvalidTransactionStructure, ErrorKind::Internal => transactions))?;
+        let from_account = self
+            .accounts_repo
+            .get(sweep_tx.cr_account_id)?
+            .ok_or(ectx!(try err ErrorContext::InvalidTransactionStructure, ErrorKind::Internal => transactions))?;
+        let to_account = self
+            .accounts_repo
+            .get(sweep_tx.dr_account_id)?
+            .ok_or(ectx!(try err ErrorContext::InvalidTransactionStructure, ErrorKind::Internal => transactions))?;
+        let from = vec![TransactionAddressInfo {
+            account_id: Some(from_account.id),
+            blockchain_address: from_account.address,
+        }];
+        let to = TransactionAddressInfo {
+            account_id: Some(to_account.id),
+            blockchain_address: to_account.address,
+        };
+        Ok(TransactionOut {
+            id: sweep_tx.gid,
+            user_id: sweep_tx.user_id,
+            from,
+            to,
+            from_value: sweep_tx.value,
+            from_currency: sweep_tx.currency,
+            to_value: sweep_tx.value,
+            to_currency: sweep_tx.currency,
+            fee: Amount::new(0),
+            status: sweep_tx.status,
+            blockchain_tx_ids: sweep_tx.blockchain_tx_id.iter().cloned().collect(),
+            created_at: sweep_tx.created_at,
+            updated_at: sweep_tx.updated_at,
+            kind: sweep_tx.kind,
+            group_kind: sweep_tx.group_kind,
+            related_tx: sweep_tx.related_tx,
+            meta: sweep_tx.meta,
+            usd_value: None,
+            estimated_completion_at: None,
+        })
+    }
+
+    // 10) FeeRefund - from fees account back to the account that paid withdrawal fee
+    fn convert_fee_refund_transaction(&self, transactions: Vec<Transaction>) -> Result<TransactionOut, Error> {
+        let refund_tx = transactions
+            .iter()
+            .find(|tx| tx.kind == TransactionKind::Fee)
+            .cloned()
+            .ok_or(ectx!(try err ErrorContext::InvalidTransactionStructure, ErrorKind::Internal => transactions))?;
+        let from_account = self
+            .accounts_repo
+            .get(refund_tx.cr_account_id)?
+            .ok_or(ectx!(try err ErrorContext::InvalidTransactionStructure, ErrorKind::Internal => transactions))?;
+        let to_account = self
+            .accounts_repo
+            .get(refund_tx.dr_account_id)?
+            .ok_or(ectx!(try err ErrorContext::InvalidTransactionStructure, ErrorKind::Internal => transactions))?;
+        let from = vec![TransactionAddressInfo {
+            account_id: Some(from_account.id),
+            blockchain_address: from_account.address,
+        }];
+        let to = TransactionAddressInfo {
+            account_id: Some(to_account.id),
+            blockchain_address: to_account.address,
+        };
+        Ok(TransactionOut {
+            id: refund_tx.gid,
+            user_id: refund_tx.user_id,
+            from,
+            to,
+            from_value: refund_tx.value,
+            from_currency: refund_tx.currency,
+            to_value: refund_tx.value,
+            to_currency: refund_tx.currency,
+            fee: Amount::new(0),
+            status: refund_tx.status,
+            blockchain_tx_ids: vec![],
+            created_at: refund_tx.created_at,
+            updated_at: refund_tx.updated_at,
+            kind: refund_tx.kind,
+            group_kind: refund_tx.group_kind,
+            related_tx: refund_tx.related_tx,
+            meta: refund_tx.meta,
+            usd_value: None,
+            estimated_completion_at: None,
         })
     }
 
@@ -284,19 +454,30 @@ impl ConverterServiceImpl {
             blockchain_tx_ids: vec![],
             created_at: from_tx.created_at,
             updated_at: from_tx.updated_at,
+            kind: from_tx.kind,
+            group_kind: from_tx.group_kind,
+            related_tx: from_tx.related_tx,
+            meta: from_tx.meta,
+            usd_value: None,
+            estimated_completion_at: None,
         })
     }
 
     // 3) Withdrawal:
     //   a) two txs: Withdrawal - Pending, Fee - Done
     //   b) three txs: Withdrwal - Done, Fee - Done, BlockchainFee - Done
+    //   c) payout: the above for each destination, `to` is the first of them
 
     fn convert_external_transaction(&self, transactions: Vec<Transaction>) -> Result<TransactionOut, Error> {
-        let fee_tx = transactions
+        if !transactions.iter().any(|tx| tx.kind == TransactionKind::Fee) {
+            return Err(ectx!(err ErrorContext::InvalidTransactionStructure, ErrorKind::Internal => transactions));
+        }
+        // payouts have a fee transaction for each of the destinations
+        let fee = transactions
             .iter()
-            .find(|tx| tx.kind == TransactionKind::Fee)
-            .cloned()
-            .ok_or(ectx!(try err ErrorContext::InvalidTransactionStructure, ErrorKind::Internal => transactions))?;
+            .filter(|tx| tx.kind == TransactionKind::Fee)
+            .fold(Some(Amount::new(0)), |acc, elem| acc.and_then(|a| a.checked_add(elem.value)))
+            .ok_or(ectx!(try err ErrorContext::BalanceOverflow, ErrorKind::Internal => transactions))?;
         // We take arbitrary first withdrawal tx to extract some data
         let withdrawal_tx = transactions
             .iter()
@@ -376,17 +557,24 @@ impl ConverterServiceImpl {
             from_currency: withdrawal_tx.currency,
             to_value: value,
             to_currency: withdrawal_tx.currency,
-            fee: fee_tx.value,
+            fee,
             status,
             blockchain_tx_ids,
             created_at,
             updated_at,
+            kind: withdrawal_tx.kind,
+            group_kind: withdrawal_tx.group_kind,
+            related_tx: withdrawal_tx.related_tx,
+            meta: withdrawal_tx.meta,
+            usd_value: None,
+            estimated_completion_at: None,
         })
     }
 
     // 5) ExternalMulti:
     //   a) MultiFrom - Done, MultiTo - Done, Withdrawal - Pending, Fee - Done
     //   b) MultiFrom - Done, MultiTo - Done, Withdrawal - Done, Fee - Done, BlockchainFee - Done
+    //   c) MultiFrom - Done, MultiTo - Done, if sending failed after the exchange
     fn convert_external_multi_transaction(&self, transactions: Vec<Transaction>) -> Result<TransactionOut, Error> {
         let currency_txs: Vec<_> = transactions
             .iter()
@@ -399,6 +587,10 @@ impl ConverterServiceImpl {
             .cloned()
             .collect();
         let currency_tx_out = self.convert_internal_multi_transaction(currency_txs)?;
+        // sending failed after the exchange, so the group is just an exchange to the user's account
+        if withdrawal_txs.is_empty() {
+            return Ok(currency_tx_out);
+        }
         let withdrawal_tx_out = self.convert_external_transaction(withdrawal_txs)?;
         Ok(TransactionOut {
             id: currency_tx_out.id,
@@ -414,6 +606,12 @@ impl ConverterServiceImpl {
             blockchain_tx_ids: withdrawal_tx_out.blockchain_tx_ids,
             created_at: withdrawal_tx_out.created_at,
             updated_at: withdrawal_tx_out.updated_at,
+            kind: currency_tx_out.kind,
+            group_kind: currency_tx_out.group_kind,
+            related_tx: currency_tx_out.related_tx,
+            meta: currency_tx_out.meta,
+            usd_value: None,
+            estimated_completion_at: None,
         })
     }
 }
@@ -441,6 +639,20 @@ impl ConverterService for ConverterServiceImpl {
     // 6) Approval - we don't serve this as TransactionOut since it's internal to our system
     // 7) Reversal -
     //   a) Withdrawal - Done, Fee - Done
+    //   b) MultiFrom - Done, MultiTo - Done
+    //   c) Sweep - Done
+    //
+    // 8) Bounce:
+    //   a) Bounce - Pending or Done
+    //   b) Bounce - Done, BlockchainFee - Done (stq, eth fee is paid by system account)
+    //
+    // 9) Sweep:
+    //   a) Internal - Done (balance is moved to the account of former address, nothing to send)
+    //   b) Internal - Done, Sweep - Pending
+    //   c) Internal - Done, Sweep - Done, BlockchainFee - Done
+    //
+    // 10) FeeRefund:
+    //   Fee - Done, returned by fees account
 
     // Input txs should be with len() > 0 and have the same `gid`- this guarantees exactly one TransactionOut
     fn convert_transaction(&self, transactions: Vec<Transaction>) -> Result<TransactionOut, Error> {
@@ -451,17 +663,23 @@ impl ConverterService for ConverterServiceImpl {
             }
         }
         let group_kind = transactions[0].group_kind;
-        match group_kind {
+        let mut tx_out = match group_kind {
             TransactionGroupKind::Deposit => self.convert_deposit_transaction(transactions),
             TransactionGroupKind::Internal => self.convert_internal_transaction(transactions),
             TransactionGroupKind::InternalMulti => self.convert_internal_multi_transaction(transactions),
             TransactionGroupKind::Withdrawal => self.convert_external_transaction(transactions),
             TransactionGroupKind::WithdrawalMulti => self.convert_external_multi_transaction(transactions),
             TransactionGroupKind::Reversal => self.convert_reversal_transaction(transactions),
+            TransactionGroupKind::Bounce => self.convert_bounce_transaction(transactions),
+            TransactionGroupKind::Sweep => self.convert_sweep_transaction(transactions),
+            TransactionGroupKind::FeeRefund => self.convert_fee_refund_transaction(transactions),
             TransactionGroupKind::Approval => {
                 return Err(ectx!(err ErrorContext::InvalidTransactionStructure, ErrorKind::Internal => transactions));
             }
-        }
+        }?;
+        tx_out.usd_value = usd_value(&tx_out);
+        tx_out.estimated_completion_at = estimated_completion_at(&tx_out);
+        Ok(tx_out)
         // // internal + withdrawal tx
         // if transactions.len() == 1 {
         //     let tx = transactions[0].clone();
@@ -523,3 +741,95 @@ impl ConverterService for ConverterServiceImpl {
         // panic!("Unsupported transactions sequence: {:#?}", transactions)
     }
 }
+
+// Rate stored at creation is used, so that fiat value doesn't change with the market
+fn usd_value(tx_out: &TransactionOut) -> Option<f64> {
+    let usd_rate = tx_out.meta.get(USD_RATE_META_KEY)?.as_f64()?;
+    let usd_value = tx_out.from_value.to_super_unit(tx_out.from_currency) * usd_rate;
+    // cents are precise enough for an approximate value
+    Some((usd_value * 100.0).round() / 100.0)
+}
+
+// Estimate is stored by the fetcher, it's meaningless once the transaction is done
+fn estimated_completion_at(tx_out: &TransactionOut) -> Option<NaiveDateTime> {
+    if tx_out.status != TransactionStatus::Pending {
+        return None;
+    }
+    let estimated_completion_at = tx_out.meta.get(ESTIMATED_COMPLETION_AT_META_KEY)?;
+    serde_json::from_value(estimated_completion_at.clone()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_usd_value() {
+        let mut tx_out = TransactionOut {
+            id: TransactionId::generate(),
+            user_id: UserId::generate(),
+            from: vec![],
+            to: TransactionAddressInfo {
+                account_id: None,
+                blockchain_address: BlockchainAddress::default(),
+            },
+            // 0.5 btc
+            from_value: Amount::new(50_000_000),
+            from_currency: Currency::Btc,
+            to_value: Amount::new(50_000_000),
+            to_currency: Currency::Btc,
+            fee: Amount::new(0),
+            status: TransactionStatus::Done,
+            blockchain_tx_ids: vec![],
+            created_at: ::chrono::Utc::now().naive_utc(),
+            updated_at: ::chrono::Utc::now().naive_utc(),
+            kind: TransactionKind::Internal,
+            group_kind: TransactionGroupKind::Internal,
+            related_tx: None,
+            meta: json!({ "usdRate": 6500.0 }),
+            usd_value: None,
+            estimated_completion_at: None,
+        };
+        assert_eq!(usd_value(&tx_out), Some(3250.0));
+        // transactions created before rates were stored have no fiat value
+        tx_out.meta = json!({});
+        assert_eq!(usd_value(&tx_out), None);
+        tx_out.meta = json!("reversal of tx");
+        assert_eq!(usd_value(&tx_out), None);
+    }
+
+    #[test]
+    fn test_estimated_completion_at() {
+        let mut tx_out = TransactionOut {
+            id: TransactionId::generate(),
+            user_id: UserId::generate(),
+            from: vec![],
+            to: TransactionAddressInfo {
+                account_id: None,
+                blockchain_address: BlockchainAddress::default(),
+            },
+            from_value: Amount::new(50_000_000),
+            from_currency: Currency::Btc,
+            to_value: Amount::new(50_000_000),
+            to_currency: Currency::Btc,
+            fee: Amount::new(0),
+            status: TransactionStatus::Pending,
+            blockchain_tx_ids: vec![],
+            created_at: ::chrono::Utc::now().naive_utc(),
+            updated_at: ::chrono::Utc::now().naive_utc(),
+            kind: TransactionKind::Withdrawal,
+            group_kind: TransactionGroupKind::Withdrawal,
+            related_tx: None,
+            meta: json!({}),
+            usd_value: None,
+            estimated_completion_at: None,
+        };
+        // not seen in blockchain yet
+        assert_eq!(estimated_completion_at(&tx_out), None);
+        let estimate = NaiveDateTime::from_timestamp(1_553_770_000, 0);
+        tx_out.meta = json!({ ESTIMATED_COMPLETION_AT_META_KEY: estimate });
+        assert_eq!(estimated_completion_at(&tx_out), Some(estimate));
+        tx_out.status = TransactionStatus::Done;
+        assert_eq!(estimated_completion_at(&tx_out), None);
+    }
+}
@@ -5,29 +5,51 @@ pub mod converter;
 use std::collections::HashMap;
 use std::sync::Arc;
 
+use failure::{Context, Fail};
 use future::Either;
 use futures::future;
 use futures::prelude::*;
 use validator::{ValidationError, ValidationErrors};
 
-use self::blockchain::{BlockchainService, BlockchainServiceImpl, FeeEstimate};
+use self::blockchain::FeeEstimate;
+pub use self::blockchain::{BlockchainService, BlockchainServiceImpl};
 use self::classifier::{ClassifierService, ClassifierServiceImpl, TransactionType};
-use self::converter::{ConverterService, ConverterServiceImpl};
+pub use self::converter::{ConverterService, ConverterServiceImpl};
 use super::auth::AuthService;
 use super::error::*;
+use super::rates::{RatesService, RatesServiceImpl};
 use super::system::{SystemService, SystemServiceImpl};
 use client::BlockchainClient;
 use client::ExchangeClient;
 use client::KeysClient;
-use config::Config;
+use config::{Config, SharedConfig};
 use models::*;
 use prelude::*;
 use rabbit::TransactionPublisher;
 use repos::{
-    AccountsRepo, BlockchainTransactionsRepo, DbExecutor, Isolation, KeyValuesRepo, PendingBlockchainTransactionsRepo, TransactionsRepo,
+    AccountsRepo, BlockchainTransactionsRepo, DbExecutor, Isolation, KeyValuesRepo, PendingBlockchainTransactionsRepo,
+    QueuedWithdrawalsRepo, RateLocksRepo, StrangeBlockchainTransactionsRepo, TransactionsRepo, UsersRepo, WithdrawalAddressesRepo,
 };
 use utils::{log_and_capture_error, log_error};
 
+/// Number of transaction groups fetched from db at once while streaming account history
+const STREAM_BATCH_SIZE: i64 = 500;
+/// Max number of user accounts looked through for the one to exchange withdrawal funds to
+const MAX_USER_ACCOUNTS: i64 = 1000;
+
+pub type TransactionsStream = Box<Stream<Item = TransactionOut, Error = Error> + Send>;
+
+// Funds of strange transaction, ready to be sent back from our address
+#[derive(Debug, Clone)]
+struct Bounce {
+    strange_tx: StrangeBlockchainTransactionDB,
+    account: Account,
+    hash: BlockchainTransactionId,
+    to: BlockchainAddress,
+    value: Amount,
+    fee_price: f64,
+}
+
 #[derive(Clone)]
 pub struct TransactionsServiceImpl<E: DbExecutor> {
     config: Arc<Config>,
@@ -36,8 +58,13 @@ pub struct TransactionsServiceImpl<E: DbExecutor> {
     classifier_service: Arc<ClassifierService>,
     converter_service: Arc<ConverterService>,
     system_service: Arc<SystemService>,
+    rates_service: Arc<RatesService>,
     transactions_repo: Arc<dyn TransactionsRepo>,
     blockchain_transactions_repo: Arc<dyn BlockchainTransactionsRepo>,
+    strange_blockchain_transactions_repo: Arc<dyn StrangeBlockchainTransactionsRepo>,
+    queued_withdrawals_repo: Arc<dyn QueuedWithdrawalsRepo>,
+    rate_locks_repo: Arc<dyn RateLocksRepo>,
+    pending_transactions_repo: Arc<dyn PendingBlockchainTransactionsRepo>,
     accounts_repo: Arc<dyn AccountsRepo>,
     db_executor: E,
     exchange_client: Arc<dyn ExchangeClient>,
@@ -50,22 +77,40 @@ pub trait TransactionsService: Send + Sync + 'static {
         token: AuthenticationToken,
         input: CreateTransactionInput,
     ) -> Box<Future<Item = TransactionOut, Error = Error> + Send>;
+    /// Withdraws from one account to several addresses, each of them gets its own blockchain
+    /// transactions within one transaction group. Fails only if none of them is sent, otherwise
+    /// the result of each destination is returned along with the group
+    fn create_payout(&self, token: AuthenticationToken, input: CreatePayoutInput) -> Box<Future<Item = PayoutOut, Error = Error> + Send>;
     fn get_transaction(
         &self,
         token: AuthenticationToken,
         transaction_id: TransactionId,
     ) -> Box<Future<Item = Option<TransactionOut>, Error = Error> + Send>;
+    /// Fee charged by the group of transaction and network fees paid for it. Only system user is allowed to see it
+    fn get_group_fee(
+        &self,
+        token: AuthenticationToken,
+        transaction_id: TransactionId,
+    ) -> Box<Future<Item = Option<GroupFee>, Error = Error> + Send>;
     fn get_account_balance(
         &self,
         token: AuthenticationToken,
         account_id: AccountId,
     ) -> Box<Future<Item = AccountWithBalance, Error = Error> + Send>;
+    /// Counts and sums of deposits and withdrawals of account over the window
+    fn get_account_stats(
+        &self,
+        token: AuthenticationToken,
+        account_id: AccountId,
+        window: StatsWindow,
+    ) -> Box<Future<Item = AccountStats, Error = Error> + Send>;
     fn get_transactions_for_user(
         &self,
         token: AuthenticationToken,
         user_id: UserId,
         offset: i64,
         limit: i64,
+        sort: TransactionsSort,
     ) -> Box<Future<Item = Vec<TransactionOut>, Error = Error> + Send>;
     fn get_account_transactions(
         &self,
@@ -73,7 +118,59 @@ pub trait TransactionsService: Send + Sync + 'static {
         account_id: AccountId,
         offset: i64,
         limit: i64,
+        sort: TransactionsSort,
     ) -> Box<Future<Item = Vec<TransactionOut>, Error = Error> + Send>;
+    /// Whole account history, newest first. Transactions are fetched from db in batches
+    /// while the stream is consumed, so that it is never loaded in memory at once
+    fn stream_account_transactions(
+        &self,
+        token: AuthenticationToken,
+        account_id: AccountId,
+    ) -> Box<Future<Item = TransactionsStream, Error = Error> + Send>;
+    /// Sends funds of strange transaction, e.g. deposit of wrong asset, back to the sender or to `to` address,
+    /// if the sender is ambiguous. Only system user is allowed to do it
+    fn bounce_strange_transaction(
+        &self,
+        token: AuthenticationToken,
+        hash: BlockchainTransactionId,
+        to: Option<BlockchainAddress>,
+    ) -> Box<Future<Item = TransactionOut, Error = Error> + Send>;
+    /// Exchanges credited deposit to the account its account is set to auto convert to, the exchange
+    /// is related to the deposit. It's triggered by blockchain fetcher, not by user, so there's no token.
+    /// Returns `None` if the account doesn't auto convert deposits
+    fn auto_convert_deposit(&self, deposit: Transaction) -> Box<Future<Item = Option<TransactionOut>, Error = Error> + Send>;
+    /// Puts withdrawal into queue instead of sending it right away. It is validated now,
+    /// but funds are checked again when the queue is drained
+    fn queue_withdrawal(
+        &self,
+        token: AuthenticationToken,
+        input: CreateTransactionInput,
+    ) -> Box<Future<Item = QueuedWithdrawal, Error = Error> + Send>;
+    fn get_queued_withdrawal(
+        &self,
+        token: AuthenticationToken,
+        id: TransactionId,
+    ) -> Box<Future<Item = Option<QueuedWithdrawal>, Error = Error> + Send>;
+    fn get_queued_withdrawals_for_user(
+        &self,
+        token: AuthenticationToken,
+        user_id: UserId,
+        offset: i64,
+        limit: i64,
+    ) -> Box<Future<Item = Vec<QueuedWithdrawal>, Error = Error> + Send>;
+    /// Only withdrawals that are still waiting for the drain can be cancelled
+    fn cancel_queued_withdrawal(
+        &self,
+        token: AuthenticationToken,
+        id: TransactionId,
+    ) -> Box<Future<Item = QueuedWithdrawal, Error = Error> + Send>;
+    /// Sends the oldest queued withdrawals, the ones from the same account to the same address
+    /// go in one blockchain transaction. Called by scheduler, so there's no token
+    fn drain_withdrawal_queue(&self) -> Box<Future<Item = Vec<QueuedWithdrawal>, Error = Error> + Send>;
+    /// Posts again signed transactions that blockchain gateway failed to accept, their placeholder ids
+    /// are replaced with the real ones. Called by scheduler, so there's no token. Returns the retried transactions,
+    /// the ones still not accepted keep placeholder ids
+    fn retry_failed_broadcasts(&self) -> Box<Future<Item = Vec<PendingBlockchainTransactionDB>, Error = Error> + Send>;
 }
 
 impl<E: DbExecutor> TransactionsServiceImpl<E> {
@@ -83,6 +180,11 @@ impl<E: DbExecutor> TransactionsServiceImpl<E> {
         transactions_repo: Arc<TransactionsRepo>,
         pending_transactions_repo: Arc<dyn PendingBlockchainTransactionsRepo>,
         blockchain_transactions_repo: Arc<dyn BlockchainTransactionsRepo>,
+        strange_blockchain_transactions_repo: Arc<dyn StrangeBlockchainTransactionsRepo>,
+        queued_withdrawals_repo: Arc<dyn QueuedWithdrawalsRepo>,
+        rate_locks_repo: Arc<dyn RateLocksRepo>,
+        users_repo: Arc<dyn UsersRepo>,
+        withdrawal_addresses_repo: Arc<dyn WithdrawalAddressesRepo>,
         accounts_repo: Arc<dyn AccountsRepo>,
         key_values_repo: Arc<dyn KeyValuesRepo>,
         db_executor: E,
@@ -96,8 +198,11 @@ impl<E: DbExecutor> TransactionsServiceImpl<E> {
             &config,
             accounts_repo.clone(),
             transactions_repo.clone(),
+            users_repo,
+            withdrawal_addresses_repo,
+            key_values_repo.clone(),
         ));
-        let system_service = Arc::new(SystemServiceImpl::new(accounts_repo.clone(), config.clone()));
+        let system_service = Arc::new(SystemServiceImpl::new(accounts_repo.clone()));
         let blockchain_service = Arc::new(BlockchainServiceImpl::new(
             config.clone(),
             keys_client,
@@ -114,14 +219,21 @@ impl<E: DbExecutor> TransactionsServiceImpl<E> {
             blockchain_transactions_repo.clone(),
             system_service.clone(),
         ));
+        // confirmation thresholds of a static config, `with_rates_service` replaces them with reloaded ones
+        let rates_service = Arc::new(RatesServiceImpl::new(key_values_repo.clone(), SharedConfig::new((*config).clone())));
         Self {
             config: config.clone(),
             auth_service,
             blockchain_service,
             classifier_service,
             system_service,
+            rates_service,
             transactions_repo,
             blockchain_transactions_repo,
+            strange_blockchain_transactions_repo,
+            queued_withdrawals_repo,
+            rate_locks_repo,
+            pending_transactions_repo,
             accounts_repo,
             db_executor,
             converter_service,
@@ -130,23 +242,36 @@ impl<E: DbExecutor> TransactionsServiceImpl<E> {
         }
     }
 
+    pub fn with_rates_service(self, rates_service: Arc<RatesService>) -> Self {
+        Self { rates_service, ..self }
+    }
+
     fn create_base_tx(&self, tx: NewTransaction, dr_account: Account, cr_account: Account) -> Result<Transaction, Error> {
         let transactions_repo = self.transactions_repo.clone();
         if dr_account.currency != cr_account.currency {
-            return Err(ectx!(err ErrorContext::InvalidCurrency, ErrorKind::Internal => tx.clone(), dr_account.clone(), cr_account.clone()));
+            return Err(
+                ectx!(err ErrorContext::InvalidCurrency, ErrorKind::Internal => tx.clone(), dr_account.clone(), cr_account.clone()),
+            );
         }
         if (tx.dr_account_id != dr_account.id) || (tx.cr_account_id != cr_account.id) {
             return Err(
                 ectx!(err ErrorContext::InvalidTransaction, ErrorKind::Internal => tx.clone(), dr_account.clone(), cr_account.clone()),
             );
         }
+        let tx = self.rates_service.with_usd_rate(tx)?;
         let tx_clone = tx.clone();
         let balance = transactions_repo
             .get_accounts_balance(tx.user_id, &[dr_account])
             .map(|accounts| accounts[0].balance)
             .map_err(ectx!(try convert => tx_clone))?;
         if balance >= tx.value {
-            transactions_repo.create(tx.clone()).map_err(ectx!(convert => tx.clone()))
+            let tx_clone = tx.clone();
+            let res = transactions_repo.create(tx.clone()).map_err(ectx!(try convert => tx_clone))?;
+            // balance might have been changed by concurrent transaction after the check
+            transactions_repo
+                .assert_non_negative_balances(&[tx.dr_account_id])
+                .map_err(ectx!(try convert => tx))?;
+            Ok(res)
         } else {
             let mut errors = ValidationErrors::new();
             let mut error = ValidationError::new("not_enough_balance");
@@ -156,32 +281,202 @@ impl<E: DbExecutor> TransactionsServiceImpl<E> {
         }
     }
 
+    // For sweeping transactions sets value to the whole balance of the sending account,
+    // less the fee if it's paid from this account. It's computed again in the transaction writing ledger entries,
+    // so only internal transfers can sweep: exchanges and withdrawals fix the value with gateway or blockchain before that
+    fn with_swept_value(&self, input: CreateTransactionInput) -> Result<CreateTransactionInput, Error> {
+        if !input.sweep {
+            return Ok(input);
+        }
+        let from = input.from;
+        let from_account = self
+            .accounts_repo
+            .get(from)
+            .map_err(ectx!(try convert => from))?
+            .ok_or(ectx!(try err ErrorContext::NoAccount, ErrorKind::NotFound => input))?;
+        if from_account.user_id != input.user_id {
+            return Err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => input.user_id));
+        }
+        let balance = self
+            .transactions_repo
+            .get_account_balance(from, AccountKind::Cr)
+            .map_err(ectx!(try convert => from))?;
+        let fee = match input.fee_payer {
+            FeePayer::Sender => input.fee,
+            FeePayer::Account | FeePayer::Recipient => Amount::default(),
+        };
+        match balance.checked_sub(fee) {
+            Some(value) if value > Amount::default() => Ok(CreateTransactionInput {
+                value,
+                value_currency: from_account.currency,
+                ..input
+            }),
+            _ => {
+                let mut errors = ValidationErrors::new();
+                let mut error = ValidationError::new("not_enough_balance");
+                error.message = Some("account balance is not enough".into());
+                errors.add("value", error);
+                Err(
+                    ectx!(err ErrorContext::NotEnoughFunds, ErrorKind::InvalidInput(serde_json::to_string(&errors).unwrap_or_default()) => balance, fee),
+                )
+            }
+        }
+    }
+
+    // Replaces rate lock with exchange id and rate it holds, the lock can't be used again after that.
+    // Currencies of the lock must be the ones of the exchange, and the value can't exceed the locked amount.
+    // Exchange is protected from slippage with the requested bound, or the configured one for locked rates
+    fn with_locked_rate(&self, input: CreateTransactionInput) -> Result<CreateTransactionInput, Error> {
+        let rate_lock_id = match input.rate_lock_id {
+            Some(rate_lock_id) => rate_lock_id,
+            None => return Ok(input),
+        };
+        if input.exchange_id.is_some() || input.exchange_rate.is_some() {
+            return Err(
+                ectx!(err ErrorContext::InvalidRateLock, invalid_input("rateLockId", "ambiguous", "rate lock is given together with exchange rate") => input),
+            );
+        }
+        let from = input.from;
+        let from_account = self
+            .accounts_repo
+            .get(from)
+            .map_err(ectx!(try convert => from))?
+            .ok_or(ectx!(try err ErrorContext::NoAccount, ErrorKind::NotFound => input))?;
+        let rate_lock = self
+            .rate_locks_repo
+            .get(rate_lock_id)
+            .map_err(ectx!(try convert => rate_lock_id))?
+            .filter(|rate_lock| rate_lock.user_id == input.user_id)
+            .ok_or(ectx!(try err ErrorContext::InvalidRateLock, invalid_input("rateLockId", "invalid", "rate lock is not found, expired or already used") => input))?;
+        if rate_lock.from_currency != from_account.currency || rate_lock.to_currency != input.to_currency {
+            return Err(
+                ectx!(err ErrorContext::InvalidRateLock, invalid_input("rateLockId", "currency", "rate lock is for another currency pair") => input, rate_lock),
+            );
+        }
+        if rate_lock.amount_currency != input.value_currency || rate_lock.amount < input.value {
+            return Err(
+                ectx!(err ErrorContext::InvalidRateLock, invalid_input("rateLockId", "amount", "rate lock is for a smaller amount") => input, rate_lock),
+            );
+        }
+        let rate_lock = self
+            .rate_locks_repo
+            .use_lock(rate_lock_id, ::chrono::Utc::now().naive_utc())
+            .map_err(ectx!(try convert => rate_lock_id))?
+            .ok_or(ectx!(try err ErrorContext::InvalidRateLock, invalid_input("rateLockId", "invalid", "rate lock is not found, expired or already used") => input))?;
+        Ok(CreateTransactionInput {
+            exchange_id: Some(rate_lock.exchange_id),
+            exchange_rate: Some(rate_lock.rate),
+            rate_lock_id: None,
+            max_slippage: input.max_slippage.or(Some(self.config.exchange_options.locked_rate_max_slippage)),
+            ..input
+        })
+    }
+
+    // Withdrawal group can be a part of exchange, that's charged once, so its fee is only stored by the first one
+    fn record_group_fee(&self, group_fee: NewGroupFee) -> Result<(), Error> {
+        let gid = group_fee.gid;
+        if self
+            .transactions_repo
+            .get_group_fee(gid)
+            .map_err(ectx!(try convert => gid))?
+            .is_some()
+        {
+            return Ok(());
+        }
+        self.transactions_repo
+            .create_group_fee(group_fee.clone())
+            .map(|_| ())
+            .map_err(ectx!(convert => group_fee))
+    }
+
+    // Validates all transactions the same way as `create_base_tx`, taking into account
+    // that several of them may spend from the same account, and inserts them with a single query
+    fn create_base_txs(&self, txs: Vec<(NewTransaction, Account, Account)>) -> Result<Vec<Transaction>, Error> {
+        let transactions_repo = self.transactions_repo.clone();
+        let mut dr_accounts: Vec<Account> = vec![];
+        for (tx, dr_account, cr_account) in txs.iter() {
+            if dr_account.currency != cr_account.currency {
+                return Err(
+                    ectx!(err ErrorContext::InvalidCurrency, ErrorKind::Internal => tx.clone(), dr_account.clone(), cr_account.clone()),
+                );
+            }
+            if (tx.dr_account_id != dr_account.id) || (tx.cr_account_id != cr_account.id) {
+                return Err(
+                    ectx!(err ErrorContext::InvalidTransaction, ErrorKind::Internal => tx.clone(), dr_account.clone(), cr_account.clone()),
+                );
+            }
+            if !dr_accounts.iter().any(|acc| acc.id == dr_account.id) {
+                dr_accounts.push(dr_account.clone());
+            }
+        }
+        let user_id = match txs.first() {
+            Some((tx, _, _)) => tx.user_id,
+            None => return Ok(vec![]),
+        };
+        let dr_account_ids: Vec<AccountId> = dr_accounts.iter().map(|account| account.id).collect();
+        let mut balances: HashMap<AccountId, Amount> = transactions_repo
+            .get_accounts_balance(user_id, &dr_accounts)
+            .map_err(ectx!(try convert => user_id, dr_accounts))?
+            .into_iter()
+            .map(|AccountWithBalance { account, balance }| (account.id, balance))
+            .collect();
+        let mut payloads = vec![];
+        for (tx, _, _) in txs {
+            let tx = self.rates_service.with_usd_rate(tx)?;
+            let balance = balances.get(&tx.dr_account_id).cloned().unwrap_or_default();
+            match balance.checked_sub(tx.value) {
+                Some(rest) => {
+                    balances.insert(tx.dr_account_id, rest);
+                    payloads.push(tx);
+                }
+                None => {
+                    let mut errors = ValidationErrors::new();
+                    let mut error = ValidationError::new("not_enough_balance");
+                    error.message = Some("account balance is not enough".into());
+                    errors.add("value", error);
+                    return Err(
+                        ectx!(err ErrorContext::NotEnoughFunds, ErrorKind::InvalidInput(serde_json::to_string(&errors).unwrap_or_default()) => tx),
+                    );
+                }
+            }
+        }
+        let payloads_clone = payloads.clone();
+        let res = transactions_repo
+            .create_batch(payloads)
+            .map_err(ectx!(try convert => payloads_clone))?;
+        transactions_repo
+            .assert_non_negative_balances(&dr_account_ids)
+            .map_err(ectx!(try convert => dr_account_ids))?;
+        Ok(res)
+    }
+
     fn create_internal_mono_currency_tx(
         &self,
         create_tx_input: CreateTransactionInput,
         dr_account: Account,
         cr_account: Account,
     ) -> impl Future<Item = Transaction, Error = Error> + Send {
-        let tx = NewTransaction {
-            id: create_tx_input.id,
-            gid: create_tx_input.id,
-            user_id: create_tx_input.user_id,
-            dr_account_id: dr_account.id,
-            cr_account_id: cr_account.id,
-            currency: dr_account.currency,
-            value: create_tx_input.value,
-            status: TransactionStatus::Done,
-            blockchain_tx_id: None,
-            kind: TransactionKind::Internal,
-            group_kind: TransactionGroupKind::Internal,
-            related_tx: None,
-            meta: None,
-        };
         let self_clone = self.clone();
-        self.db_executor
-            .execute_transaction_with_isolation(Isolation::Serializable, move || {
-                self_clone.create_base_tx(tx, dr_account, cr_account)
-            })
+        self.db_executor.execute_transaction_with_retry(Isolation::Serializable, move || {
+            // swept value is taken from the balance again, since it could have changed after classification
+            let create_tx_input = self_clone.with_swept_value(create_tx_input.clone())?;
+            let tx = NewTransaction {
+                id: create_tx_input.id,
+                gid: create_tx_input.id,
+                user_id: create_tx_input.user_id,
+                dr_account_id: dr_account.id,
+                cr_account_id: cr_account.id,
+                currency: dr_account.currency,
+                value: create_tx_input.value,
+                status: TransactionStatus::Done,
+                blockchain_tx_id: None,
+                kind: TransactionKind::Internal,
+                group_kind: TransactionGroupKind::Internal,
+                related_tx: None,
+                meta: None,
+            };
+            self_clone.create_base_tx(tx, dr_account.clone(), cr_account.clone())
+        })
     }
 
     fn create_external_mono_currency_tx(
@@ -198,7 +493,7 @@ impl<E: DbExecutor> TransactionsServiceImpl<E> {
         fee_currency: Option<Currency>,
         // by default the fee is written off from_account. However you can override this
         // using this param
-        fee_payer_account_id: Option<AccountId>,
+        fee_payer_account: Option<Account>,
     ) -> impl Future<Item = Vec<Transaction>, Error = Error> + Send {
         if from_account.currency != to_currency {
             return Either::A(future::err(
@@ -216,8 +511,9 @@ impl<E: DbExecutor> TransactionsServiceImpl<E> {
         let blockchain_service = self.blockchain_service.clone();
         let self_clone = self.clone();
         let user_id_clone = input.user_id.clone();
-        let from_account_clone = from_account.clone();
+        let fee_payer_account = fee_payer_account.unwrap_or(from_account.clone());
         let input_fee = input.fee.clone();
+        let fee_upside = self.config.fees_options.fee_upside;
         Either::B(self
             .blockchain_service
             .estimate_withdrawal_fee(input.fee, fee_currency, to_currency)
@@ -225,7 +521,7 @@ impl<E: DbExecutor> TransactionsServiceImpl<E> {
                 let fee = input.fee.clone();
                 ectx!(ErrorKind::Internal => fee, fee_currency, to_currency)
             })
-            .and_then(move |FeeEstimate {gross_fee: total_fee_est,fee_price: fee_price_est,..}|{
+            .and_then(move |FeeEstimate {gross_fee: total_fee_est,fee_price: fee_price_est,currency: fee_estimate_currency}|{
                 db_executor.execute_transaction_with_isolation(Isolation::Serializable, move || {
                     let withdrawal_accs_with_balance =
                         transactions_repo
@@ -262,13 +558,26 @@ impl<E: DbExecutor> TransactionsServiceImpl<E> {
                         return Err(ectx!(err ErrorContext::InvalidValue, ErrorKind::Internal => input.clone(), total_value));
                     }
 
+                    // fee is estimated as network one times upside, so that's how much is charged for network fee
+                    let charged_blockchain_fee = total_fee_est
+                        .checked_mul(Amount::new(fee_upside as u128))
+                        .ok_or(ectx!(try err ErrorContext::BalanceOverflow, ErrorKind::Internal => total_fee_est, fee_upside))?;
+                    let group_fee = NewGroupFee {
+                        gid,
+                        fee_currency,
+                        fee: input.fee,
+                        blockchain_fee_currency: fee_estimate_currency,
+                        charged_blockchain_fee,
+                        blockchain_fee: Amount::new(0),
+                    };
+
                     system_service
                         .get_system_fees_account(to_currency)
                         .map_err(ectx!(ErrorKind::Internal => to_currency))
-                        .map(|fees_account| (fees_account, input.id, withdrawal_accs_with_balance, fee_price_est))
+                        .map(|fees_account| (fees_account, input.id, withdrawal_accs_with_balance, fee_price_est, group_fee))
                 })
             })
-            .and_then(move |(fees_account, current_tx_id, withdrawal_accs_with_balance, fee_price_est)|{
+            .and_then(move |(fees_account, current_tx_id, withdrawal_accs_with_balance, fee_price_est, group_fee)|{
                 let new_db_transactions: Vec<(NewTransaction, Account, Account)> = Vec::new();
                 futures::stream::iter_ok(withdrawal_accs_with_balance).fold((current_tx_id, new_db_transactions), move |(current_tx_id, mut acc_), AccountWithBalance {account: acc,balance: value}| {
                     let to = to_blockchain_address.clone();
@@ -321,12 +630,11 @@ impl<E: DbExecutor> TransactionsServiceImpl<E> {
                     match res {
                         Ok((_, new_db_transactions)) =>
                         Either::A(db_executor_.execute_transaction_with_isolation(Isolation::Serializable, move || {
-                            let mut result = vec![];
                             let fee_tx = NewTransaction {
                                 id: current_tx_id,
                                 gid,
                                 user_id: user_id_clone.clone(),
-                                dr_account_id: fee_payer_account_id.unwrap_or(from_account_clone.id),
+                                dr_account_id: fee_payer_account.id,
                                 cr_account_id: fees_account.id,
                                 currency: fee_currency,
                                 value: input_fee,
@@ -337,26 +645,25 @@ impl<E: DbExecutor> TransactionsServiceImpl<E> {
                                 related_tx: None,
                                 meta: None,
                             };
-                            // first - we are adding fee transaction
-                            result.push(self_clone.create_base_tx(fee_tx, from_account_clone.clone(), fees_account.clone())?);
-                            // adding all blockchain transactions
-                            for (new_tx, dr, cr) in new_db_transactions {
-                                result.push(self_clone.create_base_tx(new_tx, dr, cr)?);
-                            }
-                            Ok(result)
+                            // first - we are adding fee transaction, then all blockchain transactions
+                            let mut txs = vec![(fee_tx, fee_payer_account.clone(), fees_account.clone())];
+                            let spent_account_ids: Vec<AccountId> = new_db_transactions.iter().map(|(_, _, acc)| acc.id).collect();
+                            txs.extend(new_db_transactions);
+                            let txs = self_clone.create_base_txs(txs)?;
+                            self_clone.record_group_fee(group_fee)?;
+                            self_clone.accounts_repo.mark_spent(&spent_account_ids).map_err(ectx!(try convert => spent_account_ids))?;
+                            Ok(txs)
                         })),
                         Err((e, new_db_transactions)) => Either::B({
                             // if we have more then zero db_transactions - so we have at least one blockchain transaction sent.
                             if new_db_transactions.len() > 0 {
                                 log_and_capture_error(e);
                                 Either::A(db_executor_.execute_transaction_with_isolation(Isolation::Serializable, move || {
-
-                                    let mut result = vec![];
                                     let fee_tx = NewTransaction {
                                         id: current_tx_id,
                                         gid,
                                         user_id: user_id_clone.clone(),
-                                        dr_account_id: fee_payer_account_id.unwrap_or(from_account_clone.id),
+                                        dr_account_id: fee_payer_account.id,
                                         cr_account_id: fees_account.id,
                                         currency: fee_currency,
                                         value: input_fee,
@@ -367,13 +674,14 @@ impl<E: DbExecutor> TransactionsServiceImpl<E> {
                                         related_tx: None,
                                         meta: None,
                                     };
-                                    // first - we are adding fee transaction
-                                    result.push(self_clone.create_base_tx(fee_tx, from_account_clone.clone(), fees_account.clone())?);
-                                    // adding all blockchain transactions successfully sent
-                                    for (new_tx, dr, cr) in new_db_transactions {
-                                        result.push(self_clone.create_base_tx(new_tx, dr, cr)?);
-                                    }
-                                    Ok(result)
+                                    // first - we are adding fee transaction, then all blockchain transactions successfully sent
+                                    let mut txs = vec![(fee_tx, fee_payer_account.clone(), fees_account.clone())];
+                                    let spent_account_ids: Vec<AccountId> = new_db_transactions.iter().map(|(_, _, acc)| acc.id).collect();
+                                    txs.extend(new_db_transactions);
+                                    let txs = self_clone.create_base_txs(txs)?;
+                                    self_clone.record_group_fee(group_fee)?;
+                                    self_clone.accounts_repo.mark_spent(&spent_account_ids).map_err(ectx!(try convert => spent_account_ids))?;
+                                    Ok(txs)
                                 }))
                             } else {
                                 Either::B(future::err(e))
@@ -391,38 +699,75 @@ impl<E: DbExecutor> TransactionsServiceImpl<E> {
         to_account: Account,
         exchange_id: ExchangeId,
         exchange_rate: f64,
+        related_tx: Option<TransactionId>,
+        // overridden when exchange is a part of withdrawal
+        tx_group_kind: Option<TransactionGroupKind>,
     ) -> impl Future<Item = Vec<Transaction>, Error = Error> + Send {
         let db_executor = self.db_executor.clone();
         let system_service = self.system_service.clone();
         let self_clone = self.clone();
+        // `exchange_rate` is the one quoted to user, gateway exchanges at its own rate and the difference stays with us
+        let spread = self.config.exchange_options.spread(from_account.currency, to_account.currency);
+        let gateway_rate = gateway_rate(exchange_rate, spread);
         let exchange_input = ExchangeInput {
             id: exchange_id,
             from: from_account.currency,
             to: to_account.currency,
-            rate: exchange_rate,
+            rate: gateway_rate,
             actual_amount: input.value,
             amount_currency: input.value_currency,
         };
         let exchange_input_clone = exchange_input.clone();
+        let exchange_client = self.exchange_client.clone();
+        let max_rate_deviation = self.config.exchange_options.max_rate_deviation;
+        let max_slippage = input.max_slippage;
+        let rate_input = RateInput::new(from_account.currency, to_account.currency, input.value, input.value_currency);
+        let rate_input_clone = rate_input.clone();
+        // client rate is checked against the current one, otherwise liquidity accounts
+        // could be drained by exchanging at arbitrary rate
         self.exchange_client
-            .exchange(exchange_input, Role::User)
-            .map_err(ectx!(convert => exchange_input_clone))
+            .rate(rate_input, Role::System)
+            .map_err(ectx!(convert => rate_input_clone))
+            .and_then(move |current_rate| {
+                let current_rate = current_rate.with_spread(spread).rate;
+                check_exchange_rate(exchange_rate, current_rate, max_rate_deviation)?;
+                match max_slippage {
+                    Some(max_slippage) => check_slippage(exchange_rate, current_rate, max_slippage),
+                    None => Ok(()),
+                }
+            })
             .and_then(move |_| {
-                db_executor.execute_transaction_with_isolation(Isolation::Serializable, move || {
+                exchange_client
+                    .exchange(exchange_input, Role::User)
+                    .map_err(ectx!(convert => exchange_input_clone))
+            })
+            // whatever fails from here on, the funds are already exchanged by gateway
+            .map_err(ectx!(convert ErrorContext::ExchangeNotPerformed))
+            .and_then(move |exchange| {
+                // request is retried with the same id as idempotency key, so the reply must be about this exchange,
+                // otherwise ledger would record an exchange that gateway didn't make
+                if exchange.id.map_or(false, |id| id != exchange_id) {
+                    return Err(ectx!(err ErrorContext::ExchangeIdMismatch, ErrorKind::Internal => exchange_id, exchange));
+                }
+                Ok(())
+            })
+            .and_then(move |_| {
+                db_executor.execute_transaction_with_retry(Isolation::Serializable, move || {
                     let mut res: Vec<Transaction> = Vec::new();
 
-                    let (from_value, to_value) = if from_account.currency == input.value_currency {
-                        (
-                            input.value,
-                            input.value.convert(from_account.currency, to_account.currency, exchange_rate),
-                        )
-                    } else if to_account.currency == input.value_currency {
-                        (
-                            input.value.convert(to_account.currency, from_account.currency, 1.0 / exchange_rate),
-                            input.value,
-                        )
+                    let (from_value, to_value) = exchanged_values(
+                        from_account.currency,
+                        to_account.currency,
+                        input.value,
+                        input.value_currency,
+                        exchange_rate,
+                    )
+                    .ok_or(ectx!(try err ErrorContext::InvalidCurrency, ErrorKind::Internal => input, from_account, to_account))?;
+                    let margin_meta = if spread > 0.0 {
+                        let margin = exchange_margin(from_account.currency, to_account.currency, from_value, to_value, gateway_rate);
+                        Some(json!({ EXCHANGE_MARGIN_META_KEY: margin }))
                     } else {
-                        return Err(ectx!(err ErrorContext::InvalidCurrency, ErrorKind::Internal => input, from_account, to_account));
+                        None
                     };
 
                     let current_tx_id = input.id;
@@ -443,8 +788,8 @@ impl<E: DbExecutor> TransactionsServiceImpl<E> {
                         status: TransactionStatus::Done,
                         blockchain_tx_id: None,
                         kind: TransactionKind::MultiFrom,
-                        group_kind: TransactionGroupKind::InternalMulti,
-                        related_tx: None,
+                        group_kind: tx_group_kind.unwrap_or(TransactionGroupKind::InternalMulti),
+                        related_tx,
                         meta: None,
                     };
                     res.push(self_clone.create_base_tx(from_tx, from_account.clone(), from_counterpart_acc)?);
@@ -466,15 +811,138 @@ impl<E: DbExecutor> TransactionsServiceImpl<E> {
                         status: TransactionStatus::Done,
                         blockchain_tx_id: None,
                         kind: TransactionKind::MultiTo,
-                        group_kind: TransactionGroupKind::InternalMulti,
-                        related_tx: None,
-                        meta: None,
+                        group_kind: tx_group_kind.unwrap_or(TransactionGroupKind::InternalMulti),
+                        related_tx,
+                        meta: margin_meta,
                     };
                     res.push(self_clone.create_base_tx(to_tx, to_counterpart_acc, to_account.clone())?);
                     Ok(res)
                 })
             })
     }
+
+    // Exchanges value and fee of withdrawal to the user's account in `to_currency` and sends it from there,
+    // both parts go in one group. Exchange confirmed by gateway is final: if nothing is sent after it,
+    // funds stay exchanged on that account
+    fn create_external_multi_currency_tx(
+        &self,
+        input: CreateTransactionInput,
+        from_account: Account,
+        to_blockchain_address: BlockchainAddress,
+        to_currency: Currency,
+        exchange_id: ExchangeId,
+        exchange_rate: f64,
+    ) -> impl Future<Item = Vec<Transaction>, Error = Error> + Send {
+        if input.sweep {
+            return Either::A(future::err(
+                ectx!(err ErrorContext::NotSupported, ErrorContext::ExchangeNotPerformed, invalid_input("sweep", "not_supported", "withdrawals with exchange can't sweep the balance") => input),
+            ));
+        }
+        let to_value = match get_exchanged_value(&input, from_account.currency, to_currency, exchange_rate) {
+            Ok(to_value) => to_value,
+            Err(e) => return Either::A(future::err(ectx!(convert err e, ErrorContext::ExchangeNotPerformed))),
+        };
+        let exchange_value = match to_value.checked_add(input.fee) {
+            Some(exchange_value) => exchange_value,
+            None => {
+                return Either::A(future::err(
+                    ectx!(err ErrorContext::BalanceOverflow, ErrorContext::ExchangeNotPerformed, ErrorKind::Internal => input),
+                ))
+            }
+        };
+        let db_executor = self.db_executor.clone();
+        let transactions_repo = self.transactions_repo.clone();
+        let self_clone = self.clone();
+        let self_clone2 = self.clone();
+        let self_clone3 = self.clone();
+        let user_id = input.user_id;
+        let gid = input.id;
+        let input_fee = input.fee;
+        // withdrawal is checked to be possible before the exchange, not to exchange funds in vain
+        Either::B(
+            self.blockchain_service
+                .estimate_withdrawal_fee(input.fee, to_currency, to_currency)
+                .map_err(ectx!(ErrorKind::Internal => input_fee, to_currency))
+                .and_then(move |FeeEstimate { gross_fee, .. }| {
+                    db_executor.execute(move || {
+                        transactions_repo
+                            .get_accounts_for_withdrawal(to_value, to_currency, gross_fee)
+                            .map_err(ectx!(try convert => to_value, to_currency, gross_fee))?;
+                        self_clone.get_exchange_account(user_id, to_currency)
+                    })
+                })
+                .map_err(ectx!(convert ErrorContext::ExchangeNotPerformed))
+                .and_then(move |exchange_account| {
+                    let exchange_input = CreateTransactionInput {
+                        to: Recepient::new(exchange_account.id.to_string()),
+                        to_type: RecepientType::Account,
+                        value: exchange_value,
+                        value_currency: to_currency,
+                        ..input.clone()
+                    };
+                    let withdrawal_input = CreateTransactionInput {
+                        // the first two ids of the group are taken by the exchange
+                        id: gid.next().next(),
+                        value: to_value,
+                        value_currency: to_currency,
+                        ..input
+                    };
+                    self_clone2
+                        .create_internal_multi_currency_tx(
+                            exchange_input,
+                            from_account,
+                            exchange_account.clone(),
+                            exchange_id,
+                            exchange_rate,
+                            None,
+                            Some(TransactionGroupKind::WithdrawalMulti),
+                        )
+                        .and_then(move |exchange_txs| {
+                            let exchange_account_id = exchange_account.id;
+                            self_clone3
+                                .create_external_mono_currency_tx(
+                                    withdrawal_input,
+                                    exchange_account,
+                                    to_blockchain_address,
+                                    to_currency,
+                                    Some(gid),
+                                    None,
+                                    Some(TransactionGroupKind::WithdrawalMulti),
+                                    None,
+                                    None,
+                                )
+                                .then(move |res| match res {
+                                    Ok(withdrawal_txs) => {
+                                        let mut txs = exchange_txs;
+                                        txs.extend(withdrawal_txs);
+                                        Ok(txs)
+                                    }
+                                    // gateway has exchanged the funds and doesn't take them back, so the exchange isn't reversed
+                                    // in ledger either, otherwise liquidity accounts would diverge from gateway balances
+                                    Err(e) => {
+                                        warn!(
+                                            "Withdrawal {} failed after exchange, funds stay on account {}",
+                                            gid, exchange_account_id
+                                        );
+                                        Err(e)
+                                    }
+                                })
+                        })
+                }),
+        )
+    }
+
+    // Account of the user in `currency` that receives exchanged funds of withdrawal before they are sent
+    fn get_exchange_account(&self, user_id: UserId, currency: Currency) -> Result<Account, Error> {
+        self.accounts_repo
+            .list_for_user(user_id, 0, MAX_USER_ACCOUNTS, AccountsFilter::default())
+            .map_err(ectx!(try convert => user_id))?
+            .into_iter()
+            .find(|account| account.currency == currency && account.kind == AccountKind::Cr && !account.archived)
+            .ok_or(
+                ectx!(err ErrorContext::NoAccount, invalid_input("toCurrency", "no_account", "user has no account in the currency to exchange to") => user_id, currency),
+            )
+    }
 }
 
 impl<E: DbExecutor> TransactionsService for TransactionsServiceImpl<E> {
@@ -488,17 +956,31 @@ impl<E: DbExecutor> TransactionsService for TransactionsServiceImpl<E> {
         let self_clone = self.clone();
         let self_clone2 = self.clone();
         let self_clone3 = self.clone();
-        let input_clone = input.clone();
+        let self_clone4 = self.clone();
+        let rate_lock_id = input.rate_lock_id;
         Box::new(
             self.auth_service
                 .authenticate(token.clone())
                 .and_then(move |user| {
                     let input = CreateTransactionInput { user_id: user.id, ..input };
                     db_executor
-                        .execute_transaction_with_isolation(Isolation::Serializable, move || {
-                            self_clone.classifier_service.validate_and_classify_transaction(&input)
+                        .execute_transaction_with_retry(Isolation::Serializable, move || {
+                            let input = self_clone.with_locked_rate(input.clone())?;
+                            let input = self_clone.with_swept_value(input)?;
+                            let tx_type = self_clone.classifier_service.validate_and_classify_transaction(&input)?;
+                            match tx_type {
+                                TransactionType::Internal(_, _) => (),
+                                _ if input.sweep => {
+                                    return Err(
+                                        ectx!(err ErrorContext::NotSupported, invalid_input("sweep", "not_supported", "only internal transfers can sweep the balance") => input),
+                                    )
+                                }
+                                _ => (),
+                            }
+                            let fee_payer_account = self_clone.classifier_service.get_fee_payer_account(&input, &tx_type)?;
+                            Ok((input, tx_type, fee_payer_account))
                         })
-                        .and_then(move |tx_type| {
+                        .and_then(move |(input_clone, tx_type, fee_payer_account)| {
                             type BoxedFuture = Box<Future<Item = Vec<Transaction>, Error = Error> + Send>;
                             match tx_type.clone() {
                                 TransactionType::Internal(from_account, to_account) => Box::new(
@@ -507,8 +989,15 @@ impl<E: DbExecutor> TransactionsService for TransactionsServiceImpl<E> {
                                         .map(|tx| vec![tx]),
                                 ) as BoxedFuture,
                                 TransactionType::Withdrawal(from_account, to_blockchain_address, currency) => {
+                                    let input = if input_clone.fee_payer == FeePayer::Recipient {
+                                        // classifier checks that fee is less than value
+                                        let value = input_clone.value.checked_sub(input_clone.fee).unwrap_or_default();
+                                        CreateTransactionInput { value, ..input_clone }
+                                    } else {
+                                        input_clone
+                                    };
                                     Box::new(self_clone3.create_external_mono_currency_tx(
-                                        input_clone,
+                                        input,
                                         from_account,
                                         to_blockchain_address,
                                         currency,
@@ -516,27 +1005,53 @@ impl<E: DbExecutor> TransactionsService for TransactionsServiceImpl<E> {
                                         None,
                                         None,
                                         None,
-                                        None,
+                                        fee_payer_account,
                                     )) as BoxedFuture
                                 }
-                                TransactionType::InternalExchange(from, to, exchange_id, rate) => {
-                                    Box::new(self_clone3.create_internal_multi_currency_tx(input_clone, from, to, exchange_id, rate))
-                                        as BoxedFuture
-                                }
-                                TransactionType::WithdrawalExchange(_from, _to_blockchain_address, _to_currency, _exchange_id, _rate) => {
-                                    // This function is implemented but not tested. For now we disable it,
-                                    // since we disable this functionality in wallet app.
-                                    // self_clone.create_external_multi_currency_tx(
-                                    //     input,
-                                    //     from,
-                                    //     to_blockchain_address,
-                                    //     to_currency,
-                                    //     exchange_id,
-                                    //     rate,
-                                    // )
-                                    Box::new(future::err(ectx!(err ErrorContext::NotSupported, ErrorKind::MalformedInput))) as BoxedFuture
+                                TransactionType::InternalExchange(from, to, exchange_id, rate) => Box::new(
+                                    self_clone3.create_internal_multi_currency_tx(input_clone, from, to, exchange_id, rate, None, None),
+                                )
+                                    as BoxedFuture,
+                                TransactionType::WithdrawalExchange(from, to_blockchain_address, to_currency, exchange_id, rate) => {
+                                    if self_clone3.config.exchange_options.withdrawal_exchange_enabled {
+                                        Box::new(self_clone3.create_external_multi_currency_tx(
+                                            input_clone,
+                                            from,
+                                            to_blockchain_address,
+                                            to_currency,
+                                            exchange_id,
+                                            rate,
+                                        )) as BoxedFuture
+                                    } else {
+                                        Box::new(future::err(
+                                            ectx!(err ErrorContext::NotSupported, ErrorContext::ExchangeNotPerformed, ErrorKind::MalformedInput),
+                                        )) as BoxedFuture
+                                    }
                                 }
                             }
+                            .or_else(move |e| match rate_lock_id {
+                                // gateway hasn't exchanged the funds, so the locked rate can be used for another try.
+                                // Once it has, the quote is spent, even if recording the transaction fails after that
+                                Some(rate_lock_id) if is_exchange_not_performed(&e) => {
+                                    let db_executor = self_clone4.db_executor.clone();
+                                    Either::A(
+                                        db_executor
+                                            .execute(move || {
+                                                self_clone4
+                                                    .rate_locks_repo
+                                                    .release_lock(rate_lock_id)
+                                                    .map_err(ectx!(convert => rate_lock_id))
+                                            })
+                                            .then(move |res: Result<Option<RateLock>, Error>| {
+                                                if let Err(release_error) = res {
+                                                    log_error(&release_error);
+                                                }
+                                                Err(e)
+                                            }),
+                                    )
+                                }
+                                _ => Either::B(future::err(e)),
+                            })
                             .map(|tx_group| (tx_group, tx_type))
                         })
                 })
@@ -576,6 +1091,33 @@ impl<E: DbExecutor> TransactionsService for TransactionsServiceImpl<E> {
         )
     }
 
+    fn create_payout(&self, token: AuthenticationToken, input: CreatePayoutInput) -> Box<Future<Item = PayoutOut, Error = Error> + Send> {
+        let db_executor = self.db_executor.clone();
+        let classifier_service = self.classifier_service.clone();
+        let self_clone = self.clone();
+        let self_clone2 = self.clone();
+        Box::new(
+            self.auth_service
+                .authenticate(token)
+                .and_then(move |user| {
+                    let input = CreatePayoutInput { user_id: user.id, ..input };
+                    let input_clone = input.clone();
+                    db_executor
+                        .execute_transaction_with_retry(Isolation::Serializable, move || classifier_service.validate_payout(&input_clone))
+                        .and_then(move |from_account| self_clone.send_payouts(input, from_account))
+                })
+                .and_then(move |(tx_group, payouts)| {
+                    let db_executor = self_clone2.db_executor.clone();
+                    db_executor.execute_transaction_with_isolation(Isolation::RepeatableRead, move || {
+                        self_clone2
+                            .converter_service
+                            .convert_transaction(tx_group)
+                            .map(|transaction| PayoutOut { transaction, payouts })
+                    })
+                }),
+        )
+    }
+
     fn get_transaction(
         &self,
         token: AuthenticationToken,
@@ -585,7 +1127,7 @@ impl<E: DbExecutor> TransactionsService for TransactionsServiceImpl<E> {
         let db_executor = self.db_executor.clone();
         let self_clone = self.clone();
         Box::new(self.auth_service.authenticate(token).and_then(move |user| {
-            db_executor.execute(move || {
+            db_executor.execute_read_only(move || {
                 let transaction = transactions_repo
                     .get(transaction_id)
                     .map_err(ectx!(try convert => transaction_id))?;
@@ -603,6 +1145,33 @@ impl<E: DbExecutor> TransactionsService for TransactionsServiceImpl<E> {
             })
         }))
     }
+    fn get_group_fee(
+        &self,
+        token: AuthenticationToken,
+        transaction_id: TransactionId,
+    ) -> Box<Future<Item = Option<GroupFee>, Error = Error> + Send> {
+        let transactions_repo = self.transactions_repo.clone();
+        let db_executor = self.db_executor.clone();
+        let system_user_id = self.config.system.system_user_id;
+        Box::new(self.auth_service.authenticate(token).and_then(move |user| {
+            if user.id != system_user_id {
+                return Either::A(future::err(
+                    ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id),
+                ));
+            }
+            Either::B(db_executor.execute_read_only(move || {
+                let transaction = transactions_repo
+                    .get(transaction_id)
+                    .map_err(ectx!(try convert => transaction_id))?;
+                match transaction {
+                    Some(transaction) => transactions_repo
+                        .get_group_fee(transaction.gid)
+                        .map_err(ectx!(convert => transaction_id)),
+                    None => Ok(None),
+                }
+            }))
+        }))
+    }
     fn get_account_balance(
         &self,
         token: AuthenticationToken,
@@ -612,7 +1181,7 @@ impl<E: DbExecutor> TransactionsService for TransactionsServiceImpl<E> {
         let accounts_repo = self.accounts_repo.clone();
         let db_executor = self.db_executor.clone();
         Box::new(self.auth_service.authenticate(token).and_then(move |user| {
-            db_executor.execute(move || -> Result<AccountWithBalance, Error> {
+            db_executor.execute_read_only(move || -> Result<AccountWithBalance, Error> {
                 let account = accounts_repo.get(account_id).map_err(ectx!(try convert => account_id))?;
                 if let Some(account) = account {
                     if account.user_id != user.id {
@@ -628,32 +1197,53 @@ impl<E: DbExecutor> TransactionsService for TransactionsServiceImpl<E> {
             })
         }))
     }
+    fn get_account_stats(
+        &self,
+        token: AuthenticationToken,
+        account_id: AccountId,
+        window: StatsWindow,
+    ) -> Box<Future<Item = AccountStats, Error = Error> + Send> {
+        let transactions_repo = self.transactions_repo.clone();
+        let accounts_repo = self.accounts_repo.clone();
+        let db_executor = self.db_executor.clone();
+        Box::new(self.auth_service.authenticate(token).and_then(move |user| {
+            db_executor.execute_read_only(move || -> Result<AccountStats, Error> {
+                let account = accounts_repo
+                    .get(account_id)
+                    .map_err(ectx!(try convert => account_id))?
+                    .ok_or(ectx!(try err ErrorContext::NoAccount, ErrorKind::NotFound => account_id))?;
+                if account.user_id != user.id {
+                    return Err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id));
+                }
+                transactions_repo
+                    .get_account_stats(account_id, window)
+                    .map_err(ectx!(convert => account_id, window))
+            })
+        }))
+    }
     fn get_transactions_for_user(
         &self,
         token: AuthenticationToken,
         user_id: UserId,
         offset: i64,
         limit: i64,
+        sort: TransactionsSort,
     ) -> Box<Future<Item = Vec<TransactionOut>, Error = Error> + Send> {
         let transactions_repo = self.transactions_repo.clone();
         let db_executor = self.db_executor.clone();
         let self_clone = self.clone();
         Box::new(self.auth_service.authenticate(token).and_then(move |user| {
-            db_executor.execute(move || -> Result<Vec<TransactionOut>, Error> {
+            db_executor.execute_read_only(move || -> Result<Vec<TransactionOut>, Error> {
                 if user_id != user.id {
                     return Err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id));
                 }
                 let txs = transactions_repo
-                    .list_groups_for_user_skip_approval(user_id, offset, limit)
-                    .map_err(ectx!(try convert => user_id, offset, limit))?;
-                let res: Result<Vec<TransactionOut>, Error> = group_transactions(&txs)
+                    .list_groups_for_user_skip_approval(user_id, offset, limit, sort)
+                    .map_err(ectx!(try convert => user_id, offset, limit, sort))?;
+                group_transactions(&txs)
                     .into_iter()
                     .map(|tx_group| self_clone.converter_service.convert_transaction(tx_group))
-                    .collect();
-                let mut res = res?;
-                res.sort_by_key(|tx| tx.created_at);
-                let res: Vec<_> = res.into_iter().rev().collect();
-                Ok(res)
+                    .collect()
             })
         }))
     }
@@ -663,13 +1253,13 @@ impl<E: DbExecutor> TransactionsService for TransactionsServiceImpl<E> {
         account_id: AccountId,
         offset: i64,
         limit: i64,
+        sort: TransactionsSort,
     ) -> Box<Future<Item = Vec<TransactionOut>, Error = Error> + Send> {
-        let transactions_repo = self.transactions_repo.clone();
         let accounts_repo = self.accounts_repo.clone();
         let db_executor = self.db_executor.clone();
         let self_clone = self.clone();
         Box::new(self.auth_service.authenticate(token).and_then(move |user| {
-            db_executor.execute(move || {
+            db_executor.execute_read_only(move || {
                 let account = accounts_repo.get(account_id).map_err(ectx!(try convert => account_id))?;
                 if let Some(ref account) = account {
                     if account.user_id != user.id {
@@ -678,63 +1268,1024 @@ impl<E: DbExecutor> TransactionsService for TransactionsServiceImpl<E> {
                 } else {
                     return Err(ectx!(err ErrorContext::NoAccount, ErrorKind::NotFound => account_id));
                 }
-                let txs = transactions_repo
-                    .list_groups_for_account_skip_approval(account_id, offset, limit)
-                    .map_err(ectx!(try convert => account_id))?;
-                let res: Result<Vec<TransactionOut>, Error> = group_transactions(&txs)
-                    .into_iter()
-                    .map(|tx_group| self_clone.converter_service.convert_transaction(tx_group))
-                    .collect();
-                let mut res = res?;
-                res.sort_by_key(|tx| tx.created_at);
-                let res: Vec<_> = res.into_iter().rev().collect();
-                Ok(res)
+                self_clone.list_account_transactions(account_id, offset, limit, sort)
             })
         }))
     }
-}
+    fn stream_account_transactions(
+        &self,
+        token: AuthenticationToken,
+        account_id: AccountId,
+    ) -> Box<Future<Item = TransactionsStream, Error = Error> + Send> {
+        let accounts_repo = self.accounts_repo.clone();
+        let db_executor = self.db_executor.clone();
+        let self_clone = self.clone();
+        Box::new(
+            self.auth_service
+                .authenticate(token)
+                .and_then(move |user| {
+                    db_executor.execute_read_only(move || {
+                        let account = accounts_repo.get(account_id).map_err(ectx!(try convert => account_id))?;
+                        match account {
+                            Some(ref account) if account.user_id != user.id => {
+                                Err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id))
+                            }
+                            Some(_) => Ok(()),
+                            None => Err(ectx!(err ErrorContext::NoAccount, ErrorKind::NotFound => account_id)),
+                        }
+                    })
+                })
+                .map(move |_| {
+                    // cursor of the next batch - the oldest group seen so far, none if the last batch was already fetched
+                    let batches = futures::stream::unfold(Some(None), move |before| {
+                        before.map(|before| {
+                            let self_clone = self_clone.clone();
+                            let db_executor = self_clone.db_executor.clone();
+                            db_executor.execute_read_only(move || {
+                                let txs = self_clone
+                                    .transactions_repo
+                                    .list_groups_for_account_before(account_id, before, STREAM_BATCH_SIZE)
+                                    .map_err(ectx!(try convert => account_id, before))?;
+                                // transactions inside a group are the newest first, so the last one has group's created_at
+                                let next_before = txs.last().map(|tx| (tx.created_at, tx.gid));
+                                let batch: Vec<TransactionOut> = group_transactions(&txs)
+                                    .into_iter()
+                                    .map(|tx_group| self_clone.converter_service.convert_transaction(tx_group))
+                                    .collect::<Result<_, _>>()?;
+                                let next_before = if (batch.len() as i64) < STREAM_BATCH_SIZE {
+                                    None
+                                } else {
+                                    next_before.map(Some)
+                                };
+                                Ok((batch, next_before))
+                            })
+                        })
+                    });
+                    Box::new(batches.map(|batch| futures::stream::iter_ok::<_, Error>(batch)).flatten()) as TransactionsStream
+                }),
+        )
+    }
 
-// group transactions into subgroups of related txs. I.e. group tx itself + fee
-fn group_transactions(transactions: &[Transaction]) -> Vec<Vec<Transaction>> {
-    let mut res: HashMap<TransactionId, Vec<Transaction>> = HashMap::new();
-    for tx in transactions.into_iter() {
-        res.entry(tx.gid).and_modify(|txs| txs.push(tx.clone())).or_insert(vec![tx.clone()]);
+    fn bounce_strange_transaction(
+        &self,
+        token: AuthenticationToken,
+        hash: BlockchainTransactionId,
+        to: Option<BlockchainAddress>,
+    ) -> Box<Future<Item = TransactionOut, Error = Error> + Send> {
+        let db_executor = self.db_executor.clone();
+        let db_executor_ = self.db_executor.clone();
+        let db_executor__ = self.db_executor.clone();
+        let blockchain_service = self.blockchain_service.clone();
+        let system_user_id = self.config.system.system_user_id;
+        let self_clone = self.clone();
+        let self_clone2 = self.clone();
+        let self_clone3 = self.clone();
+        Box::new(
+            self.auth_service
+                .authenticate(token)
+                .and_then(move |user| {
+                    if user.id != system_user_id {
+                        return Either::A(future::err(
+                            ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id),
+                        ));
+                    }
+                    // strange transaction is taken out before the funds are sent, so a concurrent request
+                    // for the same one either doesn't find it or fails to serialize
+                    Either::B(
+                        db_executor.execute_transaction_with_isolation(Isolation::Serializable, move || self_clone.claim_bounce(hash, to)),
+                    )
+                })
+                .and_then(move |bounce| {
+                    let Bounce {
+                        account,
+                        to,
+                        value,
+                        fee_price,
+                        ..
+                    } = bounce.clone();
+                    let from = account.address.clone();
+                    match account.currency {
+                        Currency::Btc => Either::A(
+                            blockchain_service
+                                .create_bitcoin_tx(from.clone(), to.clone(), value, fee_price)
+                                .map_err(ectx!(ErrorKind::Internal => from, to, value, fee_price)),
+                        ),
+                        currency => Either::B(
+                            blockchain_service
+                                .create_ethereum_tx(from.clone(), to.clone(), value, fee_price, currency)
+                                .map_err(ectx!(ErrorKind::Internal => from, to, value, fee_price, currency)),
+                        ),
+                    }
+                    .then(move |res| match res {
+                        Ok(blockchain_tx_id) => Either::A(future::ok((bounce, blockchain_tx_id))),
+                        // the funds are not sent, so the strange transaction is put back to be bounced later
+                        Err(e) => Either::B(db_executor__.execute(move || {
+                            let strange_tx = bounce.strange_tx;
+                            if let Err(restore_error) = self_clone3.restore_strange_transaction(strange_tx) {
+                                log_error(&restore_error);
+                            }
+                            Err(e)
+                        })),
+                    })
+                })
+                .and_then(move |(bounce, blockchain_tx_id)| {
+                    // Note - the funds are already sent back, so if we fail here the bounce is not recorded,
+                    // but the strange transaction is already taken out and can't be bounced again
+                    db_executor_.execute_transaction_with_isolation(Isolation::Serializable, move || {
+                        self_clone2.record_bounce(bounce, blockchain_tx_id)
+                    })
+                }),
+        )
     }
-    res.into_iter().map(|(_, txs)| txs).collect()
-}
 
-#[cfg(test)]
-#[allow(unused)]
-mod tests {
-    use super::*;
-    use client::*;
-    use config::Config;
-    use rabbit::*;
-    use repos::*;
-    use services::*;
-    use tokio_core::reactor::Core;
+    fn auto_convert_deposit(&self, deposit: Transaction) -> Box<Future<Item = Option<TransactionOut>, Error = Error> + Send> {
+        let db_executor = self.db_executor.clone();
+        let db_executor_ = self.db_executor.clone();
+        let accounts_repo = self.accounts_repo.clone();
+        let classifier_service = self.classifier_service.clone();
+        let exchange_client = self.exchange_client.clone();
+        let converter_service = self.converter_service.clone();
+        let publisher = self.publisher.clone();
+        let self_clone = self.clone();
+        let account_id = deposit.cr_account_id;
+        Box::new(
+            db_executor
+                .execute(move || -> Result<Option<(Account, Account)>, Error> {
+                    let account = accounts_repo
+                        .get(account_id)
+                        .map_err(ectx!(try convert => account_id))?
+                        .ok_or(ectx!(try err ErrorContext::NoAccount, ErrorKind::Internal => account_id))?;
+                    let to_account_id = match account.auto_convert_to {
+                        Some(to_account_id) => to_account_id,
+                        None => return Ok(None),
+                    };
+                    let to_account = accounts_repo
+                        .get(to_account_id)
+                        .map_err(ectx!(try convert => to_account_id))?
+                        .ok_or(ectx!(try err ErrorContext::NoAccount, ErrorKind::Internal => to_account_id))?;
+                    // account could be archived after the flag was set, then deposit just stays where it came
+                    if to_account.archived {
+                        return Ok(None);
+                    }
+                    // deposit stays where it came while the pair is paused, the rate isn't even asked for then
+                    let (from, to) = (account.currency, to_account.currency);
+                    if !classifier_service.is_exchange_pair_allowed(from, to)? {
+                        return Ok(None);
+                    }
+                    Ok(Some((account, to_account)))
+                })
+                .and_then(move |accounts| {
+                    let (from_account, to_account) = match accounts {
+                        Some(accounts) => accounts,
+                        None => return Either::A(future::ok(None)),
+                    };
+                    let rate_input = RateInput::new(from_account.currency, to_account.currency, deposit.value, deposit.currency);
+                    let rate_input_clone = rate_input.clone();
+                    Either::B(
+                        exchange_client
+                            .rate(rate_input, Role::System)
+                            .map_err(ectx!(convert => rate_input_clone))
+                            .and_then(move |rate| {
+                                // deposit owner pays the spread, as with exchanges made by hand
+                                let spread = self_clone
+                                    .config
+                                    .exchange_options
+                                    .spread(from_account.currency, to_account.currency);
+                                let rate = rate.with_spread(spread);
+                                let input = CreateTransactionInput {
+                                    id: TransactionId::generate(),
+                                    user_id: from_account.user_id,
+                                    from: from_account.id,
+                                    to: Recepient::new(to_account.id.to_string()),
+                                    to_type: RecepientType::Account,
+                                    to_currency: to_account.currency,
+                                    value: deposit.value,
+                                    value_currency: deposit.currency,
+                                    fee: Amount::new(0),
+                                    exchange_id: Some(rate.id),
+                                    exchange_rate: Some(rate.rate),
+                                    fee_payer: FeePayer::Sender,
+                                    fee_payer_account_id: None,
+                                    sweep: false,
+                                    rate_lock_id: None,
+                                    max_slippage: None,
+                                };
+                                self_clone.create_internal_multi_currency_tx(
+                                    input,
+                                    from_account,
+                                    to_account,
+                                    rate.id,
+                                    rate.rate,
+                                    Some(deposit.id),
+                                    None,
+                                )
+                            })
+                            .and_then(move |tx_group| db_executor_.execute(move || converter_service.convert_transaction(tx_group)))
+                            .and_then(move |tx_out| {
+                                // user didn't initiate the exchange, so it is published like the deposit itself
+                                let tx_out_clone = tx_out.clone();
+                                publisher
+                                    .publish(tx_out.clone())
+                                    .map_err(ectx!(ErrorSource::Lapin, ErrorKind::Internal => tx_out_clone))
+                                    .then(|r: Result<(), Error>| {
+                                        if let Err(e) = r {
+                                            log_error(&e);
+                                        }
+                                        Ok(Some(tx_out))
+                                    })
+                            }),
+                    )
+                }),
+        )
+    }
 
-    fn create_transaction_service(token: AuthenticationToken, user_id: UserId) -> TransactionsServiceImpl<DbExecutorMock> {
-        let config = Config::new().unwrap();
-        let auth_service = Arc::new(AuthServiceMock::new(vec![(token, user_id)]));
-        let accounts_repo = Arc::new(AccountsRepoMock::default());
-        let transactions_repo = Arc::new(TransactionsRepoMock::default());
-        let pending_transactions_repo = Arc::new(PendingBlockchainTransactionsRepoMock::default());
-        let blockchain_transactions_repo = Arc::new(BlockchainTransactionsRepoMock::default());
-        let key_values_repo = Arc::new(KeyValuesRepoMock::default());
-        let keys_client = Arc::new(KeysClientMock::default());
-        let blockchain_client = Arc::new(BlockchainClientMock::default());
-        let exchange_client = Arc::new(ExchangeClientMock::default());
-        let db_executor = DbExecutorMock::default();
-        let publisher = Arc::new(TransactionPublisherMock::default());
-        TransactionsServiceImpl::new(
-            config,
-            auth_service,
-            transactions_repo,
-            pending_transactions_repo,
-            blockchain_transactions_repo,
-            accounts_repo,
-            key_values_repo,
+    fn queue_withdrawal(
+        &self,
+        token: AuthenticationToken,
+        input: CreateTransactionInput,
+    ) -> Box<Future<Item = QueuedWithdrawal, Error = Error> + Send> {
+        let db_executor = self.db_executor.clone();
+        let classifier_service = self.classifier_service.clone();
+        let queued_withdrawals_repo = self.queued_withdrawals_repo.clone();
+        Box::new(self.auth_service.authenticate(token).and_then(move |user| {
+            let input = CreateTransactionInput { user_id: user.id, ..input };
+            db_executor.execute_transaction(move || {
+                if input.fee_payer != FeePayer::Sender {
+                    return Err(
+                        ectx!(err ErrorContext::NotSupported, invalid_input("feePayer", "not_supported", "queued withdrawals are paid by sender") => input),
+                    );
+                }
+                if input.sweep {
+                    return Err(
+                        ectx!(err ErrorContext::NotSupported, invalid_input("sweep", "not_supported", "queued withdrawals can't sweep the balance") => input),
+                    );
+                }
+                let (from_account, address, currency) = match classifier_service.validate_and_classify_transaction(&input)? {
+                    TransactionType::Withdrawal(from_account, address, currency) => (from_account, address, currency),
+                    _ => {
+                        return Err(
+                            ectx!(err ErrorContext::NotSupported, invalid_input("execution", "not_withdrawal", "only withdrawals can be queued") => input),
+                        );
+                    }
+                };
+                let new_withdrawal = NewQueuedWithdrawal {
+                    id: input.id,
+                    user_id: user.id,
+                    account_id: from_account.id,
+                    currency,
+                    address,
+                    value: input.value,
+                    fee: input.fee,
+                    status: QueuedWithdrawalStatus::Queued,
+                };
+                let new_withdrawal_clone = new_withdrawal.clone();
+                queued_withdrawals_repo
+                    .create(new_withdrawal)
+                    .map_err(ectx!(convert => new_withdrawal_clone))
+            })
+        }))
+    }
+
+    fn get_queued_withdrawal(
+        &self,
+        token: AuthenticationToken,
+        id: TransactionId,
+    ) -> Box<Future<Item = Option<QueuedWithdrawal>, Error = Error> + Send> {
+        let db_executor = self.db_executor.clone();
+        let queued_withdrawals_repo = self.queued_withdrawals_repo.clone();
+        Box::new(self.auth_service.authenticate(token).and_then(move |user| {
+            db_executor.execute_read_only(move || {
+                let withdrawal = queued_withdrawals_repo.get(id).map_err(ectx!(try convert => id))?;
+                if let Some(ref withdrawal) = withdrawal {
+                    if withdrawal.user_id != user.id {
+                        return Err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id));
+                    }
+                }
+                Ok(withdrawal)
+            })
+        }))
+    }
+
+    fn get_queued_withdrawals_for_user(
+        &self,
+        token: AuthenticationToken,
+        user_id: UserId,
+        offset: i64,
+        limit: i64,
+    ) -> Box<Future<Item = Vec<QueuedWithdrawal>, Error = Error> + Send> {
+        let db_executor = self.db_executor.clone();
+        let queued_withdrawals_repo = self.queued_withdrawals_repo.clone();
+        Box::new(self.auth_service.authenticate(token).and_then(move |user| {
+            db_executor.execute_read_only(move || {
+                if user_id != user.id {
+                    return Err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id));
+                }
+                queued_withdrawals_repo
+                    .list_for_user(user_id, offset, limit)
+                    .map_err(ectx!(convert => user_id, offset, limit))
+            })
+        }))
+    }
+
+    fn cancel_queued_withdrawal(
+        &self,
+        token: AuthenticationToken,
+        id: TransactionId,
+    ) -> Box<Future<Item = QueuedWithdrawal, Error = Error> + Send> {
+        let db_executor = self.db_executor.clone();
+        let queued_withdrawals_repo = self.queued_withdrawals_repo.clone();
+        Box::new(self.auth_service.authenticate(token).and_then(move |user| {
+            db_executor.execute_transaction(move || {
+                let withdrawal = queued_withdrawals_repo
+                    .get(id)
+                    .map_err(ectx!(try convert => id))?
+                    .ok_or(ectx!(try err ErrorContext::NoTransaction, ErrorKind::NotFound => id))?;
+                if withdrawal.user_id != user.id {
+                    return Err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id));
+                }
+                queued_withdrawals_repo.cancel(id).map_err(ectx!(try convert => id))?.ok_or(
+                    ectx!(try err ErrorContext::InvalidTransaction, invalid_input("id", "not_queued", "withdrawal is already sent or cancelled") => withdrawal),
+                )
+            })
+        }))
+    }
+
+    fn drain_withdrawal_queue(&self) -> Box<Future<Item = Vec<QueuedWithdrawal>, Error = Error> + Send> {
+        let queued_withdrawals_repo = self.queued_withdrawals_repo.clone();
+        let limit = self.config.withdrawal_queue.max_withdrawals_per_drain;
+        let self_clone = self.clone();
+        let self_clone2 = self.clone();
+        Box::new(
+            self.db_executor
+                .execute_transaction(move || {
+                    self_clone.requeue_stale_withdrawals()?;
+                    let withdrawals = queued_withdrawals_repo.take_queued(limit).map_err(ectx!(try convert => limit))?;
+                    let batches = group_queued_withdrawals(withdrawals);
+                    // transaction id is stored before sending, so that the reaper can tell
+                    // a batch that is already in ledger from the one that was never sent
+                    for batch in &batches {
+                        let ids: Vec<TransactionId> = batch.iter().map(|withdrawal| withdrawal.id).collect();
+                        let batch_tx_id = batch[0].id;
+                        queued_withdrawals_repo
+                            .finish(&ids, QueuedWithdrawalStatus::Processing, Some(batch_tx_id), None)
+                            .map_err(ectx!(try convert => ids, batch_tx_id))?;
+                    }
+                    Ok(batches)
+                })
+                .and_then(move |batches| {
+                    // batches are sent one by one, not to hit keys service with all of them at once.
+                    // Failed batch doesn't stop the others, its withdrawals are left to the reaper
+                    futures::stream::iter_ok(batches)
+                        .and_then(move |batch| {
+                            self_clone2.send_queued_withdrawals(batch).then(|res| match res {
+                                Ok(withdrawals) => Ok(withdrawals),
+                                Err(e) => {
+                                    log_error(&e);
+                                    Ok(Vec::new())
+                                }
+                            })
+                        })
+                        .concat2()
+                }),
+        )
+    }
+
+    fn retry_failed_broadcasts(&self) -> Box<Future<Item = Vec<PendingBlockchainTransactionDB>, Error = Error> + Send> {
+        let pending_transactions_repo = self.pending_transactions_repo.clone();
+        let limit = self.config.broadcast_retries.max_transactions_per_run;
+        let self_clone = self.clone();
+        Box::new(
+            self.db_executor
+                .execute(move || {
+                    pending_transactions_repo
+                        .list_due_for_broadcast(limit)
+                        .map_err(ectx!(convert => limit))
+                })
+                .and_then(move |pending_txs| {
+                    futures::stream::iter_ok(pending_txs)
+                        .and_then(move |pending_tx| self_clone.retry_broadcast(pending_tx))
+                        .collect()
+                }),
+        )
+    }
+}
+
+impl<E: DbExecutor> TransactionsServiceImpl<E> {
+    // Posts stored signed transaction once again. On success its placeholder id is replaced with the real one
+    // in pending and ledger transactions, otherwise the next attempt is scheduled, so it doesn't stop other retries
+    fn retry_broadcast(
+        &self,
+        pending_tx: PendingBlockchainTransactionDB,
+    ) -> impl Future<Item = PendingBlockchainTransactionDB, Error = Error> + Send {
+        let db_executor = self.db_executor.clone();
+        let pending_transactions_repo = self.pending_transactions_repo.clone();
+        let transactions_repo = self.transactions_repo.clone();
+        let broadcast_retries = self.config.broadcast_retries.clone();
+        let broadcast = match pending_tx.raw_tx.clone() {
+            Some(raw_tx) => Either::A(self.blockchain_service.broadcast_raw_tx(pending_tx.currency, raw_tx)),
+            None => Either::B(future::err(
+                ectx!(err ErrorContext::InvalidTransaction, ErrorKind::Internal => pending_tx.clone()),
+            )),
+        };
+        broadcast.then(move |res| {
+            let old_hash = pending_tx.hash.clone();
+            db_executor.execute_transaction(move || match res {
+                Ok(new_hash) => {
+                    let old_hash_clone = old_hash.clone();
+                    // already replaced by a concurrent retry or `rebroadcast_pending` command
+                    if pending_transactions_repo
+                        .delete(old_hash.clone())
+                        .map_err(ectx!(try convert => old_hash_clone))?
+                        .is_none()
+                    {
+                        return Ok(pending_tx);
+                    }
+                    let new_pending = NewPendingBlockchainTransactionDB {
+                        hash: new_hash.clone(),
+                        from_: pending_tx.from_,
+                        to_: pending_tx.to_,
+                        currency: pending_tx.currency,
+                        value: pending_tx.value,
+                        fee: pending_tx.fee,
+                        erc20_operation_kind: pending_tx.erc20_operation_kind,
+                        raw_tx: pending_tx.raw_tx,
+                        next_broadcast_at: None,
+                    };
+                    let new_pending_clone = new_pending.clone();
+                    let pending_tx = pending_transactions_repo
+                        .create(new_pending)
+                        .map_err(ectx!(try convert => new_pending_clone))?;
+                    if let Some(tx) = transactions_repo
+                        .get_by_blockchain_tx(old_hash.clone())
+                        .map_err(ectx!(try convert => old_hash))?
+                    {
+                        transactions_repo
+                            .update_blockchain_tx(tx.id, new_hash.clone())
+                            .map_err(ectx!(try convert => tx.id, new_hash))?;
+                    }
+                    Ok(pending_tx)
+                }
+                Err(e) => {
+                    let next_broadcast_at = broadcast_retries.next_attempt_at(pending_tx.broadcast_attempts + 1);
+                    if next_broadcast_at.is_none() {
+                        // left for `rebroadcast_pending` command
+                        log_and_capture_error(e);
+                    } else {
+                        log_error(&e);
+                    }
+                    pending_transactions_repo
+                        .reschedule_broadcast(old_hash.clone(), next_broadcast_at)
+                        .map_err(ectx!(convert => old_hash, next_broadcast_at))
+                }
+            })
+        })
+    }
+
+    // Sends validated payout destinations one by one, all of them in the group of the payout id.
+    // If some of them are already sent when one fails, the rest are skipped and the sent ones are
+    // returned with the result of each destination, since their transactions can't be taken back
+    fn send_payouts(
+        &self,
+        input: CreatePayoutInput,
+        from_account: Account,
+    ) -> impl Future<Item = (Vec<Transaction>, Vec<PayoutResult>), Error = Error> + Send {
+        let self_clone = self.clone();
+        let gid = input.id;
+        let payouts: Vec<(usize, PayoutDestination)> = input.payouts.clone().into_iter().enumerate().collect();
+        futures::stream::iter_ok(payouts)
+            .fold(
+                (Vec::<Transaction>::new(), Vec::<PayoutResult>::new(), None),
+                move |(mut tx_group, mut results, error): (Vec<Transaction>, Vec<PayoutResult>, Option<Error>), (index, payout)| {
+                    // ids of the transactions are derived from the input id, so each destination needs its own,
+                    // and a retried request gets the same ones
+                    let id = gid.derive(index as u32);
+                    let mut result = PayoutResult {
+                        id,
+                        address: payout.address.clone(),
+                        value: payout.value,
+                        status: PayoutStatus::Skipped,
+                    };
+                    if error.is_some() {
+                        results.push(result);
+                        return Either::A(future::ok((tx_group, results, error)));
+                    }
+                    let tx_input = CreateTransactionInput {
+                        id,
+                        user_id: input.user_id,
+                        from: input.from,
+                        to: Recepient::new(payout.address.to_string()),
+                        to_type: RecepientType::Address,
+                        to_currency: input.currency,
+                        value: payout.value,
+                        value_currency: input.currency,
+                        fee: input.fee,
+                        exchange_id: None,
+                        exchange_rate: None,
+                        fee_payer: FeePayer::Sender,
+                        fee_payer_account_id: None,
+                        sweep: false,
+                        rate_lock_id: None,
+                        max_slippage: None,
+                    };
+                    Either::B(
+                        self_clone
+                            .create_external_mono_currency_tx(
+                                tx_input,
+                                from_account.clone(),
+                                payout.address,
+                                input.currency,
+                                Some(gid),
+                                None,
+                                None,
+                                None,
+                                None,
+                            )
+                            .then(move |res| match res {
+                                Ok(txs) => {
+                                    tx_group.extend(txs);
+                                    result.status = PayoutStatus::Sent;
+                                    results.push(result);
+                                    Ok((tx_group, results, None))
+                                }
+                                Err(e) => {
+                                    result.status = PayoutStatus::Failed;
+                                    results.push(result);
+                                    Ok((tx_group, results, Some(e)))
+                                }
+                            }),
+                    )
+                },
+            )
+            .and_then(|(tx_group, results, error)| match error {
+                Some(e) => {
+                    if tx_group.is_empty() {
+                        Err(e)
+                    } else {
+                        log_and_capture_error(e);
+                        Ok((tx_group, results))
+                    }
+                }
+                None => Ok((tx_group, results)),
+            })
+    }
+
+    // Sends batch of queued withdrawals as one withdrawal of their total value, the highest fee
+    // of the batch pays for it. Failure is recorded in the withdrawals, so it doesn't stop the drain
+    fn send_queued_withdrawals(&self, batch: Vec<QueuedWithdrawal>) -> impl Future<Item = Vec<QueuedWithdrawal>, Error = Error> + Send {
+        let db_executor = self.db_executor.clone();
+        let db_executor_ = self.db_executor.clone();
+        let classifier_service = self.classifier_service.clone();
+        let queued_withdrawals_repo = self.queued_withdrawals_repo.clone();
+        let self_clone = self.clone();
+        let ids: Vec<TransactionId> = batch.iter().map(|withdrawal| withdrawal.id).collect();
+        let first = batch[0].clone();
+        let batch_tx_id = first.id;
+        let value = batch.iter().fold(Some(Amount::new(0)), |total, withdrawal| {
+            total.and_then(|total| total.checked_add(withdrawal.value))
+        });
+        let fee = batch.iter().fold(
+            Amount::new(0),
+            |fee, withdrawal| if withdrawal.fee > fee { withdrawal.fee } else { fee },
+        );
+        value
+            .ok_or(ectx!(err ErrorContext::BalanceOverflow, ErrorKind::Internal => batch))
+            .into_future()
+            .and_then(move |value| {
+                // the oldest withdrawal of the batch gives its id to the transaction
+                let input = CreateTransactionInput {
+                    id: first.id,
+                    user_id: first.user_id,
+                    from: first.account_id,
+                    to: Recepient::new(first.address.to_string()),
+                    to_type: RecepientType::Address,
+                    to_currency: first.currency,
+                    value,
+                    value_currency: first.currency,
+                    fee,
+                    exchange_id: None,
+                    exchange_rate: None,
+                    fee_payer: FeePayer::Sender,
+                    fee_payer_account_id: None,
+                    sweep: false,
+                    rate_lock_id: None,
+                    max_slippage: None,
+                };
+                let input_clone = input.clone();
+                db_executor
+                    .execute_transaction_with_retry(Isolation::Serializable, move || {
+                        classifier_service.validate_and_classify_transaction(&input_clone)
+                    })
+                    .and_then(move |tx_type| match tx_type {
+                        TransactionType::Withdrawal(from_account, address, currency) => {
+                            Either::A(self_clone.create_external_mono_currency_tx(
+                                input,
+                                from_account,
+                                address,
+                                currency,
+                                None,
+                                None,
+                                None,
+                                None,
+                                None,
+                            ))
+                        }
+                        tx_type => Either::B(future::err(
+                            ectx!(err ErrorContext::InvalidTransaction, ErrorKind::Internal => input, tx_type),
+                        )),
+                    })
+            })
+            .then(move |res| {
+                let (status, transaction_id, error_message) = match res {
+                    Ok(_) => (QueuedWithdrawalStatus::Done, Some(batch_tx_id), None),
+                    Err(e) => {
+                        log_error(&e);
+                        (QueuedWithdrawalStatus::Failed, None, Some(format!("{}", e)))
+                    }
+                };
+                db_executor_.execute(move || {
+                    queued_withdrawals_repo
+                        .finish(&ids, status, transaction_id, error_message.clone())
+                        .map_err(ectx!(convert => ids, status, transaction_id, error_message))
+                })
+            })
+    }
+
+    // Resolves withdrawals left in processing by a drain that was interrupted: the ones whose transaction
+    // got to ledger are done, the rest are queued again. Must be called in a db transaction
+    fn requeue_stale_withdrawals(&self) -> Result<Vec<QueuedWithdrawal>, Error> {
+        let timeout = ::chrono::Duration::seconds(self.config.withdrawal_queue.processing_timeout_secs as i64);
+        let updated_before = ::chrono::Utc::now().naive_utc() - timeout;
+        let stale = self
+            .queued_withdrawals_repo
+            .list_stale_processing(updated_before)
+            .map_err(ectx!(try convert => updated_before))?;
+        let mut res = Vec::new();
+        for withdrawal in stale {
+            let sent_tx_id = match withdrawal.transaction_id {
+                Some(tx_id) => {
+                    let txs = self.transactions_repo.get_by_gid(tx_id).map_err(ectx!(try convert => tx_id))?;
+                    if txs.is_empty() {
+                        None
+                    } else {
+                        Some(tx_id)
+                    }
+                }
+                None => None,
+            };
+            let status = match sent_tx_id {
+                Some(_) => QueuedWithdrawalStatus::Done,
+                None => QueuedWithdrawalStatus::Queued,
+            };
+            let id = withdrawal.id;
+            let mut finished = self
+                .queued_withdrawals_repo
+                .finish(&[id], status, sent_tx_id, None)
+                .map_err(ectx!(try convert => id, status, sent_tx_id))?;
+            res.append(&mut finished);
+        }
+        Ok(res)
+    }
+
+    fn claim_bounce(&self, hash: BlockchainTransactionId, to: Option<BlockchainAddress>) -> Result<Bounce, Error> {
+        let bounce = self.prepare_bounce(hash.clone(), to)?;
+        let hash_clone = hash.clone();
+        self.strange_blockchain_transactions_repo
+            .delete(hash.clone())
+            .map_err(ectx!(try convert => hash_clone))?
+            .ok_or(ectx!(try err ErrorContext::NoTransaction, ErrorKind::NotFound => hash))?;
+        Ok(bounce)
+    }
+
+    fn restore_strange_transaction(&self, strange_tx: StrangeBlockchainTransactionDB) -> Result<(), Error> {
+        let new_strange_tx = NewStrangeBlockchainTransactionDB {
+            hash: strange_tx.hash,
+            from_: strange_tx.from_,
+            to_: strange_tx.to_,
+            block_number: strange_tx.block_number,
+            currency: strange_tx.currency,
+            fee: strange_tx.fee,
+            confirmations: strange_tx.confirmations,
+            commentary: strange_tx.commentary,
+            erc20_operation_kind: strange_tx.erc20_operation_kind,
+            violation_kind: strange_tx.violation_kind,
+        };
+        let new_strange_tx_clone = new_strange_tx.clone();
+        self.strange_blockchain_transactions_repo
+            .create(new_strange_tx)
+            .map_err(ectx!(try convert => new_strange_tx_clone))?;
+        Ok(())
+    }
+
+    // Finds our account the strange transaction came to and the value that can be returned from it.
+    // Bounced funds were never credited to anyone, so btc and eth network fee is paid out of them,
+    // while eth fee of stq transfer is paid by system fees account, as for withdrawals
+    fn prepare_bounce(&self, hash: BlockchainTransactionId, to: Option<BlockchainAddress>) -> Result<Bounce, Error> {
+        let hash_clone = hash.clone();
+        let strange_tx = self
+            .strange_blockchain_transactions_repo
+            .get(hash.clone())
+            .map_err(ectx!(try convert => hash_clone))?
+            .ok_or(ectx!(try err ErrorContext::NoTransaction, ErrorKind::NotFound => hash))?;
+        let currency = strange_tx.currency;
+        // approvals and transfers from our addresses are our own operations, only a plain transfer
+        // of tokens to our address is a deposit that can be returned
+        if strange_tx.erc20_operation_kind.is_some() {
+            return Err(
+                ectx!(err ErrorContext::InvalidBlockchainTransactionStructure, invalid_input("hash", "erc20_operation", "only a transfer of tokens to our address can be returned") => strange_tx),
+            );
+        }
+        let entries: Vec<BlockchainTransactionEntryTo> = serde_json::from_value(strange_tx.to_.clone()).map_err({
+            let strange_tx = strange_tx.clone();
+            ectx!(try ErrorContext::Json, ErrorKind::Internal => strange_tx)
+        })?;
+        let senders: Vec<BlockchainAddress> = serde_json::from_value(strange_tx.from_.clone()).map_err({
+            let strange_tx = strange_tx.clone();
+            ectx!(try ErrorContext::Json, ErrorKind::Internal => strange_tx)
+        })?;
+
+        let to_addresses: Vec<_> = entries.iter().map(|entry| entry.address.clone()).collect();
+        let to_addresses_clone = to_addresses.clone();
+        let accounts = self
+            .accounts_repo
+            .get_by_addresses(&to_addresses, currency, AccountKind::Dr, ArchivedAccounts::Include)
+            .map_err(ectx!(try convert => to_addresses_clone, currency))?;
+        if accounts.len() != 1 {
+            return Err(
+                ectx!(err ErrorContext::InvalidBlockchainTransactionStructure, invalid_input("hash", "no_single_account", "transaction must be sent to exactly one of our addresses") => strange_tx, accounts),
+            );
+        }
+        let account = accounts[0].clone();
+        if currency == Currency::Stq && !account.erc20_approved {
+            return Err(
+                ectx!(err ErrorContext::InvalidBlockchainTransactionStructure, invalid_input("hash", "not_approved", "tokens can't be sent from the address until it is approved") => account),
+            );
+        }
+        let mut received = Amount::new(0);
+        for entry in entries.iter().filter(|entry| entry.address == account.address) {
+            received = received
+                .checked_add(entry.value)
+                .ok_or(ectx!(try err ErrorContext::BalanceOverflow, ErrorKind::Internal => received, entry.value))?;
+        }
+
+        let to = match to {
+            Some(to) => to,
+            None if senders.len() == 1 => senders[0].clone(),
+            None => {
+                return Err(
+                    ectx!(err ErrorContext::InvalidBlockchainTransactionStructure, invalid_input("to", "ambiguous_sender", "transaction has several senders, address to return funds to is required") => senders),
+                );
+            }
+        };
+        for kind in &[AccountKind::Dr, AccountKind::Cr] {
+            let to_clone = to.clone();
+            let internal = self
+                .accounts_repo
+                .get_by_address(to.clone(), currency, *kind, ArchivedAccounts::Include)
+                .map_err(ectx!(try convert => to_clone, currency, kind))?;
+            if internal.is_some() {
+                return Err(
+                    ectx!(err ErrorContext::InvalidTransaction, invalid_input("to", "internal_address", "funds can't be returned to our own address") => to),
+                );
+            }
+        }
+
+        let (fee, fee_price) = match currency {
+            Currency::Btc => {
+                let fee_price = self.config.fee_price.bitcoin;
+                (fee_price * self.config.fees_options.btc_transaction_size as f64, fee_price)
+            }
+            Currency::Eth => {
+                let fee_price = self.config.fee_price.ethereum;
+                (fee_price * self.config.fees_options.eth_gas_limit as f64, fee_price)
+            }
+            Currency::Stq => (0.0, self.config.fee_price.ethereum),
+        };
+        let fee = Amount::new(fee.ceil() as u128);
+        let value = match received.checked_sub(fee) {
+            Some(value) if value > Amount::new(0) => value,
+            _ => {
+                return Err(
+                    ectx!(err ErrorContext::NotEnoughFunds, invalid_input("hash", "not_enough_funds", "transaction value doesn't cover network fee") => received, fee),
+                );
+            }
+        };
+        Ok(Bounce {
+            strange_tx,
+            account,
+            hash,
+            to,
+            value,
+            fee_price,
+        })
+    }
+
+    // Bounce is recorded with the same account on both sides: the funds were never credited,
+    // so it only tracks that they left our address and doesn't change balances
+    fn record_bounce(&self, bounce: Bounce, blockchain_tx_id: BlockchainTransactionId) -> Result<TransactionOut, Error> {
+        let Bounce {
+            account, hash, to, value, ..
+        } = bounce;
+        let id = TransactionId::generate();
+        let new_tx = NewTransaction {
+            id,
+            gid: id,
+            user_id: account.user_id,
+            dr_account_id: account.id,
+            cr_account_id: account.id,
+            currency: account.currency,
+            value,
+            status: TransactionStatus::Pending,
+            blockchain_tx_id: Some(blockchain_tx_id),
+            kind: TransactionKind::Bounce,
+            group_kind: TransactionGroupKind::Bounce,
+            related_tx: None,
+            meta: Some(json!({
+                "bouncedTx": hash,
+                "to": to,
+            })),
+        };
+        let new_tx = self.rates_service.with_usd_rate(new_tx)?;
+        let new_tx_clone = new_tx.clone();
+        let tx = self.transactions_repo.create(new_tx).map_err(ectx!(try convert => new_tx_clone))?;
+        self.converter_service.convert_transaction(vec![tx])
+    }
+
+    // Transaction groups of account in `sort` order, offset and limit are in groups
+    fn list_account_transactions(
+        &self,
+        account_id: AccountId,
+        offset: i64,
+        limit: i64,
+        sort: TransactionsSort,
+    ) -> Result<Vec<TransactionOut>, Error> {
+        let txs = self
+            .transactions_repo
+            .list_groups_for_account_skip_approval(account_id, offset, limit, sort)
+            .map_err(ectx!(try convert => account_id, sort))?;
+        group_transactions(&txs)
+            .into_iter()
+            .map(|tx_group| self.converter_service.convert_transaction(tx_group))
+            .collect()
+    }
+}
+
+/// Batches of queued withdrawals that can be sent in one blockchain transaction - from the same
+/// account to the same address. Batches and withdrawals in them keep the order of the queue
+fn group_queued_withdrawals(withdrawals: Vec<QueuedWithdrawal>) -> Vec<Vec<QueuedWithdrawal>> {
+    let mut batches: Vec<Vec<QueuedWithdrawal>> = Vec::new();
+    for withdrawal in withdrawals {
+        let position = batches
+            .iter()
+            .position(|batch| batch[0].account_id == withdrawal.account_id && batch[0].address == withdrawal.address);
+        match position {
+            Some(position) => batches[position].push(withdrawal),
+            None => batches.push(vec![withdrawal]),
+        }
+    }
+    batches
+}
+
+/// Value of withdrawal with exchange in the currency it's sent to blockchain
+fn get_exchanged_value(
+    input: &CreateTransactionInput,
+    from_currency: Currency,
+    to_currency: Currency,
+    exchange_rate: f64,
+) -> Result<Amount, Error> {
+    if input.value_currency == to_currency {
+        Ok(input.value)
+    } else if input.value_currency == from_currency {
+        Ok(input.value.convert(from_currency, to_currency, exchange_rate))
+    } else {
+        Err(ectx!(err ErrorContext::InvalidCurrency, ErrorKind::MalformedInput => input))
+    }
+}
+
+/// Checks that exchange rate provided by client deviates from the current one by no more than `max_deviation` fraction
+fn check_exchange_rate(rate: f64, current_rate: f64, max_deviation: f64) -> Result<(), Error> {
+    if !current_rate.is_finite() || current_rate <= 0.0 {
+        return Err(ectx!(err ErrorContext::ExchangeRateUnavailable, ErrorKind::Internal => rate, current_rate));
+    }
+    let deviation = (rate - current_rate).abs() / current_rate;
+    if deviation <= max_deviation {
+        Ok(())
+    } else {
+        let mut errors = ValidationErrors::new();
+        let mut error = ValidationError::new("rate_out_of_bounds");
+        error.message = Some("exchange rate deviates too much from the current one".into());
+        error.add_param("current_rate".into(), &current_rate);
+        error.add_param("max_deviation".into(), &max_deviation);
+        errors.add("exchange_rate", error);
+        Err(
+            ectx!(err ErrorContext::ExchangeRateOutOfBounds, ErrorKind::InvalidInput(serde_json::to_string(&errors).unwrap_or_default()) => rate, current_rate, max_deviation),
+        )
+    }
+}
+
+/// Checks that the current rate moved from the one client agreed to by no more than `max_slippage` fraction.
+/// `current_rate` is expected to be checked by `check_exchange_rate` already
+fn check_slippage(rate: f64, current_rate: f64, max_slippage: f64) -> Result<(), Error> {
+    let slippage = (current_rate - rate).abs() / rate;
+    if slippage <= max_slippage {
+        Ok(())
+    } else {
+        let mut errors = ValidationErrors::new();
+        let mut error = ValidationError::new("slippage_exceeded");
+        error.message = Some("exchange rate moved beyond the given slippage tolerance".into());
+        error.add_param("current_rate".into(), &current_rate);
+        error.add_param("max_slippage".into(), &max_slippage);
+        errors.add("max_slippage", error);
+        Err(
+            ectx!(err ErrorContext::SlippageExceeded, ErrorKind::InvalidInput(serde_json::to_string(&errors).unwrap_or_default()) => rate, current_rate, max_slippage),
+        )
+    }
+}
+
+/// Transaction failed before exchange gateway made its exchange, see `ErrorContext::ExchangeNotPerformed`
+fn is_exchange_not_performed(e: &Error) -> bool {
+    let e: &Fail = e;
+    e.iter_chain().any(|cause| {
+        let context = cause
+            .downcast_ref::<Context<ErrorContext>>()
+            .map(|ctx| *ctx.get_context())
+            .or_else(|| cause.downcast_ref::<ErrorContext>().cloned());
+        context == Some(ErrorContext::ExchangeNotPerformed)
+    })
+}
+
+// group transactions into subgroups of related txs. I.e. group tx itself + fee.
+// Groups are in the order of their first transactions in `transactions`
+pub fn group_transactions(transactions: &[Transaction]) -> Vec<Vec<Transaction>> {
+    let mut positions: HashMap<TransactionId, usize> = HashMap::new();
+    let mut res: Vec<Vec<Transaction>> = Vec::new();
+    for tx in transactions.into_iter() {
+        match positions.get(&tx.gid).cloned() {
+            Some(position) => res[position].push(tx.clone()),
+            None => {
+                positions.insert(tx.gid, res.len());
+                res.push(vec![tx.clone()]);
+            }
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+#[allow(unused)]
+mod tests {
+    use super::*;
+    use client::*;
+    use config::{Config, ExchangeSpread};
+    use rabbit::*;
+    use repos::*;
+    use services::*;
+    use tokio_core::reactor::Core;
+
+    fn create_transaction_service(
+        token: AuthenticationToken,
+        user_id: UserId,
+        accounts_repo: Arc<AccountsRepoMock>,
+        strange_blockchain_transactions_repo: Arc<StrangeBlockchainTransactionsRepoMock>,
+    ) -> TransactionsServiceImpl<DbExecutorMock> {
+        create_exchanging_transaction_service(
+            token,
+            user_id,
+            accounts_repo,
+            strange_blockchain_transactions_repo,
+            Arc::new(ExchangeClientMock::default()),
+        )
+    }
+
+    fn create_exchanging_transaction_service(
+        token: AuthenticationToken,
+        user_id: UserId,
+        accounts_repo: Arc<AccountsRepoMock>,
+        strange_blockchain_transactions_repo: Arc<StrangeBlockchainTransactionsRepoMock>,
+        exchange_client: Arc<ExchangeClientMock>,
+    ) -> TransactionsServiceImpl<DbExecutorMock> {
+        let config = Config::new().unwrap();
+        let auth_service = Arc::new(AuthServiceMock::new(vec![(token, user_id)]));
+        let transactions_repo = Arc::new(TransactionsRepoMock::default());
+        let pending_transactions_repo = Arc::new(PendingBlockchainTransactionsRepoMock::default());
+        let blockchain_transactions_repo = Arc::new(BlockchainTransactionsRepoMock::default());
+        let key_values_repo = Arc::new(KeyValuesRepoMock::default());
+        let keys_client = Arc::new(KeysClientMock::default());
+        let blockchain_client = Arc::new(BlockchainClientMock::default());
+        let db_executor = DbExecutorMock::default();
+        let publisher = Arc::new(TransactionPublisherMock::default());
+        TransactionsServiceImpl::new(
+            config,
+            auth_service,
+            transactions_repo,
+            pending_transactions_repo,
+            blockchain_transactions_repo,
+            strange_blockchain_transactions_repo,
+            Arc::new(QueuedWithdrawalsRepoMock::default()),
+            Arc::new(RateLocksRepoMock::default()),
+            Arc::new(UsersRepoMock::default()),
+            Arc::new(WithdrawalAddressesRepoMock::default()),
+            accounts_repo,
+            key_values_repo,
             db_executor,
             keys_client,
             blockchain_client,
@@ -742,4 +2293,1605 @@ mod tests {
             publisher,
         )
     }
+
+    #[test]
+    fn test_check_exchange_rate() {
+        assert!(check_exchange_rate(100.0, 100.0, 0.05).is_ok());
+        assert!(check_exchange_rate(104.9, 100.0, 0.05).is_ok());
+        assert!(check_exchange_rate(95.1, 100.0, 0.05).is_ok());
+        assert!(check_exchange_rate(105.1, 100.0, 0.05).is_err());
+        assert!(check_exchange_rate(1000.0, 100.0, 0.05).is_err());
+        assert!(check_exchange_rate(0.0, 100.0, 0.05).is_err());
+        // rates of unknown pair are never trusted
+        assert!(check_exchange_rate(0.0, 0.0, 0.05).is_err());
+    }
+
+    #[test]
+    fn test_check_slippage() {
+        assert!(check_slippage(100.0, 100.0, 0.0).is_ok());
+        assert!(check_slippage(100.0, 100.9, 0.01).is_ok());
+        assert!(check_slippage(100.0, 99.1, 0.01).is_ok());
+        assert!(check_slippage(100.0, 101.1, 0.01).is_err());
+        assert!(check_slippage(100.0, 98.9, 0.01).is_err());
+    }
+
+    #[test]
+    fn test_get_exchanged_value() {
+        let input = CreateTransactionInput {
+            id: TransactionId::generate(),
+            user_id: UserId::generate(),
+            from: AccountId::generate(),
+            to: Recepient::new("5c3a228510d246b78a3765c20221cbf3082b44a4".to_string()),
+            to_type: RecepientType::Address,
+            to_currency: Currency::Eth,
+            value: Amount::new(1_000_000_000_000_000_000),
+            value_currency: Currency::Eth,
+            fee: Amount::new(10),
+            exchange_id: Some(ExchangeId::generate()),
+            exchange_rate: Some(0.5),
+            fee_payer: FeePayer::Sender,
+            fee_payer_account_id: None,
+            sweep: false,
+            rate_lock_id: None,
+            max_slippage: None,
+        };
+        let value = get_exchanged_value(&input, Currency::Stq, Currency::Eth, 0.5).unwrap();
+        assert_eq!(value, Amount::new(1_000_000_000_000_000_000));
+        let input = CreateTransactionInput {
+            value_currency: Currency::Stq,
+            ..input
+        };
+        let value = get_exchanged_value(&input, Currency::Stq, Currency::Eth, 0.5).unwrap();
+        assert_eq!(value, Amount::new(500_000_000_000_000_000));
+        let input = CreateTransactionInput {
+            value_currency: Currency::Btc,
+            ..input
+        };
+        assert!(get_exchanged_value(&input, Currency::Stq, Currency::Eth, 0.5).is_err());
+    }
+
+    #[test]
+    fn test_withdrawal_exchange_account() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let service = create_transaction_service(
+            token,
+            user_id,
+            accounts_repo.clone(),
+            Arc::new(StrangeBlockchainTransactionsRepoMock::default()),
+        );
+        let stq_account = accounts_repo
+            .create(NewAccount {
+                user_id,
+                currency: Currency::Stq,
+                ..Default::default()
+            })
+            .unwrap();
+        assert!(service.get_exchange_account(user_id, Currency::Eth).is_err());
+
+        let archived = accounts_repo
+            .create(NewAccount {
+                user_id,
+                currency: Currency::Eth,
+                ..Default::default()
+            })
+            .unwrap();
+        accounts_repo.archive(archived.id).unwrap();
+        accounts_repo
+            .create(NewAccount {
+                currency: Currency::Eth,
+                ..Default::default()
+            })
+            .unwrap();
+        assert!(service.get_exchange_account(user_id, Currency::Eth).is_err());
+
+        let eth_account = accounts_repo
+            .create(NewAccount {
+                user_id,
+                currency: Currency::Eth,
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(service.get_exchange_account(user_id, Currency::Eth).unwrap().id, eth_account.id);
+
+        // exchanged value is not known before the exchange, so the balance can't be swept
+        let input = CreateTransactionInput {
+            id: TransactionId::generate(),
+            user_id,
+            from: stq_account.id,
+            to: Recepient::new("5c3a228510d246b78a3765c20221cbf3082b44a4".to_string()),
+            to_type: RecepientType::Address,
+            to_currency: Currency::Eth,
+            value: Amount::new(1000),
+            value_currency: Currency::Stq,
+            fee: Amount::new(10),
+            exchange_id: Some(ExchangeId::generate()),
+            exchange_rate: Some(0.5),
+            fee_payer: FeePayer::Sender,
+            fee_payer_account_id: None,
+            sweep: true,
+            rate_lock_id: None,
+            max_slippage: None,
+        };
+        let address = BlockchainAddress::new("5c3a228510d246b78a3765c20221cbf3082b44a4".to_string());
+        let res =
+            core.run(service.create_external_multi_currency_tx(input, stq_account, address, Currency::Eth, ExchangeId::generate(), 0.5));
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_auto_convert_deposit_without_target() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let service = create_transaction_service(
+            token,
+            user_id,
+            accounts_repo.clone(),
+            Arc::new(StrangeBlockchainTransactionsRepoMock::default()),
+        );
+        let account = accounts_repo
+            .create(NewAccount {
+                user_id,
+                currency: Currency::Stq,
+                ..Default::default()
+            })
+            .unwrap();
+        let mut deposit = Transaction::default();
+        deposit.cr_account_id = account.id;
+        deposit.currency = Currency::Stq;
+        deposit.kind = TransactionKind::Deposit;
+        let converted = core.run(service.auto_convert_deposit(deposit)).unwrap();
+        assert!(converted.is_none());
+    }
+
+    /// Funded liquidity accounts and a Stq account of `user_id` auto converted to a new Eth one
+    fn create_auto_convert_accounts(
+        service: &TransactionsServiceImpl<DbExecutorMock>,
+        accounts_repo: &AccountsRepoMock,
+        user_id: UserId,
+    ) -> (Account, Account) {
+        let mut liquidity_accounts = HashMap::new();
+        for currency in &[Currency::Stq, Currency::Eth] {
+            let account = accounts_repo
+                .create(NewAccount {
+                    currency: *currency,
+                    kind: AccountKind::Cr,
+                    ..Default::default()
+                })
+                .unwrap();
+            accounts_repo
+                .set_system_role(account.id, Some(SystemAccountKind::Liquidity))
+                .unwrap();
+            liquidity_accounts.insert(*currency, account);
+        }
+        service
+            .transactions_repo
+            .create(NewTransaction {
+                cr_account_id: liquidity_accounts[&Currency::Eth].id,
+                currency: Currency::Eth,
+                value: Amount::new(1_000_000_000_000_000_000),
+                ..Default::default()
+            })
+            .unwrap();
+        let to_account = accounts_repo
+            .create(NewAccount {
+                user_id,
+                currency: Currency::Eth,
+                kind: AccountKind::Cr,
+                ..Default::default()
+            })
+            .unwrap();
+        let account = accounts_repo
+            .create(NewAccount {
+                user_id,
+                currency: Currency::Stq,
+                kind: AccountKind::Cr,
+                ..Default::default()
+            })
+            .unwrap();
+        accounts_repo
+            .update(
+                account.id,
+                UpdateAccount {
+                    auto_convert_to: Some(Some(to_account.id)),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        (account, to_account)
+    }
+
+    #[test]
+    fn test_auto_convert_deposit() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let exchange_client = Arc::new(ExchangeClientMock::default());
+        *exchange_client.rate.lock().unwrap() = 0.001;
+        let service = create_exchanging_transaction_service(
+            token,
+            user_id,
+            accounts_repo.clone(),
+            Arc::new(StrangeBlockchainTransactionsRepoMock::default()),
+            exchange_client.clone(),
+        );
+        let (account, to_account) = create_auto_convert_accounts(&service, &accounts_repo, user_id);
+        let value = Amount::new(1_000_000_000_000_000_000);
+        let deposit = service
+            .transactions_repo
+            .create(NewTransaction {
+                user_id,
+                cr_account_id: account.id,
+                currency: Currency::Stq,
+                value,
+                kind: TransactionKind::Deposit,
+                group_kind: TransactionGroupKind::Deposit,
+                ..Default::default()
+            })
+            .unwrap();
+
+        let converted = core.run(service.auto_convert_deposit(deposit.clone())).unwrap().unwrap();
+        assert_eq!(converted.related_tx, Some(deposit.id));
+        assert_eq!(converted.from_currency, Currency::Stq);
+        assert_eq!(converted.from_value, value);
+        assert_eq!(converted.to_currency, Currency::Eth);
+        assert_eq!(exchange_client.exchanges.lock().unwrap().len(), 1);
+        // the whole deposit is converted
+        let balance = service.transactions_repo.get_account_balance(account.id, AccountKind::Cr).unwrap();
+        assert_eq!(balance, Amount::new(0));
+        let balance = service
+            .transactions_repo
+            .get_account_balance(to_account.id, AccountKind::Cr)
+            .unwrap();
+        assert_eq!(balance, converted.to_value);
+        assert!(balance > Amount::new(0));
+    }
+
+    #[test]
+    fn test_auto_convert_deposit_paused_pair() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let exchange_client = Arc::new(ExchangeClientMock::default());
+        let mut service = create_exchanging_transaction_service(
+            token,
+            user_id,
+            accounts_repo.clone(),
+            Arc::new(StrangeBlockchainTransactionsRepoMock::default()),
+            exchange_client.clone(),
+        );
+        let mut config = Config::new().unwrap();
+        config
+            .exchange_options
+            .allowed_pairs
+            .retain(|pair| pair.from != Currency::Stq || pair.to != Currency::Eth);
+        service.classifier_service = Arc::new(ClassifierServiceImpl::new(
+            &config,
+            accounts_repo.clone(),
+            service.transactions_repo.clone(),
+            Arc::new(UsersRepoMock::default()),
+            Arc::new(WithdrawalAddressesRepoMock::default()),
+            Arc::new(KeyValuesRepoMock::default()),
+        ));
+        let (account, _) = create_auto_convert_accounts(&service, &accounts_repo, user_id);
+        let value = Amount::new(1_000_000_000_000_000_000);
+        let deposit = service
+            .transactions_repo
+            .create(NewTransaction {
+                user_id,
+                cr_account_id: account.id,
+                currency: Currency::Stq,
+                value,
+                kind: TransactionKind::Deposit,
+                group_kind: TransactionGroupKind::Deposit,
+                ..Default::default()
+            })
+            .unwrap();
+
+        // deposit stays where it came
+        let converted = core.run(service.auto_convert_deposit(deposit)).unwrap();
+        assert!(converted.is_none());
+        assert!(exchange_client.exchanges.lock().unwrap().is_empty());
+        let balance = service.transactions_repo.get_account_balance(account.id, AccountKind::Cr).unwrap();
+        assert_eq!(balance, value);
+    }
+
+    #[test]
+    fn test_exchange_with_spread_keeps_margin() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let exchange_client = Arc::new(ExchangeClientMock::default());
+        *exchange_client.rate.lock().unwrap() = 0.001;
+        let service = create_exchanging_transaction_service(
+            token,
+            user_id,
+            accounts_repo.clone(),
+            Arc::new(StrangeBlockchainTransactionsRepoMock::default()),
+            exchange_client.clone(),
+        );
+        let mut config = (*service.config).clone();
+        config.exchange_options.spreads = vec![ExchangeSpread {
+            from: Currency::Stq,
+            to: Currency::Eth,
+            spread: 0.1,
+        }];
+        let service = TransactionsServiceImpl {
+            config: Arc::new(config),
+            ..service
+        };
+        let (account, to_account) = create_auto_convert_accounts(&service, &accounts_repo, user_id);
+        let value = Amount::new(1_000_000_000_000_000_000);
+        let deposit = service
+            .transactions_repo
+            .create(NewTransaction {
+                user_id,
+                cr_account_id: account.id,
+                currency: Currency::Stq,
+                value,
+                kind: TransactionKind::Deposit,
+                group_kind: TransactionGroupKind::Deposit,
+                ..Default::default()
+            })
+            .unwrap();
+
+        let converted = core.run(service.auto_convert_deposit(deposit)).unwrap().unwrap();
+        // user gets the rate less the spread, gateway is asked for its own rate
+        let exchanges = exchange_client.exchanges.lock().unwrap().clone();
+        assert_eq!(exchanges.len(), 1);
+        assert!((exchanges[0].rate - 0.001).abs() < 1e-12);
+        let user_value = converted.to_value.raw() as f64;
+        assert!((user_value - 0.0009e18).abs() < 1e6);
+        let balance = service
+            .transactions_repo
+            .get_account_balance(to_account.id, AccountKind::Cr)
+            .unwrap();
+        assert_eq!(balance, converted.to_value);
+        // the difference is recorded on the crediting side of exchange
+        let multi_to = service.transactions_repo.get(converted.id.next()).unwrap().unwrap();
+        let margin = multi_to.meta[EXCHANGE_MARGIN_META_KEY].as_u64().unwrap() as f64;
+        assert!((margin - 0.0001e18).abs() < 1e6);
+    }
+
+    // Stq account of the user with 1000 on it and Eth account to send from after the exchange,
+    // liquidity account of Eth has just enough to exchange 100
+    fn create_withdrawal_exchange_accounts(
+        service: &TransactionsServiceImpl<DbExecutorMock>,
+        accounts_repo: &AccountsRepoMock,
+        user_id: UserId,
+    ) -> (Account, Account) {
+        for (currency, role) in &[
+            (Currency::Stq, SystemAccountKind::Liquidity),
+            (Currency::Eth, SystemAccountKind::Liquidity),
+            (Currency::Eth, SystemAccountKind::Fees),
+        ] {
+            let account = accounts_repo
+                .create(NewAccount {
+                    currency: *currency,
+                    ..Default::default()
+                })
+                .unwrap();
+            accounts_repo.set_system_role(account.id, Some(*role)).unwrap();
+            if *currency == Currency::Eth && *role == SystemAccountKind::Liquidity {
+                service
+                    .transactions_repo
+                    .create(NewTransaction {
+                        cr_account_id: account.id,
+                        currency: Currency::Eth,
+                        value: Amount::new(100),
+                        ..Default::default()
+                    })
+                    .unwrap();
+            }
+        }
+        let account = accounts_repo
+            .create(NewAccount {
+                user_id,
+                currency: Currency::Stq,
+                ..Default::default()
+            })
+            .unwrap();
+        service
+            .transactions_repo
+            .create(NewTransaction {
+                cr_account_id: account.id,
+                currency: Currency::Stq,
+                value: Amount::new(1000),
+                ..Default::default()
+            })
+            .unwrap();
+        let exchange_account = accounts_repo
+            .create(NewAccount {
+                user_id,
+                currency: Currency::Eth,
+                ..Default::default()
+            })
+            .unwrap();
+        (account, exchange_account)
+    }
+
+    fn withdrawal_exchange_input(user_id: UserId, from: AccountId, exchange_id: ExchangeId) -> CreateTransactionInput {
+        CreateTransactionInput {
+            id: TransactionId::generate(),
+            user_id,
+            from,
+            to: Recepient::new("5c3a228510d246b78a3765c20221cbf3082b44a4".to_string()),
+            to_type: RecepientType::Address,
+            to_currency: Currency::Eth,
+            value: Amount::new(100),
+            value_currency: Currency::Eth,
+            fee: Amount::new(0),
+            exchange_id: Some(exchange_id),
+            exchange_rate: Some(1.0),
+            fee_payer: FeePayer::Sender,
+            fee_payer_account_id: None,
+            sweep: false,
+            rate_lock_id: None,
+            max_slippage: None,
+        }
+    }
+
+    #[test]
+    fn test_withdrawal_exchange() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let exchange_client = Arc::new(ExchangeClientMock::default());
+        *exchange_client.rate.lock().unwrap() = 1.0;
+        let service = create_exchanging_transaction_service(
+            token,
+            user_id,
+            accounts_repo.clone(),
+            Arc::new(StrangeBlockchainTransactionsRepoMock::default()),
+            exchange_client.clone(),
+        );
+        let (account, exchange_account) = create_withdrawal_exchange_accounts(&service, &accounts_repo, user_id);
+        // withdrawal account, the only one that can send 100, gets 199 on it
+        let withdrawal_account_id = AccountId::generate();
+        let mut new_account = NewAccount::default();
+        new_account.id = withdrawal_account_id;
+        new_account.currency = Currency::Eth;
+        new_account.kind = AccountKind::Dr;
+        accounts_repo.create(new_account).unwrap();
+        service
+            .transactions_repo
+            .create(NewTransaction {
+                cr_account_id: withdrawal_account_id,
+                currency: Currency::Eth,
+                value: Amount::new(101),
+                ..Default::default()
+            })
+            .unwrap();
+        for _ in 0..3 {
+            service
+                .transactions_repo
+                .create(NewTransaction {
+                    dr_account_id: withdrawal_account_id,
+                    currency: Currency::Eth,
+                    value: Amount::new(100),
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+        let exchange_id = ExchangeId::generate();
+        let input = withdrawal_exchange_input(user_id, account.id, exchange_id);
+        let address = input.to.to_account_address();
+
+        let txs = core
+            .run(service.create_external_multi_currency_tx(
+                input.clone(),
+                account.clone(),
+                address.clone(),
+                Currency::Eth,
+                exchange_id,
+                1.0,
+            ))
+            .unwrap();
+        assert_eq!(exchange_client.exchanges.lock().unwrap().len(), 1);
+        assert!(txs.iter().all(|tx| tx.gid == input.id));
+        assert!(txs.iter().any(|tx| tx.kind == TransactionKind::Withdrawal));
+        let tx_out = service.converter_service.convert_transaction(txs).unwrap();
+        assert_eq!(tx_out.group_kind, TransactionGroupKind::WithdrawalMulti);
+        assert_eq!(tx_out.to.blockchain_address, address);
+        // exchanged funds are sent right away
+        assert_eq!(
+            service.transactions_repo.get_account_balance(account.id, AccountKind::Cr).unwrap(),
+            Amount::new(900)
+        );
+        assert_eq!(
+            service
+                .transactions_repo
+                .get_account_balance(exchange_account.id, AccountKind::Cr)
+                .unwrap(),
+            Amount::new(0)
+        );
+        // the next withdrawals are sent from other hot accounts first
+        let withdrawal_account = accounts_repo.get(withdrawal_account_id).unwrap().unwrap();
+        assert!(withdrawal_account.last_spent_at.is_some());
+        // charged fee is kept to be compared with network fees, that are accrued once the withdrawal is confirmed
+        let group_fee = service.transactions_repo.get_group_fee(input.id).unwrap().unwrap();
+        assert_eq!(group_fee.fee, input.fee);
+        assert_eq!(group_fee.blockchain_fee_currency, Currency::Eth);
+        assert_eq!(group_fee.blockchain_fee, Amount::new(0));
+    }
+
+    #[test]
+    fn test_get_group_fee() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let service = create_transaction_service(
+            token.clone(),
+            user_id,
+            Arc::new(AccountsRepoMock::default()),
+            Arc::new(StrangeBlockchainTransactionsRepoMock::default()),
+        );
+        assert!(core.run(service.get_group_fee(token, TransactionId::generate())).is_err());
+
+        let token = AuthenticationToken::default();
+        let system_user_id = Config::new().unwrap().system.system_user_id;
+        let service = create_transaction_service(
+            token.clone(),
+            system_user_id,
+            Arc::new(AccountsRepoMock::default()),
+            Arc::new(StrangeBlockchainTransactionsRepoMock::default()),
+        );
+        let tx = service
+            .transactions_repo
+            .create(NewTransaction {
+                currency: Currency::Stq,
+                value: Amount::new(100),
+                ..Default::default()
+            })
+            .unwrap();
+        let group_fee = service
+            .transactions_repo
+            .create_group_fee(NewGroupFee {
+                gid: tx.gid,
+                fee_currency: Currency::Stq,
+                fee: Amount::new(10),
+                blockchain_fee_currency: Currency::Eth,
+                charged_blockchain_fee: Amount::new(200),
+                blockchain_fee: Amount::new(0),
+            })
+            .unwrap();
+        assert_eq!(core.run(service.get_group_fee(token.clone(), tx.id)).unwrap(), Some(group_fee));
+        assert_eq!(core.run(service.get_group_fee(token, TransactionId::generate())).unwrap(), None);
+    }
+
+    #[test]
+    fn test_withdrawal_exchange_kept_if_not_sent() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let exchange_client = Arc::new(ExchangeClientMock::default());
+        *exchange_client.rate.lock().unwrap() = 1.0;
+        let service = create_exchanging_transaction_service(
+            token,
+            user_id,
+            accounts_repo.clone(),
+            Arc::new(StrangeBlockchainTransactionsRepoMock::default()),
+            exchange_client.clone(),
+        );
+        // there's no withdrawal account to send from
+        let (account, exchange_account) = create_withdrawal_exchange_accounts(&service, &accounts_repo, user_id);
+        let exchange_id = ExchangeId::generate();
+        let input = withdrawal_exchange_input(user_id, account.id, exchange_id);
+        let address = input.to.to_account_address();
+
+        let res =
+            core.run(service.create_external_multi_currency_tx(input.clone(), account.clone(), address, Currency::Eth, exchange_id, 1.0));
+        assert!(res.is_err());
+        assert_eq!(exchange_client.exchanges.lock().unwrap().len(), 1);
+        // gateway keeps the exchange, so funds stay exchanged on the account to send from
+        assert_eq!(
+            service.transactions_repo.get_account_balance(account.id, AccountKind::Cr).unwrap(),
+            Amount::new(900)
+        );
+        assert_eq!(
+            service
+                .transactions_repo
+                .get_account_balance(exchange_account.id, AccountKind::Cr)
+                .unwrap(),
+            Amount::new(100)
+        );
+        let exchange_txs = service.transactions_repo.get_by_gid(input.id).unwrap();
+        assert_eq!(exchange_txs.len(), 2);
+        assert!(exchange_txs.iter().all(|tx| tx.group_kind == TransactionGroupKind::WithdrawalMulti));
+        let reversals = service
+            .transactions_repo
+            .list_for_account(account.id, 0, 100)
+            .unwrap()
+            .into_iter()
+            .filter(|tx| tx.group_kind == TransactionGroupKind::Reversal)
+            .count();
+        assert_eq!(reversals, 0);
+    }
+
+    #[test]
+    fn test_exchange_id_mismatch() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let exchange_client = Arc::new(ExchangeClientMock::default());
+        *exchange_client.rate.lock().unwrap() = 1.0;
+        // gateway replies about another exchange
+        *exchange_client.replied_id.lock().unwrap() = Some(ExchangeId::generate());
+        let service = create_exchanging_transaction_service(
+            token,
+            user_id,
+            accounts_repo.clone(),
+            Arc::new(StrangeBlockchainTransactionsRepoMock::default()),
+            exchange_client.clone(),
+        );
+        let (account, exchange_account) = create_withdrawal_exchange_accounts(&service, &accounts_repo, user_id);
+        let exchange_id = ExchangeId::generate();
+        let input = withdrawal_exchange_input(user_id, account.id, exchange_id);
+        let to_value = input.value;
+
+        let res = core.run(service.create_internal_multi_currency_tx(
+            input,
+            account.clone(),
+            exchange_account.clone(),
+            exchange_id,
+            1.0,
+            None,
+            None,
+        ));
+        assert!(match res.unwrap_err().kind() {
+            ErrorKind::Internal => true,
+            _ => false,
+        });
+        assert_eq!(exchange_client.exchanges.lock().unwrap().len(), 1);
+        // nothing is written to ledger
+        assert_eq!(
+            service.transactions_repo.get_account_balance(account.id, AccountKind::Cr).unwrap(),
+            Amount::new(1000)
+        );
+        assert_eq!(
+            service
+                .transactions_repo
+                .get_account_balance(exchange_account.id, AccountKind::Cr)
+                .unwrap(),
+            Amount::new(0)
+        );
+
+        // reply about the requested exchange is recorded
+        *exchange_client.replied_id.lock().unwrap() = None;
+        let exchange_id = ExchangeId::generate();
+        let input = withdrawal_exchange_input(user_id, account.id, exchange_id);
+        assert!(core
+            .run(service.create_internal_multi_currency_tx(input, account, exchange_account.clone(), exchange_id, 1.0, None, None))
+            .is_ok());
+        assert_eq!(
+            service
+                .transactions_repo
+                .get_account_balance(exchange_account.id, AccountKind::Cr)
+                .unwrap(),
+            to_value
+        );
+    }
+
+    #[test]
+    fn test_rate_lock_kept_if_withdrawal_fails_after_exchange() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let exchange_client = Arc::new(ExchangeClientMock::default());
+        *exchange_client.rate.lock().unwrap() = 1.0;
+        let service = create_exchanging_transaction_service(
+            token.clone(),
+            user_id,
+            accounts_repo.clone(),
+            Arc::new(StrangeBlockchainTransactionsRepoMock::default()),
+            exchange_client.clone(),
+        );
+        // there's no withdrawal account to send from
+        let (account, _) = create_withdrawal_exchange_accounts(&service, &accounts_repo, user_id);
+        let rate_lock = service
+            .rate_locks_repo
+            .create(NewRateLock {
+                user_id,
+                from_currency: Currency::Stq,
+                to_currency: Currency::Eth,
+                amount: Amount::new(100),
+                amount_currency: Currency::Eth,
+                expires_at: ::chrono::Utc::now().naive_utc() + ::chrono::Duration::seconds(60),
+                ..Default::default()
+            })
+            .unwrap();
+        let input = CreateTransactionInput {
+            exchange_id: None,
+            exchange_rate: None,
+            rate_lock_id: Some(rate_lock.id),
+            ..withdrawal_exchange_input(user_id, account.id, rate_lock.exchange_id)
+        };
+
+        assert!(core.run(service.create_transaction(token, input)).is_err());
+        assert_eq!(exchange_client.exchanges.lock().unwrap().len(), 1);
+        assert_eq!(exchange_client.exchanges.lock().unwrap()[0].id, rate_lock.exchange_id);
+        // gateway has made the exchange, so the quote can't be used again
+        let rate_lock = service.rate_locks_repo.get(rate_lock.id).unwrap().unwrap();
+        assert!(rate_lock.used_at.is_some());
+    }
+
+    #[test]
+    fn test_rate_lock_kept_if_exchange_is_not_recorded() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let exchange_client = Arc::new(ExchangeClientMock::default());
+        *exchange_client.rate.lock().unwrap() = 1.0;
+        let service = create_exchanging_transaction_service(
+            token.clone(),
+            user_id,
+            accounts_repo.clone(),
+            Arc::new(StrangeBlockchainTransactionsRepoMock::default()),
+            exchange_client.clone(),
+        );
+        let (account, _) = create_withdrawal_exchange_accounts(&service, &accounts_repo, user_id);
+        // eth liquidity account is drained, so the exchange made by gateway fails to be written to ledger
+        let liquidity_account = accounts_repo
+            .get_system_account(SystemAccountKind::Liquidity, Currency::Eth, AccountKind::Cr)
+            .unwrap()
+            .unwrap();
+        service
+            .transactions_repo
+            .create(NewTransaction {
+                dr_account_id: liquidity_account.id,
+                currency: Currency::Eth,
+                value: Amount::new(100),
+                ..Default::default()
+            })
+            .unwrap();
+        let rate_lock = service
+            .rate_locks_repo
+            .create(NewRateLock {
+                user_id,
+                from_currency: Currency::Stq,
+                to_currency: Currency::Eth,
+                amount: Amount::new(100),
+                amount_currency: Currency::Eth,
+                expires_at: ::chrono::Utc::now().naive_utc() + ::chrono::Duration::seconds(60),
+                ..Default::default()
+            })
+            .unwrap();
+        let input = CreateTransactionInput {
+            exchange_id: None,
+            exchange_rate: None,
+            rate_lock_id: Some(rate_lock.id),
+            ..withdrawal_exchange_input(user_id, account.id, rate_lock.exchange_id)
+        };
+
+        assert!(core.run(service.create_transaction(token, input)).is_err());
+        assert_eq!(exchange_client.exchanges.lock().unwrap().len(), 1);
+        let rate_lock = service.rate_locks_repo.get(rate_lock.id).unwrap().unwrap();
+        assert!(rate_lock.used_at.is_some());
+    }
+
+    #[test]
+    fn test_rate_lock_released_if_exchange_is_not_made() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let exchange_client = Arc::new(ExchangeClientMock::default());
+        let service = create_exchanging_transaction_service(
+            token.clone(),
+            user_id,
+            accounts_repo.clone(),
+            Arc::new(StrangeBlockchainTransactionsRepoMock::default()),
+            exchange_client.clone(),
+        );
+        let (account, _) = create_withdrawal_exchange_accounts(&service, &accounts_repo, user_id);
+        let rate_lock = service
+            .rate_locks_repo
+            .create(NewRateLock {
+                user_id,
+                from_currency: Currency::Stq,
+                to_currency: Currency::Eth,
+                amount: Amount::new(100),
+                amount_currency: Currency::Eth,
+                expires_at: ::chrono::Utc::now().naive_utc() + ::chrono::Duration::seconds(60),
+                ..Default::default()
+            })
+            .unwrap();
+        let input = CreateTransactionInput {
+            exchange_id: None,
+            exchange_rate: None,
+            rate_lock_id: Some(rate_lock.id),
+            ..withdrawal_exchange_input(user_id, account.id, rate_lock.exchange_id)
+        };
+        // the rate has moved too far from the locked one, so gateway isn't asked to exchange
+        *exchange_client.rate.lock().unwrap() = 2.0;
+
+        assert!(core.run(service.create_transaction(token, input)).is_err());
+        assert!(exchange_client.exchanges.lock().unwrap().is_empty());
+        // the lock can be used for another try
+        let rate_lock = service.rate_locks_repo.get(rate_lock.id).unwrap().unwrap();
+        assert_eq!(rate_lock.used_at, None);
+    }
+
+    #[test]
+    fn test_queue_and_cancel_withdrawal() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let service = create_transaction_service(
+            token.clone(),
+            user_id,
+            accounts_repo.clone(),
+            Arc::new(StrangeBlockchainTransactionsRepoMock::default()),
+        );
+        let account = accounts_repo
+            .create(NewAccount {
+                user_id,
+                currency: Currency::Eth,
+                ..Default::default()
+            })
+            .unwrap();
+        let input = CreateTransactionInput {
+            id: TransactionId::generate(),
+            user_id,
+            from: account.id,
+            to: Recepient::new("5c3a228510d246b78a3765c20221cbf3082b44a4".to_string()),
+            to_type: RecepientType::Address,
+            to_currency: Currency::Eth,
+            value: Amount::new(100),
+            value_currency: Currency::Eth,
+            fee: Amount::new(10),
+            exchange_id: None,
+            exchange_rate: None,
+            fee_payer: FeePayer::Sender,
+            fee_payer_account_id: None,
+            sweep: false,
+            rate_lock_id: None,
+            max_slippage: None,
+        };
+        let withdrawal = core.run(service.queue_withdrawal(token.clone(), input.clone())).unwrap();
+        assert_eq!(withdrawal.id, input.id);
+        assert_eq!(withdrawal.status, QueuedWithdrawalStatus::Queued);
+        let withdrawals = core
+            .run(service.get_queued_withdrawals_for_user(token.clone(), user_id, 0, 10))
+            .unwrap();
+        assert_eq!(withdrawals.len(), 1);
+
+        let cancelled = core.run(service.cancel_queued_withdrawal(token.clone(), input.id)).unwrap();
+        assert_eq!(cancelled.status, QueuedWithdrawalStatus::Cancelled);
+        assert!(core.run(service.cancel_queued_withdrawal(token.clone(), input.id)).is_err());
+        // cancelled withdrawal is not sent
+        assert!(core.run(service.drain_withdrawal_queue()).unwrap().is_empty());
+
+        // transfers between accounts are never queued
+        let to_account = accounts_repo
+            .create(NewAccount {
+                user_id,
+                currency: Currency::Eth,
+                address: BlockchainAddress::new("fb6916095ca1df60bb79ce92ce3ea74c37c5d359".to_string()),
+                ..Default::default()
+            })
+            .unwrap();
+        let input = CreateTransactionInput {
+            id: TransactionId::generate(),
+            to: Recepient::new(to_account.id.to_string()),
+            to_type: RecepientType::Account,
+            ..input
+        };
+        assert!(core.run(service.queue_withdrawal(token, input)).is_err());
+    }
+
+    #[test]
+    fn test_drain_withdrawal_queue() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let service = create_transaction_service(
+            token.clone(),
+            user_id,
+            accounts_repo.clone(),
+            Arc::new(StrangeBlockchainTransactionsRepoMock::default()),
+        );
+        let mut config = (*service.config).clone();
+        // every withdrawal left in processing is stale
+        config.withdrawal_queue.processing_timeout_secs = 0;
+        let service = TransactionsServiceImpl {
+            config: Arc::new(config),
+            ..service
+        };
+        let account = accounts_repo
+            .create(NewAccount {
+                user_id,
+                currency: Currency::Eth,
+                ..Default::default()
+            })
+            .unwrap();
+        let withdrawal_input = |to: &str| CreateTransactionInput {
+            id: TransactionId::generate(),
+            user_id,
+            from: account.id,
+            to: Recepient::new(to.to_string()),
+            to_type: RecepientType::Address,
+            to_currency: Currency::Eth,
+            value: Amount::new(100),
+            value_currency: Currency::Eth,
+            fee: Amount::new(10),
+            exchange_id: None,
+            exchange_rate: None,
+            fee_payer: FeePayer::Sender,
+            fee_payer_account_id: None,
+            sweep: false,
+            rate_lock_id: None,
+            max_slippage: None,
+        };
+        let queued = core
+            .run(service.queue_withdrawal(token.clone(), withdrawal_input("5c3a228510d246b78a3765c20221cbf3082b44a4")))
+            .unwrap();
+        // taken by a drain that was interrupted before sending
+        let interrupted = core
+            .run(service.queue_withdrawal(token.clone(), withdrawal_input("fb6916095ca1df60bb79ce92ce3ea74c37c5d359")))
+            .unwrap();
+        // taken by a drain that was interrupted after its transaction got to ledger
+        let sent = core
+            .run(service.queue_withdrawal(token.clone(), withdrawal_input("2a8ff6d8a5d2d9a1a54b9a3db2a3a8a3c2e0d6f1")))
+            .unwrap();
+        service
+            .queued_withdrawals_repo
+            .finish(&[interrupted.id], QueuedWithdrawalStatus::Processing, Some(interrupted.id), None)
+            .unwrap();
+        service
+            .queued_withdrawals_repo
+            .finish(&[sent.id], QueuedWithdrawalStatus::Processing, Some(sent.id), None)
+            .unwrap();
+        service
+            .transactions_repo
+            .create(NewTransaction {
+                id: sent.id,
+                gid: sent.id,
+                user_id,
+                dr_account_id: account.id,
+                ..Default::default()
+            })
+            .unwrap();
+
+        let drained = core.run(service.drain_withdrawal_queue()).unwrap();
+        // account has no funds, so both sends fail, but nothing is left in processing
+        let mut drained_ids: Vec<TransactionId> = drained.iter().map(|withdrawal| withdrawal.id).collect();
+        drained_ids.sort_by_key(|id| id.to_string());
+        let mut expected_ids = vec![queued.id, interrupted.id];
+        expected_ids.sort_by_key(|id| id.to_string());
+        assert_eq!(drained_ids, expected_ids);
+        for id in &[queued.id, interrupted.id] {
+            let withdrawal = service.queued_withdrawals_repo.get(*id).unwrap().unwrap();
+            assert_eq!(withdrawal.status, QueuedWithdrawalStatus::Failed);
+            assert!(withdrawal.error_message.is_some());
+        }
+        let withdrawal = service.queued_withdrawals_repo.get(sent.id).unwrap().unwrap();
+        assert_eq!(withdrawal.status, QueuedWithdrawalStatus::Done);
+        assert_eq!(withdrawal.transaction_id, Some(sent.id));
+        // nothing is sent twice
+        assert!(core.run(service.drain_withdrawal_queue()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_create_payout() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let service = create_transaction_service(
+            token.clone(),
+            user_id,
+            accounts_repo.clone(),
+            Arc::new(StrangeBlockchainTransactionsRepoMock::default()),
+        );
+        let account = accounts_repo
+            .create(NewAccount {
+                user_id,
+                currency: Currency::Eth,
+                ..Default::default()
+            })
+            .unwrap();
+        let fees_account = accounts_repo
+            .create(NewAccount {
+                currency: Currency::Eth,
+                ..Default::default()
+            })
+            .unwrap();
+        accounts_repo
+            .set_system_role(fees_account.id, Some(SystemAccountKind::Fees))
+            .unwrap();
+        for _ in 0..4 {
+            service
+                .transactions_repo
+                .create(NewTransaction {
+                    cr_account_id: account.id,
+                    currency: Currency::Eth,
+                    value: Amount::new(100),
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+        // withdrawal account has enough only for the first of the payouts
+        let withdrawal_account_id = AccountId::generate();
+        service
+            .transactions_repo
+            .create(NewTransaction {
+                cr_account_id: withdrawal_account_id,
+                currency: Currency::Eth,
+                value: Amount::new(101),
+                ..Default::default()
+            })
+            .unwrap();
+        for _ in 0..3 {
+            service
+                .transactions_repo
+                .create(NewTransaction {
+                    dr_account_id: withdrawal_account_id,
+                    currency: Currency::Eth,
+                    value: Amount::new(100),
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+        let payout = |address: &str| PayoutDestination {
+            address: BlockchainAddress::new(address.to_string()),
+            value: Amount::new(100),
+        };
+        let input = CreatePayoutInput {
+            id: TransactionId::generate(),
+            user_id,
+            from: account.id,
+            currency: Currency::Eth,
+            fee: Amount::new(10),
+            payouts: vec![
+                payout("5c3a228510d246b78a3765c20221cbf3082b44a4"),
+                payout("fb6916095ca1df60bb79ce92ce3ea74c37c5d359"),
+                payout("2a8ff6d8a5d2d9a1a54b9a3db2a3a8a3c2e0d6f1"),
+            ],
+        };
+
+        let res = core.run(service.create_payout(token.clone(), input.clone())).unwrap();
+        assert!(!res.is_complete());
+        assert_eq!(res.transaction.id, input.id);
+        assert_eq!(res.transaction.from_value, Amount::new(100));
+        let statuses: Vec<PayoutStatus> = res.payouts.iter().map(|payout| payout.status).collect();
+        assert_eq!(statuses, vec![PayoutStatus::Sent, PayoutStatus::Failed, PayoutStatus::Skipped]);
+        // ids don't change when the request is retried
+        let ids: Vec<TransactionId> = res.payouts.iter().map(|payout| payout.id).collect();
+        assert_eq!(ids, vec![input.id, input.id.derive(1), input.id.derive(2)]);
+        assert_eq!(
+            service.transactions_repo.get_account_balance(account.id, AccountKind::Cr).unwrap(),
+            Amount::new(400 - 110)
+        );
+
+        // nothing is sent, so there's nothing to report but the error
+        let input = CreatePayoutInput {
+            id: TransactionId::generate(),
+            payouts: vec![payout("5c3a228510d246b78a3765c20221cbf3082b44a4")],
+            ..input
+        };
+        assert!(core.run(service.create_payout(token, input)).is_err());
+    }
+
+    #[test]
+    fn test_with_swept_value() {
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let service = create_transaction_service(
+            token,
+            user_id,
+            accounts_repo.clone(),
+            Arc::new(StrangeBlockchainTransactionsRepoMock::default()),
+        );
+        let account = accounts_repo
+            .create(NewAccount {
+                user_id,
+                currency: Currency::Eth,
+                ..Default::default()
+            })
+            .unwrap();
+        service
+            .transactions_repo
+            .create(NewTransaction {
+                cr_account_id: account.id,
+                value: Amount::new(100),
+                ..Default::default()
+            })
+            .unwrap();
+        let input = CreateTransactionInput {
+            id: TransactionId::generate(),
+            user_id,
+            from: account.id,
+            to: Recepient::new("5c3a228510d246b78a3765c20221cbf3082b44a4".to_string()),
+            to_type: RecepientType::Address,
+            to_currency: Currency::Eth,
+            value: Amount::default(),
+            value_currency: Currency::Eth,
+            fee: Amount::new(10),
+            exchange_id: None,
+            exchange_rate: None,
+            fee_payer: FeePayer::Sender,
+            fee_payer_account_id: None,
+            sweep: true,
+            rate_lock_id: None,
+            max_slippage: None,
+        };
+        let swept = service.with_swept_value(input.clone()).unwrap();
+        assert_eq!(swept.value, Amount::new(90));
+        let swept = service
+            .with_swept_value(CreateTransactionInput {
+                fee_payer: FeePayer::Recipient,
+                ..input.clone()
+            })
+            .unwrap();
+        assert_eq!(swept.value, Amount::new(100));
+        // nothing is left to send after the fee
+        assert!(service
+            .with_swept_value(CreateTransactionInput {
+                fee: Amount::new(100),
+                ..input.clone()
+            })
+            .is_err());
+        let not_swept = service.with_swept_value(CreateTransactionInput { sweep: false, ..input }).unwrap();
+        assert_eq!(not_swept.value, Amount::default());
+    }
+
+    #[test]
+    fn test_internal_sweep_takes_balance_at_writing() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let service = create_transaction_service(
+            token,
+            user_id,
+            accounts_repo.clone(),
+            Arc::new(StrangeBlockchainTransactionsRepoMock::default()),
+        );
+        let from_account = accounts_repo
+            .create(NewAccount {
+                user_id,
+                currency: Currency::Eth,
+                ..Default::default()
+            })
+            .unwrap();
+        let to_account = accounts_repo
+            .create(NewAccount {
+                user_id,
+                currency: Currency::Eth,
+                ..Default::default()
+            })
+            .unwrap();
+        service
+            .transactions_repo
+            .create(NewTransaction {
+                cr_account_id: from_account.id,
+                value: Amount::new(100),
+                ..Default::default()
+            })
+            .unwrap();
+        let input = CreateTransactionInput {
+            id: TransactionId::generate(),
+            user_id,
+            from: from_account.id,
+            to: Recepient::new(to_account.id.to_string()),
+            to_type: RecepientType::Account,
+            to_currency: Currency::Eth,
+            value: Amount::default(),
+            value_currency: Currency::Eth,
+            fee: Amount::default(),
+            exchange_id: None,
+            exchange_rate: None,
+            fee_payer: FeePayer::Sender,
+            fee_payer_account_id: None,
+            sweep: true,
+            rate_lock_id: None,
+            max_slippage: None,
+        };
+        let input = service.with_swept_value(input).unwrap();
+        assert_eq!(input.value, Amount::new(100));
+        // deposit arriving after classification is swept too
+        service
+            .transactions_repo
+            .create(NewTransaction {
+                cr_account_id: from_account.id,
+                value: Amount::new(50),
+                ..Default::default()
+            })
+            .unwrap();
+        let tx = core
+            .run(service.create_internal_mono_currency_tx(input, from_account.clone(), to_account))
+            .unwrap();
+        assert_eq!(tx.value, Amount::new(150));
+        assert_eq!(
+            service
+                .transactions_repo
+                .get_account_balance(from_account.id, AccountKind::Cr)
+                .unwrap(),
+            Amount::default()
+        );
+    }
+
+    #[test]
+    fn test_with_locked_rate() {
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let service = create_transaction_service(
+            token,
+            user_id,
+            accounts_repo.clone(),
+            Arc::new(StrangeBlockchainTransactionsRepoMock::default()),
+        );
+        let account = accounts_repo
+            .create(NewAccount {
+                user_id,
+                currency: Currency::Stq,
+                ..Default::default()
+            })
+            .unwrap();
+        let now = ::chrono::Utc::now().naive_utc();
+        let rate_lock = service
+            .rate_locks_repo
+            .create(NewRateLock {
+                user_id,
+                from_currency: Currency::Stq,
+                to_currency: Currency::Eth,
+                amount: Amount::new(100),
+                amount_currency: Currency::Stq,
+                rate: 0.5,
+                expires_at: now + ::chrono::Duration::seconds(60),
+                ..Default::default()
+            })
+            .unwrap();
+        let expired_lock = service
+            .rate_locks_repo
+            .create(NewRateLock {
+                user_id,
+                expires_at: now - ::chrono::Duration::seconds(1),
+                ..Default::default()
+            })
+            .unwrap();
+        let input = CreateTransactionInput {
+            id: TransactionId::generate(),
+            user_id,
+            from: account.id,
+            to: Recepient::new("5c3a228510d246b78a3765c20221cbf3082b44a4".to_string()),
+            to_type: RecepientType::Address,
+            to_currency: Currency::Eth,
+            value: Amount::new(100),
+            value_currency: Currency::Stq,
+            fee: Amount::new(10),
+            exchange_id: None,
+            exchange_rate: None,
+            fee_payer: FeePayer::Sender,
+            fee_payer_account_id: None,
+            sweep: false,
+            rate_lock_id: Some(rate_lock.id),
+            max_slippage: None,
+        };
+        // lock of another currency pair is rejected and stays unused
+        assert!(service
+            .with_locked_rate(CreateTransactionInput {
+                to_currency: Currency::Btc,
+                ..input.clone()
+            })
+            .is_err());
+        // so is the one for a smaller amount or another currency of it
+        assert!(service
+            .with_locked_rate(CreateTransactionInput {
+                value: Amount::new(101),
+                ..input.clone()
+            })
+            .is_err());
+        assert!(service
+            .with_locked_rate(CreateTransactionInput {
+                value_currency: Currency::Eth,
+                ..input.clone()
+            })
+            .is_err());
+        let locked = service.with_locked_rate(input.clone()).unwrap();
+        assert_eq!(locked.exchange_id, Some(rate_lock.exchange_id));
+        assert_eq!(locked.exchange_rate, Some(0.5));
+        assert_eq!(locked.rate_lock_id, None);
+        assert_eq!(locked.max_slippage, Some(service.config.exchange_options.locked_rate_max_slippage));
+        // each lock can be used once
+        assert!(service.with_locked_rate(input.clone()).is_err());
+        // requested slippage bound is kept
+        let another_lock = service
+            .rate_locks_repo
+            .create(NewRateLock {
+                user_id,
+                amount: Amount::new(100),
+                amount_currency: Currency::Stq,
+                expires_at: now + ::chrono::Duration::seconds(60),
+                ..Default::default()
+            })
+            .unwrap();
+        let locked = service
+            .with_locked_rate(CreateTransactionInput {
+                rate_lock_id: Some(another_lock.id),
+                max_slippage: Some(0.05),
+                ..input.clone()
+            })
+            .unwrap();
+        assert_eq!(locked.max_slippage, Some(0.05));
+        assert!(service
+            .with_locked_rate(CreateTransactionInput {
+                rate_lock_id: Some(expired_lock.id),
+                ..input
+            })
+            .is_err());
+    }
+
+    #[test]
+    fn test_retry_failed_broadcasts() {
+        let mut core = Core::new().unwrap();
+        let service = create_transaction_service(
+            AuthenticationToken::default(),
+            UserId::generate(),
+            Arc::new(AccountsRepoMock::default()),
+            Arc::new(StrangeBlockchainTransactionsRepoMock::default()),
+        );
+        let placeholder = BlockchainTransactionId::unbroadcasted();
+        service
+            .pending_transactions_repo
+            .create(NewPendingBlockchainTransactionDB {
+                hash: placeholder.clone(),
+                raw_tx: Some(BlockchainTransactionRaw::default()),
+                next_broadcast_at: Some(::chrono::Utc::now().naive_utc() - ::chrono::Duration::seconds(1)),
+                ..Default::default()
+            })
+            .unwrap();
+        let tx = service
+            .transactions_repo
+            .create(NewTransaction {
+                blockchain_tx_id: Some(placeholder.clone()),
+                ..Default::default()
+            })
+            .unwrap();
+
+        let retried = core.run(service.retry_failed_broadcasts()).unwrap();
+        assert_eq!(retried.len(), 1);
+        assert!(!retried[0].hash.is_unbroadcasted());
+        assert_eq!(retried[0].next_broadcast_at, None);
+        // ledger transaction gets the real hash
+        let tx = service.transactions_repo.get(tx.id).unwrap().unwrap();
+        assert_eq!(tx.blockchain_tx_id, Some(retried[0].hash.clone()));
+    }
+
+    #[test]
+    fn test_group_queued_withdrawals() {
+        let account_id = AccountId::generate();
+        let address = BlockchainAddress::new("5c3a228510d246b78a3765c20221cbf3082b44a4".to_string());
+        let withdrawal = |account_id: AccountId, address: &BlockchainAddress| QueuedWithdrawal {
+            account_id,
+            address: address.clone(),
+            ..Default::default()
+        };
+        let other_address = BlockchainAddress::new("fb6916095ca1df60bb79ce92ce3ea74c37c5d359".to_string());
+        let withdrawals = vec![
+            withdrawal(account_id, &address),
+            withdrawal(account_id, &other_address),
+            withdrawal(AccountId::generate(), &address),
+            withdrawal(account_id, &address),
+        ];
+        let ids: Vec<_> = withdrawals.iter().map(|withdrawal| withdrawal.id).collect();
+        let batches: Vec<Vec<_>> = group_queued_withdrawals(withdrawals)
+            .into_iter()
+            .map(|batch| batch.into_iter().map(|withdrawal| withdrawal.id).collect())
+            .collect();
+        assert_eq!(batches, vec![vec![ids[0], ids[3]], vec![ids[1]], vec![ids[2]]]);
+    }
+
+    #[test]
+    fn test_bounce_strange_transaction() {
+        let mut core = Core::new().unwrap();
+        let config = Config::new().unwrap();
+        let token = AuthenticationToken::default();
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let strange_blockchain_transactions_repo = Arc::new(StrangeBlockchainTransactionsRepoMock::default());
+        let service = create_transaction_service(
+            token.clone(),
+            config.system.system_user_id,
+            accounts_repo.clone(),
+            strange_blockchain_transactions_repo.clone(),
+        );
+        let address = BlockchainAddress::new("fb6916095ca1df60bb79ce92ce3ea74c37c5d359".to_string());
+        let sender = BlockchainAddress::new("5c3a228510d246b78a3765c20221cbf3082b44a4".to_string());
+        let account = accounts_repo
+            .create(NewAccount {
+                currency: Currency::Eth,
+                address: address.clone(),
+                kind: AccountKind::Dr,
+                ..Default::default()
+            })
+            .unwrap();
+        let value = Amount::new(1_000_000_000_000_000_000);
+        let hash = BlockchainTransactionId::default();
+        strange_blockchain_transactions_repo
+            .create(NewStrangeBlockchainTransactionDB {
+                hash: hash.clone(),
+                from_: serde_json::to_value(vec![sender.clone()]).unwrap(),
+                to_: serde_json::to_value(vec![BlockchainTransactionEntryTo {
+                    address: address.clone(),
+                    value,
+                }])
+                .unwrap(),
+                currency: Currency::Eth,
+                ..Default::default()
+            })
+            .unwrap();
+
+        // funds can't be returned to our own address
+        let res = core.run(service.bounce_strange_transaction(token.clone(), hash.clone(), Some(address.clone())));
+        assert!(res.is_err());
+
+        let tx_out = core
+            .run(service.bounce_strange_transaction(token.clone(), hash.clone(), None))
+            .unwrap();
+        let fee = Amount::new((config.fee_price.ethereum * config.fees_options.eth_gas_limit as f64) as u128);
+        assert_eq!(tx_out.kind, TransactionKind::Bounce);
+        assert_eq!(tx_out.status, TransactionStatus::Pending);
+        assert_eq!(tx_out.from[0].account_id, Some(account.id));
+        assert_eq!(tx_out.to.blockchain_address, sender);
+        assert_eq!(tx_out.from_value, value.checked_sub(fee).unwrap());
+        assert!(strange_blockchain_transactions_repo.get(hash.clone()).unwrap().is_none());
+
+        // bounced transaction is gone
+        let res = core.run(service.bounce_strange_transaction(token, hash, None));
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_bounce_strange_transaction_is_claimed_before_sending() {
+        let mut core = Core::new().unwrap();
+        let config = Config::new().unwrap();
+        let token = AuthenticationToken::default();
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let strange_blockchain_transactions_repo = Arc::new(StrangeBlockchainTransactionsRepoMock::default());
+        let service = create_transaction_service(
+            token.clone(),
+            config.system.system_user_id,
+            accounts_repo.clone(),
+            strange_blockchain_transactions_repo.clone(),
+        );
+        let address = BlockchainAddress::new("fb6916095ca1df60bb79ce92ce3ea74c37c5d359".to_string());
+        let sender = BlockchainAddress::new("5c3a228510d246b78a3765c20221cbf3082b44a4".to_string());
+        accounts_repo
+            .create(NewAccount {
+                currency: Currency::Eth,
+                address: address.clone(),
+                kind: AccountKind::Dr,
+                ..Default::default()
+            })
+            .unwrap();
+        let hash = BlockchainTransactionId::default();
+        strange_blockchain_transactions_repo
+            .create(NewStrangeBlockchainTransactionDB {
+                hash: hash.clone(),
+                from_: serde_json::to_value(vec![sender]).unwrap(),
+                to_: serde_json::to_value(vec![BlockchainTransactionEntryTo {
+                    address,
+                    value: Amount::new(1_000_000_000_000_000_000),
+                }])
+                .unwrap(),
+                currency: Currency::Eth,
+                ..Default::default()
+            })
+            .unwrap();
+
+        // the first request took it out and is sending the funds, the second one has nothing to bounce
+        service.claim_bounce(hash.clone(), None).unwrap();
+        let err = core.run(service.bounce_strange_transaction(token, hash, None)).unwrap_err();
+        assert!(match err.kind() {
+            ErrorKind::NotFound => true,
+            _ => false,
+        });
+    }
+
+    #[test]
+    fn test_bounce_strange_stq_transaction() {
+        let mut core = Core::new().unwrap();
+        let config = Config::new().unwrap();
+        let token = AuthenticationToken::default();
+        let accounts_repo = Arc::new(AccountsRepoMock::default());
+        let strange_blockchain_transactions_repo = Arc::new(StrangeBlockchainTransactionsRepoMock::default());
+        let service = create_transaction_service(
+            token.clone(),
+            config.system.system_user_id,
+            accounts_repo.clone(),
+            strange_blockchain_transactions_repo.clone(),
+        );
+        let address = BlockchainAddress::new("fb6916095ca1df60bb79ce92ce3ea74c37c5d359".to_string());
+        let sender = BlockchainAddress::new("5c3a228510d246b78a3765c20221cbf3082b44a4".to_string());
+        let account = accounts_repo
+            .create(NewAccount {
+                currency: Currency::Stq,
+                address: address.clone(),
+                kind: AccountKind::Dr,
+                ..Default::default()
+            })
+            .unwrap();
+        let value = Amount::new(1_000_000_000_000_000_000);
+        let new_strange_tx = NewStrangeBlockchainTransactionDB {
+            hash: BlockchainTransactionId::default(),
+            from_: serde_json::to_value(vec![sender.clone()]).unwrap(),
+            to_: serde_json::to_value(vec![BlockchainTransactionEntryTo {
+                address: address.clone(),
+                value,
+            }])
+            .unwrap(),
+            currency: Currency::Stq,
+            ..Default::default()
+        };
+        let hash = new_strange_tx.hash.clone();
+        strange_blockchain_transactions_repo.create(new_strange_tx.clone()).unwrap();
+
+        // our own erc20 operations are not deposits
+        let approve_hash = BlockchainTransactionId::new("approve".to_string());
+        strange_blockchain_transactions_repo
+            .create(NewStrangeBlockchainTransactionDB {
+                hash: approve_hash.clone(),
+                erc20_operation_kind: Some(Erc20OperationKind::Approve),
+                ..new_strange_tx
+            })
+            .unwrap();
+        let res = core.run(service.bounce_strange_transaction(token.clone(), approve_hash.clone(), None));
+        assert!(res.is_err());
+        assert!(strange_blockchain_transactions_repo.get(approve_hash).unwrap().is_some());
+
+        // tokens can't be sent until the address is approved
+        let res = core.run(service.bounce_strange_transaction(token.clone(), hash.clone(), None));
+        assert!(res.is_err());
+        assert!(strange_blockchain_transactions_repo.get(hash.clone()).unwrap().is_some());
+        accounts_repo
+            .update(
+                account.id,
+                UpdateAccount {
+                    erc20_approved: Some(true),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        // eth fee is paid by system fees account, the transaction is put back if it's missing
+        let res = core.run(service.bounce_strange_transaction(token.clone(), hash.clone(), None));
+        assert!(res.is_err());
+        assert!(strange_blockchain_transactions_repo.get(hash.clone()).unwrap().is_some());
+        let fees_account = accounts_repo
+            .create(NewAccount {
+                currency: Currency::Eth,
+                address: BlockchainAddress::new("8a4b4a3ef0f6a1f6d6c53e9ff8ec3e21c4e0a1b2".to_string()),
+                kind: AccountKind::Cr,
+                ..Default::default()
+            })
+            .unwrap();
+        accounts_repo
+            .set_system_role(fees_account.id, Some(SystemAccountKind::Fees))
+            .unwrap();
+
+        let tx_out = core.run(service.bounce_strange_transaction(token, hash.clone(), None)).unwrap();
+        assert_eq!(tx_out.kind, TransactionKind::Bounce);
+        assert_eq!(tx_out.from_currency, Currency::Stq);
+        assert_eq!(tx_out.to.blockchain_address, sender);
+        // network fee is not taken out of the tokens
+        assert_eq!(tx_out.from_value, value);
+        assert!(strange_blockchain_transactions_repo.get(hash).unwrap().is_none());
+    }
 }
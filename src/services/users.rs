@@ -1,41 +1,86 @@
 use std::sync::Arc;
 
+use futures::future;
 use futures::IntoFuture;
 use serde_json;
 use validator::Validate;
 
+use super::auth::AuthService;
 use super::error::*;
+use super::ServiceFuture;
 use models::*;
 use prelude::*;
+use rabbit::TransactionPublisher;
 use repos::{DbExecutor, UsersRepo};
+use utils::log_error;
 
 #[derive(Clone)]
 pub struct UsersServiceImpl<E: DbExecutor> {
+    auth_service: Arc<dyn AuthService>,
     users_repo: Arc<UsersRepo>,
+    system_user_id: UserId,
     db_executor: E,
+    publisher: Arc<dyn TransactionPublisher>,
 }
 
 impl<E: DbExecutor> UsersServiceImpl<E> {
-    pub fn new(users_repo: Arc<UsersRepo>, db_executor: E) -> Self {
-        Self { users_repo, db_executor }
+    pub fn new(
+        auth_service: Arc<dyn AuthService>,
+        users_repo: Arc<UsersRepo>,
+        system_user_id: UserId,
+        db_executor: E,
+        publisher: Arc<dyn TransactionPublisher>,
+    ) -> Self {
+        Self {
+            auth_service,
+            users_repo,
+            system_user_id,
+            db_executor,
+            publisher,
+        }
+    }
+
+    fn authorize(&self, token: AuthenticationToken) -> ServiceFuture<()> {
+        let system_user_id = self.system_user_id;
+        Box::new(self.auth_service.authenticate(token).and_then(move |user| {
+            if user.id == system_user_id {
+                future::ok(())
+            } else {
+                future::err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id))
+            }
+        }))
     }
 }
 
 pub trait UsersService: Send + Sync + 'static {
     fn create_user(&self, input: NewUser) -> Box<Future<Item = User, Error = Error> + Send>;
     fn find_user_by_authentication_token(&self, token: AuthenticationToken) -> Box<Future<Item = Option<User>, Error = Error> + Send>;
+    /// Users for support, newest first. `query` is matched against name and id. Only system user is allowed
+    fn get_users(&self, token: AuthenticationToken, query: Option<String>, offset: i64, limit: i64) -> ServiceFuture<Vec<UserOverview>>;
 }
 
 impl<E: DbExecutor> UsersService for UsersServiceImpl<E> {
     fn create_user(&self, input: NewUser) -> Box<Future<Item = User, Error = Error> + Send> {
         let users_repo = self.users_repo.clone();
         let db_executor = self.db_executor.clone();
+        let publisher = self.publisher.clone();
         Box::new(
             input
                 .validate()
                 .map_err(|e| ectx!(err e.clone(), ErrorKind::InvalidInput(serde_json::to_string(&e).unwrap_or_default()) => input))
                 .into_future()
-                .and_then(move |_| db_executor.execute(move || users_repo.create(input.clone()).map_err(ectx!(convert => input)))),
+                .and_then(move |_| db_executor.execute(move || users_repo.create(input.clone()).map_err(ectx!(convert => input))))
+                .and_then(move |user| {
+                    // queues are declared on the first publish anyway, so the user is created even if that fails
+                    let user_id = user.id;
+                    publisher.provision_user(user_id).then(move |res| {
+                        if let Err(e) = res {
+                            let e: Error = ectx!(err e, ErrorSource::Lapin, ErrorKind::Internal => user_id);
+                            log_error(&e);
+                        }
+                        Ok(user)
+                    })
+                }),
         )
     }
     fn find_user_by_authentication_token(&self, token: AuthenticationToken) -> Box<Future<Item = Option<User>, Error = Error> + Send> {
@@ -46,24 +91,44 @@ impl<E: DbExecutor> UsersService for UsersServiceImpl<E> {
                 .map_err(ectx!(convert => token))
         })
     }
+    fn get_users(&self, token: AuthenticationToken, query: Option<String>, offset: i64, limit: i64) -> ServiceFuture<Vec<UserOverview>> {
+        let users_repo = self.users_repo.clone();
+        let db_executor = self.db_executor.clone();
+        Box::new(self.authorize(token).and_then(move |_| {
+            db_executor.execute_read_only(move || {
+                let users = users_repo
+                    .search(query.clone(), offset, limit)
+                    .map_err(ectx!(try convert => query, offset, limit))?;
+                let user_ids: Vec<UserId> = users.iter().map(|user| user.id).collect();
+                let summaries = users_repo
+                    .get_accounts_summaries(&user_ids)
+                    .map_err(ectx!(try convert => user_ids))?;
+                Ok(users.into_iter().map(|user| UserOverview::new(user, &summaries)).collect())
+            })
+        }))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use rabbit::TransactionPublisherMock;
     use repos::*;
+    use services::*;
     use tokio_core::reactor::Core;
 
-    fn create_users_service() -> UsersServiceImpl<DbExecutorMock> {
+    fn create_users_service(token: AuthenticationToken, system_user_id: UserId) -> UsersServiceImpl<DbExecutorMock> {
+        let auth_service = Arc::new(AuthServiceMock::new(vec![(token, system_user_id)]));
         let users_repo = Arc::new(UsersRepoMock::default());
         let db_executor = DbExecutorMock::default();
-        UsersServiceImpl::new(users_repo, db_executor)
+        let publisher = Arc::new(TransactionPublisherMock::default());
+        UsersServiceImpl::new(auth_service, users_repo, system_user_id, db_executor, publisher)
     }
 
     #[test]
     fn test_create() {
         let mut core = Core::new().unwrap();
-        let users_service = create_users_service();
+        let users_service = create_users_service(AuthenticationToken::default(), UserId::generate());
         let mut new_user = NewUser::default();
         new_user.name = "fksjdlfkjsdlkfdlksf".to_string();
         new_user.authentication_token = AuthenticationToken::new("fksjdlfkjsdlkfdlksf".to_string());
@@ -74,7 +139,7 @@ mod tests {
     #[test]
     fn test_get_by_auth_token() {
         let mut core = Core::new().unwrap();
-        let users_service = create_users_service();
+        let users_service = create_users_service(AuthenticationToken::default(), UserId::generate());
         let mut new_user = NewUser::default();
         new_user.name = "fksjdlfkjsdlkfdlksf".to_string();
         new_user.authentication_token = AuthenticationToken::new("fksjdlfkjsdlkfdlksf".to_string());
@@ -86,4 +151,27 @@ mod tests {
         assert!(user.is_some());
     }
 
+    #[test]
+    fn test_get_users() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let system_user_id = UserId::generate();
+        let users_service = create_users_service(token.clone(), system_user_id);
+        for name in &["Alice", "Bob"] {
+            let mut new_user = NewUser::default();
+            new_user.name = name.to_string();
+            core.run(users_service.create_user(new_user)).unwrap();
+        }
+        let users = core
+            .run(users_service.get_users(token.clone(), Some("ali".to_string()), 0, 10))
+            .unwrap();
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].user.name, "Alice");
+        assert_eq!(users[0].accounts_count, 0);
+        let users = core.run(users_service.get_users(token, None, 1, 10)).unwrap();
+        assert_eq!(users.len(), 1);
+        let users = core.run(users_service.get_users(AuthenticationToken::new("unknown".to_string()), None, 0, 10));
+        assert!(users.is_err());
+    }
+
 }
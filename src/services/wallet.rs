@@ -0,0 +1,219 @@
+use std::sync::Arc;
+
+use super::auth::AuthService;
+use super::error::*;
+use super::exchange::RatesCache;
+use super::ServiceFuture;
+use models::*;
+use prelude::*;
+use repos::{AccountsRepo, DbExecutor, TransactionsRepo};
+
+/// Users have a handful of accounts, this only guards against loading a pathological number of them
+const MAX_WALLET_ACCOUNTS: i64 = 1000;
+
+pub trait WalletService: Send + Sync + 'static {
+    /// Balances of all user's accounts with totals by currency, so that clients don't have to
+    /// request balances account by account
+    fn get_wallet(&self, token: AuthenticationToken, user_id: UserId, reference_currency: Currency) -> ServiceFuture<Wallet>;
+}
+
+#[derive(Clone)]
+pub struct WalletServiceImpl<E: DbExecutor> {
+    auth_service: Arc<dyn AuthService>,
+    accounts_repo: Arc<dyn AccountsRepo>,
+    transactions_repo: Arc<dyn TransactionsRepo>,
+    rates_cache: RatesCache,
+    db_executor: E,
+}
+
+impl<E: DbExecutor> WalletServiceImpl<E> {
+    pub fn new(
+        auth_service: Arc<dyn AuthService>,
+        accounts_repo: Arc<dyn AccountsRepo>,
+        transactions_repo: Arc<dyn TransactionsRepo>,
+        rates_cache: RatesCache,
+        db_executor: E,
+    ) -> Self {
+        Self {
+            auth_service,
+            accounts_repo,
+            transactions_repo,
+            rates_cache,
+            db_executor,
+        }
+    }
+
+    // Rates are only used for an estimate, so the ones already received from exchange gateway are taken
+    // instead of asking it on every request. The total is omitted until rates of all currencies are cached
+    fn approximate_total(&self, totals: &[CurrencyTotal], reference_currency: Currency) -> Option<Amount> {
+        totals.iter().try_fold(Amount::new(0), |sum, total| {
+            let value = if total.currency == reference_currency || total.balance == Amount::new(0) {
+                total.balance
+            } else {
+                let rate = self.rates_cache.get(total.currency, reference_currency)?;
+                total.balance.convert(total.currency, reference_currency, rate.rate)
+            };
+            sum.checked_add(value)
+        })
+    }
+}
+
+impl<E: DbExecutor> WalletService for WalletServiceImpl<E> {
+    fn get_wallet(&self, token: AuthenticationToken, user_id: UserId, reference_currency: Currency) -> ServiceFuture<Wallet> {
+        let accounts_repo = self.accounts_repo.clone();
+        let transactions_repo = self.transactions_repo.clone();
+        let db_executor = self.db_executor.clone();
+        let self_clone = self.clone();
+        Box::new(
+            self.auth_service
+                .authenticate(token)
+                .and_then(move |user| {
+                    db_executor.execute_read_only(move || -> Result<(Vec<AccountWithBalance>, Vec<CurrencyTotal>), Error> {
+                        if user_id != user.id {
+                            return Err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id));
+                        }
+                        let accounts = accounts_repo
+                            .list_for_user(user_id, 0, MAX_WALLET_ACCOUNTS, AccountsFilter::default())
+                            .map_err(ectx!(try convert => user_id))?;
+                        let accounts = transactions_repo
+                            .get_accounts_balance(user_id, &accounts)
+                            .map_err(ectx!(try convert => user_id))?;
+                        let totals = sum_by_currency(&accounts)?;
+                        Ok((accounts, totals))
+                    })
+                })
+                .map(move |(accounts, totals)| {
+                    let approximate_total = self_clone.approximate_total(&totals, reference_currency);
+                    Wallet {
+                        accounts,
+                        totals,
+                        reference_currency,
+                        approximate_total,
+                    }
+                }),
+        )
+    }
+}
+
+// Totals are in order of the first account in each currency
+fn sum_by_currency(accounts: &[AccountWithBalance]) -> Result<Vec<CurrencyTotal>, Error> {
+    let mut totals: Vec<CurrencyTotal> = Vec::new();
+    for account in accounts {
+        let currency = account.account.currency;
+        match totals.iter().position(|total| total.currency == currency) {
+            Some(i) => {
+                totals[i].balance = totals[i]
+                    .balance
+                    .checked_add(account.balance)
+                    .ok_or_else(|| ectx!(try err ErrorContext::BalanceOverflow, ErrorKind::Internal => currency))?;
+            }
+            None => totals.push(CurrencyTotal {
+                currency,
+                balance: account.balance,
+            }),
+        }
+    }
+    Ok(totals)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use client::*;
+    use config::Config;
+    use repos::*;
+    use services::*;
+    use tokio_core::reactor::Core;
+
+    fn create_wallet_service(token: AuthenticationToken, user_id: UserId, rates_cache: RatesCache) -> WalletServiceImpl<DbExecutorMock> {
+        WalletServiceImpl::new(
+            Arc::new(AuthServiceMock::new(vec![(token, user_id)])),
+            Arc::new(AccountsRepoMock::default()),
+            Arc::new(TransactionsRepoMock::default()),
+            rates_cache,
+            DbExecutorMock::default(),
+        )
+    }
+
+    fn account_with_balance(currency: Currency, balance: u128) -> AccountWithBalance {
+        let mut account = Account::default();
+        account.currency = currency;
+        AccountWithBalance {
+            account,
+            balance: Amount::new(balance),
+        }
+    }
+
+    #[test]
+    fn test_sum_by_currency() {
+        let accounts = vec![
+            account_with_balance(Currency::Eth, 10),
+            account_with_balance(Currency::Btc, 5),
+            account_with_balance(Currency::Eth, 20),
+        ];
+        let totals = sum_by_currency(&accounts).unwrap();
+        assert_eq!(
+            totals,
+            vec![
+                CurrencyTotal {
+                    currency: Currency::Eth,
+                    balance: Amount::new(30),
+                },
+                CurrencyTotal {
+                    currency: Currency::Btc,
+                    balance: Amount::new(5),
+                },
+            ]
+        );
+        let overflowing = vec![
+            account_with_balance(Currency::Eth, u128::max_value()),
+            account_with_balance(Currency::Eth, 1),
+        ];
+        assert!(sum_by_currency(&overflowing).is_err());
+    }
+
+    #[test]
+    fn test_wallet_only_for_owner() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let service = create_wallet_service(token.clone(), user_id, RatesCache::new(&Config::new().unwrap()));
+        let wallet = core.run(service.get_wallet(token.clone(), user_id, Currency::Btc)).unwrap();
+        assert!(wallet.accounts.is_empty());
+        assert_eq!(wallet.approximate_total, Some(Amount::new(0)));
+        assert!(core.run(service.get_wallet(token, UserId::generate(), Currency::Btc)).is_err());
+    }
+
+    #[test]
+    fn test_approximate_total_from_cached_rates() {
+        let mut core = Core::new().unwrap();
+        let config = Config::new().unwrap();
+        let rates_cache = RatesCache::new(&config);
+        let service = create_wallet_service(AuthenticationToken::default(), UserId::generate(), rates_cache.clone());
+        let exchange_client = Arc::new(ExchangeClientMock::default());
+        *exchange_client.rate.lock().unwrap() = 2.0;
+        let exchange_service = ExchangeServiceImpl::new(&config, exchange_client, rates_cache);
+        let eth_balance = Amount::new(1_000_000_000_000_000_000);
+        let stq_balance = Amount::new(5_000_000_000_000_000_000);
+        let totals = vec![
+            CurrencyTotal {
+                currency: Currency::Eth,
+                balance: eth_balance,
+            },
+            CurrencyTotal {
+                currency: Currency::Stq,
+                balance: stq_balance,
+            },
+        ];
+
+        // gateway isn't asked for rates, so there's no total until they're cached
+        assert_eq!(service.approximate_total(&totals, Currency::Stq), None);
+        let rate_input = RateInput::new(Currency::Eth, Currency::Stq, eth_balance, Currency::Eth);
+        core.run(exchange_service.rate(AuthenticationToken::default(), rate_input)).unwrap();
+        let converted = eth_balance.convert(Currency::Eth, Currency::Stq, 2.0);
+        assert_eq!(
+            service.approximate_total(&totals, Currency::Stq),
+            stq_balance.checked_add(converted)
+        );
+    }
+}
@@ -0,0 +1,220 @@
+use std::sync::Arc;
+
+use chrono::Duration;
+use serde_json;
+use uuid::Uuid;
+use validator::Validate;
+
+use super::auth::AuthService;
+use super::error::*;
+use super::ServiceFuture;
+use config::Config;
+use models::*;
+use prelude::*;
+use repos::{DbExecutor, UsersRepo, WithdrawalAddressesRepo};
+
+pub trait WithdrawalAddressesService: Send + Sync + 'static {
+    fn get_withdrawal_addresses(&self, token: AuthenticationToken, user_id: UserId) -> ServiceFuture<Vec<WithdrawalAddress>>;
+    /// Adds address to the user's whitelist. It can be used for withdrawals only after the configured delay
+    fn create_withdrawal_address(&self, token: AuthenticationToken, input: CreateWithdrawalAddress) -> ServiceFuture<WithdrawalAddress>;
+    fn delete_withdrawal_address(&self, token: AuthenticationToken, user_id: UserId, id: Uuid) -> ServiceFuture<WithdrawalAddress>;
+    /// With whitelist enabled, withdrawals are allowed only to active addresses from the whitelist.
+    /// User can only enable it, disabling is up to the system user
+    fn set_withdrawal_whitelist(&self, token: AuthenticationToken, user_id: UserId, enabled: bool) -> ServiceFuture<User>;
+}
+
+#[derive(Clone)]
+pub struct WithdrawalAddressesServiceImpl<E: DbExecutor> {
+    auth_service: Arc<dyn AuthService>,
+    users_repo: Arc<dyn UsersRepo>,
+    withdrawal_addresses_repo: Arc<dyn WithdrawalAddressesRepo>,
+    activation_delay: Duration,
+    system_user_id: UserId,
+    db_executor: E,
+}
+
+impl<E: DbExecutor> WithdrawalAddressesServiceImpl<E> {
+    pub fn new(
+        config: &Config,
+        auth_service: Arc<dyn AuthService>,
+        users_repo: Arc<dyn UsersRepo>,
+        withdrawal_addresses_repo: Arc<dyn WithdrawalAddressesRepo>,
+        db_executor: E,
+    ) -> Self {
+        Self {
+            auth_service,
+            users_repo,
+            withdrawal_addresses_repo,
+            activation_delay: Duration::seconds(config.withdrawal_whitelist.activation_delay_secs as i64),
+            system_user_id: config.system.system_user_id,
+            db_executor,
+        }
+    }
+}
+
+impl<E: DbExecutor> WithdrawalAddressesService for WithdrawalAddressesServiceImpl<E> {
+    fn get_withdrawal_addresses(&self, token: AuthenticationToken, user_id: UserId) -> ServiceFuture<Vec<WithdrawalAddress>> {
+        let withdrawal_addresses_repo = self.withdrawal_addresses_repo.clone();
+        let db_executor = self.db_executor.clone();
+        Box::new(self.auth_service.authenticate(token).and_then(move |user| {
+            db_executor.execute(move || {
+                if user_id != user.id {
+                    return Err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id));
+                }
+                withdrawal_addresses_repo.list_for_user(user_id).map_err(ectx!(convert => user_id))
+            })
+        }))
+    }
+
+    fn create_withdrawal_address(&self, token: AuthenticationToken, input: CreateWithdrawalAddress) -> ServiceFuture<WithdrawalAddress> {
+        let withdrawal_addresses_repo = self.withdrawal_addresses_repo.clone();
+        let db_executor = self.db_executor.clone();
+        let activation_delay = self.activation_delay;
+        Box::new(self.auth_service.authenticate(token).and_then(move |user| {
+            db_executor.execute(move || {
+                if input.user_id != user.id {
+                    return Err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id));
+                }
+                input.validate().map_err(
+                    |e| ectx!(try err e.clone(), ErrorKind::InvalidInput(serde_json::to_string(&e).unwrap_or_default()) => input),
+                )?;
+                let new_address = NewWithdrawalAddress {
+                    id: Uuid::new_v4(),
+                    user_id: input.user_id,
+                    currency: input.currency,
+                    address: input.address.clone(),
+                    label: input.label.clone(),
+                    active_from: ::chrono::Utc::now().naive_utc() + activation_delay,
+                };
+                withdrawal_addresses_repo
+                    .create(new_address.clone())
+                    .map_err(ectx!(convert => new_address))
+            })
+        }))
+    }
+
+    fn delete_withdrawal_address(&self, token: AuthenticationToken, user_id: UserId, id: Uuid) -> ServiceFuture<WithdrawalAddress> {
+        let withdrawal_addresses_repo = self.withdrawal_addresses_repo.clone();
+        let db_executor = self.db_executor.clone();
+        Box::new(self.auth_service.authenticate(token).and_then(move |user| {
+            db_executor.execute(move || {
+                if user_id != user.id {
+                    return Err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id));
+                }
+                let address = withdrawal_addresses_repo
+                    .get(id)
+                    .map_err(ectx!(try convert => id))?
+                    .filter(|address| address.user_id == user_id)
+                    .ok_or(ectx!(try err ErrorContext::NoWithdrawalAddress, ErrorKind::NotFound => id))?;
+                withdrawal_addresses_repo
+                    .delete(address.id)
+                    .map_err(ectx!(try convert => id))?
+                    .ok_or(ectx!(err ErrorContext::NoWithdrawalAddress, ErrorKind::NotFound => id))
+            })
+        }))
+    }
+
+    fn set_withdrawal_whitelist(&self, token: AuthenticationToken, user_id: UserId, enabled: bool) -> ServiceFuture<User> {
+        let users_repo = self.users_repo.clone();
+        let db_executor = self.db_executor.clone();
+        let system_user_id = self.system_user_id;
+        Box::new(self.auth_service.authenticate(token).and_then(move |user| {
+            db_executor.execute(move || {
+                // disabling takes effect immediately, so with user's own token it would bypass
+                // the activation delay of addresses added just before
+                let authorized = if enabled { user_id == user.id } else { user.id == system_user_id };
+                if !authorized {
+                    return Err(ectx!(err ErrorContext::InvalidToken, ErrorKind::Unauthorized => user.id, user_id, enabled));
+                }
+                let payload = UpdateUser {
+                    withdrawal_whitelist: Some(enabled),
+                    ..Default::default()
+                };
+                users_repo.update(user_id, payload).map_err(ectx!(convert => user_id, enabled))
+            })
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use repos::*;
+    use services::*;
+    use tokio_core::reactor::Core;
+
+    #[test]
+    fn test_withdrawal_addresses() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let user_id = UserId::generate();
+        let config = Config::new().unwrap();
+        let service = WithdrawalAddressesServiceImpl::new(
+            &config,
+            Arc::new(AuthServiceMock::new(vec![(token.clone(), user_id)])),
+            Arc::new(UsersRepoMock::default()),
+            Arc::new(WithdrawalAddressesRepoMock::default()),
+            DbExecutorMock::default(),
+        );
+        let input = CreateWithdrawalAddress {
+            user_id,
+            currency: Currency::Btc,
+            address: BlockchainAddress::new("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa".to_string()),
+            label: Some("cold storage".to_string()),
+        };
+
+        let address = core.run(service.create_withdrawal_address(token.clone(), input.clone())).unwrap();
+        assert!(!address.is_active());
+        let addresses = core.run(service.get_withdrawal_addresses(token.clone(), user_id)).unwrap();
+        assert_eq!(addresses.len(), 1);
+        // other user's whitelist can't be touched
+        let other_input = CreateWithdrawalAddress {
+            user_id: UserId::generate(),
+            ..input
+        };
+        assert!(core.run(service.create_withdrawal_address(token.clone(), other_input)).is_err());
+        assert!(core
+            .run(service.delete_withdrawal_address(token.clone(), UserId::generate(), address.id))
+            .is_err());
+
+        let deleted = core
+            .run(service.delete_withdrawal_address(token.clone(), user_id, address.id))
+            .unwrap();
+        assert_eq!(deleted.id, address.id);
+        assert!(core.run(service.get_withdrawal_addresses(token, user_id)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_set_withdrawal_whitelist() {
+        let mut core = Core::new().unwrap();
+        let token = AuthenticationToken::default();
+        let system_token = AuthenticationToken::default();
+        let config = Config::new().unwrap();
+        let users_repo = Arc::new(UsersRepoMock::default());
+        let user = users_repo.create(NewUser::default()).unwrap();
+        let service = WithdrawalAddressesServiceImpl::new(
+            &config,
+            Arc::new(AuthServiceMock::new(vec![
+                (token.clone(), user.id),
+                (system_token.clone(), config.system.system_user_id),
+            ])),
+            users_repo,
+            Arc::new(WithdrawalAddressesRepoMock::default()),
+            DbExecutorMock::default(),
+        );
+
+        let updated = core.run(service.set_withdrawal_whitelist(token.clone(), user.id, true)).unwrap();
+        assert!(updated.withdrawal_whitelist);
+        // user can't disable it on their own, nor enable for someone else
+        let err = core
+            .run(service.set_withdrawal_whitelist(token.clone(), user.id, false))
+            .unwrap_err();
+        assert!(match err.kind() {
+            ErrorKind::Unauthorized => true,
+            _ => false,
+        });
+        assert!(core.run(service.set_withdrawal_whitelist(token, UserId::generate(), true)).is_err());
+        let updated = core.run(service.set_withdrawal_whitelist(system_token, user.id, false)).unwrap();
+        assert!(!updated.withdrawal_whitelist);
+    }
+}